@@ -0,0 +1,130 @@
+//! Times what a download does for every message, piece and peer: decoding and encoding
+//! bencode, hashing pieces, and going through bitfields. SHA-1 runs on the CPU's SHA
+//! extensions where it has them, which the `sha1` crate picks at runtime. Run with
+//! `cargo bench --bench hot_paths`.
+
+use bittorent_client::bencode::{self, decode_bencoded_value, encode_bencoded_value};
+use bittorent_client::bitfield::Bitfield;
+use bittorent_client::create::{create, CreateOptions};
+use bittorent_client::torrent::Torrent;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// How long each benchmark runs for, after as long again to warm up.
+const MEASURE: Duration = Duration::from_secs(1);
+const PIECE_LENGTH: usize = 1024 * 1024;
+const PIECES: usize = 16;
+const BITFIELD_PIECES: usize = 100_000;
+
+fn main() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // a torrent of many small pieces has a long `pieces` string, as large ones do
+    let source = dir.path().join("many-pieces.bin");
+    std::fs::File::create(&source)?.set_len(64 * 1024 * 1024)?;
+    let options = CreateOptions {
+        piece_length: Some(16 * 1024),
+        ..CreateOptions::default()
+    };
+    let metainfo = create(&source, &options)?.to_bytes();
+    let decoded = bencode::decode(&metainfo)?;
+    bench("bencode decode", metainfo.len(), || {
+        bencode::decode(&metainfo)?;
+        Ok(())
+    })?;
+    bench("bencode decode borrowed", metainfo.len(), || {
+        bencode::decode_borrowed(&metainfo)?;
+        Ok(())
+    })?;
+    bench("bencode encode", metainfo.len(), || {
+        black_box(decoded.to_bytes());
+        Ok(())
+    })?;
+    bench("torrent from bytes", metainfo.len(), || {
+        Torrent::from_bytes(&metainfo)?;
+        Ok(())
+    })?;
+    let files: Vec<_> = (0..1000)
+        .map(|i| serde_json::json!({ "length": i, "path": ["dir", format!("file {}", i)] }))
+        .collect();
+    let text = String::from_utf8(encode_bencoded_value(&serde_json::json!({
+        "files": files
+    }))?)?;
+    bench("bencode to JSON", text.len(), || {
+        decode_bencoded_value(&text)?;
+        Ok(())
+    })?;
+
+    let source = dir.path().join("large-pieces.bin");
+    let data: Vec<u8> = (0..PIECES * PIECE_LENGTH)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    std::fs::write(&source, &data)?;
+    let options = CreateOptions {
+        piece_length: Some(PIECE_LENGTH),
+        ..CreateOptions::default()
+    };
+    let torrent = create(&source, &options)?;
+    bench("verify pieces", data.len(), || {
+        for (index, piece) in data.chunks(PIECE_LENGTH).enumerate() {
+            anyhow::ensure!(torrent.verify_piece(index, piece), "piece {} is bad", index);
+        }
+        Ok(())
+    })?;
+
+    let ours: Bitfield = (0..BITFIELD_PIECES).map(|i| i % 3 == 0).collect();
+    let theirs: Bitfield = (0..BITFIELD_PIECES).map(|i| i % 5 != 0).collect();
+    let bytes = ours.as_bytes().len();
+    bench("bitfield count", bytes, || {
+        black_box(ours.count());
+        Ok(())
+    })?;
+    bench("bitfield ones", bytes, || {
+        black_box(ours.ones().count());
+        Ok(())
+    })?;
+    bench("bitfield zeros", bytes, || {
+        black_box(theirs.zeros().count());
+        Ok(())
+    })?;
+    bench("bitfield difference", bytes, || {
+        black_box(theirs.difference(&ours));
+        Ok(())
+    })?;
+    bench("bitfield collect", bytes, || {
+        black_box(
+            (0..BITFIELD_PIECES)
+                .map(|i| i % 3 == 0)
+                .collect::<Bitfield>(),
+        );
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// Runs `work` over and over, and prints how long it took each time and how many MiB of its
+/// `bytes` of input that makes a second.
+fn bench(
+    name: &str,
+    bytes: usize,
+    mut work: impl FnMut() -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let warm_up = Instant::now();
+    while warm_up.elapsed() < MEASURE {
+        work()?;
+    }
+    let start = Instant::now();
+    let mut iterations = 0u32;
+    while start.elapsed() < MEASURE {
+        work()?;
+        iterations += 1;
+    }
+    let each = start.elapsed() / iterations;
+    println!(
+        "{:<24} {:>12.1?}/iter {:>8.0} MiB/s",
+        name,
+        each,
+        bytes as f64 / (1024.0 * 1024.0) / each.as_secs_f64()
+    );
+    Ok(())
+}
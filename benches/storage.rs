@@ -0,0 +1,73 @@
+//! Compares the storage backends on a large-piece workload: writing whole pieces, as the
+//! download does, then reading them back block by block, as seeding does, and whole, as
+//! verifying does. Run with `cargo bench --bench storage`.
+
+use bittorent_client::create::{create, CreateOptions};
+use bittorent_client::storage::{Allocation, FileStorage, StorageBackend};
+use std::time::{Duration, Instant};
+
+const LENGTH: usize = 256 * 1024 * 1024;
+const PIECE_LENGTH: usize = 4 * 1024 * 1024;
+const BLOCK_LENGTH: usize = 16 * 1024;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let source = dir.path().join("source.bin");
+    std::fs::File::create(&source)?.set_len(LENGTH as u64)?;
+    let options = CreateOptions {
+        piece_length: Some(PIECE_LENGTH),
+        ..CreateOptions::default()
+    };
+    let torrent = create(&source, &options)?;
+    let piece: Vec<u8> = (0..PIECE_LENGTH).map(|i| (i * 7 % 251) as u8).collect();
+
+    for backend in [StorageBackend::Buffered, StorageBackend::Mmap] {
+        let path = dir.path().join(format!("{:?}.bin", backend));
+        let mut storage = FileStorage::create(&torrent, &path, Allocation::Full)
+            .await?
+            .with_backend(backend)
+            .await?;
+        let write = time(async {
+            for offset in (0..LENGTH).step_by(PIECE_LENGTH) {
+                storage.write_at(offset, &piece).await?;
+            }
+            Ok(storage.flush().await?)
+        })
+        .await?;
+        let read_blocks = time(async {
+            for offset in (0..LENGTH).step_by(BLOCK_LENGTH) {
+                storage.read_at(offset, BLOCK_LENGTH).await?;
+            }
+            anyhow::Ok(())
+        })
+        .await?;
+        let read_pieces = time(async {
+            for offset in (0..LENGTH).step_by(PIECE_LENGTH) {
+                storage.read_at(offset, PIECE_LENGTH).await?;
+            }
+            anyhow::Ok(())
+        })
+        .await?;
+        println!(
+            "{:<8}  write pieces {:>6.0} MiB/s  read blocks {:>6.0} MiB/s  read pieces {:>6.0} MiB/s",
+            format!("{:?}", backend),
+            throughput(write),
+            throughput(read_blocks),
+            throughput(read_pieces)
+        );
+    }
+    Ok(())
+}
+
+async fn time(
+    work: impl std::future::Future<Output = anyhow::Result<()>>,
+) -> anyhow::Result<Duration> {
+    let start = Instant::now();
+    work.await?;
+    Ok(start.elapsed())
+}
+
+fn throughput(elapsed: Duration) -> f64 {
+    LENGTH as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+}
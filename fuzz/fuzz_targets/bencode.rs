@@ -0,0 +1,42 @@
+//! Arbitrary bytes, as a tracker or DHT node could send them, into every way bencode is
+//! decoded. Whatever decodes strictly has to encode back to the same bytes.
+
+#![no_main]
+
+use bittorent_client::bencode::{self, DecodeOptions, StreamDecoder};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(value) = bencode::decode_borrowed(data) {
+        let _ = value.to_json_with(true);
+    }
+    let _ = bencode::from_bytes::<serde_json::Value>(data);
+    let _ = bencode::bencoded_schema(data);
+    let _ = bencode::dict_value_span(data, b"info");
+
+    let strict = DecodeOptions {
+        strict: true,
+        ..DecodeOptions::default()
+    };
+    let decoded = bencode::decode_with(data, strict);
+    if let Ok(value) = &decoded {
+        assert_eq!(value.to_bytes(), data);
+    }
+
+    // the same input a few bytes at a time has to decode the same
+    let mut decoder = StreamDecoder::new(strict);
+    let mut fed = Ok(0);
+    for chunk in data.chunks(7) {
+        fed = decoder.feed(chunk);
+        if fed.is_err() || decoder.is_done() {
+            break;
+        }
+    }
+    if fed.is_ok() && decoder.is_done() {
+        let streamed = decoder.finish().unwrap();
+        // trailing bytes only fail the whole-input decode
+        if let Ok(value) = decoded {
+            assert_eq!(streamed, value);
+        }
+    }
+});
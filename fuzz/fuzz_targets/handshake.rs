@@ -0,0 +1,17 @@
+//! The opening handshake of a peer that connected to us, or that we connected to.
+
+#![no_main]
+
+use bittorent_client::peer::Handshake;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some(bytes) = data.first_chunk::<{ Handshake::LENGTH }>() else {
+        return;
+    };
+    if let Ok(handshake) = Handshake::from_bytes(bytes) {
+        assert_eq!(&handshake.to_bytes(), bytes);
+        let _ = handshake.supports_extensions();
+        let _ = handshake.supports_fast();
+    }
+});
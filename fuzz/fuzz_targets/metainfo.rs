@@ -0,0 +1,22 @@
+//! Torrent metadata, as a peer sends it for a magnet link, and what is worked out from it.
+
+#![no_main]
+
+use bittorent_client::torrent::Torrent;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(torrent) = Torrent::from_bytes(data) else {
+        return;
+    };
+    let _ = torrent.info_hashes();
+    let _ = torrent.total_length();
+    let _ = torrent.files();
+    let _ = torrent.to_magnet();
+    let _ = torrent.to_bytes();
+    let pieces = torrent.piece_count();
+    for index in [0, pieces / 2, pieces.saturating_sub(1), pieces] {
+        let _ = torrent.piece_size(index);
+        let _ = torrent.verify_piece(index, &[0; 16]);
+    }
+});
@@ -0,0 +1,24 @@
+//! A peer's byte stream after the handshake, framed into messages as a connection does.
+//! Every message that parses has to encode back to the frame it came from.
+
+#![no_main]
+
+use bittorent_client::message::{MessageCodec, PeerMessage};
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    let mut stream = BytesMut::from(data);
+    loop {
+        let before = stream.clone();
+        match MessageCodec.decode(&mut stream) {
+            Ok(Some(message)) => {
+                let frame = message.to_bytes();
+                assert_eq!(&before[..frame.len()], &frame[..]);
+                assert_eq!(PeerMessage::from_payload(&frame[4..]).unwrap(), message);
+            }
+            Ok(None) | Err(_) => break,
+        }
+    }
+});
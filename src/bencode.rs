@@ -1,41 +1,1364 @@
-use anyhow;
-use serde_bencode;
+use serde::de::{self, DeserializeSeed, Visitor};
+use serde::{ser, Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::ops::Range;
+use tokio::io::{AsyncBufReadExt, AsyncRead};
 
+/// How deep lists and dictionaries may be nested in each other. Real values are a few levels
+/// deep; without a limit, a hostile peer or tracker could send one deep enough for the
+/// parsing, or dropping the value, to run out of stack.
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DecodeOptions {
+    /// Accept integers such as `i01e` or `i-0e` that the spec forbids but buggy encoders produce.
+    pub allow_leading_zeros: bool,
+    /// Only accept the canonical encoding: dictionary keys sorted and unique, and nothing after
+    /// the root value. Leading zeros are never accepted then.
+    pub strict: bool,
+}
+
+/// A decoded bencode value whose byte strings borrow from the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeRef<'a> {
+    Bytes(&'a [u8]),
+    Int(i64),
+    List(Vec<BencodeRef<'a>>),
+    Dict(BTreeMap<&'a [u8], BencodeRef<'a>>),
+}
+
+/// A decoded bencode value that owns its byte strings, which are kept as raw bytes: piece
+/// hashes and compact peer lists aren't text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bencode {
+    Bytes(Vec<u8>),
+    Int(i64),
+    List(Vec<Bencode>),
+    Dict(BTreeMap<Vec<u8>, Bencode>),
+}
+
+impl Bencode {
+    /// Encodes the value back into bencode, byte for byte as it was decoded.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn encode(value: &Bencode, out: &mut Vec<u8>) {
+            match value {
+                Bencode::Bytes(bytes) => encode_bytes(bytes, out),
+                Bencode::Int(i) => encode_int(*i, out),
+                Bencode::List(list) => {
+                    out.push(b'l');
+                    for item in list {
+                        encode(item, out);
+                    }
+                    out.push(b'e');
+                }
+                Bencode::Dict(dict) => {
+                    out.push(b'd');
+                    for (key, value) in dict {
+                        encode_bytes(key, out);
+                        encode(value, out);
+                    }
+                    out.push(b'e');
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        encode(self, &mut out);
+        out
+    }
+
+    /// The value as JSON, for display: byte strings that aren't UTF-8 are shown hex-encoded,
+    /// and dictionary keys that aren't are converted lossily.
+    pub fn to_json(&self) -> serde_json::Value {
+        self.to_json_with(false)
+    }
+
+    /// Like `to_json`, but with `hex_bytes` byte strings and keys that aren't UTF-8 are shown
+    /// as their length and hex, e.g. `<2 bytes> fffe`, so they can't be taken for text.
+    pub fn to_json_with(&self, hex_bytes: bool) -> serde_json::Value {
+        match self {
+            Bencode::Bytes(b) => serde_json::Value::String(json_text(b, false, hex_bytes)),
+            Bencode::Int(i) => serde_json::Value::Number(serde_json::Number::from(*i)),
+            Bencode::List(l) => serde_json::Value::Array(
+                l.iter()
+                    .map(|value| value.to_json_with(hex_bytes))
+                    .collect(),
+            ),
+            Bencode::Dict(d) => serde_json::Value::Object(
+                d.iter()
+                    .map(|(key, value)| {
+                        (
+                            json_text(key, true, hex_bytes),
+                            value.to_json_with(hex_bytes),
+                        )
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The value at `path` inside this one: dictionary keys and list indices separated by
+    /// dots, e.g. `info.files.0.length`. A backslash makes the character after it part of the
+    /// key, for keys with dots in them.
+    pub fn get_path(&self, path: &str) -> crate::Result<&Bencode> {
+        let mut value = self;
+        for segment in path_segments(path) {
+            value = match value {
+                Bencode::Dict(dict) => dict
+                    .get(segment.as_bytes())
+                    .ok_or_else(|| anyhow::anyhow!("no key `{}`", segment)),
+                Bencode::List(list) => segment
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| list.get(index))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("no index `{}` in a list of {}", segment, list.len())
+                    }),
+                _ => Err(anyhow::anyhow!(
+                    "can't look up `{}` in a string or integer",
+                    segment
+                )),
+            }
+            .map_err(crate::Error::Bencode)?;
+        }
+        Ok(value)
+    }
+}
+
+impl BencodeRef<'_> {
+    /// Like `Bencode::to_json`, without copying the byte strings out of the input first.
+    pub fn to_json(&self) -> serde_json::Value {
+        self.to_json_with(false)
+    }
+
+    /// Like `Bencode::to_json_with`, without copying the byte strings out of the input first.
+    pub fn to_json_with(&self, hex_bytes: bool) -> serde_json::Value {
+        match self {
+            BencodeRef::Bytes(b) => serde_json::Value::String(json_text(b, false, hex_bytes)),
+            BencodeRef::Int(i) => serde_json::Value::Number(serde_json::Number::from(*i)),
+            BencodeRef::List(l) => serde_json::Value::Array(
+                l.iter()
+                    .map(|value| value.to_json_with(hex_bytes))
+                    .collect(),
+            ),
+            BencodeRef::Dict(d) => serde_json::Value::Object(
+                d.iter()
+                    .map(|(key, value)| {
+                        (
+                            json_text(key, true, hex_bytes),
+                            value.to_json_with(hex_bytes),
+                        )
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// A byte string of a value as JSON text: itself if it is UTF-8, otherwise as `to_json_with`
+/// says, `lossy` for dictionary keys.
+fn json_text(b: &[u8], lossy: bool, hex_bytes: bool) -> String {
+    match std::str::from_utf8(b) {
+        Ok(string) => string.to_owned(),
+        Err(_) if hex_bytes => format!("<{} bytes> {}", b.len(), hex::encode(b)),
+        Err(_) if lossy => String::from_utf8_lossy(b).into_owned(),
+        Err(_) => hex::encode(b),
+    }
+}
+
+/// The keys of a `get_path` path; an empty path is the value itself.
+fn path_segments(path: &str) -> Vec<String> {
+    if path.is_empty() {
+        return Vec::new();
+    }
+    let mut segments = vec![String::new()];
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => segments.last_mut().unwrap().extend(chars.next()),
+            '.' => segments.push(String::new()),
+            c => segments.last_mut().unwrap().push(c),
+        }
+    }
+    segments
+}
+
+impl From<BencodeRef<'_>> for Bencode {
+    fn from(value: BencodeRef<'_>) -> Bencode {
+        match value {
+            BencodeRef::Bytes(b) => Bencode::Bytes(b.to_vec()),
+            BencodeRef::Int(i) => Bencode::Int(i),
+            BencodeRef::List(l) => Bencode::List(l.into_iter().map(Bencode::from).collect()),
+            BencodeRef::Dict(d) => Bencode::Dict(
+                d.into_iter()
+                    .map(|(key, value)| (key.to_vec(), Bencode::from(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Decodes the root value of `input` into an owned value. Trailing bytes after the root value
+/// are ignored.
+pub fn decode(input: &[u8]) -> crate::Result<Bencode> {
+    decode_with(input, DecodeOptions::default())
+}
+
+pub fn decode_with(input: &[u8], options: DecodeOptions) -> crate::Result<Bencode> {
+    decode_borrowed_with(input, options).map(Bencode::from)
+}
+
+/// Decodes the root value of `input` without copying byte strings.
+/// Trailing bytes after the root value are ignored.
+pub fn decode_borrowed(input: &[u8]) -> crate::Result<BencodeRef<'_>> {
+    decode_borrowed_with(input, DecodeOptions::default())
+}
+
+pub fn decode_borrowed_with(input: &[u8], options: DecodeOptions) -> crate::Result<BencodeRef<'_>> {
+    let mut parser = Parser {
+        input,
+        pos: 0,
+        options,
+        depth: 0,
+    };
+    let value = parser.parse_value().map_err(crate::Error::Bencode)?;
+    if options.strict && parser.pos < input.len() {
+        return Err(crate::Error::Bencode(anyhow::anyhow!(
+            "{} bytes of trailing data after the value",
+            input.len() - parser.pos
+        )));
+    }
+    Ok(value)
+}
+
+/// Decodes the root value of `input` and also returns the bytes that follow it, for messages
+/// that carry raw data after a bencoded header.
+pub fn decode_borrowed_prefix(input: &[u8]) -> crate::Result<(BencodeRef<'_>, &[u8])> {
+    let mut parser = Parser {
+        input,
+        pos: 0,
+        options: DecodeOptions::default(),
+        depth: 0,
+    };
+    let value = parser.parse_value().map_err(crate::Error::Bencode)?;
+    Ok((value, &input[parser.pos..]))
+}
+
+/// Where the value under `key` in the root dictionary of `input` is encoded: `input[span]` are
+/// its exact bytes, e.g. those of a torrent's info dictionary, which re-encoding a decoded copy
+/// could change. `None` if the dictionary has no such key.
+pub fn dict_value_span(input: &[u8], key: &[u8]) -> crate::Result<Option<Range<usize>>> {
+    find_dict_value(input, key).map_err(crate::Error::Bencode)
+}
+
+fn find_dict_value(input: &[u8], key: &[u8]) -> anyhow::Result<Option<Range<usize>>> {
+    if input.first() != Some(&b'd') {
+        anyhow::bail!("expected a dictionary");
+    }
+    // the bytes are taken as they are, so only their structure matters
+    let mut parser = Parser {
+        input,
+        pos: 1,
+        options: DecodeOptions {
+            allow_leading_zeros: true,
+            strict: false,
+        },
+        depth: 1,
+    };
+    while !parser.consume_end("dictionary")? {
+        if !parser.input[parser.pos].is_ascii_digit() {
+            anyhow::bail!("dictionary key at byte {} is not a string", parser.pos);
+        }
+        let found = parser.parse_bytes()? == key;
+        let start = parser.pos;
+        parser.parse_value()?;
+        if found {
+            return Ok(Some(start..parser.pos));
+        }
+    }
+    Ok(None)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    options: DecodeOptions,
+    /// How many lists and dictionaries the value being parsed is inside of.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn parse_value(&mut self) -> anyhow::Result<BencodeRef<'a>> {
+        match self.input.get(self.pos) {
+            Some(b'i') => Ok(BencodeRef::Int(self.parse_int()?)),
+            Some(b'0'..=b'9') => Ok(BencodeRef::Bytes(self.parse_bytes()?)),
+            Some(b'l' | b'd') => {
+                if self.depth == MAX_DEPTH {
+                    anyhow::bail!(
+                        "values nested more than {} deep at byte {}",
+                        MAX_DEPTH,
+                        self.pos
+                    );
+                }
+                self.depth += 1;
+                let value = self.parse_container();
+                self.depth -= 1;
+                value
+            }
+            Some(&c) => anyhow::bail!("invalid character `{}` at byte {}", c as char, self.pos),
+            None => anyhow::bail!("unexpected end of input"),
+        }
+    }
+
+    /// Parses the list or dictionary that starts at the current byte.
+    fn parse_container(&mut self) -> anyhow::Result<BencodeRef<'a>> {
+        match self.input[self.pos] {
+            b'l' => {
+                self.pos += 1;
+                let mut list = Vec::new();
+                while !self.consume_end("list")? {
+                    list.push(self.parse_value()?);
+                }
+                Ok(BencodeRef::List(list))
+            }
+            _ => {
+                self.pos += 1;
+                let mut dict: BTreeMap<&[u8], BencodeRef> = BTreeMap::new();
+                while !self.consume_end("dictionary")? {
+                    if !self.input[self.pos].is_ascii_digit() {
+                        anyhow::bail!("dictionary key at byte {} is not a string", self.pos);
+                    }
+                    let at = self.pos;
+                    let key = self.parse_bytes()?;
+                    if self.options.strict {
+                        match dict.last_key_value() {
+                            Some((&last, _)) if last == key => {
+                                anyhow::bail!("duplicate dictionary key at byte {}", at)
+                            }
+                            Some((&last, _)) if last > key => {
+                                anyhow::bail!("unsorted dictionary key at byte {}", at)
+                            }
+                            _ => {}
+                        }
+                    }
+                    let value = self.parse_value()?;
+                    dict.insert(key, value);
+                }
+                Ok(BencodeRef::Dict(dict))
+            }
+        }
+    }
+
+    /// Returns true (and skips it) if the next byte closes the current list or dictionary.
+    fn consume_end(&mut self, what: &str) -> anyhow::Result<bool> {
+        match self.input.get(self.pos) {
+            Some(b'e') => {
+                self.pos += 1;
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => anyhow::bail!("unterminated {}", what),
+        }
+    }
+
+    fn parse_int(&mut self) -> anyhow::Result<i64> {
+        let start = self.pos + 1;
+        let end = start
+            + self.input[start..]
+                .iter()
+                .position(|&b| b == b'e')
+                .ok_or_else(|| anyhow::anyhow!("unterminated integer at byte {}", self.pos))?;
+        let value = int_value(&self.input[start..end], self.options)?;
+        self.pos = end + 1;
+        Ok(value)
+    }
+
+    fn parse_bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let colon = self.pos
+            + self.input[self.pos..]
+                .iter()
+                .position(|&b| b == b':')
+                .ok_or_else(|| anyhow::anyhow!("missing `:` after string length"))?;
+        let digits = &self.input[self.pos..colon];
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            anyhow::bail!("invalid string length: {}", String::from_utf8_lossy(digits));
+        }
+        if self.options.strict && digits.len() > 1 && digits[0] == b'0' {
+            anyhow::bail!(
+                "string length with leading zero: {}",
+                String::from_utf8_lossy(digits)
+            );
+        }
+        // every byte string has one, so it is read in place rather than through a `str`
+        let length = digits
+            .iter()
+            .try_fold(0usize, |length, &digit| {
+                length.checked_mul(10)?.checked_add((digit - b'0') as usize)
+            })
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "string length {} is too large",
+                    String::from_utf8_lossy(digits)
+                )
+            })?;
+        let start = colon + 1;
+        let Some(bytes) = start
+            .checked_add(length)
+            .and_then(|end| self.input.get(start..end))
+        else {
+            anyhow::bail!("string of length {} runs past the end of input", length);
+        };
+        self.pos = start + length;
+        Ok(bytes)
+    }
+}
+
+/// The integer spelled by `digits`, the part of an `i...e` between those two.
+fn int_value(digits: &[u8], options: DecodeOptions) -> anyhow::Result<i64> {
+    let magnitude = digits.strip_prefix(b"-").unwrap_or(digits);
+    if magnitude.is_empty() || !magnitude.iter().all(u8::is_ascii_digit) {
+        anyhow::bail!("invalid integer: i{}e", String::from_utf8_lossy(digits));
+    }
+    if (options.strict || !options.allow_leading_zeros)
+        && magnitude[0] == b'0'
+        && (magnitude.len() > 1 || digits.len() > 1)
+    {
+        anyhow::bail!(
+            "integer with leading zero: i{}e",
+            String::from_utf8_lossy(digits)
+        );
+    }
+    Ok(std::str::from_utf8(digits)?.parse()?)
+}
+
+/// Decodes a value from input that arrives a chunk at a time, e.g. from a socket or a large
+/// file, so the input never has to be in memory whole. Byte strings are only allocated as
+/// their bytes arrive, so a bogus length costs nothing.
+#[derive(Debug, Default)]
+pub struct StreamDecoder {
+    options: DecodeOptions,
+    /// The lists and dictionaries that are open, innermost last.
+    open: Vec<Container>,
+    token: Token,
+    value: Option<Bencode>,
+    /// How many bytes were taken in so far.
+    pos: usize,
+}
+
+#[derive(Debug)]
+enum Container {
+    List(Vec<Bencode>),
+    /// A dictionary, with the key of the value that is read next once there is one.
+    Dict(BTreeMap<Vec<u8>, Bencode>, Option<Vec<u8>>),
+}
+
+/// The integer or byte string being read.
+#[derive(Debug, Default)]
+enum Token {
+    #[default]
+    None,
+    Int(Vec<u8>),
+    Length(Vec<u8>),
+    Bytes {
+        bytes: Vec<u8>,
+        remaining: usize,
+    },
+}
+
+/// More digits than any `i64` and any string length that fits in memory have.
+const MAX_DIGITS: usize = 20;
+
+impl StreamDecoder {
+    pub fn new(options: DecodeOptions) -> StreamDecoder {
+        StreamDecoder {
+            options,
+            ..StreamDecoder::default()
+        }
+    }
+
+    /// Takes in as much of `input` as belongs to the value and returns how many bytes that
+    /// was, which is fewer than all only once the value is complete.
+    pub fn feed(&mut self, input: &[u8]) -> crate::Result<usize> {
+        self.take(input).map_err(crate::Error::Bencode)
+    }
+
+    fn take(&mut self, input: &[u8]) -> anyhow::Result<usize> {
+        let mut taken = 0;
+        while taken < input.len() && self.value.is_none() {
+            // the bytes of a string are copied over in one go
+            if let Token::Bytes { bytes, remaining } = &mut self.token {
+                let count = (*remaining).min(input.len() - taken);
+                bytes.extend_from_slice(&input[taken..taken + count]);
+                *remaining -= count;
+                taken += count;
+                self.pos += count;
+                if *remaining == 0 {
+                    let Token::Bytes { bytes, .. } = std::mem::take(&mut self.token) else {
+                        unreachable!("the token is a string");
+                    };
+                    self.complete(Bencode::Bytes(bytes))?;
+                }
+                continue;
+            }
+            self.step(input[taken])?;
+            taken += 1;
+            self.pos += 1;
+        }
+        Ok(taken)
+    }
+
+    /// Whether the value is complete, so no more input is taken.
+    pub fn is_done(&self) -> bool {
+        self.value.is_some()
+    }
+
+    /// The decoded value, once the input ended.
+    pub fn finish(self) -> crate::Result<Bencode> {
+        let missing = match (self.value, self.open.last()) {
+            (Some(value), _) => return Ok(value),
+            (None, Some(Container::List(_))) => "unterminated list",
+            (None, Some(Container::Dict(..))) => "unterminated dictionary",
+            (None, None) => "unexpected end of input",
+        };
+        Err(crate::Error::Bencode(anyhow::anyhow!(missing)))
+    }
+
+    fn step(&mut self, byte: u8) -> anyhow::Result<()> {
+        match &mut self.token {
+            Token::None => self.start(byte),
+            Token::Int(digits) if byte == b'e' => {
+                let value = int_value(digits, self.options)?;
+                self.token = Token::None;
+                self.complete(Bencode::Int(value))
+            }
+            Token::Int(digits) if digits.len() < MAX_DIGITS => {
+                digits.push(byte);
+                Ok(())
+            }
+            Token::Int(_) => anyhow::bail!("integer at byte {} is too long", self.pos),
+            Token::Length(digits) if byte == b':' => {
+                if self.options.strict && digits.len() > 1 && digits[0] == b'0' {
+                    anyhow::bail!("string length with leading zero at byte {}", self.pos);
+                }
+                let length = std::str::from_utf8(digits)?.parse()?;
+                self.token = Token::None;
+                if length == 0 {
+                    return self.complete(Bencode::Bytes(Vec::new()));
+                }
+                self.token = Token::Bytes {
+                    bytes: Vec::new(),
+                    remaining: length,
+                };
+                Ok(())
+            }
+            Token::Length(digits) if byte.is_ascii_digit() && digits.len() < MAX_DIGITS => {
+                digits.push(byte);
+                Ok(())
+            }
+            Token::Length(_) => anyhow::bail!("invalid string length at byte {}", self.pos),
+            Token::Bytes { .. } => unreachable!("strings are taken in by `feed`"),
+        }
+    }
+
+    /// Starts the value or closes the container that `byte` opens or closes.
+    fn start(&mut self, byte: u8) -> anyhow::Result<()> {
+        let wants_key = matches!(self.open.last(), Some(Container::Dict(_, None)));
+        match byte {
+            b'0'..=b'9' => self.token = Token::Length(vec![byte]),
+            b'e' if matches!(self.open.last(), Some(Container::Dict(_, Some(_)))) => {
+                anyhow::bail!("dictionary key without a value at byte {}", self.pos)
+            }
+            b'e' if !self.open.is_empty() => {
+                let value = match self.open.pop() {
+                    Some(Container::List(list)) => Bencode::List(list),
+                    Some(Container::Dict(dict, _)) => Bencode::Dict(dict),
+                    None => unreachable!("a container is open"),
+                };
+                return self.complete(value);
+            }
+            _ if wants_key => {
+                anyhow::bail!("dictionary key at byte {} is not a string", self.pos)
+            }
+            b'i' => self.token = Token::Int(Vec::new()),
+            b'l' | b'd' if self.open.len() == MAX_DEPTH => {
+                anyhow::bail!(
+                    "values nested more than {} deep at byte {}",
+                    MAX_DEPTH,
+                    self.pos
+                )
+            }
+            b'l' => self.open.push(Container::List(Vec::new())),
+            b'd' => self.open.push(Container::Dict(BTreeMap::new(), None)),
+            _ => anyhow::bail!("invalid character `{}` at byte {}", byte as char, self.pos),
+        }
+        Ok(())
+    }
+
+    /// Puts a value that was read completely where it belongs.
+    fn complete(&mut self, value: Bencode) -> anyhow::Result<()> {
+        match self.open.last_mut() {
+            None => self.value = Some(value),
+            Some(Container::List(list)) => list.push(value),
+            Some(Container::Dict(dict, key @ None)) => {
+                let Bencode::Bytes(bytes) = value else {
+                    unreachable!("only strings start where a key is wanted");
+                };
+                if self.options.strict {
+                    match dict.last_key_value() {
+                        Some((last, _)) if *last == bytes => {
+                            anyhow::bail!("duplicate dictionary key before byte {}", self.pos)
+                        }
+                        Some((last, _)) if *last > bytes => {
+                            anyhow::bail!("unsorted dictionary key before byte {}", self.pos)
+                        }
+                        _ => {}
+                    }
+                }
+                *key = Some(bytes);
+            }
+            Some(Container::Dict(dict, key)) => {
+                dict.insert(key.take().expect("the key was read"), value);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the first value in `reader`, reading no further than it goes unless `options` are
+/// strict, which makes sure nothing follows it. The input is never held in memory whole.
+pub fn decode_reader(reader: impl Read, options: DecodeOptions) -> crate::Result<Bencode> {
+    read_value(reader, options).map_err(crate::Error::from)
+}
+
+fn read_value(reader: impl Read, options: DecodeOptions) -> anyhow::Result<Bencode> {
+    let mut reader = BufReader::new(reader);
+    let mut decoder = StreamDecoder::new(options);
+    while !decoder.is_done() {
+        let chunk = reader.fill_buf()?;
+        if chunk.is_empty() {
+            break;
+        }
+        let taken = decoder.feed(chunk)?;
+        reader.consume(taken);
+    }
+    if options.strict && decoder.is_done() && !reader.fill_buf()?.is_empty() {
+        let trailing = anyhow::anyhow!("trailing data after the value");
+        return Err(crate::Error::Bencode(trailing).into());
+    }
+    Ok(decoder.finish()?)
+}
+
+/// Like `decode_reader`, for an asynchronous `reader`.
+pub async fn decode_async_reader(
+    reader: impl AsyncRead + Unpin,
+    options: DecodeOptions,
+) -> crate::Result<Bencode> {
+    read_value_async(reader, options)
+        .await
+        .map_err(crate::Error::from)
+}
+
+async fn read_value_async(
+    reader: impl AsyncRead + Unpin,
+    options: DecodeOptions,
+) -> anyhow::Result<Bencode> {
+    let mut reader = tokio::io::BufReader::new(reader);
+    let mut decoder = StreamDecoder::new(options);
+    while !decoder.is_done() {
+        let chunk = reader.fill_buf().await?;
+        if chunk.is_empty() {
+            break;
+        }
+        let taken = decoder.feed(chunk)?;
+        reader.consume(taken);
+    }
+    if options.strict && decoder.is_done() && !reader.fill_buf().await?.is_empty() {
+        let trailing = anyhow::anyhow!("trailing data after the value");
+        return Err(crate::Error::Bencode(trailing).into());
+    }
+    Ok(decoder.finish()?)
+}
+
+/// An error from mapping a Rust value to bencode or back.
+#[derive(Debug)]
+pub struct Error(String);
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: std::fmt::Display>(message: T) -> Error {
+        Error(message.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: std::fmt::Display>(message: T) -> Error {
+        Error(message.to_string())
+    }
+}
+
+/// Deserializes a `T` from the root value of `input`, borrowing strings from it where `T` does.
+/// Trailing bytes after the root value are ignored.
+pub fn from_bytes<'de, T: Deserialize<'de>>(input: &'de [u8]) -> crate::Result<T> {
+    T::deserialize(Deserializer(decode_borrowed(input)?))
+        .map_err(|e| crate::Error::Bencode(e.into()))
+}
+
+/// Serializes `value` to bencode, with dictionary keys sorted. `None` leaves a struct field,
+/// map entry or list item out; bencode has no null.
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> crate::Result<Vec<u8>> {
+    match value.serialize(Serializer) {
+        Ok(Some(value)) => Ok(value.to_bytes()),
+        Ok(None) => Err(crate::Error::Bencode(anyhow::anyhow!(
+            "there is no value to encode"
+        ))),
+        Err(e) => Err(crate::Error::Bencode(e.into())),
+    }
+}
+
+/// Deserializes from a decoded value. Byte strings go to visitors as bytes, or as strings
+/// where a string is asked for and they are UTF-8.
+struct Deserializer<'de>(BencodeRef<'de>);
+
+impl<'de> de::Deserializer<'de> for Deserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            BencodeRef::Bytes(bytes) => visitor.visit_borrowed_bytes(bytes),
+            BencodeRef::Int(i) => visitor.visit_i64(i),
+            BencodeRef::List(list) => visitor.visit_seq(SeqAccess(list.into_iter())),
+            BencodeRef::Dict(dict) => visitor.visit_map(MapAccess {
+                entries: dict.into_iter(),
+                value: None,
+            }),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            BencodeRef::Int(i @ (0 | 1)) => visitor.visit_bool(i == 1),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            BencodeRef::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(string) => visitor.visit_borrowed_str(string),
+                Err(_) => visitor.visit_borrowed_bytes(bytes),
+            },
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    // a value that is there is never none, and missing fields are left to serde
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// A unit variant is its name, any other a dictionary from its name to its contents.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.0 {
+            BencodeRef::Bytes(_) => visitor.visit_enum(EnumAccess(self.0, None)),
+            BencodeRef::Dict(dict) if dict.len() == 1 => {
+                let (name, value) = dict.into_iter().next().expect("it has one entry");
+                visitor.visit_enum(EnumAccess(BencodeRef::Bytes(name), Some(value)))
+            }
+            _ => Err(de::Error::custom(
+                "expected an enum variant name or a dictionary with one entry",
+            )),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf unit unit_struct
+        seq tuple tuple_struct map struct ignored_any
+    }
+}
+
+struct SeqAccess<'de>(std::vec::IntoIter<BencodeRef<'de>>);
+
+impl<'de> de::SeqAccess<'de> for SeqAccess<'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        self.0
+            .next()
+            .map(|item| seed.deserialize(Deserializer(item)))
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+struct MapAccess<'de> {
+    entries: std::collections::btree_map::IntoIter<&'de [u8], BencodeRef<'de>>,
+    /// The value of the key that was just taken.
+    value: Option<BencodeRef<'de>>,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess<'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let Some((key, value)) = self.entries.next() else {
+            return Ok(None);
+        };
+        self.value = Some(value);
+        seed.deserialize(Deserializer(BencodeRef::Bytes(key)))
+            .map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = self.value.take().expect("a key was taken before its value");
+        seed.deserialize(Deserializer(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+/// A variant's name and, unless it is a unit variant, its contents.
+struct EnumAccess<'de>(BencodeRef<'de>, Option<BencodeRef<'de>>);
+
+impl<'de> de::EnumAccess<'de> for EnumAccess<'de> {
+    type Error = Error;
+    type Variant = VariantAccess<'de>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, VariantAccess<'de>), Error> {
+        let variant = seed.deserialize(Deserializer(self.0))?;
+        Ok((variant, VariantAccess(self.1)))
+    }
+}
+
+struct VariantAccess<'de>(Option<BencodeRef<'de>>);
+
+impl<'de> VariantAccess<'de> {
+    fn contents(self) -> Result<Deserializer<'de>, Error> {
+        self.0
+            .map(Deserializer)
+            .ok_or_else(|| de::Error::custom("expected the contents of the variant"))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for VariantAccess<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        match self.0 {
+            None => Ok(()),
+            Some(_) => Err(de::Error::custom("unit variant has contents")),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self.contents()?)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self.contents()?, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(self.contents()?, visitor)
+    }
+}
+
+/// Serializes into a `Bencode` value, or into `None` for a value that is left out.
+struct Serializer;
+
+impl Serializer {
+    fn int(i: impl TryInto<i64>) -> Result<Option<Bencode>, Error> {
+        let i = i
+            .try_into()
+            .map_err(|_| Error("integer is too large for bencode".to_owned()))?;
+        Ok(Some(Bencode::Int(i)))
+    }
+
+    fn unsupported(what: &str) -> Result<Option<Bencode>, Error> {
+        Err(Error(format!("{} can't be encoded as bencode", what)))
+    }
+
+    /// `value` as the contents of a variant: a dictionary from its name to them.
+    fn variant(variant: &str, value: Bencode) -> Bencode {
+        Bencode::Dict(BTreeMap::from([(variant.as_bytes().to_vec(), value)]))
+    }
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Option<Bencode>;
+    type Error = Error;
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = ListSerializer;
+    type SerializeTupleStruct = ListSerializer;
+    type SerializeTupleVariant = ListSerializer;
+    type SerializeMap = DictSerializer;
+    type SerializeStruct = DictSerializer;
+    type SerializeStructVariant = DictSerializer;
+
+    fn serialize_bool(self, v: bool) -> Result<Option<Bencode>, Error> {
+        Serializer::int(v as i64)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Option<Bencode>, Error> {
+        Serializer::int(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Option<Bencode>, Error> {
+        Serializer::int(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Option<Bencode>, Error> {
+        Serializer::int(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Option<Bencode>, Error> {
+        Serializer::int(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Option<Bencode>, Error> {
+        Serializer::int(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Option<Bencode>, Error> {
+        Serializer::int(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Option<Bencode>, Error> {
+        Serializer::int(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Option<Bencode>, Error> {
+        Serializer::int(v)
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Option<Bencode>, Error> {
+        Serializer::unsupported("a floating point number")
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Option<Bencode>, Error> {
+        Serializer::unsupported("a floating point number")
+    }
+
+    fn serialize_char(self, v: char) -> Result<Option<Bencode>, Error> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Option<Bencode>, Error> {
+        Ok(Some(Bencode::Bytes(v.as_bytes().to_vec())))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Option<Bencode>, Error> {
+        Ok(Some(Bencode::Bytes(v.to_vec())))
+    }
+
+    fn serialize_none(self) -> Result<Option<Bencode>, Error> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Option<Bencode>, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Option<Bencode>, Error> {
+        Serializer::unsupported("a unit value")
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Option<Bencode>, Error> {
+        Serializer::unsupported("a unit struct")
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Option<Bencode>, Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Option<Bencode>, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Option<Bencode>, Error> {
+        let value = value.serialize(Serializer)?;
+        Ok(value.map(|value| Serializer::variant(variant, value)))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<ListSerializer, Error> {
+        Ok(ListSerializer {
+            items: Vec::with_capacity(len.unwrap_or(0)),
+            variant: None,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ListSerializer, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer, Error> {
+        Ok(ListSerializer {
+            items: Vec::with_capacity(len),
+            variant: Some(variant),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<DictSerializer, Error> {
+        Ok(DictSerializer {
+            dict: BTreeMap::new(),
+            key: None,
+            variant: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<DictSerializer, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<DictSerializer, Error> {
+        Ok(DictSerializer {
+            dict: BTreeMap::new(),
+            key: None,
+            variant: Some(variant),
+        })
+    }
+}
+
+struct ListSerializer {
+    items: Vec<Bencode>,
+    /// The variant the list is the contents of, for a tuple variant.
+    variant: Option<&'static str>,
+}
+
+impl ListSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.items.extend(value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<Bencode>, Error> {
+        let list = Bencode::List(self.items);
+        Ok(Some(match self.variant {
+            Some(variant) => Serializer::variant(variant, list),
+            None => list,
+        }))
+    }
+}
+
+impl ser::SerializeSeq for ListSerializer {
+    type Ok = Option<Bencode>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Option<Bencode>, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer {
+    type Ok = Option<Bencode>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Option<Bencode>, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer {
+    type Ok = Option<Bencode>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Option<Bencode>, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for ListSerializer {
+    type Ok = Option<Bencode>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Option<Bencode>, Error> {
+        self.finish()
+    }
+}
+
+struct DictSerializer {
+    dict: BTreeMap<Vec<u8>, Bencode>,
+    /// The key of the value that is serialized next.
+    key: Option<Vec<u8>>,
+    /// The variant the dictionary is the contents of, for a struct variant.
+    variant: Option<&'static str>,
+}
+
+impl DictSerializer {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: Vec<u8>, value: &T) -> Result<(), Error> {
+        if let Some(value) = value.serialize(Serializer)? {
+            self.dict.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<Bencode>, Error> {
+        let dict = Bencode::Dict(self.dict);
+        Ok(Some(match self.variant {
+            Some(variant) => Serializer::variant(variant, dict),
+            None => dict,
+        }))
+    }
+}
+
+impl ser::SerializeMap for DictSerializer {
+    type Ok = Option<Bencode>;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        match key.serialize(Serializer)? {
+            Some(Bencode::Bytes(key)) => {
+                self.key = Some(key);
+                Ok(())
+            }
+            _ => Err(Error("dictionary keys have to be strings".to_owned())),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .expect("a key was serialized before its value");
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Option<Bencode>, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for DictSerializer {
+    type Ok = Option<Bencode>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Option<Bencode>, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for DictSerializer {
+    type Ok = Option<Bencode>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Option<Bencode>, Error> {
+        self.finish()
+    }
+}
+
+/// Decodes `encoded_value` for display as JSON; see `Bencode::to_json`.
 pub fn decode_bencoded_value(encoded_value: &str) -> anyhow::Result<serde_json::Value> {
-    fn convert(value: serde_bencode::value::Value) -> anyhow::Result<serde_json::Value> {
+    decode_bencoded_value_with(encoded_value, DecodeOptions::default())
+}
+
+pub fn decode_bencoded_value_with(
+    encoded_value: &str,
+    options: DecodeOptions,
+) -> anyhow::Result<serde_json::Value> {
+    Ok(decode_borrowed_with(encoded_value.as_bytes(), options)?.to_json())
+}
+
+/// Encodes a JSON value as bencode. Dictionary keys are emitted in sorted order as the
+/// spec requires; `null`, booleans and non-integer numbers have no bencode equivalent.
+pub fn encode_bencoded_value(value: &serde_json::Value) -> anyhow::Result<Vec<u8>> {
+    fn encode(value: &serde_json::Value, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        match value {
+            serde_json::Value::String(string) => encode_bytes(string.as_bytes(), out),
+            serde_json::Value::Number(number) => {
+                let Some(i) = number.as_i64() else {
+                    anyhow::bail!("cannot encode non-integer number {} as bencode", number);
+                };
+                encode_int(i, out);
+            }
+            serde_json::Value::Array(array) => {
+                out.push(b'l');
+                for item in array {
+                    encode(item, out)?;
+                }
+                out.push(b'e');
+            }
+            serde_json::Value::Object(object) => {
+                let mut entries: Vec<_> = object.iter().collect();
+                entries.sort_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+                out.push(b'd');
+                for (key, value) in entries {
+                    encode_bytes(key.as_bytes(), out);
+                    encode(value, out)?;
+                }
+                out.push(b'e');
+            }
+            serde_json::Value::Null | serde_json::Value::Bool(_) => {
+                anyhow::bail!("cannot encode {} as bencode", value)
+            }
+        }
+        Ok(())
+    }
+
+    let mut out = Vec::new();
+    encode(value, &mut out)?;
+    Ok(out)
+}
+
+// both write their digits straight into `out`, rather than into a `String` of their own
+fn encode_int(i: i64, out: &mut Vec<u8>) {
+    write!(out, "i{}e", i).unwrap();
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    write!(out, "{}:", bytes.len()).unwrap();
+    out.extend_from_slice(bytes);
+}
+
+/// Summarizes the shape of a bencoded value, e.g. `{announce: string, info: {pieces: bytes(20)}}`.
+pub fn bencoded_schema(encoded_value: &[u8]) -> anyhow::Result<String> {
+    fn schema(value: &BencodeRef) -> String {
         match value {
-            serde_bencode::value::Value::Bytes(b) => {
-                let string = String::from_utf8(b)?;
-                Ok(serde_json::Value::String(string))
-            }
-            serde_bencode::value::Value::Int(i) => {
-                Ok(serde_json::Value::Number(serde_json::Number::from(i)))
-            }
-            serde_bencode::value::Value::List(l) => {
-                let array = l
-                    .into_iter()
-                    .map(|item| convert(item))
-                    .collect::<anyhow::Result<Vec<serde_json::Value>>>()?;
-                Ok(serde_json::Value::Array(array))
-            }
-            serde_bencode::value::Value::Dict(d) => {
-                let mut dict = serde_json::Map::new();
-                for (key, value) in d {
-                    let decoded_key = convert(serde_bencode::value::Value::Bytes(key))?;
-                    let decoded_value = convert(value)?;
-                    if let Some(key_str) = decoded_key.as_str() {
-                        dict.insert(key_str.to_owned(), decoded_value);
-                    } else {
-                        anyhow::bail!( "Dictionary key is not a valid UTF-8 (byte)string");
+            BencodeRef::Bytes(b) => match std::str::from_utf8(b) {
+                Ok(_) => "string".to_owned(),
+                Err(_) => format!("bytes({})", b.len()),
+            },
+            BencodeRef::Int(_) => "integer".to_owned(),
+            BencodeRef::List(l) => {
+                // lists are usually homogeneous, so only list each distinct shape once
+                let mut shapes: Vec<String> = Vec::new();
+                for item in l {
+                    let shape = schema(item);
+                    if !shapes.contains(&shape) {
+                        shapes.push(shape);
                     }
                 }
-                Ok(serde_json::Value::Object(dict))
+                format!("[{}]", shapes.join(", "))
+            }
+            BencodeRef::Dict(d) => {
+                let fields: Vec<String> = d
+                    .iter()
+                    .map(|(key, value)| {
+                        format!("{}: {}", String::from_utf8_lossy(key), schema(value))
+                    })
+                    .collect();
+                format!("{{{}}}", fields.join(", "))
             }
         }
     }
 
-    let value: serde_bencode::value::Value = serde_bencode::from_str(encoded_value)?;
-    convert(value)
+    let options = DecodeOptions {
+        allow_leading_zeros: true,
+        ..DecodeOptions::default()
+    };
+    Ok(schema(&decode_borrowed_with(encoded_value, options)?))
 }
 
 #[cfg(test)]
@@ -53,6 +1376,12 @@ mod tests {
         assert_eq!(decode_bencoded_value("0:").unwrap(), json!(""));
     }
 
+    #[test]
+    fn decode_string_non_utf8_as_hex() {
+        // "é" is two bytes, the length only covers the first one
+        assert_eq!(decode_bencoded_value("1:\u{e9}").unwrap(), json!("c3"));
+    }
+
     #[test]
     #[should_panic]
     fn decode_string_non_numeric_length() {
@@ -77,6 +1406,34 @@ mod tests {
         decode_bencoded_value("6:hello").unwrap();
     }
 
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(decode(&nested(MAX_DEPTH)).is_ok());
+        // deep enough to run out of stack without the limit
+        let error = decode(&nested(1_000_000)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "values nested more than {} deep at byte {}",
+                MAX_DEPTH, MAX_DEPTH
+            )
+        );
+        let mut decoder = StreamDecoder::default();
+        assert_eq!(decoder.feed(&nested(MAX_DEPTH)).unwrap(), 2 * MAX_DEPTH);
+        let mut decoder = StreamDecoder::default();
+        assert!(decoder.feed(&nested(1_000_000)).is_err());
+    }
+
+    #[test]
+    fn decode_string_length_too_large() {
+        let error = decode(b"99999999999999999999999:x").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "string length 99999999999999999999999 is too large"
+        );
+    }
+
     #[test]
     fn decode_string_length_is_smaller() {
         assert_eq!(decode_bencoded_value("5:helloo").unwrap(), json!("hello"));
@@ -111,7 +1468,6 @@ mod tests {
     }
 
     // specs say this shouldn't be allowed
-    /*
     #[test]
     #[should_panic]
     fn test_decode_number_invalid_leading_zero() {
@@ -123,7 +1479,90 @@ mod tests {
     fn test_decode_number_invalid_negative_zero() {
         decode_bencoded_value("i-0e").unwrap();
     }
-    */
+
+    #[test]
+    #[should_panic]
+    fn decode_number_invalid_nested_leading_zero() {
+        decode_bencoded_value("d1:ali1ei02eee").unwrap();
+    }
+
+    #[test]
+    fn decode_number_valid_zero() {
+        assert_eq!(decode_bencoded_value("i0e").unwrap(), json!(0));
+    }
+
+    #[test]
+    fn decode_number_leading_zero_allowed() {
+        let options = DecodeOptions {
+            allow_leading_zeros: true,
+            ..DecodeOptions::default()
+        };
+        assert_eq!(
+            decode_bencoded_value_with("i01e", options).unwrap(),
+            json!(1)
+        );
+        assert_eq!(
+            decode_bencoded_value_with("i-0e", options).unwrap(),
+            json!(0)
+        );
+        assert_eq!(
+            decode_bencoded_value_with("li007ee", options).unwrap(),
+            json!([7])
+        );
+    }
+
+    #[test]
+    fn get_path() {
+        let value = decode(b"d4:infod4:name1:a5:filesld6:lengthi3eeee3:a.bi1ee").unwrap();
+        assert_eq!(value.get_path("").unwrap(), &value);
+        assert_eq!(
+            value.get_path("info.name").unwrap(),
+            &Bencode::Bytes(b"a".to_vec())
+        );
+        assert_eq!(
+            value.get_path("info.files.0.length").unwrap(),
+            &Bencode::Int(3)
+        );
+        assert_eq!(value.get_path("a\\.b").unwrap(), &Bencode::Int(1));
+        for missing in ["info.size", "info.files.1", "info.files.x", "info.name.x"] {
+            assert!(value.get_path(missing).is_err(), "{}", missing);
+        }
+    }
+
+    #[test]
+    fn decode_strict() {
+        let strict = DecodeOptions {
+            strict: true,
+            ..DecodeOptions::default()
+        };
+        assert_eq!(
+            decode_bencoded_value_with("d1:ai1e1:bli2eee", strict).unwrap(),
+            json!({"a": 1, "b": [2]})
+        );
+        for bad in [
+            "5:helloo",
+            "i1ei2e",
+            "d1:bi1e1:ai2ee",
+            "d1:ai1e1:ai2ee",
+            "ld1:bi1e1:ai2eee",
+            "i01e",
+            "i-0e",
+            "05:hello",
+        ] {
+            assert!(decode_bencoded_value_with(bad, strict).is_err(), "{}", bad);
+        }
+        // leading zeros aren't let through by the other option either
+        let both = DecodeOptions {
+            allow_leading_zeros: true,
+            strict: true,
+        };
+        assert!(decode_bencoded_value_with("i01e", both).is_err());
+        // otherwise the last of duplicate keys wins
+        assert_eq!(
+            decode_bencoded_value("d1:bi1e1:bi2ee").unwrap(),
+            json!({"b": 2})
+        );
+    }
 
     #[test]
     fn decode_list_valid_empty() {
@@ -215,4 +1654,308 @@ mod tests {
     fn decode_dictionary_invalid_missing_end() {
         decode_bencoded_value("d3:key5:value").unwrap();
     }
+
+    #[test]
+    fn schema_scalars() {
+        assert_eq!(bencoded_schema(b"5:hello").unwrap(), "string");
+        assert_eq!(bencoded_schema(b"i42e").unwrap(), "integer");
+        assert_eq!(bencoded_schema(b"2:\xff\xfe").unwrap(), "bytes(2)");
+    }
+
+    #[test]
+    fn schema_list() {
+        assert_eq!(bencoded_schema(b"le").unwrap(), "[]");
+        assert_eq!(bencoded_schema(b"li1ei2ee").unwrap(), "[integer]");
+        assert_eq!(
+            bencoded_schema(b"li1e1:ai2ee").unwrap(),
+            "[integer, string]"
+        );
+    }
+
+    #[test]
+    fn schema_torrent() {
+        let torrent = b"d8:announce23:http://tracker/announce4:infod6:lengthi3e4:name5:a.txt\
+12:piece lengthi16384e6:pieces20:\x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\
+\xff\xfe\xfd\xfc\xfb\xfa\xf9\xf8\xf7\xf6ee";
+        assert_eq!(
+            bencoded_schema(torrent).unwrap(),
+            "{announce: string, info: {length: integer, name: string, \
+piece length: integer, pieces: bytes(20)}}"
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn schema_invalid() {
+        bencoded_schema(b"d3:key").unwrap();
+    }
+
+    #[test]
+    fn decode_borrowed_bytes_point_into_input() {
+        let input = b"d6:pieces4:\x00\x01\xfe\xff3:zip3:fooe";
+        let BencodeRef::Dict(dict) = decode_borrowed(input).unwrap() else {
+            panic!("expected a dictionary");
+        };
+        let BencodeRef::Bytes(pieces) = dict[&b"pieces"[..]] else {
+            panic!("expected a byte string");
+        };
+        assert_eq!(pieces, b"\x00\x01\xfe\xff");
+        assert!(std::ptr::eq(pieces.as_ptr(), input[11..].as_ptr()));
+        assert_eq!(dict[&b"zip"[..]], BencodeRef::Bytes(b"foo"));
+    }
+
+    #[test]
+    fn decode_borrowed_nested() {
+        assert_eq!(
+            decode_borrowed(b"li-3eli0eee").unwrap(),
+            BencodeRef::List(vec![
+                BencodeRef::Int(-3),
+                BencodeRef::List(vec![BencodeRef::Int(0)])
+            ])
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn decode_borrowed_non_string_key() {
+        decode_borrowed(b"di1ei2ee").unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn decode_borrowed_huge_length() {
+        decode_borrowed(b"18446744073709551615:a").unwrap();
+    }
+
+    #[test]
+    fn encode_string() {
+        assert_eq!(encode_bencoded_value(&json!("hello")).unwrap(), b"5:hello");
+        assert_eq!(encode_bencoded_value(&json!("")).unwrap(), b"0:");
+    }
+
+    #[test]
+    fn encode_number() {
+        assert_eq!(encode_bencoded_value(&json!(-42)).unwrap(), b"i-42e");
+    }
+
+    #[test]
+    fn encode_dictionary_sorted_keys() {
+        assert_eq!(
+            encode_bencoded_value(&json!({"zoo": [1, "a"], "bar": {"x": 0}})).unwrap(),
+            b"d3:bard1:xi0ee3:zooli1e1:aee"
+        );
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let encoded = "d1:ad1:bli1ei2eee5:hello5:worlde";
+        let decoded = decode_bencoded_value(encoded).unwrap();
+        assert_eq!(encode_bencoded_value(&decoded).unwrap(), encoded.as_bytes());
+    }
+
+    #[test]
+    #[should_panic]
+    fn encode_float() {
+        encode_bencoded_value(&json!(1.5)).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn encode_null() {
+        encode_bencoded_value(&json!({"a": null})).unwrap();
+    }
+
+    #[test]
+    fn decode_keeps_raw_bytes() {
+        let input = b"d5:peers6:\x7f\x00\x00\x01\x1a\xe16:pieces2:\xff\xfe2:\xc3\x28i1ee";
+        let value = decode(input).unwrap();
+        assert_eq!(
+            value,
+            Bencode::Dict(BTreeMap::from([
+                (
+                    b"peers".to_vec(),
+                    Bencode::Bytes(b"\x7f\x00\x00\x01\x1a\xe1".to_vec())
+                ),
+                (b"pieces".to_vec(), Bencode::Bytes(vec![0xff, 0xfe])),
+                (vec![0xc3, 0x28], Bencode::Int(1)),
+            ]))
+        );
+        assert_eq!(value.to_bytes(), input);
+        // only the display is lossy
+        assert_eq!(
+            value.to_json(),
+            json!({"peers": "7f0000011ae1", "pieces": "fffe", "\u{fffd}(": 1})
+        );
+        assert_eq!(
+            value.to_json_with(true),
+            json!({
+                "peers": "<6 bytes> 7f0000011ae1",
+                "pieces": "<2 bytes> fffe",
+                "<2 bytes> c328": 1
+            })
+        );
+    }
+
+    #[test]
+    fn dict_value_spans() {
+        let input = b"d1:ai1e4:infod1:xi01ee1:zlee";
+        let span = dict_value_span(input, b"info").unwrap().unwrap();
+        assert_eq!(&input[span], b"d1:xi01ee");
+        assert_eq!(dict_value_span(input, b"z").unwrap(), Some(25..27));
+        assert_eq!(dict_value_span(input, b"missing").unwrap(), None);
+        assert!(dict_value_span(b"li1ee", b"info").is_err());
+        assert!(dict_value_span(b"d4:infod", b"info").is_err());
+    }
+
+    #[test]
+    fn stream_decoder_takes_input_in_any_chunks() {
+        let input = b"d4:infod6:lengthi42e6:pieces4:\x00\xff\x01\x02e4:listli-1e0:ee5:after";
+        let expected = decode(input).unwrap();
+        for chunk_size in [1, 2, 3, 7, input.len()] {
+            let mut decoder = StreamDecoder::default();
+            let mut taken = 0;
+            for chunk in input.chunks(chunk_size) {
+                taken += decoder.feed(chunk).unwrap();
+            }
+            // the trailing bytes are left alone
+            assert_eq!(taken, input.len() - 7, "{}", chunk_size);
+            assert_eq!(decoder.finish().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn stream_decoder_errors() {
+        for bad in [
+            &b"i12"[..],
+            b"l1:a",
+            b"d1:ai1e",
+            b"di1ei2ee",
+            b"d1:ae",
+            b"x",
+            b"1x:a",
+            b"i123456789012345678901e",
+            b"i01e",
+            b"5:abc",
+        ] {
+            let mut decoder = StreamDecoder::default();
+            let fed = decoder.feed(bad);
+            assert!(fed.is_err() || decoder.finish().is_err(), "{:?}", bad);
+        }
+        // a huge length only fails once the bytes don't come
+        let mut decoder = StreamDecoder::default();
+        assert_eq!(decoder.feed(b"99999999999:ab").unwrap(), 14);
+        assert!(decoder.finish().is_err());
+        let strict = DecodeOptions {
+            strict: true,
+            ..DecodeOptions::default()
+        };
+        let mut decoder = StreamDecoder::new(strict);
+        assert!(decoder.feed(b"d1:bi1e1:ai2ee").is_err());
+        let mut decoder = StreamDecoder::new(strict);
+        assert!(decoder.feed(b"05:hello").is_err());
+    }
+
+    #[test]
+    fn decode_from_readers() {
+        let input = b"d1:ali1ei2ee1:b3:\xff\xfe\xfde";
+        let expected = decode(input).unwrap();
+        let options = DecodeOptions::default();
+        assert_eq!(decode_reader(&input[..], options).unwrap(), expected);
+
+        let mut trailing = input.to_vec();
+        trailing.extend_from_slice(b"i1e");
+        assert_eq!(decode_reader(&trailing[..], options).unwrap(), expected);
+        let strict = DecodeOptions {
+            strict: true,
+            ..DecodeOptions::default()
+        };
+        assert!(decode_reader(&trailing[..], strict).is_err());
+        assert!(decode_reader(&input[..5], options).is_err());
+    }
+
+    #[tokio::test]
+    async fn decode_from_async_reader() {
+        let (mut writer, reader) = tokio::io::duplex(4);
+        let writing = tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            writer.write_all(b"d4:spam4:eggs3:numi7ee").await.unwrap();
+        });
+        let value = decode_async_reader(reader, DecodeOptions::default())
+            .await
+            .unwrap();
+        writing.await.unwrap();
+        assert_eq!(value, decode(b"d4:spam4:eggs3:numi7ee").unwrap());
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct Typed<'a> {
+        name: String,
+        #[serde(rename = "piece length")]
+        piece_length: usize,
+        #[serde(with = "serde_bytes")]
+        pieces: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        comment: Option<String>,
+        private: Option<bool>,
+        #[serde(borrow)]
+        tags: Vec<&'a str>,
+        kind: Kind,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    enum Kind {
+        Single,
+        Multi { files: u32 },
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let typed = Typed {
+            name: "a.txt".to_owned(),
+            piece_length: 16384,
+            pieces: vec![0xff, 0, 0xfe],
+            comment: None,
+            private: Some(true),
+            tags: vec!["x", "y"],
+            kind: Kind::Multi { files: 2 },
+        };
+        let bytes = to_bytes(&typed).unwrap();
+        assert_eq!(
+            bytes,
+            b"d4:kindd5:Multid5:filesi2eee4:name5:a.txt12:piece lengthi16384e\
+6:pieces3:\xff\x00\xfe7:privatei1e4:tagsl1:x1:yee"
+        );
+        let parsed: Typed = from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, typed);
+        // strings are borrowed from the input
+        assert!(std::ptr::eq(
+            parsed.tags[0].as_ptr(),
+            bytes[bytes.len() - 6..].as_ptr()
+        ));
+
+        let single: Typed =
+            from_bytes(b"d4:kind6:Single4:name1:b12:piece lengthi1e6:pieces0:4:tagslee").unwrap();
+        assert_eq!(single.kind, Kind::Single);
+        assert_eq!(single.private, None);
+    }
+
+    #[test]
+    fn serde_errors() {
+        assert!(from_bytes::<Typed>(b"d4:name1:be").is_err());
+        assert!(from_bytes::<String>(b"2:\xff\xfe").is_err());
+        assert!(from_bytes::<u8>(b"i256e").is_err());
+        assert!(to_bytes(&1.5).is_err());
+        assert!(to_bytes(&BTreeMap::from([(1, 2)])).is_err());
+        assert!(to_bytes(&None::<u8>).is_err());
+    }
+
+    #[test]
+    fn decode_prefix_returns_trailing_data() {
+        let (value, rest) = decode_borrowed_prefix(b"d1:ai1ee\x00\x01").unwrap();
+        assert_eq!(
+            value,
+            BencodeRef::Dict(BTreeMap::from([(&b"a"[..], BencodeRef::Int(1))]))
+        );
+        assert_eq!(rest, b"\x00\x01");
+    }
 }
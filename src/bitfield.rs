@@ -0,0 +1,351 @@
+use std::fmt;
+
+/// Which of a torrent's pieces someone has, a bit for each, kept the way the bitfield message
+/// sends them: the first piece in the high bit of the first byte, and the spare bits at the
+/// end clear.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// A bitfield of `len` pieces, none of them set.
+    pub fn new(len: usize) -> Bitfield {
+        Bitfield {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// A bitfield of `len` pieces, all of them set.
+    pub fn full(len: usize) -> Bitfield {
+        let mut bitfield = Bitfield {
+            bytes: vec![0xff; len.div_ceil(8)],
+            len,
+        };
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    /// The bitfield of `len` pieces that `bytes` from the wire hold. Bits past the last piece
+    /// are dropped and missing bytes count as pieces not set, so a peer's wrong length costs
+    /// nothing but the pieces it got wrong.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Bitfield {
+        let mut bytes = bytes.to_vec();
+        bytes.resize(len.div_ceil(8), 0);
+        let mut bitfield = Bitfield { bytes, len };
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    /// Like `from_bytes`, but for a peer's bitfield message, which has to be exactly as long
+    /// as `len` pieces take and have the spare bits clear; a peer sending anything else is
+    /// broken or up to something.
+    pub fn from_message(bytes: &[u8], len: usize) -> anyhow::Result<Bitfield> {
+        if bytes.len() != len.div_ceil(8) {
+            anyhow::bail!(
+                "peer sent a bitfield of {} bytes for {} pieces",
+                bytes.len(),
+                len
+            );
+        }
+        let bitfield = Bitfield::from_bytes(bytes, len);
+        if bitfield.bytes != bytes {
+            anyhow::bail!("peer sent a bitfield with spare bits set");
+        }
+        Ok(bitfield)
+    }
+
+    /// The bytes of the bitfield message.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the piece at `index` is set; pieces past the end never are.
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & mask(index) != 0
+    }
+
+    /// Sets the piece at `index`, which has to be within the bitfield.
+    pub fn set(&mut self, index: usize) {
+        assert!(
+            index < self.len,
+            "piece {} is past the end of a bitfield of {}",
+            index,
+            self.len
+        );
+        self.bytes[index / 8] |= mask(index);
+    }
+
+    pub fn unset(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] &= !mask(index);
+        }
+    }
+
+    /// Makes the bitfield `len` pieces long, dropping the pieces past it or adding pieces that
+    /// aren't set.
+    pub fn resize(&mut self, len: usize) {
+        self.bytes.resize(len.div_ceil(8), 0);
+        self.len = len;
+        self.clear_spare_bits();
+    }
+
+    /// How many pieces are set.
+    pub fn count(&self) -> usize {
+        count_ones(&self.bytes)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn is_clear(&self) -> bool {
+        self.bytes.iter().all(|&byte| byte == 0)
+    }
+
+    /// How many of the pieces before the one at `index` are set.
+    pub fn rank(&self, index: usize) -> usize {
+        let index = index.min(self.len);
+        let whole = count_ones(&self.bytes[..index / 8]);
+        let partial = match index % 8 {
+            0 => 0,
+            bits => (self.bytes[index / 8] & !(0xff >> bits)).count_ones() as usize,
+        };
+        whole + partial
+    }
+
+    /// The index of the piece set after `n` others, so that `rank(select(n)) == n`; `None` if
+    /// no more than `n` are set.
+    pub fn select(&self, mut n: usize) -> Option<usize> {
+        for (at, &byte) in self.bytes.iter().enumerate() {
+            let ones = byte.count_ones() as usize;
+            if n < ones {
+                return set_bits(byte).nth(n).map(|bit| at * 8 + bit);
+            }
+            n -= ones;
+        }
+        None
+    }
+
+    /// The indices of the pieces that are set, in order.
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.bytes
+            .iter()
+            .enumerate()
+            .filter(|(_, &byte)| byte != 0)
+            .flat_map(|(at, &byte)| set_bits(byte).map(move |bit| at * 8 + bit))
+    }
+
+    /// The indices of the pieces that aren't set, in order.
+    pub fn zeros(&self) -> impl Iterator<Item = usize> + '_ {
+        // of a nearly complete torrent, whole bytes at a time are skipped
+        self.bytes
+            .iter()
+            .enumerate()
+            .filter(|(_, &byte)| byte != 0xff)
+            .flat_map(|(at, &byte)| set_bits(!byte).map(move |bit| at * 8 + bit))
+            .take_while(|&index| index < self.len)
+    }
+
+    /// The pieces set both here and in `other`, which counts as not set past its end.
+    pub fn intersection(&self, other: &Bitfield) -> Bitfield {
+        self.combine(other, |ours, theirs| ours & theirs)
+    }
+
+    /// The pieces set here but not in `other`, e.g. those a peer has that we are missing.
+    pub fn difference(&self, other: &Bitfield) -> Bitfield {
+        self.combine(other, |ours, theirs| ours & !theirs)
+    }
+
+    /// Also sets every piece set in `other` that is within this bitfield.
+    pub fn union_with(&mut self, other: &Bitfield) {
+        for (ours, theirs) in self.bytes.iter_mut().zip(&other.bytes) {
+            *ours |= theirs;
+        }
+        self.clear_spare_bits();
+    }
+
+    fn combine(&self, other: &Bitfield, op: impl Fn(u8, u8) -> u8) -> Bitfield {
+        let theirs = other.bytes.iter().copied().chain(std::iter::repeat(0));
+        let bytes = self
+            .bytes
+            .iter()
+            .zip(theirs)
+            .map(|(&ours, theirs)| op(ours, theirs))
+            .collect();
+        let mut bitfield = Bitfield {
+            bytes,
+            len: self.len,
+        };
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    fn clear_spare_bits(&mut self) {
+        if let (Some(last), bits @ 1..) = (self.bytes.last_mut(), self.len % 8) {
+            *last &= !(0xff >> bits);
+        }
+    }
+}
+
+fn mask(index: usize) -> u8 {
+    0x80 >> (index % 8)
+}
+
+/// How many bits of `bytes` are set, counted eight bytes at a time.
+fn count_ones(bytes: &[u8]) -> usize {
+    let words = bytes.chunks_exact(8);
+    let rest: usize = words
+        .remainder()
+        .iter()
+        .map(|byte| byte.count_ones() as usize)
+        .sum();
+    let whole: usize = words
+        .map(|word| u64::from_ne_bytes(word.try_into().unwrap()).count_ones() as usize)
+        .sum();
+    whole + rest
+}
+
+/// The positions of the bits set in `byte`, from the high one down.
+fn set_bits(byte: u8) -> impl Iterator<Item = usize> {
+    (0..8).filter(move |bit| byte & (0x80 >> bit) != 0)
+}
+
+impl FromIterator<bool> for Bitfield {
+    fn from_iter<I: IntoIterator<Item = bool>>(iter: I) -> Bitfield {
+        let mut bitfield = Bitfield::default();
+        for (index, set) in iter.into_iter().enumerate() {
+            if index % 8 == 0 {
+                bitfield.bytes.push(0);
+            }
+            if set {
+                bitfield.bytes[index / 8] |= mask(index);
+            }
+            bitfield.len = index + 1;
+        }
+        bitfield
+    }
+}
+
+impl fmt::Debug for Bitfield {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bits: String = (0..self.len)
+            .map(|index| if self.get(index) { '1' } else { '0' })
+            .collect();
+        write!(f, "Bitfield({})", bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wire_format() {
+        let bitfield = Bitfield::from_bytes(&[0b1010_0000, 0b1111_1111], 10);
+        assert_eq!(bitfield.as_bytes(), [0b1010_0000, 0b1100_0000]);
+        assert_eq!(bitfield.ones().collect::<Vec<_>>(), [0, 2, 8, 9]);
+        assert_eq!(bitfield.zeros().collect::<Vec<_>>(), [1, 3, 4, 5, 6, 7]);
+        assert!(bitfield.get(9) && !bitfield.get(10) && !bitfield.get(100));
+        // too short, and too long
+        assert_eq!(Bitfield::from_bytes(&[0xff], 12).as_bytes(), [0xff, 0]);
+        assert_eq!(
+            Bitfield::from_bytes(&[0xff, 0xff], 3).as_bytes(),
+            [0b1110_0000]
+        );
+
+        assert_eq!(Bitfield::full(8).as_bytes(), [0xff]);
+        assert_eq!(Bitfield::full(10).as_bytes(), [0xff, 0b1100_0000]);
+        assert_eq!(Bitfield::new(10).as_bytes(), [0, 0]);
+        assert!(Bitfield::full(10).is_full() && Bitfield::new(10).is_clear());
+        assert!(Bitfield::new(0).is_full() && Bitfield::new(0).is_empty());
+    }
+
+    #[test]
+    fn strict_bitfield_messages() {
+        let bitfield = Bitfield::from_message(&[0b1010_0000, 0b1100_0000], 10).unwrap();
+        assert_eq!(bitfield.ones().collect::<Vec<_>>(), [0, 2, 8, 9]);
+        assert!(Bitfield::from_message(&[], 0).unwrap().is_empty());
+        assert!(Bitfield::from_message(&[0xff], 10).is_err());
+        assert!(Bitfield::from_message(&[0xff, 0, 0], 10).is_err());
+        assert!(Bitfield::from_message(&[0xff, 0b1110_0000], 10).is_err());
+    }
+
+    #[test]
+    fn set_and_resize() {
+        let mut bitfield = Bitfield::new(3);
+        bitfield.set(2);
+        bitfield.set(0);
+        bitfield.unset(0);
+        bitfield.unset(7);
+        assert_eq!(bitfield, [false, false, true].into_iter().collect());
+        bitfield.resize(20);
+        bitfield.set(19);
+        assert_eq!(bitfield.count(), 2);
+        bitfield.resize(10);
+        assert_eq!(bitfield.ones().collect::<Vec<_>>(), [2]);
+        assert_eq!(format!("{:?}", bitfield), "Bitfield(0010000000)");
+    }
+
+    #[test]
+    #[should_panic]
+    fn set_past_the_end() {
+        Bitfield::new(10).set(10);
+    }
+
+    #[test]
+    fn rank_and_select() {
+        let bitfield: Bitfield = (0..30).map(|index| index % 3 == 0).collect();
+        for (n, index) in bitfield.ones().enumerate() {
+            assert_eq!(bitfield.rank(index), n);
+            assert_eq!(bitfield.select(n), Some(index));
+        }
+        assert_eq!(bitfield.rank(0), 0);
+        assert_eq!(bitfield.rank(4), 2);
+        assert_eq!(bitfield.rank(30), 10);
+        assert_eq!(bitfield.rank(1000), 10);
+        assert_eq!(bitfield.select(10), None);
+        assert_eq!(Bitfield::new(5).select(0), None);
+    }
+
+    #[test]
+    fn iterators_agree_with_get() {
+        for len in [0, 1, 7, 8, 9, 63, 130] {
+            let bitfield: Bitfield = (0..len).map(|index| index % 5 < 2).collect();
+            assert_eq!(bitfield.len(), len);
+            let ones: Vec<_> = (0..len).filter(|&index| bitfield.get(index)).collect();
+            let zeros: Vec<_> = (0..len).filter(|&index| !bitfield.get(index)).collect();
+            assert_eq!(bitfield.ones().collect::<Vec<_>>(), ones);
+            assert_eq!(bitfield.zeros().collect::<Vec<_>>(), zeros);
+            assert_eq!(Bitfield::full(len).zeros().count(), 0);
+            assert_eq!(Bitfield::new(len).zeros().count(), len);
+        }
+    }
+
+    #[test]
+    fn set_operations() {
+        let ours: Bitfield = [true, true, false, false, true].into_iter().collect();
+        let theirs: Bitfield = [true, false, true, false].into_iter().collect();
+        assert_eq!(
+            ours.intersection(&theirs),
+            [true, false, false, false, false].into_iter().collect()
+        );
+        assert_eq!(
+            theirs.difference(&ours),
+            [false, false, true, false].into_iter().collect()
+        );
+        let mut both = theirs.clone();
+        both.union_with(&ours);
+        assert_eq!(both.ones().collect::<Vec<_>>(), [0, 1, 2]);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::rate::parse_rate;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Memory the pieces kept by a seed's `PieceCache` take up at most, by default.
+pub const DEFAULT_CACHE_SIZE: usize = 16 << 20;
+
+/// A cache size in bytes as `parse_rate` reads it, e.g. 64M, or 0 for no cache.
+pub fn parse_cache_size(value: &str) -> anyhow::Result<usize> {
+    if value == "0" {
+        return Ok(0);
+    }
+    parse_rate(value)
+}
+
+/// How a `PieceCache` did since it was made.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads served from memory.
+    pub hits: usize,
+    /// Reads that had to go to the disk.
+    pub misses: usize,
+    /// Bytes of the pieces kept right now, at most `budget`.
+    pub size: usize,
+    pub budget: usize,
+}
+
+impl CacheStats {
+    /// The share of reads that were hits, from 0 to 1; 0 before the first read.
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            reads => self.hits as f64 / reads as f64,
+        }
+    }
+}
+
+/// The pieces written or read last, kept in memory up to a budget of bytes so that the blocks
+/// peers request of them in a row, and pieces read again, don't each go to the disk. The
+/// piece used longest ago goes first once the budget is used up.
+#[derive(Debug)]
+pub struct PieceCache {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// The pieces by index, the one used last at the back.
+    pieces: VecDeque<(usize, Arc<Vec<u8>>)>,
+    stats: CacheStats,
+}
+
+impl PieceCache {
+    pub fn new(budget: usize) -> PieceCache {
+        PieceCache {
+            inner: Mutex::new(Inner {
+                pieces: VecDeque::new(),
+                stats: CacheStats {
+                    budget,
+                    ..CacheStats::default()
+                },
+            }),
+        }
+    }
+
+    /// The piece at `index` if it is kept, counting a hit or a miss.
+    pub fn get(&self, index: usize) -> Option<Arc<Vec<u8>>> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(at) = inner.pieces.iter().position(|(kept, _)| *kept == index) else {
+            inner.stats.misses += 1;
+            return None;
+        };
+        inner.stats.hits += 1;
+        let kept = inner.pieces.remove(at).unwrap();
+        let piece = kept.1.clone();
+        inner.pieces.push_back(kept);
+        Some(piece)
+    }
+
+    /// Keeps `piece` as the one at `index`, in place of what was kept of it, making room by
+    /// dropping the pieces used longest ago. A piece larger than the budget isn't kept.
+    pub fn insert(&self, index: usize, piece: Arc<Vec<u8>>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(index);
+        if piece.len() > inner.stats.budget {
+            return;
+        }
+        while inner.stats.size + piece.len() > inner.stats.budget {
+            let Some((_, oldest)) = inner.pieces.pop_front() else {
+                break;
+            };
+            inner.stats.size -= oldest.len();
+        }
+        inner.stats.size += piece.len();
+        inner.pieces.push_back((index, piece));
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().unwrap().stats
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, index: usize) {
+        if let Some(at) = self.pieces.iter().position(|(kept, _)| *kept == index) {
+            let (_, piece) = self.pieces.remove(at).unwrap();
+            self.stats.size -= piece.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_pieces_used_last_within_its_budget() {
+        let cache = PieceCache::new(250);
+        for index in 0..3 {
+            cache.insert(index, Arc::new(vec![index as u8; 100]));
+        }
+        // the third didn't fit next to the other two
+        assert!(cache.get(0).is_none());
+        assert_eq!(cache.get(1).unwrap()[0], 1);
+        cache.insert(3, Arc::new(vec![3; 100]));
+        // 1 was used after 2, so 2 made room
+        assert!(cache.get(2).is_none());
+        assert!(cache.get(1).is_some() && cache.get(3).is_some());
+        cache.insert(4, Arc::new(vec![4; 300]));
+        assert!(cache.get(4).is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 3));
+        assert_eq!((stats.size, stats.budget), (200, 250));
+        assert_eq!(stats.hit_ratio(), 0.5);
+    }
+
+    #[test]
+    fn parse_cache_sizes() {
+        assert_eq!(parse_cache_size("0").unwrap(), 0);
+        assert_eq!(parse_cache_size("64M").unwrap(), 64 << 20);
+        assert!(parse_cache_size("lots").is_err());
+    }
+}
@@ -0,0 +1,281 @@
+use rand::seq::IndexedRandom;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How often the unchoked peers are chosen anew.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// Peers unchoked for their transfer rate, besides the optimistic unchoke.
+pub const UPLOAD_SLOTS: usize = 4;
+/// The optimistic unchoke moves on every third rechoke, i.e. every 30 seconds.
+const OPTIMISTIC_ROUNDS: usize = 3;
+
+struct Peer {
+    interested: bool,
+    /// Bytes sent since the last rechoke.
+    uploaded: usize,
+    /// Bytes sent during the last full rechoke interval.
+    rate: usize,
+    /// Whether the peer stopped sending us the blocks we asked it for.
+    snubbed: bool,
+    unchoked: watch::Sender<bool>,
+}
+
+#[derive(Default)]
+struct State {
+    peers: HashMap<usize, Peer>,
+    next_id: usize,
+    optimistic: Option<usize>,
+    round: usize,
+}
+
+/// Decides which peers we upload to, tit-for-tat style.
+///
+/// Every rechoke the interested peers we transfer the most to keep their slots and everyone
+/// else is choked, except for one optimistic unchoke. That one is picked at random and rotated
+/// every 30 seconds, so that new peers get a chance to show what they're worth. Peers that snub
+/// us only get a regular slot when the others leave one free.
+pub struct Choker {
+    slots: usize,
+    state: Mutex<State>,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Choker {
+        Choker {
+            slots,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Adds a connected peer, which starts out choked.
+    pub fn register(self: &Arc<Choker>) -> UploadSlot {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        let (unchoked, receiver) = watch::channel(false);
+        state.peers.insert(
+            id,
+            Peer {
+                interested: false,
+                uploaded: 0,
+                rate: 0,
+                snubbed: false,
+                unchoked,
+            },
+        );
+        UploadSlot {
+            id,
+            choker: self.clone(),
+            unchoked: receiver,
+        }
+    }
+
+    /// Rechokes every `RECHOKE_INTERVAL`, forever.
+    pub async fn run(self: Arc<Choker>) {
+        let mut interval = tokio::time::interval(RECHOKE_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.round();
+        }
+    }
+
+    /// Ends a rechoke interval: the bytes sent during it become the peers' rates, and the
+    /// peers are rechoked by them.
+    pub fn round(&self) {
+        let mut state = self.state.lock().unwrap();
+        for peer in state.peers.values_mut() {
+            peer.rate = std::mem::take(&mut peer.uploaded);
+        }
+        let rotate = state.round.is_multiple_of(OPTIMISTIC_ROUNDS);
+        state.round += 1;
+        self.rechoke(&mut state, rotate);
+    }
+
+    fn rechoke(&self, state: &mut State, rotate: bool) {
+        let mut interested: Vec<(usize, bool, usize)> = state
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.interested)
+            .map(|(&id, peer)| (id, peer.snubbed, peer.rate))
+            .collect();
+        // fastest first, ties to the peer that has been connected the longest
+        interested.sort_by_key(|&(id, snubbed, rate)| (snubbed, std::cmp::Reverse(rate), id));
+        let regular: HashSet<usize> = interested
+            .iter()
+            .take(self.slots)
+            .map(|&(id, _, _)| id)
+            .collect();
+
+        let keep_optimistic = state.optimistic.is_some_and(|id| {
+            !regular.contains(&id) && state.peers.get(&id).is_some_and(|peer| peer.interested)
+        });
+        if rotate || !keep_optimistic {
+            let candidates: Vec<usize> = interested
+                .iter()
+                .map(|&(id, _, _)| id)
+                .filter(|id| !regular.contains(id))
+                .collect();
+            state.optimistic = candidates.choose(&mut rand::rng()).copied();
+        }
+
+        for (id, peer) in &state.peers {
+            let unchoke = regular.contains(id) || state.optimistic == Some(*id);
+            peer.unchoked.send_if_modified(|unchoked| {
+                let changed = *unchoked != unchoke;
+                *unchoked = unchoke;
+                changed
+            });
+        }
+    }
+}
+
+/// A peer's place in the choker, held by its connection and given up when dropped.
+pub struct UploadSlot {
+    id: usize,
+    choker: Arc<Choker>,
+    unchoked: watch::Receiver<bool>,
+}
+
+impl UploadSlot {
+    /// Tells whether the peer may currently request blocks from us.
+    pub fn unchoked(&self) -> watch::Receiver<bool> {
+        self.unchoked.clone()
+    }
+
+    /// Records a change of interest; a newly interested peer is unchoked right away if there's
+    /// room, instead of waiting for the next rechoke.
+    pub fn set_interested(&self, interested: bool) {
+        let mut state = self.choker.state.lock().unwrap();
+        match state.peers.get_mut(&self.id) {
+            Some(peer) if peer.interested != interested => peer.interested = interested,
+            _ => return,
+        }
+        self.choker.rechoke(&mut state, false);
+    }
+
+    /// Records whether the peer is snubbing us; it takes effect at the next rechoke.
+    pub fn set_snubbed(&self, snubbed: bool) {
+        let mut state = self.choker.state.lock().unwrap();
+        if let Some(peer) = state.peers.get_mut(&self.id) {
+            peer.snubbed = snubbed;
+        }
+    }
+
+    pub fn uploaded(&self, bytes: usize) {
+        let mut state = self.choker.state.lock().unwrap();
+        if let Some(peer) = state.peers.get_mut(&self.id) {
+            peer.uploaded += bytes;
+        }
+    }
+}
+
+impl Drop for UploadSlot {
+    fn drop(&mut self) {
+        let mut state = self.choker.state.lock().unwrap();
+        if state
+            .peers
+            .remove(&self.id)
+            .is_some_and(|peer| *peer.unchoked.borrow())
+        {
+            // hand the slot to someone else
+            self.choker.rechoke(&mut state, false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unchoked(slots: &[UploadSlot]) -> Vec<usize> {
+        (0..slots.len())
+            .filter(|&index| *slots[index].unchoked.borrow())
+            .collect()
+    }
+
+    #[test]
+    fn unchokes_fastest_peers_and_one_optimistic() {
+        let choker = Arc::new(Choker::new(2));
+        let slots: Vec<UploadSlot> = (0..6).map(|_| choker.register()).collect();
+        for (index, slot) in slots.iter().enumerate() {
+            slot.set_interested(true);
+            slot.uploaded(index * 1000);
+        }
+        choker.round();
+        let unchoked = unchoked(&slots);
+        assert_eq!(unchoked.len(), 3);
+        assert!(unchoked.contains(&4) && unchoked.contains(&5));
+    }
+
+    #[test]
+    fn snubbing_peers_come_last() {
+        let choker = Arc::new(Choker::new(2));
+        let slots: Vec<UploadSlot> = (0..3).map(|_| choker.register()).collect();
+        for (index, slot) in slots.iter().enumerate() {
+            slot.set_interested(true);
+            slot.uploaded((index + 1) * 1000);
+        }
+        slots[2].set_snubbed(true);
+        choker.round();
+        let regular = |state: &State| {
+            let optimistic = state.optimistic;
+            (0..3)
+                .filter(|&id| *slots[id].unchoked.borrow() && Some(id) != optimistic)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(regular(&choker.state.lock().unwrap()), [0, 1]);
+        slots[2].set_snubbed(false);
+        for (index, slot) in slots.iter().enumerate() {
+            slot.uploaded((index + 1) * 1000);
+        }
+        choker.round();
+        assert_eq!(regular(&choker.state.lock().unwrap()), [1, 2]);
+    }
+
+    #[test]
+    fn uninterested_peers_stay_choked() {
+        let choker = Arc::new(Choker::new(2));
+        let slots: Vec<UploadSlot> = (0..3).map(|_| choker.register()).collect();
+        slots[1].uploaded(1000);
+        choker.round();
+        assert!(unchoked(&slots).is_empty());
+
+        slots[1].set_interested(true);
+        assert_eq!(unchoked(&slots), vec![1]);
+        slots[1].set_interested(false);
+        assert!(unchoked(&slots).is_empty());
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates_every_third_round() {
+        let choker = Arc::new(Choker::new(1));
+        let slots: Vec<UploadSlot> = (0..5).map(|_| choker.register()).collect();
+        let optimistic = || choker.state.lock().unwrap().optimistic;
+        for slot in &slots {
+            slot.set_interested(true);
+        }
+        choker.round();
+        let first = optimistic();
+        assert!(first.is_some());
+        for _ in 0..2 {
+            slots[0].uploaded(1000);
+            choker.round();
+            assert_eq!(optimistic(), first);
+        }
+    }
+
+    #[test]
+    fn dropped_peer_frees_its_slot() {
+        let choker = Arc::new(Choker::new(1));
+        let mut slots: Vec<UploadSlot> = (0..3).map(|_| choker.register()).collect();
+        for slot in &slots {
+            slot.set_interested(true);
+        }
+        assert_eq!(unchoked(&slots).len(), 2);
+        let first = unchoked(&slots)[0];
+        slots.remove(first);
+        assert_eq!(unchoked(&slots).len(), 2);
+    }
+}
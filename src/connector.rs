@@ -0,0 +1,566 @@
+use crate::ipfilter::{FilterStats, IpFilter};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Peer connections open at once, over all torrents, unless configured otherwise.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+/// Peer connections one torrent keeps open at once unless configured otherwise.
+pub const DEFAULT_MAX_TORRENT_CONNECTIONS: usize = 50;
+/// Outgoing connections still being set up at once unless configured otherwise.
+pub const DEFAULT_MAX_HALF_OPEN: usize = 8;
+/// A peer that delivered nothing for this long gives up its connection to a waiting
+/// candidate.
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+/// Pieces failing their hash check an IP may send before it is banned.
+pub const MAX_HASH_FAILURES: usize = 2;
+/// How long a peer we couldn't connect to isn't tried again, doubling with each attempt
+/// that fails in a row.
+pub const RETRY_BACKOFF: Duration = Duration::from_secs(30);
+/// Attempts to connect to a peer that may fail in a row before it is given up on for the
+/// rest of the download.
+pub const MAX_CONNECT_FAILURES: u32 = 5;
+
+/// Attempts a handshake that failed transiently, e.g. timed out or was reset, may be made
+/// again on the spot before the peer waits `RETRY_BACKOFF` like any other.
+pub const HANDSHAKE_RETRIES: u32 = 2;
+/// How long the first of those waits, doubling with each.
+pub const HANDSHAKE_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Whether a handshake failed in a way that may not happen again if it is retried: it timed
+/// out, or the connection was reset or closed under it, e.g. by a peer that was briefly busy.
+/// A peer that refused the connection or said something wrong, such as another info hash,
+/// would just do it again.
+pub fn is_transient(error: &crate::Error) -> bool {
+    let error = match error {
+        crate::Error::Network(error) | crate::Error::PeerProtocol(error) => error,
+        _ => return false,
+    };
+    error.chain().any(|cause| {
+        if cause.is::<tokio::time::error::Elapsed>() {
+            return true;
+        }
+        cause.downcast_ref::<io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+            )
+        })
+    })
+}
+
+/// The connection slots every torrent of a session takes its connections from.
+pub struct ConnectionSlots {
+    connections: Arc<Semaphore>,
+    half_open: Arc<Semaphore>,
+}
+
+impl ConnectionSlots {
+    pub fn new(max_connections: usize, max_half_open: usize) -> ConnectionSlots {
+        ConnectionSlots {
+            connections: Arc::new(Semaphore::new(max_connections)),
+            half_open: Arc::new(Semaphore::new(max_half_open.max(1))),
+        }
+    }
+
+    /// A slot for one connection, held for as long as it is open, if one is free.
+    pub fn try_connection(&self) -> Option<OwnedSemaphorePermit> {
+        self.connections.clone().try_acquire_owned().ok()
+    }
+
+    fn is_full(&self) -> bool {
+        self.connections.available_permits() == 0
+    }
+
+    /// Waits for a slot to set up an outgoing connection in, held until the handshake is done.
+    pub async fn half_open(&self) -> OwnedSemaphorePermit {
+        self.half_open
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed")
+    }
+}
+
+/// The IPs that sent pieces failing their hash check, shared by every torrent of a session.
+/// One that sent `MAX_HASH_FAILURES` of them is banned for as long as the session runs, so it
+/// can't keep on poisoning the download by connecting again. The ranges of an `IpFilter` are
+/// banned from the start.
+#[derive(Debug, Default)]
+pub struct BanList {
+    failures: Mutex<HashMap<IpAddr, usize>>,
+    filter: IpFilter,
+    blocked_inbound: AtomicUsize,
+    blocked_outbound: AtomicUsize,
+}
+
+impl BanList {
+    pub fn new() -> BanList {
+        BanList::default()
+    }
+
+    pub fn with_filter(filter: IpFilter) -> BanList {
+        BanList {
+            filter,
+            ..BanList::default()
+        }
+    }
+
+    /// Counts a piece from `ip` that failed its hash check; true if that got it banned.
+    pub fn hash_failed(&self, ip: IpAddr) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        let count = failures.entry(ip).or_default();
+        *count += 1;
+        *count == MAX_HASH_FAILURES
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.filter.blocks(ip)
+            || self
+                .failures
+                .lock()
+                .unwrap()
+                .get(&ip)
+                .is_some_and(|&count| count >= MAX_HASH_FAILURES)
+    }
+
+    /// Like `is_banned`, for a peer that connected to us, counting it if the filter blocks it.
+    pub fn refuses_connection_from(&self, ip: IpAddr) -> bool {
+        if self.filter.blocks(ip) {
+            self.blocked_inbound.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        self.is_banned(ip)
+    }
+
+    /// Like `refuses_connection_from`, for a peer we learned of and would connect to.
+    pub fn refuses_connection_to(&self, ip: IpAddr) -> bool {
+        if self.filter.blocks(ip) {
+            self.blocked_outbound.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        self.is_banned(ip)
+    }
+
+    pub fn filter_stats(&self) -> FilterStats {
+        FilterStats {
+            ranges: self.filter.range_count(),
+            blocked_inbound: self.blocked_inbound.load(Ordering::Relaxed),
+            blocked_outbound: self.blocked_outbound.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Decides which peers of one torrent to connect to, keeping within the torrent's connection
+/// limit and the session's slots.
+///
+/// Candidates queue up in the order they are learned about until a slot is free. While any
+/// are waiting with all slots taken, peers that have been idle for `SNUB_TIMEOUT` are made
+/// way for, one at a time. Peers on the ban list are neither connected to nor admitted.
+///
+/// A peer we couldn't finish a handshake with isn't queued again for `RETRY_BACKOFF`, twice
+/// that after the next failure and so on, however often the trackers, the DHT or other peers
+/// bring it up; after `MAX_CONNECT_FAILURES` in a row, never again. A handshake that failed
+/// transiently is retried on its own up to `HANDSHAKE_RETRIES` times first, after a short
+/// `HANDSHAKE_RETRY_BACKOFF`, while a peer that failed it for good, e.g. with another info
+/// hash, is given up on right away.
+pub struct Connector {
+    max_connections: usize,
+    bans: Arc<BanList>,
+    queue: VecDeque<SocketAddr>,
+    queued: HashSet<SocketAddr>,
+    /// Connected peers, with when they last delivered a piece or connected.
+    peers: HashMap<SocketAddr, Instant>,
+    /// The peers of `peers` we are connecting to whose handshake isn't done yet.
+    connecting: HashSet<SocketAddr>,
+    /// Peers the last attempts to connect to failed, with how many did and when the next
+    /// may be made.
+    failures: HashMap<SocketAddr, (u32, Instant)>,
+    /// Peers whose handshake was retried after it failed transiently, with how many times and
+    /// when the next retry is due, if it isn't under way.
+    retries: HashMap<SocketAddr, (u32, Option<Instant>)>,
+}
+
+impl Connector {
+    pub fn new(max_connections: usize, bans: Arc<BanList>) -> Connector {
+        Connector {
+            max_connections: max_connections.max(1),
+            bans,
+            queue: VecDeque::new(),
+            queued: HashSet::new(),
+            peers: HashMap::new(),
+            connecting: HashSet::new(),
+            failures: HashMap::new(),
+            retries: HashMap::new(),
+        }
+    }
+
+    /// Queues the addresses that are neither connected, queued already, banned nor waiting
+    /// to be retried.
+    pub fn enqueue(&mut self, addrs: impl IntoIterator<Item = SocketAddr>) {
+        let now = Instant::now();
+        for addr in addrs {
+            if self.bans.refuses_connection_to(addr.ip()) {
+                continue;
+            }
+            let backing_off = self
+                .failures
+                .get(&addr)
+                .is_some_and(|&(failures, retry_at)| {
+                    failures >= MAX_CONNECT_FAILURES || now < retry_at
+                });
+            if backing_off {
+                continue;
+            }
+            if !self.peers.contains_key(&addr) && self.queued.insert(addr) {
+                self.queue.push_back(addr);
+            }
+        }
+    }
+
+    /// Like `enqueue`, for a peer to try again right away however often connecting to it
+    /// failed, e.g. because hole punching opened its NAT to us.
+    pub fn retry(&mut self, addr: SocketAddr) {
+        self.failures.remove(&addr);
+        self.enqueue([addr]);
+    }
+
+    /// The candidates waiting for a slot, counting those waiting for their handshake retry.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+            + self
+                .retries
+                .values()
+                .filter(|(_, due)| due.is_some())
+                .count()
+    }
+
+    /// When the next handshake retry is due, if any is waiting.
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.retries.values().filter_map(|&(_, due)| due).min()
+    }
+
+    /// The next candidate to connect to, with its slot, if there's room for it.
+    pub fn next(&mut self, slots: &ConnectionSlots) -> Option<(SocketAddr, OwnedSemaphorePermit)> {
+        // handshake retries that are due go first, as the peers were picked already
+        let now = Instant::now();
+        for (&addr, (_, due)) in &mut self.retries {
+            if due.is_some_and(|due| due <= now) && !self.peers.contains_key(&addr) {
+                *due = None;
+                if self.queued.insert(addr) {
+                    self.queue.push_front(addr);
+                }
+            }
+        }
+        if self.queue.is_empty() || self.peers.len() >= self.max_connections {
+            return None;
+        }
+        // candidates may have been banned while they waited
+        let bans = &self.bans;
+        self.queue.retain(|addr| !bans.is_banned(addr.ip()));
+        self.queued.retain(|addr| !bans.is_banned(addr.ip()));
+        if self.queue.is_empty() {
+            return None;
+        }
+        let permit = slots.try_connection()?;
+        let addr = self.queue.pop_front()?;
+        self.queued.remove(&addr);
+        self.peers.insert(addr, Instant::now());
+        self.connecting.insert(addr);
+        Some((addr, permit))
+    }
+
+    /// Takes a peer that connected to us if there's room for it, it isn't connected yet and
+    /// it isn't banned.
+    pub fn admit(
+        &mut self,
+        addr: SocketAddr,
+        slots: &ConnectionSlots,
+    ) -> Option<OwnedSemaphorePermit> {
+        if self.peers.contains_key(&addr)
+            || self.peers.len() >= self.max_connections
+            || self.bans.is_banned(addr.ip())
+        {
+            return None;
+        }
+        let permit = slots.try_connection()?;
+        if self.queued.remove(&addr) {
+            self.queue.retain(|&queued| queued != addr);
+        }
+        self.peers.insert(addr, Instant::now());
+        Some(permit)
+    }
+
+    /// Notes that the peer at `addr` delivered something at `now`.
+    pub fn active(&mut self, addr: SocketAddr, now: Instant) {
+        if let Some(last) = self.peers.get_mut(&addr) {
+            *last = now;
+        }
+    }
+
+    /// Notes that the handshake with the peer at `addr` is done, which makes up for the
+    /// attempts to connect to it that failed.
+    pub fn connected(&mut self, addr: SocketAddr) {
+        self.connecting.remove(&addr);
+        self.failures.remove(&addr);
+        self.retries.remove(&addr);
+    }
+
+    /// Notes that the handshake with the peer at `addr` failed with `error`. One that failed
+    /// transiently is retried shortly while `HANDSHAKE_RETRIES` allows; one that said something
+    /// wrong is given up on, and any other waits as after a failed connection.
+    pub fn handshake_failed(&mut self, addr: SocketAddr, error: &crate::Error) {
+        self.handshake_failed_at(addr, error, Instant::now());
+    }
+
+    fn handshake_failed_at(&mut self, addr: SocketAddr, error: &crate::Error, now: Instant) {
+        if !self.connecting.remove(&addr) {
+            return;
+        }
+        let retries = self.retries.remove(&addr).map_or(0, |(retries, _)| retries);
+        if is_transient(error) {
+            if retries < HANDSHAKE_RETRIES {
+                let wait = HANDSHAKE_RETRY_BACKOFF.saturating_mul(2u32.pow(retries));
+                self.retries.insert(addr, (retries + 1, Some(now + wait)));
+            } else {
+                self.connect_failed(addr, now);
+            }
+        } else if let crate::Error::PeerProtocol(_) = error {
+            self.failures.insert(addr, (MAX_CONNECT_FAILURES, now));
+        } else {
+            self.connect_failed(addr, now);
+        }
+    }
+
+    /// Frees the peer's place; one we were connecting to that didn't get as far as
+    /// `connected` waits before it is tried again.
+    pub fn disconnected(&mut self, addr: SocketAddr) {
+        self.peers.remove(&addr);
+        if self.connecting.remove(&addr) {
+            self.connect_failed(addr, Instant::now());
+        }
+    }
+
+    fn connect_failed(&mut self, addr: SocketAddr, now: Instant) {
+        let (failures, retry_at) = self.failures.entry(addr).or_insert((0, now));
+        *retry_at = now + RETRY_BACKOFF.saturating_mul(2u32.saturating_pow(*failures));
+        *failures += 1;
+    }
+
+    /// The peer to disconnect to make room for a waiting candidate: the one idle the longest,
+    /// as long as that is `SNUB_TIMEOUT` or more.
+    pub fn idle_peer(&self, slots: &ConnectionSlots, now: Instant) -> Option<SocketAddr> {
+        let full = self.peers.len() >= self.max_connections || slots.is_full();
+        if self.queue.is_empty() || !full {
+            return None;
+        }
+        self.peers
+            .iter()
+            .filter(|(_, &last)| now.saturating_duration_since(last) >= SNUB_TIMEOUT)
+            .min_by_key(|(_, &last)| last)
+            .map(|(&addr, _)| addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn connects_within_the_torrent_limit() {
+        let slots = ConnectionSlots::new(10, 1);
+        let mut connector = Connector::new(2, Arc::default());
+        connector.enqueue([addr(1), addr(2), addr(1), addr(3)]);
+        assert_eq!(connector.queued(), 3);
+        let (first, _a) = connector.next(&slots).unwrap();
+        let (second, _b) = connector.next(&slots).unwrap();
+        assert_eq!((first, second), (addr(1), addr(2)));
+        assert!(connector.next(&slots).is_none());
+        // connected peers aren't queued again
+        connector.enqueue([addr(1)]);
+        assert_eq!(connector.queued(), 1);
+
+        connector.disconnected(addr(1));
+        assert_eq!(connector.next(&slots).unwrap().0, addr(3));
+    }
+
+    #[test]
+    fn backs_off_from_peers_it_cannot_connect_to() {
+        let slots = ConnectionSlots::new(10, 1);
+        let mut connector = Connector::new(5, Arc::default());
+        connector.enqueue([addr(1), addr(2)]);
+        let (_a, _b) = (connector.next(&slots), connector.next(&slots));
+        connector.connected(addr(2));
+        connector.disconnected(addr(1));
+        connector.disconnected(addr(2));
+        // only the peer that never finished its handshake has to wait
+        connector.enqueue([addr(1), addr(2)]);
+        assert_eq!(connector.queued(), 1);
+        assert_eq!(connector.next(&slots).unwrap().0, addr(2));
+
+        let now = Instant::now();
+        let (failures, retry_at) = connector.failures[&addr(1)];
+        assert_eq!(failures, 1);
+        assert!(retry_at > now && retry_at <= now + RETRY_BACKOFF);
+        // once the wait is over, it is tried again
+        connector.failures.insert(addr(1), (1, now));
+        connector.enqueue([addr(1)]);
+        assert_eq!(connector.next(&slots).unwrap().0, addr(1));
+        connector.disconnected(addr(1));
+        assert_eq!(connector.failures[&addr(1)].0, 2);
+        assert!(connector.failures[&addr(1)].1 >= now + RETRY_BACKOFF * 2);
+
+        // the wait doubles every time, until the peer is given up on
+        for _ in 2..MAX_CONNECT_FAILURES {
+            connector.connect_failed(addr(1), now);
+        }
+        let longest = RETRY_BACKOFF * 2u32.pow(MAX_CONNECT_FAILURES - 1);
+        assert_eq!(connector.failures[&addr(1)].1, now + longest);
+        connector.failures.get_mut(&addr(1)).unwrap().1 = now;
+        connector.enqueue([addr(1)]);
+        assert_eq!(connector.queued(), 0);
+        // unless it is retried on purpose, or connects to us after all
+        connector.retry(addr(1));
+        assert_eq!(connector.next(&slots).unwrap().0, addr(1));
+        connector.disconnected(addr(1));
+        let _slot = connector.admit(addr(1), &slots).unwrap();
+        connector.connected(addr(1));
+        connector.disconnected(addr(1));
+        connector.enqueue([addr(1)]);
+        assert_eq!(connector.queued(), 1);
+    }
+
+    #[test]
+    fn retries_handshakes_that_failed_transiently() {
+        let slots = ConnectionSlots::new(10, 1);
+        let mut connector = Connector::new(5, Arc::default());
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        let reset = crate::Error::Network(reset.into());
+        let mismatch = crate::Error::PeerProtocol(anyhow::anyhow!("peer replied with info hash"));
+        assert!(is_transient(&reset) && !is_transient(&mismatch));
+        connector.enqueue([addr(1), addr(2)]);
+        let (_a, _b) = (connector.next(&slots), connector.next(&slots));
+
+        // the reset peer is back once its short wait is over, the other is given up on
+        let earlier = Instant::now() - HANDSHAKE_RETRY_BACKOFF;
+        connector.handshake_failed_at(addr(1), &reset, earlier);
+        connector.handshake_failed(addr(2), &mismatch);
+        connector.disconnected(addr(1));
+        connector.disconnected(addr(2));
+        assert_eq!(connector.queued(), 1);
+        assert_eq!(
+            connector.next_retry(),
+            Some(earlier + HANDSHAKE_RETRY_BACKOFF)
+        );
+        assert_eq!(connector.next(&slots).unwrap().0, addr(1));
+        assert!(!connector.failures.contains_key(&addr(1)));
+        connector.enqueue([addr(2)]);
+        assert_eq!(connector.queued(), 0);
+
+        // the retries run out into the usual backoff
+        let later = Instant::now();
+        connector.handshake_failed_at(addr(1), &reset, later);
+        assert_eq!(
+            connector.retries[&addr(1)],
+            (2, Some(later + HANDSHAKE_RETRY_BACKOFF * 2))
+        );
+        connector.retries.get_mut(&addr(1)).unwrap().1 = Some(later);
+        connector.disconnected(addr(1));
+        assert_eq!(connector.next(&slots).unwrap().0, addr(1));
+        connector.handshake_failed_at(addr(1), &reset, later);
+        connector.disconnected(addr(1));
+        assert_eq!(connector.next_retry(), None);
+        assert_eq!(connector.failures[&addr(1)], (1, later + RETRY_BACKOFF));
+    }
+
+    #[test]
+    fn shares_the_session_slots() {
+        let slots = ConnectionSlots::new(1, 1);
+        let mut first = Connector::new(5, Arc::default());
+        let mut second = Connector::new(5, Arc::default());
+        first.enqueue([addr(1)]);
+        second.enqueue([addr(2)]);
+        let (_, permit) = first.next(&slots).unwrap();
+        assert!(second.next(&slots).is_none());
+        assert!(second.admit(addr(3), &slots).is_none());
+        drop(permit);
+        first.disconnected(addr(1));
+        assert_eq!(second.next(&slots).unwrap().0, addr(2));
+    }
+
+    #[test]
+    fn recycles_idle_peers_for_waiting_candidates() {
+        let slots = ConnectionSlots::new(10, 1);
+        let mut connector = Connector::new(2, Arc::default());
+        let start = Instant::now();
+        let _a = connector.admit(addr(1), &slots).unwrap();
+        let _b = connector.admit(addr(2), &slots).unwrap();
+        let later = start + SNUB_TIMEOUT + Duration::from_secs(1);
+        // nobody is waiting for a slot
+        assert_eq!(connector.idle_peer(&slots, later), None);
+
+        connector.enqueue([addr(3)]);
+        assert_eq!(connector.idle_peer(&slots, start), None);
+        connector.active(addr(1), start + Duration::from_secs(30));
+        assert_eq!(connector.idle_peer(&slots, later), Some(addr(2)));
+    }
+
+    #[test]
+    fn skips_banned_peers() {
+        let slots = ConnectionSlots::new(10, 1);
+        let bans = Arc::new(BanList::new());
+        let mut connector = Connector::new(5, bans.clone());
+        connector.enqueue([addr(1), addr(2)]);
+        let bad = addr(1).ip();
+        assert!(!bans.hash_failed(bad));
+        assert!(!bans.is_banned(bad));
+        assert!(bans.hash_failed(bad));
+        // every port of the IP is banned, including the queued ones
+        assert!(bans.is_banned(bad));
+        assert!(connector.next(&slots).is_none());
+        assert_eq!(connector.queued(), 0);
+        connector.enqueue([addr(3)]);
+        assert!(connector.admit(addr(4), &slots).is_none());
+        assert!(connector.next(&slots).is_none());
+
+        let other = SocketAddr::from(([127, 0, 0, 2], 1));
+        connector.enqueue([other]);
+        assert_eq!(connector.next(&slots).unwrap().0, other);
+        assert!(!bans.hash_failed(bad));
+    }
+
+    #[test]
+    fn skips_filtered_peers() {
+        let slots = ConnectionSlots::new(10, 1);
+        let filter = IpFilter::parse("Loopback:127.0.0.2-127.0.0.9\n").unwrap();
+        let bans = Arc::new(BanList::with_filter(filter));
+        let mut connector = Connector::new(5, bans.clone());
+        let blocked = SocketAddr::from(([127, 0, 0, 2], 1));
+        connector.enqueue([blocked, addr(1), blocked]);
+        assert_eq!(connector.queued(), 1);
+        assert_eq!(connector.next(&slots).unwrap().0, addr(1));
+        assert!(bans.is_banned(blocked.ip()));
+        assert!(bans.refuses_connection_from(blocked.ip()));
+        assert!(!bans.refuses_connection_from(addr(1).ip()));
+        assert_eq!(
+            bans.filter_stats(),
+            FilterStats {
+                ranges: 1,
+                blocked_inbound: 1,
+                blocked_outbound: 2,
+            }
+        );
+    }
+}
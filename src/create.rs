@@ -0,0 +1,238 @@
+use crate::torrent::{File, Hashes, Info, Keys, Torrent};
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Smallest piece length `default_piece_length` picks: one block.
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+/// Largest piece length `default_piece_length` picks.
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+/// About how many pieces `default_piece_length` aims for, keeping the metainfo small without
+/// making pieces too large to be worth re-downloading after a hash failure.
+const TARGET_PIECES: usize = 1500;
+
+/// What goes into a new torrent besides its content.
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    /// Bytes per piece; `default_piece_length` of the content's size if `None`.
+    pub piece_length: Option<usize>,
+    /// Tracker URLs, each tried in turn; none makes a trackerless torrent.
+    pub trackers: Vec<String>,
+    pub comment: Option<String>,
+    /// Whether peers may only come from the trackers (BEP 27).
+    pub private: bool,
+}
+
+/// A power-of-two piece length giving about `TARGET_PIECES` pieces for `total_length` bytes.
+pub fn default_piece_length(total_length: u64) -> usize {
+    let piece_length = (total_length / TARGET_PIECES as u64).next_power_of_two();
+    (piece_length as usize).clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Hashes the file or directory at `path` into a torrent named after it. The files of a
+/// directory are taken in order of their paths.
+pub fn create(path: &Path, options: &CreateOptions) -> anyhow::Result<Torrent> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow::anyhow!("{} has no usable name", path.display()))?
+        .to_owned();
+    let (files, keys) = if path.metadata()?.is_dir() {
+        let mut files = Vec::new();
+        collect_files(path, &mut Vec::new(), &mut files)?;
+        if files.is_empty() {
+            anyhow::bail!("{} contains no files", path.display());
+        }
+        let keys = Keys::MultiFile {
+            files: files
+                .iter()
+                .map(|(components, _, length)| File {
+                    length: *length as usize,
+                    path: components.clone(),
+                    attr: String::new(),
+                })
+                .collect(),
+        };
+        let files = files.into_iter().map(|(_, path, _)| path).collect();
+        (files, keys)
+    } else {
+        let length = path.metadata()?.len() as usize;
+        (vec![path.to_path_buf()], Keys::SingleFile { length })
+    };
+    let total_length = match &keys {
+        Keys::SingleFile { length } => *length as u64,
+        Keys::MultiFile { files } => files.iter().map(|file| file.length as u64).sum(),
+        Keys::FileTree {} => unreachable!("created torrents are v1"),
+    };
+    let piece_length = options
+        .piece_length
+        .unwrap_or_else(|| default_piece_length(total_length));
+    if piece_length == 0 {
+        anyhow::bail!("piece length must be positive");
+    }
+
+    let (pieces, hashed) = hash_pieces(&files, piece_length)?;
+    if hashed != total_length {
+        anyhow::bail!("files changed while they were hashed");
+    }
+    Ok(Torrent {
+        announce: options.trackers.first().cloned().unwrap_or_default(),
+        // a single tracker needs no list; several are tried one after the other
+        announce_list: match options.trackers.len() {
+            0 | 1 => Vec::new(),
+            _ => vec![options.trackers.clone()],
+        },
+        url_list: Vec::new(),
+        httpseeds: Vec::new(),
+        piece_layers: BTreeMap::new(),
+        comment: options.comment.clone(),
+        created_by: Some(format!("bittorent_client {}", env!("CARGO_PKG_VERSION"))),
+        creation_date: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|since| since.as_secs() as i64),
+        encoding: None,
+        info: Info {
+            name,
+            piece_length,
+            pieces,
+            keys,
+            meta_version: None,
+            private: options.private.then_some(1),
+            file_tree: None,
+        },
+        raw_info: None,
+        renamed: BTreeMap::new(),
+    })
+}
+
+/// Adds the files below `dir` to `files`, with their path components below the torrent's
+/// directory, starting at `prefix`, and their lengths. Symbolic links are skipped, so the
+/// torrent can't point outside the directory.
+fn collect_files(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    files: &mut Vec<(Vec<String>, PathBuf, u64)>,
+) -> anyhow::Result<()> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let Ok(name) = entry.file_name().into_string() else {
+            anyhow::bail!("{} has a name that isn't UTF-8", entry.path().display());
+        };
+        let file_type = entry.file_type()?;
+        prefix.push(name);
+        if file_type.is_dir() {
+            collect_files(&entry.path(), prefix, files)?;
+        } else if file_type.is_file() {
+            files.push((prefix.clone(), entry.path(), entry.metadata()?.len()));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// SHA-1 hashes of the pieces of `files` laid out back to back, and how many bytes they cover.
+fn hash_pieces(files: &[PathBuf], piece_length: usize) -> anyhow::Result<(Hashes, u64)> {
+    let mut hashes = Vec::new();
+    let mut hashed = 0;
+    let mut piece = Vec::with_capacity(piece_length);
+    for path in files {
+        let mut file = std::fs::File::open(path)
+            .map_err(|e| anyhow::anyhow!("can't open {}: {}", path.display(), e))?;
+        loop {
+            let filled = piece.len();
+            piece.resize(piece_length, 0);
+            let read = file.read(&mut piece[filled..])?;
+            piece.truncate(filled + read);
+            hashed += read as u64;
+            if read == 0 {
+                break;
+            }
+            if piece.len() == piece_length {
+                hashes.push(Sha1::digest(&piece).into());
+                piece.clear();
+            }
+        }
+    }
+    if !piece.is_empty() {
+        hashes.push(Sha1::digest(&piece).into());
+    }
+    Ok((Hashes(hashes), hashed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::test_data;
+    use crate::storage;
+
+    #[test]
+    fn piece_length_grows_with_size() {
+        assert_eq!(default_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(default_piece_length(1500 * 1024 * 1024), 1024 * 1024);
+        assert_eq!(default_piece_length(u64::MAX / 2), MAX_PIECE_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn create_single_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let data = test_data(100_000);
+        std::fs::write(&path, &data).unwrap();
+        let options = CreateOptions {
+            piece_length: Some(32768),
+            trackers: vec!["http://tracker/announce".to_owned()],
+            comment: Some("test data".to_owned()),
+            private: true,
+        };
+        let torrent = Torrent::from_bytes(&create(&path, &options).unwrap().to_bytes()).unwrap();
+        assert_eq!(torrent.info.name, "data.bin");
+        assert_eq!(torrent.announce, "http://tracker/announce");
+        assert!(torrent.announce_list.is_empty());
+        assert_eq!(torrent.comment.as_deref(), Some("test data"));
+        assert!(torrent.is_private());
+        assert_eq!(torrent.total_length(), data.len());
+        assert_eq!(torrent.piece_count(), 4);
+        let valid = storage::verify(&torrent, &path).await.unwrap();
+        assert!(valid.iter().all(|&valid| valid));
+    }
+
+    #[tokio::test]
+    async fn create_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        let data = test_data(50_000);
+        std::fs::write(root.join("b"), &data[..30_000]).unwrap();
+        std::fs::write(root.join("a"), &data[30_000..]).unwrap();
+        std::fs::write(root.join("sub/empty"), b"").unwrap();
+        let options = CreateOptions {
+            piece_length: Some(16384),
+            trackers: vec!["http://one".to_owned(), "http://two".to_owned()],
+            ..CreateOptions::default()
+        };
+        let torrent = Torrent::from_bytes(&create(&root, &options).unwrap().to_bytes()).unwrap();
+        assert!(!torrent.is_private());
+        assert_eq!(torrent.announce_list.len(), 1);
+        let paths: Vec<PathBuf> = torrent
+            .files()
+            .unwrap()
+            .into_iter()
+            .map(|span| span.path)
+            .collect();
+        let expected = ["a", "b", "sub/empty"].map(PathBuf::from);
+        assert_eq!(paths, expected);
+        assert_eq!(torrent.piece_count(), 4);
+        let valid = storage::verify(&torrent, &root).await.unwrap();
+        assert!(valid.iter().all(|&valid| valid));
+    }
+
+    #[test]
+    fn empty_directory_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(create(dir.path(), &CreateOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,1063 @@
+use crate::bitfield::Bitfield;
+use crate::error::Error;
+use crate::events::{EventKind, TorrentEvent};
+use crate::metrics::{Kind, Metrics};
+use crate::resume::ResumeData;
+use crate::session::{Session, TorrentState};
+use crate::snapshot::SessionSnapshot;
+use crate::stats::TorrentStats;
+use crate::torrent::Torrent;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+/// Where the daemon listens unless told otherwise.
+pub const DEFAULT_ENDPOINT: &str = "127.0.0.1:6880";
+
+// error codes from the JSON-RPC 2.0 spec; -32000 is the first one left to applications
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SESSION_ERROR: i64 = -32000;
+const ALREADY_ADDED: i64 = -32001;
+
+/// A local address to accept control connections on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    /// A Unix domain socket, written as `unix:<path>`.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Endpoint> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return Ok(Endpoint::Unix(PathBuf::from(path)));
+            #[cfg(not(unix))]
+            anyhow::bail!("unix sockets aren't supported here: {}", path);
+        }
+        s.parse().map(Endpoint::Tcp).map_err(|_| {
+            anyhow::anyhow!(
+                "unknown endpoint: {} (expected <ip>:<port> or unix:<path>)",
+                s
+            )
+        })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(address) => write!(f, "{}", address),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A torrent added through the daemon, and what its events told about it.
+struct Added {
+    torrent: Arc<Torrent>,
+    output: PathBuf,
+    have: Bitfield,
+    download_rate: usize,
+    upload_rate: usize,
+}
+
+type Torrents = Arc<Mutex<HashMap<[u8; 20], Added>>>;
+
+/// Drives a `Session` through JSON-RPC 2.0 requests, one per line, from local connections.
+///
+/// The methods are:
+/// - `add` with `torrent`, the path of a torrent file, and optionally `output`; the data goes
+///   to the download directory under the torrent's name by default. With `rename`, an object
+///   of paths inside the output, the files or directories at its keys are kept at its values
+///   instead. Returns the info hash. A torrent that was added already fails with error code
+///   -32001, and with `merge_trackers` set the one in the session gets the trackers it lacks
+///   first.
+/// - `remove`, `pause`, `resume` and `force_start` with the `info_hash` of a torrent. A
+///   resumed torrent waits in the queue if the session downloads as many as it may, and one
+///   started by force doesn't.
+/// - `set_queue_position` with the `info_hash` of a torrent and its new `position`, 0 for the
+///   front of the queue.
+/// - `status`, with the `info_hash` of a torrent or without to get all of them. Besides the
+///   torrent's progress it lists the connected peers, with what was transferred with each,
+///   and how much of it went to waste as `corrupt` and `redundant` bytes.
+/// - `set_download_limit` with `rate` in bytes per second, or null for no limit, and the
+///   `info_hash` of a torrent, or none to change the limit of the whole session.
+/// - `set_seed_limits` with the `info_hash` of a torrent and the share `ratio` and seeding
+///   `time` in seconds it seeds up to, either null for the daemon's own.
+/// - `stats`, for the counters of the whole session: how many ranges the IP filter has, and
+///   how many peers it kept out each way; and for `external_ips`, our address as the
+///   trackers and DHT nodes report it.
+/// - `export` with a `path` to write a `SessionSnapshot` of the session's torrents to, and
+///   `import` with the `path` of one to add them back, e.g. on another machine; with
+///   `output_dir`, their data is looked for there instead of where it was. Returns the info
+///   hashes it added.
+///
+/// The same state can be scraped by Prometheus, with `metrics::serve`.
+///
+/// Torrents are known by their info hash in hex.
+pub struct Daemon {
+    session: Session,
+    download_dir: PathBuf,
+    torrents: Torrents,
+    tracker: JoinHandle<()>,
+}
+
+impl Daemon {
+    pub fn new(session: Session, download_dir: &Path) -> Daemon {
+        let torrents = Torrents::default();
+        let tracker = tokio::spawn(track_progress(session.subscribe(), torrents.clone()));
+        Daemon {
+            session,
+            download_dir: download_dir.to_path_buf(),
+            torrents,
+            tracker,
+        }
+    }
+
+    /// Answers requests on `endpoint` until `shutdown` completes. A Unix socket is removed
+    /// again when it does.
+    pub async fn serve(
+        self: Arc<Self>,
+        endpoint: &Endpoint,
+        shutdown: impl Future<Output = ()>,
+    ) -> anyhow::Result<()> {
+        tokio::pin!(shutdown);
+        match endpoint {
+            Endpoint::Tcp(address) => {
+                let listener = TcpListener::bind(address).await?;
+                loop {
+                    tokio::select! {
+                        accepted = listener.accept() => {
+                            let (stream, _) = accepted?;
+                            tokio::spawn(self.clone().serve_connection(stream));
+                        }
+                        _ = &mut shutdown => return Ok(()),
+                    }
+                }
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                let listener = tokio::net::UnixListener::bind(path)?;
+                let result = loop {
+                    tokio::select! {
+                        accepted = listener.accept() => match accepted {
+                            Ok((stream, _)) => {
+                                tokio::spawn(self.clone().serve_connection(stream));
+                            }
+                            Err(e) => break Err(e.into()),
+                        },
+                        _ = &mut shutdown => break Ok(()),
+                    }
+                };
+                let _ = std::fs::remove_file(path);
+                result
+            }
+        }
+    }
+
+    async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(self: Arc<Self>, stream: S) {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.handle(&line) {
+                let mut response = response.to_string();
+                response.push('\n');
+                if writer.write_all(response.as_bytes()).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Answers one JSON-RPC message, a request or a batch of them. Notifications, which have
+    /// no id, get no answer.
+    pub fn handle(&self, message: &str) -> Option<Value> {
+        let message: Value = match serde_json::from_str(message) {
+            Ok(message) => message,
+            Err(e) => return Some(error_response(Value::Null, PARSE_ERROR, e.to_string())),
+        };
+        match message {
+            Value::Array(batch) if !batch.is_empty() => {
+                let responses: Vec<Value> = batch
+                    .into_iter()
+                    .filter_map(|request| self.handle_request(request))
+                    .collect();
+                (!responses.is_empty()).then_some(Value::Array(responses))
+            }
+            request => self.handle_request(request),
+        }
+    }
+
+    fn handle_request(&self, request: Value) -> Option<Value> {
+        let id = request.get("id").cloned();
+        let method = request.get("method").and_then(Value::as_str);
+        let (Some(method), Some("2.0")) = (method, request.get("jsonrpc").and_then(Value::as_str))
+        else {
+            let message = "expected a JSON-RPC 2.0 request".to_owned();
+            return Some(error_response(
+                id.unwrap_or_default(),
+                INVALID_REQUEST,
+                message,
+            ));
+        };
+        let params = request.get("params").cloned().unwrap_or(json!({}));
+        let result = self.call(method, params);
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err((code, message)) => error_response(id, code, message),
+        })
+    }
+
+    fn call(&self, method: &str, params: Value) -> Result<Value, (i64, String)> {
+        match method {
+            "add" => {
+                let params: AddParams = parse_params(params)?;
+                let mut torrent = Torrent::read(&params.torrent).map_err(session_error)?;
+                for (from, to) in &params.rename {
+                    torrent.rename(from, to).map_err(session_error)?;
+                }
+                let info_hash = self
+                    .add(torrent, params.output, params.merge_trackers)
+                    .map_err(session_error)?;
+                Ok(json!(hex::encode(info_hash)))
+            }
+            "remove" => {
+                let info_hash = parse_params::<TorrentParams>(params)?.info_hash()?;
+                self.session
+                    .remove_torrent(&info_hash)
+                    .map_err(session_error)?;
+                self.torrents.lock().unwrap().remove(&info_hash);
+                Ok(Value::Null)
+            }
+            "pause" => {
+                let info_hash = parse_params::<TorrentParams>(params)?.info_hash()?;
+                self.session.pause(&info_hash).map_err(session_error)?;
+                Ok(Value::Null)
+            }
+            "resume" => {
+                let info_hash = parse_params::<TorrentParams>(params)?.info_hash()?;
+                self.session.resume(&info_hash).map_err(session_error)?;
+                Ok(Value::Null)
+            }
+            "force_start" => {
+                let info_hash = parse_params::<TorrentParams>(params)?.info_hash()?;
+                self.session
+                    .force_start(&info_hash)
+                    .map_err(session_error)?;
+                Ok(Value::Null)
+            }
+            "set_queue_position" => {
+                let params: QueueParams = parse_params(params)?;
+                let info_hash = parse_info_hash(&params.info_hash)?;
+                self.session
+                    .set_queue_position(&info_hash, params.position)
+                    .map_err(session_error)?;
+                Ok(Value::Null)
+            }
+            "status" => {
+                let params: StatusParams = parse_params(params)?;
+                let torrents = self.torrents.lock().unwrap();
+                match params.info_hash {
+                    Some(info_hash) => {
+                        let info_hash = parse_info_hash(&info_hash)?;
+                        let added = torrents
+                            .get(&info_hash)
+                            .ok_or_else(|| session_error(Error::unknown_torrent(info_hash)))?;
+                        Ok(self.status(&info_hash, added))
+                    }
+                    None => {
+                        let mut all: Vec<_> = torrents.iter().collect();
+                        all.sort_by(|a, b| a.1.torrent.info.name.cmp(&b.1.torrent.info.name));
+                        let all = all
+                            .into_iter()
+                            .map(|(hash, added)| self.status(hash, added));
+                        Ok(Value::Array(all.collect()))
+                    }
+                }
+            }
+            "set_download_limit" => {
+                let params: LimitParams = parse_params(params)?;
+                match params.info_hash {
+                    Some(info_hash) => {
+                        let info_hash = parse_info_hash(&info_hash)?;
+                        self.session
+                            .set_download_limit(&info_hash, params.rate)
+                            .map_err(session_error)?;
+                    }
+                    None => self.session.set_global_download_limit(params.rate),
+                }
+                Ok(Value::Null)
+            }
+            "set_seed_limits" => {
+                let params: SeedLimitParams = parse_params(params)?;
+                let info_hash = parse_info_hash(&params.info_hash)?;
+                if params
+                    .ratio
+                    .is_some_and(|ratio| !(ratio.is_finite() && ratio > 0.0))
+                {
+                    return Err((INVALID_PARAMS, "the ratio must be above 0".to_owned()));
+                }
+                if params.time == Some(0) {
+                    return Err((INVALID_PARAMS, "the time must be at least 1s".to_owned()));
+                }
+                let time = params.time.map(Duration::from_secs);
+                self.session
+                    .set_seed_limits(&info_hash, params.ratio, time)
+                    .map_err(session_error)?;
+                Ok(Value::Null)
+            }
+            "stats" => {
+                let filter = self.session.filter_stats();
+                Ok(json!({
+                    "filter_ranges": filter.ranges,
+                    "blocked_inbound": filter.blocked_inbound,
+                    "blocked_outbound": filter.blocked_outbound,
+                    "external_ips": self.session.external_ips(),
+                }))
+            }
+            "export" => {
+                let params: PathParams = parse_params(params)?;
+                self.session
+                    .snapshot()
+                    .save(&params.path)
+                    .map_err(session_error)?;
+                Ok(Value::Null)
+            }
+            "import" => {
+                let params: ImportParams = parse_params(params)?;
+                let mut snapshot = SessionSnapshot::load(&params.path).map_err(session_error)?;
+                if let Some(dir) = &params.output_dir {
+                    snapshot.relocate(dir);
+                }
+                let info_hashes = self.import(snapshot).map_err(session_error)?;
+                Ok(json!(info_hashes
+                    .iter()
+                    .map(hex::encode)
+                    .collect::<Vec<_>>()))
+            }
+            method => Err((METHOD_NOT_FOUND, format!("unknown method: {}", method))),
+        }
+    }
+
+    /// The state of the session and of each torrent in it, for Prometheus; `metrics::serve`
+    /// sends it over HTTP.
+    pub fn metrics(&self) -> String {
+        struct Row {
+            info_hash: String,
+            name: String,
+            progress: f64,
+            download_rate: usize,
+            upload_rate: usize,
+            stats: TorrentStats,
+        }
+        let mut rows: Vec<Row> = {
+            let torrents = self.torrents.lock().unwrap();
+            torrents
+                .iter()
+                .map(|(info_hash, added)| {
+                    let length = added.torrent.total_length();
+                    let completed: usize = added
+                        .have
+                        .ones()
+                        .map(|index| added.torrent.piece_size(index))
+                        .sum();
+                    Row {
+                        info_hash: hex::encode(info_hash),
+                        name: added.torrent.info.name.clone(),
+                        progress: if length == 0 {
+                            1.0
+                        } else {
+                            completed as f64 / length as f64
+                        },
+                        download_rate: added.download_rate,
+                        upload_rate: added.upload_rate,
+                        stats: self.session.stats(info_hash).unwrap_or_default(),
+                    }
+                })
+                .collect()
+        };
+        rows.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut metrics = Metrics::new();
+        metrics.family(
+            "bittorrent_torrents",
+            Kind::Gauge,
+            "Torrents in the session.",
+        );
+        metrics.sample(&[], rows.len() as f64);
+        type Family = (&'static str, Kind, &'static str, fn(&Row) -> f64);
+        let families: [Family; 13] = [
+            (
+                "bittorrent_progress_ratio",
+                Kind::Gauge,
+                "Share of the torrent's bytes that are on disk.",
+                |row| row.progress,
+            ),
+            (
+                "bittorrent_download_rate_bytes",
+                Kind::Gauge,
+                "Bytes per second downloaded.",
+                |row| row.download_rate as f64,
+            ),
+            (
+                "bittorrent_upload_rate_bytes",
+                Kind::Gauge,
+                "Bytes per second uploaded.",
+                |row| row.upload_rate as f64,
+            ),
+            (
+                "bittorrent_downloaded_bytes_total",
+                Kind::Counter,
+                "Bytes of blocks downloaded since the torrent started.",
+                |row| row.stats.downloaded as f64,
+            ),
+            (
+                "bittorrent_uploaded_bytes_total",
+                Kind::Counter,
+                "Bytes of blocks uploaded since the torrent started.",
+                |row| row.stats.uploaded as f64,
+            ),
+            ("bittorrent_peers", Kind::Gauge, "Connected peers.", |row| {
+                row.stats.peers.len() as f64
+            }),
+            (
+                "bittorrent_hash_failures_total",
+                Kind::Counter,
+                "Pieces that failed their hash check.",
+                |row| row.stats.hash_failures as f64,
+            ),
+            (
+                "bittorrent_corrupt_bytes_total",
+                Kind::Counter,
+                "Bytes of pieces that failed their hash check.",
+                |row| row.stats.corrupt as f64,
+            ),
+            (
+                "bittorrent_redundant_bytes_total",
+                Kind::Counter,
+                "Bytes of blocks downloaded that had arrived already.",
+                |row| row.stats.redundant as f64,
+            ),
+            (
+                "bittorrent_disk_queue",
+                Kind::Gauge,
+                "Requests waiting for the torrent's disk task.",
+                |row| row.stats.disk_queue as f64,
+            ),
+            (
+                "bittorrent_cache_hits_total",
+                Kind::Counter,
+                "Pieces uploaded from that were in memory.",
+                |row| row.stats.cache.hits as f64,
+            ),
+            (
+                "bittorrent_cache_misses_total",
+                Kind::Counter,
+                "Pieces uploaded from that had to be read from disk.",
+                |row| row.stats.cache.misses as f64,
+            ),
+            (
+                "bittorrent_cache_bytes",
+                Kind::Gauge,
+                "Bytes of the pieces kept in memory.",
+                |row| row.stats.cache.size as f64,
+            ),
+        ];
+        for (name, kind, help, value) in families {
+            metrics.family(name, kind, help);
+            for row in &rows {
+                let labels = [("info_hash", row.info_hash.as_str()), ("name", &row.name)];
+                metrics.sample(&labels, value(row));
+            }
+        }
+        if let Some(nodes) = self.session.dht_nodes() {
+            metrics.family(
+                "bittorrent_dht_nodes",
+                Kind::Gauge,
+                "Nodes in the DHT routing table.",
+            );
+            metrics.sample(&[], nodes as f64);
+        }
+        let filter = self.session.filter_stats();
+        metrics.family(
+            "bittorrent_blocked_peers_total",
+            Kind::Counter,
+            "Peer connections the IP filter kept out.",
+        );
+        metrics.sample(&[("direction", "inbound")], filter.blocked_inbound as f64);
+        metrics.sample(&[("direction", "outbound")], filter.blocked_outbound as f64);
+        metrics.finish()
+    }
+
+    /// The session the daemon's torrents run in.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Starts downloading `torrent` to `output`, or under its name in the download directory,
+    /// returning its info hash. A torrent that was added already fails with
+    /// `Error::AlreadyAdded`, after taking over the trackers of `torrent` it lacks if
+    /// `merge_trackers` is set.
+    pub(crate) fn add(
+        &self,
+        torrent: Torrent,
+        output: Option<PathBuf>,
+        merge_trackers: bool,
+    ) -> crate::Result<[u8; 20]> {
+        let output = output.unwrap_or_else(|| self.download_dir.join(&torrent.info.name));
+        let info_hash = torrent.info_hash();
+        let mut torrents = self.torrents.lock().unwrap();
+        if let Some(added) = torrents.get_mut(&info_hash) {
+            if !merge_trackers {
+                return Err(Error::already_added(info_hash, Vec::new()));
+            }
+            Arc::make_mut(&mut added.torrent).merge_trackers(&torrent);
+            return self
+                .session
+                .add_or_merge(torrent, &output)
+                .map(|_| info_hash);
+        }
+        let added = Added {
+            torrent: Arc::new(torrent.clone()),
+            output: output.clone(),
+            have: Bitfield::new(torrent.piece_count()),
+            download_rate: 0,
+            upload_rate: 0,
+        };
+        // registered first, so the pieces found on disk as it starts are counted
+        torrents.insert(info_hash, added);
+        if let Err(e) = self.session.add_torrent(torrent, &output) {
+            torrents.remove(&info_hash);
+            return Err(e);
+        }
+        Ok(info_hash)
+    }
+
+    /// Adds the torrents of `snapshot` to the session, as `Session::restore` does.
+    fn import(&self, snapshot: SessionSnapshot) -> crate::Result<Vec<[u8; 20]>> {
+        let mut torrents = self.torrents.lock().unwrap();
+        let mut imported = Vec::new();
+        for restored in &snapshot.torrents {
+            let info_hash = restored.torrent.info_hash();
+            if torrents.contains_key(&info_hash) {
+                continue;
+            }
+            let added = Added {
+                torrent: Arc::new(restored.torrent.clone()),
+                output: restored.output.clone(),
+                have: Bitfield::new(restored.torrent.piece_count()),
+                download_rate: 0,
+                upload_rate: 0,
+            };
+            torrents.insert(info_hash, added);
+            imported.push(info_hash);
+        }
+        let restored = self.session.restore(snapshot);
+        if restored.is_err() {
+            for info_hash in &imported {
+                torrents.remove(info_hash);
+            }
+        }
+        restored
+    }
+
+    fn status(&self, info_hash: &[u8; 20], added: &Added) -> Value {
+        let state = self
+            .session
+            .state(info_hash)
+            .map(|state| state.borrow().clone());
+        let (state, error) = match state {
+            Some(TorrentState::Queued) => ("queued", None),
+            Some(TorrentState::Downloading) => ("downloading", None),
+            Some(TorrentState::Paused) => ("paused", None),
+            Some(TorrentState::Finished) => ("finished", None),
+            Some(TorrentState::Seeding) => ("seeding", None),
+            Some(TorrentState::Failed(error)) => ("failed", Some(error)),
+            None => ("removed", None),
+        };
+        let stats = self.session.stats(info_hash).unwrap_or_default();
+        let peers: Vec<Value> = stats
+            .peers
+            .iter()
+            .map(|peer| {
+                json!({
+                    "address": peer.addr.to_string(),
+                    "client": peer.client,
+                    "downloaded": peer.downloaded,
+                    "uploaded": peer.uploaded,
+                    "download_rate": peer.download_rate,
+                    "upload_rate": peer.upload_rate,
+                    "round_trip_ms": peer.round_trip.map(|round_trip| round_trip.as_millis() as u64),
+                    "hash_failures": peer.hash_failures,
+                    "corrupt": peer.corrupt,
+                    "redundant": peer.redundant,
+                    "waste_percent": peer.waste(),
+                })
+            })
+            .collect();
+        let downloaded: usize = added
+            .have
+            .ones()
+            .map(|index| added.torrent.piece_size(index))
+            .sum();
+        // every piece we are missing is on some connected peer
+        let completable = added.have.zeros().all(|index| {
+            stats
+                .availability
+                .get(index)
+                .is_some_and(|&copies| copies > 0)
+        });
+        json!({
+            "info_hash": hex::encode(info_hash),
+            "name": added.torrent.info.name,
+            "output": added.output,
+            "state": state,
+            "error": error,
+            "queue_position": self.session.queue_position(info_hash),
+            "length": added.torrent.total_length(),
+            "downloaded": downloaded,
+            "uploaded": stats.uploaded,
+            "download_rate": added.download_rate,
+            "upload_rate": added.upload_rate,
+            "hash_failures": stats.hash_failures,
+            "corrupt": stats.corrupt,
+            "redundant": stats.redundant,
+            "waste_percent": stats.waste(),
+            "distributed_copies": stats.distributed_copies(),
+            "completable": completable,
+            "availability": stats.availability,
+            "cache": {
+                "hits": stats.cache.hits,
+                "misses": stats.cache.misses,
+                "size": stats.cache.size,
+                "budget": stats.cache.budget,
+            },
+            "peers": peers,
+        })
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        self.tracker.abort();
+    }
+}
+
+/// Keeps the pieces and transfer rates of the torrents in `torrents` up to date.
+async fn track_progress(mut events: broadcast::Receiver<TorrentEvent>, torrents: Torrents) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            // the resume data has every piece written so far, including the missed ones
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                let added: Vec<_> = {
+                    let torrents = torrents.lock().unwrap();
+                    let added = torrents.iter();
+                    added
+                        .map(|(hash, added)| (*hash, added.torrent.clone(), added.output.clone()))
+                        .collect()
+                };
+                for (info_hash, torrent, output) in added {
+                    let Some(resume) = ResumeData::load(&torrent, &output).await else {
+                        continue;
+                    };
+                    if let Some(added) = torrents.lock().unwrap().get_mut(&info_hash) {
+                        let pieces = added.have.len();
+                        added
+                            .have
+                            .union_with(&Bitfield::from_bytes(&resume.pieces, pieces));
+                    }
+                }
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let mut torrents = torrents.lock().unwrap();
+        let Some(added) = torrents.get_mut(&event.info_hash) else {
+            continue;
+        };
+        match event.kind {
+            EventKind::PieceCompleted(index) => added.have.set(index),
+            EventKind::Throughput {
+                download_rate,
+                upload_rate,
+            } => {
+                added.download_rate = download_rate;
+                added.upload_rate = upload_rate;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AddParams {
+    torrent: PathBuf,
+    output: Option<PathBuf>,
+    #[serde(default)]
+    rename: BTreeMap<PathBuf, PathBuf>,
+    #[serde(default)]
+    merge_trackers: bool,
+}
+
+#[derive(Deserialize)]
+struct TorrentParams {
+    info_hash: String,
+}
+
+impl TorrentParams {
+    fn info_hash(&self) -> Result<[u8; 20], (i64, String)> {
+        parse_info_hash(&self.info_hash)
+    }
+}
+
+#[derive(Deserialize)]
+struct StatusParams {
+    info_hash: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LimitParams {
+    info_hash: Option<String>,
+    rate: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct SeedLimitParams {
+    info_hash: String,
+    ratio: Option<f64>,
+    /// Seconds.
+    time: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct PathParams {
+    path: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct ImportParams {
+    path: PathBuf,
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct QueueParams {
+    info_hash: String,
+    position: usize,
+}
+
+fn parse_params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, (i64, String)> {
+    serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+fn parse_info_hash(hash: &str) -> Result<[u8; 20], (i64, String)> {
+    let mut info_hash = [0; 20];
+    hex::decode_to_slice(hash, &mut info_hash)
+        .map_err(|_| (INVALID_PARAMS, format!("invalid info hash: {}", hash)))?;
+    Ok(info_hash)
+}
+
+/// Sends one request to the daemon at `endpoint`, returning its result or failing with the
+/// error it answered with.
+pub async fn call(endpoint: &Endpoint, method: &str, params: Value) -> anyhow::Result<Value> {
+    let request = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 });
+    let connect_error = |e| anyhow::anyhow!("can't connect to the daemon at {}: {}", endpoint, e);
+    let response = match endpoint {
+        Endpoint::Tcp(address) => {
+            let stream = tokio::net::TcpStream::connect(address)
+                .await
+                .map_err(connect_error)?;
+            exchange(stream, &request).await?
+        }
+        #[cfg(unix)]
+        Endpoint::Unix(path) => {
+            let stream = tokio::net::UnixStream::connect(path)
+                .await
+                .map_err(connect_error)?;
+            exchange(stream, &request).await?
+        }
+    };
+    if let Some(error) = response.get("error") {
+        let message = error["message"].as_str().unwrap_or("unknown error");
+        anyhow::bail!("{}", message);
+    }
+    Ok(response["result"].clone())
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    request: &Value,
+) -> anyhow::Result<Value> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut line = request.to_string();
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+    let response = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("the daemon closed the connection without answering"))?;
+    Ok(serde_json::from_str(&response)?)
+}
+
+fn session_error(error: impl Into<anyhow::Error>) -> (i64, String) {
+    let error = error.into();
+    let code = match Error::find(&error) {
+        Some(Error::AlreadyAdded { .. }) => ALREADY_ADDED,
+        _ => SESSION_ERROR,
+    };
+    (code, format!("{:#}", error))
+}
+
+fn error_response(id: Value, code: i64, message: String) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{
+        announce_body, spawn_seeder, spawn_tracker, test_data, torrent_for,
+    };
+    use crate::download::DownloadOptions;
+    use crate::tracker::tests::unreachable_tracker;
+    use std::time::Duration;
+
+    async fn daemon(dir: &Path) -> Daemon {
+        let options = DownloadOptions {
+            port: 0,
+            ..DownloadOptions::default()
+        };
+        Daemon::new(Session::new([1; 20], options).await.unwrap(), dir)
+    }
+
+    fn request(daemon: &Daemon, method: &str, params: Value) -> Value {
+        let request = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 });
+        daemon.handle(&request.to_string()).unwrap()
+    }
+
+    #[test]
+    fn parse_endpoint() {
+        assert_eq!(
+            "127.0.0.1:6880".parse::<Endpoint>().unwrap(),
+            Endpoint::Tcp("127.0.0.1:6880".parse().unwrap())
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            "unix:/tmp/client.sock".parse::<Endpoint>().unwrap(),
+            Endpoint::Unix(PathBuf::from("/tmp/client.sock"))
+        );
+        assert!("localhost".parse::<Endpoint>().is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let daemon = daemon(dir.path()).await;
+        let code = |response: Value| response["error"]["code"].as_i64().unwrap();
+
+        assert_eq!(code(daemon.handle("{").unwrap()), PARSE_ERROR);
+        assert_eq!(
+            code(daemon.handle(r#"{"method": "status"}"#).unwrap()),
+            INVALID_REQUEST
+        );
+        assert_eq!(code(request(&daemon, "start", json!({}))), METHOD_NOT_FOUND);
+        assert_eq!(code(request(&daemon, "pause", json!({}))), INVALID_PARAMS);
+        let params = json!({ "info_hash": "00".repeat(20) });
+        assert_eq!(code(request(&daemon, "pause", params)), SESSION_ERROR);
+        let params = json!({ "torrent": dir.path().join("missing.torrent") });
+        assert_eq!(code(request(&daemon, "add", params)), SESSION_ERROR);
+        // notifications get no answer, even when they fail
+        let notification = json!({ "jsonrpc": "2.0", "method": "start" });
+        assert!(daemon.handle(&notification.to_string()).is_none());
+
+        let batch = json!([
+            { "jsonrpc": "2.0", "method": "status", "id": 1 },
+            { "jsonrpc": "2.0", "method": "set_download_limit", "params": { "rate": 1000 } },
+        ]);
+        let responses = daemon.handle(&batch.to_string()).unwrap();
+        assert_eq!(
+            responses,
+            json!([{ "jsonrpc": "2.0", "result": [], "id": 1 }])
+        );
+    }
+
+    #[tokio::test]
+    async fn controls_torrents() {
+        let data = test_data(50_000);
+        let mut torrent = torrent_for(&data, 16384);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let (url, _) = spawn_tracker(vec![announce_body(60, &[seeder])]).await;
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.torrent");
+        std::fs::write(&path, torrent.to_bytes()).unwrap();
+        let daemon = daemon(dir.path()).await;
+
+        let info_hash = request(&daemon, "add", json!({ "torrent": path }))["result"].clone();
+        assert_eq!(info_hash, hex::encode(torrent.info_hash()));
+        let again = request(&daemon, "add", json!({ "torrent": path }));
+        assert_eq!(again["error"]["code"], ALREADY_ADDED);
+        let mut more_trackers = torrent.clone();
+        more_trackers.announce_list =
+            vec![vec![torrent.announce.clone()], vec![unreachable_tracker()]];
+        let more_path = dir.path().join("more.torrent");
+        std::fs::write(&more_path, more_trackers.to_bytes()).unwrap();
+        let params = json!({ "torrent": more_path, "merge_trackers": true });
+        let merged = request(&daemon, "add", params);
+        assert_eq!(merged["error"]["code"], ALREADY_ADDED);
+        let message = merged["error"]["message"].as_str().unwrap();
+        assert!(
+            message.contains(&more_trackers.announce_list[1][0]),
+            "{}",
+            message
+        );
+        let params = json!({ "info_hash": info_hash, "rate": null });
+        assert!(request(&daemon, "set_download_limit", params)["error"].is_null());
+
+        let status = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let status = request(&daemon, "status", json!({ "info_hash": info_hash }));
+                if status["result"]["state"] == "finished" {
+                    return status["result"].clone();
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(status["name"], "test.bin");
+        assert_eq!(status["downloaded"], 50_000);
+        assert_eq!(status["length"], 50_000);
+        assert_eq!(status["hash_failures"], 0);
+        assert_eq!(status["waste_percent"], 0.0);
+        assert_eq!(status["completable"], true);
+        assert!(status["distributed_copies"].is_number());
+        assert!(status["cache"]["budget"].is_number());
+        assert!(status["peers"].is_array());
+        assert_eq!(std::fs::read(dir.path().join("test.bin")).unwrap(), data);
+
+        let metrics = daemon.metrics();
+        let labels = format!(
+            "{{info_hash=\"{}\",name=\"test.bin\"}}",
+            info_hash.as_str().unwrap()
+        );
+        assert!(metrics.contains(&format!("\nbittorrent_progress_ratio{} 1\n", labels)));
+        assert!(metrics.contains(&format!("\nbittorrent_hash_failures_total{} 0\n", labels)));
+        assert!(metrics.contains("\nbittorrent_torrents 1\n"));
+        assert!(metrics.contains(&format!("\nbittorrent_cache_misses_total{} ", labels)));
+
+        let stats = request(&daemon, "stats", json!({}))["result"].clone();
+        assert_eq!(
+            stats,
+            json!({
+                "filter_ranges": 0,
+                "blocked_inbound": 0,
+                "blocked_outbound": 0,
+                // the test tracker doesn't say
+                "external_ips": [],
+            })
+        );
+
+        let exported = dir.path().join("session");
+        let params = json!({ "path": exported });
+        assert!(request(&daemon, "export", params)["error"].is_null());
+        let moved = tempfile::tempdir().unwrap();
+        let other = self::daemon(moved.path()).await;
+        let params = json!({ "path": exported, "output_dir": moved.path() });
+        let imported = request(&other, "import", params.clone())["result"].clone();
+        assert_eq!(imported, json!([info_hash]));
+        let status = request(&other, "status", json!({ "info_hash": info_hash }));
+        assert_eq!(
+            status["result"]["output"],
+            json!(moved.path().join("test.bin"))
+        );
+        assert_eq!(
+            request(&other, "import", params)["error"]["code"],
+            ALREADY_ADDED
+        );
+
+        let params = json!({ "info_hash": info_hash });
+        assert!(request(&daemon, "remove", params.clone())["error"].is_null());
+        assert_eq!(request(&daemon, "status", json!({}))["result"], json!([]));
+        assert_eq!(
+            request(&daemon, "resume", params)["error"]["code"],
+            SESSION_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let daemon = Arc::new(daemon(dir.path()).await);
+        let mut endpoints = vec![Endpoint::Tcp("127.0.0.1:0".parse().unwrap())];
+        #[cfg(unix)]
+        endpoints.push(Endpoint::Unix(dir.path().join("client.sock")));
+        for endpoint in endpoints {
+            // an ephemeral TCP port isn't known until bound, so find a free one first
+            let endpoint = match endpoint {
+                Endpoint::Tcp(address) => {
+                    let listener = std::net::TcpListener::bind(address).unwrap();
+                    Endpoint::Tcp(listener.local_addr().unwrap())
+                }
+                endpoint => endpoint,
+            };
+            let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+            let server = tokio::spawn({
+                let (daemon, endpoint) = (daemon.clone(), endpoint.clone());
+                async move {
+                    let shutdown = async {
+                        let _ = stopped.await;
+                    };
+                    daemon.serve(&endpoint, shutdown).await
+                }
+            });
+            let result = loop {
+                match call(&endpoint, "status", json!({})).await {
+                    Ok(result) => break result,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+            assert_eq!(result, json!([]));
+            let unknown = call(&endpoint, "pause", json!({ "info_hash": "00" })).await;
+            assert!(unknown
+                .unwrap_err()
+                .to_string()
+                .contains("invalid info hash"));
+            stop.send(()).unwrap();
+            server.await.unwrap().unwrap();
+            #[cfg(unix)]
+            if let Endpoint::Unix(path) = endpoint {
+                assert!(!path.exists());
+            }
+        }
+    }
+}
@@ -0,0 +1,1608 @@
+use crate::bencode;
+use crate::external_ip::{ExternalIp, Voter};
+use crate::net::{self, Bind};
+use crate::tracker::{Peers, Progress, ScrapeStats};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Well-known nodes to join the DHT through when we don't know any other node yet.
+pub const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+/// How often a download looks up and announces its info hash again.
+pub const REANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// How soon a lookup that found no peers is tried again, e.g. while still bootstrapping.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// Nodes per bucket, and the number of closest nodes a lookup converges on (k in Kademlia).
+const K: usize = 8;
+/// Queries a lookup keeps in flight at once (alpha in Kademlia).
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// A node we haven't heard from for this long is questionable and gets pinged.
+const NODE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+/// A node that failed to answer this many queries in a row is dropped from the routing table.
+const MAX_FAILURES: u32 = 2;
+/// How often questionable nodes are pinged and stale buckets refreshed.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+/// Tokens are derived from a secret that changes this often; the previous one stays valid.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Peers announced to us are forgotten after this long.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Bits of the bloom filters a scrape (BEP 33) estimates the size of a swarm from.
+const BLOOM_FILTER_BITS: usize = 2048;
+
+const ERROR_PROTOCOL: i64 = 203;
+const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// A DHT node: its id and where it listens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Node {
+    pub id: [u8; 20],
+    pub addr: SocketAddr,
+}
+
+/// The DHT of IPv4 nodes, or the separate one of IPv6 nodes (BEP 32), each with a routing
+/// table of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    fn of(addr: &SocketAddr) -> Family {
+        match addr {
+            SocketAddr::V4(_) => Family::V4,
+            SocketAddr::V6(_) => Family::V6,
+        }
+    }
+
+    /// What a query puts in `want` to get nodes of the family.
+    fn name(self) -> &'static str {
+        match self {
+            Family::V4 => "n4",
+            Family::V6 => "n6",
+        }
+    }
+
+    /// Bytes of compact node info per node: the id, the address and the port.
+    fn compact_length(self) -> usize {
+        match self {
+            Family::V4 => 26,
+            Family::V6 => 38,
+        }
+    }
+}
+
+/// A KRPC message (BEP 5): a query, a reply or an error, told apart by `y`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+struct Message {
+    /// Transaction id, echoed back in the reply.
+    t: ByteBuf,
+    y: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    a: Option<Arguments>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    r: Option<Reply>,
+    /// Error code and message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    e: Option<(i64, String)>,
+    /// In a reply, the compact address the query came from as the replying node saw it
+    /// (BEP 42), which tells us our external address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip: Option<ByteBuf>,
+}
+
+/// The arguments of every query type; which ones are set depends on the query.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+struct Arguments {
+    id: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    /// 1 if the peer's port is the one the query came from rather than `port`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    implied_port: Option<u8>,
+    /// The families of nodes a `find_node` or `get_peers` wants back, `n4` and `n6`; the
+    /// family the query came over if not given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    want: Option<Vec<String>>,
+    /// 1 to have `get_peers` also return the bloom filters of the swarm (BEP 33).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scrape: Option<u8>,
+    /// 1 to have `get_peers` return no seeds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    noseed: Option<u8>,
+    /// 1 if the peer `announce_peer` announces is a seed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+struct Reply {
+    id: ByteBuf,
+    /// Compact node info: 20 bytes of id, 4 of IPv4 address and 2 of port per node.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nodes: Option<ByteBuf>,
+    /// The same for IPv6 nodes, with 16 bytes of address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nodes6: Option<ByteBuf>,
+    /// Compact peer addresses, one per string.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    /// For a scrape, the bloom filter of the seeds that announced to the node.
+    #[serde(rename = "BFsd", skip_serializing_if = "Option::is_none")]
+    seeds: Option<ByteBuf>,
+    /// For a scrape, the bloom filter of the other peers that announced to the node.
+    #[serde(rename = "BFpe", skip_serializing_if = "Option::is_none")]
+    leechers: Option<ByteBuf>,
+}
+
+impl Reply {
+    /// The nodes of `family` the reply lists.
+    fn nodes(&self, family: Family) -> Vec<Node> {
+        let nodes = match family {
+            Family::V4 => &self.nodes,
+            Family::V6 => &self.nodes6,
+        };
+        nodes
+            .as_deref()
+            .map(|nodes| parse_nodes(nodes, family))
+            .unwrap_or_default()
+    }
+}
+
+impl Message {
+    fn query(transaction: &[u8], method: &str, arguments: Arguments) -> Message {
+        Message {
+            t: ByteBuf::from(transaction),
+            y: "q".to_owned(),
+            q: Some(method.to_owned()),
+            a: Some(arguments),
+            r: None,
+            e: None,
+            ip: None,
+        }
+    }
+
+    /// A reply to a query from `to`.
+    fn reply(transaction: ByteBuf, reply: Reply, to: SocketAddr) -> Message {
+        let ip = net::compact_addr(to);
+        Message {
+            t: transaction,
+            y: "r".to_owned(),
+            q: None,
+            a: None,
+            r: Some(reply),
+            e: None,
+            ip: Some(ByteBuf::from(ip)),
+        }
+    }
+
+    fn error(transaction: ByteBuf, code: i64, message: &str) -> Message {
+        Message {
+            t: transaction,
+            y: "e".to_owned(),
+            q: None,
+            a: None,
+            r: None,
+            e: Some((code, message.to_owned())),
+            ip: None,
+        }
+    }
+}
+
+fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+fn node_id(bytes: &[u8]) -> anyhow::Result<[u8; 20]> {
+    bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("node id is {} bytes long, not 20", bytes.len()))
+}
+
+/// The compact node info of those of `nodes` in `family`.
+fn compact_nodes(nodes: &[Node], family: Family) -> Vec<u8> {
+    let nodes = nodes.iter().filter(|node| Family::of(&node.addr) == family);
+    let mut compact = Vec::with_capacity(nodes.clone().count() * family.compact_length());
+    for node in nodes {
+        compact.extend_from_slice(&node.id);
+        compact.extend_from_slice(&net::compact_addr(node.addr));
+    }
+    compact
+}
+
+fn compact_ip(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn parse_nodes(bytes: &[u8], family: Family) -> Vec<Node> {
+    // a truncated entry at the end is ignored rather than failing the whole reply
+    bytes
+        .chunks_exact(family.compact_length())
+        .map(|chunk| {
+            let (id, addr) = chunk.split_at(20);
+            Node {
+                id: id.try_into().unwrap(),
+                addr: net::parse_compact_addr(addr).unwrap(),
+            }
+        })
+        .collect()
+}
+
+/// A bloom filter of IP addresses (BEP 33): each sets two of its bits, picked by the SHA-1
+/// of the address, so a few hundred bytes stand for the peers of a swarm however many they
+/// are, and the filters of several nodes merge by or-ing them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BloomFilter([u8; BLOOM_FILTER_BITS / 8]);
+
+impl BloomFilter {
+    fn new() -> BloomFilter {
+        BloomFilter([0; BLOOM_FILTER_BITS / 8])
+    }
+
+    /// The filter a node sent, `None` if it isn't as long as it has to be.
+    fn from_bytes(bytes: &[u8]) -> Option<BloomFilter> {
+        Some(BloomFilter(bytes.try_into().ok()?))
+    }
+
+    fn insert(&mut self, ip: IpAddr) {
+        let hash = Sha1::digest(compact_ip(ip));
+        for pair in [[hash[0], hash[1]], [hash[2], hash[3]]] {
+            let bit = u16::from_le_bytes(pair) as usize % BLOOM_FILTER_BITS;
+            self.0[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    fn union_with(&mut self, other: &BloomFilter) {
+        for (ours, theirs) in self.0.iter_mut().zip(other.0) {
+            *ours |= theirs;
+        }
+    }
+
+    /// How many addresses were likely inserted, from the share of bits still clear.
+    fn estimate(&self) -> usize {
+        let set: u32 = self.0.iter().map(|byte| byte.count_ones()).sum();
+        let bits = BLOOM_FILTER_BITS as f64;
+        // a full filter only says that there are at least this many
+        let clear = (bits - set as f64).max(1.0);
+        ((clear / bits).ln() / (2.0 * (1.0 - 1.0 / bits).ln())).round() as usize
+    }
+}
+
+struct Contact {
+    node: Node,
+    last_seen: Instant,
+    /// Queries in a row the node didn't answer.
+    failures: u32,
+}
+
+struct Bucket {
+    contacts: Vec<Contact>,
+    last_changed: Instant,
+}
+
+/// The nodes we know, in one bucket of at most `K` per length of the id prefix they share
+/// with ours, so we know many nodes close to us and a few far away.
+struct RoutingTable {
+    id: [u8; 20],
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    fn new(id: [u8; 20]) -> RoutingTable {
+        let now = Instant::now();
+        RoutingTable {
+            id,
+            buckets: (0..160)
+                .map(|_| Bucket {
+                    contacts: Vec::new(),
+                    last_changed: now,
+                })
+                .collect(),
+        }
+    }
+
+    /// The bucket a node belongs in, or `None` for our own id.
+    fn bucket_index(&self, id: &[u8; 20]) -> Option<usize> {
+        let distance = distance(&self.id, id);
+        let byte = distance.iter().position(|&byte| byte != 0)?;
+        Some(byte * 8 + distance[byte].leading_zeros() as usize)
+    }
+
+    /// Records that we heard from `node`. A new node only gets into a full bucket by taking
+    /// the place of one that stopped answering.
+    fn insert(&mut self, node: Node) {
+        let Some(index) = self.bucket_index(&node.id) else {
+            return;
+        };
+        let now = Instant::now();
+        let bucket = &mut self.buckets[index];
+        if let Some(contact) = bucket.contacts.iter_mut().find(|c| c.node.id == node.id) {
+            contact.node.addr = node.addr;
+            contact.last_seen = now;
+            contact.failures = 0;
+        } else if bucket.contacts.len() < K {
+            bucket.contacts.push(Contact {
+                node,
+                last_seen: now,
+                failures: 0,
+            });
+        } else if let Some(contact) = bucket
+            .contacts
+            .iter_mut()
+            .filter(|contact| contact.failures > 0)
+            .max_by_key(|contact| contact.failures)
+        {
+            *contact = Contact {
+                node,
+                last_seen: now,
+                failures: 0,
+            };
+        } else {
+            return;
+        }
+        bucket.last_changed = now;
+    }
+
+    /// Counts a query the node at `addr` didn't answer, dropping it after `MAX_FAILURES`.
+    fn failed(&mut self, addr: SocketAddr) {
+        for bucket in &mut self.buckets {
+            for contact in &mut bucket.contacts {
+                if contact.node.addr == addr {
+                    contact.failures += 1;
+                }
+            }
+            bucket
+                .contacts
+                .retain(|contact| contact.failures < MAX_FAILURES);
+        }
+    }
+
+    /// The `count` known nodes closest to `target`, closest first.
+    fn closest(&self, target: &[u8; 20], count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.nodes().collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = Node> + '_ {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.contacts.iter().map(|contact| contact.node))
+    }
+
+    fn len(&self) -> usize {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.contacts.len())
+            .sum()
+    }
+
+    /// Nodes we haven't heard from in a while.
+    fn questionable(&self) -> Vec<Node> {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| &bucket.contacts)
+            .filter(|contact| contact.last_seen.elapsed() >= NODE_TIMEOUT)
+            .map(|contact| contact.node)
+            .collect()
+    }
+
+    /// A random id for each non-empty bucket that hasn't changed in a while; looking it up
+    /// fills the bucket with fresh nodes.
+    fn refresh_targets(&self) -> Vec<[u8; 20]> {
+        (0..self.buckets.len())
+            .filter(|&index| {
+                let bucket = &self.buckets[index];
+                !bucket.contacts.is_empty() && bucket.last_changed.elapsed() >= NODE_TIMEOUT
+            })
+            .map(|index| {
+                // share the first `index` bits with our id, differ in the next one
+                let mut target: [u8; 20] = rand::random();
+                for bit in 0..=index {
+                    let mask = 0x80 >> (bit % 8);
+                    let ours = self.id[bit / 8] & mask;
+                    let wanted = if bit == index { ours ^ mask } else { ours };
+                    target[bit / 8] = (target[bit / 8] & !mask) | wanted;
+                }
+                target
+            })
+            .collect()
+    }
+}
+
+/// The secrets announce tokens are derived from.
+struct Secrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+}
+
+impl Secrets {
+    fn rotate_if_due(&mut self) {
+        if self.rotated.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current = rand::random();
+            self.rotated = Instant::now();
+        }
+    }
+}
+
+fn token(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    hasher.update(compact_ip(ip));
+    hasher.finalize().to_vec()
+}
+
+/// What a node keeps between runs: its id, and the nodes of its routing tables in compact
+/// form.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+struct SavedState {
+    id: ByteBuf,
+    nodes: ByteBuf,
+    #[serde(default)]
+    nodes6: ByteBuf,
+}
+
+/// A peer that announced itself to us.
+struct Announced {
+    at: Instant,
+    seed: bool,
+}
+
+struct Shared {
+    id: [u8; 20],
+    /// Where the id and the routing tables are saved, if they are.
+    state_path: Option<PathBuf>,
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    /// The families of nodes the socket reaches: both for a dual-stack one.
+    families: Vec<Family>,
+    table: Mutex<RoutingTable>,
+    table6: Mutex<RoutingTable>,
+    /// Queries waiting for their reply, by transaction id.
+    pending: Mutex<HashMap<[u8; 2], Pending>>,
+    next_transaction: AtomicU16,
+    /// Peers that announced themselves to us, by info hash.
+    peers: Mutex<HashMap<[u8; 20], HashMap<SocketAddr, Announced>>>,
+    secrets: Mutex<Secrets>,
+    /// What the nodes that replied to us say our address is.
+    external_ip: ExternalIp,
+}
+
+struct Pending {
+    /// The node the query went to; only it may answer.
+    addr: SocketAddr,
+    reply: oneshot::Sender<anyhow::Result<Reply>>,
+}
+
+/// What an iterative lookup asks the nodes closest to its target for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Find {
+    Nodes,
+    Peers,
+    /// The peers, and the bloom filters of the swarm.
+    Scrape,
+}
+
+/// What an iterative lookup found.
+struct Lookup {
+    /// The closest nodes that answered, with the token they gave us for announcing.
+    closest: Vec<(Node, Option<ByteBuf>)>,
+    peers: Vec<SocketAddr>,
+    /// For a scrape, what the closest nodes' bloom filters of seeds and other peers add up to.
+    seeds: BloomFilter,
+    leechers: BloomFilter,
+}
+
+/// A node of the mainline DHT (BEP 5), for finding peers without a tracker.
+///
+/// It answers the queries of other nodes and keeps its routing table fresh in the background
+/// for as long as it lives. On a dual-stack socket it is a node of both the IPv4 and the
+/// IPv6 DHT (BEP 32), with a routing table for each; lookups go through both and return the
+/// peers of both families.
+pub struct Dht {
+    shared: Arc<Shared>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Dht {
+    /// Starts a node with a random id on a UDP socket bound to `addr`, of the DHT of its
+    /// address family. It knows no other nodes until it is bootstrapped.
+    pub async fn bind(addr: impl ToSocketAddrs) -> anyhow::Result<Dht> {
+        Dht::start(UdpSocket::bind(addr).await?, None).await
+    }
+
+    /// Like `bind`, but with the id and the nodes saved at `path` by an earlier run, so the
+    /// node keeps its identity and can rejoin through nodes it knew. A missing or unreadable
+    /// file gives a new id. The state is saved back there every `MAINTENANCE_INTERVAL`, and
+    /// by `save`.
+    pub async fn bind_with_state(addr: impl ToSocketAddrs, path: &Path) -> anyhow::Result<Dht> {
+        Dht::start(UdpSocket::bind(addr).await?, Some(path.to_owned())).await
+    }
+
+    /// Starts a node of both the IPv4 and the IPv6 DHT on `port` of every address, or of
+    /// the IPv4 one only where there is no IPv6; with the state at `state_path` like
+    /// `bind_with_state` if given. With `bind`, it is a node of the DHT of the family of the
+    /// address of `bind` only.
+    pub async fn bind_dual_stack(
+        port: u16,
+        state_path: Option<&Path>,
+        bind: Option<&Bind>,
+    ) -> anyhow::Result<Dht> {
+        let socket = net::bind_udp_on(port, bind)?;
+        Dht::start(socket, state_path.map(Path::to_owned)).await
+    }
+
+    async fn start(socket: UdpSocket, state_path: Option<PathBuf>) -> anyhow::Result<Dht> {
+        let saved = match &state_path {
+            Some(path) => tokio::fs::read(path)
+                .await
+                .ok()
+                .and_then(|bytes| bencode::from_bytes::<SavedState>(&bytes).ok())
+                .and_then(|state| Some((node_id(&state.id).ok()?, state))),
+            None => None,
+        };
+        let (id, nodes) = match saved {
+            Some((id, state)) => {
+                let mut nodes = parse_nodes(&state.nodes, Family::V4);
+                nodes.extend(parse_nodes(&state.nodes6, Family::V6));
+                (id, nodes)
+            }
+            None => (rand::random(), Vec::new()),
+        };
+        let local_addr = socket.local_addr()?;
+        let families = match local_addr.ip() {
+            IpAddr::V4(_) => vec![Family::V4],
+            IpAddr::V6(ip) if ip.is_unspecified() => vec![Family::V4, Family::V6],
+            IpAddr::V6(_) => vec![Family::V6],
+        };
+        let shared = Arc::new(Shared {
+            id,
+            state_path,
+            socket,
+            local_addr,
+            families,
+            table: Mutex::new(RoutingTable::new(id)),
+            table6: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(rand::random()),
+            peers: Mutex::new(HashMap::new()),
+            secrets: Mutex::new(Secrets {
+                current: rand::random(),
+                previous: rand::random(),
+                rotated: Instant::now(),
+            }),
+            external_ip: ExternalIp::new(),
+        });
+        for node in nodes {
+            shared.insert(node);
+        }
+        let tasks = vec![
+            tokio::spawn(receive(shared.clone())),
+            tokio::spawn(maintain(shared.clone())),
+        ];
+        Ok(Dht { shared, tasks })
+    }
+
+    pub fn id(&self) -> [u8; 20] {
+        self.shared.id
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.shared.socket.local_addr()?)
+    }
+
+    /// Our address as the nodes that replied to us saw it.
+    pub fn external_ip(&self) -> &ExternalIp {
+        &self.shared.external_ip
+    }
+
+    /// Number of nodes in the routing tables.
+    pub fn node_count(&self) -> usize {
+        self.shared.table.lock().unwrap().len() + self.shared.table6.lock().unwrap().len()
+    }
+
+    /// Saves the id and the routing table where `bind_with_state` read them from; a node
+    /// started with `bind` has nowhere to save them to.
+    pub async fn save(&self) -> anyhow::Result<()> {
+        self.shared.save().await
+    }
+
+    /// Joins the DHT through `nodes`, given as `host:port`, by looking up our own id.
+    /// Nodes saved by an earlier run are asked first, and `nodes` only if none of them
+    /// answers. Fails if none of either answers.
+    pub async fn bootstrap(&self, nodes: &[&str]) -> anyhow::Result<()> {
+        if self.node_count() > 0 {
+            let lookup = self.shared.lookup(self.shared.id, Find::Nodes).await;
+            if !lookup.closest.is_empty() {
+                return Ok(());
+            }
+        }
+        let mut addrs = Vec::new();
+        for node in nodes {
+            // one bootstrap node that doesn't resolve shouldn't keep us out
+            if let Ok(resolved) = tokio::net::lookup_host(node).await {
+                addrs.extend(resolved.filter(|addr| self.shared.reaches(addr)));
+            }
+        }
+        let arguments = Arguments {
+            target: Some(ByteBuf::from(self.shared.id)),
+            ..self.shared.lookup_arguments()
+        };
+        let queries: FuturesUnordered<_> = addrs
+            .iter()
+            .map(|&addr| self.shared.query(addr, "find_node", arguments.clone()))
+            .collect();
+        let replies: Vec<_> = queries.collect().await;
+        // a bootstrap node of one family may well know nodes of the other
+        for reply in replies.into_iter().flatten() {
+            for &family in &self.shared.families {
+                for node in reply.nodes(family) {
+                    self.shared.insert(node);
+                }
+            }
+        }
+        if self.node_count() == 0 {
+            anyhow::bail!("none of the {} bootstrap nodes answered", addrs.len());
+        }
+        self.shared.lookup(self.shared.id, Find::Nodes).await;
+        Ok(())
+    }
+
+    /// Asks the node at `addr` whether it is alive, returning its id.
+    pub async fn ping(&self, addr: SocketAddr) -> anyhow::Result<[u8; 20]> {
+        let reply = self
+            .shared
+            .query(addr, "ping", self.shared.arguments())
+            .await?;
+        node_id(&reply.id)
+    }
+
+    /// The nodes closest to `target` that we can find, closest first.
+    pub async fn find_node(&self, target: [u8; 20]) -> Vec<Node> {
+        let lookup = self.shared.lookup(target, Find::Nodes).await;
+        lookup.closest.into_iter().map(|(node, _)| node).collect()
+    }
+
+    /// The peers the DHT knows for `info_hash`.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        self.shared.lookup(info_hash, Find::Peers).await.peers
+    }
+
+    /// Estimates how many seeds and other peers the swarm of `info_hash` has (BEP 33), from
+    /// the bloom filters of those that announced to the closest nodes; for a torrent without
+    /// trackers to scrape. Nothing is known of completed downloads.
+    pub async fn scrape(&self, info_hash: [u8; 20]) -> ScrapeStats {
+        let lookup = self.shared.lookup(info_hash, Find::Scrape).await;
+        ScrapeStats {
+            seeders: lookup.seeds.estimate(),
+            leechers: lookup.leechers.estimate(),
+            completed: 0,
+        }
+    }
+
+    /// Looks up the peers for `info_hash` like `get_peers`, then tells the closest nodes
+    /// that we are a peer too, accepting connections on `port`, and a seed if `seed` is set.
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16, seed: bool) -> Vec<SocketAddr> {
+        let lookup = self.shared.lookup(info_hash, Find::Peers).await;
+        let announces: FuturesUnordered<_> = lookup
+            .closest
+            .into_iter()
+            .filter_map(|(node, token)| Some((node, token?)))
+            .map(|(node, token)| {
+                let arguments = Arguments {
+                    info_hash: Some(ByteBuf::from(info_hash)),
+                    port: Some(port),
+                    token: Some(token),
+                    seed: seed.then_some(1),
+                    ..self.shared.arguments()
+                };
+                self.shared.query(node.addr, "announce_peer", arguments)
+            })
+            .collect();
+        // a node that doesn't take the announce still told us its peers
+        let _: Vec<_> = announces.collect().await;
+        lookup.peers
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Shared {
+    fn table(&self, family: Family) -> &Mutex<RoutingTable> {
+        match family {
+            Family::V4 => &self.table,
+            Family::V6 => &self.table6,
+        }
+    }
+
+    /// Whether the socket can send to `addr`.
+    fn reaches(&self, addr: &SocketAddr) -> bool {
+        self.families.contains(&Family::of(addr))
+    }
+
+    /// Records that we heard from `node`, in the routing table of its family.
+    fn insert(&self, node: Node) {
+        if self.reaches(&node.addr) {
+            self.table(Family::of(&node.addr))
+                .lock()
+                .unwrap()
+                .insert(node);
+        }
+    }
+
+    async fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.state_path else {
+            return Ok(());
+        };
+        let nodes: Vec<Node> = {
+            let (table, table6) = (self.table.lock().unwrap(), self.table6.lock().unwrap());
+            table.nodes().chain(table6.nodes()).collect()
+        };
+        let state = SavedState {
+            id: ByteBuf::from(self.id),
+            nodes: ByteBuf::from(compact_nodes(&nodes, Family::V4)),
+            nodes6: ByteBuf::from(compact_nodes(&nodes, Family::V6)),
+        };
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let mut partial = path.clone().into_os_string();
+        partial.push(".part");
+        tokio::fs::write(&partial, bencode::to_bytes(&state)?).await?;
+        tokio::fs::rename(&partial, path).await?;
+        Ok(())
+    }
+
+    fn arguments(&self) -> Arguments {
+        Arguments {
+            id: ByteBuf::from(self.id),
+            ..Arguments::default()
+        }
+    }
+
+    /// The arguments of a query for nodes, which asks for those of both families when we
+    /// are a node of both.
+    fn lookup_arguments(&self) -> Arguments {
+        let want = self.families.iter().map(|family| family.name().to_owned());
+        Arguments {
+            want: (self.families.len() > 1).then(|| want.collect()),
+            ..self.arguments()
+        }
+    }
+
+    async fn send(&self, message: &Message, addr: SocketAddr) -> anyhow::Result<()> {
+        self.socket
+            .send_to(
+                &bencode::to_bytes(message)?,
+                net::for_socket(addr, self.local_addr),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Sends a query and waits for its reply. A node that doesn't answer in time counts as
+    /// failed in the routing table.
+    async fn query(
+        &self,
+        addr: SocketAddr,
+        method: &str,
+        arguments: Arguments,
+    ) -> anyhow::Result<Reply> {
+        let transaction = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(
+            transaction,
+            Pending {
+                addr,
+                reply: reply_tx,
+            },
+        );
+        let message = Message::query(&transaction, method, arguments);
+        if let Err(e) = self.send(&message, addr).await {
+            self.pending.lock().unwrap().remove(&transaction);
+            return Err(e);
+        }
+        let result = tokio::time::timeout(QUERY_TIMEOUT, reply_rx).await;
+        self.pending.lock().unwrap().remove(&transaction);
+        match result {
+            Ok(Ok(reply)) => reply,
+            _ => {
+                self.table(Family::of(&addr)).lock().unwrap().failed(addr);
+                anyhow::bail!("node {} didn't answer {}", addr, method)
+            }
+        }
+    }
+
+    /// Looks up `target` in the DHT of every family we are a node of, and puts together
+    /// what each found.
+    async fn lookup(&self, target: [u8; 20], find: Find) -> Lookup {
+        let lookups = self
+            .families
+            .iter()
+            .map(|&family| self.lookup_in(family, target, find));
+        let mut lookup = Lookup {
+            closest: Vec::new(),
+            peers: Vec::new(),
+            seeds: BloomFilter::new(),
+            leechers: BloomFilter::new(),
+        };
+        for found in futures_util::future::join_all(lookups).await {
+            lookup.closest.extend(found.closest);
+            lookup.peers.extend(found.peers);
+            lookup.seeds.union_with(&found.seeds);
+            lookup.leechers.union_with(&found.leechers);
+        }
+        let mut seen = HashSet::new();
+        lookup.peers.retain(|peer| seen.insert(*peer));
+        lookup
+    }
+
+    /// Queries ever closer nodes of `family` to `target` until the `K` closest ones have all
+    /// answered, with `find_node` or `get_peers` for what `find` asks for.
+    async fn lookup_in(&self, family: Family, target: [u8; 20], find: Find) -> Lookup {
+        let mut candidates: BTreeMap<[u8; 20], Node> = self
+            .table(family)
+            .lock()
+            .unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, &target), node))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut answered: BTreeMap<[u8; 20], (Node, Option<ByteBuf>)> = BTreeMap::new();
+        let mut peers = Vec::new();
+        let (mut seeds, mut leechers) = (BloomFilter::new(), BloomFilter::new());
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            while in_flight.len() < ALPHA {
+                // a candidate is only worth asking while it could still be among the closest
+                let kth_closest = answered.keys().nth(K - 1).copied();
+                let next = candidates
+                    .iter()
+                    .find(|(distance, node)| {
+                        !queried.contains(&node.addr)
+                            && kth_closest.is_none_or(|kth| **distance < kth)
+                    })
+                    .map(|(_, &node)| node);
+                let Some(node) = next else {
+                    break;
+                };
+                queried.insert(node.addr);
+                let (method, arguments) = match find {
+                    Find::Nodes => {
+                        let arguments = Arguments {
+                            target: Some(ByteBuf::from(target)),
+                            ..self.lookup_arguments()
+                        };
+                        ("find_node", arguments)
+                    }
+                    Find::Peers | Find::Scrape => {
+                        let arguments = Arguments {
+                            info_hash: Some(ByteBuf::from(target)),
+                            scrape: (find == Find::Scrape).then_some(1),
+                            ..self.lookup_arguments()
+                        };
+                        ("get_peers", arguments)
+                    }
+                };
+                in_flight
+                    .push(async move { (node, self.query(node.addr, method, arguments).await) });
+            }
+            let Some((node, reply)) = in_flight.next().await else {
+                break;
+            };
+            let Ok(reply) = reply else {
+                continue;
+            };
+            for found in reply.nodes(family) {
+                if found.id != self.id {
+                    candidates.insert(distance(&found.id, &target), found);
+                }
+            }
+            for value in reply.values.iter().flatten() {
+                // nodes that know of IPv6 peers hand them out in the longer format
+                let found = match value.len() {
+                    18 => Peers::from_compact6(value),
+                    _ => Peers::from_compact(value),
+                };
+                if let Ok(found) = found {
+                    peers.extend(found.addrs);
+                }
+            }
+            let distance = distance(&node.id, &target);
+            // only the closest nodes are the ones a swarm announces itself to
+            let kth_closest = answered.keys().nth(K - 1).copied();
+            if kth_closest.is_none_or(|kth| distance < kth) {
+                let filter = |bytes: &Option<ByteBuf>| {
+                    bytes
+                        .as_deref()
+                        .and_then(|bytes| BloomFilter::from_bytes(bytes))
+                };
+                if let Some(filter) = filter(&reply.seeds) {
+                    seeds.union_with(&filter);
+                }
+                if let Some(filter) = filter(&reply.leechers) {
+                    leechers.union_with(&filter);
+                }
+            }
+            answered.insert(distance, (node, reply.token));
+        }
+
+        Lookup {
+            closest: answered.into_values().take(K).collect(),
+            peers,
+            seeds,
+            leechers,
+        }
+    }
+
+    /// Answers a query from `from`, which also lands the querying node in our routing table.
+    fn answer(
+        &self,
+        from: SocketAddr,
+        method: &str,
+        arguments: Arguments,
+    ) -> Result<Reply, (i64, &'static str)> {
+        let id = node_id(&arguments.id).map_err(|_| (ERROR_PROTOCOL, "invalid node id"))?;
+        self.insert(Node { id, addr: from });
+        let reply = Reply {
+            id: ByteBuf::from(self.id),
+            ..Reply::default()
+        };
+        // the nodes of the families asked for that we know, those of the querier's by default
+        let wanted: Vec<Family> = match &arguments.want {
+            Some(want) => [Family::V4, Family::V6]
+                .into_iter()
+                .filter(|family| want.iter().any(|name| name == family.name()))
+                .collect(),
+            None => vec![Family::of(&from)],
+        };
+        let closest_nodes = |target: &[u8; 20]| {
+            let compact = |family: Family| {
+                if !wanted.contains(&family) || !self.families.contains(&family) {
+                    return None;
+                }
+                let nodes = self.table(family).lock().unwrap().closest(target, K);
+                Some(ByteBuf::from(compact_nodes(&nodes, family)))
+            };
+            (compact(Family::V4), compact(Family::V6))
+        };
+        let twenty_bytes = |bytes: Option<ByteBuf>, name| {
+            bytes
+                .and_then(|bytes| node_id(&bytes).ok())
+                .ok_or((ERROR_PROTOCOL, name))
+        };
+        match method {
+            "ping" => Ok(reply),
+            "find_node" => {
+                let target = twenty_bytes(arguments.target, "invalid target")?;
+                let (nodes, nodes6) = closest_nodes(&target);
+                Ok(Reply {
+                    nodes,
+                    nodes6,
+                    ..reply
+                })
+            }
+            "get_peers" => {
+                let info_hash = twenty_bytes(arguments.info_hash, "invalid info_hash")?;
+                let token = {
+                    let mut secrets = self.secrets.lock().unwrap();
+                    secrets.rotate_if_due();
+                    token(&secrets.current, from.ip())
+                };
+                let (seeds, leechers, values) = {
+                    let peers = self.peers.lock().unwrap();
+                    let peers = peers.get(&info_hash);
+                    let announced = peers.into_iter().flatten();
+                    let mut seeds = BloomFilter::new();
+                    let mut leechers = BloomFilter::new();
+                    for (peer, announced) in announced.clone() {
+                        match announced.seed {
+                            true => seeds.insert(peer.ip()),
+                            false => leechers.insert(peer.ip()),
+                        }
+                    }
+                    // only peers of its own family are any use to the querier
+                    let values: Vec<ByteBuf> = announced
+                        .filter(|(peer, announced)| {
+                            Family::of(peer) == Family::of(&from)
+                                && !(announced.seed && arguments.noseed == Some(1))
+                        })
+                        .map(|(&peer, _)| ByteBuf::from(net::compact_addr(peer)))
+                        .collect();
+                    (seeds, leechers, values)
+                };
+                let (nodes, nodes6) = match values.is_empty() {
+                    true => closest_nodes(&info_hash),
+                    false => (None, None),
+                };
+                let scrape = arguments.scrape == Some(1);
+                Ok(Reply {
+                    token: Some(ByteBuf::from(token)),
+                    nodes,
+                    nodes6,
+                    values: (!values.is_empty()).then_some(values),
+                    seeds: scrape.then(|| ByteBuf::from(seeds.0.to_vec())),
+                    leechers: scrape.then(|| ByteBuf::from(leechers.0.to_vec())),
+                    ..reply
+                })
+            }
+            "announce_peer" => {
+                let info_hash = twenty_bytes(arguments.info_hash, "invalid info_hash")?;
+                let valid = {
+                    let mut secrets = self.secrets.lock().unwrap();
+                    secrets.rotate_if_due();
+                    arguments.token.as_deref().is_some_and(|given| {
+                        *given == token(&secrets.current, from.ip())
+                            || *given == token(&secrets.previous, from.ip())
+                    })
+                };
+                if !valid {
+                    return Err((ERROR_PROTOCOL, "bad token"));
+                }
+                let port = match (arguments.implied_port, arguments.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => port,
+                    _ => return Err((ERROR_PROTOCOL, "missing port")),
+                };
+                self.peers
+                    .lock()
+                    .unwrap()
+                    .entry(info_hash)
+                    .or_default()
+                    .insert(
+                        SocketAddr::new(from.ip(), port),
+                        Announced {
+                            at: Instant::now(),
+                            seed: arguments.seed == Some(1),
+                        },
+                    );
+                Ok(reply)
+            }
+            _ => Err((ERROR_METHOD_UNKNOWN, "method unknown")),
+        }
+    }
+}
+
+/// Answers queries and hands replies to the queries waiting for them.
+async fn receive(shared: Arc<Shared>) {
+    let mut buffer = vec![0; 2048];
+    loop {
+        let Ok((n, from)) = shared.socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        let from = net::canonical(from);
+        let Ok(message) = bencode::from_bytes::<Message>(&buffer[..n]) else {
+            continue;
+        };
+        match message.y.as_str() {
+            "q" => {
+                let (Some(method), Some(arguments)) = (message.q, message.a) else {
+                    let error = Message::error(message.t, ERROR_PROTOCOL, "malformed query");
+                    let _ = shared.send(&error, from).await;
+                    continue;
+                };
+                let answer = match shared.answer(from, &method, arguments) {
+                    Ok(reply) => Message::reply(message.t, reply, from),
+                    Err((code, error)) => Message::error(message.t, code, error),
+                };
+                let _ = shared.send(&answer, from).await;
+            }
+            "r" | "e" => {
+                let Ok(transaction) = <[u8; 2]>::try_from(message.t.as_slice()) else {
+                    continue;
+                };
+                let waiting = {
+                    let mut pending = shared.pending.lock().unwrap();
+                    match pending.get(&transaction) {
+                        // a reply has to come from the node we asked
+                        Some(waiting) if waiting.addr == from => pending.remove(&transaction),
+                        _ => None,
+                    }
+                };
+                let Some(waiting) = waiting else {
+                    continue;
+                };
+                if let Some(addr) = message
+                    .ip
+                    .as_ref()
+                    .and_then(|ip| net::parse_compact_addr(ip))
+                {
+                    shared.external_ip.record(Voter::Node(from.ip()), addr.ip());
+                }
+                let reply = match (message.r, message.e) {
+                    (Some(reply), _) => node_id(&reply.id).map(|id| {
+                        shared.insert(Node { id, addr: from });
+                        reply
+                    }),
+                    (None, Some((code, error))) => {
+                        Err(anyhow::anyhow!("node returned error {}: {}", code, error))
+                    }
+                    (None, None) => Err(anyhow::anyhow!("reply without a result")),
+                };
+                let _ = waiting.reply.send(reply);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Pings questionable nodes, refreshes buckets nobody touched in a while, forgets peers that
+/// stopped announcing and saves the routing table.
+async fn maintain(shared: Arc<Shared>) {
+    let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        for &family in &shared.families {
+            let (questionable, targets) = {
+                let table = shared.table(family).lock().unwrap();
+                (table.questionable(), table.refresh_targets())
+            };
+            let pings: FuturesUnordered<_> = questionable
+                .into_iter()
+                .map(|node| shared.query(node.addr, "ping", shared.arguments()))
+                .collect();
+            let _: Vec<_> = pings.collect().await;
+            for target in targets {
+                shared.lookup_in(family, target, Find::Nodes).await;
+            }
+        }
+        shared.peers.lock().unwrap().retain(|_, peers| {
+            peers.retain(|_, announced| announced.at.elapsed() < PEER_TIMEOUT);
+            !peers.is_empty()
+        });
+        // failing to save only costs the next run a slower start
+        let _ = shared.save().await;
+    }
+}
+
+/// Looks up and announces `info_hash` on the DHT every `REANNOUNCE_INTERVAL`, sending the
+/// peers found to `peers`, until `progress` says the download completed or goes away.
+pub async fn announce_periodically(
+    dht: Arc<Dht>,
+    info_hash: [u8; 20],
+    port: u16,
+    peers: mpsc::Sender<Vec<SocketAddr>>,
+    mut progress: watch::Receiver<Progress>,
+) {
+    loop {
+        let seed = progress.borrow().left == 0;
+        let found = dht.announce(info_hash, port, seed).await;
+        let wait = if found.is_empty() {
+            RETRY_INTERVAL
+        } else {
+            REANNOUNCE_INTERVAL
+        };
+        if !found.is_empty() && peers.send(found).await.is_err() {
+            break;
+        }
+        let sleep = tokio::time::sleep(wait);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                () = &mut sleep => break,
+                changed = progress.changed() => {
+                    if changed.is_err() || progress.borrow_and_update().left == 0 {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: u8, port: u16) -> Node {
+        let mut node_id = [0; 20];
+        node_id[0] = id;
+        Node {
+            id: node_id,
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+        }
+    }
+
+    async fn spawn_nodes(count: usize) -> Vec<Dht> {
+        spawn_nodes_on("127.0.0.1:0", count).await
+    }
+
+    async fn spawn_nodes_on(addr: &str, count: usize) -> Vec<Dht> {
+        let mut nodes = Vec::new();
+        for _ in 0..count {
+            nodes.push(Dht::bind(addr).await.unwrap());
+        }
+        let first = nodes[0].local_addr().unwrap().to_string();
+        for node in &nodes[1..] {
+            node.bootstrap(&[&first]).await.unwrap();
+        }
+        nodes
+    }
+
+    #[test]
+    fn ping_query_bytes() {
+        // the example from BEP 5
+        let arguments = Arguments {
+            id: ByteBuf::from(b"abcdefghij0123456789".to_vec()),
+            ..Arguments::default()
+        };
+        let message = Message::query(b"aa", "ping", arguments);
+        assert_eq!(
+            bencode::to_bytes(&message).unwrap(),
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        );
+    }
+
+    #[test]
+    fn parse_error_message() {
+        let message: Message =
+            bencode::from_bytes(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+        assert_eq!(
+            message,
+            Message::error(
+                ByteBuf::from(b"aa".to_vec()),
+                201,
+                "A Generic Error Ocurred"
+            )
+        );
+    }
+
+    #[test]
+    fn compact_nodes_round_trip() {
+        let nodes = vec![node(1, 6881), node(2, 6882)];
+        let compact = compact_nodes(&nodes, Family::V4);
+        assert_eq!(compact.len(), 52);
+        assert_eq!(parse_nodes(&compact, Family::V4), nodes);
+        assert_eq!(parse_nodes(&compact[..30], Family::V4), nodes[..1]);
+
+        let v6 = Node {
+            id: [3; 20],
+            addr: "[2001:db8::1]:6883".parse().unwrap(),
+        };
+        let mixed = vec![node(1, 6881), v6];
+        let compact6 = compact_nodes(&mixed, Family::V6);
+        assert_eq!(compact6.len(), 38);
+        assert_eq!(parse_nodes(&compact6, Family::V6), [v6]);
+        assert_eq!(compact_nodes(&mixed, Family::V4), compact[..26]);
+    }
+
+    #[test]
+    fn bloom_filter_estimates() {
+        // a thousand peers, 2.0.0.0 to 2.0.3.231, are estimated as about that many
+        let mut filter = BloomFilter::new();
+        for i in 0..1000u16 {
+            let [high, low] = i.to_be_bytes();
+            filter.insert(IpAddr::from([2, 0, high, low]));
+        }
+        let estimate = filter.estimate();
+        assert!((950..=1050).contains(&estimate), "{}", estimate);
+        assert_eq!(BloomFilter::new().estimate(), 0);
+
+        let mut one = BloomFilter::new();
+        one.insert("2001:db8::1".parse().unwrap());
+        assert_eq!(one.estimate(), 1);
+        // inserting again, or merging in the same addresses, counts them once
+        one.insert("2001:db8::1".parse().unwrap());
+        filter.union_with(&filter.clone());
+        assert_eq!(one.estimate(), 1);
+        assert_eq!(filter.estimate(), estimate);
+        assert!(BloomFilter::from_bytes(&[0; 255]).is_none());
+    }
+
+    #[test]
+    fn routing_table_buckets() {
+        let table = RoutingTable::new([0; 20]);
+        assert_eq!(table.bucket_index(&[0; 20]), None);
+        assert_eq!(table.bucket_index(&node(0x80, 1).id), Some(0));
+        assert_eq!(table.bucket_index(&node(0x01, 1).id), Some(7));
+        let mut id = [0; 20];
+        id[19] = 1;
+        assert_eq!(table.bucket_index(&id), Some(159));
+    }
+
+    #[test]
+    fn full_bucket_only_replaces_failing_nodes() {
+        let mut table = RoutingTable::new([0; 20]);
+        // ids 0x80..=0x87 all share no prefix with ours and land in bucket 0
+        for i in 0..K as u8 {
+            table.insert(node(0x80 + i, 1000 + i as u16));
+        }
+        table.insert(node(0xf0, 2000));
+        assert_eq!(table.len(), K);
+        assert!(!table.nodes().any(|node| node.id[0] == 0xf0));
+
+        table.failed(node(0x83, 1003).addr);
+        table.insert(node(0xf0, 2000));
+        assert_eq!(table.len(), K);
+        assert!(table.nodes().any(|node| node.id[0] == 0xf0));
+        assert!(!table.nodes().any(|node| node.id[0] == 0x83));
+    }
+
+    #[test]
+    fn failing_node_is_dropped() {
+        let mut table = RoutingTable::new([0; 20]);
+        table.insert(node(1, 1000));
+        table.failed(node(1, 1000).addr);
+        assert_eq!(table.len(), 1);
+        table.failed(node(1, 1000).addr);
+        assert_eq!(table.len(), 0);
+    }
+
+    #[test]
+    fn closest_nodes_first() {
+        let mut table = RoutingTable::new([0; 20]);
+        for id in [0x01, 0x40, 0x41, 0x80, 0xc0] {
+            table.insert(node(id, id as u16));
+        }
+        let closest = table.closest(&node(0x42, 0).id, 3);
+        let ids: Vec<u8> = closest.iter().map(|node| node.id[0]).collect();
+        assert_eq!(ids, vec![0x40, 0x41, 0x01]);
+    }
+
+    #[test]
+    fn refresh_targets_fall_in_their_bucket() {
+        let mut table = RoutingTable::new(rand::random());
+        let mut id = table.id;
+        id[2] ^= 0x10;
+        table.insert(Node {
+            id,
+            addr: SocketAddr::from(([127, 0, 0, 1], 1)),
+        });
+        let index = table.bucket_index(&id).unwrap();
+        table.buckets[index].last_changed -= NODE_TIMEOUT;
+        let targets = table.refresh_targets();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(table.bucket_index(&targets[0]), Some(index));
+    }
+
+    #[tokio::test]
+    async fn ping_learns_the_node() {
+        let nodes = spawn_nodes(2).await;
+        let id = nodes[1].ping(nodes[0].local_addr().unwrap()).await.unwrap();
+        assert_eq!(id, nodes[0].id());
+        assert_eq!(nodes[0].node_count(), 1);
+        assert_eq!(nodes[1].node_count(), 1);
+        // the reply says where the ping came from
+        let loopback: IpAddr = "127.0.0.1".parse().unwrap();
+        assert_eq!(nodes[1].external_ip().ips(), vec![loopback]);
+    }
+
+    #[tokio::test]
+    async fn bootstrap_fails_without_answers() {
+        let dht = Dht::bind("127.0.0.1:0").await.unwrap();
+        // bound but never answering
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent = silent.local_addr().unwrap().to_string();
+        assert!(dht.bootstrap(&[&silent]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn saved_state_survives_a_restart() {
+        let nodes = spawn_nodes(3).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("dht.dat");
+        let dht = Dht::bind_with_state("127.0.0.1:0", &path).await.unwrap();
+        let first = nodes[0].local_addr().unwrap().to_string();
+        dht.bootstrap(&[&first]).await.unwrap();
+        dht.save().await.unwrap();
+        let (id, known) = (dht.id(), dht.node_count());
+        drop(dht);
+
+        // the same id, and joining again needs no bootstrap node
+        let dht = Dht::bind_with_state("127.0.0.1:0", &path).await.unwrap();
+        assert_eq!(dht.id(), id);
+        assert_eq!(dht.node_count(), known);
+        dht.bootstrap(&[]).await.unwrap();
+        assert_eq!(dht.find_node(nodes[2].id()).await[0].id, nodes[2].id());
+
+        std::fs::write(&path, b"garbage").unwrap();
+        let dht = Dht::bind_with_state("127.0.0.1:0", &path).await.unwrap();
+        assert_ne!(dht.id(), id);
+        assert_eq!(dht.node_count(), 0);
+        // a node without a state file has nothing to save
+        Dht::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .save()
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn find_node_through_others() {
+        let nodes = spawn_nodes(5).await;
+        let target = nodes[4].id();
+        let found = nodes[1].find_node(target).await;
+        assert_eq!(found[0].id, target);
+        assert_eq!(found[0].addr, nodes[4].local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn announced_peers_are_found() {
+        let nodes = spawn_nodes(4).await;
+        let info_hash = [7; 20];
+        assert!(nodes[1].announce(info_hash, 7000, false).await.is_empty());
+        let peers = nodes[2].get_peers(info_hash).await;
+        assert_eq!(peers, vec!["127.0.0.1:7000".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn ipv6_peers_are_found() {
+        // a node answering every query with one IPv4 and one IPv6 peer
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        let v6: SocketAddr = "[2001:db8::1]:7000".parse().unwrap();
+        let (_, compact6) = Peers::to_compact(&[v6]);
+        tokio::spawn(async move {
+            let mut buffer = [0; 1500];
+            while let Ok((n, from)) = socket.recv_from(&mut buffer).await {
+                let query: Message = bencode::from_bytes(&buffer[..n]).unwrap();
+                let reply = Reply {
+                    id: ByteBuf::from([9; 20]),
+                    values: Some(vec![
+                        ByteBuf::from(b"\x7f\x00\x00\x01\x1b\x58".to_vec()),
+                        ByteBuf::from(compact6.clone()),
+                    ]),
+                    token: Some(ByteBuf::from(b"token".to_vec())),
+                    ..Reply::default()
+                };
+                let reply = bencode::to_bytes(&Message::reply(query.t, reply, from)).unwrap();
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        });
+        let dht = Dht::bind("127.0.0.1:0").await.unwrap();
+        dht.bootstrap(&[&addr]).await.unwrap();
+        let peers = dht.get_peers([7; 20]).await;
+        assert_eq!(peers, vec!["127.0.0.1:7000".parse().unwrap(), v6]);
+    }
+
+    #[tokio::test]
+    async fn ipv6_nodes_find_each_other() {
+        let nodes = spawn_nodes_on("[::1]:0", 4).await;
+        let target = nodes[3].id();
+        let found = nodes[1].find_node(target).await;
+        assert_eq!(found[0].addr, nodes[3].local_addr().unwrap());
+        assert!(nodes[2].announce([7; 20], 7000, false).await.is_empty());
+        let peers = nodes[1].get_peers([7; 20]).await;
+        assert_eq!(peers, vec!["[::1]:7000".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn dual_stack_nodes_want_both_families() {
+        let dual = Dht::bind_dual_stack(0, None, None).await.unwrap();
+        let port = dual.local_addr().unwrap().port();
+        let v4 = Dht::bind("127.0.0.1:0").await.unwrap();
+        v4.bootstrap(&[&format!("127.0.0.1:{}", port)])
+            .await
+            .unwrap();
+        let v6 = Dht::bind("[::1]:0").await.unwrap();
+        v6.bootstrap(&[&format!("[::1]:{}", port)]).await.unwrap();
+        assert_eq!(dual.node_count(), 2);
+
+        // a dual-stack node hands out the nodes of the family asked for
+        let arguments = Arguments {
+            target: Some(ByteBuf::from([0; 20])),
+            want: Some(vec!["n4".to_owned(), "n6".to_owned()]),
+            ..v4.shared.arguments()
+        };
+        let reply = v4
+            .shared
+            .query(
+                format!("127.0.0.1:{}", port).parse().unwrap(),
+                "find_node",
+                arguments,
+            )
+            .await
+            .unwrap();
+        assert_eq!(reply.nodes(Family::V4)[0].id, v4.id());
+        assert_eq!(reply.nodes(Family::V6)[0].id, v6.id());
+    }
+
+    #[tokio::test]
+    async fn scrape_estimates_the_swarm() {
+        let nodes = spawn_nodes(4).await;
+        let info_hash = [7; 20];
+        assert_eq!(
+            nodes[1].scrape(info_hash).await,
+            ScrapeStats {
+                seeders: 0,
+                leechers: 0,
+                completed: 0,
+            }
+        );
+        nodes[1].announce(info_hash, 7000, true).await;
+        nodes[2].announce(info_hash, 7001, false).await;
+        // all the nodes share an address, so the filters count one seed and one leecher
+        let stats = nodes[3].scrape(info_hash).await;
+        assert_eq!((stats.seeders, stats.leechers), (1, 1));
+
+        // seeds can be left out of the peers a node returns
+        let arguments = Arguments {
+            info_hash: Some(ByteBuf::from(info_hash)),
+            noseed: Some(1),
+            ..nodes[3].shared.arguments()
+        };
+        let reply = nodes[3]
+            .shared
+            .query(nodes[0].local_addr().unwrap(), "get_peers", arguments)
+            .await
+            .unwrap();
+        assert_eq!(reply.values.map(|values| values.len()), Some(1));
+        assert!(reply.seeds.is_none());
+    }
+
+    #[tokio::test]
+    async fn announce_needs_a_valid_token() {
+        let nodes = spawn_nodes(2).await;
+        let arguments = Arguments {
+            info_hash: Some(ByteBuf::from([7; 20])),
+            port: Some(7000),
+            token: Some(ByteBuf::from(b"forged".to_vec())),
+            ..nodes[1].shared.arguments()
+        };
+        let result = nodes[1]
+            .shared
+            .query(nodes[0].local_addr().unwrap(), "announce_peer", arguments)
+            .await;
+        assert!(result.unwrap_err().to_string().contains("bad token"));
+        assert!(nodes[1].get_peers([7; 20]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_method_is_an_error() {
+        let nodes = spawn_nodes(2).await;
+        let result = nodes[1]
+            .shared
+            .query(
+                nodes[0].local_addr().unwrap(),
+                "vote",
+                nodes[1].shared.arguments(),
+            )
+            .await;
+        assert!(result.unwrap_err().to_string().contains("204"));
+    }
+}
@@ -0,0 +1,165 @@
+use crate::torrent::Torrent;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// How two torrents differ, e.g. two releases of the same content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentDiff {
+    /// The names of the first and the second torrent, if they differ.
+    pub name: Option<(String, String)>,
+    /// Their total lengths, if they differ.
+    pub total_length: Option<(usize, usize)>,
+    /// Their piece lengths, if they differ.
+    pub piece_length: Option<(usize, usize)>,
+    /// The files that are only in one of them or of another length in each, by path.
+    pub files: Vec<FileChange>,
+    /// Whether they have the same info hash, so peers see them as the same torrent.
+    pub same_info_hash: bool,
+}
+
+impl TorrentDiff {
+    /// Whether the torrents describe the same content the same way.
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.total_length.is_none()
+            && self.piece_length.is_none()
+            && self.files.is_empty()
+    }
+}
+
+/// A file of one torrent that the other doesn't have as it is. Paths are relative to the
+/// download location, as in `FileSpan`, and the name of a single-file torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileChange {
+    /// Only in the second torrent.
+    Added { path: PathBuf, length: usize },
+    /// Only in the first torrent.
+    Removed { path: PathBuf, length: usize },
+    /// In both, of `from` bytes in the first and `to` in the second.
+    Resized {
+        path: PathBuf,
+        from: usize,
+        to: usize,
+    },
+}
+
+impl FileChange {
+    pub fn path(&self) -> &PathBuf {
+        match self {
+            FileChange::Added { path, .. }
+            | FileChange::Removed { path, .. }
+            | FileChange::Resized { path, .. } => path,
+        }
+    }
+}
+
+/// Compares `a` with `b`: their names, lengths and piece lengths, and their files by path and
+/// length. Files of the same path and length are taken to be the same, whatever they hold.
+pub fn diff(a: &Torrent, b: &Torrent) -> crate::Result<TorrentDiff> {
+    let (files_a, files_b) = (file_lengths(a)?, file_lengths(b)?);
+    let mut files = Vec::new();
+    for (path, &from) in &files_a {
+        match files_b.get(path) {
+            None => files.push(FileChange::Removed {
+                path: path.clone(),
+                length: from,
+            }),
+            Some(&to) if to != from => files.push(FileChange::Resized {
+                path: path.clone(),
+                from,
+                to,
+            }),
+            Some(_) => {}
+        }
+    }
+    for (path, &length) in &files_b {
+        if !files_a.contains_key(path) {
+            files.push(FileChange::Added {
+                path: path.clone(),
+                length,
+            });
+        }
+    }
+    files.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(TorrentDiff {
+        name: changed(a.info.name.clone(), b.info.name.clone()),
+        total_length: changed(a.total_length(), b.total_length()),
+        piece_length: changed(a.info.piece_length, b.info.piece_length),
+        files,
+        same_info_hash: a.info_hash() == b.info_hash(),
+    })
+}
+
+/// Both values, if they differ.
+fn changed<T: PartialEq>(a: T, b: T) -> Option<(T, T)> {
+    (a != b).then_some((a, b))
+}
+
+/// The length of every file of `torrent`, by path.
+fn file_lengths(torrent: &Torrent) -> crate::Result<BTreeMap<PathBuf, usize>> {
+    let spans = torrent.files().map_err(crate::Error::Metainfo)?;
+    Ok(spans
+        .into_iter()
+        .map(|span| match span.path.as_os_str().is_empty() {
+            true => (PathBuf::from(&torrent.info.name), span.length),
+            false => (span.path, span.length),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{multi_file_torrent_for, test_data, torrent_for};
+
+    #[test]
+    fn diff_torrents_differing_by_one_file() {
+        let data = test_data(1000);
+        let a = multi_file_torrent_for(&data, 100, &[("a", 250), ("b", 500), ("c", 250)]);
+        let b = multi_file_torrent_for(&data, 100, &[("a", 250), ("b", 500), ("d", 250)]);
+        let changes = diff(&a, &b).unwrap();
+        assert_eq!(
+            changes.files,
+            [
+                FileChange::Removed {
+                    path: "c".into(),
+                    length: 250
+                },
+                FileChange::Added {
+                    path: "d".into(),
+                    length: 250
+                },
+            ]
+        );
+        // the same pieces, only under other paths
+        assert_eq!(changes.total_length, None);
+        assert!(!changes.same_info_hash);
+
+        let c = multi_file_torrent_for(&data[..900], 100, &[("a", 250), ("b", 400), ("c", 250)]);
+        let changes = diff(&a, &c).unwrap();
+        assert_eq!(
+            changes.files,
+            [FileChange::Resized {
+                path: "b".into(),
+                from: 500,
+                to: 400
+            }]
+        );
+        assert_eq!(changes.total_length, Some((1000, 900)));
+        assert!(diff(&a, &a).unwrap().is_empty());
+    }
+
+    #[test]
+    fn diff_single_file_torrents() {
+        let a = torrent_for(&test_data(1000), 100);
+        let mut b = torrent_for(&test_data(1000), 200);
+        b.info.name = "other.bin".to_owned();
+        let changes = diff(&a, &b).unwrap();
+        assert_eq!(
+            changes.name,
+            Some(("test.bin".to_owned(), "other.bin".to_owned()))
+        );
+        assert_eq!(changes.piece_length, Some((100, 200)));
+        assert_eq!(changes.files.len(), 2);
+    }
+}
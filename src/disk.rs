@@ -0,0 +1,343 @@
+use crate::cache::{CacheStats, PieceCache};
+use crate::hasher::HashPool;
+use crate::storage::Storage;
+use crate::torrent::Torrent;
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{trace, warn};
+
+/// Requests queued for the disk task before callers wait to queue more.
+const QUEUE_LENGTH: usize = 64;
+
+/// Handle to the task that owns the files of a torrent, so that file I/O and piece hashing
+/// happen away from the tasks talking to peers.
+///
+/// The task carries out reads and writes one at a time, in the order they were queued.
+/// Pieces are hashed on the shared `HashPool` before their write is queued, several at once if
+/// several callers are writing, and are written whole rather than block by block. The task
+/// ends when the last handle is dropped.
+///
+/// With a `PieceCache`, the pieces written and read last are kept in memory, and reads of
+/// what is kept don't go to the task at all.
+#[derive(Clone)]
+pub struct Disk {
+    torrent: Arc<Torrent>,
+    requests: mpsc::Sender<Request>,
+    cache: Option<Arc<PieceCache>>,
+}
+
+enum Request {
+    Write {
+        offset: usize,
+        /// Shared with the cache rather than copied.
+        data: Arc<Vec<u8>>,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Read {
+        offset: usize,
+        length: usize,
+        reply: oneshot::Sender<anyhow::Result<Vec<u8>>>,
+    },
+    Flush {
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Complete {
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+}
+
+impl Disk {
+    pub fn spawn(torrent: Arc<Torrent>, storage: impl Storage + 'static) -> Disk {
+        let (disk, writer) = Disk::new(torrent, storage);
+        tokio::spawn(writer);
+        disk
+    }
+
+    /// A disk and the task that does its reads and writes, for the caller to run. The task
+    /// ends once every handle to the disk is dropped.
+    pub(crate) fn new(
+        torrent: Arc<Torrent>,
+        storage: impl Storage + 'static,
+    ) -> (Disk, impl Future<Output = ()> + Send + 'static) {
+        let (requests, queued) = mpsc::channel(QUEUE_LENGTH);
+        let disk = Disk {
+            torrent,
+            requests,
+            cache: None,
+        };
+        (disk, run(storage, queued))
+    }
+
+    /// Keeps up to `budget` bytes of pieces in memory, or none for 0. The handles cloned from
+    /// this one afterwards share the cache.
+    pub fn with_cache(self, budget: usize) -> Disk {
+        Disk {
+            cache: (budget > 0).then(|| Arc::new(PieceCache::new(budget))),
+            ..self
+        }
+    }
+
+    /// What the cache did so far; all 0 without one.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache
+            .as_ref()
+            .map(|cache| cache.stats())
+            .unwrap_or_default()
+    }
+
+    /// How many requests are waiting for the disk task, up to `QUEUE_LENGTH`.
+    pub fn queued(&self) -> usize {
+        self.requests.max_capacity() - self.requests.capacity()
+    }
+
+    /// Checks `piece` against the hash of the piece at `index` and writes it if it matches.
+    /// Tells whether it did.
+    pub async fn write_piece(&self, index: usize, piece: Vec<u8>) -> crate::Result<bool> {
+        let (valid, piece) = self.hash(index, piece).await?;
+        if !valid {
+            warn!(piece = index, "hash check failed");
+            return Ok(false);
+        }
+        trace!(piece = index, length = piece.len(), "queueing write");
+        let offset = index * self.torrent.info.piece_length;
+        let data = Arc::new(piece);
+        if let Some(cache) = &self.cache {
+            cache.insert(index, data.clone());
+        }
+        self.request(|reply| Request::Write {
+            offset,
+            data,
+            reply,
+        })
+        .await?;
+        Ok(true)
+    }
+
+    /// Reads `length` bytes starting at `offset` in the torrent. With a cache, a read within
+    /// one piece reads all of it, to serve the reads of the rest from memory.
+    pub async fn read(&self, offset: usize, length: usize) -> crate::Result<Vec<u8>> {
+        let Some(cache) = &self.cache else {
+            return self.read_uncached(offset, length).await;
+        };
+        let piece_length = self.torrent.info.piece_length;
+        let (index, begin) = (offset / piece_length, offset % piece_length);
+        let size = self.torrent.piece_size(index);
+        if begin + length > size {
+            return self.read_uncached(offset, length).await;
+        }
+        let piece = match cache.get(index) {
+            Some(piece) => piece,
+            None => {
+                let piece = Arc::new(self.read_uncached(index * piece_length, size).await?);
+                cache.insert(index, piece.clone());
+                piece
+            }
+        };
+        Ok(piece[begin..begin + length].to_vec())
+    }
+
+    async fn read_uncached(&self, offset: usize, length: usize) -> crate::Result<Vec<u8>> {
+        self.request(|reply| Request::Read {
+            offset,
+            length,
+            reply,
+        })
+        .await
+    }
+
+    /// Reads the piece at `index` back and checks it against its hash, from the disk even if
+    /// it is cached, so it is what the files hold that is checked.
+    pub async fn verify_piece(&self, index: usize) -> crate::Result<bool> {
+        Ok(self.read_verified_piece(index).await?.is_some())
+    }
+
+    /// Like `verify_piece`, but returns the piece if it matches its hash.
+    pub async fn read_verified_piece(&self, index: usize) -> crate::Result<Option<Vec<u8>>> {
+        let offset = index * self.torrent.info.piece_length;
+        let piece = self
+            .read_uncached(offset, self.torrent.piece_size(index))
+            .await?;
+        let (valid, piece) = self.hash(index, piece).await?;
+        Ok(valid.then_some(piece))
+    }
+
+    /// Like `verify_piece` for each of `indices`, hashing as many pieces at once as the pool
+    /// has threads while the next ones are read.
+    pub async fn verify_pieces(&self, indices: &[usize]) -> crate::Result<Vec<bool>> {
+        // collected first, so the stream doesn't borrow `indices` across awaits
+        let verifying: Vec<_> = indices
+            .iter()
+            .map(|&index| {
+                let disk = self.clone();
+                async move { disk.verify_piece(index).await }
+            })
+            .collect();
+        stream::iter(verifying)
+            .buffered(HashPool::shared().threads())
+            .try_collect()
+            .await
+    }
+
+    /// Waits until everything written so far has reached the files.
+    pub async fn flush(&self) -> crate::Result<()> {
+        self.request(|reply| Request::Flush { reply }).await
+    }
+
+    /// Flushes what was written and lets the storage move the content to where it belongs
+    /// once complete, as `Storage::complete` does.
+    pub async fn complete(&self) -> crate::Result<()> {
+        self.flush().await?;
+        self.request(|reply| Request::Complete { reply }).await
+    }
+
+    async fn hash(&self, index: usize, piece: Vec<u8>) -> crate::Result<(bool, Vec<u8>)> {
+        HashPool::shared()
+            .verify(self.torrent.clone(), index, piece)
+            .await
+            .map_err(crate::Error::Disk)
+    }
+
+    async fn request<T>(
+        &self,
+        request: impl FnOnce(oneshot::Sender<anyhow::Result<T>>) -> Request,
+    ) -> crate::Result<T> {
+        let (reply, response) = oneshot::channel();
+        let stopped = || crate::Error::Disk(anyhow::anyhow!("disk task stopped"));
+        self.requests
+            .send(request(reply))
+            .await
+            .map_err(|_| stopped())?;
+        response
+            .await
+            .map_err(|_| stopped())?
+            .map_err(crate::Error::Disk)
+    }
+}
+
+async fn run(mut storage: impl Storage, mut requests: mpsc::Receiver<Request>) {
+    while let Some(request) = requests.recv().await {
+        // a caller that stopped waiting doesn't need the result
+        match request {
+            Request::Write {
+                offset,
+                data,
+                reply,
+            } => {
+                let written = storage.write_block(offset, &data).await;
+                if let Err(e) = &written {
+                    warn!(offset, length = data.len(), "write failed: {:#}", e);
+                }
+                let _ = reply.send(written);
+            }
+            Request::Read {
+                offset,
+                length,
+                reply,
+            } => {
+                let read = storage.read_block(offset, length).await;
+                if let Err(e) = &read {
+                    warn!(offset, length, "read failed: {:#}", e);
+                }
+                let _ = reply.send(read);
+            }
+            Request::Flush { reply } => {
+                let _ = reply.send(storage.flush().await);
+            }
+            Request::Complete { reply } => {
+                let completed = storage.complete().await;
+                if let Err(e) = &completed {
+                    warn!("completing the storage failed: {:#}", e);
+                }
+                let _ = reply.send(completed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{test_data, torrent_for};
+    use crate::storage::{Allocation, FileStorage};
+
+    #[tokio::test]
+    async fn writes_only_valid_pieces() {
+        let data = test_data(1000);
+        let torrent = Arc::new(torrent_for(&data, 300));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.bin");
+        let storage = FileStorage::create(&torrent, &path, Allocation::Sparse)
+            .await
+            .unwrap();
+        let disk = Disk::spawn(torrent, storage);
+
+        assert!(disk.write_piece(1, data[300..600].to_vec()).await.unwrap());
+        assert!(!disk.write_piece(3, vec![0; 100]).await.unwrap());
+        disk.flush().await.unwrap();
+        assert_eq!(disk.read(300, 100).await.unwrap(), &data[300..400]);
+        // the rejected piece never reached the file
+        assert_eq!(disk.read(900, 100).await.unwrap(), vec![0; 100]);
+        assert!(disk.verify_piece(1).await.unwrap());
+        assert!(!disk.verify_piece(3).await.unwrap());
+        let valid = disk.verify_pieces(&[3, 1, 0]).await.unwrap();
+        assert_eq!(valid, [false, true, false]);
+    }
+
+    #[tokio::test]
+    async fn reads_cached_pieces_from_memory() {
+        let data = test_data(1000);
+        let torrent = Arc::new(torrent_for(&data, 300));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.bin");
+        let storage = FileStorage::create(&torrent, &path, Allocation::Sparse)
+            .await
+            .unwrap();
+        let disk = Disk::spawn(torrent, storage).with_cache(900);
+
+        assert!(disk.write_piece(1, data[300..600].to_vec()).await.unwrap());
+        assert_eq!(disk.clone().read(400, 100).await.unwrap(), &data[400..500]);
+        // the first read of a piece reads all of it, for the next
+        assert!(disk.write_piece(2, data[600..900].to_vec()).await.unwrap());
+        assert!(disk.write_piece(3, data[900..].to_vec()).await.unwrap());
+        disk.flush().await.unwrap();
+        assert_eq!(disk.read(0, 100).await.unwrap(), vec![0; 100]);
+        assert_eq!(disk.read(200, 100).await.unwrap(), vec![0; 100]);
+        // reads across pieces, and checks of what is on disk, don't go through the cache
+        assert_eq!(disk.read(250, 100).await.unwrap()[50..], data[300..350]);
+        assert!(disk.verify_piece(3).await.unwrap());
+
+        let stats = disk.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        // piece 1 was used longest ago when piece 0 needed the room
+        assert_eq!(stats.size, 700);
+        assert!(disk.cache.as_ref().unwrap().get(1).is_none());
+    }
+
+    #[tokio::test]
+    async fn handles_are_shared() {
+        let data = test_data(1000);
+        let torrent = Arc::new(torrent_for(&data, 300));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.bin");
+        let storage = FileStorage::create(&torrent, &path, Allocation::Sparse)
+            .await
+            .unwrap();
+        let disk = Disk::spawn(torrent, storage);
+
+        let writers: Vec<_> = (0..4)
+            .map(|index| {
+                let disk = disk.clone();
+                let piece = data[index * 300..data.len().min((index + 1) * 300)].to_vec();
+                tokio::spawn(async move { disk.write_piece(index, piece).await.unwrap() })
+            })
+            .collect();
+        for writer in writers {
+            assert!(writer.await.unwrap());
+        }
+        disk.flush().await.unwrap();
+        let valid = crate::storage::verify(&torrent_for(&data, 300), &path).await;
+        assert_eq!(valid.unwrap(), [true; 4]);
+    }
+}
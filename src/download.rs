@@ -0,0 +1,4551 @@
+use crate::bencode;
+use crate::bitfield::Bitfield;
+use crate::cache::DEFAULT_CACHE_SIZE;
+use crate::choker::{Choker, UploadSlot, UPLOAD_SLOTS};
+use crate::connector::{
+    BanList, ConnectionSlots, Connector, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_HALF_OPEN,
+    DEFAULT_MAX_TORRENT_CONNECTIONS,
+};
+use crate::dht::{self, Dht};
+use crate::disk::Disk;
+use crate::events::{EventKind, EventSender, THROUGHPUT_INTERVAL};
+use crate::extension::{
+    ExtensionHandshake, HolepunchMessage, PexMessage, PexState, MAX_PEX_PEERS, UT_HOLEPUNCH_ID,
+    UT_PEX_ID,
+};
+use crate::external_ip::ExternalIp;
+use crate::hook::{self, HookEvent};
+use crate::ipfilter::IpFilter;
+use crate::lsd::{self, Lsd};
+use crate::message::*;
+use crate::mse::{EncryptionPolicy, PeerStream};
+use crate::net::{self, Bind};
+use crate::peer::{self, Handshake, IDLE_TIMEOUT, KEEP_ALIVE_INTERVAL};
+use crate::picker::{self, PiecePicker, Priority};
+use crate::pipeline::{PipelineSizer, DEFAULT_REQUEST_QUEUE_TIME};
+use crate::proxy::Proxy;
+use crate::rate::RateLimiter;
+use crate::resume::ResumeData;
+use crate::schedule::{self, Limits, Schedule};
+use crate::seed::{SeedLimits, Seeding, DEFAULT_MAX_REQUEST_LENGTH};
+use crate::session::{Session, TorrentState};
+use crate::stats::SwarmStats;
+use crate::storage::{
+    self, Allocation, FileStorage, Incomplete, OpenStorage, Storage, StorageBackend,
+};
+use crate::supervisor::Supervisor;
+use crate::torrent::Torrent;
+use crate::tracker::{
+    announce_periodically, AnnounceParams, Event, HttpOptions, Progress, TrackerList,
+    TrackerOverride, TrackerRequest,
+};
+use crate::utp::UtpSocket;
+use crate::webseed::{self, WebSeed};
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_util::codec::Framed;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+/// Number of block requests kept outstanding per peer unless configured otherwise.
+pub const DEFAULT_PIPELINE_DEPTH: usize = 10;
+/// Port we listen on for incoming peer connections unless configured otherwise.
+pub const DEFAULT_PORT: u16 = 6881;
+/// How many more ports a session tries when the one it is to listen on is taken, unless
+/// configured otherwise.
+pub const DEFAULT_LISTEN_RETRIES: u16 = 10;
+/// How long a peer may take to send any of the blocks we asked for unless configured otherwise.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// A peer that snubs us this many times in a row is disconnected.
+const MAX_SNUBS: u32 = 2;
+/// The most pieces a bitfield message has room for, which a peer's announcements are held to
+/// until the session knows how many pieces the torrent has.
+const MAX_PIECES: usize = (MAX_MESSAGE_LENGTH - 1) * 8;
+/// Holepunch messages waiting to be handed on, to or from one peer, before more are dropped.
+const HOLEPUNCH_QUEUE: usize = 16;
+/// A web seed that fails this many pieces in a row is no longer used for the download.
+const WEB_SEED_RETRIES: u32 = 3;
+/// How long to wait before asking a failing web seed again, times the failures so far.
+const WEB_SEED_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Settings for a whole-torrent download.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Block requests kept outstanding per peer, at first if `request_queue_time` is set.
+    pub pipeline_depth: usize,
+    /// How many seconds of each peer's download rate its requests are to cover, growing and
+    /// shrinking its pipeline from `pipeline_depth` as the rate is measured; `None` keeps it
+    /// at `pipeline_depth`.
+    pub request_queue_time: Option<Duration>,
+    /// Port to accept incoming peer connections on; 0 picks any free port.
+    pub port: u16,
+    /// Addresses a session accepts peers on, each over TCP and, with `utp`, over UDP; those
+    /// of port 0 take the one of `port`. Without any, it listens on `port` of every address,
+    /// or of the address of `bind`.
+    pub listen: Vec<SocketAddr>,
+    /// Listen on a random port of the dynamic range instead of `port`, a new one every
+    /// session.
+    pub random_port: bool,
+    /// How many more ports a session tries when `port` is taken, each the one after the last,
+    /// or other random ones with `random_port`. Trackers and the DHT are told the port it got.
+    pub listen_retries: u16,
+    /// Also find peers through the DHT, on a UDP socket with the same port number unless uTP
+    /// takes it.
+    pub dht: bool,
+    /// Where the DHT node keeps its id and routing table between runs; a new id and only the
+    /// bootstrap nodes each time if `None`.
+    pub dht_state: Option<PathBuf>,
+    /// Also find peers on the local network through multicast announcements.
+    pub lsd: bool,
+    /// Whether connections to and from peers are encrypted.
+    pub encryption: EncryptionPolicy,
+    /// Also connect to peers over uTP, falling back to TCP for those that don't answer, and
+    /// accept uTP connections on the UDP port with the same number.
+    pub utp: bool,
+    /// Ask the router to forward the port to us, through NAT-PMP or UPnP, along with the UDP
+    /// port of the same number if the DHT or uTP uses it.
+    pub port_mapping: bool,
+    /// Most bytes per second to download, from peers and web seeds together; `None` for no
+    /// limit. In a session it covers all of its torrents.
+    pub max_download_rate: Option<usize>,
+    /// Other download limits for periods of the week, instead of `max_download_rate`.
+    pub schedule: Schedule,
+    /// Most peer connections open at once; in a session, over all of its torrents.
+    pub max_connections: usize,
+    /// Most peer connections open at once for one torrent.
+    pub max_torrent_connections: usize,
+    /// Most outgoing connections being set up at once; in a session, over all of its torrents.
+    pub max_half_open: usize,
+    /// In a session, most torrents downloading at once; the others are queued until one of
+    /// them ends. Torrents started by force don't count. `None` for no limit.
+    pub max_active_downloads: Option<usize>,
+    /// How the files being downloaded to get their disk space.
+    pub allocation: Allocation,
+    /// How blocks are read from and written to the files.
+    pub storage_backend: StorageBackend,
+    /// Where the files are kept until every piece is in, to be moved to the output path then.
+    pub incomplete: Incomplete,
+    /// Keeps the content somewhere else than in files at the output path, which then only
+    /// holds the resume data; `allocation` and `storage_backend` don't apply.
+    pub storage: Option<Arc<dyn OpenStorage>>,
+    /// Fetch pieces roughly in order, so media can be played while it downloads.
+    pub sequential: bool,
+    /// Fetch the first and last piece of every file before the others of the same priority,
+    /// e.g. to preview media or open archives, whose headers and indexes are there.
+    pub first_last_pieces: bool,
+    /// How long a peer may take to send any of the blocks we asked for before it counts as
+    /// snubbing us, and its piece goes to other peers.
+    pub request_timeout: Duration,
+    /// Proxy the announces to HTTP trackers go through; UDP trackers are announced to
+    /// directly, unless the proxy is the only way out.
+    pub tracker_proxy: Option<Proxy>,
+    /// The `User-Agent` and TLS settings of the announces to HTTP trackers.
+    pub tracker_http: HttpOptions,
+    /// Trackers announced to in place of or besides the torrent's.
+    pub trackers: TrackerOverride,
+    /// Optional parameters of the announces to trackers.
+    pub announce: AnnounceParams,
+    /// The local address or network interface that connections to peers, trackers and web
+    /// seeds go out from, and that a session listens on, instead of any.
+    pub bind: Option<Bind>,
+    /// Proxy the connections to peers and web seeds go through, over TCP; peers connecting
+    /// to us still come in directly.
+    pub peer_proxy: Option<Proxy>,
+    /// Blocklist of IP ranges never to connect to nor accept connections from, as
+    /// `IpFilter::load` reads it.
+    pub ip_filter: Option<PathBuf>,
+    /// Shell command to run once the download finishes, as `hook::run` does; not when the
+    /// data was complete before it started.
+    pub on_complete: Option<String>,
+    /// Bytes of the pieces written and read last kept in memory to upload from, 0 for none;
+    /// `DEFAULT_CACHE_SIZE` if not set.
+    pub cache_size: Option<usize>,
+    /// Download over files of full size at the output that no resume data records, which are
+    /// otherwise left alone and fail the download. Files picked up through their resume
+    /// data, or kept elsewhere while incomplete, aren't affected.
+    pub overwrite: bool,
+    /// Largest torrent in bytes to download; a larger one fails before anything is announced
+    /// or allocated, e.g. to keep a sandbox's disk from filling up.
+    pub max_size: Option<usize>,
+    /// In a session, what its finished torrents seed by until they reach their goal; with
+    /// `None` they stop once finished.
+    pub seeding: Option<SeedLimits>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> DownloadOptions {
+        DownloadOptions {
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            request_queue_time: Some(DEFAULT_REQUEST_QUEUE_TIME),
+            port: DEFAULT_PORT,
+            listen: Vec::new(),
+            random_port: false,
+            listen_retries: DEFAULT_LISTEN_RETRIES,
+            dht: false,
+            dht_state: None,
+            lsd: false,
+            encryption: EncryptionPolicy::default(),
+            utp: false,
+            port_mapping: false,
+            max_download_rate: None,
+            schedule: Schedule::default(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_torrent_connections: DEFAULT_MAX_TORRENT_CONNECTIONS,
+            max_half_open: DEFAULT_MAX_HALF_OPEN,
+            max_active_downloads: None,
+            allocation: Allocation::default(),
+            storage_backend: StorageBackend::default(),
+            incomplete: Incomplete::default(),
+            storage: None,
+            sequential: false,
+            first_last_pieces: false,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            tracker_proxy: None,
+            tracker_http: HttpOptions::default(),
+            trackers: TrackerOverride::default(),
+            announce: AnnounceParams::default(),
+            bind: None,
+            peer_proxy: None,
+            ip_filter: None,
+            on_complete: None,
+            cache_size: None,
+            overwrite: false,
+            max_size: None,
+            seeding: None,
+        }
+    }
+}
+
+/// A contiguous range of bytes within a piece, requested from a peer in one message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// Splits a piece into blocks of at most `block_size` bytes; the last piece of the torrent and
+/// the last block of a piece may be shorter.
+pub fn blocks_of_piece(
+    piece_length: u32,
+    piece_index: usize,
+    total_length: u64,
+    block_size: u32,
+) -> Vec<Block> {
+    let piece_start = piece_index as u64 * piece_length as u64;
+    let length = total_length
+        .saturating_sub(piece_start)
+        .min(piece_length as u64) as u32;
+    (0..length)
+        .step_by(block_size as usize)
+        .map(|begin| Block {
+            begin,
+            length: block_size.min(length - begin),
+        })
+        .collect()
+}
+
+/// The blocks of the piece at `piece_index` of `torrent`, which has to be within it.
+fn piece_blocks(torrent: &Torrent, piece_index: usize) -> Vec<Block> {
+    // in a v2 torrent the last piece of every file may be short, not just the last one
+    let piece_end = piece_index * torrent.info.piece_length + torrent.piece_size(piece_index);
+    blocks_of_piece(
+        torrent.info.piece_length as u32,
+        piece_index,
+        piece_end as u64,
+        BLOCK_SIZE,
+    )
+}
+
+/// A piece as its blocks arrive, shared by every peer it is downloaded from so that each block
+/// only has to come once. In endgame, a block one peer delivers is cancelled at the others that
+/// were asked for it; and a piece handed to another peer after the first one gave up on it
+/// only needs the blocks that are still missing.
+struct PieceBuffer {
+    state: Mutex<BufferState>,
+    /// Signalled whenever a block arrives.
+    arrived: watch::Sender<()>,
+}
+
+struct BufferState {
+    data: Vec<u8>,
+    received: Vec<bool>,
+    /// Whether whoever delivered the last block took the whole piece.
+    taken: bool,
+}
+
+impl PieceBuffer {
+    fn new(blocks: &[Block]) -> PieceBuffer {
+        PieceBuffer {
+            state: Mutex::new(BufferState {
+                data: vec![0; blocks.iter().map(|b| b.length as usize).sum()],
+                received: vec![false; blocks.len()],
+                taken: false,
+            }),
+            arrived: watch::Sender::new(()),
+        }
+    }
+
+    /// Puts in the block at `position`, unless it arrived already, and hands out the piece if
+    /// that was its last block.
+    fn insert(&self, position: usize, begin: u32, data: &[u8]) -> Inserted {
+        let mut state = self.state.lock().unwrap();
+        if state.taken || state.received[position] {
+            return Inserted::Redundant;
+        }
+        state.data[begin as usize..][..data.len()].copy_from_slice(data);
+        state.received[position] = true;
+        let complete = state.received.iter().all(|&received| received);
+        self.arrived.send_replace(());
+        if !complete {
+            return Inserted::Missing;
+        }
+        state.taken = true;
+        Inserted::Complete(std::mem::take(&mut state.data))
+    }
+
+    /// Which blocks arrived, or `None` once the piece was taken.
+    fn received(&self) -> Option<Vec<bool>> {
+        let state = self.state.lock().unwrap();
+        (!state.taken).then(|| state.received.clone())
+    }
+}
+
+/// What became of a block put into a `PieceBuffer`.
+enum Inserted {
+    /// Another peer sent it first.
+    Redundant,
+    /// Other blocks of the piece are still missing.
+    Missing,
+    /// It was the last block, and here is the piece.
+    Complete(Vec<u8>),
+}
+
+/// Where a session hands on the holepunch messages (BEP 55) its peer sends, and takes those to
+/// send it from.
+struct Holepunch {
+    /// The peer's address, which the messages handed on are marked with.
+    addr: SocketAddr,
+    /// Set once the peer's extension handshake says whether it speaks ut_holepunch.
+    supported: Arc<AtomicBool>,
+    received: mpsc::Sender<(SocketAddr, HolepunchMessage)>,
+    to_send: mpsc::Receiver<HolepunchMessage>,
+}
+
+/// What a session needs to serve its peer the pieces we have, besides downloading from it.
+pub(crate) struct Uploads {
+    torrent: Arc<Torrent>,
+    seeding: Arc<Seeding>,
+    slot: UploadSlot,
+    unchoked: watch::Receiver<bool>,
+    /// Whether the peer was last told it is unchoked.
+    unchoking: bool,
+    /// The pieces we have, as the download verifies them.
+    have: watch::Receiver<Bitfield>,
+    /// The pieces the peer was told we have.
+    announced: Bitfield,
+}
+
+impl Uploads {
+    pub(crate) fn new(
+        torrent: Arc<Torrent>,
+        seeding: Arc<Seeding>,
+        slot: UploadSlot,
+        have: watch::Receiver<Bitfield>,
+    ) -> Uploads {
+        Uploads {
+            torrent,
+            seeding,
+            unchoked: slot.unchoked(),
+            slot,
+            unchoking: false,
+            have,
+            announced: Bitfield::default(),
+        }
+    }
+
+    /// Waits for what the peer has to be told next: that the choker unchoked or choked it, or
+    /// of the pieces we got since it was last told.
+    async fn changed(&mut self) -> Vec<PeerMessage> {
+        loop {
+            tokio::select! {
+                Ok(()) = self.unchoked.changed() => {
+                    let unchoked = *self.unchoked.borrow_and_update();
+                    if unchoked != self.unchoking {
+                        self.unchoking = unchoked;
+                        return vec![match unchoked {
+                            true => PeerMessage::Unchoke,
+                            false => PeerMessage::Choke,
+                        }];
+                    }
+                }
+                Ok(()) = self.have.changed() => {
+                    let have = self.have.borrow_and_update().clone();
+                    let got = have.difference(&self.announced);
+                    self.announced = have;
+                    if !got.is_clear() {
+                        return got.ones().map(|index| PeerMessage::Have(index as u32)).collect();
+                    }
+                }
+                else => std::future::pending().await,
+            }
+        }
+    }
+}
+
+/// A connection to a peer after the handshake, tracking its choke state and the pieces it has.
+pub struct PeerSession<S> {
+    framed: Framed<S, MessageCodec>,
+    /// The pieces the peer said it has, as long as its bitfield or the last piece it announced
+    /// made it until the session is told how many pieces there are.
+    bitfield: Bitfield,
+    /// The length of the peer's bitfield message, to check once the piece count is known.
+    bitfield_length: Option<usize>,
+    /// How many pieces the torrent has, once `set_piece_count` said so.
+    piece_count: Option<usize>,
+    choked: bool,
+    pipeline_depth: usize,
+    /// Resizes the pipeline to the peer's download rate, starting from `pipeline_depth`, if
+    /// set.
+    pipeline_sizer: Option<PipelineSizer>,
+    /// What we told the peer over PEX, if we offered it.
+    pex: Option<PexState>,
+    /// The id the peer wants its ut_pex messages under, once its extension handshake said so.
+    pex_id: Option<u8>,
+    /// Peers the peer told us about over PEX that haven't been taken yet.
+    pex_peers: Vec<SocketAddr>,
+    /// The id the peer wants its ut_holepunch messages under, if it speaks it.
+    holepunch_id: Option<u8>,
+    holepunch: Option<Holepunch>,
+    /// Every block received waits for this before the next is taken.
+    download_limit: Arc<RateLimiter>,
+    request_timeout: Duration,
+    /// Whether the peer let the last piece's requests time out without sending anything; it
+    /// only gets one request at a time until it sends a block again.
+    snubbed: bool,
+    /// Whether both sides speak the Fast Extension (BEP 6), so the peer rejects the requests
+    /// it won't answer instead of silently dropping them.
+    fast: bool,
+    /// Whether the peer said it has every piece, which `bitfield` then doesn't record.
+    has_all: bool,
+    /// Pieces the peer lets us download while it chokes us.
+    allowed_fast: HashSet<usize>,
+    /// Pieces the peer rejected a request for, which aren't asked of it again.
+    rejected: HashSet<usize>,
+    /// Where the blocks received are counted, under the peer's address.
+    stats: Option<(Arc<SwarmStats>, SocketAddr)>,
+    /// When each outstanding block, by piece and offset, was requested.
+    requested_at: HashMap<(u32, u32), tokio::time::Instant>,
+    /// When we last sent the peer anything, for a keep-alive to follow in time.
+    last_sent: tokio::time::Instant,
+    /// When the peer last sent us anything, to notice when it went silent.
+    last_received: tokio::time::Instant,
+    /// Set when the peer is served what we have, not only downloaded from.
+    uploads: Option<Uploads>,
+    /// Whether the peer wants any of our pieces.
+    peer_interested: bool,
+    /// The DHT the node the peer runs is added to, and the peer's address.
+    dht: Option<(Arc<Dht>, SocketAddr)>,
+    /// The port of the peer's DHT node, once it sent it.
+    dht_port: Option<u16>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> PeerSession<S> {
+    /// Declares interest and waits until the peer unchokes us.
+    pub async fn start(stream: S) -> anyhow::Result<PeerSession<S>> {
+        PeerSession::open(stream, false, false, None).await
+    }
+
+    /// Like `start`, but first offers peer exchange (BEP 11) in an extension handshake, which
+    /// the peer has to have advertised support for in its handshake.
+    pub async fn start_with_pex(stream: S) -> anyhow::Result<PeerSession<S>> {
+        PeerSession::open(stream, true, false, None).await
+    }
+
+    /// Like `start`, with peer exchange and the Fast Extension if the peer advertised them in
+    /// its `handshake`. A peer with the Fast Extension may let us download some pieces while
+    /// it chokes us, and the session is ready as soon as it does.
+    pub async fn start_for(stream: S, handshake: &Handshake) -> anyhow::Result<PeerSession<S>> {
+        PeerSession::open(
+            stream,
+            handshake.supports_extensions(),
+            handshake.supports_fast(),
+            None,
+        )
+        .await
+    }
+
+    /// Like `start_for`, but never offers peer exchange, as for a private torrent (BEP 27).
+    pub async fn start_private_for(
+        stream: S,
+        handshake: &Handshake,
+    ) -> anyhow::Result<PeerSession<S>> {
+        PeerSession::open(stream, false, handshake.supports_fast(), None).await
+    }
+
+    /// Like `start_for`, or `start_private_for` for a private torrent, and the peer is also
+    /// told which pieces we have and served them while the choker lets it, with a have
+    /// message for every piece we get after.
+    pub(crate) async fn start_uploading_for(
+        stream: S,
+        handshake: &Handshake,
+        private: bool,
+        uploads: Uploads,
+    ) -> anyhow::Result<PeerSession<S>> {
+        let pex = handshake.supports_extensions() && !private;
+        PeerSession::open(stream, pex, handshake.supports_fast(), Some(uploads)).await
+    }
+
+    async fn open(
+        stream: S,
+        pex: bool,
+        fast: bool,
+        mut uploads: Option<Uploads>,
+    ) -> anyhow::Result<PeerSession<S>> {
+        let have = match &mut uploads {
+            Some(uploads) => {
+                uploads.announced = uploads.have.borrow_and_update().clone();
+                uploads.announced.clone()
+            }
+            None => Bitfield::default(),
+        };
+        let mut session = PeerSession {
+            framed: Framed::new(stream, MessageCodec),
+            bitfield: Bitfield::default(),
+            bitfield_length: None,
+            piece_count: None,
+            choked: true,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            pipeline_sizer: None,
+            pex: pex.then(PexState::default),
+            pex_id: None,
+            pex_peers: Vec::new(),
+            holepunch_id: None,
+            holepunch: None,
+            download_limit: RateLimiter::unlimited(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            snubbed: false,
+            fast,
+            has_all: false,
+            allowed_fast: HashSet::new(),
+            rejected: HashSet::new(),
+            stats: None,
+            requested_at: HashMap::new(),
+            last_sent: tokio::time::Instant::now(),
+            last_received: tokio::time::Instant::now(),
+            uploads,
+            peer_interested: false,
+            dht: None,
+            dht_port: None,
+        };
+        // with the Fast Extension the first message has to say which pieces we have
+        if have.is_clear() {
+            if fast {
+                session.send(PeerMessage::HaveNone).await?;
+            }
+        } else if fast && have.is_full() {
+            session.send(PeerMessage::HaveAll).await?;
+        } else {
+            let bitfield = have.as_bytes().to_vec();
+            session.send(PeerMessage::Bitfield(bitfield)).await?;
+        }
+        if pex {
+            let handshake = ExtensionHandshake {
+                m: BTreeMap::from([("ut_pex".to_owned(), UT_PEX_ID)]),
+                metadata_size: None,
+            };
+            session.send(handshake.to_message()?).await?;
+        }
+        session.send(PeerMessage::Interested).await?;
+        session.wait_for_requestable().await?;
+        Ok(session)
+    }
+
+    pub fn has_piece(&self, piece_index: usize) -> bool {
+        self.has_all || self.bitfield.get(piece_index)
+    }
+
+    /// Whether the piece can be asked of the peer now: it has it, didn't reject it before, and
+    /// either unchoked us or allows it while choked.
+    fn may_request(&self, piece_index: usize) -> bool {
+        self.has_piece(piece_index)
+            && !self.rejected.contains(&piece_index)
+            && (!self.choked || self.allowed_fast.contains(&piece_index))
+    }
+
+    /// The peer's bitfield, filled in for `piece_count` pieces if it said it has all of them.
+    fn pieces(&self, piece_count: usize) -> Bitfield {
+        if self.has_all {
+            return Bitfield::full(piece_count);
+        }
+        let mut pieces = self.bitfield.clone();
+        pieces.resize(piece_count);
+        pieces
+    }
+
+    /// Checks what the peer announced so far against the torrent's `piece_count`, and from now
+    /// on everything it announces: a bitfield of the wrong length, or a piece past the end,
+    /// ends the session.
+    pub fn set_piece_count(&mut self, piece_count: usize) -> anyhow::Result<()> {
+        self.piece_count = Some(piece_count);
+        if let Some(length) = self.bitfield_length {
+            if length != piece_count.div_ceil(8) {
+                anyhow::bail!(
+                    "peer sent a bitfield of {} bytes for {} pieces",
+                    length,
+                    piece_count
+                );
+            }
+        }
+        let past_end = self.bitfield.select(self.bitfield.rank(piece_count));
+        for index in past_end
+            .into_iter()
+            .chain(self.allowed_fast.iter().copied())
+        {
+            self.check_piece(index)?;
+        }
+        self.bitfield.resize(piece_count);
+        Ok(())
+    }
+
+    /// Fails for a piece the peer announced that the torrent doesn't have, or, as long as the
+    /// piece count isn't known, that no bitfield could hold.
+    fn check_piece(&self, index: usize) -> anyhow::Result<()> {
+        match self.piece_count {
+            Some(count) if index >= count => anyhow::bail!(
+                "peer announced piece {}, but the torrent has {} pieces",
+                index,
+                count
+            ),
+            None if index >= MAX_PIECES => {
+                anyhow::bail!("peer announced piece {}, past any bitfield", index)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sets how many block requests are kept outstanding at once; at least one always is.
+    pub fn set_pipeline_depth(&mut self, depth: usize) {
+        self.pipeline_depth = depth.max(1);
+    }
+
+    /// Has the pipeline hold `queue_time` of the peer's download rate once it is measured,
+    /// as `PipelineSizer` does, rather than always the depth `set_pipeline_depth` set; `None`
+    /// goes back to that.
+    pub fn set_request_queue_time(&mut self, queue_time: Option<Duration>) {
+        self.pipeline_sizer = queue_time.map(PipelineSizer::new);
+    }
+
+    /// How many block requests are kept outstanding now.
+    pub fn pipeline_depth(&self) -> usize {
+        match &self.pipeline_sizer {
+            Some(sizer) => sizer.depth(self.pipeline_depth),
+            None => self.pipeline_depth,
+        }
+    }
+
+    /// Sets how long the peer may take to send any of the blocks we requested before a piece
+    /// download gives up on it.
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        self.request_timeout = timeout;
+    }
+
+    /// Tells whether the peer stopped sending the blocks we asked for.
+    pub fn is_snubbed(&self) -> bool {
+        self.snubbed
+    }
+
+    /// Shares `limit` with the other connections it is given to; the peer's data is only read
+    /// as fast as it allows.
+    /// Counts every block received in `stats`, as coming from `addr`.
+    pub fn set_stats(&mut self, stats: Arc<SwarmStats>, addr: SocketAddr) {
+        self.stats = Some((stats, addr));
+    }
+
+    pub fn set_download_limit(&mut self, limit: Arc<RateLimiter>) {
+        self.download_limit = limit;
+    }
+
+    /// Tells the peer at `addr` the port of our `dht` if its `handshake` advertised a DHT
+    /// node (BEP 5), and adds the node of the port it sends, before or after, to `dht` once
+    /// it answers a ping.
+    pub async fn set_dht(
+        &mut self,
+        dht: Arc<Dht>,
+        addr: SocketAddr,
+        handshake: &Handshake,
+    ) -> anyhow::Result<()> {
+        if handshake.supports_dht() {
+            let port = dht.local_addr()?.port();
+            self.send(PeerMessage::Port(port)).await?;
+        }
+        self.dht = Some((dht, addr));
+        self.add_dht_node();
+        Ok(())
+    }
+
+    fn add_dht_node(&self) {
+        if let (Some((dht, addr)), Some(port)) = (&self.dht, self.dht_port) {
+            let (dht, node) = (dht.clone(), SocketAddr::new(addr.ip(), port));
+            // answering puts it in the routing table
+            tokio::spawn(async move { dht.ping(node).await });
+        }
+    }
+
+    /// Tells the peer which peers joined and left `swarm` since the last time, if it takes
+    /// PEX messages and one is due.
+    pub async fn send_pex(&mut self, swarm: &HashSet<SocketAddr>) -> anyhow::Result<()> {
+        let (Some(their_id), Some(state)) = (self.pex_id, &mut self.pex) else {
+            return Ok(());
+        };
+        if let Some(message) = state.update(swarm) {
+            let message = message.to_message(their_id)?;
+            self.send(message).await?;
+        }
+        Ok(())
+    }
+
+    /// The peers this peer told us about over PEX since the last call.
+    pub fn take_pex_peers(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.pex_peers)
+    }
+
+    /// Offers hole punching (BEP 55) in another extension handshake, if the session offered
+    /// extensions at all. The holepunch messages the peer sends then go to `received`, marked
+    /// with its `addr`, and those that arrive from `to_send` are sent to it while the session
+    /// waits for messages. Whether the peer speaks ut_holepunch ends up in `supported`;
+    /// messages for a peer that doesn't are dropped.
+    pub async fn offer_holepunch(
+        &mut self,
+        addr: SocketAddr,
+        supported: Arc<AtomicBool>,
+        received: mpsc::Sender<(SocketAddr, HolepunchMessage)>,
+        to_send: mpsc::Receiver<HolepunchMessage>,
+    ) -> anyhow::Result<()> {
+        if self.pex.is_none() {
+            return Ok(());
+        }
+        // a later handshake replaces what the first one offered
+        let handshake = ExtensionHandshake {
+            m: BTreeMap::from([
+                ("ut_pex".to_owned(), UT_PEX_ID),
+                ("ut_holepunch".to_owned(), UT_HOLEPUNCH_ID),
+            ]),
+            metadata_size: None,
+        };
+        self.send(handshake.to_message()?).await?;
+        supported.store(self.holepunch_id.is_some(), Ordering::Relaxed);
+        self.holepunch = Some(Holepunch {
+            addr,
+            supported,
+            received,
+            to_send,
+        });
+        Ok(())
+    }
+
+    async fn send(&mut self, message: PeerMessage) -> anyhow::Result<()> {
+        self.framed.send(message).await?;
+        self.last_sent = tokio::time::Instant::now();
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        self.framed.flush().await?;
+        self.last_sent = tokio::time::Instant::now();
+        Ok(())
+    }
+
+    /// Reads the next message, keeping track of state changes announced by the peer. While
+    /// waiting, the peer gets a keep-alive whenever we sent it nothing for
+    /// `KEEP_ALIVE_INTERVAL`, and a peer that sent nothing for `IDLE_TIMEOUT` is given up on.
+    async fn receive(&mut self) -> anyhow::Result<PeerMessage> {
+        let message = loop {
+            let keep_alive = self.last_sent + KEEP_ALIVE_INTERVAL;
+            let silent = self.last_received + IDLE_TIMEOUT;
+            let holepunch = async {
+                match &mut self.holepunch {
+                    Some(holepunch) => holepunch.to_send.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let uploads = async {
+                match &mut self.uploads {
+                    Some(uploads) => uploads.changed().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                message = self.framed.next() => break message,
+                messages = uploads => {
+                    for message in messages {
+                        self.framed.feed(message).await?;
+                    }
+                    self.flush().await?;
+                }
+                Some(message) = holepunch => {
+                    if let Some(their_id) = self.holepunch_id {
+                        self.send(message.to_message(their_id)).await?;
+                    }
+                }
+                () = tokio::time::sleep_until(keep_alive) => {
+                    self.send(PeerMessage::KeepAlive).await?;
+                }
+                () = tokio::time::sleep_until(silent) => {
+                    anyhow::bail!("peer sent nothing for {} seconds", IDLE_TIMEOUT.as_secs());
+                }
+            }
+        };
+        let Some(message) = message else {
+            anyhow::bail!("peer closed the connection");
+        };
+        let message = message?;
+        self.last_received = tokio::time::Instant::now();
+        match &message {
+            PeerMessage::Choke => self.choked = true,
+            PeerMessage::Unchoke => self.choked = false,
+            PeerMessage::Interested | PeerMessage::NotInterested => {
+                self.peer_interested = message == PeerMessage::Interested;
+                if let Some(uploads) = &self.uploads {
+                    uploads.slot.set_interested(self.peer_interested);
+                }
+            }
+            &PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => self.serve(index, begin, length).await?,
+            PeerMessage::Bitfield(bytes) => {
+                self.bitfield = match self.piece_count {
+                    Some(count) => Bitfield::from_message(bytes, count)?,
+                    None => Bitfield::from_bytes(bytes, bytes.len() * 8),
+                };
+                self.bitfield_length = Some(bytes.len());
+            }
+            PeerMessage::Have(index) => {
+                let index = *index as usize;
+                self.check_piece(index)?;
+                if self.bitfield.len() <= index {
+                    self.bitfield.resize(index + 1);
+                }
+                self.bitfield.set(index);
+            }
+            PeerMessage::HaveAll if self.fast => self.has_all = true,
+            PeerMessage::HaveNone if self.fast => {
+                self.has_all = false;
+                self.bitfield = Bitfield::new(self.piece_count.unwrap_or_default());
+                self.bitfield_length = None;
+            }
+            PeerMessage::AllowedFast(index) if self.fast => {
+                self.check_piece(*index as usize)?;
+                self.allowed_fast.insert(*index as usize);
+            }
+            &PeerMessage::Port(port) => {
+                self.dht_port = Some(port);
+                self.add_dht_node();
+            }
+            // malformed extension messages only cost us the peer exchange
+            PeerMessage::Extended { id: 0, payload } if self.pex.is_some() => {
+                if let Ok(handshake) = bencode::from_bytes::<ExtensionHandshake>(payload) {
+                    self.pex_id = handshake.m.get("ut_pex").copied().filter(|&id| id != 0);
+                    self.holepunch_id = handshake
+                        .m
+                        .get("ut_holepunch")
+                        .copied()
+                        .filter(|&id| id != 0);
+                    if let Some(holepunch) = &self.holepunch {
+                        let supported = self.holepunch_id.is_some();
+                        holepunch.supported.store(supported, Ordering::Relaxed);
+                    }
+                }
+            }
+            PeerMessage::Extended {
+                id: UT_HOLEPUNCH_ID,
+                payload,
+            } if self.holepunch.is_some() => {
+                if let (Some(holepunch), Ok(message)) =
+                    (&self.holepunch, HolepunchMessage::parse(payload))
+                {
+                    // a peer flooding us with them only loses the ones that don't fit
+                    let _ = holepunch.received.try_send((holepunch.addr, message));
+                }
+            }
+            PeerMessage::Extended {
+                id: UT_PEX_ID,
+                payload,
+            } if self.pex.is_some() => {
+                let added = bencode::from_bytes::<PexMessage>(payload)
+                    .ok()
+                    .and_then(|pex| pex.added_peers().ok());
+                if let Some(added) = added {
+                    self.pex_peers.extend(added.into_iter().take(MAX_PEX_PEERS));
+                }
+            }
+            _ => {}
+        }
+        Ok(message)
+    }
+
+    /// Sends the peer the block it requested, if we have its piece, told it so and unchoked
+    /// it; a peer with the Fast Extension has other requests rejected.
+    async fn serve(&mut self, index: u32, begin: u32, length: u32) -> anyhow::Result<()> {
+        let Some(uploads) = &self.uploads else {
+            return Ok(());
+        };
+        let block = match uploads.unchoking && uploads.announced.get(index as usize) {
+            true => {
+                let torrent = &uploads.torrent;
+                let seeding = &uploads.seeding;
+                seeding.read_block(torrent, index, begin, length).await?
+            }
+            false => None,
+        };
+        let Some(block) = block else {
+            if self.fast {
+                let rejected = PeerMessage::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                };
+                self.send(rejected).await?;
+            }
+            return Ok(());
+        };
+        self.send(PeerMessage::Piece {
+            index,
+            begin,
+            block,
+        })
+        .await?;
+        if let Some(uploads) = &self.uploads {
+            uploads.seeding.sent(&uploads.slot, length as usize);
+        }
+        if let Some((stats, addr)) = &self.stats {
+            stats.uploaded(Some(*addr), length as usize);
+        }
+        Ok(())
+    }
+
+    async fn wait_for_unchoke(&mut self) -> anyhow::Result<()> {
+        while self.choked {
+            self.receive().await?;
+        }
+        Ok(())
+    }
+
+    /// Like `wait_for_unchoke`, but also done once the peer allows us any piece while choked.
+    async fn wait_for_requestable(&mut self) -> anyhow::Result<()> {
+        while self.choked && self.allowed_fast.is_empty() {
+            self.receive().await?;
+        }
+        Ok(())
+    }
+
+    /// Downloads all blocks of a piece and checks them against the piece hash.
+    ///
+    /// Up to the pipeline depth of requests are kept in flight, with a new one sent for every
+    /// block that arrives, so the transfer isn't bound by round trips. A peer that sends none of
+    /// them for the request timeout is snubbing us, and fails the download.
+    pub async fn download_piece(
+        &mut self,
+        torrent: &Torrent,
+        piece_index: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let piece = self
+            .download_piece_unless(torrent, piece_index, std::future::pending())
+            .await?;
+        piece.ok_or_else(|| anyhow::anyhow!("peer stopped sending blocks"))
+    }
+
+    /// Like `download_piece`, but gives up as soon as `cancel` completes, e.g. because another
+    /// peer delivered the piece first. The outstanding requests are cancelled and `None` is
+    /// returned. The same happens when the peer snubs us, or when it chokes us and doesn't
+    /// unchoke us again within the request timeout, so that the piece can go to other peers.
+    pub async fn download_piece_unless(
+        &mut self,
+        torrent: &Torrent,
+        piece_index: usize,
+        cancel: impl Future<Output = ()>,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        if piece_index >= torrent.piece_count() {
+            anyhow::bail!(
+                "piece {} is out of range, the torrent has {} pieces",
+                piece_index,
+                torrent.piece_count()
+            );
+        }
+        let buffer = PieceBuffer::new(&piece_blocks(torrent, piece_index));
+        let piece = self
+            .fetch_piece_unless(torrent, piece_index, &buffer, cancel)
+            .await?;
+        if let Some(piece) = &piece {
+            if !torrent.verify_piece(piece_index, piece) {
+                anyhow::bail!("piece {} failed hash verification", piece_index);
+            }
+        }
+        Ok(piece)
+    }
+
+    /// Like `download_piece_unless`, but leaves the hash check to the caller, and puts the
+    /// blocks in `buffer`, which other peers may be filling as well. Only the blocks missing
+    /// from it are requested, the requests for blocks that arrive from elsewhere are cancelled,
+    /// and only the peer that delivers the last block gets the piece; for the others this
+    /// returns `None`.
+    async fn fetch_piece_unless(
+        &mut self,
+        torrent: &Torrent,
+        piece_index: usize,
+        buffer: &PieceBuffer,
+        cancel: impl Future<Output = ()>,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let blocks = piece_blocks(torrent, piece_index);
+        let mut arrived = buffer.arrived.subscribe();
+        let Some(mut received) = buffer.received() else {
+            return Ok(None);
+        };
+        let mut requested = received.clone();
+        self.request_more(piece_index, &blocks, &received, &mut requested)
+            .await?;
+        let mut cancel = std::pin::pin!(cancel);
+        let mut deadline = tokio::time::Instant::now() + self.request_timeout;
+        loop {
+            let message = tokio::select! {
+                message = self.receive() => message?,
+                changed = arrived.changed() => {
+                    changed?;
+                    let Some(now) = buffer.received() else {
+                        // another peer delivered the last block
+                        self.cancel_outstanding(piece_index, &blocks, &received, &requested)
+                            .await?;
+                        return Ok(None);
+                    };
+                    // the blocks that came from elsewhere needn't come from this peer too
+                    let elsewhere: Vec<bool> = now
+                        .iter()
+                        .zip(received.iter().zip(&requested))
+                        .map(|(&now, (&before, &requested))| now && !before && requested)
+                        .collect();
+                    self.cancel_outstanding(piece_index, &blocks, &received, &elsewhere)
+                        .await?;
+                    for (requested, &now) in requested.iter_mut().zip(&now) {
+                        *requested |= now;
+                    }
+                    received = now;
+                    self.request_more(piece_index, &blocks, &received, &mut requested)
+                        .await?;
+                    continue;
+                }
+                () = &mut cancel => {
+                    self.cancel_outstanding(piece_index, &blocks, &received, &requested)
+                        .await?;
+                    return Ok(None);
+                }
+                () = tokio::time::sleep_until(deadline) => {
+                    debug!(piece = piece_index, "peer snubbed us");
+                    self.snubbed = true;
+                    self.cancel_outstanding(piece_index, &blocks, &received, &requested)
+                        .await?;
+                    return Ok(None);
+                }
+            };
+            match message {
+                PeerMessage::Piece {
+                    index,
+                    begin,
+                    block: data,
+                } if index as usize == piece_index => {
+                    let Some(position) = blocks.iter().position(|b| b.begin == begin) else {
+                        anyhow::bail!("peer sent a block at unexpected offset {}", begin);
+                    };
+                    if received[position] {
+                        self.redundant(data.len());
+                        continue;
+                    }
+                    let block = blocks[position];
+                    if data.len() != block.length as usize {
+                        anyhow::bail!(
+                            "peer sent {} bytes for a {}-byte block",
+                            data.len(),
+                            block.length
+                        );
+                    }
+                    let requested_at = self.requested_at.remove(&(index, begin));
+                    let now = tokio::time::Instant::now();
+                    let round_trip = requested_at.map(|at| now.duration_since(at));
+                    if let Some((stats, addr)) = &self.stats {
+                        stats.downloaded(Some(*addr), data.len(), round_trip);
+                    }
+                    if let Some(sizer) = &mut self.pipeline_sizer {
+                        sizer.received(data.len(), round_trip, now);
+                    }
+                    self.download_limit.acquire(data.len()).await;
+                    deadline = tokio::time::Instant::now() + self.request_timeout;
+                    self.snubbed = false;
+                    received[position] = true;
+                    requested[position] = true;
+                    match buffer.insert(position, begin, &data) {
+                        Inserted::Complete(piece) => return Ok(Some(piece)),
+                        Inserted::Missing => {}
+                        Inserted::Redundant => {
+                            if let Some((stats, addr)) = &self.stats {
+                                stats.redundant(Some(*addr), data.len());
+                            }
+                        }
+                    }
+                    self.request_more(piece_index, &blocks, &received, &mut requested)
+                        .await?;
+                }
+                // the peer goes on serving a piece it allows us while choked
+                PeerMessage::Choke if self.fast && self.allowed_fast.contains(&piece_index) => {}
+                // a choke discards our pending requests, so ask again once unchoked
+                PeerMessage::Choke => {
+                    requested.clone_from(&received);
+                    self.requested_at.clear();
+                    let unchoked = tokio::time::timeout_at(deadline, self.wait_for_unchoke());
+                    let Ok(unchoked) = unchoked.await else {
+                        return Ok(None);
+                    };
+                    unchoked?;
+                    self.request_more(piece_index, &blocks, &received, &mut requested)
+                        .await?;
+                }
+                // the peer won't send this piece, so other peers get to
+                PeerMessage::RejectRequest { index, begin, .. }
+                    if self.fast && index as usize == piece_index =>
+                {
+                    debug!(piece = piece_index, "peer rejected our request");
+                    if let Some(position) = blocks.iter().position(|b| b.begin == begin) {
+                        requested[position] = received[position];
+                    }
+                    self.requested_at.remove(&(index, begin));
+                    self.rejected.insert(piece_index);
+                    self.cancel_outstanding(piece_index, &blocks, &received, &requested)
+                        .await?;
+                    return Ok(None);
+                }
+                // a block of an earlier piece that was on its way when we cancelled it
+                PeerMessage::Piece { block, .. } => self.redundant(block.len()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Counts a block of `bytes` the peer sent that we needn't have downloaded.
+    fn redundant(&self, bytes: usize) {
+        if let Some((stats, addr)) = &self.stats {
+            stats.downloaded(Some(*addr), bytes, None);
+            stats.redundant(Some(*addr), bytes);
+        }
+    }
+
+    /// Requests blocks that haven't been requested yet, until the pipeline is full.
+    async fn request_more(
+        &mut self,
+        piece_index: usize,
+        blocks: &[Block],
+        received: &[bool],
+        requested: &mut [bool],
+    ) -> anyhow::Result<()> {
+        let outstanding = received
+            .iter()
+            .zip(requested.iter())
+            .filter(|&(&received, &requested)| requested && !received)
+            .count();
+        let depth = if self.snubbed {
+            1
+        } else {
+            self.pipeline_depth()
+        };
+        let mut sent = 0;
+        for (block, requested) in blocks.iter().zip(requested.iter_mut()) {
+            if outstanding + sent >= depth {
+                break;
+            }
+            if *requested {
+                continue;
+            }
+            self.framed
+                .feed(PeerMessage::Request {
+                    index: piece_index as u32,
+                    begin: block.begin,
+                    length: block.length,
+                })
+                .await?;
+            *requested = true;
+            sent += 1;
+            let now = tokio::time::Instant::now();
+            self.requested_at
+                .insert((piece_index as u32, block.begin), now);
+        }
+        if sent > 0 {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn cancel_outstanding(
+        &mut self,
+        piece_index: usize,
+        blocks: &[Block],
+        received: &[bool],
+        requested: &[bool],
+    ) -> anyhow::Result<()> {
+        let outstanding = blocks
+            .iter()
+            .zip(received.iter().zip(requested))
+            .filter(|&(_, (&received, &requested))| requested && !received);
+        for (block, _) in outstanding {
+            self.requested_at.remove(&(piece_index as u32, block.begin));
+            self.framed
+                .feed(PeerMessage::Cancel {
+                    index: piece_index as u32,
+                    begin: block.begin,
+                    length: block.length,
+                })
+                .await?;
+        }
+        self.flush().await?;
+        Ok(())
+    }
+}
+
+/// Tries `peers` in order until one of them delivers a verified copy of the piece.
+pub async fn download_piece_from_peers(
+    torrent: &Torrent,
+    peers: &[SocketAddr],
+    piece_index: usize,
+    peer_id: [u8; 20],
+) -> crate::Result<Vec<u8>> {
+    async {
+        let mut last_error = anyhow::anyhow!("tracker returned no peers");
+        for &addr in peers {
+            let attempt = async {
+                let (stream, handshake) = peer::connect(addr, torrent.info_hash(), peer_id).await?;
+                let mut session = PeerSession::start_for(stream, &handshake).await?;
+                session.set_piece_count(torrent.piece_count())?;
+                if !session.has_piece(piece_index) {
+                    anyhow::bail!("peer doesn't have piece {}", piece_index);
+                }
+                session.download_piece(torrent, piece_index).await
+            };
+            match attempt.await {
+                Ok(piece) => return Ok(piece),
+                Err(e) => last_error = e.context(format!("peer {}", addr)),
+            }
+        }
+        Err(last_error)
+    }
+    .await
+    .map_err(crate::Error::from)
+}
+
+/// Connects to every peer concurrently and writes verified pieces to `output` as they arrive.
+#[tracing::instrument(name = "torrent", skip_all, fields(name = %torrent.info.name))]
+pub async fn download(
+    torrent: &Torrent,
+    peers: &[SocketAddr],
+    peer_id: [u8; 20],
+    output: &Path,
+    options: &DownloadOptions,
+) -> crate::Result<()> {
+    check_max_size(torrent, options)?;
+    async {
+        let (peer_tx, peer_rx) = mpsc::channel(1);
+        peer_tx.send(peers.to_vec()).await?;
+        drop(peer_tx);
+        // nobody can connect to us without a listener
+        let (_, inbound) = mpsc::channel(1);
+        let (progress, _) = watch::channel(Progress {
+            left: torrent.total_length(),
+            ..Progress::default()
+        });
+        // nobody connects to us here, so the socket only opens connections and any port does
+        let utp = if options.utp {
+            Some(Arc::new(UtpSocket::listen(0, options.bind.as_ref())?))
+        } else {
+            None
+        };
+        let download_limit = Arc::new(RateLimiter::new(options.max_download_rate));
+        let scheduler = follow_schedule(options, &download_limit);
+        let peers = PeerSources {
+            announced: peer_rx,
+            inbound,
+            utp,
+            dht: None,
+            download_limit,
+            slots: Arc::new(ConnectionSlots::new(
+                options.max_connections,
+                options.max_half_open,
+            )),
+            bans: Arc::new(ban_list(options).await?),
+            file_priorities: watch::channel(Vec::new()).1,
+            read_position: watch::channel(0).1,
+            piece_deadlines: watch::channel(HashMap::new()).1,
+            stats: Arc::new(SwarmStats::new()),
+            shutdown: watch::channel(false).1,
+            trackers: None,
+            tasks: Supervisor::new(),
+        };
+        let events = EventSender::unobserved(torrent.info_hash());
+        let downloaded =
+            download_from(torrent, peers, peer_id, output, options, &progress, &events).await;
+        if let Some(scheduler) = scheduler {
+            scheduler.abort();
+        }
+        downloaded
+    }
+    .await
+    .map_err(crate::Error::from)
+}
+
+/// Switches `download_limit` to the limits of the schedule in `options`, if it has one.
+pub(crate) fn follow_schedule(
+    options: &DownloadOptions,
+    download_limit: &Arc<RateLimiter>,
+) -> Option<JoinHandle<()>> {
+    if options.schedule.is_empty() {
+        return None;
+    }
+    let usual = Limits {
+        download: options.max_download_rate,
+        upload: None,
+    };
+    let download_limit = download_limit.clone();
+    Some(tokio::spawn(schedule::follow(
+        options.schedule.clone(),
+        usual,
+        move |limits| download_limit.set_rate(limits.download),
+    )))
+}
+
+/// Fails if `torrent` is larger than the `max_size` of `options`.
+pub(crate) fn check_max_size(torrent: &Torrent, options: &DownloadOptions) -> crate::Result<()> {
+    match options.max_size {
+        Some(max_size) if torrent.total_length() > max_size => {
+            Err(crate::Error::Disk(anyhow::anyhow!(
+                "{} is {} bytes, more than the most allowed, {}",
+                torrent.info.name,
+                torrent.total_length(),
+                max_size
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// An empty ban list, but for the ranges of the IP filter in `options`.
+pub(crate) async fn ban_list(options: &DownloadOptions) -> anyhow::Result<BanList> {
+    Ok(match &options.ip_filter {
+        Some(path) => BanList::with_filter(IpFilter::load(path).await?),
+        None => BanList::new(),
+    })
+}
+
+/// Like `download`, but gets its peers from the torrent's trackers and keeps re-announcing
+/// for fresh ones until the download is done. Peers that find us through the trackers may
+/// also connect to the port in `options`.
+pub async fn download_with_tracker(
+    torrent: &Torrent,
+    peer_id: [u8; 20],
+    output: &Path,
+    options: &DownloadOptions,
+) -> crate::Result<()> {
+    async {
+        let session = Session::new(peer_id, options.clone()).await?;
+        let mut state = session.add_torrent(torrent.clone(), output)?;
+        let state = state
+            .wait_for(|state| {
+                matches!(
+                    state,
+                    TorrentState::Finished | TorrentState::Failed(_) | TorrentState::Paused
+                )
+            })
+            .await?
+            .clone();
+        match state {
+            TorrentState::Failed(error) => Err(anyhow::anyhow!(error)),
+            // nothing else pauses the torrent of a session of its own
+            TorrentState::Paused => Err(crate::Error::Disk(anyhow::anyhow!(
+                "no disk space left to write {}",
+                output.display()
+            ))
+            .into()),
+            _ => Ok(()),
+        }
+    }
+    .await
+    .map_err(crate::Error::from)
+}
+
+/// A peer that connected to us, with its handshake.
+pub(crate) type InboundPeer = (PeerStream, Handshake);
+
+/// How the peers of a swarm find us, and how we find them besides the trackers.
+pub(crate) struct Discovery {
+    /// The port peers connect to us on.
+    pub port: u16,
+    /// Peers that connected to us on `port`.
+    pub inbound: mpsc::Receiver<InboundPeer>,
+    pub dht: Option<Arc<Dht>>,
+    pub lsd: Option<Arc<Lsd>>,
+    /// The socket uTP connections to peers are opened from, if uTP is enabled.
+    pub utp: Option<Arc<UtpSocket>>,
+    /// What the download from all of the swarm's peers is limited to.
+    pub download_limit: Arc<RateLimiter>,
+    /// Where the swarm's connections take their slots from.
+    pub slots: Arc<ConnectionSlots>,
+    /// The peers not to connect to, and where the ones sending bad pieces are counted.
+    pub bans: Arc<BanList>,
+    /// How eagerly each file is downloaded, as for `picker::piece_priorities`.
+    pub file_priorities: watch::Receiver<Vec<Priority>>,
+    /// The piece a sequential download goes on from, e.g. where the data is being read.
+    pub read_position: watch::Receiver<usize>,
+    /// When pieces are wanted by, for `PiecePicker::set_deadlines`.
+    pub piece_deadlines: watch::Receiver<HashMap<usize, Instant>>,
+    /// Where what is transferred with each peer is counted.
+    pub stats: Arc<SwarmStats>,
+    /// Set to stop the download, keeping what it got so far.
+    pub shutdown: watch::Receiver<bool>,
+    /// Where the trackers' view of our address is counted.
+    pub external_ip: ExternalIp,
+}
+
+/// Downloads a torrent with peers from its trackers, the DHT and the local network if they
+/// are enabled, and those connecting to us.
+///
+/// Without either of them a torrent whose trackers all fail the first announce can't find any
+/// peers, so the download fails right away unless it has web seeds; with them, it goes on
+/// with the peers they find. A private torrent (BEP 27) uses neither, nor peer exchange, so it
+/// only gets its peers from its own trackers and those connecting to us.
+#[tracing::instrument(name = "torrent", skip_all, fields(name = %torrent.info.name))]
+pub(crate) async fn download_from_swarm(
+    torrent: &Torrent,
+    peer_id: [u8; 20],
+    discovery: Discovery,
+    output: &Path,
+    options: &DownloadOptions,
+    events: EventSender,
+) -> anyhow::Result<()> {
+    let private = torrent.is_private();
+    let dht = discovery.dht.filter(|_| !private);
+    let lsd = discovery.lsd.filter(|_| !private);
+    let mut trackers = TrackerList::with_override(torrent, &options.trackers)?
+        .with_proxy(options.tracker_proxy.clone())
+        .with_bind(options.bind.clone())
+        .with_http(options.tracker_http.clone())
+        .with_external_ip(discovery.external_ip);
+    let info_hash = torrent.info_hash();
+    let mut request = TrackerRequest::new(torrent, peer_id, discovery.port);
+    request.event = Some(Event::Started);
+    request.params = options.announce.clone();
+    // a download killed before it told the trackers it stopped goes on with the same session,
+    // so they see the totals of all its runs
+    if let Some(resume) = ResumeData::load_recorded(torrent, output).await {
+        request.uploaded = resume.uploaded;
+        request.downloaded = resume.downloaded;
+        // the trackers know us by the key of the session, not the one made up for this run
+        if resume.tracker.in_session() {
+            request.event = None;
+            request.params.key = resume.tracker.key.or(request.params.key);
+        }
+        trackers = trackers.with_state(resume.tracker);
+    }
+    let (peer_tx, peer_rx) = mpsc::channel(4);
+    let (progress_tx, progress_rx) = watch::channel(Progress {
+        left: torrent.total_length(),
+        ..Progress::default()
+    });
+    let announcers = match trackers.announce(&info_hash, &request).await {
+        Ok(response) => {
+            let wait = response.reannounce_after();
+            // nobody in the swarm yet isn't an error: the announcers ask again when it says to
+            if response.peers.addrs.is_empty() {
+                info!(retry_in = ?wait, "no peers available yet, will retry");
+            }
+            peer_tx.send(response.peers.addrs).await?;
+            let announce = |info_hash, wait| {
+                let announcer = announce_periodically(
+                    trackers.clone(),
+                    info_hash,
+                    request.clone(),
+                    wait,
+                    peer_tx.clone(),
+                    progress_rx.clone(),
+                    events.clone(),
+                );
+                tokio::spawn(announcer.in_current_span())
+            };
+            // a hybrid torrent is also in the swarm of its v2 info hash, announced right away
+            let mut announcers = vec![announce(info_hash, wait)];
+            let others = torrent.info_hashes().into_iter().skip(1);
+            announcers.extend(others.map(|other| announce(other, Duration::ZERO)));
+            announcers
+        }
+        Err(e) => {
+            events.send(EventKind::TrackerError(format!("{:#}", e)));
+            if dht.is_none() && lsd.is_none() && WebSeed::from_torrent(torrent).is_empty() {
+                return Err(e.into());
+            }
+            Vec::new()
+        }
+    };
+    // unlike the announcers, these have nothing to say when the download stops, so they go
+    // with it however it ends
+    let mut tasks = Supervisor::new();
+    if let Some(dht) = dht.clone() {
+        let lookup = dht::announce_periodically(
+            dht,
+            info_hash,
+            discovery.port,
+            peer_tx.clone(),
+            progress_rx.clone(),
+        );
+        tasks.spawn("DHT lookup", lookup.map(Ok));
+    }
+    if let Some(lsd) = lsd {
+        let announcer = lsd::announce_periodically(
+            lsd,
+            info_hash,
+            discovery.port,
+            peer_tx.clone(),
+            progress_rx,
+        );
+        tasks.spawn("local peer discovery", announcer.map(Ok));
+    }
+    // the download ends once the announcers are gone and no peer is left
+    drop(peer_tx);
+
+    let peers = PeerSources {
+        announced: peer_rx,
+        inbound: discovery.inbound,
+        utp: discovery.utp,
+        dht,
+        download_limit: discovery.download_limit,
+        slots: discovery.slots,
+        bans: discovery.bans,
+        file_priorities: discovery.file_priorities,
+        read_position: discovery.read_position,
+        piece_deadlines: discovery.piece_deadlines,
+        stats: discovery.stats,
+        shutdown: discovery.shutdown,
+        trackers: Some(trackers.clone()),
+        tasks,
+    };
+    let result = download_from(
+        torrent,
+        peers,
+        peer_id,
+        output,
+        options,
+        &progress_tx,
+        &events,
+    )
+    .await;
+    // let the trackers know we're done before returning
+    drop(progress_tx);
+    for announcer in announcers {
+        announcer.await?;
+    }
+    // the last of it is how the trackers took the stop
+    if let Some(mut resume) = ResumeData::load_recorded(torrent, output).await {
+        resume.tracker = trackers.state();
+        if let Err(e) = resume.save(output).await {
+            warn!("can't save the resume data: {:#}", e);
+        }
+    }
+    result
+}
+
+/// Where the peers of a download come from.
+struct PeerSources {
+    /// Addresses to connect to, e.g. from tracker responses.
+    announced: mpsc::Receiver<Vec<SocketAddr>>,
+    /// Peers that connected to us.
+    inbound: mpsc::Receiver<InboundPeer>,
+    /// The socket to connect to peers over uTP from, if uTP is enabled.
+    utp: Option<Arc<UtpSocket>>,
+    /// The DHT the nodes peers run are added to, if it is enabled and the torrent is public.
+    dht: Option<Arc<Dht>>,
+    download_limit: Arc<RateLimiter>,
+    slots: Arc<ConnectionSlots>,
+    bans: Arc<BanList>,
+    file_priorities: watch::Receiver<Vec<Priority>>,
+    read_position: watch::Receiver<usize>,
+    piece_deadlines: watch::Receiver<HashMap<usize, Instant>>,
+    stats: Arc<SwarmStats>,
+    shutdown: watch::Receiver<bool>,
+    /// The trackers announced to, whose state goes into the resume data.
+    trackers: Option<TrackerList>,
+    /// The tasks the download owns, e.g. the DHT lookup and the disk writer it adds, so that
+    /// they end with it and their failures fail it.
+    tasks: Supervisor,
+}
+
+/// State shared between the peer workers of one download.
+struct Swarm {
+    picker: Mutex<PiecePicker>,
+    /// Signalled whenever a piece is completed, so that endgame duplicates can be cancelled.
+    completed: watch::Sender<()>,
+    events: EventSender,
+    /// Peers we connected to ourselves, so they accept connections: those we tell other peers
+    /// about over PEX.
+    reachable: Mutex<HashSet<SocketAddr>>,
+    /// Where the workers send the peers they learn about over PEX.
+    exchanged: mpsc::Sender<Vec<SocketAddr>>,
+    holepunching: Mutex<Holepunching>,
+    /// Where the workers hand on the holepunch messages their peers send.
+    holepunched: mpsc::Sender<(SocketAddr, HolepunchMessage)>,
+    utp: Option<Arc<UtpSocket>>,
+    dht: Option<Arc<Dht>>,
+    /// Shared by every peer connection and web seed of the download.
+    download_limit: Arc<RateLimiter>,
+    slots: Arc<ConnectionSlots>,
+    /// Counts the pieces each peer sent that failed their hash check.
+    bans: Arc<BanList>,
+    /// The candidates waiting to be connected to and the peers that are.
+    connector: Mutex<Connector>,
+    /// Where the workers hash and write the pieces they download.
+    disk: Disk,
+    /// Set once a write found the disk full, which stops the download.
+    disk_full: watch::Sender<bool>,
+    /// What each peer and the swarm as a whole transferred.
+    stats: Arc<SwarmStats>,
+    /// The blocks that arrived so far of the pieces being downloaded from peers.
+    buffers: Mutex<HashMap<usize, Arc<PieceBuffer>>>,
+    /// The pieces written so far, which the peers are told of and served.
+    have: watch::Sender<Bitfield>,
+    /// What the peers are served from.
+    seeding: Arc<Seeding>,
+    /// Decides which of the peers are served.
+    choker: Arc<Choker>,
+}
+
+/// A connected peer as far as hole punching goes.
+struct HolepunchPeer {
+    /// Whether it speaks ut_holepunch, once its extension handshake said so.
+    supported: Arc<AtomicBool>,
+    /// Where the messages to send it go.
+    to_send: mpsc::Sender<HolepunchMessage>,
+}
+
+/// Which peers of a swarm can relay hole punches (BEP 55) for us and which we can relay them
+/// for.
+#[derive(Default)]
+struct Holepunching {
+    /// The connected peers hole punching was offered to, by address.
+    peers: HashMap<SocketAddr, HolepunchPeer>,
+    /// For each peer learned about over PEX, the connected peer that told us about it.
+    introduced: HashMap<SocketAddr, SocketAddr>,
+}
+
+impl Holepunching {
+    fn connected(&mut self, addr: SocketAddr, peer: HolepunchPeer) {
+        self.peers.insert(addr, peer);
+    }
+
+    fn disconnected(&mut self, addr: SocketAddr) {
+        self.peers.remove(&addr);
+        self.introduced.retain(|_, introducer| *introducer != addr);
+    }
+
+    /// Asks the peer that told us about `addr` over PEX to have it connect to us at the same
+    /// time as we connect to it, after connecting to it failed, e.g. as it is behind a NAT.
+    /// That's only tried once, and only if that peer is still connected and can relay.
+    fn request(&mut self, addr: SocketAddr) {
+        let Some(relay) = self.introduced.remove(&addr) else {
+            return;
+        };
+        if let Some(relay) = self.peers.get(&relay) {
+            if relay.supported.load(Ordering::Relaxed) {
+                let _ = relay.to_send.try_send(HolepunchMessage::Rendezvous(addr));
+            }
+        }
+    }
+
+    /// Relays the rendezvous the peer at `from` asked for with the peer at `target`: both are
+    /// told to connect to the other, or `from` why they can't be.
+    fn relay(&self, from: SocketAddr, target: SocketAddr) {
+        let reply = match self.peers.get(&target) {
+            _ if target == from => HolepunchMessage::Error(target, HolepunchMessage::NO_SELF),
+            None => HolepunchMessage::Error(target, HolepunchMessage::NOT_CONNECTED),
+            Some(peer) if !peer.supported.load(Ordering::Relaxed) => {
+                HolepunchMessage::Error(target, HolepunchMessage::NO_SUPPORT)
+            }
+            Some(peer) => {
+                let _ = peer.to_send.try_send(HolepunchMessage::Connect(from));
+                HolepunchMessage::Connect(target)
+            }
+        };
+        if let Some(peer) = self.peers.get(&from) {
+            let _ = peer.to_send.try_send(reply);
+        }
+    }
+}
+
+impl Swarm {
+    /// Acts on a holepunch message the peer at `from` sent: relays a rendezvous to the peer
+    /// it wants to connect to, or connects to the peer a relay tells us to.
+    fn holepunch_received(&self, from: SocketAddr, message: HolepunchMessage) {
+        match message {
+            HolepunchMessage::Rendezvous(target) => {
+                self.holepunching.lock().unwrap().relay(from, target);
+            }
+            // the peer connects to us at the same time, opening its NAT to us
+            HolepunchMessage::Connect(target) => {
+                self.connector.lock().unwrap().retry(target);
+            }
+            HolepunchMessage::Error(target, code) => {
+                debug!(%from, %target, code, "hole punching failed");
+            }
+        }
+    }
+
+    fn buffer(&self, torrent: &Torrent, piece_index: usize) -> Arc<PieceBuffer> {
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers
+            .entry(piece_index)
+            .or_insert_with(|| Arc::new(PieceBuffer::new(&piece_blocks(torrent, piece_index))))
+            .clone()
+    }
+}
+
+/// Downloads from every peer that arrives from `peers` until all pieces are written, keeping
+/// the number of bytes still missing in `progress` and reporting what happens to `events`.
+///
+/// Peers that connected peers tell us about over PEX are connected to as well, and the web
+/// seeds of the torrent are downloaded from alongside the peers. Every peer is told of the
+/// pieces we have, and served them while the choker gives it a slot. A peer whose connection
+/// ended is connected to again if it is announced once more. The download fails once no more peers
+/// are announced and no connected peer or working web seed remains.
+///
+/// The pieces written so far are recorded in resume data next to `output`, so a download that
+/// is interrupted picks up where it left off when started again. Once `peers.shutdown` is set
+/// the download stops: the peers are disconnected, the pieces they wrote are recorded and it
+/// returns without finishing.
+async fn download_from(
+    torrent: &Torrent,
+    mut peers: PeerSources,
+    peer_id: [u8; 20],
+    output: &Path,
+    options: &DownloadOptions,
+    progress: &watch::Sender<Progress>,
+    events: &EventSender,
+) -> anyhow::Result<()> {
+    let piece_count = torrent.piece_count();
+    // check the resume data first, as creating the files would hide missing ones
+    let resumed = match options.storage {
+        Some(_) => ResumeData::load_recorded(torrent, output).await,
+        None => ResumeData::load_incomplete(torrent, output, &options.incomplete).await,
+    };
+    // a complete file no resume data records was put there by someone else, or downloaded
+    // without keeping its resume data; files of no length lose nothing
+    let recorded = resumed.is_some() || ResumeData::load_recorded(torrent, output).await.is_some();
+    if !recorded && options.storage.is_none() && !options.overwrite {
+        for span in torrent.files()?.iter().filter(|span| span.length > 0) {
+            let path = storage::file_path(output, span);
+            if let Ok(metadata) = tokio::fs::metadata(&path).await {
+                if metadata.len() >= span.length as u64 {
+                    return Err(crate::Error::Disk(anyhow::anyhow!(
+                        "{} exists already and is not overwritten",
+                        path.display()
+                    ))
+                    .into());
+                }
+            }
+        }
+    }
+    // a torrent added again without its renames finds its files where they were renamed to
+    let torrent = Arc::new(match &resumed {
+        Some(resume) => resume.renaming(torrent),
+        None => torrent.clone(),
+    });
+    let info_hash = torrent.info_hash();
+    let mut existed = false;
+    for span in torrent.files()? {
+        for path in [
+            options.incomplete.file_path(&info_hash, output, &span),
+            storage::file_path(output, &span),
+        ] {
+            existed |= tokio::fs::try_exists(path).await.unwrap_or(false);
+        }
+    }
+    let mut priorities =
+        picker::piece_priorities(&torrent, &peers.file_priorities.borrow_and_update())?;
+    // files picked up again already took some of the space, which can't be told apart from
+    // what they still need, so only a fresh download is checked
+    if options.storage.is_none() && !existed {
+        let needed: usize = (0..piece_count)
+            .filter(|&index| priorities[index] != Priority::Skip)
+            .map(|index| torrent.piece_size(index))
+            .sum();
+        if let Some(span) = torrent.files()?.first() {
+            let path = options.incomplete.file_path(&info_hash, output, span);
+            storage::check_free_space(&path, needed as u64)?;
+        }
+    }
+    let storage: Box<dyn Storage> = match &options.storage {
+        Some(storage) => storage.open(&torrent).await?,
+        None => Box::new(
+            FileStorage::create_in(&torrent, output, options.allocation, &options.incomplete)
+                .await?
+                .with_backend(options.storage_backend)
+                .await?,
+        ),
+    };
+    let (disk, writer) = Disk::new(torrent.clone(), storage);
+    let disk = disk.with_cache(options.cache_size.unwrap_or(DEFAULT_CACHE_SIZE));
+    peers.tasks.spawn("disk writer", writer.map(Ok));
+    let (exchanged, mut exchanged_rx) = mpsc::channel(16);
+    let (holepunched, mut holepunched_rx) = mpsc::channel(HOLEPUNCH_QUEUE);
+    let swarm = Arc::new(Swarm {
+        picker: Mutex::new(PiecePicker::new(piece_count)),
+        completed: watch::Sender::new(()),
+        events: events.clone(),
+        reachable: Mutex::new(HashSet::new()),
+        exchanged,
+        holepunching: Mutex::new(Holepunching::default()),
+        holepunched,
+        utp: peers.utp.clone(),
+        dht: peers.dht.clone(),
+        download_limit: peers.download_limit.clone(),
+        slots: peers.slots.clone(),
+        bans: peers.bans.clone(),
+        connector: Mutex::new(Connector::new(
+            options.max_torrent_connections,
+            peers.bans.clone(),
+        )),
+        disk: disk.clone(),
+        disk_full: watch::Sender::new(false),
+        stats: peers.stats.clone(),
+        buffers: Mutex::new(HashMap::new()),
+        have: watch::Sender::new(Bitfield::new(piece_count)),
+        seeding: Arc::new(Seeding {
+            disk: disk.clone(),
+            progress: progress.clone(),
+            upload_limit: RateLimiter::new(None),
+            super_seeder: None,
+            max_request_length: DEFAULT_MAX_REQUEST_LENGTH,
+            verified: None,
+        }),
+        choker: Arc::new(Choker::new(UPLOAD_SLOTS)),
+    });
+    peers
+        .tasks
+        .spawn("rechoking", swarm.choker.clone().run().map(Ok));
+    let mut disk_full = swarm.disk_full.subscribe();
+    let (tx, mut rx) = mpsc::channel(16);
+    let mut workers = JoinSet::new();
+    // to disconnect the workers of idle peers
+    let mut running = HashMap::new();
+    let mut peers_open = true;
+    {
+        let mut picker = swarm.picker.lock().unwrap();
+        picker.set_priorities(&priorities);
+        picker.set_sequential(options.sequential);
+        if options.first_last_pieces {
+            picker.set_edges(&picker::file_edges(&torrent)?);
+        }
+        picker.set_position(*peers.read_position.borrow_and_update());
+        picker.set_deadlines(&peers.piece_deadlines.borrow_and_update());
+    }
+    let mut priorities_open = true;
+    let mut position_open = true;
+    let mut deadlines_open = true;
+    let mut shutdown_open = true;
+    let mut shut_down = false;
+    let mut out_of_space = false;
+    // skipped pieces weren't written, at most a part of them that belongs to a wanted file
+    let wanted: Vec<usize> = (0..piece_count)
+        .filter(|&index| priorities[index] != Priority::Skip)
+        .collect();
+
+    let fresh = resumed.is_none();
+    let mut resume = match resumed {
+        Some(mut resume) => {
+            // a piece may have been written without the resume data being saved after it
+            let unrecorded: Vec<usize> = wanted
+                .iter()
+                .copied()
+                .filter(|&index| !resume.has_piece(index))
+                .collect();
+            let valid = disk.verify_pieces(&unrecorded).await?;
+            for (&index, valid) in unrecorded.iter().zip(valid) {
+                if valid {
+                    resume.set_piece(index);
+                }
+            }
+            resume
+        }
+        // files that grow as they are written don't have the sizes resume data records until
+        // they are complete, so the pieces they hold are found by checking every one
+        None if existed && options.storage.is_none() && options.allocation == Allocation::None => {
+            let mut resume = ResumeData::new(&torrent)?;
+            let valid = disk.verify_pieces(&wanted).await?;
+            for (&index, valid) in wanted.iter().zip(valid) {
+                if valid {
+                    resume.set_piece(index);
+                }
+            }
+            resume
+        }
+        None => ResumeData::new(&torrent)?,
+    };
+    // the trackers' state is saved along with the pieces, so it isn't lost if we're killed
+    let trackers = peers.trackers.take();
+    let track = |resume: &mut ResumeData| {
+        if let Some(trackers) = &trackers {
+            resume.tracker = trackers.state();
+        }
+    };
+    // the files are the download's from now on, even if it stops before a piece is in
+    if fresh {
+        track(&mut resume);
+        resume.save(output).await?;
+    }
+    let mut remaining = missing_pieces(&priorities, &resume);
+    info!(pieces = piece_count, remaining, "starting download");
+    {
+        let mut picker = swarm.picker.lock().unwrap();
+        for index in (0..piece_count).filter(|&index| resume.has_piece(index)) {
+            picker.complete(index);
+            swarm.have.send_modify(|have| have.set(index));
+            let size = torrent.piece_size(index);
+            progress.send_modify(|progress| progress.left = progress.left.saturating_sub(size));
+            events.send(EventKind::PieceCompleted(index));
+        }
+    }
+    let uploaded_before = resume.uploaded;
+    let mut downloaded = 0;
+    let start = tokio::time::Instant::now() + THROUGHPUT_INTERVAL;
+    let mut sampling = tokio::time::interval_at(start, THROUGHPUT_INTERVAL);
+    let mut last_sample = (0, progress.borrow().uploaded);
+    let mut web_seeds = JoinSet::new();
+    for seed in WebSeed::from_torrent(&torrent) {
+        let span = info_span!("web_seed", url = seed.url());
+        let worker = web_seed_worker(
+            seed,
+            torrent.clone(),
+            swarm.clone(),
+            tx.clone(),
+            options.peer_proxy.clone(),
+            options.bind.clone(),
+        );
+        web_seeds.spawn(
+            async move {
+                // like a failing peer, a failing web seed only costs us its share of the work
+                if let Err(e) = worker.await {
+                    debug!("web seed failed: {:#}", e);
+                }
+            }
+            .instrument(span),
+        );
+    }
+    let mut seen_any_peer = !web_seeds.is_empty();
+
+    while remaining > 0 {
+        let (queued, next_retry) = {
+            let connector = swarm.connector.lock().unwrap();
+            (connector.queued(), connector.next_retry())
+        };
+        if !peers_open
+            && queued == 0
+            && workers.is_empty()
+            && web_seeds.is_empty()
+            && rx.is_empty()
+            && exchanged_rx.is_empty()
+        {
+            if !seen_any_peer {
+                anyhow::bail!("tracker returned no peers");
+            }
+            anyhow::bail!(
+                "ran out of peers with {} of {} pieces missing",
+                remaining,
+                piece_count
+            );
+        }
+        tokio::select! {
+            batch = peers.announced.recv(), if peers_open => match batch {
+                Some(addrs) => {
+                    seen_any_peer |= !addrs.is_empty();
+                    swarm.connector.lock().unwrap().enqueue(addrs);
+                }
+                None => peers_open = false,
+            },
+            Some(addrs) = exchanged_rx.recv() => swarm.connector.lock().unwrap().enqueue(addrs),
+            // connecting to the peers whose handshake retry is due follows the select
+            _ = tokio::time::sleep_until(next_retry.unwrap_or_else(Instant::now).into()),
+                if next_retry.is_some() => {}
+            Some((from, message)) = holepunched_rx.recv() => swarm.holepunch_received(from, message),
+            Some((stream, handshake)) = peers.inbound.recv() => {
+                seen_any_peer = true;
+                let Ok(addr) = stream.peer_addr() else {
+                    continue;
+                };
+                // without a free slot the peer is turned away
+                let admitted = swarm.connector.lock().unwrap().admit(addr, &swarm.slots);
+                if let Some(slot) = admitted {
+                    let (stop, stopped) = watch::channel(false);
+                    let worker = peer_worker(
+                        Connection::Inbound(stream, handshake),
+                        torrent.clone(),
+                        peer_id,
+                        options.clone(),
+                        swarm.clone(),
+                        tx.clone(),
+                        stopped,
+                    );
+                    let handle = workers.spawn(
+                        async move {
+                            if let Err(e) = worker.await {
+                                debug!("peer failed: {:#}", e);
+                            }
+                            drop(slot);
+                            addr
+                        }
+                        .instrument(info_span!("peer", %addr, inbound = true)),
+                    );
+                    running.insert(addr, (handle.id(), stop));
+                }
+            }
+            Some(finished) = workers.join_next_with_id(), if !workers.is_empty() => {
+                let (id, addr) = finished?;
+                // a peer that was stopped may have been connected to again since
+                if running.get(&addr).is_some_and(|(running, _)| *running == id) {
+                    running.remove(&addr);
+                    swarm.connector.lock().unwrap().disconnected(addr);
+                }
+            }
+            Some(finished) = web_seeds.join_next(), if !web_seeds.is_empty() => finished?,
+            changed = peers.file_priorities.changed(), if priorities_open => match changed {
+                Ok(()) => {
+                    let file_priorities = peers.file_priorities.borrow_and_update().clone();
+                    priorities = picker::piece_priorities(&torrent, &file_priorities)?;
+                    swarm.picker.lock().unwrap().set_priorities(&priorities);
+                    remaining = missing_pieces(&priorities, &resume);
+                }
+                Err(_) => priorities_open = false,
+            },
+            changed = peers.read_position.changed(), if position_open => match changed {
+                Ok(()) => {
+                    let position = *peers.read_position.borrow_and_update();
+                    swarm.picker.lock().unwrap().set_position(position);
+                }
+                Err(_) => position_open = false,
+            },
+            changed = peers.piece_deadlines.changed(), if deadlines_open => match changed {
+                Ok(()) => {
+                    let deadlines = peers.piece_deadlines.borrow_and_update();
+                    swarm.picker.lock().unwrap().set_deadlines(&deadlines);
+                }
+                Err(_) => deadlines_open = false,
+            },
+            changed = peers.shutdown.changed(), if shutdown_open => match changed {
+                Ok(()) if *peers.shutdown.borrow_and_update() => {
+                    shut_down = true;
+                    break;
+                }
+                Ok(()) => {}
+                Err(_) => shutdown_open = false,
+            },
+            error = peers.tasks.failure() => return Err(error),
+            // it is only ever set, once
+            Ok(()) = disk_full.changed() => {
+                out_of_space = true;
+                break;
+            }
+            Some((piece_index, length)) = rx.recv() => {
+                // a piece that was in flight when it got skipped isn't counted any more
+                if priorities[piece_index] != Priority::Skip {
+                    remaining -= 1;
+                }
+                progress.send_modify(|progress| progress.left = progress.left.saturating_sub(length));
+                // the piece has to be on disk before the resume data claims it is
+                disk.flush().await?;
+                resume.set_piece(piece_index);
+                resume.downloaded += length;
+                resume.uploaded = uploaded_before + progress.borrow().uploaded;
+                track(&mut resume);
+                resume.save(output).await?;
+                downloaded += length;
+                debug!(piece = piece_index, remaining, "piece completed");
+                events.send(EventKind::PieceCompleted(piece_index));
+            }
+            _ = sampling.tick() => {
+                let uploaded = progress.borrow().uploaded;
+                let seconds = THROUGHPUT_INTERVAL.as_secs_f64();
+                events.send(EventKind::Throughput {
+                    download_rate: ((downloaded - last_sample.0) as f64 / seconds) as usize,
+                    upload_rate: (uploaded.saturating_sub(last_sample.1) as f64 / seconds) as usize,
+                });
+                last_sample = (downloaded, uploaded);
+                swarm.stats.sample(THROUGHPUT_INTERVAL);
+                swarm.stats.set_availability(swarm.picker.lock().unwrap().availability());
+                swarm.stats.set_disk_queue(disk.queued());
+                swarm.stats.set_cache(swarm.seeding.cache_stats());
+                // the trackers hear of the waste with the next announce
+                let waste = swarm.stats.wasted();
+                progress.send_if_modified(|progress| {
+                    let changed = (progress.corrupt, progress.redundant) != waste;
+                    (progress.corrupt, progress.redundant) = waste;
+                    changed
+                });
+            }
+        }
+        let mut connector = swarm.connector.lock().unwrap();
+        if let Some(idle) = connector.idle_peer(&swarm.slots, Instant::now()) {
+            if let Some((_, stop)) = running.remove(&idle) {
+                debug!(addr = %idle, "disconnecting idle peer");
+                stop.send_replace(true);
+            }
+            connector.disconnected(idle);
+        }
+        while let Some((addr, slot)) = connector.next(&swarm.slots) {
+            let (stop, stopped) = watch::channel(false);
+            let worker = peer_worker(
+                Connection::Outbound(addr),
+                torrent.clone(),
+                peer_id,
+                options.clone(),
+                swarm.clone(),
+                tx.clone(),
+                stopped,
+            );
+            let handle = workers.spawn(
+                async move {
+                    // a failing peer only costs us its share of the work
+                    if let Err(e) = worker.await {
+                        debug!("peer failed: {:#}", e);
+                    }
+                    drop(slot);
+                    addr
+                }
+                .instrument(info_span!("peer", %addr, inbound = false)),
+            );
+            running.insert(addr, (handle.id(), stop));
+        }
+    }
+    if shut_down || out_of_space {
+        // dropping the workers closes their connections; a piece one wrote without getting to
+        // report it is found by checking the unrecorded pieces on the next start
+        workers.shutdown().await;
+        web_seeds.shutdown().await;
+        while let Ok((piece_index, length)) = rx.try_recv() {
+            resume.set_piece(piece_index);
+            resume.downloaded += length;
+        }
+    }
+    if out_of_space {
+        // what made it to disk is kept for the next start, if there's room left to record it
+        let saved = async {
+            disk.flush().await?;
+            resume.uploaded = uploaded_before + progress.borrow().uploaded;
+            track(&mut resume);
+            resume.save(output).await
+        };
+        if let Err(e) = saved.await {
+            warn!("can't save the resume data: {:#}", e);
+        }
+        let full = std::io::Error::from(std::io::ErrorKind::StorageFull);
+        let full = anyhow::Error::new(full)
+            .context(format!("no disk space left to write {}", output.display()));
+        return Err(crate::Error::Disk(full).into());
+    }
+    disk.flush().await?;
+    resume.uploaded = uploaded_before + progress.borrow().uploaded;
+    track(&mut resume);
+    resume.save(output).await?;
+    if shut_down {
+        info!("download stopped");
+        return Ok(());
+    }
+    // skipped files may still be missing pieces, and aren't complete yet
+    if (0..piece_count).all(|index| resume.has_piece(index)) {
+        disk.complete().await?;
+    }
+    progress.send_modify(|progress| progress.left = 0);
+    events.send(EventKind::TorrentFinished);
+    info!("download finished");
+    if let Some(command) = options.on_complete.as_deref().filter(|_| downloaded > 0) {
+        hook::spawn(command, HookEvent::Completed, &torrent, output);
+    }
+    Ok(())
+}
+
+/// How many of the pieces `priorities` doesn't skip `resume` has yet to record.
+fn missing_pieces(priorities: &[Priority], resume: &ResumeData) -> usize {
+    (0..priorities.len())
+        .filter(|&index| priorities[index] != Priority::Skip && !resume.has_piece(index))
+        .count()
+}
+
+/// How a peer worker gets hold of its peer.
+enum Connection {
+    /// A peer we connect to ourselves.
+    Outbound(SocketAddr),
+    /// A peer that connected to us, with its handshake.
+    Inbound(PeerStream, Handshake),
+}
+
+/// Downloads pieces from one peer until nothing is left that it can help with, or until
+/// `stop` turns true to make room for another peer.
+async fn peer_worker(
+    connection: Connection,
+    torrent: Arc<Torrent>,
+    peer_id: [u8; 20],
+    options: DownloadOptions,
+    swarm: Arc<Swarm>,
+    done: mpsc::Sender<(usize, usize)>,
+    stop: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let info_hash = torrent.info_hash();
+    let (stream, handshake, addr, outbound) = match connection {
+        Connection::Outbound(addr) => {
+            let _half_open = swarm.slots.half_open().await;
+            let (stream, handshake) = peer::connect_with(
+                addr,
+                info_hash,
+                peer_id,
+                options.encryption,
+                swarm.utp.as_deref(),
+                options.peer_proxy.as_ref(),
+                options.bind.as_ref(),
+            )
+            .await
+            .inspect_err(|e| {
+                swarm.connector.lock().unwrap().handshake_failed(addr, e);
+                swarm.holepunching.lock().unwrap().request(addr);
+            })?;
+            (stream, handshake, addr, true)
+        }
+        Connection::Inbound(stream, handshake) => {
+            let addr = stream.peer_addr()?;
+            (stream, handshake, addr, false)
+        }
+    };
+    swarm.connector.lock().unwrap().connected(addr);
+    swarm.events.send(EventKind::PeerConnected(addr));
+    let _listed = swarm.stats.peer_connected(addr, &handshake.peer_id);
+    if outbound {
+        swarm.reachable.lock().unwrap().insert(addr);
+    }
+    let result = async {
+        let uploads = Uploads::new(
+            torrent.clone(),
+            swarm.seeding.clone(),
+            swarm.choker.register(),
+            swarm.have.subscribe(),
+        );
+        let private = torrent.is_private();
+        let mut session =
+            PeerSession::start_uploading_for(stream, &handshake, private, uploads).await?;
+        session.set_piece_count(torrent.piece_count())?;
+        session.set_pipeline_depth(options.pipeline_depth);
+        session.set_request_queue_time(options.request_queue_time);
+        session.set_request_timeout(options.request_timeout);
+        session.set_download_limit(swarm.download_limit.clone());
+        session.set_stats(swarm.stats.clone(), addr);
+        if let Some(dht) = &swarm.dht {
+            session.set_dht(dht.clone(), addr, &handshake).await?;
+        }
+        if !torrent.is_private() {
+            let (to_send, to_send_rx) = mpsc::channel(HOLEPUNCH_QUEUE);
+            let supported = Arc::new(AtomicBool::new(false));
+            let received = swarm.holepunched.clone();
+            session
+                .offer_holepunch(addr, supported.clone(), received, to_send_rx)
+                .await?;
+            let peer = HolepunchPeer { supported, to_send };
+            swarm.holepunching.lock().unwrap().connected(addr, peer);
+        }
+        // the part of the peer's bitfield the picker's availability counts include
+        let mut counted = Bitfield::default();
+        let result = download_pieces(
+            &mut session,
+            addr,
+            &torrent,
+            &swarm,
+            &done,
+            &mut counted,
+            stop,
+        )
+        .await;
+        swarm.picker.lock().unwrap().remove_peer(&counted);
+        result
+    }
+    .await;
+    swarm.reachable.lock().unwrap().remove(&addr);
+    swarm.holepunching.lock().unwrap().disconnected(addr);
+    swarm.events.send(EventKind::PeerDisconnected(addr));
+    result
+}
+
+async fn download_pieces<S: AsyncRead + AsyncWrite + Unpin>(
+    session: &mut PeerSession<S>,
+    addr: SocketAddr,
+    torrent: &Torrent,
+    swarm: &Swarm,
+    done: &mpsc::Sender<(usize, usize)>,
+    counted: &mut Bitfield,
+    mut stop: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut snubs = 0;
+    loop {
+        if *stop.borrow() {
+            return Ok(());
+        }
+        // a choked peer gets no piece to hold on to until it unchokes us, or allows us some
+        if session.choked {
+            tokio::select! {
+                unchoked = session.wait_for_requestable() => unchoked?,
+                _ = stop.wait_for(|&stop| stop) => return Ok(()),
+            }
+        }
+        // between pieces is when we get to exchange peers
+        let mut others = swarm.reachable.lock().unwrap().clone();
+        others.remove(&addr);
+        session.send_pex(&others).await?;
+        let exchanged = session.take_pex_peers();
+        if !exchanged.is_empty() {
+            let introduced = exchanged.iter().map(|&peer| (peer, addr));
+            swarm
+                .holepunching
+                .lock()
+                .unwrap()
+                .introduced
+                .extend(introduced);
+            let _ = swarm.exchanged.send(exchanged).await;
+        }
+
+        let next = {
+            let mut picker = swarm.picker.lock().unwrap();
+            let bitfield = session.pieces(torrent.piece_count());
+            if *counted != bitfield {
+                picker.remove_peer(counted);
+                picker.add_peer(&bitfield);
+                *counted = bitfield;
+            }
+            match picker.pick(|index| session.may_request(index)) {
+                Some(index) => Some(index),
+                // pieces in flight elsewhere may still come back if their peer fails, and a
+                // peer that wants some of ours is kept to be served
+                None if picker.in_flight() == 0 && !session.choked && !session.peer_interested => {
+                    return Ok(())
+                }
+                None => None,
+            }
+        };
+        let Some(piece_index) = next else {
+            if done.is_closed() {
+                return Ok(());
+            }
+            // the pieces allowed while choked are done, the rest waits for an unchoke
+            if session.choked {
+                tokio::select! {
+                    unchoked = session.wait_for_unchoke() => unchoked?,
+                    _ = stop.wait_for(|&stop| stop) => return Ok(()),
+                }
+                continue;
+            }
+            // meanwhile the peer may announce pieces, and is kept alive
+            if let Ok(received) =
+                tokio::time::timeout(Duration::from_millis(200), session.receive()).await
+            {
+                received?;
+            }
+            continue;
+        };
+
+        // in endgame another peer may be downloading the same piece; stop once it has
+        let cancel = async {
+            tokio::select! {
+                () = completed_elsewhere(swarm, piece_index) => {}
+                _ = stop.wait_for(|&stop| stop) => {}
+            }
+        };
+        let buffer = swarm.buffer(torrent, piece_index);
+        let result = session
+            .fetch_piece_unless(torrent, piece_index, &buffer, cancel)
+            .await;
+        if let Ok(Some(_)) = result {
+            // a piece that fails its hash check starts over from nothing
+            swarm.buffers.lock().unwrap().remove(&piece_index);
+        }
+        let piece = {
+            let mut picker = swarm.picker.lock().unwrap();
+            match result {
+                Ok(Some(piece)) if !picker.is_done(piece_index) => piece,
+                // the piece arrived from another peer first, we were stopped, or the peer held
+                // it up; either way other peers can have it now
+                Ok(_) => {
+                    if !picker.is_done(piece_index) {
+                        picker.abort(piece_index);
+                    }
+                    drop(picker);
+                    if session.is_snubbed() {
+                        snubs += 1;
+                        if snubs == MAX_SNUBS {
+                            anyhow::bail!("peer stopped sending blocks");
+                        }
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    picker.abort(piece_index);
+                    return Err(e);
+                }
+            }
+        };
+        snubs = 0;
+        store_piece(swarm, piece_index, piece, Some(addr), done).await?;
+        swarm.connector.lock().unwrap().active(addr, Instant::now());
+    }
+}
+
+/// Hands a downloaded piece to the disk to be checked and written, then marks it complete.
+/// A piece that fails its hash check goes back to the picker and fails the download from its
+/// source, and counts against the peer at `from`, if it came from a peer, on the ban list.
+async fn store_piece(
+    swarm: &Swarm,
+    piece_index: usize,
+    piece: Vec<u8>,
+    from: Option<SocketAddr>,
+    done: &mpsc::Sender<(usize, usize)>,
+) -> anyhow::Result<()> {
+    let length = piece.len();
+    let result = swarm
+        .disk
+        .write_piece(piece_index, piece)
+        .await
+        .map_err(anyhow::Error::from);
+    {
+        let mut picker = swarm.picker.lock().unwrap();
+        // in endgame another worker may have delivered the same piece meanwhile
+        if picker.is_done(piece_index) {
+            swarm.stats.redundant(from, length);
+            return result.map(|_| ());
+        }
+        match result {
+            Ok(true) => {
+                picker.complete(piece_index);
+                swarm.have.send_modify(|have| have.set(piece_index));
+            }
+            Ok(false) => {
+                picker.abort(piece_index);
+                swarm.stats.hash_failed(from, length);
+                if let Some(addr) = from {
+                    if swarm.bans.hash_failed(addr.ip()) {
+                        warn!(ip = %addr.ip(), "banning peer for sending bad pieces");
+                    }
+                }
+                anyhow::bail!("piece {} failed hash verification", piece_index);
+            }
+            Err(e) => {
+                picker.abort(piece_index);
+                // the peers would keep using up the download on pieces that can't be written
+                if storage::is_disk_full(&e) {
+                    swarm.disk_full.send_replace(true);
+                }
+                return Err(e);
+            }
+        }
+    }
+    swarm.completed.send_replace(());
+    done.send((piece_index, length)).await?;
+    Ok(())
+}
+
+/// Downloads pieces from a web seed until nothing is left that it can help with. It has every
+/// piece, so the picker hands it the rarest ones first: those no connected peer has.
+async fn web_seed_worker(
+    seed: WebSeed,
+    torrent: Arc<Torrent>,
+    swarm: Arc<Swarm>,
+    done: mpsc::Sender<(usize, usize)>,
+    proxy: Option<Proxy>,
+    bind: Option<Bind>,
+) -> anyhow::Result<()> {
+    let mut client = net::http_client(bind.as_ref())?.timeout(webseed::REQUEST_TIMEOUT);
+    if let Some(proxy) = &proxy {
+        client = client.proxy(proxy.to_reqwest()?);
+    }
+    let client = client.build()?;
+    let mut failures = 0;
+    loop {
+        let next = {
+            let mut picker = swarm.picker.lock().unwrap();
+            match picker.pick(|_| true) {
+                Some(index) => Some(index),
+                None if picker.in_flight() == 0 => return Ok(()),
+                None => None,
+            }
+        };
+        let Some(piece_index) = next else {
+            if done.is_closed() {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            continue;
+        };
+
+        let result = tokio::select! {
+            result = seed.fetch_unverified(&client, &torrent, piece_index) => Some(result),
+            () = completed_elsewhere(&swarm, piece_index) => None,
+        };
+        let stored = match result {
+            Some(Ok(piece)) => {
+                let length = piece.len();
+                swarm.stats.downloaded(None, length, None);
+                store_piece(&swarm, piece_index, piece, None, &done)
+                    .await
+                    .map(|()| length)
+            }
+            // a peer delivered the piece first
+            None => continue,
+            Some(Err(e)) => {
+                swarm.picker.lock().unwrap().abort(piece_index);
+                Err(e)
+            }
+        };
+        // a piece that failed is back in the picker already
+        let length = match stored {
+            Ok(length) => length,
+            Err(e) => {
+                failures += 1;
+                if failures == WEB_SEED_RETRIES {
+                    return Err(e.context(format!("web seed {}", seed.url())));
+                }
+                tokio::time::sleep(WEB_SEED_RETRY_DELAY * failures).await;
+                continue;
+            }
+        };
+        failures = 0;
+        swarm.download_limit.acquire(length).await;
+    }
+}
+
+/// Resolves once the piece at `piece_index` has been completed by any worker.
+async fn completed_elsewhere(swarm: &Swarm, piece_index: usize) {
+    let mut completed = swarm.completed.subscribe();
+    loop {
+        if swarm.picker.lock().unwrap().is_done(piece_index) {
+            return;
+        }
+        if completed.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::choker::{Choker, UPLOAD_SLOTS};
+    use crate::connector::MAX_HASH_FAILURES;
+    use crate::peer::Handshake;
+    use crate::seed::{serve_peer, Seeding, DEFAULT_MAX_REQUEST_LENGTH};
+    use crate::storage::MemoryStorage;
+    use crate::torrent::{File, Hashes, Info, Keys};
+    use crate::webseed::tests::spawn_web_seed;
+    use sha1::{Digest, Sha1};
+    use std::collections::VecDeque;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    pub(crate) fn torrent_for(data: &[u8], piece_length: usize) -> Torrent {
+        Torrent {
+            announce: "http://127.0.0.1/announce".to_owned(),
+            announce_list: Vec::new(),
+            url_list: Vec::new(),
+            httpseeds: Vec::new(),
+            piece_layers: BTreeMap::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            encoding: None,
+            info: Info {
+                name: "test.bin".to_owned(),
+                piece_length,
+                pieces: Hashes(
+                    data.chunks(piece_length)
+                        .map(|chunk| Sha1::digest(chunk).into())
+                        .collect(),
+                ),
+                keys: Keys::SingleFile { length: data.len() },
+                meta_version: None,
+                private: None,
+                file_tree: None,
+            },
+            raw_info: None,
+            renamed: BTreeMap::new(),
+        }
+    }
+
+    /// Like `torrent_for`, but with `data` split into files of the given lengths.
+    pub(crate) fn multi_file_torrent_for(
+        data: &[u8],
+        piece_length: usize,
+        files: &[(&str, usize)],
+    ) -> Torrent {
+        let mut torrent = torrent_for(data, piece_length);
+        torrent.info.name = "test".to_owned();
+        torrent.info.keys = Keys::MultiFile {
+            files: files
+                .iter()
+                .map(|&(path, length)| File {
+                    length,
+                    path: path.split('/').map(str::to_owned).collect(),
+                    attr: String::new(),
+                })
+                .collect(),
+        };
+        torrent
+    }
+
+    /// Spawns a peer that has all of `data` and answers every request for it.
+    pub(crate) async fn spawn_seeder(torrent: &Torrent, data: Vec<u8>) -> SocketAddr {
+        spawn_seeder_on("127.0.0.1:0", torrent, data).await
+    }
+
+    /// Like `spawn_seeder`, listening on `addr`.
+    async fn spawn_seeder_on(addr: &str, torrent: &Torrent, data: Vec<u8>) -> SocketAddr {
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let torrent = Arc::new(torrent.clone());
+        let data = Arc::new(data);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_as_seeder(stream, torrent.clone(), data.clone()));
+            }
+        });
+        addr
+    }
+
+    /// Like `spawn_seeder`, accepting connections over uTP only.
+    async fn spawn_utp_seeder(torrent: &Torrent, data: Vec<u8>) -> SocketAddr {
+        let socket = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let torrent = Arc::new(torrent.clone());
+        let data = Arc::new(data);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = socket.accept().await {
+                tokio::spawn(serve_as_seeder(stream, torrent.clone(), data.clone()));
+            }
+        });
+        addr
+    }
+
+    async fn serve_as_seeder<S: AsyncRead + AsyncWrite + Unpin>(
+        mut stream: S,
+        torrent: Arc<Torrent>,
+        data: Arc<Vec<u8>>,
+    ) {
+        let piece_length = torrent.info.piece_length;
+        let piece_count = torrent.info.pieces.0.len();
+        let mut handshake = [0; Handshake::LENGTH];
+        stream.read_exact(&mut handshake).await.unwrap();
+        stream
+            .write_all(&Handshake::new(torrent.info_hash(), [7; 20]).to_bytes())
+            .await
+            .unwrap();
+        let mut framed = Framed::new(stream, MessageCodec);
+        let bitfield = Bitfield::full(piece_count).as_bytes().to_vec();
+        framed.send(PeerMessage::Bitfield(bitfield)).await.unwrap();
+        while let Some(Ok(message)) = framed.next().await {
+            match message {
+                PeerMessage::Interested => framed.send(PeerMessage::Unchoke).await.unwrap(),
+                PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    let start = index as usize * piece_length + begin as usize;
+                    let block = data[start..start + length as usize].to_vec();
+                    let piece = PeerMessage::Piece {
+                        index,
+                        begin,
+                        block,
+                    };
+                    if framed.send(piece).await.is_err() {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Spawns a peer that claims to have every piece but never answers a request. Every message
+    /// it receives is forwarded to the returned channel.
+    pub(crate) async fn spawn_stalling_peer(
+        torrent: &Torrent,
+    ) -> (SocketAddr, mpsc::UnboundedReceiver<PeerMessage>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let info_hash = torrent.info_hash();
+        let piece_count = torrent.info.pieces.0.len();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut handshake = [0; Handshake::LENGTH];
+                    stream.read_exact(&mut handshake).await.unwrap();
+                    stream
+                        .write_all(&Handshake::new(info_hash, [8; 20]).to_bytes())
+                        .await
+                        .unwrap();
+                    let mut framed = Framed::new(stream, MessageCodec);
+                    let bitfield = Bitfield::full(piece_count).as_bytes().to_vec();
+                    framed.send(PeerMessage::Bitfield(bitfield)).await.unwrap();
+                    while let Some(Ok(message)) = framed.next().await {
+                        if message == PeerMessage::Interested {
+                            framed.send(PeerMessage::Unchoke).await.unwrap();
+                        }
+                        let _ = tx.send(message);
+                    }
+                });
+            }
+        });
+        (addr, rx)
+    }
+
+    /// Serves announces with `bodies` in turn, repeating the last one, and records the request
+    /// line of each announce.
+    pub(crate) async fn spawn_tracker(bodies: Vec<Vec<u8>>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = [0; 4096];
+                let n = stream.read(&mut buffer).await.unwrap();
+                let request = String::from_utf8_lossy(&buffer[..n]);
+                let count = {
+                    let mut log = log.lock().unwrap();
+                    log.push(request.lines().next().unwrap_or_default().to_owned());
+                    log.len()
+                };
+                let body = &bodies[(count - 1).min(bodies.len() - 1)];
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(header.as_bytes()).await.unwrap();
+                stream.write_all(body).await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    pub(crate) fn announce_body(interval: usize, peers: &[SocketAddr]) -> Vec<u8> {
+        let mut compact = Vec::new();
+        for peer in peers {
+            let SocketAddr::V4(peer) = peer else {
+                panic!("compact peers are IPv4 only");
+            };
+            compact.extend_from_slice(&peer.ip().octets());
+            compact.extend_from_slice(&peer.port().to_be_bytes());
+        }
+        let mut body = format!("d8:intervali{}e5:peers{}:", interval, compact.len()).into_bytes();
+        body.extend_from_slice(&compact);
+        body.push(b'e');
+        body
+    }
+
+    pub(crate) fn test_data(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn blocks_of_full_piece() {
+        let blocks = blocks_of_piece(32768, 0, 100_000, BLOCK_SIZE);
+        assert_eq!(
+            blocks,
+            vec![
+                Block {
+                    begin: 0,
+                    length: 16384
+                },
+                Block {
+                    begin: 16384,
+                    length: 16384
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn blocks_of_last_piece() {
+        // 100_000 - 3 * 32768 = 1696 bytes left
+        let blocks = blocks_of_piece(32768, 3, 100_000, BLOCK_SIZE);
+        assert_eq!(
+            blocks,
+            vec![Block {
+                begin: 0,
+                length: 1696
+            }]
+        );
+    }
+
+    #[test]
+    fn blocks_of_piece_smaller_than_block() {
+        let blocks = blocks_of_piece(1024, 2, 10_000, BLOCK_SIZE);
+        assert_eq!(
+            blocks,
+            vec![Block {
+                begin: 0,
+                length: 1024
+            }]
+        );
+    }
+
+    #[test]
+    fn blocks_of_piece_past_end() {
+        assert!(blocks_of_piece(32768, 4, 100_000, BLOCK_SIZE).is_empty());
+    }
+
+    #[tokio::test]
+    async fn download_piece_from_seeder() {
+        let data = test_data(100_000);
+        let torrent = torrent_for(&data, 32768);
+        let addr = spawn_seeder(&torrent, data.clone()).await;
+
+        let (stream, _) = peer::connect(addr, torrent.info_hash(), [1; 20])
+            .await
+            .unwrap();
+        let mut session = PeerSession::start(stream).await.unwrap();
+        assert!(session.has_piece(3));
+        assert_eq!(
+            session.download_piece(&torrent, 1).await.unwrap(),
+            &data[32768..65536]
+        );
+        assert_eq!(
+            session.download_piece(&torrent, 3).await.unwrap(),
+            &data[98304..]
+        );
+    }
+
+    #[tokio::test]
+    async fn download_piece_keeps_pipeline_full() {
+        let data = test_data(8 * BLOCK_SIZE as usize);
+        let torrent = torrent_for(&data, data.len());
+        let (ours, theirs) = tokio::io::duplex(1 << 20);
+        let peer = tokio::spawn(async move {
+            let mut framed = Framed::new(theirs, MessageCodec);
+            let mut outstanding = VecDeque::new();
+            let mut most_outstanding = 0;
+            loop {
+                // take in whatever requests are on their way before answering the oldest one
+                while let Ok(Some(Ok(message))) =
+                    tokio::time::timeout(Duration::from_millis(50), framed.next()).await
+                {
+                    match message {
+                        PeerMessage::Interested => framed.send(PeerMessage::Unchoke).await.unwrap(),
+                        PeerMessage::Request { begin, length, .. } => {
+                            outstanding.push_back((begin, length))
+                        }
+                        _ => {}
+                    }
+                }
+                most_outstanding = most_outstanding.max(outstanding.len());
+                let Some((begin, length)) = outstanding.pop_front() else {
+                    return most_outstanding;
+                };
+                let block = data[begin as usize..][..length as usize].to_vec();
+                let piece = PeerMessage::Piece {
+                    index: 0,
+                    begin,
+                    block,
+                };
+                framed.send(piece).await.unwrap();
+            }
+        });
+
+        let mut session = PeerSession::start(ours).await.unwrap();
+        session.set_pipeline_depth(3);
+        session.download_piece(&torrent, 0).await.unwrap();
+        assert_eq!(peer.await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn peers_dht_nodes_join_the_routing_table() {
+        let node = Dht::bind("127.0.0.1:0").await.unwrap();
+        let node_port = node.local_addr().unwrap().port();
+        let dht = Arc::new(Dht::bind("127.0.0.1:0").await.unwrap());
+        let (ours, theirs) = tokio::io::duplex(1 << 16);
+        let peer = tokio::spawn(async move {
+            let mut framed = Framed::new(theirs, MessageCodec);
+            while let Some(Ok(message)) = framed.next().await {
+                match message {
+                    PeerMessage::Interested => {
+                        // a message of an extension we don't know doesn't end the session
+                        let unknown = PeerMessage::Unknown {
+                            id: 0x42,
+                            payload: vec![1, 2, 3],
+                        };
+                        framed.send(unknown).await.unwrap();
+                        framed.send(PeerMessage::Port(node_port)).await.unwrap();
+                        framed.send(PeerMessage::Unchoke).await.unwrap();
+                    }
+                    PeerMessage::Port(port) => return port,
+                    _ => {}
+                }
+            }
+            panic!("no port sent");
+        });
+
+        let mut session = PeerSession::start(ours).await.unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let handshake = Handshake::new([1; 20], [2; 20]);
+        session
+            .set_dht(dht.clone(), addr, &handshake)
+            .await
+            .unwrap();
+        assert_eq!(peer.await.unwrap(), dht.local_addr().unwrap().port());
+        while dht.node_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop(node);
+    }
+
+    #[tokio::test]
+    async fn fast_peer_serves_allowed_pieces_while_choking() {
+        let data = test_data(3 * BLOCK_SIZE as usize);
+        let torrent = torrent_for(&data, BLOCK_SIZE as usize);
+        let (ours, theirs) = tokio::io::duplex(1 << 20);
+        let second = data[BLOCK_SIZE as usize..][..BLOCK_SIZE as usize].to_vec();
+        let peer = tokio::spawn(async move {
+            let mut framed = Framed::new(theirs, MessageCodec);
+            let first = framed.next().await.unwrap().unwrap();
+            framed.send(PeerMessage::HaveAll).await.unwrap();
+            framed.send(PeerMessage::AllowedFast(1)).await.unwrap();
+            // never unchokes: piece 1 is served, anything else rejected
+            while let Some(Ok(message)) = framed.next().await {
+                let PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                } = message
+                else {
+                    continue;
+                };
+                let reply = if index == 1 {
+                    PeerMessage::Piece {
+                        index,
+                        begin,
+                        block: second[begin as usize..][..length as usize].to_vec(),
+                    }
+                } else {
+                    PeerMessage::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    }
+                };
+                framed.send(reply).await.unwrap();
+            }
+            first
+        });
+
+        let handshake = Handshake::new(torrent.info_hash(), [2; 20]);
+        let mut session = PeerSession::start_for(ours, &handshake).await.unwrap();
+        assert!(session.has_piece(2));
+        assert!(session.may_request(1));
+        assert!(!session.may_request(0));
+        let piece = session.download_piece(&torrent, 1).await.unwrap();
+        assert_eq!(piece, &data[BLOCK_SIZE as usize..][..BLOCK_SIZE as usize]);
+        // a rejected piece is given up on and not asked for again
+        let rejected = session
+            .download_piece_unless(&torrent, 0, std::future::pending())
+            .await
+            .unwrap();
+        assert_eq!(rejected, None);
+        session.choked = false;
+        assert!(!session.may_request(0));
+        assert!(session.may_request(2));
+        drop(session);
+        assert_eq!(peer.await.unwrap(), PeerMessage::HaveNone);
+    }
+
+    #[tokio::test]
+    async fn download_piece_skips_unreachable_peer() {
+        let data = test_data(40_000);
+        let torrent = torrent_for(&data, 32768);
+        let addr = spawn_seeder(&torrent, data.clone()).await;
+        // nothing listens on a freshly closed port
+        let unreachable = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let piece = download_piece_from_peers(&torrent, &[unreachable, addr], 1, [1; 20])
+            .await
+            .unwrap();
+        assert_eq!(piece, &data[32768..]);
+    }
+
+    #[tokio::test]
+    async fn download_whole_file_from_several_peers() {
+        let data = test_data(300_000);
+        let torrent = torrent_for(&data, 32768);
+        let first = spawn_seeder(&torrent, data.clone()).await;
+        let second = spawn_seeder(&torrent, data.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        download(
+            &torrent,
+            &[first, second],
+            [1; 20],
+            &output,
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn completion_hook_runs_once_finished() {
+        let data = test_data(100_000);
+        let torrent = torrent_for(&data, 32768);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        let log = dir.path().join("test.bin.log");
+        let options = DownloadOptions {
+            on_complete: Some("echo \"$TORRENT_EVENT\" >> \"$TORRENT_PATH.log\"".to_owned()),
+            ..DownloadOptions::default()
+        };
+
+        download(&torrent, &[seeder], [1; 20], &output, &options)
+            .await
+            .unwrap();
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        while std::fs::read_to_string(&log).unwrap_or_default().is_empty() {
+            assert!(tokio::time::Instant::now() < deadline, "hook didn't run");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        // nothing is downloaded the second time, so the hook isn't run again
+        download(&torrent, &[seeder], [1; 20], &output, &options)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "completed\n");
+    }
+
+    #[tokio::test]
+    async fn download_within_connection_limit() {
+        let data = test_data(100_000);
+        let torrent = torrent_for(&data, 32768);
+        // the seeder waits in the queue until the connection to the closed port fails
+        let unreachable = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        let options = DownloadOptions {
+            max_torrent_connections: 1,
+            ..DownloadOptions::default()
+        };
+        download(&torrent, &[unreachable, seeder], [1; 20], &output, &options)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn download_respects_rate_limit() {
+        let data = test_data(200_000);
+        let torrent = torrent_for(&data, 32768);
+        let first = spawn_seeder(&torrent, data.clone()).await;
+        let second = spawn_seeder(&torrent, data.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        let options = DownloadOptions {
+            max_download_rate: Some(100_000),
+            ..DownloadOptions::default()
+        };
+        let start = std::time::Instant::now();
+        download(&torrent, &[first, second], [1; 20], &output, &options)
+            .await
+            .unwrap();
+        // a second's worth comes in a burst, the rest at the rate, whichever peer sends it
+        assert!(start.elapsed() >= Duration::from_millis(900));
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn download_retries_a_handshake_that_was_cut_off() {
+        let data = test_data(40_000);
+        let torrent = torrent_for(&data, 32768);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // a peer that is busy for a moment hangs up on every attempt in it
+        let seeder = {
+            let (torrent, data) = (Arc::new(torrent.clone()), Arc::new(data.clone()));
+            tokio::spawn(async move {
+                let mut busy_until = None;
+                while let Ok((stream, _)) = listener.accept().await {
+                    let now = Instant::now();
+                    let busy_until = *busy_until.get_or_insert(now + Duration::from_millis(300));
+                    if now < busy_until {
+                        drop(stream);
+                        continue;
+                    }
+                    tokio::spawn(serve_as_seeder(stream, torrent.clone(), data.clone()));
+                }
+            })
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        download(
+            &torrent,
+            &[addr],
+            [1; 20],
+            &output,
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        seeder.abort();
+    }
+
+    #[tokio::test]
+    async fn download_resumes_from_saved_pieces() {
+        let data = test_data(4 * 32768);
+        let torrent = torrent_for(&data, 32768);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        // piece 0 is recorded, piece 1 was written but not recorded yet, the rest is missing
+        let mut partial = data[..2 * 32768].to_vec();
+        partial.resize(data.len(), 0);
+        std::fs::write(&output, &partial).unwrap();
+        let mut resume = ResumeData::new(&torrent).unwrap();
+        resume.set_piece(0);
+        resume.downloaded = 32768;
+        resume.save(&output).await.unwrap();
+
+        let (addr, _) = spawn_stalling_peer(&torrent).await;
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        download(
+            &torrent,
+            &[seeder],
+            [1; 20],
+            &output,
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        let resume = ResumeData::load(&torrent, &output).await.unwrap();
+        assert!((0..4).all(|index| resume.has_piece(index)));
+        assert_eq!(resume.downloaded, 3 * 32768);
+
+        // everything is there now, so not even a peer is needed
+        download(
+            &torrent,
+            &[addr],
+            [1; 20],
+            &output,
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn download_over_an_existing_file_only_if_asked_to() {
+        let data = test_data(4 * 32768);
+        let torrent = torrent_for(&data, 32768);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        let other = vec![7; data.len()];
+        std::fs::write(&output, &other).unwrap();
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+
+        let error = download(
+            &torrent,
+            &[seeder],
+            [1; 20],
+            &output,
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error, crate::Error::Disk(_)), "{:?}", error);
+        assert!(error.to_string().contains("exists already"));
+        assert_eq!(std::fs::read(&output).unwrap(), other);
+        assert!(ResumeData::load(&torrent, &output).await.is_none());
+
+        let options = DownloadOptions {
+            overwrite: true,
+            ..DownloadOptions::default()
+        };
+        download(&torrent, &[seeder], [1; 20], &output, &options)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn download_without_allocation_finds_written_pieces() {
+        let data = test_data(4 * 32768);
+        let torrent = torrent_for(&data, 32768);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        // the file has only grown as far as the first two pieces, and has no resume data
+        std::fs::write(&output, &data[..2 * 32768]).unwrap();
+
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let options = DownloadOptions {
+            allocation: Allocation::None,
+            ..DownloadOptions::default()
+        };
+        download(&torrent, &[seeder], [1; 20], &output, &options)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        let resume = ResumeData::load(&torrent, &output).await.unwrap();
+        assert_eq!(resume.downloaded, 2 * 32768);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn download_to_memory_mapped_files() {
+        let data = test_data(100_000);
+        let torrent = multi_file_torrent_for(&data, 32768, &[("a", 40_000), ("b", 60_000)]);
+        let dir = tempfile::tempdir().unwrap();
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let options = DownloadOptions {
+            storage_backend: StorageBackend::Mmap,
+            ..DownloadOptions::default()
+        };
+        download(&torrent, &[seeder], [1; 20], dir.path(), &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("a")).unwrap(),
+            &data[..40_000]
+        );
+        assert_eq!(
+            std::fs::read(dir.path().join("b")).unwrap(),
+            &data[40_000..]
+        );
+    }
+
+    #[tokio::test]
+    async fn download_to_renamed_files() {
+        let data = test_data(100_000);
+        let mut torrent = multi_file_torrent_for(&data, 32768, &[("dir/a", 40_000), ("b", 60_000)]);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        torrent
+            .rename(Path::new("dir"), Path::new("renamed"))
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        download(
+            &torrent,
+            &[seeder],
+            [1; 20],
+            dir.path(),
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("renamed/a")).unwrap(),
+            &data[..40_000]
+        );
+        assert!(!dir.path().join("dir").exists());
+        let resume = ResumeData::load(&torrent, dir.path()).await.unwrap();
+        assert_eq!(resume.renamed[0].path, "renamed/a");
+    }
+
+    #[tokio::test]
+    async fn download_to_part_files() {
+        let data = test_data(100_000);
+        let torrent = torrent_for(&data, 32768);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        let part = dir.path().join("test.bin.part");
+        // an earlier run got the first piece in
+        let mut partial = data[..32768].to_vec();
+        partial.resize(data.len(), 0);
+        std::fs::write(&part, partial).unwrap();
+        let mut resume = ResumeData::new(&torrent).unwrap();
+        resume.set_piece(0);
+        resume.save(&output).await.unwrap();
+
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let options = DownloadOptions {
+            incomplete: Incomplete {
+                dir: None,
+                part_suffix: true,
+            },
+            ..DownloadOptions::default()
+        };
+        download(&torrent, &[seeder], [1; 20], &output, &options)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        assert!(!part.exists());
+        let resume = ResumeData::load(&torrent, &output).await.unwrap();
+        assert_eq!(resume.downloaded, 100_000 - 32768);
+    }
+
+    #[tokio::test]
+    async fn download_to_custom_storage() {
+        let data = test_data(100_000);
+        let torrent = multi_file_torrent_for(&data, 32768, &[("a", 40_000), ("b", 60_000)]);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test");
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let memory = MemoryStorage::default();
+        let options = DownloadOptions {
+            storage: Some(Arc::new(memory.clone())),
+            ..DownloadOptions::default()
+        };
+        download(&torrent, &[seeder], [1; 20], &output, &options)
+            .await
+            .unwrap();
+        assert_eq!(memory.content(), data);
+        assert!(memory.is_complete());
+        // only the resume data went to disk
+        assert!(!output.join("a").exists());
+        assert!(ResumeData::load(&torrent, &output).await.is_none());
+        let resume = ResumeData::load_recorded(&torrent, &output).await.unwrap();
+        assert_eq!(resume.downloaded, 100_000);
+    }
+
+    #[tokio::test]
+    async fn download_survives_corrupt_peer() {
+        let data = test_data(100_000);
+        let torrent = torrent_for(&data, 32768);
+        let mut corrupted = data.clone();
+        corrupted[40_000] ^= 0xff;
+        let bad = spawn_seeder(&torrent, corrupted).await;
+        let good = spawn_seeder(&torrent, data.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        download(
+            &torrent,
+            &[bad, good],
+            [1; 20],
+            &output,
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn bans_peers_that_keep_sending_bad_pieces() {
+        let data = test_data(100_000);
+        let torrent = torrent_for(&data, 32768);
+        let corrupted = data.iter().map(|byte| byte ^ 0xff).collect();
+        // on another IP than the good peer, which would be banned along with it otherwise
+        let bad = spawn_seeder_on("127.0.0.2:0", &torrent, corrupted).await;
+        let good = spawn_seeder(&torrent, data.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        let (peer_tx, announced) = mpsc::channel(1);
+        let (_, inbound) = mpsc::channel(1);
+        let bans = Arc::new(BanList::new());
+        let stats = Arc::new(SwarmStats::new());
+        let peers = PeerSources {
+            announced,
+            inbound,
+            utp: None,
+            dht: None,
+            download_limit: RateLimiter::unlimited(),
+            slots: Arc::new(ConnectionSlots::new(10, 10)),
+            bans: bans.clone(),
+            file_priorities: watch::channel(Vec::new()).1,
+            read_position: watch::channel(0).1,
+            piece_deadlines: watch::channel(HashMap::new()).1,
+            stats: stats.clone(),
+            shutdown: watch::channel(false).1,
+            trackers: None,
+            tasks: Supervisor::new(),
+        };
+        let (progress, _) = watch::channel(Progress {
+            left: torrent.total_length(),
+            ..Progress::default()
+        });
+        let channel = crate::events::channel();
+        let mut received = channel.subscribe();
+        let events = EventSender::new(torrent.info_hash(), channel);
+        let options = DownloadOptions::default();
+        let download = download_from(
+            &torrent, peers, [1; 20], &output, &options, &progress, &events,
+        );
+        let announcer = async {
+            // the bad peer is connected to again every time it is announced, until it is banned
+            let mut connections = 0;
+            while !bans.is_banned(bad.ip()) {
+                // an announce arriving while it still counts as connected is dropped, so repeat it
+                peer_tx.send(vec![bad]).await.unwrap();
+                let _ = tokio::time::timeout(Duration::from_millis(100), async {
+                    loop {
+                        match received.recv().await.unwrap().kind {
+                            EventKind::PeerConnected(addr) if addr == bad => connections += 1,
+                            EventKind::PeerDisconnected(addr) if addr == bad => break,
+                            _ => {}
+                        }
+                    }
+                })
+                .await;
+            }
+            peer_tx.send(vec![bad, good]).await.unwrap();
+            drop(peer_tx);
+            connections
+        };
+        let (result, connections) = tokio::join!(download, announcer);
+        result.unwrap();
+        assert_eq!(connections, MAX_HASH_FAILURES);
+        // the bad pieces count as waste, at least as long as the short last one each
+        let stats = stats.snapshot();
+        assert_eq!(stats.hash_failures, MAX_HASH_FAILURES);
+        assert!(stats.corrupt >= MAX_HASH_FAILURES * (100_000 % 32768));
+        assert!(stats.waste() > 0.0);
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        while let Ok(event) = received.try_recv() {
+            assert_ne!(event.kind, EventKind::PeerConnected(bad));
+        }
+    }
+
+    #[tokio::test]
+    async fn download_from_ipv6_and_ipv4_peers() {
+        let data = test_data(100_000);
+        let torrent = torrent_for(&data, 16384);
+        let v6 = spawn_seeder_on("[::1]:0", &torrent, data.clone()).await;
+        let v4 = spawn_seeder(&torrent, data.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        download(
+            &torrent,
+            &[v6],
+            [1; 20],
+            &output,
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+
+        let output = dir.path().join("both.bin");
+        download(
+            &torrent,
+            &[v6, v4],
+            [1; 20],
+            &output,
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn download_over_utp() {
+        let data = test_data(100_000);
+        let torrent = torrent_for(&data, 16384);
+        let seeder = spawn_utp_seeder(&torrent, data.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        let options = DownloadOptions {
+            utp: true,
+            ..DownloadOptions::default()
+        };
+        download(&torrent, &[seeder], [1; 20], &output, &options)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn download_multi_file() {
+        let data = test_data(100_000);
+        let torrent = multi_file_torrent_for(
+            &data,
+            32768,
+            &[
+                ("a.bin", 40_000),
+                ("sub/dir/b.bin", 0),
+                ("sub/c.bin", 60_000),
+            ],
+        );
+        let addr = spawn_seeder(&torrent, data.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+
+        download(
+            &torrent,
+            &[addr],
+            [1; 20],
+            dir.path(),
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("a.bin")).unwrap(),
+            &data[..40_000]
+        );
+        assert!(std::fs::read(dir.path().join("sub/dir/b.bin"))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            std::fs::read(dir.path().join("sub/c.bin")).unwrap(),
+            &data[40_000..]
+        );
+    }
+
+    #[tokio::test]
+    async fn download_with_tracker_sends_events() {
+        let data = test_data(100_000);
+        let mut torrent = torrent_for(&data, 32768);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let (url, requests) = spawn_tracker(vec![announce_body(60, &[seeder])]).await;
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        download_with_tracker(
+            &torrent,
+            [1; 20],
+            &output,
+            &DownloadOptions {
+                port: 0,
+                ..DownloadOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].contains("&left=100000&compact=1&event=started"));
+        assert!(requests[1].contains("&downloaded=100000&left=0&compact=1&event=completed"));
+        assert!(requests[2].contains("&event=stopped"));
+    }
+
+    #[tokio::test]
+    async fn restarted_download_goes_on_with_the_tracker_session() {
+        let data = test_data(100_000);
+        let mut torrent = torrent_for(&data, 32768);
+        let (url, requests) = spawn_tracker(vec![announce_body(60, &[])]).await;
+        torrent.announce = url.clone();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        std::fs::write(&output, &data).unwrap();
+        // killed before the trackers were told it stopped
+        let mut resume = ResumeData::from_verified(&torrent, &[true; 4]).unwrap();
+        resume.downloaded = 100_000;
+        resume.uploaded = 5000;
+        resume
+            .tracker
+            .tracker_ids
+            .insert(url.clone(), b"t1".to_vec().into());
+        resume.tracker.key = Some(0xbeef);
+        resume.tracker.last_announce = Some(1);
+        resume.save(&output).await.unwrap();
+
+        download_with_tracker(
+            &torrent,
+            [1; 20],
+            &output,
+            &DownloadOptions {
+                port: 0,
+                ..DownloadOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        let requests = requests.lock().unwrap().clone();
+        assert!(requests[0].contains("&uploaded=5000&downloaded=100000&"));
+        assert!(requests[0].contains("&trackerid=t1&key=0000BEEF "));
+        assert!(!requests[0].contains("event="));
+        assert!(requests.last().unwrap().contains("&uploaded=5000&"));
+        // the stop ended the session, so the next start announces it anew
+        let resume = ResumeData::load(&torrent, &output).await.unwrap();
+        assert!(!resume.tracker.in_session());
+        assert_eq!(resume.tracker.interval, Some(60));
+        assert_eq!(resume.tracker.key, Some(0xbeef));
+    }
+
+    #[tokio::test]
+    async fn download_with_tracker_uses_reannounced_peers() {
+        let data = test_data(40_000);
+        let mut torrent = torrent_for(&data, 32768);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let (url, requests) =
+            spawn_tracker(vec![announce_body(0, &[]), announce_body(60, &[seeder])]).await;
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        download_with_tracker(
+            &torrent,
+            [1; 20],
+            &output,
+            &DownloadOptions {
+                port: 0,
+                ..DownloadOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        assert!(!requests.lock().unwrap()[1].contains("event="));
+    }
+
+    #[tokio::test]
+    async fn download_with_tracker_waits_out_an_empty_swarm() {
+        let data = test_data(40_000);
+        let mut torrent = torrent_for(&data, 32768);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let (url, requests) = spawn_tracker(vec![
+            announce_body(1, &[]),
+            announce_body(1, &[]),
+            announce_body(60, &[seeder]),
+        ])
+        .await;
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        let started = Instant::now();
+        let options = DownloadOptions {
+            port: 0,
+            ..DownloadOptions::default()
+        };
+        let downloaded = download_with_tracker(&torrent, [1; 20], &output, &options);
+        // neither given up on at the first empty peer list nor left hanging
+        tokio::time::timeout(Duration::from_secs(30), downloaded)
+            .await
+            .expect("download hangs without peers")
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        // asked again each time the interval was up
+        assert!(started.elapsed() >= Duration::from_secs(2));
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].contains("event=started"));
+        assert!(!requests[1].contains("event=") && !requests[2].contains("event="));
+    }
+
+    #[tokio::test]
+    async fn download_with_tracker_accepts_incoming_peers() {
+        let data = test_data(40_000);
+        let mut torrent = torrent_for(&data, 32768);
+        let (url, requests) = spawn_tracker(vec![announce_body(60, &[])]).await;
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.bin");
+        std::fs::write(&source, &data).unwrap();
+        let output = dir.path().join("test.bin");
+
+        // a seed that learns about us from the tracker and connects
+        let seed = {
+            let torrent = torrent.clone();
+            tokio::spawn(async move {
+                let port = loop {
+                    let first = requests.lock().unwrap().first().cloned();
+                    if let Some(request) = first {
+                        let port = request.split("&port=").nth(1).unwrap();
+                        break port.split('&').next().unwrap().parse::<u16>().unwrap();
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                };
+                let addr = SocketAddr::from(([127, 0, 0, 1], port));
+                let (stream, _) = peer::connect(addr, torrent.info_hash(), [7; 20])
+                    .await
+                    .unwrap();
+                let storage = FileStorage::open(&torrent, &source).await.unwrap();
+                let disk = Disk::spawn(Arc::new(torrent.clone()), storage);
+                let seeding = Seeding {
+                    disk,
+                    progress: watch::channel(Progress::default()).0,
+                    upload_limit: RateLimiter::new(None),
+                    super_seeder: None,
+                    max_request_length: DEFAULT_MAX_REQUEST_LENGTH,
+                    verified: None,
+                };
+                let slot = Arc::new(Choker::new(UPLOAD_SLOTS)).register();
+                let _ = serve_peer(stream, &torrent, &seeding, slot, None).await;
+            })
+        };
+
+        let options = DownloadOptions {
+            port: 0,
+            ..DownloadOptions::default()
+        };
+        download_with_tracker(&torrent, [1; 20], &output, &options)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        seed.abort();
+    }
+
+    #[tokio::test]
+    async fn serves_other_peers_while_downloading() {
+        let data = test_data(100_000);
+        let mut torrent = torrent_for(&data, 32768);
+        let last = torrent.piece_count() as u32 - 1;
+        // a seed that holds back the last piece until told to send it
+        let (release, released) = watch::channel(false);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let seed = listener.local_addr().unwrap();
+        {
+            let (torrent, data) = (torrent.clone(), data.clone());
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut handshake = [0; Handshake::LENGTH];
+                stream.read_exact(&mut handshake).await.unwrap();
+                let ours = Handshake::new(torrent.info_hash(), [7; 20]).to_bytes();
+                stream.write_all(&ours).await.unwrap();
+                let mut framed = Framed::new(stream, MessageCodec);
+                let bitfield = Bitfield::full(torrent.piece_count()).as_bytes().to_vec();
+                framed.send(PeerMessage::Bitfield(bitfield)).await.unwrap();
+                while let Some(Ok(message)) = framed.next().await {
+                    match message {
+                        PeerMessage::Interested => framed.send(PeerMessage::Unchoke).await.unwrap(),
+                        PeerMessage::Request {
+                            index,
+                            begin,
+                            length,
+                        } => {
+                            if index == last {
+                                released
+                                    .clone()
+                                    .wait_for(|&released| released)
+                                    .await
+                                    .unwrap();
+                            }
+                            let start = index as usize * 32768 + begin as usize;
+                            let block = data[start..start + length as usize].to_vec();
+                            let piece = PeerMessage::Piece {
+                                index,
+                                begin,
+                                block,
+                            };
+                            framed.send(piece).await.unwrap();
+                        }
+                        _ => {}
+                    }
+                }
+            });
+        }
+        let (url, requests) = spawn_tracker(vec![announce_body(60, &[seed])]).await;
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        let options = DownloadOptions {
+            port: 0,
+            ..DownloadOptions::default()
+        };
+        let downloading = {
+            let (torrent, output) = (torrent.clone(), output.clone());
+            tokio::spawn(async move {
+                download_with_tracker(&torrent, [1; 20], &output, &options).await
+            })
+        };
+
+        // another leecher connects to the downloader, which announces and serves it a piece
+        let port = loop {
+            let first = requests.lock().unwrap().first().cloned();
+            if let Some(request) = first {
+                let port = request.split("&port=").nth(1).unwrap();
+                break port.split('&').next().unwrap().parse::<u16>().unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let (stream, _) = peer::connect(addr, torrent.info_hash(), [8; 20])
+            .await
+            .unwrap();
+        let mut framed = Framed::new(stream, MessageCodec);
+        let (mut has_first, mut interested, mut unchoked, mut requested) =
+            (false, false, false, false);
+        let block = loop {
+            let message = tokio::time::timeout(Duration::from_secs(10), framed.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            match message {
+                PeerMessage::Bitfield(bytes) => has_first = bytes[0] & 0x80 != 0,
+                PeerMessage::Have(0) => has_first = true,
+                PeerMessage::Unchoke => unchoked = true,
+                PeerMessage::Piece {
+                    index: 0, block, ..
+                } => break block,
+                _ => {}
+            }
+            if has_first && !interested {
+                framed.send(PeerMessage::Interested).await.unwrap();
+                interested = true;
+            }
+            if interested && unchoked && !requested {
+                let request = PeerMessage::Request {
+                    index: 0,
+                    begin: 0,
+                    length: BLOCK_SIZE,
+                };
+                framed.send(request).await.unwrap();
+                requested = true;
+            }
+        };
+        assert_eq!(block, data[..BLOCK_SIZE as usize]);
+        assert!(!downloading.is_finished());
+
+        release.send_replace(true);
+        downloading.await.unwrap().unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn cancelled_piece_download_cancels_requests() {
+        let data = test_data(40_000);
+        let torrent = torrent_for(&data, 32768);
+        let (addr, mut messages) = spawn_stalling_peer(&torrent).await;
+
+        let (stream, _) = peer::connect(addr, torrent.info_hash(), [1; 20])
+            .await
+            .unwrap();
+        let mut session = PeerSession::start(stream).await.unwrap();
+        let piece = session
+            .download_piece_unless(&torrent, 0, async {})
+            .await
+            .unwrap();
+        assert_eq!(piece, None);
+
+        let mut cancelled = Vec::new();
+        while cancelled.len() < 2 {
+            if let PeerMessage::Cancel { begin, .. } = messages.recv().await.unwrap() {
+                cancelled.push(begin);
+            }
+        }
+        assert_eq!(cancelled, [0, 16384]);
+    }
+
+    #[tokio::test]
+    async fn blocks_from_other_peers_are_cancelled() {
+        let data = test_data(2 * BLOCK_SIZE as usize);
+        let torrent = torrent_for(&data, data.len());
+        let block = |begin: u32| PeerMessage::Piece {
+            index: 0,
+            begin,
+            block: data[begin as usize..][..BLOCK_SIZE as usize].to_vec(),
+        };
+        // one peer only sends the first block and the other only the second, once the first
+        // was cancelled
+        let (a, theirs) = tokio::io::duplex(1 << 20);
+        let first = block(0);
+        let peer_a = tokio::spawn(async move {
+            let mut framed = Framed::new(theirs, MessageCodec);
+            loop {
+                match framed.next().await.unwrap().unwrap() {
+                    PeerMessage::Interested => framed.send(PeerMessage::Unchoke).await.unwrap(),
+                    PeerMessage::Request { begin: 0, .. } => {
+                        framed.send(first.clone()).await.unwrap()
+                    }
+                    PeerMessage::Cancel { begin, .. } => return begin,
+                    _ => {}
+                }
+            }
+        });
+        let (b, theirs) = tokio::io::duplex(1 << 20);
+        let second = block(BLOCK_SIZE);
+        let peer_b = tokio::spawn(async move {
+            let mut framed = Framed::new(theirs, MessageCodec);
+            let mut requested = Vec::new();
+            loop {
+                match framed.next().await.unwrap().unwrap() {
+                    PeerMessage::Interested => framed.send(PeerMessage::Unchoke).await.unwrap(),
+                    PeerMessage::Request { begin, .. } => requested.push(begin),
+                    PeerMessage::Cancel { begin, .. } => {
+                        framed.send(second).await.unwrap();
+                        return (requested, begin);
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        let mut a = PeerSession::start(a).await.unwrap();
+        let mut b = PeerSession::start(b).await.unwrap();
+        let buffer = PieceBuffer::new(&piece_blocks(&torrent, 0));
+        let (from_a, from_b) = tokio::join!(
+            a.fetch_piece_unless(&torrent, 0, &buffer, std::future::pending()),
+            b.fetch_piece_unless(&torrent, 0, &buffer, std::future::pending()),
+        );
+        // the peer that sent the last block gets the piece
+        assert_eq!(from_a.unwrap(), None);
+        assert_eq!(from_b.unwrap().unwrap(), data);
+        assert_eq!(peer_a.await.unwrap(), BLOCK_SIZE);
+        assert_eq!(peer_b.await.unwrap(), (vec![0, BLOCK_SIZE], 0));
+    }
+
+    #[tokio::test]
+    async fn peers_announcing_pieces_past_the_end_are_dropped() {
+        let torrent = torrent_for(&test_data(1000), 100);
+        // a peer that announces `before` the unchoke, and `later` once we request a block
+        let spawn_peer = |before: Vec<PeerMessage>, later: Vec<PeerMessage>| {
+            let (ours, theirs) = tokio::io::duplex(1 << 16);
+            tokio::spawn(async move {
+                let mut framed = Framed::new(theirs, MessageCodec);
+                for message in before {
+                    framed.send(message).await.unwrap();
+                }
+                framed.send(PeerMessage::Unchoke).await.unwrap();
+                let mut later = later.into_iter();
+                while let Some(Ok(message)) = framed.next().await {
+                    if matches!(message, PeerMessage::Request { .. }) {
+                        for message in later.by_ref() {
+                            framed.send(message).await.unwrap();
+                        }
+                    }
+                }
+            });
+            ours
+        };
+
+        for (before, error) in [
+            (PeerMessage::Bitfield(vec![0xff]), "bitfield of 1 bytes"),
+            (
+                PeerMessage::Bitfield(vec![0xff, 0xff]),
+                "announced piece 10",
+            ),
+            (PeerMessage::Have(10), "announced piece 10"),
+        ] {
+            let mut session = PeerSession::start(spawn_peer(vec![before], Vec::new()))
+                .await
+                .unwrap();
+            let e = session.set_piece_count(torrent.piece_count()).unwrap_err();
+            assert!(e.to_string().contains(error), "{:#}", e);
+        }
+
+        let bitfield = PeerMessage::Bitfield(vec![0xff, 0b1100_0000]);
+        let stream = spawn_peer(vec![bitfield], vec![PeerMessage::Have(10)]);
+        let mut session = PeerSession::start(stream).await.unwrap();
+        session.set_piece_count(torrent.piece_count()).unwrap();
+        let e = session.download_piece(&torrent, 0).await.unwrap_err();
+        assert!(e.to_string().contains("announced piece 10"), "{:#}", e);
+
+        // before the piece count is known, as far as any bitfield could reach
+        let stream = spawn_peer(vec![PeerMessage::Have(u32::MAX)], Vec::new());
+        let e = PeerSession::start(stream).await.err().unwrap();
+        assert!(e.to_string().contains("past any bitfield"), "{:#}", e);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_idle_connections_alive() {
+        let (ours, theirs) = tokio::io::duplex(1 << 20);
+        // a peer that never unchokes us, and goes silent after a while
+        let peer = tokio::spawn(async move {
+            let mut framed = Framed::new(theirs, MessageCodec);
+            let mut received = Vec::new();
+            let mut keep_alive = std::pin::pin!(tokio::time::sleep(Duration::from_secs(150)));
+            let mut sent = false;
+            loop {
+                tokio::select! {
+                    message = framed.next() => match message {
+                        Some(Ok(message)) => received.push((message, tokio::time::Instant::now())),
+                        _ => return received,
+                    },
+                    () = &mut keep_alive, if !sent => {
+                        framed.send(PeerMessage::KeepAlive).await.unwrap();
+                        sent = true;
+                    }
+                }
+            }
+        });
+
+        let start = tokio::time::Instant::now();
+        let error = PeerSession::start(ours).await.err().unwrap();
+        assert!(error.to_string().contains("sent nothing"), "{:#}", error);
+        assert_eq!(start.elapsed().as_secs(), 150 + IDLE_TIMEOUT.as_secs());
+        let keep_alives: Vec<u64> = peer
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|(message, _)| *message == PeerMessage::KeepAlive)
+            .map(|(_, at)| (at - start).as_secs())
+            .collect();
+        assert_eq!(keep_alives, [120, 240]);
+    }
+
+    #[tokio::test]
+    async fn snubbing_peer_gets_one_request_at_a_time() {
+        let data = test_data(40_000);
+        let torrent = torrent_for(&data, 32768);
+        let (addr, mut messages) = spawn_stalling_peer(&torrent).await;
+
+        let (stream, _) = peer::connect(addr, torrent.info_hash(), [1; 20])
+            .await
+            .unwrap();
+        let mut session = PeerSession::start(stream).await.unwrap();
+        session.set_request_timeout(Duration::from_millis(100));
+        for _ in 0..2 {
+            let piece = session
+                .download_piece_unless(&torrent, 0, std::future::pending())
+                .await
+                .unwrap();
+            assert_eq!(piece, None);
+            assert!(session.is_snubbed());
+        }
+        assert!(session.download_piece(&torrent, 0).await.is_err());
+
+        let mut requests = Vec::new();
+        let mut cancels = 0;
+        while cancels < 4 {
+            match messages.recv().await.unwrap() {
+                PeerMessage::Request { begin, .. } => requests.push(begin),
+                PeerMessage::Cancel { .. } => cancels += 1,
+                _ => {}
+            }
+        }
+        assert_eq!(requests, [0, 16384, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn drops_snubbing_peers() {
+        let data = test_data(40_000);
+        let torrent = torrent_for(&data, 32768);
+        let (stalling, _messages) = spawn_stalling_peer(&torrent).await;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        let options = DownloadOptions {
+            request_timeout: Duration::from_millis(100),
+            ..DownloadOptions::default()
+        };
+        let peers = [stalling];
+        let download = download(&torrent, &peers, [1; 20], &output, &options);
+        let error = tokio::time::timeout(Duration::from_secs(5), download)
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("ran out of peers"),
+            "{:#}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn endgame_finishes_despite_stalling_peer() {
+        let data = test_data(100_000);
+        let torrent = torrent_for(&data, 32768);
+        let (stalling, _messages) = spawn_stalling_peer(&torrent).await;
+        let good = spawn_seeder(&torrent, data.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        let peers = [stalling, good];
+        let options = DownloadOptions::default();
+        let download = download(&torrent, &peers, [1; 20], &output, &options);
+        tokio::time::timeout(Duration::from_secs(10), download)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    /// Spawns a peer without any pieces that tells whoever connects about `introduced` over
+    /// PEX, returning its address and the extension messages it receives.
+    async fn spawn_pex_peer(
+        torrent: &Torrent,
+        introduced: SocketAddr,
+    ) -> (SocketAddr, mpsc::UnboundedReceiver<PeerMessage>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let info_hash = torrent.info_hash();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0; Handshake::LENGTH];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream
+                .write_all(&Handshake::new(info_hash, [8; 20]).to_bytes())
+                .await
+                .unwrap();
+            let mut framed = Framed::new(stream, MessageCodec);
+            let mut introducing = true;
+            while let Some(Ok(message)) = framed.next().await {
+                match &message {
+                    PeerMessage::Extended { id: 0, .. } if introducing => {
+                        introducing = false;
+                        let ours = ExtensionHandshake {
+                            m: BTreeMap::from([("ut_pex".to_owned(), 5)]),
+                            metadata_size: None,
+                        };
+                        framed.send(ours.to_message().unwrap()).await.unwrap();
+                        let pex = PexMessage::new(&[introduced], &[]);
+                        framed
+                            .send(pex.to_message(UT_PEX_ID).unwrap())
+                            .await
+                            .unwrap();
+                    }
+                    PeerMessage::Interested => {
+                        framed.send(PeerMessage::Unchoke).await.unwrap();
+                    }
+                    _ => {}
+                }
+                if matches!(message, PeerMessage::Extended { .. }) {
+                    let _ = tx.send(message);
+                }
+            }
+        });
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn download_from_peers_learned_over_pex() {
+        let data = test_data(40_000);
+        let torrent = torrent_for(&data, 32768);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let (introducer, mut received) = spawn_pex_peer(&torrent, seeder).await;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        download(
+            &torrent,
+            &[introducer],
+            [1; 20],
+            &output,
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        let PeerMessage::Extended { id: 0, payload } = received.recv().await.unwrap() else {
+            panic!("expected the extension handshake first");
+        };
+        let handshake: ExtensionHandshake = bencode::from_bytes(&payload).unwrap();
+        assert_eq!(handshake.m.get("ut_pex"), Some(&UT_PEX_ID));
+        // hole punching is offered once the swarm takes the peer on
+        let PeerMessage::Extended { id: 0, payload } = received.recv().await.unwrap() else {
+            panic!("expected another extension handshake");
+        };
+        let handshake: ExtensionHandshake = bencode::from_bytes(&payload).unwrap();
+        assert_eq!(handshake.m.get("ut_holepunch"), Some(&UT_HOLEPUNCH_ID));
+        // our first PEX message goes out right away and uses the id the peer asked for
+        let PeerMessage::Extended { id: 5, .. } = received.recv().await.unwrap() else {
+            panic!("expected a PEX message");
+        };
+    }
+
+    /// Spawns a peer that claims every piece but never sends any, and that tells whoever
+    /// connects about `introduced` over PEX. When asked to relay a hole punch to it, it starts
+    /// a seeder of `data` there and tells the asking peer to connect. The holepunch messages it
+    /// receives are handed back.
+    async fn spawn_holepunch_relay(
+        torrent: &Torrent,
+        data: Vec<u8>,
+        introduced: SocketAddr,
+    ) -> (SocketAddr, mpsc::UnboundedReceiver<HolepunchMessage>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let torrent = torrent.clone();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0; Handshake::LENGTH];
+            stream.read_exact(&mut handshake).await.unwrap();
+            let ours = Handshake::new(torrent.info_hash(), [8; 20]);
+            stream.write_all(&ours.to_bytes()).await.unwrap();
+            let mut framed = Framed::new(stream, MessageCodec);
+            let bitfield = Bitfield::full(torrent.piece_count());
+            let bitfield = PeerMessage::Bitfield(bitfield.as_bytes().to_vec());
+            framed.send(bitfield).await.unwrap();
+            let mut introducing = true;
+            while let Some(Ok(message)) = framed.next().await {
+                match message {
+                    PeerMessage::Extended { id: 0, .. } if introducing => {
+                        introducing = false;
+                        let ours = ExtensionHandshake {
+                            m: BTreeMap::from([
+                                ("ut_pex".to_owned(), 5),
+                                ("ut_holepunch".to_owned(), 6),
+                            ]),
+                            metadata_size: None,
+                        };
+                        framed.send(ours.to_message().unwrap()).await.unwrap();
+                        let pex = PexMessage::new(&[introduced], &[]);
+                        framed
+                            .send(pex.to_message(UT_PEX_ID).unwrap())
+                            .await
+                            .unwrap();
+                    }
+                    PeerMessage::Interested => {
+                        framed.send(PeerMessage::Unchoke).await.unwrap();
+                    }
+                    PeerMessage::Extended { id: 6, payload } => {
+                        let message = HolepunchMessage::parse(&payload).unwrap();
+                        if let HolepunchMessage::Rendezvous(target) = message {
+                            spawn_seeder_on(&target.to_string(), &torrent, data.clone()).await;
+                            let connect = HolepunchMessage::Connect(target);
+                            framed
+                                .send(connect.to_message(UT_HOLEPUNCH_ID))
+                                .await
+                                .unwrap();
+                        }
+                        let _ = tx.send(message);
+                    }
+                    _ => {}
+                }
+            }
+        });
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn unreachable_peers_are_connected_to_through_a_relay() {
+        let data = test_data(40_000);
+        let torrent = torrent_for(&data, 32768);
+        // nothing listens there until the relay has the peer connect to us
+        let behind_nat = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (relay, mut received) = spawn_holepunch_relay(&torrent, data.clone(), behind_nat).await;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        download(&torrent, &[relay], [1; 20], &output, &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        assert_eq!(
+            received.recv().await,
+            Some(HolepunchMessage::Rendezvous(behind_nat))
+        );
+    }
+
+    #[test]
+    fn rendezvous_are_relayed_between_connected_peers() {
+        let mut holepunching = Holepunching::default();
+        let mut inboxes = Vec::new();
+        let peers: Vec<SocketAddr> = ["10.0.0.1:1", "10.0.0.2:2", "10.0.0.3:3"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        for (index, &addr) in peers.iter().enumerate() {
+            let (to_send, inbox) = mpsc::channel(HOLEPUNCH_QUEUE);
+            let supported = Arc::new(AtomicBool::new(index < 2));
+            holepunching.connected(addr, HolepunchPeer { supported, to_send });
+            inboxes.push(inbox);
+        }
+        let (a, b, no_support) = (peers[0], peers[1], peers[2]);
+        let elsewhere: SocketAddr = "10.0.0.4:4".parse().unwrap();
+
+        holepunching.relay(a, b);
+        assert_eq!(inboxes[0].try_recv(), Ok(HolepunchMessage::Connect(b)));
+        assert_eq!(inboxes[1].try_recv(), Ok(HolepunchMessage::Connect(a)));
+        for (target, code) in [
+            (a, HolepunchMessage::NO_SELF),
+            (elsewhere, HolepunchMessage::NOT_CONNECTED),
+            (no_support, HolepunchMessage::NO_SUPPORT),
+        ] {
+            holepunching.relay(a, target);
+            assert_eq!(
+                inboxes[0].try_recv(),
+                Ok(HolepunchMessage::Error(target, code))
+            );
+        }
+        assert!(inboxes[1].try_recv().is_err() && inboxes[2].try_recv().is_err());
+
+        // a peer that failed to connect is asked of its introducer once, while it is connected
+        holepunching.introduced.insert(elsewhere, b);
+        holepunching.request(elsewhere);
+        holepunching.request(elsewhere);
+        assert_eq!(
+            inboxes[1].try_recv(),
+            Ok(HolepunchMessage::Rendezvous(elsewhere))
+        );
+        assert!(inboxes[1].try_recv().is_err());
+        holepunching.introduced.insert(elsewhere, b);
+        holepunching.disconnected(b);
+        assert!(holepunching.introduced.is_empty());
+    }
+
+    #[tokio::test]
+    async fn private_torrents_exchange_no_peers() {
+        let data = test_data(40_000);
+        let mut torrent = torrent_for(&data, 32768);
+        torrent.info.private = Some(1);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let (introducer, mut received) = spawn_pex_peer(&torrent, seeder).await;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        // the seeder has to come from the tracker, it isn't learned from the introducer
+        let peers = [introducer];
+        assert!(
+            download(&torrent, &peers, [1; 20], &output, &Default::default())
+                .await
+                .is_err()
+        );
+        let peers = [introducer, seeder];
+        download(&torrent, &peers, [1; 20], &output, &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        assert!(received.try_recv().is_err());
+
+        // nor from the DHT or the local network
+        torrent.announce = String::new();
+        let (_, inbound) = mpsc::channel(1);
+        let discovery = Discovery {
+            port: 1,
+            inbound,
+            dht: Some(Arc::new(Dht::bind("127.0.0.1:0").await.unwrap())),
+            lsd: None,
+            utp: None,
+            download_limit: RateLimiter::unlimited(),
+            slots: Arc::new(ConnectionSlots::new(10, 10)),
+            bans: Arc::default(),
+            file_priorities: watch::channel(Vec::new()).1,
+            read_position: watch::channel(0).1,
+            piece_deadlines: watch::channel(HashMap::new()).1,
+            stats: Arc::default(),
+            shutdown: watch::channel(false).1,
+            external_ip: ExternalIp::new(),
+        };
+        let events = EventSender::unobserved(torrent.info_hash());
+        let options = DownloadOptions::default();
+        let output = dir.path().join("other.bin");
+        assert!(
+            download_from_swarm(&torrent, [1; 20], discovery, &output, &options, events)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn trackerless_download_finds_peers_in_the_dht() {
+        let data = test_data(40_000);
+        let mut torrent = torrent_for(&data, 32768);
+        torrent.announce = String::new();
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+
+        let router = Dht::bind("127.0.0.1:0").await.unwrap();
+        let router_addr = router.local_addr().unwrap().to_string();
+        let seeding_node = Dht::bind("127.0.0.1:0").await.unwrap();
+        seeding_node.bootstrap(&[&router_addr]).await.unwrap();
+        seeding_node
+            .announce(torrent.info_hash(), seeder.port(), true)
+            .await;
+        let dht = Dht::bind("127.0.0.1:0").await.unwrap();
+        dht.bootstrap(&[&router_addr]).await.unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        let (_, inbound) = mpsc::channel(1);
+        let discovery = Discovery {
+            port: 1,
+            inbound,
+            dht: Some(Arc::new(dht)),
+            lsd: None,
+            utp: None,
+            download_limit: RateLimiter::unlimited(),
+            slots: Arc::new(ConnectionSlots::new(10, 10)),
+            bans: Arc::default(),
+            file_priorities: watch::channel(Vec::new()).1,
+            read_position: watch::channel(0).1,
+            piece_deadlines: watch::channel(HashMap::new()).1,
+            stats: Arc::default(),
+            shutdown: watch::channel(false).1,
+            external_ip: ExternalIp::new(),
+        };
+        let events = EventSender::unobserved(torrent.info_hash());
+        download_from_swarm(
+            &torrent,
+            [1; 20],
+            discovery,
+            &output,
+            &DownloadOptions::default(),
+            events,
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn trackerless_download_finds_local_peers() {
+        let data = test_data(40_000);
+        let mut torrent = torrent_for(&data, 32768);
+        torrent.announce = String::new();
+        // local peers are reached at the address their announcements come from, which isn't
+        // the loopback one
+        let seeder = spawn_seeder_on("0.0.0.0:0", &torrent, data.clone()).await;
+
+        let group = crate::lsd::tests::test_group();
+        let neighbour = Lsd::bind(group).await.unwrap();
+        let info_hash = torrent.info_hash();
+        let announcing = tokio::spawn(async move {
+            loop {
+                neighbour.announce(info_hash, seeder.port()).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        let (_, inbound) = mpsc::channel(1);
+        let discovery = Discovery {
+            port: 1,
+            inbound,
+            dht: None,
+            lsd: Some(Arc::new(Lsd::bind(group).await.unwrap())),
+            utp: None,
+            download_limit: RateLimiter::unlimited(),
+            slots: Arc::new(ConnectionSlots::new(10, 10)),
+            bans: Arc::default(),
+            file_priorities: watch::channel(Vec::new()).1,
+            read_position: watch::channel(0).1,
+            piece_deadlines: watch::channel(HashMap::new()).1,
+            stats: Arc::default(),
+            shutdown: watch::channel(false).1,
+            external_ip: ExternalIp::new(),
+        };
+        let events = EventSender::unobserved(info_hash);
+        download_from_swarm(
+            &torrent,
+            [1; 20],
+            discovery,
+            &output,
+            &DownloadOptions::default(),
+            events,
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        announcing.abort();
+    }
+
+    #[tokio::test]
+    async fn trackerless_download_from_web_seed() {
+        let data = test_data(100_000);
+        let mut torrent = multi_file_torrent_for(&data, 32768, &[("a", 50_000), ("b", 50_000)]);
+        torrent.announce = String::new();
+        let url = spawn_web_seed(vec![
+            ("/test/a", data[..50_000].to_vec()),
+            ("/test/b", data[50_000..].to_vec()),
+        ])
+        .await;
+        torrent.url_list = vec![format!("{}/", url)];
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test");
+        let (_, inbound) = mpsc::channel(1);
+        let discovery = Discovery {
+            port: 1,
+            inbound,
+            dht: None,
+            lsd: None,
+            utp: None,
+            download_limit: RateLimiter::unlimited(),
+            slots: Arc::new(ConnectionSlots::new(10, 10)),
+            bans: Arc::default(),
+            file_priorities: watch::channel(Vec::new()).1,
+            read_position: watch::channel(0).1,
+            piece_deadlines: watch::channel(HashMap::new()).1,
+            stats: Arc::default(),
+            shutdown: watch::channel(false).1,
+            external_ip: ExternalIp::new(),
+        };
+        let events = EventSender::unobserved(torrent.info_hash());
+        download_from_swarm(
+            &torrent,
+            [1; 20],
+            discovery,
+            &output,
+            &DownloadOptions::default(),
+            events,
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(output.join("a")).unwrap(), data[..50_000]);
+        assert_eq!(std::fs::read(output.join("b")).unwrap(), data[50_000..]);
+    }
+
+    #[tokio::test]
+    async fn download_from_web_seed_and_peers() {
+        let data = test_data(200_000);
+        let mut torrent = torrent_for(&data, 16384);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let url = spawn_web_seed(vec![("/test.bin", data.clone())]).await;
+        torrent.httpseeds = vec!["http://127.0.0.1:1/unreachable".to_owned()];
+        torrent.url_list = vec![format!("{}/test.bin", url)];
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        download(
+            &torrent,
+            &[seeder],
+            [1; 20],
+            &output,
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    #[should_panic]
+    async fn download_without_peers() {
+        let data = test_data(1000);
+        let torrent = torrent_for(&data, 32768);
+        let dir = tempfile::tempdir().unwrap();
+        download(
+            &torrent,
+            &[],
+            [1; 20],
+            &dir.path().join("test.bin"),
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    #[should_panic]
+    async fn download_piece_hash_mismatch() {
+        let data = test_data(40_000);
+        let torrent = torrent_for(&data, 32768);
+        let mut corrupted = data.clone();
+        corrupted[100] ^= 0xff;
+        let addr = spawn_seeder(&torrent, corrupted).await;
+
+        let (stream, _) = peer::connect(addr, torrent.info_hash(), [1; 20])
+            .await
+            .unwrap();
+        let mut session = PeerSession::start(stream).await.unwrap();
+        session.download_piece(&torrent, 0).await.unwrap();
+    }
+}
@@ -0,0 +1,288 @@
+use crate::bencode::{self, dict_value_span, Bencode};
+use crate::torrent::Torrent;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The keys of a metainfo file's root dictionary that the BEPs and common clients define;
+/// `EditOptions::strip` drops every other one.
+const STANDARD_KEYS: &[&[u8]] = &[
+    b"announce",
+    b"announce-list",
+    b"comment",
+    b"created by",
+    b"creation date",
+    b"encoding",
+    b"httpseeds",
+    b"info",
+    b"piece layers",
+    b"url-list",
+];
+
+/// Changes to make to a torrent file. Everything but `private` is outside the info
+/// dictionary, which is kept byte for byte, so the info hash stays the same.
+#[derive(Debug, Clone, Default)]
+pub struct EditOptions {
+    /// Tracker URLs to add, each in a tier of its own after the existing ones.
+    pub add_trackers: Vec<String>,
+    /// Tracker URLs to take out of every tier.
+    pub remove_trackers: Vec<String>,
+    /// The new comment; an empty one removes it.
+    pub comment: Option<String>,
+    /// The new creation date, in seconds since the Unix epoch; `Some(None)` removes it.
+    pub creation_date: Option<Option<i64>>,
+    /// Whether peers may only come from the trackers (BEP 27). This is the one setting in
+    /// the info dictionary, so changing it makes a torrent with another info hash.
+    pub private: Option<bool>,
+    /// Drop the keys of the root dictionary that aren't in `STANDARD_KEYS`.
+    pub strip: bool,
+}
+
+/// A creation date for `EditOptions::creation_date`: `now`, seconds since the Unix epoch,
+/// or an empty string for none.
+pub fn parse_creation_date(text: &str) -> anyhow::Result<Option<i64>> {
+    match text {
+        "" => Ok(None),
+        "now" => Ok(Some(
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
+        )),
+        seconds => seconds
+            .parse::<i64>()
+            .ok()
+            .filter(|&seconds| seconds >= 0)
+            .map(Some)
+            .with_context(|| {
+                format!(
+                    "invalid creation date `{}`, expected now or seconds since 1970",
+                    seconds
+                )
+            }),
+    }
+}
+
+/// The metainfo file `bytes` with the changes of `options` made to it. Keys that aren't
+/// changed are kept as they are, those the client doesn't know of too unless stripped, and
+/// the info dictionary is copied unchanged unless the private flag changes.
+pub fn edit(bytes: &[u8], options: &EditOptions) -> anyhow::Result<Vec<u8>> {
+    // make sure it is a torrent before changing anything
+    let torrent = Torrent::from_bytes(bytes)?;
+    let Bencode::Dict(mut root) = bencode::decode(bytes)? else {
+        anyhow::bail!("torrent is not a dictionary");
+    };
+    let span = dict_value_span(bytes, b"info")?.context("torrent has no info dictionary")?;
+    let mut raw_info = bytes[span].to_vec();
+
+    if options.strip {
+        root.retain(|key, _| STANDARD_KEYS.contains(&key.as_slice()));
+    }
+    if !options.add_trackers.is_empty() || !options.remove_trackers.is_empty() {
+        edit_trackers(&mut root, &torrent, options)?;
+    }
+    match options.comment.as_deref() {
+        Some("") => {
+            root.remove(&b"comment"[..]);
+        }
+        Some(comment) => {
+            root.insert(b"comment".to_vec(), Bencode::Bytes(comment.into()));
+        }
+        None => {}
+    }
+    match options.creation_date {
+        Some(Some(date)) => {
+            root.insert(b"creation date".to_vec(), Bencode::Int(date));
+        }
+        Some(None) => {
+            root.remove(&b"creation date"[..]);
+        }
+        None => {}
+    }
+    if let Some(private) = options
+        .private
+        .filter(|&private| private != torrent.is_private())
+    {
+        let Bencode::Dict(mut info) = bencode::decode(&raw_info)? else {
+            anyhow::bail!("info is not a dictionary");
+        };
+        if private {
+            info.insert(b"private".to_vec(), Bencode::Int(1));
+        } else {
+            info.remove(&b"private"[..]);
+        }
+        raw_info = Bencode::Dict(info).to_bytes();
+    }
+
+    // the info dictionary goes in as its bytes, so re-encoding can't change them
+    root.insert(b"info".to_vec(), Bencode::Dict(BTreeMap::new()));
+    let mut edited = Bencode::Dict(root).to_bytes();
+    let span = dict_value_span(&edited, b"info")?.expect("edited torrent has an info dictionary");
+    edited.splice(span, raw_info);
+    Torrent::from_bytes(&edited).context("edited torrent is invalid")?;
+    Ok(edited)
+}
+
+/// Adds and removes the trackers of `options` in `announce-list`, and keeps `announce` at
+/// its first tracker, or the one it named if that is still there.
+fn edit_trackers(
+    root: &mut BTreeMap<Vec<u8>, Bencode>,
+    torrent: &Torrent,
+    options: &EditOptions,
+) -> anyhow::Result<()> {
+    let mut tiers = match torrent.announce_list.is_empty() {
+        true if torrent.announce.is_empty() => Vec::new(),
+        true => vec![vec![torrent.announce.clone()]],
+        false => torrent.announce_list.clone(),
+    };
+    for url in &options.remove_trackers {
+        if !tiers.iter().flatten().any(|tracker| tracker == url) {
+            anyhow::bail!("torrent has no tracker {}", url);
+        }
+        for tier in &mut tiers {
+            tier.retain(|tracker| tracker != url);
+        }
+    }
+    tiers.retain(|tier| !tier.is_empty());
+    for url in &options.add_trackers {
+        if !tiers.iter().flatten().any(|tracker| tracker == url) {
+            tiers.push(vec![url.clone()]);
+        }
+    }
+
+    let announce = match tiers.iter().flatten().any(|url| *url == torrent.announce) {
+        true => Some(torrent.announce.clone()),
+        false => tiers.first().map(|tier| tier[0].clone()),
+    };
+    match announce {
+        Some(url) => root.insert(b"announce".to_vec(), Bencode::Bytes(url.into_bytes())),
+        None => root.remove(&b"announce"[..]),
+    };
+    // a single tracker needs no list
+    if tiers.iter().flatten().count() > 1 {
+        let tiers = tiers
+            .into_iter()
+            .map(|tier| {
+                Bencode::List(
+                    tier.into_iter()
+                        .map(|url| Bencode::Bytes(url.into_bytes()))
+                        .collect(),
+                )
+            })
+            .collect();
+        root.insert(b"announce-list".to_vec(), Bencode::List(tiers));
+    } else {
+        root.remove(&b"announce-list"[..]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{test_data, torrent_for};
+
+    /// A torrent file with a key of its own in the root and the info dictionary.
+    fn torrent_file() -> Vec<u8> {
+        let torrent = torrent_for(&test_data(1000), 300);
+        let Bencode::Dict(mut root) = bencode::decode(&torrent.to_bytes()).unwrap() else {
+            unreachable!();
+        };
+        root.insert(b"x-custom".to_vec(), Bencode::Int(7));
+        let Some(Bencode::Dict(info)) = root.get_mut(&b"info"[..]) else {
+            unreachable!();
+        };
+        info.insert(b"source".to_vec(), Bencode::Bytes(b"test".to_vec()));
+        Bencode::Dict(root).to_bytes()
+    }
+
+    #[test]
+    fn edits_keep_the_info_hash() {
+        let bytes = torrent_file();
+        let original = Torrent::from_bytes(&bytes).unwrap();
+        let options = EditOptions {
+            add_trackers: vec!["udp://backup:6969".to_owned()],
+            comment: Some("edited".to_owned()),
+            creation_date: Some(Some(1_700_000_000)),
+            ..EditOptions::default()
+        };
+        let edited = edit(&bytes, &options).unwrap();
+        let torrent = Torrent::from_bytes(&edited).unwrap();
+        assert_eq!(torrent.info_hash(), original.info_hash());
+        assert_eq!(torrent.announce, original.announce);
+        assert_eq!(
+            torrent.announce_list,
+            [
+                vec!["http://127.0.0.1/announce".to_owned()],
+                vec!["udp://backup:6969".to_owned()]
+            ]
+        );
+        assert_eq!(torrent.comment.as_deref(), Some("edited"));
+        assert_eq!(torrent.creation_date, Some(1_700_000_000));
+        assert!(bencode::decode(&edited)
+            .unwrap()
+            .get_path("x-custom")
+            .is_ok());
+
+        // taking the first tracker out moves announce to the one left
+        let options = EditOptions {
+            remove_trackers: vec!["http://127.0.0.1/announce".to_owned()],
+            comment: Some(String::new()),
+            creation_date: Some(None),
+            strip: true,
+            ..EditOptions::default()
+        };
+        let stripped = edit(&edited, &options).unwrap();
+        let torrent = Torrent::from_bytes(&stripped).unwrap();
+        assert_eq!(torrent.info_hash(), original.info_hash());
+        assert_eq!(torrent.announce, "udp://backup:6969");
+        assert!(torrent.announce_list.is_empty());
+        assert_eq!((torrent.comment, torrent.creation_date), (None, None));
+        let root = bencode::decode(&stripped).unwrap();
+        assert!(root.get_path("x-custom").is_err());
+        // the info dictionary isn't stripped
+        assert!(root.get_path("info.source").is_ok());
+
+        let options = EditOptions {
+            remove_trackers: vec!["http://nowhere".to_owned()],
+            ..EditOptions::default()
+        };
+        assert!(edit(&bytes, &options).is_err());
+    }
+
+    #[test]
+    fn private_flag_changes_the_info_hash() {
+        let bytes = torrent_file();
+        let original = Torrent::from_bytes(&bytes).unwrap();
+        let unchanged = EditOptions {
+            private: Some(false),
+            ..EditOptions::default()
+        };
+        assert_eq!(edit(&bytes, &unchanged).unwrap(), bytes);
+
+        let options = EditOptions {
+            private: Some(true),
+            ..EditOptions::default()
+        };
+        let private = edit(&bytes, &options).unwrap();
+        let torrent = Torrent::from_bytes(&private).unwrap();
+        assert!(torrent.is_private());
+        assert_ne!(torrent.info_hash(), original.info_hash());
+        assert!(bencode::decode(&private)
+            .unwrap()
+            .get_path("info.source")
+            .is_ok());
+
+        let public = edit(&private, &unchanged).unwrap();
+        assert_eq!(
+            Torrent::from_bytes(&public).unwrap().info_hash(),
+            original.info_hash()
+        );
+    }
+
+    #[test]
+    fn creation_dates() {
+        assert_eq!(parse_creation_date("").unwrap(), None);
+        assert_eq!(parse_creation_date("1234").unwrap(), Some(1234));
+        assert!(parse_creation_date("now").unwrap().unwrap() > 1_700_000_000);
+        assert!(parse_creation_date("-5").is_err());
+        assert!(parse_creation_date("yesterday").is_err());
+    }
+}
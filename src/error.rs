@@ -0,0 +1,280 @@
+use std::fmt;
+
+/// The result of the library's entry points.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// What failed, so callers can tell failures apart without parsing messages. Each kind carries
+/// the error with everything that was added as context on the way up.
+///
+/// Errors keep their kind when they pass through code that reports `anyhow` errors: use
+/// `Error::find` on those.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Input that isn't valid bencode, or a value that can't be mapped to or from it.
+    Bencode(anyhow::Error),
+    /// A torrent file that can't be read, or it or a magnet link doesn't describe a valid
+    /// torrent.
+    Metainfo(anyhow::Error),
+    /// A tracker that couldn't be reached, or refused or garbled an announce or scrape.
+    Tracker(anyhow::Error),
+    /// A peer that broke the wire protocol or one of its extensions.
+    PeerProtocol(anyhow::Error),
+    /// A peer, proxy or other host that couldn't be connected to or stopped answering.
+    Network(anyhow::Error),
+    /// Reading or writing a torrent's content failed, e.g. because the disk is full.
+    Disk(anyhow::Error),
+    /// A settings file or option that doesn't make sense.
+    Config(anyhow::Error),
+    /// A tracker URL of a scheme other than the `tracker::SUPPORTED_SCHEMES`, e.g. a
+    /// WebTorrent `wss://` one.
+    UnsupportedTrackerScheme {
+        /// In lower case, empty if the URL has none.
+        scheme: String,
+        error: anyhow::Error,
+    },
+    /// A torrent added to a `Session` that has it already.
+    AlreadyAdded {
+        info_hash: [u8; 20],
+        /// The URLs of the trackers `Session::add_or_merge` took over from the torrent.
+        merged_trackers: Vec<String>,
+        error: anyhow::Error,
+    },
+    /// A torrent asked for of a `Session` that doesn't have it.
+    UnknownTorrent {
+        info_hash: [u8; 20],
+        error: anyhow::Error,
+    },
+    /// Anything the other kinds don't cover, e.g. a download that ran out of peers.
+    Other(anyhow::Error),
+}
+
+impl Error {
+    /// The kind of error `error` is or was caused by, the outermost if there are several.
+    pub fn find(error: &anyhow::Error) -> Option<&Error> {
+        error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<Error>())
+    }
+
+    pub(crate) fn unsupported_tracker_scheme(scheme: String) -> Error {
+        let message = format!(
+            "unsupported tracker scheme {:?}, only {} are",
+            scheme,
+            crate::tracker::SUPPORTED_SCHEMES.join(", ")
+        );
+        Error::UnsupportedTrackerScheme {
+            scheme,
+            error: anyhow::Error::msg(message),
+        }
+    }
+
+    pub(crate) fn already_added(info_hash: [u8; 20], merged_trackers: Vec<String>) -> Error {
+        let mut message = format!("torrent {} was already added", hex::encode(info_hash));
+        match merged_trackers.len() {
+            0 => {}
+            1 => message += &format!("; added its tracker {}", merged_trackers[0]),
+            count => message += &format!("; added its {} new trackers", count),
+        }
+        Error::AlreadyAdded {
+            info_hash,
+            merged_trackers,
+            error: anyhow::Error::msg(message),
+        }
+    }
+
+    pub(crate) fn unknown_torrent(info_hash: [u8; 20]) -> Error {
+        let message = format!("no torrent {} in the session", hex::encode(info_hash));
+        Error::UnknownTorrent {
+            info_hash,
+            error: anyhow::Error::msg(message),
+        }
+    }
+
+    /// Adds `context` to what the error says, keeping its kind.
+    pub fn context(self, context: impl fmt::Display + Send + Sync + 'static) -> Error {
+        self.map(|error| error.context(context))
+    }
+
+    /// The error without its kind: what happened and why.
+    pub fn inner(&self) -> &anyhow::Error {
+        match self {
+            Error::Bencode(error)
+            | Error::Metainfo(error)
+            | Error::Tracker(error)
+            | Error::PeerProtocol(error)
+            | Error::Network(error)
+            | Error::Disk(error)
+            | Error::Config(error)
+            | Error::UnsupportedTrackerScheme { error, .. }
+            | Error::AlreadyAdded { error, .. }
+            | Error::UnknownTorrent { error, .. }
+            | Error::Other(error) => error,
+        }
+    }
+
+    /// The error without its kind, to add to or to report on.
+    pub fn into_inner(self) -> anyhow::Error {
+        match self {
+            Error::Bencode(error)
+            | Error::Metainfo(error)
+            | Error::Tracker(error)
+            | Error::PeerProtocol(error)
+            | Error::Network(error)
+            | Error::Disk(error)
+            | Error::Config(error)
+            | Error::UnsupportedTrackerScheme { error, .. }
+            | Error::AlreadyAdded { error, .. }
+            | Error::UnknownTorrent { error, .. }
+            | Error::Other(error) => error,
+        }
+    }
+
+    fn map(self, f: impl FnOnce(anyhow::Error) -> anyhow::Error) -> Error {
+        let kind = self.kind();
+        kind(f(self.into_inner()))
+    }
+
+    /// Makes another error of this kind, with what else this one says.
+    fn kind(&self) -> Box<dyn FnOnce(anyhow::Error) -> Error> {
+        match self {
+            Error::Bencode(_) => Box::new(Error::Bencode),
+            Error::Metainfo(_) => Box::new(Error::Metainfo),
+            Error::Tracker(_) => Box::new(Error::Tracker),
+            Error::PeerProtocol(_) => Box::new(Error::PeerProtocol),
+            Error::Network(_) => Box::new(Error::Network),
+            Error::Disk(_) => Box::new(Error::Disk),
+            Error::Config(_) => Box::new(Error::Config),
+            Error::UnsupportedTrackerScheme { scheme, .. } => {
+                let scheme = scheme.clone();
+                Box::new(move |error| Error::UnsupportedTrackerScheme { scheme, error })
+            }
+            Error::AlreadyAdded {
+                info_hash,
+                merged_trackers,
+                ..
+            } => {
+                let (info_hash, merged_trackers) = (*info_hash, merged_trackers.clone());
+                Box::new(move |error| Error::AlreadyAdded {
+                    info_hash,
+                    merged_trackers,
+                    error,
+                })
+            }
+            &Error::UnknownTorrent { info_hash, .. } => {
+                Box::new(move |error| Error::UnknownTorrent { info_hash, error })
+            }
+            Error::Other(_) => Box::new(Error::Other),
+        }
+    }
+}
+
+/// Says what the inner error does, so a kind adds nothing to the message.
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            write!(f, "{:#}", self.inner())
+        } else {
+            write!(f, "{}", self.inner())
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.inner().source()
+    }
+}
+
+/// Takes the kind of the error the chain holds, along with all of the chain's context.
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Error {
+        // `downcast` would also find one under context, and drop the context
+        if error.chain().next().is_some_and(|top| top.is::<Error>()) {
+            return error.downcast().unwrap();
+        }
+        let kind = match Error::find(&error) {
+            Some(found) => found.kind(),
+            None => Box::new(Error::Other),
+        };
+        kind(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn keeps_its_kind_and_context() {
+        let error = Error::Tracker(anyhow::anyhow!("connection refused")).context("announcing");
+        assert!(matches!(error, Error::Tracker(_)));
+        assert_eq!(format!("{:#}", error), "announcing: connection refused");
+
+        // through code reporting `anyhow` errors and back
+        let wrapped = Err::<(), _>(error).context("downloading").unwrap_err();
+        assert!(matches!(Error::find(&wrapped), Some(Error::Tracker(_))));
+        assert_eq!(
+            format!("{:#}", wrapped),
+            "downloading: announcing: connection refused"
+        );
+        let error = Error::from(wrapped);
+        assert!(matches!(error, Error::Tracker(_)));
+        assert_eq!(
+            format!("{:#}", error),
+            "downloading: announcing: connection refused"
+        );
+
+        let full = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::StorageFull));
+        let error = Error::from(anyhow::Error::new(Error::Disk(full.context("writing"))));
+        assert!(matches!(error, Error::Disk(_)));
+        assert_eq!(error.inner().chain().count(), 2);
+
+        let added = Error::already_added([1; 20], vec!["http://two/announce".to_owned()]);
+        let error = Error::from(anyhow::Error::new(added).context("importing"));
+        assert!(
+            matches!(&error, Error::AlreadyAdded { info_hash, merged_trackers, .. }
+            if *info_hash == [1; 20] && merged_trackers.len() == 1)
+        );
+        assert!(error.to_string().starts_with("importing"));
+
+        let error = Error::from(anyhow::anyhow!("ran out of peers"));
+        assert!(matches!(error, Error::Other(_)));
+        assert_eq!(error.to_string(), "ran out of peers");
+    }
+
+    #[test]
+    fn entry_points_report_their_kind() {
+        use crate::config::Config;
+        use crate::magnet::Magnet;
+        use crate::message::PeerMessage;
+        use crate::torrent::Torrent;
+        use crate::tracker::TrackerResponse;
+
+        assert!(matches!(
+            crate::bencode::decode(b"i1"),
+            Err(Error::Bencode(_))
+        ));
+        assert!(matches!(
+            Torrent::from_bytes(b"de"),
+            Err(Error::Metainfo(_))
+        ));
+        assert!(matches!(
+            Magnet::parse("magnet:?dn=x"),
+            Err(Error::Metainfo(_))
+        ));
+        assert!(matches!(
+            TrackerResponse::from_bytes(b"d14:failure reason4:nopee"),
+            Err(Error::Tracker(_))
+        ));
+        assert!(matches!(
+            PeerMessage::from_payload(&[0, 1]),
+            Err(Error::PeerProtocol(_))
+        ));
+        assert!(matches!(
+            Config::parse("pipeline_depth = 0"),
+            Err(Error::Config(_))
+        ));
+    }
+}
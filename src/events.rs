@@ -0,0 +1,66 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// How often `EventKind::Throughput` samples are taken.
+pub const THROUGHPUT_INTERVAL: Duration = Duration::from_secs(1);
+/// Events a subscriber may fall behind by before it misses some.
+const CAPACITY: usize = 1024;
+
+/// Something that happened to one of the torrents being downloaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentEvent {
+    pub info_hash: [u8; 20],
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    /// A peer completed the handshake.
+    PeerConnected(SocketAddr),
+    PeerDisconnected(SocketAddr),
+    /// A piece was verified and written, or found on disk when resuming.
+    PieceCompleted(usize),
+    /// An announce failed on every tracker.
+    TrackerError(String),
+    /// Bytes per second over the last `THROUGHPUT_INTERVAL`.
+    Throughput {
+        download_rate: usize,
+        upload_rate: usize,
+    },
+    /// Every piece has been written.
+    TorrentFinished,
+    /// The disk filled up, so the torrent was paused until it is resumed.
+    DiskFull,
+}
+
+/// Publishes the events of one torrent to everyone subscribed to the channel.
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    info_hash: [u8; 20],
+    channel: broadcast::Sender<TorrentEvent>,
+}
+
+impl EventSender {
+    pub fn new(info_hash: [u8; 20], channel: broadcast::Sender<TorrentEvent>) -> EventSender {
+        EventSender { info_hash, channel }
+    }
+
+    /// A sender nobody listens to, for downloads run on their own.
+    pub fn unobserved(info_hash: [u8; 20]) -> EventSender {
+        EventSender::new(info_hash, channel())
+    }
+
+    pub fn send(&self, kind: EventKind) {
+        // having no subscribers is fine
+        let _ = self.channel.send(TorrentEvent {
+            info_hash: self.info_hash,
+            kind,
+        });
+    }
+}
+
+/// A channel for the events of any number of torrents.
+pub fn channel() -> broadcast::Sender<TorrentEvent> {
+    broadcast::Sender::new(CAPACITY)
+}
@@ -0,0 +1,617 @@
+use crate::bencode::{self, decode_borrowed_prefix};
+use crate::dht::Dht;
+use crate::magnet::Magnet;
+use crate::message::*;
+use crate::net;
+use crate::peer;
+use crate::torrent::{Info, Torrent};
+use crate::tracker::{Peers, TrackerList, TrackerRequest};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
+
+/// The id we ask peers to use for the ut_metadata messages they send us.
+pub const UT_METADATA_ID: u8 = 1;
+/// The id we ask peers to use for the ut_pex messages they send us.
+pub const UT_PEX_ID: u8 = 2;
+/// The id we ask peers to use for the ut_holepunch messages they send us.
+pub const UT_HOLEPUNCH_ID: u8 = 3;
+/// Peer exchange messages may be sent to a peer at most this often.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Most peers a single PEX message adds or drops, as BEP 11 recommends.
+pub const MAX_PEX_PEERS: usize = 50;
+/// Metadata is exchanged in pieces of this size; only the last piece may be shorter.
+const METADATA_PIECE_SIZE: usize = 16 * 1024;
+/// Refuse to allocate for metadata larger than this, whatever the peer claims.
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+/// The payload of the BEP 10 extension handshake.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ExtensionHandshake {
+    /// Extension names mapped to the message ids the sender wants to receive them under.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    /// Size of the info dictionary in bytes, sent by peers that can serve it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtensionHandshake {
+    pub fn to_message(&self) -> anyhow::Result<PeerMessage> {
+        Ok(PeerMessage::Extended {
+            id: 0,
+            payload: bencode::to_bytes(self)?,
+        })
+    }
+}
+
+/// The bencoded header of a ut_metadata message (BEP 9).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MetadataMessage {
+    /// 0 for a request, 1 for data, 2 for a reject.
+    pub msg_type: u8,
+    pub piece: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<usize>,
+}
+
+impl MetadataMessage {
+    pub const REQUEST: u8 = 0;
+    pub const DATA: u8 = 1;
+    pub const REJECT: u8 = 2;
+
+    /// Splits an extended message payload into its header and the metadata bytes following it.
+    pub fn parse(payload: &[u8]) -> anyhow::Result<(MetadataMessage, &[u8])> {
+        let (_, data) = decode_borrowed_prefix(payload)?;
+        let header = &payload[..payload.len() - data.len()];
+        Ok((bencode::from_bytes(header)?, data))
+    }
+}
+
+/// A ut_pex message (BEP 11): the peers that joined and left the sender's swarm since its last
+/// message, the IPv4 and the IPv6 ones apart. The IPv6 keys are left out while empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PexMessage {
+    /// Compact addresses of the IPv4 peers that joined.
+    #[serde(default, with = "serde_bytes")]
+    pub added: Vec<u8>,
+    /// A flags byte per added peer, e.g. whether it prefers encryption; ignored by us.
+    #[serde(rename = "added.f", default, with = "serde_bytes")]
+    pub added_flags: Vec<u8>,
+    /// Compact addresses of the IPv6 peers that joined.
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    pub added6: Vec<u8>,
+    #[serde(
+        rename = "added6.f",
+        default,
+        with = "serde_bytes",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub added6_flags: Vec<u8>,
+    /// Compact addresses of the IPv4 peers that left.
+    #[serde(default, with = "serde_bytes")]
+    pub dropped: Vec<u8>,
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    pub dropped6: Vec<u8>,
+}
+
+impl PexMessage {
+    pub fn new(added: &[SocketAddr], dropped: &[SocketAddr]) -> PexMessage {
+        let (added, added6) = Peers::to_compact(added);
+        let (dropped, dropped6) = Peers::to_compact(dropped);
+        PexMessage {
+            added_flags: vec![0; added.len() / 6],
+            added,
+            added6_flags: vec![0; added6.len() / 18],
+            added6,
+            dropped,
+            dropped6,
+        }
+    }
+
+    pub fn added_peers(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        let mut added = Peers::from_compact(&self.added)?.addrs;
+        added.extend(Peers::from_compact6(&self.added6)?.addrs);
+        Ok(added)
+    }
+
+    pub fn to_message(&self, their_id: u8) -> anyhow::Result<PeerMessage> {
+        Ok(PeerMessage::Extended {
+            id: their_id,
+            payload: bencode::to_bytes(self)?,
+        })
+    }
+}
+
+/// A ut_holepunch message (BEP 55), with which a peer connected to two others that can't
+/// connect to each other, being behind NATs, gets them to connect at the same time, so that
+/// each opens its NAT to the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchMessage {
+    /// Asks the peer relaying to have us and the peer at the address connect.
+    Rendezvous(SocketAddr),
+    /// Tells us to connect to the peer at the address now, as it does to us.
+    Connect(SocketAddr),
+    /// Tells us the rendezvous with the peer at the address failed, and why.
+    Error(SocketAddr, u32),
+}
+
+impl HolepunchMessage {
+    pub const RENDEZVOUS: u8 = 0;
+    pub const CONNECT: u8 = 1;
+    pub const ERROR: u8 = 2;
+
+    /// The peer to connect to isn't a valid one.
+    pub const NO_SUCH_PEER: u32 = 1;
+    /// The relaying peer isn't connected to the peer to connect to.
+    pub const NOT_CONNECTED: u32 = 2;
+    /// The peer to connect to doesn't support hole punching.
+    pub const NO_SUPPORT: u32 = 3;
+    /// The peer to connect to is the one asking.
+    pub const NO_SELF: u32 = 4;
+
+    /// Reads a message from its binary form: its type, whether it is about an IPv4 or an IPv6
+    /// peer, the peer's address and port, and an error code that is 0 unless it is an error.
+    pub fn parse(payload: &[u8]) -> anyhow::Result<HolepunchMessage> {
+        let &[kind, family, ref rest @ ..] = payload else {
+            anyhow::bail!("holepunch message is {} bytes long", payload.len());
+        };
+        let ip_length = match family {
+            0 => 4,
+            1 => 16,
+            _ => anyhow::bail!("holepunch message for address family {}", family),
+        };
+        if rest.len() != ip_length + 6 {
+            anyhow::bail!("holepunch message is {} bytes long", payload.len());
+        }
+        let (addr, code) = rest.split_at(ip_length + 2);
+        let addr = net::parse_compact_addr(addr).unwrap();
+        let code = u32::from_be_bytes(code.try_into().unwrap());
+        Ok(match kind {
+            HolepunchMessage::RENDEZVOUS => HolepunchMessage::Rendezvous(addr),
+            HolepunchMessage::CONNECT => HolepunchMessage::Connect(addr),
+            HolepunchMessage::ERROR => HolepunchMessage::Error(addr, code),
+            _ => anyhow::bail!("unknown holepunch message type {}", kind),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (kind, addr, code) = match *self {
+            HolepunchMessage::Rendezvous(addr) => (HolepunchMessage::RENDEZVOUS, addr, 0),
+            HolepunchMessage::Connect(addr) => (HolepunchMessage::CONNECT, addr, 0),
+            HolepunchMessage::Error(addr, code) => (HolepunchMessage::ERROR, addr, code),
+        };
+        let mut bytes = vec![kind, u8::from(addr.is_ipv6())];
+        bytes.extend_from_slice(&net::compact_addr(addr));
+        bytes.extend_from_slice(&code.to_be_bytes());
+        bytes
+    }
+
+    pub fn to_message(&self, their_id: u8) -> PeerMessage {
+        PeerMessage::Extended {
+            id: their_id,
+            payload: self.to_bytes(),
+        }
+    }
+}
+
+/// What we have told one peer about our swarm, so that every PEX message after the first
+/// only carries the changes.
+#[derive(Debug, Default)]
+pub struct PexState {
+    sent: HashSet<SocketAddr>,
+    last_sent: Option<tokio::time::Instant>,
+}
+
+impl PexState {
+    /// The message telling the peer how `swarm` changed, if one is due: the first right
+    /// away, later ones at most every `PEX_INTERVAL` and only if something changed.
+    pub fn update(&mut self, swarm: &HashSet<SocketAddr>) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|last_sent| last_sent.elapsed() < PEX_INTERVAL)
+        {
+            return None;
+        }
+        let added: Vec<SocketAddr> = swarm
+            .difference(&self.sent)
+            .copied()
+            .take(MAX_PEX_PEERS)
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .difference(swarm)
+            .copied()
+            .take(MAX_PEX_PEERS)
+            .collect();
+        if self.last_sent.is_some() && added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        self.sent.extend(&added);
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        self.last_sent = Some(tokio::time::Instant::now());
+        Some(PexMessage::new(&added, &dropped))
+    }
+}
+
+/// Fetches and verifies the info dictionary from a peer that completed the handshake, and
+/// returns it both decoded and as the bytes it was sent as. `fast` says whether the peer
+/// advertised the Fast Extension, which has us tell it first that we have no pieces.
+pub async fn fetch_metadata<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    info_hash: [u8; 20],
+    fast: bool,
+) -> anyhow::Result<(Info, Vec<u8>)> {
+    let mut framed = Framed::new(stream, MessageCodec);
+    if fast {
+        framed.send(PeerMessage::HaveNone).await?;
+    }
+    let ours = ExtensionHandshake {
+        m: BTreeMap::from([("ut_metadata".to_owned(), UT_METADATA_ID)]),
+        metadata_size: None,
+    };
+    framed.send(ours.to_message()?).await?;
+
+    let theirs = loop {
+        match receive(&mut framed).await? {
+            PeerMessage::Extended { id: 0, payload } => {
+                break bencode::from_bytes::<ExtensionHandshake>(&payload)?;
+            }
+            _ => continue,
+        }
+    };
+    let their_id = match theirs.m.get("ut_metadata") {
+        Some(&id) if id != 0 => id,
+        _ => anyhow::bail!("peer doesn't support ut_metadata"),
+    };
+    let size = match theirs.metadata_size {
+        Some(size) if size > 0 && size <= MAX_METADATA_SIZE => size,
+        Some(size) => anyhow::bail!("peer announced an unreasonable metadata size {}", size),
+        None => anyhow::bail!("peer didn't announce the metadata size"),
+    };
+
+    let piece_count = size.div_ceil(METADATA_PIECE_SIZE);
+    for piece in 0..piece_count {
+        let request = MetadataMessage {
+            msg_type: MetadataMessage::REQUEST,
+            piece,
+            total_size: None,
+        };
+        framed
+            .feed(PeerMessage::Extended {
+                id: their_id,
+                payload: bencode::to_bytes(&request)?,
+            })
+            .await?;
+    }
+    framed.flush().await?;
+
+    let mut metadata = vec![0; size];
+    let mut received = vec![false; piece_count];
+    let mut remaining = piece_count;
+    while remaining > 0 {
+        let PeerMessage::Extended {
+            id: UT_METADATA_ID,
+            payload,
+        } = receive(&mut framed).await?
+        else {
+            continue;
+        };
+        let (header, data) = MetadataMessage::parse(&payload)?;
+        match header.msg_type {
+            MetadataMessage::DATA => {
+                if header.piece >= piece_count {
+                    anyhow::bail!("peer sent metadata piece {} out of range", header.piece);
+                }
+                let start = header.piece * METADATA_PIECE_SIZE;
+                let length = METADATA_PIECE_SIZE.min(size - start);
+                if data.len() != length {
+                    anyhow::bail!(
+                        "peer sent {} bytes for {}-byte metadata piece {}",
+                        data.len(),
+                        length,
+                        header.piece
+                    );
+                }
+                if !received[header.piece] {
+                    metadata[start..start + length].copy_from_slice(data);
+                    received[header.piece] = true;
+                    remaining -= 1;
+                }
+            }
+            MetadataMessage::REJECT => {
+                anyhow::bail!(
+                    "peer rejected the request for metadata piece {}",
+                    header.piece
+                )
+            }
+            _ => {}
+        }
+    }
+
+    let hash: [u8; 20] = Sha1::digest(&metadata).into();
+    if hash != info_hash {
+        anyhow::bail!("metadata doesn't match the info hash");
+    }
+    Ok((bencode::from_bytes(&metadata)?, metadata))
+}
+
+async fn receive<S: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<S, MessageCodec>,
+) -> anyhow::Result<PeerMessage> {
+    match framed.next().await {
+        Some(message) => message,
+        None => anyhow::bail!("peer closed the connection"),
+    }
+}
+
+/// Tries `peers` in order until one of them serves metadata matching `info_hash`.
+pub async fn metadata_from_peers(
+    peers: &[SocketAddr],
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+) -> anyhow::Result<(Info, Vec<u8>)> {
+    let mut last_error = anyhow::anyhow!("found no peers to fetch the metadata from");
+    for &addr in peers {
+        let attempt = async {
+            let (stream, reply) = peer::connect(addr, info_hash, peer_id).await?;
+            if !reply.supports_extensions() {
+                anyhow::bail!("peer doesn't support the extension protocol");
+            }
+            fetch_metadata(stream, info_hash, reply.supports_fast()).await
+        };
+        match attempt.await {
+            Ok(metadata) => return Ok(metadata),
+            Err(e) => last_error = e.context(format!("peer {}", addr)),
+        }
+    }
+    Err(last_error)
+}
+
+/// Asks the magnet's trackers for peers and fetches the torrent's metadata from them. If
+/// the link has no trackers or none of them answers, the peers are looked up in `dht`.
+///
+/// Returns the torrent together with the peers, so a download can reuse them.
+pub async fn resolve_magnet(
+    magnet: &Magnet,
+    peer_id: [u8; 20],
+    port: u16,
+    dht: Option<&Dht>,
+) -> anyhow::Result<(Torrent, Vec<SocketAddr>)> {
+    if magnet.trackers.is_empty() && dht.is_none() {
+        anyhow::bail!("magnet link has no trackers");
+    }
+    // the trackers of a magnet link are all equally good, so they make up a single tier
+    let mut trackers = TrackerList::new(vec![magnet.trackers.clone()]);
+    // the length is unknown until we have the metadata, but trackers expect a non-zero value
+    let request = TrackerRequest::with_left(1, peer_id, port);
+    let peers = match (trackers.announce(&magnet.info_hash, &request).await, dht) {
+        (Ok(response), _) => response.peers.addrs,
+        (Err(_), Some(dht)) => dht.get_peers(magnet.info_hash).await,
+        (Err(e), None) => return Err(e.into()),
+    };
+    let (info, raw_info) = metadata_from_peers(&peers, magnet.info_hash, peer_id).await?;
+    let torrent = Torrent {
+        announce: trackers
+            .tiers
+            .first()
+            .and_then(|tier| tier.first())
+            .cloned()
+            .unwrap_or_default(),
+        announce_list: trackers.tiers,
+        url_list: Vec::new(),
+        httpseeds: Vec::new(),
+        // v2-only torrents need their piece layers from peers (BEP 52 hash requests), which
+        // aren't supported, so magnet links only work for v1 and hybrid torrents
+        piece_layers: BTreeMap::new(),
+        comment: None,
+        created_by: None,
+        creation_date: None,
+        encoding: None,
+        info,
+        raw_info: Some(raw_info),
+        renamed: BTreeMap::new(),
+    };
+    torrent.validate_name()?;
+    Ok((torrent, peers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{test_data, torrent_for};
+    use tokio::io::DuplexStream;
+
+    /// Serves `metadata` over ut_metadata, rejecting requests for pieces in `reject`.
+    fn spawn_metadata_peer(metadata: Vec<u8>, reject: Option<usize>) -> DuplexStream {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let mut framed = Framed::new(theirs, MessageCodec);
+            let handshake = ExtensionHandshake {
+                m: BTreeMap::from([("ut_metadata".to_owned(), 3)]),
+                metadata_size: Some(metadata.len()),
+            };
+            framed.send(handshake.to_message().unwrap()).await.unwrap();
+            while let Some(Ok(message)) = framed.next().await {
+                let PeerMessage::Extended { id: 3, payload } = message else {
+                    continue;
+                };
+                let (request, _) = MetadataMessage::parse(&payload).unwrap();
+                let start = request.piece * METADATA_PIECE_SIZE;
+                let data = &metadata[start..metadata.len().min(start + METADATA_PIECE_SIZE)];
+                let header = MetadataMessage {
+                    msg_type: if reject == Some(request.piece) {
+                        MetadataMessage::REJECT
+                    } else {
+                        MetadataMessage::DATA
+                    },
+                    piece: request.piece,
+                    total_size: Some(metadata.len()),
+                };
+                let mut payload = bencode::to_bytes(&header).unwrap();
+                payload.extend_from_slice(data);
+                let reply = PeerMessage::Extended {
+                    id: UT_METADATA_ID,
+                    payload,
+                };
+                if framed.send(reply).await.is_err() {
+                    break;
+                }
+            }
+        });
+        ours
+    }
+
+    /// A torrent whose info dictionary spans more than one metadata piece.
+    fn large_torrent() -> (Torrent, Vec<u8>) {
+        let torrent = torrent_for(&test_data(1_000_000), 1000);
+        let metadata = bencode::to_bytes(&torrent.info).unwrap();
+        assert!(metadata.len() > METADATA_PIECE_SIZE);
+        (torrent, metadata)
+    }
+
+    #[test]
+    fn extension_handshake_bytes() {
+        let handshake = ExtensionHandshake {
+            m: BTreeMap::from([("ut_metadata".to_owned(), 1)]),
+            metadata_size: None,
+        };
+        assert_eq!(
+            handshake.to_message().unwrap(),
+            PeerMessage::Extended {
+                id: 0,
+                payload: b"d1:md11:ut_metadatai1eee".to_vec()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_metadata_data_message() {
+        let (header, data) =
+            MetadataMessage::parse(b"d8:msg_typei1e5:piecei0e10:total_sizei3eeabc").unwrap();
+        assert_eq!(
+            header,
+            MetadataMessage {
+                msg_type: MetadataMessage::DATA,
+                piece: 0,
+                total_size: Some(3)
+            }
+        );
+        assert_eq!(data, b"abc");
+    }
+
+    #[test]
+    fn pex_message_bytes() {
+        let added: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let message = PexMessage::new(&[added], &[]);
+        let payload = bencode::to_bytes(&message).unwrap();
+        assert_eq!(
+            payload,
+            b"d5:added6:\x7f\x00\x00\x01\x1a\xe17:added.f1:\x007:dropped0:e"
+        );
+        let parsed: PexMessage = bencode::from_bytes(&payload).unwrap();
+        assert_eq!(parsed.added_peers().unwrap(), vec![added]);
+        // clients leave out the keys they have nothing for
+        let parsed: PexMessage = bencode::from_bytes(b"de").unwrap();
+        assert!(parsed.added_peers().unwrap().is_empty());
+
+        let added6: SocketAddr = "[::1]:6881".parse().unwrap();
+        let message = PexMessage::new(&[added, added6], &[added6]);
+        let payload = bencode::to_bytes(&message).unwrap();
+        let mut expected = b"d5:added6:\x7f\x00\x00\x01\x1a\xe17:added.f1:\x006:added618:".to_vec();
+        let v6 = [&[0; 15][..], &[1, 0x1a, 0xe1]].concat();
+        expected.extend_from_slice(&v6);
+        expected.extend_from_slice(b"8:added6.f1:\x007:dropped0:8:dropped618:");
+        expected.extend_from_slice(&v6);
+        expected.push(b'e');
+        assert_eq!(payload, expected);
+        let parsed: PexMessage = bencode::from_bytes(&payload).unwrap();
+        assert_eq!(parsed.added_peers().unwrap(), vec![added, added6]);
+    }
+
+    #[test]
+    fn pex_state_sends_changes() {
+        let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:2".parse().unwrap();
+        let mut state = PexState::default();
+        let first = state.update(&HashSet::from([a])).unwrap();
+        assert_eq!(first.added_peers().unwrap(), vec![a]);
+        // too soon for another message
+        assert_eq!(state.update(&HashSet::from([b])), None);
+
+        let due = || Some(tokio::time::Instant::now() - PEX_INTERVAL);
+        state.last_sent = due();
+        let second = state.update(&HashSet::from([b])).unwrap();
+        assert_eq!(second, PexMessage::new(&[b], &[a]));
+        state.last_sent = due();
+        assert_eq!(state.update(&HashSet::from([b])), None);
+    }
+
+    #[test]
+    fn holepunch_messages() {
+        let v4: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let rendezvous = HolepunchMessage::Rendezvous(v4);
+        assert_eq!(
+            rendezvous.to_bytes(),
+            b"\x00\x00\x0a\x00\x00\x01\x1a\xe1\x00\x00\x00\x00"
+        );
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let error = HolepunchMessage::Error(v6, HolepunchMessage::NOT_CONNECTED);
+        let bytes = error.to_bytes();
+        assert_eq!(bytes.len(), 24);
+        assert_eq!(bytes[..2], [2, 1]);
+        assert_eq!(bytes[20..], [0, 0, 0, 2]);
+        for message in [rendezvous, HolepunchMessage::Connect(v6), error] {
+            assert_eq!(
+                HolepunchMessage::parse(&message.to_bytes()).unwrap(),
+                message
+            );
+        }
+
+        assert!(HolepunchMessage::parse(&[]).is_err());
+        assert!(HolepunchMessage::parse(&bytes[..23]).is_err());
+        let mut unknown = rendezvous.to_bytes();
+        unknown[0] = 3;
+        assert!(HolepunchMessage::parse(&unknown).is_err());
+        unknown[0] = 0;
+        unknown[1] = 2;
+        assert!(HolepunchMessage::parse(&unknown).is_err());
+    }
+
+    #[tokio::test]
+    async fn fetch_metadata_in_several_pieces() {
+        let (torrent, metadata) = large_torrent();
+        let stream = spawn_metadata_peer(metadata.clone(), None);
+        let (info, raw_info) = fetch_metadata(stream, torrent.info_hash(), true)
+            .await
+            .unwrap();
+        assert_eq!(raw_info, metadata);
+        assert_eq!(info.pieces, torrent.info.pieces);
+        assert_eq!(info.piece_length, 1000);
+    }
+
+    #[tokio::test]
+    #[should_panic]
+    async fn fetch_metadata_rejected() {
+        let (torrent, metadata) = large_torrent();
+        let stream = spawn_metadata_peer(metadata, Some(1));
+        fetch_metadata(stream, torrent.info_hash(), false)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[should_panic]
+    async fn fetch_metadata_hash_mismatch() {
+        let (_, metadata) = large_torrent();
+        let stream = spawn_metadata_peer(metadata, None);
+        fetch_metadata(stream, [0; 20], false).await.unwrap();
+    }
+}
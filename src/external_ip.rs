@@ -0,0 +1,131 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// How many voters' claims are kept; a new voter pushes out the one heard from longest ago.
+const MAX_VOTERS: usize = 64;
+
+/// Who told us what our address is.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Voter {
+    /// A tracker, by announce URL, through the `external ip` of its responses.
+    Tracker(String),
+    /// A DHT node, by its IP address so that nodes sharing one get one vote, through the `ip`
+    /// of its replies (BEP 42).
+    Node(IpAddr),
+}
+
+/// The address the rest of the internet sees us at, as far as the trackers and DHT nodes we
+/// talk to agree on it. Behind a NAT that is the router's, where incoming connections have to
+/// be forwarded from.
+///
+/// Every voter has one claim, the latest it made; the address claimed by the most of them
+/// wins, separately for IPv4 and IPv6. Clones share the tally.
+#[derive(Debug, Clone, Default)]
+pub struct ExternalIp {
+    /// The claims, oldest first.
+    votes: Arc<Mutex<VecDeque<(Voter, IpAddr)>>>,
+}
+
+impl ExternalIp {
+    pub fn new() -> ExternalIp {
+        ExternalIp::default()
+    }
+
+    /// Counts `voter` as saying we are at `ip`, in place of whatever it said before.
+    pub fn record(&self, voter: Voter, ip: IpAddr) {
+        let ip = ip.to_canonical();
+        if ip.is_unspecified() {
+            return;
+        }
+        let mut votes = self.votes.lock().unwrap();
+        votes.retain(|(other, _)| *other != voter);
+        votes.push_back((voter, ip));
+        if votes.len() > MAX_VOTERS {
+            votes.pop_front();
+        }
+    }
+
+    /// The address most voters agree on for each family we were told one of, IPv4 first.
+    /// A tie goes to the address claimed last.
+    pub fn ips(&self) -> Vec<IpAddr> {
+        let votes = self.votes.lock().unwrap();
+        // per address, how many claim it and when it was last claimed
+        let mut tally: HashMap<IpAddr, (usize, usize)> = HashMap::new();
+        for (i, (_, ip)) in votes.iter().enumerate() {
+            let (count, last) = tally.entry(*ip).or_default();
+            *count += 1;
+            *last = i;
+        }
+        let winner = |ipv4: bool| {
+            tally
+                .iter()
+                .filter(|(ip, _)| ip.is_ipv4() == ipv4)
+                .max_by_key(|(_, &counted)| counted)
+                .map(|(&ip, _)| ip)
+        };
+        winner(true).into_iter().chain(winner(false)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(url: &str) -> Voter {
+        Voter::Tracker(url.to_owned())
+    }
+
+    #[test]
+    fn the_majority_wins_in_each_family() {
+        let external = ExternalIp::new();
+        assert!(external.ips().is_empty());
+        let (a, b) = (
+            "203.0.113.7".parse().unwrap(),
+            "198.51.100.1".parse().unwrap(),
+        );
+        external.record(tracker("http://one/announce"), a);
+        external.record(Voter::Node("192.0.2.1".parse().unwrap()), b);
+        external.record(Voter::Node("192.0.2.2".parse().unwrap()), b);
+        external.record(
+            Voter::Node("192.0.2.3".parse().unwrap()),
+            "::".parse().unwrap(),
+        );
+        external.record(
+            Voter::Node("2001:db8::2".parse().unwrap()),
+            "2001:db8::1".parse().unwrap(),
+        );
+        assert_eq!(external.ips(), vec![b, "2001:db8::1".parse().unwrap()]);
+
+        // a voter changing its mind takes back its old claim; the tie goes to the latest
+        external.record(Voter::Node("192.0.2.2".parse().unwrap()), a);
+        assert_eq!(external.clone().ips()[0], a);
+        external.record(tracker("http://one/announce"), b);
+        assert_eq!(external.ips()[0], b);
+    }
+
+    #[test]
+    fn keeps_the_latest_voters() {
+        let external = ExternalIp::new();
+        let (a, b) = (
+            "203.0.113.7".parse().unwrap(),
+            "::ffff:198.51.100.1".parse().unwrap(),
+        );
+        for i in 0..MAX_VOTERS {
+            external.record(tracker(&format!("http://{}/announce", i)), a);
+        }
+        for ip in ["192.0.2.1", "192.0.2.2"] {
+            external.record(Voter::Node(ip.parse().unwrap()), b);
+        }
+        assert_eq!(external.votes.lock().unwrap().len(), MAX_VOTERS);
+        assert_eq!(external.ips(), vec![a]);
+        // IPv4-mapped addresses count as the IPv4 ones they are
+        for i in 0..MAX_VOTERS {
+            external.record(Voter::Node([10, 0, 0, i as u8].into()), b);
+        }
+        assert_eq!(
+            external.ips(),
+            vec!["198.51.100.1".parse::<IpAddr>().unwrap()]
+        );
+    }
+}
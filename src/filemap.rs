@@ -0,0 +1,284 @@
+use crate::torrent::{FileSpan, Torrent};
+use std::ops::Range;
+
+/// The part of one file that a range of a torrent's content covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    /// The file's index in `Torrent::files`.
+    pub file: usize,
+    /// Where the slice starts in the file.
+    pub offset: usize,
+    /// Where the slice starts in the range it is part of.
+    pub start: usize,
+    pub length: usize,
+}
+
+impl FileSlice {
+    /// The slice's bytes within the range it is part of.
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.start + self.length
+    }
+}
+
+/// Where the ranges of a torrent's content lie in its files.
+///
+/// The content is the files laid back to back, so a piece or block may cover the end of one
+/// file, any number of files in between and the start of the next. Padding files and the gaps
+/// that align the files of a v2 torrent to pieces are in no file, so the slices skip them, and
+/// empty files never have a slice.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMap {
+    spans: Vec<FileSpan>,
+    piece_length: usize,
+    total_length: usize,
+}
+
+impl FileMap {
+    pub fn new(torrent: &Torrent) -> anyhow::Result<FileMap> {
+        Ok(FileMap::from_spans(
+            torrent.files()?,
+            torrent.info.piece_length,
+            torrent.total_length(),
+        ))
+    }
+
+    /// The map of content `total_length` bytes long, in pieces of `piece_length`, that is laid
+    /// out in files at `spans`, in the order of their offsets.
+    pub fn from_spans(spans: Vec<FileSpan>, piece_length: usize, total_length: usize) -> FileMap {
+        debug_assert!(spans
+            .windows(2)
+            .all(|pair| pair[0].offset <= pair[1].offset));
+        FileMap {
+            spans,
+            piece_length,
+            total_length,
+        }
+    }
+
+    pub fn spans(&self) -> &[FileSpan] {
+        &self.spans
+    }
+
+    /// The slices of files that the `length` bytes starting at `offset` into the torrent's
+    /// content cover, in order.
+    pub fn slices(&self, offset: usize, length: usize) -> anyhow::Result<Vec<FileSlice>> {
+        let end = offset
+            .checked_add(length)
+            .filter(|&end| end <= self.total_length)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "{} bytes at {} are past the end of the torrent at {}",
+                    length,
+                    offset,
+                    self.total_length
+                )
+            })?;
+        // the first file that ends past the start of the range
+        let first = self
+            .spans
+            .partition_point(|span| span.offset + span.length <= offset);
+        let slices = self.spans[first..]
+            .iter()
+            .enumerate()
+            .take_while(|(_, span)| span.offset < end)
+            .filter_map(|(index, span)| {
+                let from = offset.max(span.offset);
+                let to = end.min(span.offset + span.length);
+                (from < to).then(|| FileSlice {
+                    file: first + index,
+                    offset: from - span.offset,
+                    start: from - offset,
+                    length: to - from,
+                })
+            })
+            .collect();
+        Ok(slices)
+    }
+
+    /// Like `slices`, for the `length` bytes starting at `offset` into the piece at `index`.
+    pub fn piece_slices(
+        &self,
+        index: usize,
+        offset: usize,
+        length: usize,
+    ) -> anyhow::Result<Vec<FileSlice>> {
+        let start = index * self.piece_length;
+        let piece_size = self
+            .total_length
+            .saturating_sub(start)
+            .min(self.piece_length);
+        if offset
+            .checked_add(length)
+            .is_none_or(|end| end > piece_size)
+        {
+            anyhow::bail!(
+                "{} bytes at {} are past the end of piece {}, {} bytes long",
+                length,
+                offset,
+                index,
+                piece_size
+            );
+        }
+        self.slices(start + offset, length)
+    }
+
+    /// The pieces that hold some of the file at `file`; none for an empty file.
+    pub fn pieces_of(&self, file: usize) -> Range<usize> {
+        let span = &self.spans[file];
+        if span.length == 0 {
+            return 0..0;
+        }
+        span.offset / self.piece_length..(span.offset + span.length).div_ceil(self.piece_length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{multi_file_torrent_for, test_data};
+    use std::path::PathBuf;
+
+    fn span(path: &str, offset: usize, length: usize) -> FileSpan {
+        FileSpan {
+            path: PathBuf::from(path),
+            offset,
+            length,
+        }
+    }
+
+    fn slice(file: usize, offset: usize, start: usize, length: usize) -> FileSlice {
+        FileSlice {
+            file,
+            offset,
+            start,
+            length,
+        }
+    }
+
+    #[test]
+    fn pieces_across_file_boundaries() {
+        let data = test_data(1000);
+        let torrent = multi_file_torrent_for(
+            &data,
+            300,
+            &[("a", 250), ("b", 0), ("c", 100), ("d", 50), ("e", 600)],
+        );
+        let map = FileMap::new(&torrent).unwrap();
+
+        // within a single file
+        assert_eq!(map.piece_slices(0, 0, 250).unwrap(), [slice(0, 0, 0, 250)]);
+        assert_eq!(map.piece_slices(0, 10, 20).unwrap(), [slice(0, 10, 0, 20)]);
+        // the end of one file and the start of the next, skipping the empty one between
+        assert_eq!(
+            map.piece_slices(0, 0, 300).unwrap(),
+            [slice(0, 0, 0, 250), slice(2, 0, 250, 50)]
+        );
+        // starting right at a file boundary
+        assert_eq!(map.piece_slices(0, 250, 50).unwrap(), [slice(2, 0, 0, 50)]);
+        // over a whole small file in the middle
+        assert_eq!(
+            map.piece_slices(1, 0, 300).unwrap(),
+            [
+                slice(2, 50, 0, 50),
+                slice(3, 0, 50, 50),
+                slice(4, 0, 100, 200)
+            ]
+        );
+        // the short last piece
+        assert_eq!(
+            map.piece_slices(3, 0, 100).unwrap(),
+            [slice(4, 500, 0, 100)]
+        );
+        assert_eq!(map.piece_slices(3, 100, 0).unwrap(), []);
+
+        assert!(map.piece_slices(3, 0, 101).is_err());
+        assert!(map.piece_slices(0, 200, 101).is_err());
+        assert!(map.piece_slices(4, 0, 1).is_err());
+        assert!(map.piece_slices(0, usize::MAX, 2).is_err());
+    }
+
+    #[test]
+    fn piece_over_four_files() {
+        let data = test_data(40);
+        let torrent = multi_file_torrent_for(
+            &data,
+            16,
+            &[("a", 3), ("b", 5), ("c", 2), ("d", 10), ("e", 20)],
+        );
+        let map = FileMap::new(&torrent).unwrap();
+        assert_eq!(
+            map.piece_slices(0, 0, 16).unwrap(),
+            [
+                slice(0, 0, 0, 3),
+                slice(1, 0, 3, 5),
+                slice(2, 0, 8, 2),
+                slice(3, 0, 10, 6)
+            ]
+        );
+        // a block out of the middle of the piece, from inside the first file to the third
+        assert_eq!(
+            map.piece_slices(0, 1, 8).unwrap(),
+            [slice(0, 1, 0, 2), slice(1, 0, 2, 5), slice(2, 0, 7, 1)]
+        );
+        assert_eq!(map.pieces_of(3), 0..2);
+    }
+
+    #[test]
+    fn slices_cover_every_byte_once() {
+        let data = test_data(1000);
+        let torrent = multi_file_torrent_for(
+            &data,
+            64,
+            &[("a", 1), ("b", 63), ("c", 0), ("d", 65), ("e", 871)],
+        );
+        let map = FileMap::new(&torrent).unwrap();
+        for offset in 0..1000 {
+            for length in [0, 1, 2, 63, 64, 65, 200, 1000 - offset] {
+                let Ok(slices) = map.slices(offset, length) else {
+                    assert!(offset + length > 1000);
+                    continue;
+                };
+                // the slices read the file contents back as the range of the whole content
+                let mut read = Vec::new();
+                for slice in &slices {
+                    assert_eq!(slice.start, read.len());
+                    let span = &map.spans()[slice.file];
+                    let file = &data[span.offset..span.offset + span.length];
+                    read.extend_from_slice(&file[slice.offset..slice.offset + slice.length]);
+                }
+                assert_eq!(read, &data[offset..offset + length]);
+            }
+        }
+    }
+
+    #[test]
+    fn gaps_between_files() {
+        // as a v2 torrent pads its files to whole pieces
+        let map = FileMap::from_spans(
+            vec![span("a", 0, 100), span("b", 256, 300), span("c", 768, 10)],
+            256,
+            778,
+        );
+        assert_eq!(
+            map.slices(50, 300).unwrap(),
+            [slice(0, 50, 0, 50), slice(1, 0, 206, 94)]
+        );
+        assert_eq!(map.piece_slices(0, 100, 156).unwrap(), []);
+        assert_eq!(map.piece_slices(3, 0, 10).unwrap(), [slice(2, 0, 0, 10)]);
+        assert_eq!(map.pieces_of(0), 0..1);
+        assert_eq!(map.pieces_of(1), 1..3);
+        assert_eq!(map.pieces_of(2), 3..4);
+    }
+
+    #[test]
+    fn pieces_of_files() {
+        let data = test_data(1000);
+        let torrent =
+            multi_file_torrent_for(&data, 300, &[("a", 300), ("b", 0), ("c", 301), ("d", 399)]);
+        let map = FileMap::new(&torrent).unwrap();
+        assert_eq!(map.pieces_of(0), 0..1);
+        assert_eq!(map.pieces_of(1), 0..0);
+        assert_eq!(map.pieces_of(2), 1..3);
+        assert_eq!(map.pieces_of(3), 2..4);
+    }
+}
@@ -0,0 +1,202 @@
+/// The client and version a peer id names, for ids in Azureus style (`-qB4250-…`) or Shadow
+/// style (`M4-3-6--…`, `T03I-----…`).
+pub fn client_name(peer_id: &[u8; 20]) -> Option<String> {
+    if peer_id[0] == b'-' && peer_id[7] == b'-' {
+        return azureus(peer_id);
+    }
+    shadow(peer_id)
+}
+
+/// `-` and two letters for the client, four characters of version and another `-`. The first
+/// three characters are version numbers, in base 36 so that `D` is 13; the last is one more, or
+/// a letter that tags the build, such as Transmission's `Z` for a beta or µTorrent's `S` for
+/// a stable release.
+fn azureus(peer_id: &[u8; 20]) -> Option<String> {
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    let version = &peer_id[3..7];
+    if !code.bytes().all(|c| c.is_ascii_alphabetic())
+        || !version.iter().all(|c| c.is_ascii_alphanumeric())
+    {
+        return None;
+    }
+    let name = azureus_client(code).unwrap_or(code);
+    let last = version[3].is_ascii_digit().then_some(version[3]);
+    let mut parts: Vec<u32> = version[..3]
+        .iter()
+        .chain(last.as_ref())
+        .map(|&c| (c as char).to_digit(36).unwrap())
+        .collect();
+    // trailing zeros are the unused parts of the version, though there's always a minor one
+    while parts.len() > 2 && parts.last() == Some(&0) {
+        parts.pop();
+    }
+    let version: Vec<String> = parts.iter().map(ToString::to_string).collect();
+    Some(format!("{} {}", name, version.join(".")))
+}
+
+fn shadow(peer_id: &[u8; 20]) -> Option<String> {
+    let name = shadow_client(peer_id[0])?;
+    if peer_id[0] == b'M' {
+        // Mainline writes its version as digits between dashes, e.g. M4-3-6--
+        let rest = std::str::from_utf8(&peer_id[1..8]).ok()?;
+        let parts: Vec<&str> = rest.split('-').filter(|part| !part.is_empty()).collect();
+        if parts.is_empty()
+            || !parts
+                .iter()
+                .all(|part| part.bytes().all(|c| c.is_ascii_digit()))
+        {
+            return None;
+        }
+        return Some(format!("{} {}", name, parts.join(".")));
+    }
+    // the version is up to five characters, padded with dashes, and then come three more
+    if &peer_id[6..9] != b"---" {
+        return None;
+    }
+    let version: Vec<String> = peer_id[1..6]
+        .iter()
+        .take_while(|&&c| c != b'-')
+        .map(|&c| match c {
+            b'0'..=b'9' => Some((c - b'0').to_string()),
+            b'A'..=b'Z' => Some((c - b'A' + 10).to_string()),
+            b'a'..=b'z' => Some((c - b'a' + 36).to_string()),
+            b'.' => Some("62".to_owned()),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    if version.is_empty() || peer_id[1 + version.len()..6].iter().any(|&c| c != b'-') {
+        return None;
+    }
+    Some(format!("{} {}", name, version.join(".")))
+}
+
+fn azureus_client(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "AG" => "Ares",
+        "AT" => "Artemis",
+        "AX" => "BitPump",
+        "AZ" => "Vuze",
+        "BB" => "BitBuddy",
+        "BC" => "BitComet",
+        "BF" => "Bitflu",
+        "BI" => "BiglyBT",
+        "BL" => "BitCometLite",
+        "BR" => "BitRocket",
+        "BT" => "BitTorrent",
+        "BW" => "BitWombat",
+        "CD" => "Enhanced CTorrent",
+        "DE" => "Deluge",
+        "EB" => "EBit",
+        "FD" => "Free Download Manager",
+        "FG" => "FlashGet",
+        "FW" => "FrostWire",
+        "FX" => "Freebox BitTorrent",
+        "HL" => "Halite",
+        "KG" => "KGet",
+        "KT" => "KTorrent",
+        "LH" => "LH-ABC",
+        "LP" => "Lphant",
+        "LT" => "libtorrent",
+        "lt" => "rTorrent",
+        "LW" => "LimeWire",
+        "MG" => "MediaGet",
+        "MO" => "MonoTorrent",
+        "NX" => "Net Transport",
+        "OS" => "OneSwarm",
+        "PD" => "Pando",
+        "PI" => "PicoTorrent",
+        "qB" => "qBittorrent",
+        "QD" => "QQDownload",
+        "RS" => "bittorrent_client",
+        "RT" => "Retriever",
+        "SB" => "Swiftbit",
+        "SD" => "Thunder",
+        "SK" => "spark",
+        "SP" => "BitSpirit",
+        "ST" => "SymTorrent",
+        "SZ" => "Shareaza",
+        "TL" => "Tribler",
+        "TR" => "Transmission",
+        "TS" => "Torrentstorm",
+        "TT" => "TuoTu",
+        "TX" => "Tixati",
+        "UL" => "uLeecher!",
+        "UM" => "µTorrent for Mac",
+        "UT" => "µTorrent",
+        "UW" => "µTorrent Web",
+        "VG" => "Vagaa",
+        "WD" => "WebTorrent Desktop",
+        "WT" => "BitLet",
+        "WW" => "WebTorrent",
+        "XF" => "Xfplay",
+        "XL" => "Xunlei",
+        "XT" => "XanTorrent",
+        "XX" => "Xtorrent",
+        "ZT" => "ZipTorrent",
+        _ => return None,
+    })
+}
+
+fn shadow_client(code: u8) -> Option<&'static str> {
+    Some(match code {
+        b'A' => "ABC",
+        b'M' => "Mainline",
+        b'O' => "Osprey Permaseed",
+        b'Q' => "BTQueue",
+        b'R' => "Tribler",
+        b'S' => "Shadow",
+        b'T' => "BitTornado",
+        b'U' => "UPnP NAT Bit Torrent",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::peer::{generate_peer_id, PEER_ID_PREFIX};
+
+    pub(crate) fn peer_id(prefix: &[u8]) -> [u8; 20] {
+        let mut id = [b'x'; 20];
+        id[..prefix.len()].copy_from_slice(prefix);
+        id
+    }
+
+    #[test]
+    fn client_names() {
+        for (prefix, name) in [
+            (&b"-qB4250-"[..], Some("qBittorrent 4.2.5")),
+            (b"-qB4600-", Some("qBittorrent 4.6")),
+            (b"-TR2940-", Some("Transmission 2.9.4")),
+            (b"-TR4000-", Some("Transmission 4.0")),
+            (b"-TR400Z-", Some("Transmission 4.0")),
+            (b"-UT355S-", Some("µTorrent 3.5.5")),
+            (b"-lt0D60-", Some("rTorrent 0.13.6")),
+            (b"-LT2010-", Some("libtorrent 2.0.1")),
+            (b"-DE2110-", Some("Deluge 2.1.1")),
+            (b"-BI3600-", Some("BiglyBT 3.6")),
+            (b"-ZZ1000-", Some("ZZ 1.0")),
+            (b"M4-3-6--", Some("Mainline 4.3.6")),
+            (b"M7-10-2-", Some("Mainline 7.10.2")),
+            (b"T03I-----", Some("BitTornado 0.3.18")),
+            (b"S58B-----", Some("Shadow 5.8.11")),
+            (b"-qB42-0-", None),
+            (b"-q14250-", None),
+            (b"00112233", None),
+            (b"T03I-x---", None),
+            (b"ABCDEFGHI", None),
+        ] {
+            assert_eq!(
+                client_name(&peer_id(prefix)).as_deref(),
+                name,
+                "{:?}",
+                prefix
+            );
+        }
+        // our own ids read back as us
+        assert_eq!(
+            client_name(&generate_peer_id(PEER_ID_PREFIX).unwrap()).as_deref(),
+            Some("bittorrent_client 0.1")
+        );
+    }
+}
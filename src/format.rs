@@ -0,0 +1,88 @@
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "yaml" => Ok(OutputFormat::Yaml),
+            "toml" => Ok(OutputFormat::Toml),
+            _ => anyhow::bail!("unknown output format: {} (expected json, yaml or toml)", s),
+        }
+    }
+}
+
+pub fn render(value: &serde_json::Value, format: OutputFormat) -> anyhow::Result<String> {
+    match format {
+        OutputFormat::Json => Ok(value.to_string()),
+        OutputFormat::Yaml => Ok(serde_yaml::to_string(value)?.trim_end().to_owned()),
+        OutputFormat::Toml => {
+            // TOML documents are always tables, so scalars and lists can't be the root
+            if !value.is_object() {
+                anyhow::bail!("TOML output requires a dictionary at the top level");
+            }
+            Ok(toml::to_string(value)?.trim_end().to_owned())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_format_valid() {
+        assert_eq!("json".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert_eq!("yaml".parse::<OutputFormat>().unwrap(), OutputFormat::Yaml);
+        assert_eq!("toml".parse::<OutputFormat>().unwrap(), OutputFormat::Toml);
+    }
+
+    #[test]
+    #[should_panic]
+    fn parse_format_invalid() {
+        "xml".parse::<OutputFormat>().unwrap();
+    }
+
+    #[test]
+    fn render_json_dictionary() {
+        let value = json!({"hello": 123, "key": "value"});
+        assert_eq!(
+            render(&value, OutputFormat::Json).unwrap(),
+            r#"{"hello":123,"key":"value"}"#
+        );
+    }
+
+    #[test]
+    fn render_yaml_dictionary() {
+        let value = json!({"hello": 123, "key": "value", "list": [1, "a"]});
+        assert_eq!(
+            render(&value, OutputFormat::Yaml).unwrap(),
+            "hello: 123\nkey: value\nlist:\n- 1\n- a"
+        );
+    }
+
+    #[test]
+    fn render_toml_dictionary() {
+        let value = json!({"hello": 123, "key": "value", "nested": {"a": [1, 2]}});
+        assert_eq!(
+            render(&value, OutputFormat::Toml).unwrap(),
+            "hello = 123\nkey = \"value\"\n\n[nested]\na = [1, 2]"
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn render_toml_non_dictionary() {
+        render(&json!([1, 2]), OutputFormat::Toml).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use crate::torrent::Torrent;
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads of their own that pieces are hashed on, so that hashing a fast download's large
+/// pieces runs on every core and doesn't hold up the tasks talking to peers.
+///
+/// Jobs are taken in the order they were queued by whichever thread is free, and their results
+/// are handed back to the waiting task. The threads end once the pool is dropped and the
+/// queued jobs are done.
+pub struct HashPool {
+    jobs: mpsc::Sender<Job>,
+    threads: usize,
+}
+
+impl HashPool {
+    pub fn new(threads: usize) -> HashPool {
+        let threads = threads.max(1);
+        let (jobs, queued) = mpsc::channel::<Job>();
+        let queued = Arc::new(Mutex::new(queued));
+        for i in 0..threads {
+            let queued = queued.clone();
+            std::thread::Builder::new()
+                .name(format!("hasher-{}", i))
+                .spawn(move || loop {
+                    // the lock is only held while waiting, never while hashing
+                    let job = queued.lock().unwrap().recv();
+                    match job {
+                        // a job that panics only loses its own result, not the thread
+                        Ok(job) => {
+                            let _ = std::panic::catch_unwind(AssertUnwindSafe(job));
+                        }
+                        Err(_) => break,
+                    }
+                })
+                .expect("can't start a hashing thread");
+        }
+        HashPool { jobs, threads }
+    }
+
+    /// The pool every torrent shares, with a thread for every core.
+    pub fn shared() -> &'static HashPool {
+        static SHARED: OnceLock<HashPool> = OnceLock::new();
+        SHARED.get_or_init(|| {
+            HashPool::new(std::thread::available_parallelism().map_or(1, |threads| threads.get()))
+        })
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Runs `job` on one of the pool's threads and waits for what it returns.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let (reply, result) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            let _ = reply.send(job());
+        });
+        self.jobs
+            .send(job)
+            .map_err(|_| anyhow::anyhow!("hashing threads stopped"))?;
+        result
+            .await
+            .map_err(|_| anyhow::anyhow!("hashing thread panicked"))
+    }
+
+    /// Checks `piece` against the hash of the piece at `index`, handing the piece back along
+    /// with whether it matched.
+    pub async fn verify(
+        &self,
+        torrent: Arc<Torrent>,
+        index: usize,
+        piece: Vec<u8>,
+    ) -> anyhow::Result<(bool, Vec<u8>)> {
+        self.run(move || (torrent.verify_piece(index, &piece), piece))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{test_data, torrent_for};
+    use std::sync::Barrier;
+
+    #[tokio::test]
+    async fn verifies_pieces() {
+        let data = test_data(1000);
+        let torrent = Arc::new(torrent_for(&data, 300));
+        let pool = HashPool::new(2);
+        let (valid, piece) = pool
+            .verify(torrent.clone(), 1, data[300..600].to_vec())
+            .await
+            .unwrap();
+        assert!(valid);
+        assert_eq!(piece, &data[300..600]);
+        let (valid, _) = pool.verify(torrent, 2, vec![0; 300]).await.unwrap();
+        assert!(!valid);
+        assert!(HashPool::shared().threads() >= 1);
+    }
+
+    #[tokio::test]
+    async fn hashes_on_every_thread_at_once() {
+        let pool = HashPool::new(4);
+        // every job waits for the others, so they only finish if they all run side by side
+        let barrier = Arc::new(Barrier::new(4));
+        let jobs = (0..4).map(|_| {
+            let barrier = barrier.clone();
+            pool.run(move || {
+                barrier.wait();
+                std::thread::current().name().unwrap().to_string()
+            })
+        });
+        let mut names = futures_util::future::try_join_all(jobs).await.unwrap();
+        names.sort();
+        assert_eq!(names, ["hasher-0", "hasher-1", "hasher-2", "hasher-3"]);
+        assert!(pool.run(|| panic!("bad job")).await.is_err());
+        assert_eq!(pool.run(|| 1).await.unwrap(), 1);
+    }
+}
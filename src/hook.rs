@@ -0,0 +1,116 @@
+use crate::torrent::Torrent;
+use std::io;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+/// What a hook is run for, passed to it as `TORRENT_EVENT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    /// The download finished.
+    Completed,
+    /// Seeding stopped at its ratio or time limit.
+    Seeded,
+}
+
+impl HookEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            HookEvent::Completed => "completed",
+            HookEvent::Seeded => "seeded",
+        }
+    }
+}
+
+/// Runs `command` through the shell for `event` of `torrent`, whose data is at `path`, and
+/// waits for it to exit. The command finds the torrent in `TORRENT_NAME`, `TORRENT_PATH` and
+/// `TORRENT_INFO_HASH` (in hex) and what happened in `TORRENT_EVENT`.
+pub async fn run(
+    command: &str,
+    event: HookEvent,
+    torrent: &Torrent,
+    path: &Path,
+) -> io::Result<ExitStatus> {
+    let child = start(command, event, torrent, path)?;
+    wait(child).await
+}
+
+/// Like `run`, but only logging how the command went, in a task that ends when the command
+/// does. The command is started right away, and keeps running if we exit first.
+pub fn spawn(command: &str, event: HookEvent, torrent: &Torrent, path: &Path) -> JoinHandle<()> {
+    let child = start(command, event, torrent, path);
+    let command = command.to_owned();
+    tokio::spawn(async move {
+        let status = match child {
+            Ok(child) => wait(child).await,
+            Err(e) => Err(e),
+        };
+        match status {
+            Ok(status) if status.success() => debug!(command, "hook ran"),
+            Ok(status) => warn!(command, %status, "hook failed"),
+            Err(e) => warn!(command, "can't run hook: {}", e),
+        }
+    })
+}
+
+fn start(command: &str, event: HookEvent, torrent: &Torrent, path: &Path) -> io::Result<Child> {
+    shell(command)
+        .env("TORRENT_EVENT", event.name())
+        .env("TORRENT_NAME", &torrent.info.name)
+        .env("TORRENT_PATH", path)
+        .env("TORRENT_INFO_HASH", hex::encode(torrent.info_hash()))
+        .spawn()
+}
+
+async fn wait(mut child: Child) -> io::Result<ExitStatus> {
+    tokio::task::spawn_blocking(move || child.wait()).await?
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{test_data, torrent_for};
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hooks_get_the_torrent_in_their_environment() {
+        let torrent = torrent_for(&test_data(1000), 300);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.bin");
+        let command = "echo \"$TORRENT_EVENT $TORRENT_NAME $TORRENT_INFO_HASH $TORRENT_PATH\" \
+                       > \"$TORRENT_PATH.done\"";
+        let status = run(command, HookEvent::Seeded, &torrent, &path)
+            .await
+            .unwrap();
+        assert!(status.success());
+        let written = std::fs::read_to_string(dir.path().join("test.bin.done")).unwrap();
+        assert_eq!(
+            written,
+            format!(
+                "seeded test.bin {} {}\n",
+                hex::encode(torrent.info_hash()),
+                path.display()
+            )
+        );
+
+        let status = run("exit 3", HookEvent::Completed, &torrent, &path)
+            .await
+            .unwrap();
+        assert_eq!(status.code(), Some(3));
+    }
+}
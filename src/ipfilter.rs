@@ -0,0 +1,488 @@
+use anyhow::Context;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use tracing::warn;
+
+/// eMule blocklists give every range an access level; those below this one are blocked.
+const DAT_ALLOWED_LEVEL: u32 = 128;
+
+/// IP ranges not to talk to, loaded from a blocklist in the eMule `ipfilter.dat` format
+/// (`1.2.3.0 - 1.2.3.255 , 000 , Description`) or the PeerGuardian text format
+/// (`Description:1.2.3.0-1.2.3.255`), gzipped or not. A file may mix both.
+///
+/// IPv4 addresses are kept mapped into IPv6, so both families are looked up in one sorted
+/// list of disjoint ranges.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    ranges: Vec<(u128, u128)>,
+}
+
+/// What an `IpFilter` kept out, as `BanList::filter_stats` counts it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FilterStats {
+    /// Disjoint ranges in the blocklist.
+    pub ranges: usize,
+    /// Connections from blocked addresses that were refused.
+    pub blocked_inbound: usize,
+    /// Blocked peer addresses that weren't connected to.
+    pub blocked_outbound: usize,
+}
+
+impl IpFilter {
+    /// Parses a blocklist. Lines that are neither format are skipped, like the comments and
+    /// blank ones, as long as some range was found.
+    pub fn parse(text: &str) -> anyhow::Result<IpFilter> {
+        let mut ranges = Vec::new();
+        let mut skipped = 0;
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_line(line) {
+                Some(Some(range)) => ranges.push(range),
+                Some(None) => {}
+                None => skipped += 1,
+            }
+        }
+        if ranges.is_empty() && skipped > 0 {
+            anyhow::bail!("no IP ranges in the blocklist");
+        }
+        if skipped > 0 {
+            warn!(
+                skipped,
+                "skipped lines of the blocklist that aren't IP ranges"
+            );
+        }
+        ranges.sort_unstable();
+        let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Ok(IpFilter { ranges: merged })
+    }
+
+    /// Reads and parses the blocklist at `path`, unpacking it first if it is gzipped.
+    pub async fn load(path: &Path) -> anyhow::Result<IpFilter> {
+        let bytes = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("can't read {}", path.display()))?;
+        let bytes = if bytes.starts_with(&GZIP_MAGIC) {
+            gunzip(&bytes).with_context(|| format!("can't unpack {}", path.display()))?
+        } else {
+            bytes
+        };
+        IpFilter::parse(&String::from_utf8_lossy(&bytes))
+            .with_context(|| format!("in {}", path.display()))
+    }
+
+    pub fn blocks(&self, ip: IpAddr) -> bool {
+        let key = key(ip);
+        let i = self.ranges.partition_point(|&(start, _)| start <= key);
+        i > 0 && key <= self.ranges[i - 1].1
+    }
+
+    /// How many disjoint ranges are blocked.
+    pub fn range_count(&self) -> usize {
+        self.ranges.len()
+    }
+}
+
+fn key(ip: IpAddr) -> u128 {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+/// The blocked range of a blocklist line: `None` if it isn't one, `Some(None)` if it is one
+/// that is allowed.
+fn parse_line(line: &str) -> Option<Option<(u128, u128)>> {
+    // PeerGuardian descriptions may have colons of their own, the range never does
+    if let Some(range) = line
+        .rsplit_once(':')
+        .and_then(|(_, range)| parse_range(range))
+    {
+        return Some(Some(range));
+    }
+    let mut fields = line.split(',');
+    let range = parse_range(fields.next()?)?;
+    let level = match fields.next() {
+        Some(level) => level.trim().parse().ok()?,
+        None => 0,
+    };
+    Some((level < DAT_ALLOWED_LEVEL).then_some(range))
+}
+
+fn parse_range(range: &str) -> Option<(u128, u128)> {
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (parse_ip(start.trim())?, parse_ip(end.trim())?);
+    if start.is_ipv4() != end.is_ipv4() || key(start) > key(end) {
+        return None;
+    }
+    Some((key(start), key(end)))
+}
+
+/// Like parsing an `IpAddr`, but taking the zero-padded octets eMule lists are written with.
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    if ip.contains(':') {
+        return ip.parse().ok();
+    }
+    let mut octets = [0; 4];
+    let mut parts = ip.split('.');
+    for octet in &mut octets {
+        let part = parts.next()?;
+        if part.is_empty() || part.len() > 3 {
+            return None;
+        }
+        *octet = part.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(Ipv4Addr::from(octets).into())
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Unpacks the first member of a gzip file (RFC 1952), checking its CRC and length.
+fn gunzip(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    const FHCRC: u8 = 2;
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+    let truncated = || anyhow::anyhow!("gzip data is truncated");
+    if data.len() < 18 || data[2] != 8 {
+        anyhow::bail!("not gzip data compressed with deflate");
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let len = data.get(pos..pos + 2).ok_or_else(truncated)?;
+        pos += 2 + u16::from_le_bytes([len[0], len[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let rest = data.get(pos..).ok_or_else(truncated)?;
+            pos += rest.iter().position(|&b| b == 0).ok_or_else(truncated)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    let (output, used) = inflate(data.get(pos..).ok_or_else(truncated)?)?;
+    let trailer = data.get(pos + used..pos + used + 8).ok_or_else(truncated)?;
+    let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    let size = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+    if crc != crc32(&output) || size != output.len() as u32 {
+        anyhow::bail!("gzip data is corrupt");
+    }
+    Ok(output)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Reads a deflate stream LSB first, the way RFC 1951 packs it.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, n: u32) -> anyhow::Result<u32> {
+        while self.count < n {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| anyhow::anyhow!("deflate data is truncated"))?;
+            self.bits |= (byte as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = self.bits & ((1u64 << n) - 1) as u32;
+        self.bits = self.bits.checked_shr(n).unwrap_or(0);
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Drops what is left of the current byte, for a stored block.
+    fn align(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+    }
+}
+
+/// A canonical Huffman code, by how many codes there are of each length and the symbols in
+/// code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> anyhow::Result<u16> {
+        let (mut code, mut first, mut index) = (0u32, 0u32, 0u32);
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1)?;
+            let count = count as u32;
+            if code < first + count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        anyhow::bail!("bad Huffman code in deflate data")
+    }
+}
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order code length code lengths are sent in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Decompresses a raw deflate stream (RFC 1951), returning the data and how many bytes of
+/// `data` the stream took up.
+fn inflate(data: &[u8]) -> anyhow::Result<(Vec<u8>, usize)> {
+    let mut reader = BitReader {
+        data,
+        pos: 0,
+        bits: 0,
+        count: 0,
+    };
+    let mut output = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = data
+                    .get(reader.pos..reader.pos + 4)
+                    .ok_or_else(|| anyhow::anyhow!("deflate data is truncated"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    anyhow::bail!("bad stored block length in deflate data");
+                }
+                let start = reader.pos + 4;
+                let block = data
+                    .get(start..start + len as usize)
+                    .ok_or_else(|| anyhow::anyhow!("deflate data is truncated"))?;
+                output.extend_from_slice(block);
+                reader.pos = start + len as usize;
+            }
+            1 => {
+                let mut lengths = [0; 288 + 30];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..288].fill(8);
+                lengths[288..].fill(5);
+                let literals = Huffman::new(&lengths[..288]);
+                let distances = Huffman::new(&lengths[288..]);
+                inflate_block(&mut reader, &literals, &distances, &mut output)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &literals, &distances, &mut output)?;
+            }
+            _ => anyhow::bail!("bad block type in deflate data"),
+        }
+        if last {
+            return Ok((output, reader.pos));
+        }
+    }
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> anyhow::Result<(Huffman, Huffman)> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    let mut code_lengths = [0; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[i] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match code_lengths.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or_else(|| anyhow::anyhow!("bad code lengths in deflate data"))?;
+                (previous, 3 + reader.bits(2)?)
+            }
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+    if lengths.len() > literal_count + distance_count {
+        anyhow::bail!("bad code lengths in deflate data");
+    }
+    let (literals, distances) = lengths.split_at(literal_count);
+    Ok((Huffman::new(literals), Huffman::new(distances)))
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    literals: &Huffman,
+    distances: &Huffman,
+    output: &mut Vec<u8>,
+) -> anyhow::Result<()> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 => output.push(symbol as u8),
+            256 => return Ok(()),
+            257..=285 => {
+                let i = symbol - 257;
+                let length =
+                    LENGTH_BASES[i] as usize + reader.bits(LENGTH_EXTRA[i] as u32)? as usize;
+                let i = distances.decode(reader)? as usize;
+                if i >= DISTANCE_BASES.len() {
+                    anyhow::bail!("bad distance in deflate data");
+                }
+                let distance =
+                    DISTANCE_BASES[i] as usize + reader.bits(DISTANCE_EXTRA[i] as u32)? as usize;
+                if distance > output.len() {
+                    anyhow::bail!("distance too far back in deflate data");
+                }
+                // the copy may overlap what it is copying, repeating it
+                let start = output.len() - distance;
+                for i in 0..length {
+                    output.push(output[start + i]);
+                }
+            }
+            _ => anyhow::bail!("bad literal in deflate data"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_both_formats() {
+        let filter = IpFilter::parse(
+            "# a comment
+001.002.004.000 - 001.002.004.255 , 000 , eMule range
+010.000.000.000 - 010.255.255.255 , 200 , allowed eMule range
+Some: PeerGuardian range:5.6.7.0-5.6.7.9
+5.6.7.10-5.6.7.20
+2001:db8:: - 2001:db8::ffff , 100 , IPv6
+not a range
+",
+        )
+        .unwrap();
+        assert_eq!(filter.range_count(), 3);
+        for blocked in [
+            "1.2.4.0",
+            "1.2.4.255",
+            "5.6.7.15",
+            "::ffff:5.6.7.0",
+            "2001:db8::1",
+        ] {
+            assert!(filter.blocks(ip(blocked)), "{}", blocked);
+        }
+        for allowed in ["1.2.5.0", "10.1.1.1", "5.6.7.21", "2001:db8::1:0", "::1"] {
+            assert!(!filter.blocks(ip(allowed)), "{}", allowed);
+        }
+
+        assert!(IpFilter::parse("garbage\n").is_err());
+        assert!(IpFilter::parse("1.2.3.4 - 1.2.3.0\n").is_err());
+        assert_eq!(IpFilter::parse("").unwrap(), IpFilter::default());
+    }
+
+    #[test]
+    fn inflates_every_kind_of_block() {
+        let stored = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x04\x03\x01\x19\x00\xe6\xff\x53\x74\x6f\
+\x72\x65\x64\x3a\x31\x2e\x32\x2e\x33\x2e\x30\x2d\x31\x2e\x32\x2e\x33\x2e\x32\x35\x35\x0a\x78\xcd\
+\x37\xf6\x19\x00\x00\x00";
+        assert_eq!(gunzip(stored).unwrap(), b"Stored:1.2.3.0-1.2.3.255\n");
+        let fixed = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\x73\xcb\xac\x48\x4d\xb1\x32\xd4\
+\x33\xd2\x33\xd6\x33\xd0\x85\xd0\x46\xa6\xa6\x5c\x00\xa1\xd3\xb1\x48\x18\x00\x00\x00";
+        assert_eq!(gunzip(fixed).unwrap(), b"Fixed:1.2.3.0-1.2.3.255\n");
+        let mut corrupt = fixed.to_vec();
+        corrupt[30] ^= 1;
+        assert!(gunzip(&corrupt).is_err());
+        assert!(gunzip(&fixed[..30]).is_err());
+    }
+
+    #[tokio::test]
+    async fn loads_gzipped_blocklists() {
+        // seven PeerGuardian lines, compressed with dynamic Huffman codes
+        let gzipped = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\x4d\xc9\xbb\x09\x80\x40\x10\
+\x45\xd1\xdc\x2a\x6c\x40\xd9\xf9\x06\x96\xb1\x1d\x18\xc8\x66\xf6\x1f\x0a\xf3\x7c\x30\xdc\xe4\xc2\
+\x99\xf7\xbb\x9e\x7d\x5c\x32\xce\xea\xf8\x47\x23\xb6\x59\x26\x30\xa1\x49\x33\x85\x29\x4d\x9b\x19\
+\xcc\x68\xd6\xcc\x61\x4e\xf3\x66\x01\x0b\x5a\x34\x4b\x58\xd2\xb2\xec\x03\x30\xc3\x12\x11\xc4\x00\
+\x00\x00";
+        let text: String = (0..7)
+            .map(|i| format!("Range {}:10.0.{}.0-10.0.{}.255\n", i, i, i))
+            .collect();
+        assert_eq!(gunzip(gzipped).unwrap(), text.as_bytes());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocklist.p2p.gz");
+        std::fs::write(&path, gzipped).unwrap();
+        let filter = IpFilter::load(&path).await.unwrap();
+        // the ranges follow each other, so they come out as one
+        assert_eq!(filter.range_count(), 1);
+        assert!(filter.blocks(ip("10.0.6.255")));
+        assert!(!filter.blocks(ip("10.0.7.0")));
+        assert!(IpFilter::load(&dir.path().join("missing")).await.is_err());
+    }
+}
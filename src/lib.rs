@@ -0,0 +1,60 @@
+//! A BitTorrent client: bencoding, v1 and v2 torrent metainfo and magnet links, trackers, the
+//! DHT and local peer discovery, port mapping through the router, SOCKS5 and HTTP proxies, the
+//! peer wire protocol over TCP or uTP and web seeds, and downloading and seeding on top of them.
+//!
+//! The `bittorent_client` binary is a thin command line front end to this library.
+
+pub mod bencode;
+pub mod bitfield;
+pub mod cache;
+pub mod choker;
+pub mod config;
+pub mod connector;
+pub mod create;
+pub mod daemon;
+pub mod dht;
+pub mod diff;
+pub mod disk;
+pub mod download;
+pub mod edit;
+pub mod error;
+pub mod events;
+pub mod extension;
+pub mod external_ip;
+pub mod filemap;
+pub mod fingerprint;
+pub mod format;
+pub mod hasher;
+pub mod hook;
+pub mod ipfilter;
+pub mod lsd;
+pub mod magnet;
+pub mod merkle;
+pub mod message;
+pub mod metrics;
+pub mod mse;
+pub mod net;
+pub mod peer;
+pub mod picker;
+pub mod pipeline;
+pub mod portmap;
+pub mod proxy;
+pub mod rate;
+pub mod resume;
+pub mod schedule;
+pub mod seed;
+pub mod session;
+pub mod snapshot;
+pub mod stats;
+pub mod storage;
+pub mod stream;
+mod supervisor;
+pub mod testing;
+pub mod torrent;
+pub mod tracker;
+pub mod udp_tracker;
+pub mod utp;
+pub mod watch;
+pub mod webseed;
+
+pub use error::{Error, Result};
@@ -0,0 +1,322 @@
+use crate::tracker::Progress;
+use anyhow::Context;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+/// The multicast group peers on the local network announce their torrents to (BEP 14).
+pub const LSD_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+/// How often a download announces itself on the local network.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Where to send the peers announcing each info hash we are interested in.
+type Subscribers = Mutex<HashMap<[u8; 20], mpsc::Sender<Vec<SocketAddr>>>>;
+
+/// A `BT-SEARCH` message: the sender accepts peers for these torrents on `port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// Lets the sender recognise its own announcements when they are looped back to it.
+    pub cookie: Option<String>,
+}
+
+impl Announcement {
+    /// The message sent to `group`.
+    pub fn to_bytes(&self, group: SocketAddrV4) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            group, self.port
+        );
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    pub fn parse(bytes: &[u8]) -> anyhow::Result<Announcement> {
+        let text = std::str::from_utf8(bytes).context("announcement is not text")?;
+        let mut lines = text.split("\r\n");
+        if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+            anyhow::bail!("not a BT-SEARCH announcement");
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                anyhow::bail!("malformed header {:?}", line);
+            };
+            let value = value.trim();
+            // header names are case-insensitive, as in HTTP
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = Some(value.parse::<u16>().context("invalid port")?),
+                "infohash" => {
+                    let mut info_hash = [0; 20];
+                    hex::decode_to_slice(value, &mut info_hash)
+                        .with_context(|| format!("invalid info hash {:?}", value))?;
+                    info_hashes.push(info_hash);
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        let port = port
+            .filter(|&port| port != 0)
+            .ok_or_else(|| anyhow::anyhow!("announcement has no port"))?;
+        if info_hashes.is_empty() {
+            anyhow::bail!("announcement has no info hash");
+        }
+        Ok(Announcement {
+            port,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+/// Announces our torrents to a multicast group and learns about the peers on the local
+/// network that announce the same ones.
+pub struct Lsd {
+    socket: Arc<UdpSocket>,
+    group: SocketAddrV4,
+    cookie: String,
+    subscribers: Arc<Subscribers>,
+    receiver: JoinHandle<()>,
+}
+
+impl Lsd {
+    /// Joins `group`, normally `LSD_GROUP`. The port is shared with any other client on this
+    /// machine doing the same.
+    pub async fn bind(group: SocketAddrV4) -> anyhow::Result<Lsd> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port()).into())?;
+        socket
+            .join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)
+            .with_context(|| format!("failed to join multicast group {}", group.ip()))?;
+        let socket = Arc::new(UdpSocket::from_std(socket.into())?);
+        let cookie = hex::encode(rand::random::<[u8; 8]>());
+        let subscribers = Arc::new(Mutex::new(HashMap::new()));
+        let receiver = tokio::spawn(receive(socket.clone(), cookie.clone(), subscribers.clone()));
+        Ok(Lsd {
+            socket,
+            group,
+            cookie,
+            subscribers,
+            receiver,
+        })
+    }
+
+    /// Tells the local network that we accept peers for `info_hash` on `port`.
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> anyhow::Result<()> {
+        let announcement = Announcement {
+            port,
+            info_hashes: vec![info_hash],
+            cookie: Some(self.cookie.clone()),
+        };
+        self.socket
+            .send_to(&announcement.to_bytes(self.group), self.group)
+            .await?;
+        Ok(())
+    }
+
+    /// Sends the peers announcing `info_hash` to `peers` until the subscription is dropped.
+    fn subscribe(
+        &self,
+        info_hash: [u8; 20],
+        peers: mpsc::Sender<Vec<SocketAddr>>,
+    ) -> Subscription<'_> {
+        self.subscribers.lock().unwrap().insert(info_hash, peers);
+        Subscription {
+            lsd: self,
+            info_hash,
+        }
+    }
+}
+
+impl Drop for Lsd {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+/// Removes its info hash from the subscribers when dropped, also when the task owning it is
+/// aborted.
+struct Subscription<'a> {
+    lsd: &'a Lsd,
+    info_hash: [u8; 20],
+}
+
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        self.lsd.subscribers.lock().unwrap().remove(&self.info_hash);
+    }
+}
+
+async fn receive(socket: Arc<UdpSocket>, cookie: String, subscribers: Arc<Subscribers>) {
+    let mut buffer = vec![0; 1500];
+    loop {
+        let Ok((n, from)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        let Ok(announcement) = Announcement::parse(&buffer[..n]) else {
+            continue;
+        };
+        if announcement.cookie.as_deref() == Some(cookie.as_str()) {
+            continue;
+        }
+        let peer = SocketAddr::new(from.ip(), announcement.port);
+        let subscribers = subscribers.lock().unwrap();
+        for info_hash in &announcement.info_hashes {
+            if let Some(peers) = subscribers.get(info_hash) {
+                // a download that hasn't caught up with its peers doesn't need more
+                let _ = peers.try_send(vec![peer]);
+            }
+        }
+    }
+}
+
+/// Announces `info_hash` every `ANNOUNCE_INTERVAL` and sends the local peers announcing it to
+/// `peers`, until nothing is `left` to download.
+pub async fn announce_periodically(
+    lsd: Arc<Lsd>,
+    info_hash: [u8; 20],
+    port: u16,
+    peers: mpsc::Sender<Vec<SocketAddr>>,
+    mut progress: watch::Receiver<Progress>,
+) {
+    let _subscription = lsd.subscribe(info_hash, peers);
+    loop {
+        // an announcement that fails to go out is simply made again next time
+        let _ = lsd.announce(info_hash, port).await;
+        let sleep = tokio::time::sleep(ANNOUNCE_INTERVAL);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                () = &mut sleep => break,
+                changed = progress.changed() => {
+                    if changed.is_err() || progress.borrow_and_update().left == 0 {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The LSD group on a port of its own, so that tests running at once don't hear each other.
+    pub(crate) fn test_group() -> SocketAddrV4 {
+        let socket = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        SocketAddrV4::new(*LSD_GROUP.ip(), port)
+    }
+
+    #[test]
+    fn announcement_bytes() {
+        let announcement = Announcement {
+            port: 6881,
+            info_hashes: vec![[0xab; 20]],
+            cookie: Some("c00k1e".to_string()),
+        };
+        let bytes = announcement.to_bytes(LSD_GROUP);
+        assert_eq!(
+            bytes,
+            format!(
+                "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\n\
+                 Infohash: {}\r\ncookie: c00k1e\r\n\r\n\r\n",
+                "ab".repeat(20)
+            )
+            .as_bytes()
+        );
+        assert_eq!(Announcement::parse(&bytes).unwrap(), announcement);
+    }
+
+    #[test]
+    fn parse_announcement_headers() {
+        let message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nhost: 239.192.152.143:6771\r\nPORT: 51413\r\n\
+             infohash: {}\r\nInfohash: {}\r\n\r\n\r\n",
+            "01".repeat(20),
+            "02".repeat(20)
+        );
+        let announcement = Announcement::parse(message.as_bytes()).unwrap();
+        assert_eq!(announcement.port, 51413);
+        assert_eq!(announcement.info_hashes, vec![[1; 20], [2; 20]]);
+        assert_eq!(announcement.cookie, None);
+    }
+
+    #[test]
+    fn parse_invalid_announcements() {
+        let info_hash = "01".repeat(20);
+        for message in [
+            format!(
+                "M-SEARCH * HTTP/1.1\r\nPort: 1\r\nInfohash: {}\r\n\r\n",
+                info_hash
+            ),
+            format!("BT-SEARCH * HTTP/1.1\r\nInfohash: {}\r\n\r\n", info_hash),
+            format!(
+                "BT-SEARCH * HTTP/1.1\r\nPort: 0\r\nInfohash: {}\r\n\r\n",
+                info_hash
+            ),
+            "BT-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n".to_string(),
+            "BT-SEARCH * HTTP/1.1\r\nPort: 1\r\nInfohash: 0102\r\n\r\n".to_string(),
+        ] {
+            assert!(
+                Announcement::parse(message.as_bytes()).is_err(),
+                "{:?}",
+                message
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_peers_announcing_the_same_torrent() {
+        let group = test_group();
+        let ours = Arc::new(Lsd::bind(group).await.unwrap());
+        let theirs = Lsd::bind(group).await.unwrap();
+        let (peers_tx, mut peers) = mpsc::channel(4);
+        let (_progress_tx, progress) = watch::channel(Progress {
+            left: 1,
+            ..Progress::default()
+        });
+        let announcer = tokio::spawn(announce_periodically(
+            ours.clone(),
+            [1; 20],
+            6881,
+            peers_tx,
+            progress,
+        ));
+
+        // our own announcement is looped back, but mustn't show up as a peer
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        theirs.announce([2; 20], 7000).await.unwrap();
+        theirs.announce([1; 20], 7001).await.unwrap();
+        let found = tokio::time::timeout(Duration::from_secs(5), peers.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].port(), 7001);
+
+        announcer.abort();
+        let _ = announcer.await;
+        assert!(ours.subscribers.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,281 @@
+use crate::picker::Priority;
+use crate::tracker::urlencode;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// The parts of a `magnet:?xt=urn:btih:...` link that identify a torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    /// Display name (`dn`), if the link has one.
+    pub name: Option<String>,
+    /// Tracker URLs (`tr`), in the order they appear.
+    pub trackers: Vec<String>,
+    /// The only files to download (`so`, BEP 53), by their 0-based numbers in the order of
+    /// `Torrent::files`; every file if empty.
+    pub select_only: Vec<RangeInclusive<usize>>,
+}
+
+impl Magnet {
+    pub fn parse(link: &str) -> crate::Result<Magnet> {
+        Magnet::parse_link(link).map_err(crate::Error::Metainfo)
+    }
+
+    fn parse_link(link: &str) -> anyhow::Result<Magnet> {
+        let Some(query) = link.strip_prefix("magnet:?") else {
+            anyhow::bail!("not a magnet link: {}", link);
+        };
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut select_only = Vec::new();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode(value)?;
+            match key {
+                "xt" => {
+                    // other topics (e.g. btmh for v2 torrents) may appear alongside btih
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                "so" => select_only.extend(parse_select_only(&value)?),
+                _ => {}
+            }
+        }
+
+        Ok(Magnet {
+            info_hash: info_hash.ok_or_else(|| anyhow::anyhow!("magnet link has no btih hash"))?,
+            name,
+            trackers,
+            select_only,
+        })
+    }
+
+    /// The priorities of all `file_count` files of the torrent, with those `select_only`
+    /// leaves out skipped; `None` if it selects every file. Numbers past the last file are
+    /// ignored.
+    pub fn file_priorities(&self, file_count: usize) -> Option<Vec<Priority>> {
+        if self.select_only.is_empty() {
+            return None;
+        }
+        let mut priorities = vec![Priority::Skip; file_count];
+        for files in &self.select_only {
+            for file in files.clone().take_while(|&file| file < file_count) {
+                priorities[file] = Priority::Normal;
+            }
+        }
+        Some(priorities)
+    }
+}
+
+/// A list of file numbers and inclusive ranges of them, e.g. `0,2,4-6`.
+fn parse_select_only(value: &str) -> anyhow::Result<Vec<RangeInclusive<usize>>> {
+    let number = |text: &str| {
+        text.parse::<usize>()
+            .map_err(|_| anyhow::anyhow!("invalid file number {:?} in magnet link", text))
+    };
+    value
+        .split(',')
+        .map(|files| match files.split_once('-') {
+            Some((first, last)) => {
+                let files = number(first)?..=number(last)?;
+                if files.is_empty() {
+                    anyhow::bail!("invalid file range {} in magnet link", files_text(&files));
+                }
+                Ok(files)
+            }
+            None => number(files).map(|file| file..=file),
+        })
+        .collect()
+}
+
+/// How `so` writes `files`: one number, or the first and last joined by a dash.
+fn files_text(files: &RangeInclusive<usize>) -> String {
+    if files.start() == files.end() {
+        files.start().to_string()
+    } else {
+        format!("{}-{}", files.start(), files.end())
+    }
+}
+
+/// Formats the link with the info hash in hex, the form `parse` reads back.
+impl fmt::Display for Magnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "magnet:?xt=urn:btih:{}", hex::encode(self.info_hash))?;
+        if let Some(name) = &self.name {
+            write!(f, "&dn={}", urlencode(name.as_bytes()))?;
+        }
+        for tracker in &self.trackers {
+            write!(f, "&tr={}", urlencode(tracker.as_bytes()))?;
+        }
+        if !self.select_only.is_empty() {
+            let files: Vec<String> = self.select_only.iter().map(files_text).collect();
+            write!(f, "&so={}", files.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// Accepts the 40-character hex and the older 32-character base32 forms.
+fn parse_info_hash(hash: &str) -> anyhow::Result<[u8; 20]> {
+    match hash.len() {
+        40 => {
+            let mut info_hash = [0; 20];
+            hex::decode_to_slice(hash, &mut info_hash)?;
+            Ok(info_hash)
+        }
+        32 => base32_decode(hash),
+        _ => anyhow::bail!("invalid info hash length {} in magnet link", hash.len()),
+    }
+}
+
+fn base32_decode(input: &str) -> anyhow::Result<[u8; 20]> {
+    let mut info_hash = [0; 20];
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    let mut position = 0;
+    for c in input.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => anyhow::bail!("invalid base32 character {:?} in info hash", c as char),
+        };
+        buffer = buffer << 5 | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            info_hash[position] = (buffer >> bits) as u8;
+            position += 1;
+        }
+    }
+    Ok(info_hash)
+}
+
+fn percent_decode(input: &str) -> anyhow::Result<String> {
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut bytes = input.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [
+                bytes.next().unwrap_or_default(),
+                bytes.next().unwrap_or_default(),
+            ];
+            let mut value = [0];
+            hex::decode_to_slice(hex, &mut value)
+                .map_err(|_| anyhow::anyhow!("invalid percent-encoding in {}", input))?;
+            decoded.push(value[0]);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_full_link() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165\
+&dn=magnet1.gif&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce",
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(magnet.info_hash),
+            "ad42ce8109f54c99613ce38f9b4d87e70f24a165"
+        );
+        assert_eq!(magnet.name.as_deref(), Some("magnet1.gif"));
+        assert_eq!(
+            magnet.trackers,
+            vec!["http://bittorrent-test-tracker.codecrafters.io/announce"]
+        );
+    }
+
+    #[test]
+    fn parse_several_trackers_without_name() {
+        let magnet = Magnet::parse(
+            "magnet:?tr=udp%3A%2F%2Fa%3A80&xt=urn:btih:AD42CE8109F54C99613CE38F9B4D87E70F24A165\
+&tr=http%3A%2F%2Fb%2Fannounce",
+        )
+        .unwrap();
+        assert_eq!(magnet.name, None);
+        assert_eq!(magnet.trackers, vec!["udp://a:80", "http://b/announce"]);
+    }
+
+    #[test]
+    fn parse_base32_info_hash() {
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:VVBM5AIJ6VGJSYJ44OHZWTMH44HSJILF").unwrap();
+        assert_eq!(
+            hex::encode(magnet.info_hash),
+            "ad42ce8109f54c99613ce38f9b4d87e70f24a165"
+        );
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let magnet = Magnet {
+            info_hash: [0xad; 20],
+            name: Some("a file & more.bin".to_owned()),
+            trackers: vec![
+                "http://t/announce?key=1".to_owned(),
+                "udp://u:80".to_owned(),
+            ],
+            select_only: vec![0..=0, 2..=4],
+        };
+        let link = magnet.to_string();
+        assert!(link.starts_with(&format!(
+            "magnet:?xt=urn:btih:{}&dn=a%20file",
+            "ad".repeat(20)
+        )));
+        assert_eq!(Magnet::parse(&link).unwrap(), magnet);
+    }
+
+    #[test]
+    fn parse_select_only_files() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&so=0,2,4-6&so=9",
+        )
+        .unwrap();
+        assert_eq!(magnet.select_only, vec![0..=0, 2..=2, 4..=6, 9..=9]);
+        let priorities = magnet.file_priorities(6).unwrap();
+        let (skip, normal) = (Priority::Skip, Priority::Normal);
+        assert_eq!(priorities, [normal, skip, normal, skip, normal, normal]);
+        assert!(magnet.to_string().ends_with("&so=0,2,4-6,9"));
+
+        let all = Magnet::parse("magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165");
+        assert_eq!(all.unwrap().file_priorities(6), None);
+        for bad in ["so=6-4", "so=a", "so=1,", "so=-3"] {
+            let link = format!(
+                "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&{}",
+                bad
+            );
+            assert!(Magnet::parse(&link).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn parse_missing_info_hash() {
+        Magnet::parse("magnet:?dn=file.bin").unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn parse_not_a_magnet_link() {
+        Magnet::parse("http://example.com/?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165")
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn parse_invalid_percent_encoding() {
+        Magnet::parse("magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=%zz")
+            .unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+use sha2::{Digest, Sha256};
+
+/// Size of the blocks whose hashes are the leaves of a file's merkle tree (BEP 52).
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+fn pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Root of the tree over `layer`, padded to a power of two with `padding`, the hash of a
+/// subtree of the same height as the others that covers no data.
+fn root(mut layer: Vec<[u8; 32]>, padding: [u8; 32]) -> [u8; 32] {
+    layer.resize(layer.len().next_power_of_two(), padding);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|nodes| pair(&nodes[0], &nodes[1]))
+            .collect();
+    }
+    layer[0]
+}
+
+/// Root of the tree over the blocks of `data`, padded with zero leaves to `leaves` leaves.
+fn blocks_root(data: &[u8], leaves: usize) -> [u8; 32] {
+    let mut layer: Vec<[u8; 32]> = data
+        .chunks(BLOCK_SIZE)
+        .map(|block| Sha256::digest(block).into())
+        .collect();
+    layer.resize(leaves.max(layer.len()), [0; 32]);
+    root(layer, [0; 32])
+}
+
+/// Hash of a piece of a file longer than one piece: the root of the subtree over its blocks,
+/// as listed in the file's piece layer. The last piece of a file is padded with zero leaves.
+pub fn piece_hash(piece: &[u8], piece_length: usize) -> [u8; 32] {
+    blocks_root(piece, piece_length / BLOCK_SIZE)
+}
+
+/// The `pieces root` of a file whose piece layer is `piece_hashes`.
+pub fn root_from_layer(piece_hashes: &[[u8; 32]], piece_length: usize) -> [u8; 32] {
+    let empty_piece = blocks_root(&[], piece_length / BLOCK_SIZE);
+    root(piece_hashes.to_vec(), empty_piece)
+}
+
+/// The `pieces root` of a file with contents `data`. A file of at most one piece has no piece
+/// layer, so this is also what its only piece is checked against.
+pub fn file_root(data: &[u8], piece_length: usize) -> [u8; 32] {
+    if data.len() <= piece_length {
+        return blocks_root(data, data.len().div_ceil(BLOCK_SIZE).max(1));
+    }
+    let layer: Vec<[u8; 32]> = data
+        .chunks(piece_length)
+        .map(|piece| piece_hash(piece, piece_length))
+        .collect();
+    root_from_layer(&layer, piece_length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_file_root_is_its_hash() {
+        let data = vec![7; 1000];
+        let hash: [u8; 32] = Sha256::digest(&data).into();
+        assert_eq!(file_root(&data, 4 * BLOCK_SIZE), hash);
+    }
+
+    #[test]
+    fn file_root_pads_with_zero_leaves() {
+        let data = vec![1; 3 * BLOCK_SIZE];
+        let leaves: Vec<[u8; 32]> = data
+            .chunks(BLOCK_SIZE)
+            .map(|block| Sha256::digest(block).into())
+            .collect();
+        let expected = pair(&pair(&leaves[0], &leaves[1]), &pair(&leaves[2], &[0; 32]));
+        assert_eq!(file_root(&data, 4 * BLOCK_SIZE), expected);
+    }
+
+    #[test]
+    fn root_from_piece_layer() {
+        let piece_length = 2 * BLOCK_SIZE;
+        let data: Vec<u8> = (0..5 * BLOCK_SIZE + 100).map(|i| i as u8).collect();
+        let layer: Vec<[u8; 32]> = data
+            .chunks(piece_length)
+            .map(|piece| piece_hash(piece, piece_length))
+            .collect();
+        assert_eq!(layer.len(), 3);
+        // the tree over the blocks is the same whichever layer it is built from
+        assert_eq!(root_from_layer(&layer, piece_length), blocks_root(&data, 8));
+        assert_eq!(file_root(&data, piece_length), blocks_root(&data, 8));
+    }
+}
@@ -0,0 +1,460 @@
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Longest message a peer may send, so that its length prefix can't have us allocate more:
+/// room for a 128 KiB block, or the bitfield of eight million pieces.
+pub const MAX_MESSAGE_LENGTH: usize = 1 << 20;
+
+/// A message of the peer wire protocol, as exchanged after the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    /// A zero-length message sent to keep the connection open.
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    /// One bit per piece, high bit of the first byte is piece 0.
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// DHT (BEP 5): the UDP port the peer's DHT node listens on.
+    Port(u16),
+    /// Fast Extension (BEP 6): a piece the peer would like us to download from it.
+    Suggest(u32),
+    /// Fast Extension: the peer has every piece, in place of a bitfield.
+    HaveAll,
+    /// Fast Extension: the peer has no pieces, in place of a bitfield.
+    HaveNone,
+    /// Fast Extension: the peer won't answer this request.
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// Fast Extension: a piece the peer serves to us even while it chokes us.
+    AllowedFast(u32),
+    /// A BEP 10 extension message; `id` 0 is the extension handshake.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// A message of an extension we don't know, which BEP 3 has us ignore.
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl PeerMessage {
+    /// Serializes the message including its 4-byte big-endian length prefix.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            PeerMessage::KeepAlive => {}
+            PeerMessage::Choke => payload.push(0),
+            PeerMessage::Unchoke => payload.push(1),
+            PeerMessage::Interested => payload.push(2),
+            PeerMessage::NotInterested => payload.push(3),
+            PeerMessage::Have(index) => {
+                payload.push(4);
+                payload.extend_from_slice(&index.to_be_bytes());
+            }
+            PeerMessage::Bitfield(bitfield) => {
+                payload.push(5);
+                payload.extend_from_slice(bitfield);
+            }
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => {
+                payload.push(6);
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(&length.to_be_bytes());
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                payload.push(7);
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(block);
+            }
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                payload.push(8);
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(&length.to_be_bytes());
+            }
+            PeerMessage::Port(port) => {
+                payload.push(9);
+                payload.extend_from_slice(&port.to_be_bytes());
+            }
+            PeerMessage::Suggest(index) => {
+                payload.push(0x0d);
+                payload.extend_from_slice(&index.to_be_bytes());
+            }
+            PeerMessage::HaveAll => payload.push(0x0e),
+            PeerMessage::HaveNone => payload.push(0x0f),
+            PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                payload.push(0x10);
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(&length.to_be_bytes());
+            }
+            PeerMessage::AllowedFast(index) => {
+                payload.push(0x11);
+                payload.extend_from_slice(&index.to_be_bytes());
+            }
+            PeerMessage::Extended { id, payload: body } => {
+                payload.push(20);
+                payload.push(*id);
+                payload.extend_from_slice(body);
+            }
+            PeerMessage::Unknown { id, payload: body } => {
+                payload.push(*id);
+                payload.extend_from_slice(body);
+            }
+        }
+
+        let mut bytes = Vec::with_capacity(4 + payload.len());
+        bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+
+    /// Parses a message from the bytes following its length prefix.
+    pub fn from_payload(payload: &[u8]) -> crate::Result<PeerMessage> {
+        PeerMessage::parse(payload).map_err(crate::Error::PeerProtocol)
+    }
+
+    fn parse(payload: &[u8]) -> anyhow::Result<PeerMessage> {
+        let Some((&id, body)) = payload.split_first() else {
+            return Ok(PeerMessage::KeepAlive);
+        };
+        let message = match id {
+            0..=3 | 0x0e | 0x0f => {
+                if !body.is_empty() {
+                    anyhow::bail!("message {} must not have a payload", id);
+                }
+                match id {
+                    0 => PeerMessage::Choke,
+                    1 => PeerMessage::Unchoke,
+                    2 => PeerMessage::Interested,
+                    3 => PeerMessage::NotInterested,
+                    0x0e => PeerMessage::HaveAll,
+                    _ => PeerMessage::HaveNone,
+                }
+            }
+            4 => PeerMessage::Have(u32_at(body, 0, 4)?),
+            0x0d => PeerMessage::Suggest(u32_at(body, 0, 4)?),
+            0x11 => PeerMessage::AllowedFast(u32_at(body, 0, 4)?),
+            5 => PeerMessage::Bitfield(body.to_vec()),
+            9 => {
+                let Ok(port) = body.try_into() else {
+                    anyhow::bail!("expected a 2-byte payload, got {} bytes", body.len());
+                };
+                PeerMessage::Port(u16::from_be_bytes(port))
+            }
+            6 | 8 | 0x10 => {
+                let index = u32_at(body, 0, 12)?;
+                let begin = u32_at(body, 4, 12)?;
+                let length = u32_at(body, 8, 12)?;
+                match id {
+                    6 => PeerMessage::Request {
+                        index,
+                        begin,
+                        length,
+                    },
+                    8 => PeerMessage::Cancel {
+                        index,
+                        begin,
+                        length,
+                    },
+                    _ => PeerMessage::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    },
+                }
+            }
+            7 => {
+                if body.len() < 8 {
+                    anyhow::bail!("piece message too short: {} bytes", body.len());
+                }
+                PeerMessage::Piece {
+                    index: u32_at(&body[..8], 0, 8)?,
+                    begin: u32_at(&body[..8], 4, 8)?,
+                    block: body[8..].to_vec(),
+                }
+            }
+            20 => {
+                let Some((&id, payload)) = body.split_first() else {
+                    anyhow::bail!("extended message without an extension id");
+                };
+                PeerMessage::Extended {
+                    id,
+                    payload: payload.to_vec(),
+                }
+            }
+            _ => PeerMessage::Unknown {
+                id,
+                payload: body.to_vec(),
+            },
+        };
+        Ok(message)
+    }
+}
+
+/// Reads a big-endian u32 at `offset`, checking that `body` is exactly `expected_len` long.
+fn u32_at(body: &[u8], offset: usize, expected_len: usize) -> anyhow::Result<u32> {
+    if body.len() != expected_len {
+        anyhow::bail!(
+            "expected a {}-byte payload, got {} bytes",
+            expected_len,
+            body.len()
+        );
+    }
+    Ok(u32::from_be_bytes(body[offset..offset + 4].try_into()?))
+}
+
+/// Frames peer messages on a byte stream, for use with `tokio_util::codec::Framed`.
+#[derive(Debug, Default)]
+pub struct MessageCodec;
+
+impl Decoder for MessageCodec {
+    type Item = PeerMessage;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<PeerMessage>> {
+        let Some(prefix) = src.get(..4) else {
+            return Ok(None);
+        };
+        let length = u32::from_be_bytes(prefix.try_into()?) as usize;
+        if length > MAX_MESSAGE_LENGTH {
+            anyhow::bail!(
+                "peer sent a {}-byte message, longer than the {} allowed",
+                length,
+                MAX_MESSAGE_LENGTH
+            );
+        }
+        if src.len() < 4 + length {
+            src.reserve(4 + length - src.len());
+            return Ok(None);
+        }
+        let frame = src.split_to(4 + length);
+        Ok(Some(PeerMessage::from_payload(&frame[4..])?))
+    }
+}
+
+impl Encoder<PeerMessage> for MessageCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, message: PeerMessage, dst: &mut BytesMut) -> anyhow::Result<()> {
+        dst.extend_from_slice(&message.to_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: PeerMessage) {
+        let mut buffer = BytesMut::new();
+        MessageCodec.encode(message.clone(), &mut buffer).unwrap();
+        assert_eq!(MessageCodec.decode(&mut buffer).unwrap(), Some(message));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn round_trip_all_messages() {
+        round_trip(PeerMessage::KeepAlive);
+        round_trip(PeerMessage::Choke);
+        round_trip(PeerMessage::Unchoke);
+        round_trip(PeerMessage::Interested);
+        round_trip(PeerMessage::NotInterested);
+        round_trip(PeerMessage::Have(7));
+        round_trip(PeerMessage::Bitfield(vec![0b1010_0000, 0xff]));
+        round_trip(PeerMessage::Request {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        });
+        round_trip(PeerMessage::Piece {
+            index: 1,
+            begin: 0,
+            block: vec![1, 2, 3],
+        });
+        round_trip(PeerMessage::Cancel {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        });
+        round_trip(PeerMessage::Suggest(3));
+        round_trip(PeerMessage::HaveAll);
+        round_trip(PeerMessage::HaveNone);
+        round_trip(PeerMessage::RejectRequest {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        });
+        round_trip(PeerMessage::AllowedFast(4));
+        round_trip(PeerMessage::Port(6881));
+        round_trip(PeerMessage::Unknown {
+            id: 0x42,
+            payload: vec![1, 2],
+        });
+        round_trip(PeerMessage::Extended {
+            id: 0,
+            payload: b"de".to_vec(),
+        });
+    }
+
+    #[test]
+    fn encode_request() {
+        assert_eq!(
+            PeerMessage::Request {
+                index: 1,
+                begin: 2,
+                length: 3
+            }
+            .to_bytes(),
+            [0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]
+        );
+    }
+
+    #[test]
+    fn encode_keep_alive() {
+        assert_eq!(PeerMessage::KeepAlive.to_bytes(), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn encode_fast_extension_messages() {
+        assert_eq!(PeerMessage::HaveAll.to_bytes(), [0, 0, 0, 1, 0x0e]);
+        assert_eq!(PeerMessage::HaveNone.to_bytes(), [0, 0, 0, 1, 0x0f]);
+        assert_eq!(
+            PeerMessage::AllowedFast(2).to_bytes(),
+            [0, 0, 0, 5, 0x11, 0, 0, 0, 2]
+        );
+        assert!(PeerMessage::from_payload(&[0x0e, 0]).is_err());
+    }
+
+    #[test]
+    fn decode_consecutive_messages() {
+        let mut buffer = BytesMut::from(&[0, 0, 0, 1, 1, 0, 0, 0, 5, 4, 0, 0, 0, 9][..]);
+        assert_eq!(
+            MessageCodec.decode(&mut buffer).unwrap(),
+            Some(PeerMessage::Unchoke)
+        );
+        assert_eq!(
+            MessageCodec.decode(&mut buffer).unwrap(),
+            Some(PeerMessage::Have(9))
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn decode_keep_alives() {
+        // between other messages, back to back, and with the length arriving in parts
+        let mut buffer = BytesMut::from(&[0, 0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 0, 0][..]);
+        for expected in [PeerMessage::KeepAlive, PeerMessage::Interested] {
+            assert_eq!(MessageCodec.decode(&mut buffer).unwrap(), Some(expected));
+        }
+        assert_eq!(
+            MessageCodec.decode(&mut buffer).unwrap(),
+            Some(PeerMessage::KeepAlive)
+        );
+        assert_eq!(MessageCodec.decode(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(&[0, 0]);
+        assert_eq!(
+            MessageCodec.decode(&mut buffer).unwrap(),
+            Some(PeerMessage::KeepAlive)
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn refuse_overlong_messages() {
+        let length = MAX_MESSAGE_LENGTH as u32 + 1;
+        let mut buffer = BytesMut::from(&length.to_be_bytes()[..]);
+        assert!(MessageCodec.decode(&mut buffer).is_err());
+        // nothing was reserved for it
+        assert!(buffer.capacity() < MAX_MESSAGE_LENGTH);
+
+        let block = vec![7; 128 * 1024];
+        round_trip(PeerMessage::Piece {
+            index: 0,
+            begin: 0,
+            block,
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn decode_have_wrong_length() {
+        PeerMessage::from_payload(&[4, 0, 0, 1]).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn decode_extended_without_id() {
+        PeerMessage::from_payload(&[20]).unwrap();
+    }
+
+    #[test]
+    fn decode_unknown_id() {
+        assert_eq!(
+            PeerMessage::from_payload(&[42]).unwrap(),
+            PeerMessage::Unknown {
+                id: 42,
+                payload: Vec::new()
+            }
+        );
+        assert!(PeerMessage::from_payload(&[9, 0]).is_err());
+    }
+
+    #[test]
+    fn decode_incomplete_message() {
+        let mut buffer = BytesMut::from(&[0, 0, 0, 5, 4, 0][..]);
+        assert_eq!(MessageCodec.decode(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(&[0, 0, 3]);
+        assert_eq!(
+            MessageCodec.decode(&mut buffer).unwrap(),
+            Some(PeerMessage::Have(3))
+        );
+    }
+
+    #[test]
+    fn decode_incomplete_length_prefix() {
+        let mut buffer = BytesMut::from(&[0, 0][..]);
+        assert_eq!(MessageCodec.decode(&mut buffer).unwrap(), None);
+        assert_eq!(buffer.len(), 2);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::daemon::Daemon;
+use crate::stream::read_head;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+
+/// Where the metrics are served on their listener.
+pub const METRICS_PATH: &str = "/metrics";
+
+/// What a family of samples measures, for Prometheus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Only goes up, like bytes transferred.
+    Counter,
+    /// Goes up and down, like a rate.
+    Gauge,
+}
+
+/// Metrics in the Prometheus text exposition format, written a family at a time.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    text: String,
+    family: String,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Starts the family `name`, which the samples added after it belong to.
+    pub fn family(&mut self, name: &str, kind: Kind, help: &str) {
+        let kind = match kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        };
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+        self.family = name.to_owned();
+    }
+
+    /// Adds a sample of the current family, told apart from its others by `labels`.
+    pub fn sample(&mut self, labels: &[(&str, &str)], value: f64) {
+        self.text.push_str(&self.family);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+                .collect();
+            let _ = write!(self.text, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.text, " {}", value);
+    }
+
+    pub fn finish(self) -> String {
+        self.text
+    }
+}
+
+/// A label value with the characters the format gives a meaning escaped.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answers `GET /metrics` over HTTP on `listener` with the metrics of `daemon`, for Prometheus
+/// to scrape, until `shutdown` completes.
+pub async fn serve(
+    listener: TcpListener,
+    daemon: Arc<Daemon>,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    tokio::pin!(shutdown);
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let daemon = daemon.clone();
+                connections.spawn(async move {
+                    // a client that goes away only ends its own connection
+                    let _ = respond(stream, &daemon).await;
+                });
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            () = &mut shutdown => return Ok(()),
+        }
+    }
+}
+
+async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    daemon: &Daemon,
+) -> anyhow::Result<()> {
+    let head = read_head(&mut stream).await?;
+    let mut request = head.lines().next().unwrap_or_default().split(' ');
+    let (method, target) = (request.next(), request.next().unwrap_or_default());
+    let path = target.split('?').next().unwrap_or_default();
+    let response = match (method, path) {
+        (Some("GET"), METRICS_PATH) => {
+            let body = daemon.metrics();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        (Some("GET"), _) => {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+        }
+        _ => "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\nContent-Length: 0\r\n\
+              Connection: close\r\n\r\n"
+            .to_owned(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::DownloadOptions;
+    use crate::session::Session;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn text_format() {
+        let mut metrics = Metrics::new();
+        metrics.family("torrents", Kind::Gauge, "Torrents in the session.");
+        metrics.sample(&[], 2.0);
+        metrics.family("uploaded_bytes_total", Kind::Counter, "Bytes uploaded.");
+        metrics.sample(&[("name", "a \"b\"\\c\n"), ("hash", "00")], 1024.0);
+        metrics.sample(&[("name", "d"), ("hash", "01")], 0.5);
+        assert_eq!(
+            metrics.finish(),
+            "# HELP torrents Torrents in the session.\n\
+             # TYPE torrents gauge\n\
+             torrents 2\n\
+             # HELP uploaded_bytes_total Bytes uploaded.\n\
+             # TYPE uploaded_bytes_total counter\n\
+             uploaded_bytes_total{name=\"a \\\"b\\\"\\\\c\\n\",hash=\"00\"} 1024\n\
+             uploaded_bytes_total{name=\"d\",hash=\"01\"} 0.5\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_metrics_over_http() {
+        let options = DownloadOptions {
+            port: 0,
+            ..DownloadOptions::default()
+        };
+        let session = Session::new([1; 20], options).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let daemon = Arc::new(Daemon::new(session, dir.path()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, daemon, std::future::pending()));
+
+        let get = |request: &'static str| async move {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = get("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n# HELP "));
+        assert!(response.contains("\nbittorrent_torrents 0\n"));
+        let response = get("GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 "));
+        let response = get("POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 "));
+        server.abort();
+    }
+}
@@ -0,0 +1,621 @@
+use crate::net;
+use crate::utp::UtpStream;
+use anyhow::Context as _;
+use bytes::{Buf, BytesMut};
+use num_bigint::BigUint;
+use sha1::{Digest, Sha1};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+/// The 768-bit prime of the Diffie-Hellman key exchange; the generator is 2.
+const PRIME: &str =
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22\
+514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A3\
+6210000000000090563";
+/// Length of the public keys and the shared secret.
+const KEY_LENGTH: usize = 96;
+/// Random bytes either side may send after its public key, and at most that many.
+const MAX_PADDING: usize = 512;
+/// The verification constant, whose encryption tells where the encrypted part begins.
+const VC: [u8; 8] = [0; 8];
+/// The start of the RC4 keystream isn't used, as it leaks information about the key.
+const DISCARDED_KEYSTREAM: usize = 1024;
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/// Whether peer connections use Message Stream Encryption, which keeps networks that
+/// throttle or block BitTorrent traffic from recognising it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncryptionPolicy {
+    /// Connect in plaintext, and fall back to encryption for peers that insist on it.
+    #[default]
+    PreferPlaintext,
+    /// Connect encrypted, and fall back to plaintext for peers that don't support encryption.
+    PreferEncrypted,
+    /// Only use encrypted connections, both to and from peers.
+    RequireEncrypted,
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "prefer-plaintext" => Ok(EncryptionPolicy::PreferPlaintext),
+            "prefer-encrypted" => Ok(EncryptionPolicy::PreferEncrypted),
+            "require-encrypted" => Ok(EncryptionPolicy::RequireEncrypted),
+            _ => anyhow::bail!(
+                "unknown encryption policy: {} (expected prefer-plaintext, prefer-encrypted or \
+                 require-encrypted)",
+                s
+            ),
+        }
+    }
+}
+
+impl EncryptionPolicy {
+    /// The methods we offer when we open an encrypted connection.
+    fn provide(self) -> u32 {
+        match self {
+            EncryptionPolicy::RequireEncrypted => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        }
+    }
+
+    /// The method we pick from those a peer `provided` when it connects to us.
+    fn select(self, provided: u32) -> Option<u32> {
+        let preference = match self {
+            EncryptionPolicy::PreferPlaintext => [CRYPTO_PLAINTEXT, CRYPTO_RC4],
+            EncryptionPolicy::PreferEncrypted => [CRYPTO_RC4, CRYPTO_PLAINTEXT],
+            EncryptionPolicy::RequireEncrypted => [CRYPTO_RC4, CRYPTO_RC4],
+        };
+        preference
+            .into_iter()
+            .find(|&method| provided & method != 0)
+    }
+}
+
+/// The RC4 stream cipher; encrypting and decrypting are the same operation.
+#[derive(Clone)]
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Rc4 {
+        let mut state = [0; 256];
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[k as usize];
+        }
+    }
+}
+
+/// The ciphers of one end of an encrypted connection.
+struct Ciphers {
+    encrypt: Rc4,
+    decrypt: Rc4,
+}
+
+impl Ciphers {
+    /// Derives the keys from the shared `secret` and the info hash of the torrent (`SKEY`); the
+    /// side that opened the connection encrypts with key A, the other with key B.
+    fn new(secret: &[u8; KEY_LENGTH], info_hash: &[u8; 20], initiator: bool) -> Ciphers {
+        let cipher = |name: &[u8]| {
+            let mut rc4 = Rc4::new(&hash(&[name, secret, info_hash]));
+            rc4.apply(&mut [0; DISCARDED_KEYSTREAM]);
+            rc4
+        };
+        let (a, b) = (cipher(b"keyA"), cipher(b"keyB"));
+        if initiator {
+            Ciphers {
+                encrypt: a,
+                decrypt: b,
+            }
+        } else {
+            Ciphers {
+                encrypt: b,
+                decrypt: a,
+            }
+        }
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// One side's half of the Diffie-Hellman key exchange.
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LENGTH],
+}
+
+impl KeyPair {
+    fn generate() -> KeyPair {
+        let private = BigUint::from_bytes_be(&rand::random::<[u8; 20]>());
+        let public = BigUint::from(2u32).modpow(&private, &prime());
+        KeyPair {
+            private,
+            public: key_bytes(&public),
+        }
+    }
+
+    fn secret(&self, theirs: &[u8; KEY_LENGTH]) -> [u8; KEY_LENGTH] {
+        let theirs = BigUint::from_bytes_be(theirs);
+        key_bytes(&theirs.modpow(&self.private, &prime()))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME.as_bytes(), 16).expect("prime is valid hex")
+}
+
+/// `n` as a big-endian number of exactly `KEY_LENGTH` bytes.
+fn key_bytes(n: &BigUint) -> [u8; KEY_LENGTH] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0; KEY_LENGTH];
+    key[KEY_LENGTH - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+/// Our public key followed by a random amount of random padding.
+fn public_key_message(keys: &KeyPair) -> Vec<u8> {
+    let padding = rand::random_range(0..=MAX_PADDING);
+    let mut message = keys.public.to_vec();
+    message.extend((0..padding).map(|_| rand::random::<u8>()));
+    message
+}
+
+/// Reads from `stream` until the bytes read last are `pattern`, reading at most `limit` bytes.
+async fn read_until(stream: &mut Transport, pattern: &[u8], limit: usize) -> anyhow::Result<()> {
+    let mut read = Vec::with_capacity(limit);
+    while read.len() < limit {
+        read.push(stream.read_u8().await?);
+        if read.ends_with(pattern) {
+            return Ok(());
+        }
+    }
+    anyhow::bail!("peer didn't follow the encryption handshake")
+}
+
+/// Negotiates encryption on a connection we opened to a peer of the torrent with `info_hash`,
+/// offering the methods `policy` allows. The stream is plaintext afterwards if the peer chose
+/// so; either way the BitTorrent handshake comes next.
+pub(crate) async fn initiate(
+    mut stream: Transport,
+    info_hash: [u8; 20],
+    policy: EncryptionPolicy,
+) -> anyhow::Result<PeerStream> {
+    let keys = KeyPair::generate();
+    stream.write_all(&public_key_message(&keys)).await?;
+    let mut theirs = [0; KEY_LENGTH];
+    stream.read_exact(&mut theirs).await?;
+    let secret = keys.secret(&theirs);
+    let mut ciphers = Ciphers::new(&secret, &info_hash, true);
+
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(hash(&[b"req2", &info_hash]), hash(&[b"req3", &secret])));
+    let mut offer = VC.to_vec();
+    offer.extend(policy.provide().to_be_bytes());
+    // no padding, and no initial payload: the handshake follows once we know the method
+    offer.extend([0, 0, 0, 0]);
+    ciphers.encrypt.apply(&mut offer);
+    message.extend(offer);
+    stream.write_all(&message).await?;
+
+    // their padding comes first, so look for the encrypted verification constant
+    let mut vc = VC;
+    ciphers.decrypt.clone().apply(&mut vc);
+    read_until(&mut stream, &vc, MAX_PADDING + VC.len()).await?;
+    ciphers.decrypt.apply(&mut [0; VC.len()]);
+    let mut reply = [0; 6];
+    stream.read_exact(&mut reply).await?;
+    ciphers.decrypt.apply(&mut reply);
+    let selected = u32::from_be_bytes(reply[..4].try_into()?);
+    let mut padding = vec![0; u16::from_be_bytes([reply[4], reply[5]]) as usize];
+    if padding.len() > MAX_PADDING {
+        anyhow::bail!("peer sent {} bytes of padding", padding.len());
+    }
+    stream.read_exact(&mut padding).await?;
+    ciphers.decrypt.apply(&mut padding);
+
+    if selected & policy.provide() == 0 || selected.count_ones() != 1 {
+        anyhow::bail!("peer selected encryption method {:#x}", selected);
+    }
+    let cipher = (selected == CRYPTO_RC4).then_some(ciphers);
+    Ok(PeerStream::new(stream, &[], cipher))
+}
+
+/// Negotiates encryption on a connection a peer opened to us, whose first bytes (`start`) have
+/// been read already. Returns the stream, positioned at the peer's BitTorrent handshake, and
+/// which of `info_hashes` the peer asked for.
+pub(crate) async fn respond(
+    mut stream: Transport,
+    start: &[u8],
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> anyhow::Result<(PeerStream, [u8; 20])> {
+    let mut theirs = [0; KEY_LENGTH];
+    theirs[..start.len()].copy_from_slice(start);
+    stream.read_exact(&mut theirs[start.len()..]).await?;
+    let keys = KeyPair::generate();
+    stream.write_all(&public_key_message(&keys)).await?;
+    let secret = keys.secret(&theirs);
+
+    read_until(&mut stream, &hash(&[b"req1", &secret]), MAX_PADDING + 20).await?;
+    let mut requested = [0; 20];
+    stream.read_exact(&mut requested).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| xor(hash(&[b"req2", *info_hash]), req3) == requested)
+        .context("peer asked for a torrent we don't serve")?;
+    let mut ciphers = Ciphers::new(&secret, &info_hash, false);
+
+    let mut offer = [0; 14];
+    stream.read_exact(&mut offer).await?;
+    ciphers.decrypt.apply(&mut offer);
+    if offer[..8] != VC {
+        anyhow::bail!("peer sent the wrong verification constant");
+    }
+    let provided = u32::from_be_bytes(offer[8..12].try_into()?);
+    let mut padding = vec![0; u16::from_be_bytes([offer[12], offer[13]]) as usize];
+    if padding.len() > MAX_PADDING {
+        anyhow::bail!("peer sent {} bytes of padding", padding.len());
+    }
+    stream.read_exact(&mut padding).await?;
+    ciphers.decrypt.apply(&mut padding);
+    let mut length = [0; 2];
+    stream.read_exact(&mut length).await?;
+    ciphers.decrypt.apply(&mut length);
+    // the initial payload is usually the peer's handshake, which is always encrypted
+    let mut payload = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut payload).await?;
+    ciphers.decrypt.apply(&mut payload);
+
+    let selected = policy.select(provided).with_context(|| {
+        format!(
+            "peer offered no acceptable encryption method: {:#x}",
+            provided
+        )
+    })?;
+    let mut reply = VC.to_vec();
+    reply.extend(selected.to_be_bytes());
+    reply.extend([0, 0]);
+    ciphers.encrypt.apply(&mut reply);
+    stream.write_all(&reply).await?;
+    let cipher = (selected == CRYPTO_RC4).then_some(ciphers);
+    Ok((PeerStream::new(stream, &payload, cipher), info_hash))
+}
+
+/// The connection underneath a `PeerStream`.
+pub enum Transport {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl Transport {
+    /// The peer's address, an IPv4 one as such even over a dual-stack socket.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        let addr = match self {
+            Transport::Tcp(stream) => stream.peer_addr()?,
+            Transport::Utp(stream) => stream.peer_addr()?,
+        };
+        Ok(net::canonical(addr))
+    }
+}
+
+impl From<TcpStream> for Transport {
+    fn from(stream: TcpStream) -> Transport {
+        Transport::Tcp(stream)
+    }
+}
+
+impl From<UtpStream> for Transport {
+    fn from(stream: UtpStream) -> Transport {
+        Transport::Utp(stream)
+    }
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A connection to a peer, encrypted if the encryption handshake chose RC4.
+pub struct PeerStream {
+    stream: Transport,
+    /// Data read during the encryption handshake that comes after it, already decrypted.
+    read_ahead: BytesMut,
+    cipher: Option<Box<Ciphers>>,
+    /// Data encrypted by a write that the socket hasn't taken yet.
+    unsent: BytesMut,
+}
+
+impl PeerStream {
+    /// A connection that is not encrypted.
+    pub fn plaintext(stream: impl Into<Transport>) -> PeerStream {
+        PeerStream::new(stream.into(), &[], None)
+    }
+
+    /// A plaintext connection whose first bytes, `read_ahead`, were read from it already.
+    pub(crate) fn with_read_ahead(stream: Transport, read_ahead: &[u8]) -> PeerStream {
+        PeerStream::new(stream, read_ahead, None)
+    }
+
+    fn new(stream: Transport, read_ahead: &[u8], cipher: Option<Ciphers>) -> PeerStream {
+        PeerStream {
+            stream,
+            read_ahead: BytesMut::from(read_ahead),
+            cipher: cipher.map(Box::new),
+            unsent: BytesMut::new(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub fn is_utp(&self) -> bool {
+        matches!(self.stream, Transport::Utp(_))
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.read_ahead.is_empty() {
+            let n = this.read_ahead.len().min(buf.remaining());
+            buf.put_slice(&this.read_ahead[..n]);
+            this.read_ahead.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.cipher {
+            cipher.decrypt.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(cipher) = &mut this.cipher else {
+            return Pin::new(&mut this.stream).poll_write(cx, buf);
+        };
+        // the keystream has moved on, so encrypted data has to be sent before taking more
+        ready!(send_unsent(&mut this.stream, &mut this.unsent, cx))?;
+        this.unsent.extend_from_slice(buf);
+        cipher.encrypt.apply(&mut this.unsent);
+        // what the socket doesn't take now goes out with the next write or flush
+        if let Poll::Ready(Err(e)) = send_unsent(&mut this.stream, &mut this.unsent, cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(send_unsent(&mut this.stream, &mut this.unsent, cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(send_unsent(&mut this.stream, &mut this.unsent, cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+fn send_unsent(
+    stream: &mut Transport,
+    unsent: &mut BytesMut,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<()>> {
+    while !unsent.is_empty() {
+        let n = ready!(Pin::new(&mut *stream).poll_write(cx, unsent))?;
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        unsent.advance(n);
+    }
+    Poll::Ready(Ok(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn connected() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ours = TcpStream::connect(listener.local_addr().unwrap());
+        let (ours, theirs) = tokio::join!(ours, listener.accept());
+        (ours.unwrap(), theirs.unwrap().0)
+    }
+
+    /// Runs the encryption handshake between two peers with the given policies.
+    async fn negotiate(
+        initiator: EncryptionPolicy,
+        responder: EncryptionPolicy,
+    ) -> anyhow::Result<(PeerStream, PeerStream)> {
+        let (ours, mut theirs) = connected().await;
+        let responding = tokio::spawn(async move {
+            let mut start = [0; 20];
+            theirs.read_exact(&mut start).await?;
+            respond(theirs.into(), &start, &[[1; 20], [2; 20]], responder).await
+        });
+        let ours = initiate(ours.into(), [2; 20], initiator).await;
+        let (theirs, info_hash) = responding.await??;
+        assert_eq!(info_hash, [2; 20]);
+        Ok((ours?, theirs))
+    }
+
+    #[test]
+    fn rc4_test_vector() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+    }
+
+    #[test]
+    fn key_exchange_agrees_on_secret() {
+        let (a, b) = (KeyPair::generate(), KeyPair::generate());
+        assert_eq!(prime().bits(), 768);
+        assert_eq!(a.secret(&b.public), b.secret(&a.public));
+        assert_ne!(a.public, b.public);
+    }
+
+    #[test]
+    fn parse_policy() {
+        assert_eq!(
+            "require-encrypted".parse::<EncryptionPolicy>().unwrap(),
+            EncryptionPolicy::RequireEncrypted
+        );
+        assert!("always".parse::<EncryptionPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn encrypted_connection_round_trip() {
+        let (mut ours, mut theirs) = negotiate(
+            EncryptionPolicy::RequireEncrypted,
+            EncryptionPolicy::PreferPlaintext,
+        )
+        .await
+        .unwrap();
+        assert!(ours.is_encrypted() && theirs.is_encrypted());
+        let message = vec![7; 100_000];
+        let sending = {
+            let message = message.clone();
+            tokio::spawn(async move {
+                ours.write_all(&message).await.unwrap();
+                ours.flush().await.unwrap();
+                ours
+            })
+        };
+        let mut received = vec![0; message.len()];
+        theirs.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, message);
+        theirs.write_all(b"pong").await.unwrap();
+        let mut ours = sending.await.unwrap();
+        let mut reply = [0; 4];
+        ours.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"pong");
+    }
+
+    #[tokio::test]
+    async fn responder_picks_plaintext_when_preferred() {
+        let (ours, theirs) = negotiate(
+            EncryptionPolicy::PreferEncrypted,
+            EncryptionPolicy::PreferPlaintext,
+        )
+        .await
+        .unwrap();
+        assert!(!ours.is_encrypted() && !theirs.is_encrypted());
+        let (ours, theirs) = negotiate(
+            EncryptionPolicy::PreferEncrypted,
+            EncryptionPolicy::PreferEncrypted,
+        )
+        .await
+        .unwrap();
+        assert!(ours.is_encrypted() && theirs.is_encrypted());
+    }
+
+    #[tokio::test]
+    async fn unknown_torrent_is_refused() {
+        let (ours, mut theirs) = connected().await;
+        let responding = tokio::spawn(async move {
+            let mut start = [0; 20];
+            theirs.read_exact(&mut start).await?;
+            respond(
+                theirs.into(),
+                &start,
+                &[[1; 20]],
+                EncryptionPolicy::default(),
+            )
+            .await
+        });
+        let _ = initiate(ours.into(), [2; 20], EncryptionPolicy::default()).await;
+        assert!(responding.await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,435 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, ToSocketAddrs, UdpSocket};
+
+/// A local address, or a network interface, that the client's sockets are bound to, so its
+/// traffic only ever leaves that way, e.g. through a VPN.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bind {
+    Address(IpAddr),
+    /// Whatever addresses the interface of this name has when a socket is opened.
+    Interface(String),
+}
+
+impl FromStr for Bind {
+    type Err = anyhow::Error;
+
+    /// An IP address, or else the name of an interface.
+    fn from_str(s: &str) -> anyhow::Result<Bind> {
+        if let Ok(ip) = s.parse() {
+            return Ok(Bind::Address(ip));
+        }
+        if s.is_empty() || s.contains(char::is_whitespace) {
+            anyhow::bail!("not an IP address nor an interface name: {:?}", s);
+        }
+        Ok(Bind::Interface(s.to_owned()))
+    }
+}
+
+impl Bind {
+    /// The addresses to bind to, the IPv4 ones first.
+    pub fn addresses(&self) -> io::Result<Vec<IpAddr>> {
+        match self {
+            Bind::Address(ip) => Ok(vec![*ip]),
+            Bind::Interface(name) => {
+                let mut addresses = interface_addresses(name)?;
+                if addresses.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        format!("interface {} has no addresses", name),
+                    ));
+                }
+                addresses.sort_by_key(IpAddr::is_ipv6);
+                Ok(addresses)
+            }
+        }
+    }
+
+    /// The address to listen on: the first of them.
+    pub fn listen_address(&self) -> io::Result<IpAddr> {
+        Ok(self.addresses()?[0])
+    }
+
+    /// The address a socket that talks to `remote` is bound to: the first of the same family.
+    /// Without one, `remote` can't be reached this way at all.
+    pub fn address_for(&self, remote: IpAddr) -> io::Result<IpAddr> {
+        let ipv4 = remote.to_canonical().is_ipv4();
+        self.addresses()?
+            .into_iter()
+            .find(|ip| ip.is_ipv4() == ipv4)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("no local address of the family of {} to bind to", remote),
+                )
+            })
+    }
+}
+
+/// The addresses of the network interface `name`, but IPv6 link-local ones, which need a
+/// scope to be bound to.
+#[cfg(unix)]
+fn interface_addresses(name: &str) -> io::Result<Vec<IpAddr>> {
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut addresses = Vec::new();
+    let mut entry = list;
+    while !entry.is_null() {
+        // the entries stay valid until the list is freed below
+        let interface = unsafe { &*entry };
+        entry = interface.ifa_next;
+        let ifa_name = unsafe { std::ffi::CStr::from_ptr(interface.ifa_name) };
+        if ifa_name.to_bytes() != name.as_bytes() || interface.ifa_addr.is_null() {
+            continue;
+        }
+        let address = match i32::from(unsafe { (*interface.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let addr = unsafe { &*interface.ifa_addr.cast::<libc::sockaddr_in>() };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*interface.ifa_addr.cast::<libc::sockaddr_in6>() };
+                IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr))
+            }
+            _ => continue,
+        };
+        if !matches!(address, IpAddr::V6(ip) if ip.is_unicast_link_local()) {
+            addresses.push(address);
+        }
+    }
+    unsafe { libc::freeifaddrs(list) };
+    Ok(addresses)
+}
+
+#[cfg(not(unix))]
+fn interface_addresses(name: &str) -> io::Result<Vec<IpAddr>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("can't look up the addresses of interface {} here", name),
+    ))
+}
+
+/// Connects to `addr` over TCP, from the address of `bind` of the right family if there is
+/// one; a host name is tried at each of its addresses in turn.
+pub async fn connect_tcp(addr: impl ToSocketAddrs, bind: Option<&Bind>) -> io::Result<TcpStream> {
+    let Some(bind) = bind else {
+        return TcpStream::connect(addr).await;
+    };
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        let connected = async {
+            let socket = if addr.is_ipv4() {
+                TcpSocket::new_v4()?
+            } else {
+                TcpSocket::new_v6()?
+            };
+            socket.bind(SocketAddr::new(bind.address_for(addr.ip())?, 0))?;
+            socket.connect(addr).await
+        };
+        match connected.await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")))
+}
+
+/// An HTTP client whose requests go out from the address `bind` listens on, if any.
+pub fn http_client(bind: Option<&Bind>) -> io::Result<reqwest::ClientBuilder> {
+    let client = reqwest::Client::builder();
+    Ok(match bind {
+        Some(bind) => client.local_address(bind.listen_address()?),
+        None => client,
+    })
+}
+
+/// `addr` with an IPv4 address mapped into IPv6, the way dual-stack sockets report IPv4
+/// peers, turned back into the plain IPv4 one, so every peer is known by one address.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// `addr` in the address family of a socket bound to `local`: a dual-stack socket takes IPv4
+/// addresses only mapped into IPv6.
+pub fn for_socket(addr: SocketAddr, local: SocketAddr) -> SocketAddr {
+    match (addr, local) {
+        (SocketAddr::V4(v4), SocketAddr::V6(_)) => {
+            SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+        }
+        _ => addr,
+    }
+}
+
+/// Listens on `port` of every IPv6 and IPv4 address, or of every IPv4 address only where
+/// there is no IPv6.
+pub fn listen_tcp(port: u16) -> io::Result<TcpListener> {
+    let socket = bind_dual_stack(port, Type::STREAM, Protocol::TCP)?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Listens on `port` of the address of `bind`, or like `listen_tcp` without one.
+pub fn listen_tcp_on(port: u16, bind: Option<&Bind>) -> io::Result<TcpListener> {
+    let Some(bind) = bind else {
+        return listen_tcp(port);
+    };
+    let addr = SocketAddr::new(bind.listen_address()?, port);
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Listens on `addr` alone: on an IPv6 address, only over IPv6, so that a socket on an IPv4
+/// address can take the same port.
+pub fn listen_tcp_at(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = bind_at(addr, Type::STREAM, Protocol::TCP)?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Like `listen_tcp_at`, for a UDP socket.
+pub fn bind_udp_at(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = bind_at(addr, Type::DGRAM, Protocol::UDP)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// An address to listen on: `<ip>:<port>`, or an IP address alone for port 0, IPv6 ones in
+/// brackets if with a port.
+pub fn parse_listen(s: &str) -> anyhow::Result<SocketAddr> {
+    let bare = s.strip_prefix('[').and_then(|s| s.strip_suffix(']'));
+    if let Ok(ip) = bare.unwrap_or(s).parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, 0));
+    }
+    s.parse()
+        .map_err(|_| anyhow::anyhow!("not an address to listen on: {:?}", s))
+}
+
+/// A peer's address: `<ip>:<port>`, IPv6 ones in brackets, as in `[::1]:6881`.
+pub fn parse_peer(s: &str) -> anyhow::Result<SocketAddr> {
+    if let Ok(addr) = s.parse() {
+        return Ok(addr);
+    }
+    // without brackets, the port can't be told apart from the last group of the address
+    if let Some((ip, port)) = s.rsplit_once(':') {
+        if ip.parse::<Ipv6Addr>().is_ok() && port.parse::<u16>().is_ok() {
+            anyhow::bail!(
+                "IPv6 peer addresses go in brackets, as in [{}]:{}",
+                ip,
+                port
+            );
+        }
+    }
+    if s.parse::<Ipv6Addr>().is_ok() {
+        anyhow::bail!("IPv6 peer addresses go in brackets, as in [{}]:6881", s);
+    }
+    anyhow::bail!("not a peer address <ip>:<port>: {:?}", s)
+}
+
+/// The compact form of `addr`, as trackers, the DHT and peer exchange send them: the 4 or 16
+/// bytes of its IP address, then the 2 of its port, in network order.
+pub fn compact_addr(addr: SocketAddr) -> Vec<u8> {
+    let mut compact = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    compact.extend_from_slice(&addr.port().to_be_bytes());
+    compact
+}
+
+/// The address of a compact one, unless `bytes` is neither 6 nor 18 bytes long.
+pub fn parse_compact_addr(bytes: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = bytes.split_at_checked(bytes.len().checked_sub(2)?)?;
+    let ip = match ip.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(ip).unwrap()),
+        16 => IpAddr::from(<[u8; 16]>::try_from(ip).unwrap()),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+}
+
+/// Like `listen_tcp`, for a UDP socket.
+pub fn bind_udp(port: u16) -> io::Result<UdpSocket> {
+    let socket = bind_dual_stack(port, Type::DGRAM, Protocol::UDP)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Like `listen_tcp_on`, for a UDP socket.
+pub fn bind_udp_on(port: u16, bind: Option<&Bind>) -> io::Result<UdpSocket> {
+    let Some(bind) = bind else {
+        return bind_udp(port);
+    };
+    let socket = std::net::UdpSocket::bind((bind.listen_address()?, port))?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+fn bind_dual_stack(port: u16, kind: Type, protocol: Protocol) -> io::Result<Socket> {
+    let dual_stack = Socket::new(Domain::IPV6, kind, Some(protocol))
+        .and_then(|socket| socket.set_only_v6(false).map(|()| socket));
+    // a port that is taken is an error either way, only a missing IPv6 stack is made do with
+    let (socket, addr) = match dual_stack {
+        Ok(socket) => (socket, SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))),
+        Err(_) => (
+            Socket::new(Domain::IPV4, kind, Some(protocol))?,
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+        ),
+    };
+    if kind == Type::STREAM {
+        // like tokio does, so a restarted client gets its port back right away
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+fn bind_at(addr: SocketAddr, kind: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), kind, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    if kind == Type::STREAM {
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn address_families() {
+        let v4: SocketAddr = "1.2.3.4:5".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:1.2.3.4]:5".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:5".parse().unwrap();
+        assert_eq!(canonical(mapped), v4);
+        assert_eq!(canonical(v6), v6);
+        assert_eq!(for_socket(v4, "[::]:1".parse().unwrap()), mapped);
+        assert_eq!(for_socket(v4, "0.0.0.0:1".parse().unwrap()), v4);
+        assert_eq!(for_socket(v6, "[::]:1".parse().unwrap()), v6);
+    }
+
+    #[test]
+    fn parse_bind() {
+        assert_eq!(
+            "10.8.0.2".parse::<Bind>().unwrap(),
+            Bind::Address("10.8.0.2".parse().unwrap())
+        );
+        assert_eq!(
+            "wg0".parse::<Bind>().unwrap(),
+            Bind::Interface("wg0".to_owned())
+        );
+        assert!("".parse::<Bind>().is_err());
+        let bind = Bind::Address("127.0.0.1".parse().unwrap());
+        assert_eq!(
+            bind.address_for("::ffff:10.0.0.1".parse().unwrap())
+                .unwrap(),
+            bind.listen_address().unwrap()
+        );
+        assert!(bind.address_for("::1".parse().unwrap()).is_err());
+        assert!(Bind::Interface("no-such-interface0".to_owned())
+            .addresses()
+            .is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn binds_to_an_interface() {
+        let bind: Bind = "lo".parse().unwrap();
+        let listener = listen_tcp_on(0, Some(&bind)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(addr.ip().is_loopback());
+        let stream = connect_tcp(addr, Some(&bind)).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), addr.ip());
+        listener.accept().await.unwrap();
+    }
+
+    #[test]
+    fn parse_listen_addresses() {
+        assert_eq!(
+            parse_listen("0.0.0.0:6881").unwrap(),
+            SocketAddr::from(([0, 0, 0, 0], 6881))
+        );
+        assert_eq!(
+            parse_listen("[::]:6881").unwrap(),
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 6881))
+        );
+        assert_eq!(
+            parse_listen("::").unwrap(),
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        );
+        assert_eq!(parse_listen("[::1]").unwrap(), "[::1]:0".parse().unwrap());
+        assert!(parse_listen("wg0:6881").is_err());
+    }
+
+    #[test]
+    fn parse_peer_addresses() {
+        assert_eq!(
+            parse_peer("1.2.3.4:6881").unwrap(),
+            SocketAddr::from(([1, 2, 3, 4], 6881))
+        );
+        assert_eq!(
+            parse_peer("[::1]:6881").unwrap(),
+            SocketAddr::from((Ipv6Addr::LOCALHOST, 6881))
+        );
+        let unbracketed = parse_peer("2001:db8::1:6881").unwrap_err();
+        assert!(unbracketed.to_string().contains("[2001:db8::1]:6881"));
+        assert!(parse_peer("::1")
+            .unwrap_err()
+            .to_string()
+            .contains("[::1]:6881"));
+        assert!(parse_peer("1.2.3.4").is_err());
+        assert!(parse_peer("example.org:6881").is_err());
+    }
+
+    #[test]
+    fn compact_addresses() {
+        let v4 = SocketAddr::from(([1, 2, 3, 4], 0x1ae1));
+        assert_eq!(compact_addr(v4), [1, 2, 3, 4, 0x1a, 0xe1]);
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        assert_eq!(compact_addr(v6).len(), 18);
+        for addr in [v4, v6] {
+            assert_eq!(parse_compact_addr(&compact_addr(addr)), Some(addr));
+        }
+        assert_eq!(parse_compact_addr(&[1, 2, 3, 4, 5]), None);
+        assert_eq!(parse_compact_addr(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn shares_a_port_between_families() {
+        let v6 = listen_tcp_at("[::1]:0".parse().unwrap()).unwrap();
+        let port = v6.local_addr().unwrap().port();
+        let v4 = listen_tcp_at(SocketAddr::from(([127, 0, 0, 1], port))).unwrap();
+        let _client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        v4.accept().await.unwrap();
+        let taken = listen_tcp_at(SocketAddr::from(([127, 0, 0, 1], port))).unwrap_err();
+        assert_eq!(taken.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[tokio::test]
+    async fn listens_on_both_families() {
+        let listener = listen_tcp(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        for ip in ["127.0.0.1", "::1"] {
+            let ip: std::net::IpAddr = ip.parse().unwrap();
+            let _client = TcpStream::connect((ip, port)).await.unwrap();
+            let (_, from) = listener.accept().await.unwrap();
+            assert_eq!(canonical(from).ip(), ip);
+        }
+    }
+}
@@ -0,0 +1,545 @@
+use crate::mse::{self, EncryptionPolicy, PeerStream, Transport};
+use crate::net::Bind;
+use crate::proxy::{self, Proxy};
+use crate::utp::UtpSocket;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a peer gets to answer over uTP before we try TCP instead.
+const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a connection may go without us sending anything before we send a keep-alive, so
+/// that the peer doesn't take it for dead.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+/// How long a peer may send nothing at all, not even a keep-alive, before we hang up on it.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// How our peer ids start, in the Azureus style: the client's two letters and its version in
+/// four digits between dashes, here RS for version 0.1.0.
+pub const PEER_ID_PREFIX: &str = "-RS0100-";
+
+/// Makes up a peer id of `prefix` followed by random bytes. It is made once for every session,
+/// so that trackers and peers tell our sessions apart but know every connection of one.
+pub fn generate_peer_id(prefix: &str) -> anyhow::Result<[u8; 20]> {
+    if prefix.len() > 20 {
+        anyhow::bail!(
+            "peer id prefix {:?} is {} bytes long, at most 20 fit",
+            prefix,
+            prefix.len()
+        );
+    }
+    let mut peer_id: [u8; 20] = rand::random();
+    peer_id[..prefix.len()].copy_from_slice(prefix.as_bytes());
+    Ok(peer_id)
+}
+
+/// The 68-byte message that opens every peer connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub const LENGTH: usize = 1 + PROTOCOL.len() + 8 + 20 + 20;
+
+    /// Bit 20 from the right of the reserved bytes, advertising the BEP 10 extension protocol.
+    const EXTENSION_BIT: (usize, u8) = (5, 0x10);
+    /// Bit 3 from the right, advertising the Fast Extension (BEP 6).
+    const FAST_BIT: (usize, u8) = (7, 0x04);
+    /// The last bit, advertising a DHT node (BEP 5) whose port follows in a Port message.
+    const DHT_BIT: (usize, u8) = (7, 0x01);
+
+    /// Builds our handshake, which always advertises support for the extension protocol, the
+    /// Fast Extension and the DHT. Without a DHT of our own, the ports peers send are ignored.
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Handshake {
+        let mut reserved = [0; 8];
+        reserved[Handshake::EXTENSION_BIT.0] |= Handshake::EXTENSION_BIT.1;
+        reserved[Handshake::FAST_BIT.0] |= Handshake::FAST_BIT.1;
+        reserved[Handshake::DHT_BIT.0] |= Handshake::DHT_BIT.1;
+        Handshake {
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[Handshake::EXTENSION_BIT.0] & Handshake::EXTENSION_BIT.1 != 0
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[Handshake::FAST_BIT.0] & Handshake::FAST_BIT.1 != 0
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[Handshake::DHT_BIT.0] & Handshake::DHT_BIT.1 != 0
+    }
+
+    pub fn to_bytes(&self) -> [u8; Handshake::LENGTH] {
+        let mut bytes = [0; Handshake::LENGTH];
+        bytes[0] = PROTOCOL.len() as u8;
+        bytes[1..20].copy_from_slice(PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; Handshake::LENGTH]) -> crate::Result<Handshake> {
+        if bytes[0] as usize != PROTOCOL.len() || &bytes[1..20] != PROTOCOL {
+            return Err(crate::Error::PeerProtocol(anyhow::anyhow!(
+                "peer does not speak the BitTorrent protocol"
+            )));
+        }
+        // the ranges fit the fixed length of a handshake
+        Ok(Handshake {
+            reserved: bytes[20..28].try_into().unwrap(),
+            info_hash: bytes[28..48].try_into().unwrap(),
+            peer_id: bytes[48..68].try_into().unwrap(),
+        })
+    }
+}
+
+/// Sends our handshake over `stream` and reads and validates the peer's reply.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+) -> crate::Result<Handshake> {
+    let mut reply = [0; Handshake::LENGTH];
+    let exchange = async {
+        stream
+            .write_all(&Handshake::new(info_hash, peer_id).to_bytes())
+            .await?;
+        stream.read_exact(&mut reply).await
+    };
+    exchange.await.map_err(network)?;
+    let reply = Handshake::from_bytes(&reply)?;
+    if reply.info_hash != info_hash {
+        return Err(crate::Error::PeerProtocol(anyhow::anyhow!(
+            "peer replied with info hash {}, expected {}",
+            hex::encode(reply.info_hash),
+            hex::encode(info_hash)
+        )));
+    }
+    Ok(reply)
+}
+
+/// Reads the handshake of a peer that connected to us and answers it, provided the peer asks
+/// for one of `info_hashes`. The returned handshake tells which torrent it wants.
+pub async fn accept_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    info_hashes: &[[u8; 20]],
+    peer_id: [u8; 20],
+) -> crate::Result<Handshake> {
+    let exchange = async {
+        let mut theirs = [0; Handshake::LENGTH];
+        stream.read_exact(&mut theirs).await.map_err(network)?;
+        let theirs = Handshake::from_bytes(&theirs)?;
+        if !info_hashes.contains(&theirs.info_hash) {
+            return Err(crate::Error::PeerProtocol(anyhow::anyhow!(
+                "peer asked for info hash {}, which we don't serve",
+                hex::encode(theirs.info_hash)
+            )));
+        }
+        stream
+            .write_all(&Handshake::new(theirs.info_hash, peer_id).to_bytes())
+            .await
+            .map_err(network)?;
+        Ok(theirs)
+    };
+    tokio::time::timeout(CONNECT_TIMEOUT, exchange)
+        .await
+        .map_err(network)?
+}
+
+/// Connects to `addr` and performs the handshake, with the default encryption policy.
+pub async fn connect(
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+) -> crate::Result<(PeerStream, Handshake)> {
+    connect_with(
+        addr,
+        info_hash,
+        peer_id,
+        EncryptionPolicy::default(),
+        None,
+        None,
+        None,
+    )
+    .await
+}
+
+/// Connects to `addr` and performs the handshake, encrypted or not as `encryption` asks. A
+/// peer that rejects the preferred kind of connection is connected to again with the other,
+/// unless encryption is required. With a `utp` socket, the peer is tried over uTP first and
+/// over TCP if it doesn't answer. With a `proxy`, the peer is connected to through it, over
+/// TCP only. TCP connections go out from `bind`, if given.
+pub async fn connect_with(
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    encryption: EncryptionPolicy,
+    utp: Option<&UtpSocket>,
+    proxy: Option<&Proxy>,
+    bind: Option<&Bind>,
+) -> crate::Result<(PeerStream, Handshake)> {
+    let attempts: &[bool] = match encryption {
+        EncryptionPolicy::PreferPlaintext => &[false, true],
+        EncryptionPolicy::PreferEncrypted => &[true, false],
+        EncryptionPolicy::RequireEncrypted => &[true],
+    };
+    let mut last_error = None;
+    for &encrypted in attempts {
+        let stream = match open(addr, utp, proxy, bind).await {
+            Ok(stream) => stream,
+            // the error of the previous attempt says more than the peer going away after it
+            Err(_) if last_error.is_some() => break,
+            Err(e) => return Err(network(e)),
+        };
+        let attempt = async {
+            let mut stream = if encrypted {
+                mse::initiate(stream, info_hash, encryption)
+                    .await
+                    .map_err(crate::Error::PeerProtocol)?
+            } else {
+                PeerStream::plaintext(stream)
+            };
+            let reply = handshake(&mut stream, info_hash, peer_id).await?;
+            crate::Result::Ok((stream, reply))
+        };
+        match tokio::time::timeout(CONNECT_TIMEOUT, attempt).await {
+            Ok(Ok(connected)) => {
+                debug!(%addr, encrypted, "connected");
+                return Ok(connected);
+            }
+            Ok(Err(e)) => {
+                debug!(%addr, encrypted, "handshake failed: {:#}", e);
+                last_error = Some(e);
+            }
+            Err(e) => {
+                debug!(%addr, encrypted, "handshake timed out");
+                last_error = Some(network(e));
+            }
+        }
+    }
+    Err(last_error.expect("every attempt failed"))
+}
+
+/// Opens a connection to `addr`, over uTP if there is a `utp` socket and the peer answers on
+/// it, and over TCP otherwise. uTP can't go through a proxy, so it isn't tried with one.
+async fn open(
+    addr: SocketAddr,
+    utp: Option<&UtpSocket>,
+    proxy: Option<&Proxy>,
+    bind: Option<&Bind>,
+) -> anyhow::Result<Transport> {
+    if let Some(utp) = utp.filter(|_| proxy.is_none()) {
+        if let Ok(Ok(stream)) = tokio::time::timeout(UTP_CONNECT_TIMEOUT, utp.connect(addr)).await {
+            return Ok(stream.into());
+        }
+    }
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, proxy::connect(addr, proxy, bind)).await??;
+    Ok(stream.into())
+}
+
+/// Accepts a connection a peer opened to us: negotiates encryption if the peer starts with
+/// it, then answers its handshake like `accept_handshake`. Plaintext connections are refused
+/// if `encryption` requires encryption.
+pub async fn accept(
+    stream: impl Into<Transport>,
+    info_hashes: &[[u8; 20]],
+    peer_id: [u8; 20],
+    encryption: EncryptionPolicy,
+) -> crate::Result<(PeerStream, Handshake)> {
+    let mut stream = stream.into();
+    let negotiate = async {
+        // a plaintext handshake starts with the protocol name, an encrypted one with a key
+        let mut start = [0; 1 + PROTOCOL.len()];
+        stream.read_exact(&mut start).await.map_err(network)?;
+        if start[0] as usize == PROTOCOL.len() && &start[1..] == PROTOCOL {
+            if encryption == EncryptionPolicy::RequireEncrypted {
+                return Err(crate::Error::PeerProtocol(anyhow::anyhow!(
+                    "peer didn't encrypt the connection"
+                )));
+            }
+            Ok((PeerStream::with_read_ahead(stream, &start), None))
+        } else {
+            let (stream, info_hash) = mse::respond(stream, &start, info_hashes, encryption)
+                .await
+                .map_err(crate::Error::PeerProtocol)?;
+            Ok((stream, Some(info_hash)))
+        }
+    };
+    let (mut stream, negotiated) = tokio::time::timeout(CONNECT_TIMEOUT, negotiate)
+        .await
+        .map_err(network)??;
+    // an encrypted connection has already settled which torrent it is for
+    let info_hashes = match &negotiated {
+        Some(info_hash) => std::slice::from_ref(info_hash),
+        None => info_hashes,
+    };
+    let handshake = accept_handshake(&mut stream, info_hashes, peer_id).await?;
+    Ok((stream, handshake))
+}
+
+/// A connection that failed, timed out or was closed before the peer said anything wrong.
+fn network(error: impl Into<anyhow::Error>) -> crate::Error {
+    crate::Error::Network(error.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    async fn spawn_peer(reply: Handshake) -> (SocketAddr, JoinHandle<Handshake>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = [0; Handshake::LENGTH];
+            stream.read_exact(&mut received).await.unwrap();
+            stream.write_all(&reply.to_bytes()).await.unwrap();
+            Handshake::from_bytes(&received).unwrap()
+        });
+        (addr, peer)
+    }
+
+    #[test]
+    fn handshake_bytes_round_trip() {
+        let handshake = Handshake::new([1; 20], [2; 20]);
+        let bytes = handshake.to_bytes();
+        assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+        assert_eq!(&bytes[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+        assert!(handshake.supports_extensions());
+        assert!(handshake.supports_fast());
+        assert!(handshake.supports_dht());
+        assert_eq!(Handshake::from_bytes(&bytes).unwrap(), handshake);
+    }
+
+    #[test]
+    fn generated_peer_ids() {
+        let peer_id = generate_peer_id(PEER_ID_PREFIX).unwrap();
+        assert!(peer_id.starts_with(b"-RS0100-"));
+        // the rest is new every time
+        assert_ne!(peer_id, generate_peer_id(PEER_ID_PREFIX).unwrap());
+        assert!(generate_peer_id("-XX9999-")
+            .unwrap()
+            .starts_with(b"-XX9999-"));
+        assert!(generate_peer_id("").is_ok());
+        assert!(generate_peer_id(&"x".repeat(20)).is_ok());
+        assert!(generate_peer_id(&"x".repeat(21)).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn handshake_invalid_protocol() {
+        let mut bytes = Handshake::new([1; 20], [2; 20]).to_bytes();
+        bytes[1] = b'X';
+        Handshake::from_bytes(&bytes).unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_valid() {
+        let (addr, peer) = spawn_peer(Handshake::new([1; 20], [9; 20])).await;
+        let (_, reply) = connect(addr, [1; 20], [2; 20]).await.unwrap();
+        assert_eq!(reply.peer_id, [9; 20]);
+        assert_eq!(peer.await.unwrap(), Handshake::new([1; 20], [2; 20]));
+    }
+
+    #[tokio::test]
+    #[should_panic]
+    async fn connect_info_hash_mismatch() {
+        let (addr, _peer) = spawn_peer(Handshake::new([3; 20], [9; 20])).await;
+        connect(addr, [1; 20], [2; 20]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn accept_known_info_hash() {
+        let (mut ours, mut theirs) = tokio::io::duplex(1024);
+        let peer =
+            tokio::spawn(async move { handshake(&mut theirs, [3; 20], [9; 20]).await.unwrap() });
+        let request = accept_handshake(&mut ours, &[[1; 20], [3; 20]], [2; 20])
+            .await
+            .unwrap();
+        assert_eq!(request.info_hash, [3; 20]);
+        assert_eq!(request.peer_id, [9; 20]);
+        assert_eq!(peer.await.unwrap().peer_id, [2; 20]);
+    }
+
+    /// Accepts connections with `accept` until one of them succeeds.
+    async fn spawn_acceptor(encryption: EncryptionPolicy) -> (SocketAddr, JoinHandle<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                if let Ok((stream, _)) = accept(stream, &[[1; 20]], [9; 20], encryption).await {
+                    return stream.is_encrypted();
+                }
+            }
+        });
+        (addr, acceptor)
+    }
+
+    #[tokio::test]
+    async fn connect_encrypted() {
+        let (addr, acceptor) = spawn_acceptor(EncryptionPolicy::PreferPlaintext).await;
+        let (stream, reply) = connect_with(
+            addr,
+            [1; 20],
+            [2; 20],
+            EncryptionPolicy::RequireEncrypted,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.peer_id, [9; 20]);
+        assert!(stream.is_encrypted());
+        assert!(acceptor.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn connect_falls_back_to_encryption() {
+        let (addr, acceptor) = spawn_acceptor(EncryptionPolicy::RequireEncrypted).await;
+        let (stream, _) = connect(addr, [1; 20], [2; 20]).await.unwrap();
+        assert!(stream.is_encrypted());
+        assert!(acceptor.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn connect_falls_back_to_plaintext() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // a peer that only knows plaintext takes the encryption handshake for a bad handshake
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                if accept_handshake(&mut stream, &[[1; 20]], [9; 20])
+                    .await
+                    .is_ok()
+                {
+                    return stream;
+                }
+            }
+        });
+        let (stream, reply) = connect_with(
+            addr,
+            [1; 20],
+            [2; 20],
+            EncryptionPolicy::PreferEncrypted,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.peer_id, [9; 20]);
+        assert!(!stream.is_encrypted());
+    }
+
+    #[tokio::test]
+    async fn required_encryption_is_not_given_up() {
+        let (addr, _acceptor) = spawn_acceptor(EncryptionPolicy::PreferPlaintext).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let plaintext_only = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = accept_handshake(&mut stream, &[[1; 20]], [9; 20]).await;
+        });
+        assert!(connect_with(
+            plaintext_only,
+            [1; 20],
+            [2; 20],
+            EncryptionPolicy::RequireEncrypted,
+            None,
+            None,
+            None
+        )
+        .await
+        .is_err());
+        let (stream, _) = connect_with(
+            addr,
+            [1; 20],
+            [2; 20],
+            EncryptionPolicy::RequireEncrypted,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(stream.is_encrypted());
+    }
+
+    #[tokio::test]
+    async fn connect_over_utp() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let acceptor = tokio::spawn(async move {
+            let (stream, _) = server.accept().await.unwrap();
+            let (stream, _) = accept(stream, &[[1; 20]], [9; 20], EncryptionPolicy::default())
+                .await
+                .unwrap();
+            stream.is_utp()
+        });
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let encryption = EncryptionPolicy::RequireEncrypted;
+        let (stream, reply) = connect_with(
+            addr,
+            [1; 20],
+            [2; 20],
+            encryption,
+            Some(&client),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.peer_id, [9; 20]);
+        assert!(stream.is_utp());
+        assert!(stream.is_encrypted());
+        assert!(acceptor.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn connect_falls_back_to_tcp() {
+        let (addr, acceptor) = spawn_acceptor(EncryptionPolicy::PreferPlaintext).await;
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let encryption = EncryptionPolicy::default();
+        let (stream, _) = connect_with(
+            addr,
+            [1; 20],
+            [2; 20],
+            encryption,
+            Some(&client),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!stream.is_utp());
+        assert!(!acceptor.await.unwrap());
+    }
+
+    #[tokio::test]
+    #[should_panic]
+    async fn accept_unknown_info_hash() {
+        let (mut ours, mut theirs) = tokio::io::duplex(1024);
+        tokio::spawn(async move { handshake(&mut theirs, [3; 20], [9; 20]).await });
+        accept_handshake(&mut ours, &[[1; 20]], [2; 20])
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,538 @@
+use crate::bitfield::Bitfield;
+use crate::filemap::FileMap;
+use crate::torrent::Torrent;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing::trace;
+
+/// How many of the next pieces in order a sequential download fetches before turning to
+/// others.
+pub const READAHEAD: usize = 8;
+/// How close to its deadline a piece in flight has to be before it is handed to another peer
+/// as well.
+pub const DEADLINE_MARGIN: Duration = Duration::from_secs(2);
+/// Most peers a piece with a deadline is downloaded from at once before endgame.
+pub const MAX_DEADLINE_COPIES: usize = 2;
+
+/// How eagerly a file, or a piece, is downloaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Not downloaded at all.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "skip" => Ok(Priority::Skip),
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => anyhow::bail!(
+                "unknown priority: {} (expected skip, low, normal or high)",
+                s
+            ),
+        }
+    }
+}
+
+/// The priority of every piece of `torrent` given those of its files, in the order of
+/// `Torrent::files`: that of the most wanted file the piece holds data of. Files past the end
+/// of `file_priorities` are `Normal`, so with none given every piece is.
+pub fn piece_priorities(
+    torrent: &Torrent,
+    file_priorities: &[Priority],
+) -> anyhow::Result<Vec<Priority>> {
+    let piece_count = torrent.piece_count();
+    if file_priorities.is_empty() {
+        return Ok(vec![Priority::Normal; piece_count]);
+    }
+    let map = FileMap::new(torrent)?;
+    let file_count = map.spans().len();
+    if file_priorities.len() > file_count {
+        anyhow::bail!(
+            "got priorities for {} files, the torrent has {}",
+            file_priorities.len(),
+            file_count
+        );
+    }
+    let mut priorities = vec![Priority::Skip; piece_count];
+    for index in 0..file_count {
+        let priority = file_priorities.get(index).copied().unwrap_or_default();
+        for piece in &mut priorities[map.pieces_of(index)] {
+            *piece = (*piece).max(priority);
+        }
+    }
+    Ok(priorities)
+}
+
+/// The first and last piece of every file of `torrent`, in order and each once, e.g. for
+/// `PiecePicker::set_edges`. Empty files have none.
+pub fn file_edges(torrent: &Torrent) -> anyhow::Result<Vec<usize>> {
+    let map = FileMap::new(torrent)?;
+    let mut edges: Vec<usize> = (0..map.spans().len())
+        .map(|file| map.pieces_of(file))
+        .filter(|pieces| !pieces.is_empty())
+        .flat_map(|pieces| [pieces.start, pieces.end - 1])
+        .collect();
+    edges.dedup();
+    Ok(edges)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceState {
+    Missing,
+    /// Being downloaded by this many peers; more than one only in endgame.
+    InFlight(usize),
+    Done,
+}
+
+/// Decides which piece to download next, preferring the pieces of the highest priority and
+/// among those the ones fewest peers have. `Skip` pieces are never picked.
+///
+/// Availability is counted from the bitfields of the connected peers, so a peer's bitfield has
+/// to be removed again when it disconnects.
+///
+/// In sequential mode, the `READAHEAD` pieces following the first one not done yet are picked
+/// in order instead, so the data can be used from the start while the rest is downloading. A
+/// peer that has none of them still gets the rarest piece elsewhere. The pieces in order start
+/// from the position set with `set_position`, e.g. where a reader seeked to.
+///
+/// Pieces given a deadline with `set_deadline` come before all others, the earliest deadline
+/// first, until they are done. One that is still in flight within `DEADLINE_MARGIN` of its
+/// deadline is handed to another peer too, up to `MAX_DEADLINE_COPIES` of them.
+///
+/// Pieces set with `set_edges`, e.g. the first and last piece of every file, come before the
+/// others of the same priority, the rarest of them first, so that media can be previewed and
+/// archives opened early.
+#[derive(Debug, Clone)]
+pub struct PiecePicker {
+    availability: Vec<usize>,
+    state: Vec<PieceState>,
+    priority: Vec<Priority>,
+    /// Whether each piece is one of those set with `set_edges`.
+    edges: Vec<bool>,
+    sequential: bool,
+    position: usize,
+    deadlines: HashMap<usize, Instant>,
+}
+
+impl PiecePicker {
+    pub fn new(piece_count: usize) -> PiecePicker {
+        PiecePicker {
+            availability: vec![0; piece_count],
+            state: vec![PieceState::Missing; piece_count],
+            priority: vec![Priority::Normal; piece_count],
+            edges: vec![false; piece_count],
+            sequential: false,
+            position: 0,
+            deadlines: HashMap::new(),
+        }
+    }
+
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    /// Makes a sequential download go on in order from the piece at `index`.
+    pub fn set_position(&mut self, index: usize) {
+        self.position = index;
+    }
+
+    /// Replaces the priorities of all pieces, e.g. with those from `piece_priorities`. Pieces
+    /// already in flight are still finished.
+    pub fn set_priorities(&mut self, priorities: &[Priority]) {
+        self.priority.copy_from_slice(priorities);
+    }
+
+    /// Replaces the pieces picked ahead of the others of their priority, e.g. with those
+    /// from `file_edges`. Indices past the last piece are ignored.
+    pub fn set_edges(&mut self, pieces: &[usize]) {
+        self.edges.fill(false);
+        for &index in pieces {
+            if let Some(edge) = self.edges.get_mut(index) {
+                *edge = true;
+            }
+        }
+    }
+
+    /// Wants the piece at `index` by `deadline`, unless it is done already. An earlier
+    /// deadline for it is replaced.
+    pub fn set_deadline(&mut self, index: usize, deadline: Instant) {
+        if index < self.state.len() && !self.is_done(index) {
+            self.deadlines.insert(index, deadline);
+        }
+    }
+
+    /// Replaces the deadlines of all pieces, as by `set_deadline` for each of `deadlines`.
+    pub fn set_deadlines(&mut self, deadlines: &HashMap<usize, Instant>) {
+        self.deadlines.clear();
+        for (&index, &deadline) in deadlines {
+            self.set_deadline(index, deadline);
+        }
+    }
+
+    pub fn is_wanted(&self, index: usize) -> bool {
+        self.priority[index] != Priority::Skip
+    }
+
+    pub fn add_peer(&mut self, bitfield: &Bitfield) {
+        for index in self.pieces_in(bitfield) {
+            self.availability[index] += 1;
+        }
+    }
+
+    pub fn remove_peer(&mut self, bitfield: &Bitfield) {
+        for index in self.pieces_in(bitfield) {
+            self.availability[index] = self.availability[index].saturating_sub(1);
+        }
+    }
+
+    fn pieces_in<'a>(&self, bitfield: &'a Bitfield) -> impl Iterator<Item = usize> + 'a {
+        let piece_count = self.state.len();
+        bitfield
+            .ones()
+            .take_while(move |&index| index < piece_count)
+    }
+
+    /// Picks the rarest missing piece of the highest priority for which `has_piece` holds,
+    /// an edge one if there is any, and marks it in flight. Ties go to the lowest index.
+    ///
+    /// Once every wanted piece is done or in flight, this enters endgame: the in-flight piece with the
+    /// fewest downloaders is handed out again, so a slow peer can't hold up the last pieces.
+    pub fn pick(&mut self, has_piece: impl Fn(usize) -> bool) -> Option<usize> {
+        let endgame = !(0..self.state.len())
+            .any(|index| self.state[index] == PieceState::Missing && self.is_wanted(index));
+        let urgent = (!endgame)
+            .then(|| self.next_by_deadline(&has_piece, Instant::now()))
+            .flatten();
+        let index = if let Some(index) = urgent {
+            index
+        } else if endgame {
+            (0..self.state.len())
+                .filter(|&index| has_piece(index))
+                .filter_map(|index| match self.state[index] {
+                    PieceState::InFlight(copies) => Some((copies, index)),
+                    _ => None,
+                })
+                .min()?
+                .1
+        } else {
+            let in_order = self
+                .sequential
+                .then(|| self.next_in_order(&has_piece))
+                .flatten();
+            match in_order {
+                Some(index) => index,
+                None => (0..self.state.len())
+                    .filter(|&index| self.state[index] == PieceState::Missing)
+                    .filter(|&index| self.is_wanted(index) && has_piece(index))
+                    .min_by_key(|&index| {
+                        (
+                            Reverse(self.priority[index]),
+                            Reverse(self.edges[index]),
+                            self.availability[index],
+                        )
+                    })?,
+            }
+        };
+        self.state[index] = match self.state[index] {
+            PieceState::InFlight(copies) => PieceState::InFlight(copies + 1),
+            _ => PieceState::InFlight(1),
+        };
+        trace!(
+            piece = index,
+            endgame,
+            availability = self.availability[index],
+            "picked"
+        );
+        Some(index)
+    }
+
+    /// The first missing piece for which `has_piece` holds among the `READAHEAD` wanted pieces
+    /// from the first one at the position that isn't done yet.
+    fn next_in_order(&self, has_piece: &impl Fn(usize) -> bool) -> Option<usize> {
+        (self.position.min(self.state.len())..self.state.len())
+            .filter(|&index| self.is_wanted(index) && !self.is_done(index))
+            .take(READAHEAD)
+            .find(|&index| self.state[index] == PieceState::Missing && has_piece(index))
+    }
+
+    /// The wanted piece for which `has_piece` holds with the earliest deadline that is either
+    /// missing or in flight but close to its deadline at `now` and not yet downloaded from as
+    /// many peers as it may be.
+    fn next_by_deadline(&self, has_piece: &impl Fn(usize) -> bool, now: Instant) -> Option<usize> {
+        self.deadlines
+            .iter()
+            .filter(|&(&index, _)| self.is_wanted(index) && has_piece(index))
+            .filter(|&(&index, &deadline)| match self.state[index] {
+                PieceState::Missing => true,
+                PieceState::InFlight(copies) => {
+                    copies < MAX_DEADLINE_COPIES
+                        && deadline.saturating_duration_since(now) <= DEADLINE_MARGIN
+                }
+                PieceState::Done => false,
+            })
+            .map(|(&index, &deadline)| (deadline, index))
+            .min()
+            .map(|(_, index)| index)
+    }
+
+    /// Gives up one download of a piece; once no peer is left downloading it, it can be picked
+    /// again.
+    pub fn abort(&mut self, index: usize) {
+        self.state[index] = match self.state[index] {
+            PieceState::InFlight(1) => PieceState::Missing,
+            PieceState::InFlight(copies) => PieceState::InFlight(copies - 1),
+            state => state,
+        };
+    }
+
+    pub fn complete(&mut self, index: usize) {
+        self.state[index] = PieceState::Done;
+        self.deadlines.remove(&index);
+    }
+
+    pub fn is_done(&self, index: usize) -> bool {
+        self.state[index] == PieceState::Done
+    }
+
+    /// How many connected peers have each piece.
+    pub fn availability(&self) -> &[usize] {
+        &self.availability
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state
+            .iter()
+            .filter(|state| matches!(state, PieceState::InFlight(_)))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{multi_file_torrent_for, test_data};
+
+    /// A bitfield as a peer sends it, whatever the number of pieces.
+    fn bits(bytes: &[u8]) -> Bitfield {
+        Bitfield::from_bytes(bytes, bytes.len() * 8)
+    }
+
+    #[test]
+    fn picks_rarest_piece() {
+        let mut picker = PiecePicker::new(4);
+        picker.add_peer(&bits(&[0b1111_0000]));
+        picker.add_peer(&bits(&[0b1101_0000]));
+        picker.add_peer(&bits(&[0b1001_0000]));
+        // availability is now [3, 2, 1, 3]
+        assert_eq!(picker.pick(|_| true), Some(2));
+        assert_eq!(picker.pick(|_| true), Some(1));
+        assert_eq!(picker.pick(|_| true), Some(0));
+        assert_eq!(picker.pick(|_| true), Some(3));
+        assert_eq!(picker.in_flight(), 4);
+    }
+
+    #[test]
+    fn endgame_only_once_nothing_is_missing() {
+        let mut picker = PiecePicker::new(2);
+        assert_eq!(picker.pick(|index| index == 0), Some(0));
+        // piece 1 is still missing, so a peer with only piece 0 gets nothing
+        assert_eq!(picker.pick(|index| index == 0), None);
+        assert_eq!(picker.pick(|_| true), Some(1));
+        assert_eq!(picker.pick(|_| true), Some(0));
+        // piece 0 now has two downloaders, piece 1 one
+        assert_eq!(picker.pick(|_| true), Some(1));
+        picker.complete(1);
+        assert_eq!(picker.pick(|_| true), Some(0));
+        picker.complete(0);
+        assert_eq!(picker.pick(|_| true), None);
+        assert_eq!(picker.in_flight(), 0);
+    }
+
+    #[test]
+    fn endgame_abort_keeps_other_downloaders() {
+        let mut picker = PiecePicker::new(1);
+        assert_eq!(picker.pick(|_| true), Some(0));
+        assert_eq!(picker.pick(|_| true), Some(0));
+        picker.abort(0);
+        assert_eq!(picker.in_flight(), 1);
+        picker.abort(0);
+        assert_eq!(picker.in_flight(), 0);
+        assert!(!picker.is_done(0));
+    }
+
+    #[test]
+    fn picks_only_pieces_the_peer_has() {
+        let mut picker = PiecePicker::new(3);
+        picker.add_peer(&bits(&[0b0100_0000]));
+        assert_eq!(picker.pick(|index| index != 1), Some(0));
+    }
+
+    #[test]
+    fn aborted_piece_can_be_picked_again() {
+        let mut picker = PiecePicker::new(2);
+        assert_eq!(picker.pick(|_| true), Some(0));
+        picker.abort(0);
+        assert_eq!(picker.in_flight(), 0);
+        assert_eq!(picker.pick(|_| true), Some(0));
+        picker.complete(0);
+        picker.abort(0);
+        assert_eq!(picker.pick(|_| true), Some(1));
+        assert_eq!(picker.in_flight(), 1);
+    }
+
+    #[test]
+    fn removed_peer_no_longer_counts() {
+        let mut picker = PiecePicker::new(2);
+        picker.add_peer(&bits(&[0b1000_0000]));
+        picker.add_peer(&bits(&[0b1100_0000]));
+        assert_eq!(picker.pick(|_| true), Some(1));
+        picker.abort(1);
+        picker.remove_peer(&bits(&[0b1100_0000]));
+        picker.add_peer(&bits(&[0b0100_0000]));
+        picker.add_peer(&bits(&[0b0100_0000]));
+        assert_eq!(picker.pick(|_| true), Some(0));
+    }
+
+    #[test]
+    fn ignores_spare_bits() {
+        let mut picker = PiecePicker::new(3);
+        picker.add_peer(&bits(&[0xff, 0xff]));
+        picker.remove_peer(&bits(&[0xff, 0xff]));
+        assert_eq!(picker.pick(|_| true), Some(0));
+    }
+
+    #[test]
+    fn prefers_higher_priorities_and_skips() {
+        let mut picker = PiecePicker::new(4);
+        picker.add_peer(&bits(&[0b0100_0000]));
+        picker.set_priorities(&[
+            Priority::Normal,
+            Priority::High,
+            Priority::Skip,
+            Priority::Low,
+        ]);
+        // piece 1 is the most common one, but comes first anyway
+        assert_eq!(picker.pick(|_| true), Some(1));
+        assert_eq!(picker.pick(|_| true), Some(0));
+        assert_eq!(picker.pick(|_| true), Some(3));
+        // endgame doesn't hand out the skipped piece either
+        assert_eq!(picker.pick(|_| true), Some(0));
+        assert!(!picker.is_wanted(2));
+    }
+
+    #[test]
+    fn piece_priorities_follow_files() {
+        let data = test_data(1000);
+        let torrent =
+            multi_file_torrent_for(&data, 100, &[("a", 250), ("b", 0), ("c", 100), ("d", 650)]);
+        assert_eq!(
+            piece_priorities(&torrent, &[]).unwrap(),
+            [Priority::Normal; 10]
+        );
+        let priorities =
+            piece_priorities(&torrent, &[Priority::Skip, Priority::Skip, Priority::High]).unwrap();
+        // c covers bytes 250 to 350, sharing pieces with a and d
+        assert_eq!(
+            priorities[..4],
+            [
+                Priority::Skip,
+                Priority::Skip,
+                Priority::High,
+                Priority::High
+            ]
+        );
+        assert_eq!(priorities[4..], [Priority::Normal; 6]);
+        assert!(piece_priorities(&torrent, &[Priority::Skip; 5]).is_err());
+    }
+
+    #[test]
+    fn edges_come_first_within_their_priority() {
+        let data = test_data(1000);
+        let torrent =
+            multi_file_torrent_for(&data, 100, &[("a", 250), ("b", 0), ("c", 50), ("d", 700)]);
+        // c is all in piece 2, where a ends
+        assert_eq!(file_edges(&torrent).unwrap(), [0, 2, 3, 9]);
+
+        let mut picker = PiecePicker::new(10);
+        picker.set_edges(&[0, 2, 9, 12]);
+        picker.add_peer(&bits(&[0xff, 0b1100_0000]));
+        picker.add_peer(&bits(&[0b1000_0000, 0b0100_0000]));
+        // among the edges the rarest comes first, the others only after all of them
+        assert_eq!(picker.pick(|_| true), Some(2));
+        assert_eq!(picker.pick(|_| true), Some(0));
+        assert_eq!(picker.pick(|_| true), Some(9));
+        assert_eq!(picker.pick(|_| true), Some(1));
+        // a higher priority still wins over an edge
+        let mut picker = PiecePicker::new(4);
+        picker.set_edges(&[0]);
+        picker.set_priorities(&[
+            Priority::Normal,
+            Priority::High,
+            Priority::Normal,
+            Priority::Skip,
+        ]);
+        assert_eq!(picker.pick(|_| true), Some(1));
+        assert_eq!(picker.pick(|_| true), Some(0));
+        picker.set_edges(&[3]);
+        assert_eq!(picker.pick(|_| true), Some(2));
+    }
+
+    #[test]
+    fn sequential_picks_in_order_within_readahead() {
+        let mut picker = PiecePicker::new(READAHEAD + 4);
+        picker.set_sequential(true);
+        // the last piece is the rarest, but the first ones come first
+        picker.add_peer(&bits(&[0xff, 0b1110_0000]));
+        assert_eq!(picker.pick(|_| true), Some(0));
+        assert_eq!(picker.pick(|_| true), Some(1));
+        picker.complete(0);
+        // pieces 1 to READAHEAD are next; a peer with none of them gets the rarest one
+        let beyond = |index| index > READAHEAD;
+        assert_eq!(picker.pick(beyond), Some(READAHEAD + 3));
+        assert_eq!(picker.pick(|_| true), Some(2));
+        // after a seek the pieces from there come first
+        picker.set_position(6);
+        assert_eq!(picker.pick(|_| true), Some(6));
+        picker.set_sequential(false);
+        assert_eq!(picker.pick(|index| index > 2), Some(3));
+    }
+
+    #[test]
+    fn deadlines_come_first_and_duplicate_when_close() {
+        let mut picker = PiecePicker::new(5);
+        picker.add_peer(&bits(&[0b1111_0000]));
+        let later = Instant::now() + Duration::from_secs(60);
+        picker.set_deadline(3, later);
+        picker.set_deadline(2, Instant::now());
+        // piece 4 is the rarest, but the ones with deadlines come first, the earlier one first
+        assert_eq!(picker.pick(|_| true), Some(2));
+        // piece 2 is already due, so it goes to another peer too, but not a third
+        assert_eq!(picker.pick(|_| true), Some(2));
+        assert_eq!(picker.pick(|_| true), Some(3));
+        // piece 3 isn't due yet, so it isn't handed out twice
+        assert_eq!(picker.pick(|_| true), Some(4));
+        // a done piece takes no deadline any more
+        picker.complete(2);
+        picker.set_deadline(2, Instant::now());
+        assert_eq!(picker.pick(|_| true), Some(0));
+
+        // skipped pieces aren't downloaded despite a deadline
+        let mut picker = PiecePicker::new(3);
+        picker.set_priorities(&[Priority::Normal, Priority::Skip, Priority::Normal]);
+        picker.set_deadlines(&HashMap::from([(1, Instant::now()), (2, Instant::now())]));
+        assert_eq!(picker.pick(|_| true), Some(2));
+    }
+}
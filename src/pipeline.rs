@@ -0,0 +1,171 @@
+use crate::download::BLOCK_SIZE;
+use crate::stats::{Ewma, RATE_WEIGHT, ROUND_TRIP_WEIGHT};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How many seconds of a peer's download rate its request pipeline holds unless configured
+/// otherwise.
+pub const DEFAULT_REQUEST_QUEUE_TIME: Duration = Duration::from_secs(3);
+/// The most seconds `PipelineSizer` may be configured with; more can't keep any more peers
+/// busy, at most `MAX_PIPELINE_DEPTH` blocks are out anyway.
+pub const MAX_REQUEST_QUEUE_TIME: f64 = 60.0;
+/// The fewest block requests a measured peer is left with, so that it always has the next
+/// one while sending the last.
+pub const MIN_PIPELINE_DEPTH: usize = 2;
+/// The most block requests a peer is sent at once, however fast it is.
+pub const MAX_PIPELINE_DEPTH: usize = 500;
+/// A request queue time in seconds, from 0, for a fixed pipeline depth, up to
+/// `MAX_REQUEST_QUEUE_TIME`.
+pub fn parse_request_queue_time(value: &str) -> anyhow::Result<f64> {
+    let seconds: f64 = value.parse()?;
+    if !(0.0..=MAX_REQUEST_QUEUE_TIME).contains(&seconds) {
+        anyhow::bail!("must be between 0 and {} seconds", MAX_REQUEST_QUEUE_TIME);
+    }
+    Ok(seconds)
+}
+
+/// The `DownloadOptions::request_queue_time` of a number of seconds, none for 0.
+pub fn request_queue_time(seconds: f64) -> Option<Duration> {
+    Some(Duration::from_secs_f64(seconds)).filter(|time| !time.is_zero())
+}
+
+/// How long the blocks of one rate sample take to arrive at least. A longer gap between two
+/// blocks is the peer having nothing to send, e.g. while choking us, rather than being slow:
+/// the sample starts over after it.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Sizes a peer's request pipeline to how fast it delivers: deep enough to hold
+/// `queue_time` of its download rate, or its round trip if that is longer, so a fast peer
+/// is never left waiting for requests and a slow one isn't asked for blocks others could
+/// deliver sooner.
+#[derive(Debug, Clone)]
+pub struct PipelineSizer {
+    queue_time: Duration,
+    /// Bytes per second.
+    rate: Ewma,
+    /// Seconds.
+    round_trip: Ewma,
+    /// When the current rate sample started, and the bytes received since.
+    window: Option<(Instant, usize)>,
+    last_block: Option<Instant>,
+}
+
+impl PipelineSizer {
+    pub fn new(queue_time: Duration) -> PipelineSizer {
+        PipelineSizer {
+            queue_time,
+            rate: Ewma::new(RATE_WEIGHT),
+            round_trip: Ewma::new(ROUND_TRIP_WEIGHT),
+            window: None,
+            last_block: None,
+        }
+    }
+
+    /// Counts a block of `bytes` arriving at `now`, `round_trip` after it was requested.
+    pub fn received(&mut self, bytes: usize, round_trip: Option<Duration>, now: Instant) {
+        if let Some(round_trip) = round_trip {
+            self.round_trip.add(round_trip.as_secs_f64());
+        }
+        let idle = self
+            .last_block
+            .is_none_or(|last| now.duration_since(last) > RATE_WINDOW);
+        self.last_block = Some(now);
+        // the bytes of the block that starts a sample were on their way before it
+        let (start, received) = match self.window {
+            Some((start, received)) if !idle => (start, received + bytes),
+            _ => {
+                self.window = Some((now, 0));
+                return;
+            }
+        };
+        let elapsed = now.duration_since(start);
+        if elapsed < RATE_WINDOW {
+            self.window = Some((start, received));
+            return;
+        }
+        self.rate.add(received as f64 / elapsed.as_secs_f64());
+        self.window = Some((now, 0));
+    }
+
+    /// The download rate measured so far, in bytes per second.
+    pub fn rate(&self) -> Option<f64> {
+        self.rate.value
+    }
+
+    /// How many block requests to keep outstanding: `initial` until the rate is measured.
+    pub fn depth(&self, initial: usize) -> usize {
+        let Some(rate) = self.rate.value else {
+            return initial;
+        };
+        let round_trip = Duration::from_secs_f64(self.round_trip.value.unwrap_or_default());
+        let seconds = self.queue_time.max(round_trip).as_secs_f64();
+        let blocks = (rate * seconds / BLOCK_SIZE as f64).ceil() as usize;
+        blocks.clamp(MIN_PIPELINE_DEPTH, MAX_PIPELINE_DEPTH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `sizer` a block every `interval` for `count` blocks from `start`, each taking
+    /// `round_trip`; returns when the last arrived.
+    fn feed(
+        sizer: &mut PipelineSizer,
+        start: Instant,
+        interval: Duration,
+        count: u32,
+        round_trip: Duration,
+    ) -> Instant {
+        for i in 0..count {
+            sizer.received(BLOCK_SIZE as usize, Some(round_trip), start + interval * i);
+        }
+        start + interval * (count - 1)
+    }
+
+    #[test]
+    fn holds_seconds_of_the_rate() {
+        let mut sizer = PipelineSizer::new(Duration::from_secs(2));
+        assert_eq!(sizer.depth(10), 10);
+        // 100 blocks a second, for two seconds' worth of 200
+        let start = Instant::now();
+        let ms = Duration::from_millis(10);
+        feed(&mut sizer, start, ms, 301, ms);
+        let rate = sizer.rate().unwrap();
+        assert!((rate - 100.0 * BLOCK_SIZE as f64).abs() < 1.0, "{}", rate);
+        assert_eq!(sizer.depth(10), 200);
+
+        // a slow peer gets fewer, but never less than the minimum
+        let mut slow = PipelineSizer::new(Duration::from_secs(2));
+        feed(&mut slow, start, Duration::from_millis(500), 5, ms);
+        assert_eq!(slow.depth(10), 4);
+        let mut slower = PipelineSizer::new(Duration::from_secs(1));
+        feed(&mut slower, start, Duration::from_millis(900), 5, ms);
+        assert_eq!(slower.depth(10), MIN_PIPELINE_DEPTH);
+        // however fast a peer is, the pipeline stays within bounds
+        let mut fast = PipelineSizer::new(Duration::from_secs(10));
+        feed(&mut fast, start, Duration::from_micros(100), 20_001, ms);
+        assert_eq!(fast.depth(10), MAX_PIPELINE_DEPTH);
+    }
+
+    #[test]
+    fn covers_a_long_round_trip_and_skips_idle_time() {
+        let mut sizer = PipelineSizer::new(Duration::from_secs(1));
+        let start = Instant::now();
+        let ten_ms = Duration::from_millis(10);
+        let last = feed(&mut sizer, start, ten_ms, 101, Duration::from_secs(3));
+        let depth = sizer.depth(10);
+        assert!((290..=300).contains(&depth), "{}", depth);
+
+        // a peer that had nothing to send for a while isn't taken for a slow one
+        let rate = sizer.rate().unwrap();
+        feed(
+            &mut sizer,
+            last + Duration::from_secs(5),
+            ten_ms,
+            101,
+            ten_ms,
+        );
+        assert!((sizer.rate().unwrap() - rate).abs() < 1.0);
+    }
+}
@@ -0,0 +1,625 @@
+use anyhow::Context;
+use reqwest::{Client, Url};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// The port NAT-PMP gateways answer on (RFC 6886).
+pub const NAT_PMP_PORT: u16 = 5351;
+/// The multicast group UPnP devices are searched for on.
+pub const SSDP_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+/// How long mappings are asked for. They are renewed halfway through, and run out on their own
+/// if we never get to remove them.
+pub const LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// How long a gateway has to answer a UPnP search or request.
+const UPNP_TIMEOUT: Duration = Duration::from_secs(3);
+/// The kind of device searched for over SSDP.
+const INTERNET_GATEWAY: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    fn as_str(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        }
+    }
+
+    fn nat_pmp_opcode(self) -> u8 {
+        match self {
+            Protocol::Tcp => 2,
+            Protocol::Udp => 1,
+        }
+    }
+}
+
+/// A port the gateway forwards to us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub protocol: Protocol,
+    pub internal_port: u16,
+    /// The port peers on the internet reach us on. A NAT-PMP gateway may pick another one than
+    /// the one we asked for.
+    pub external_port: u16,
+    gateway: Gateway,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Gateway {
+    NatPmp(SocketAddr),
+    /// A UPnP Internet Gateway Device, by the control URL and type of its WAN connection
+    /// service.
+    Upnp {
+        control_url: String,
+        service_type: String,
+    },
+}
+
+/// Asks the gateway to forward `port` of `protocol` to us: through NAT-PMP if the default
+/// gateway speaks it, or else through whichever UPnP gateway answers a search.
+pub async fn map_port(protocol: Protocol, port: u16) -> anyhow::Result<Mapping> {
+    let nat_pmp = match default_gateway() {
+        Some(gateway) => {
+            let gateway = SocketAddr::from((gateway, NAT_PMP_PORT));
+            nat_pmp_map(gateway, protocol, port).await
+        }
+        None => Err(anyhow::anyhow!("no default gateway")),
+    };
+    let nat_pmp_error = match nat_pmp {
+        Ok(mapping) => return Ok(mapping),
+        Err(e) => e,
+    };
+    let upnp = async {
+        let location = ssdp_search(SSDP_GROUP.into()).await?;
+        upnp_map(&location, protocol, port).await
+    };
+    upnp.await
+        .map_err(|e| anyhow::anyhow!("NAT-PMP: {:#}; UPnP: {:#}", nat_pmp_error, e))
+}
+
+/// Maps `port` through the NAT-PMP gateway at `gateway`, asking for the same external port.
+pub async fn nat_pmp_map(
+    gateway: SocketAddr,
+    protocol: Protocol,
+    port: u16,
+) -> anyhow::Result<Mapping> {
+    let mut mapping = Mapping {
+        protocol,
+        internal_port: port,
+        external_port: port,
+        gateway: Gateway::NatPmp(gateway),
+    };
+    mapping.renew().await?;
+    Ok(mapping)
+}
+
+/// Maps `port` through the UPnP gateway whose device description is at `location`.
+pub async fn upnp_map(location: &str, protocol: Protocol, port: u16) -> anyhow::Result<Mapping> {
+    let description = client()?
+        .get(location)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let (control_url, service_type) = wan_connection_service(&description, location)?;
+    let mut mapping = Mapping {
+        protocol,
+        internal_port: port,
+        external_port: port,
+        gateway: Gateway::Upnp {
+            control_url,
+            service_type,
+        },
+    };
+    mapping.renew().await?;
+    Ok(mapping)
+}
+
+impl Mapping {
+    /// Asks for the mapping again, for another `LIFETIME`.
+    pub async fn renew(&mut self) -> anyhow::Result<()> {
+        let lifetime = LIFETIME.as_secs() as u32;
+        match &self.gateway {
+            Gateway::NatPmp(gateway) => {
+                let request = nat_pmp_request(
+                    self.protocol,
+                    self.internal_port,
+                    self.external_port,
+                    lifetime,
+                );
+                let response = nat_pmp_exchange(*gateway, &request).await?;
+                self.external_port = parse_nat_pmp_response(&response, self.protocol)?;
+            }
+            Gateway::Upnp {
+                control_url,
+                service_type,
+            } => {
+                let client_ip = local_ip_towards(control_url).await?;
+                let arguments = [
+                    ("NewRemoteHost", String::new()),
+                    ("NewExternalPort", self.external_port.to_string()),
+                    ("NewProtocol", self.protocol.as_str().to_string()),
+                    ("NewInternalPort", self.internal_port.to_string()),
+                    ("NewInternalClient", client_ip.to_string()),
+                    ("NewEnabled", "1".to_string()),
+                    ("NewPortMappingDescription", "bittorrent_client".to_string()),
+                    ("NewLeaseDuration", lifetime.to_string()),
+                ];
+                soap_call(control_url, service_type, "AddPortMapping", &arguments).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks the gateway to stop forwarding the port.
+    pub async fn remove(&self) -> anyhow::Result<()> {
+        match &self.gateway {
+            Gateway::NatPmp(gateway) => {
+                // a mapping is deleted by asking for no time and no external port
+                let request = nat_pmp_request(self.protocol, self.internal_port, 0, 0);
+                let response = nat_pmp_exchange(*gateway, &request).await?;
+                parse_nat_pmp_response(&response, self.protocol)?;
+            }
+            Gateway::Upnp {
+                control_url,
+                service_type,
+            } => {
+                let arguments = [
+                    ("NewRemoteHost", String::new()),
+                    ("NewExternalPort", self.external_port.to_string()),
+                    ("NewProtocol", self.protocol.as_str().to_string()),
+                ];
+                soap_call(control_url, service_type, "DeletePortMapping", &arguments).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps ports forwarded to us until it is stopped.
+pub struct PortMapper {
+    task: JoinHandle<()>,
+    stop: watch::Sender<bool>,
+}
+
+impl PortMapper {
+    /// Maps each of `ports` in the background, renewing the mappings before they run out, and
+    /// trying the ones that failed again at the same time.
+    pub fn spawn(ports: Vec<(Protocol, u16)>) -> PortMapper {
+        let (stop, stopped) = watch::channel(false);
+        let task = tokio::spawn(keep_mapped(ports, stopped));
+        PortMapper { task, stop }
+    }
+
+    /// Removes the mappings and stops.
+    pub async fn stop(mut self) {
+        self.stop.send_replace(true);
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for PortMapper {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn keep_mapped(ports: Vec<(Protocol, u16)>, mut stop: watch::Receiver<bool>) {
+    let mut mappings: Vec<Option<Mapping>> = vec![None; ports.len()];
+    loop {
+        let refresh = async {
+            for (&(protocol, port), mapping) in ports.iter().zip(&mut mappings) {
+                let result = match mapping {
+                    Some(mapping) => mapping.renew().await,
+                    None => map_port(protocol, port).await.map(|mapped| {
+                        info!(
+                            protocol = protocol.as_str(),
+                            port,
+                            external_port = mapped.external_port,
+                            "port mapped"
+                        );
+                        *mapping = Some(mapped);
+                    }),
+                };
+                if let Err(e) = result {
+                    warn!(
+                        protocol = protocol.as_str(),
+                        port, "can't map port: {:#}", e
+                    );
+                    *mapping = None;
+                }
+            }
+        };
+        tokio::select! {
+            () = refresh => {}
+            _ = stop.changed() => break,
+        }
+        tokio::select! {
+            () = tokio::time::sleep(LIFETIME / 2) => {}
+            _ = stop.changed() => break,
+        }
+    }
+    for mapping in mappings.into_iter().flatten() {
+        if let Err(e) = mapping.remove().await {
+            debug!(
+                port = mapping.internal_port,
+                "can't remove port mapping: {:#}", e
+            );
+        }
+    }
+}
+
+fn nat_pmp_request(protocol: Protocol, internal: u16, external: u16, lifetime: u32) -> [u8; 12] {
+    let mut request = [0; 12];
+    request[1] = protocol.nat_pmp_opcode();
+    request[4..6].copy_from_slice(&internal.to_be_bytes());
+    request[6..8].copy_from_slice(&external.to_be_bytes());
+    request[8..12].copy_from_slice(&lifetime.to_be_bytes());
+    request
+}
+
+/// The external port of a mapping response, or why the gateway refused it.
+fn parse_nat_pmp_response(response: &[u8], protocol: Protocol) -> anyhow::Result<u16> {
+    if response.len() < 16 || response[0] != 0 || response[1] != 128 + protocol.nat_pmp_opcode() {
+        anyhow::bail!("malformed NAT-PMP response");
+    }
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    let reason = match result {
+        0 => return Ok(u16::from_be_bytes([response[10], response[11]])),
+        1 => "unsupported version",
+        2 => "not authorized",
+        3 => "network failure",
+        4 => "out of resources",
+        5 => "unsupported opcode",
+        _ => "unknown error",
+    };
+    anyhow::bail!("gateway refused the mapping: {} ({})", reason, result)
+}
+
+/// Sends `request` to `gateway` until it answers, waiting twice as long every time.
+async fn nat_pmp_exchange(gateway: SocketAddr, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    socket.connect(gateway).await?;
+    let mut wait = Duration::from_millis(250);
+    for _ in 0..4 {
+        socket.send(request).await?;
+        let mut response = [0; 16];
+        if let Ok(received) = tokio::time::timeout(wait, socket.recv(&mut response)).await {
+            let length = received?;
+            return Ok(response[..length].to_vec());
+        }
+        wait *= 2;
+    }
+    anyhow::bail!("no answer from {}", gateway)
+}
+
+/// The IPv4 default gateway from the kernel's routing table; only known on Linux.
+fn default_gateway() -> Option<Ipv4Addr> {
+    parse_route_table(&std::fs::read_to_string("/proc/net/route").ok()?)
+}
+
+/// The gateway of the default route in the format of `/proc/net/route`, which shows addresses
+/// as hex numbers in the machine's byte order.
+fn parse_route_table(table: &str) -> Option<Ipv4Addr> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        (gateway != 0).then(|| Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+/// Searches `group` for an Internet Gateway Device, returning where its description is.
+async fn ssdp_search(group: SocketAddr) -> anyhow::Result<String> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n",
+        group, INTERNET_GATEWAY
+    );
+    socket.send_to(search.as_bytes(), group).await?;
+    let answer = async {
+        let mut response = [0; 2048];
+        loop {
+            let (length, _) = socket.recv_from(&mut response).await?;
+            if let Some(location) = ssdp_location(&response[..length]) {
+                return anyhow::Ok(location);
+            }
+        }
+    };
+    tokio::time::timeout(UPNP_TIMEOUT, answer)
+        .await
+        .context("no gateway answered")?
+}
+
+/// The `LOCATION` header of a successful search response.
+fn ssdp_location(response: &[u8]) -> Option<String> {
+    let text = std::str::from_utf8(response).ok()?;
+    let mut lines = text.split("\r\n");
+    if !lines.next()?.starts_with("HTTP/1.1 200") {
+        return None;
+    }
+    lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("location")
+            .then(|| value.trim().to_string())
+    })
+}
+
+/// The control URL and type of the WAN connection service in a device description fetched from
+/// `location`.
+fn wan_connection_service(description: &str, location: &str) -> anyhow::Result<(String, String)> {
+    for service in description.split("<service>").skip(1) {
+        let Some(service_type) = xml_value(service, "serviceType") else {
+            continue;
+        };
+        if !service_type.contains(":WANIPConnection:")
+            && !service_type.contains(":WANPPPConnection:")
+        {
+            continue;
+        }
+        let control_url = xml_value(service, "controlURL").context("service has no controlURL")?;
+        let control_url = Url::parse(location)?.join(control_url)?;
+        return Ok((control_url.to_string(), service_type.to_string()));
+    }
+    anyhow::bail!("gateway has no WAN connection service")
+}
+
+/// The text of the first `tag` element in `xml`.
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(xml[start..end].trim())
+}
+
+async fn soap_call(
+    control_url: &str,
+    service_type: &str,
+    action: &str,
+    arguments: &[(&str, String)],
+) -> anyhow::Result<()> {
+    let mut body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{} xmlns:u=\"{}\">",
+        action, service_type
+    );
+    for (name, value) in arguments {
+        body.push_str(&format!("<{}>{}</{}>", name, value, name));
+    }
+    body.push_str(&format!("</u:{}></s:Body></s:Envelope>", action));
+    let response = client()?
+        .post(control_url)
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header("SOAPAction", format!("\"{}#{}\"", service_type, action))
+        .body(body)
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        let fault = response.text().await.unwrap_or_default();
+        match xml_value(&fault, "errorDescription") {
+            Some(description) => anyhow::bail!("{} failed: {}", action, description),
+            None => anyhow::bail!("{} failed with {}", action, status),
+        }
+    }
+    Ok(())
+}
+
+fn client() -> anyhow::Result<Client> {
+    Ok(Client::builder().timeout(UPNP_TIMEOUT).build()?)
+}
+
+/// The address of ours that the host of `url` gets our traffic from, for it to forward to.
+async fn local_ip_towards(url: &str) -> anyhow::Result<IpAddr> {
+    let url = Url::parse(url)?;
+    let host = url.socket_addrs(|| Some(80))?;
+    let host = host.first().context("gateway has no address")?;
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    socket.connect(host).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn nat_pmp_messages() {
+        assert_eq!(
+            nat_pmp_request(Protocol::Tcp, 6881, 6881, 3600),
+            [0, 2, 0, 0, 0x1a, 0xe1, 0x1a, 0xe1, 0, 0, 0x0e, 0x10]
+        );
+        let mut response = [0; 16];
+        response[1] = 130;
+        response[8..10].copy_from_slice(&6881u16.to_be_bytes());
+        response[10..12].copy_from_slice(&7000u16.to_be_bytes());
+        assert_eq!(
+            parse_nat_pmp_response(&response, Protocol::Tcp).unwrap(),
+            7000
+        );
+        // the answer to another request, a refusal, and a short one
+        assert!(parse_nat_pmp_response(&response, Protocol::Udp).is_err());
+        response[3] = 2;
+        let refused = parse_nat_pmp_response(&response, Protocol::Tcp).unwrap_err();
+        assert!(refused.to_string().contains("not authorized"));
+        assert!(parse_nat_pmp_response(&response[..12], Protocol::Tcp).is_err());
+    }
+
+    #[test]
+    fn default_route() {
+        let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                     eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+                     eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\n";
+        if cfg!(target_endian = "little") {
+            assert_eq!(
+                parse_route_table(table),
+                Some(Ipv4Addr::new(192, 168, 1, 1))
+            );
+        }
+        assert_eq!(
+            parse_route_table(&table[..table.rfind("eth0").unwrap()]),
+            None
+        );
+    }
+
+    #[test]
+    fn ssdp_responses() {
+        let response = b"HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+                         Location: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(
+            ssdp_location(response).as_deref(),
+            Some("http://192.168.1.1:5000/rootDesc.xml")
+        );
+        assert_eq!(
+            ssdp_location(b"M-SEARCH * HTTP/1.1\r\nLOCATION: x\r\n\r\n"),
+            None
+        );
+    }
+
+    const DESCRIPTION: &str = "<root><device><serviceList>\
+        <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+        <controlURL>/ctl/L3F</controlURL></service>\
+        <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+        <controlURL>/ctl/IPConn</controlURL></service>\
+        </serviceList></device></root>";
+
+    #[test]
+    fn finds_the_wan_connection_service() {
+        let (control_url, service_type) =
+            wan_connection_service(DESCRIPTION, "http://192.168.1.1:5000/rootDesc.xml").unwrap();
+        assert_eq!(control_url, "http://192.168.1.1:5000/ctl/IPConn");
+        assert_eq!(
+            service_type,
+            "urn:schemas-upnp-org:service:WANIPConnection:1"
+        );
+        let other = DESCRIPTION.replace("WANIPConnection", "WANCommonInterfaceConfig");
+        assert!(wan_connection_service(&other, "http://192.168.1.1:5000/").is_err());
+    }
+
+    #[tokio::test]
+    async fn maps_through_nat_pmp() {
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = gateway.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let mut request = [0; 12];
+                let (_, from) = gateway.recv_from(&mut request).await.unwrap();
+                // the gateway gives us another external port than we asked for
+                let mut response = [0; 16];
+                response[1] = 128 + request[1];
+                response[8..10].copy_from_slice(&request[4..6]);
+                response[10..12].copy_from_slice(&7000u16.to_be_bytes());
+                response[12..16].copy_from_slice(&request[8..12]);
+                gateway.send_to(&response, from).await.unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+
+        let mapping = nat_pmp_map(addr, Protocol::Udp, 6881).await.unwrap();
+        assert_eq!(mapping.external_port, 7000);
+        mapping.remove().await.unwrap();
+        let requests = server.await.unwrap();
+        assert_eq!(
+            requests[0],
+            nat_pmp_request(Protocol::Udp, 6881, 6881, 3600)
+        );
+        assert_eq!(requests[1], nat_pmp_request(Protocol::Udp, 6881, 0, 0));
+    }
+
+    /// Serves the device description and answers every SOAP request, recording the bodies of
+    /// the requests.
+    async fn spawn_upnp_gateway() -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let location = format!("http://{}/rootDesc.xml", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).await.unwrap();
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).await.unwrap();
+                let reply = if head.starts_with("GET") {
+                    DESCRIPTION.to_string()
+                } else {
+                    log.lock().unwrap().push(format!(
+                        "{}{}",
+                        head,
+                        String::from_utf8(body).unwrap()
+                    ));
+                    String::new()
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    reply.len(),
+                    reply
+                );
+                reader
+                    .get_mut()
+                    .write_all(response.as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+        (location, requests)
+    }
+
+    #[tokio::test]
+    async fn maps_through_upnp() {
+        let (location, requests) = spawn_upnp_gateway().await;
+        let mapping = upnp_map(&location, Protocol::Tcp, 6881).await.unwrap();
+        assert_eq!(mapping.external_port, 6881);
+        mapping.remove().await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let (add, delete) = (&requests[0], &requests[1]);
+        assert!(add.starts_with("POST /ctl/IPConn "));
+        let action = "\"urn:schemas-upnp-org:service:WANIPConnection:1#AddPortMapping\"";
+        assert!(add
+            .to_lowercase()
+            .contains(&format!("soapaction: {}", action).to_lowercase()));
+        for argument in [
+            "<NewExternalPort>6881</NewExternalPort>",
+            "<NewProtocol>TCP</NewProtocol>",
+            "<NewInternalPort>6881</NewInternalPort>",
+            "<NewInternalClient>127.0.0.1</NewInternalClient>",
+            "<NewLeaseDuration>3600</NewLeaseDuration>",
+        ] {
+            assert!(add.contains(argument), "{}", argument);
+        }
+        assert!(delete.contains("<u:DeletePortMapping"));
+        assert!(delete.contains("<NewExternalPort>6881</NewExternalPort>"));
+    }
+}
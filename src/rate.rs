@@ -0,0 +1,172 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Caps the bytes per second passing through it with a token bucket, shared by every
+/// connection that goes through the same limiter.
+///
+/// The bucket holds up to a second's worth of bytes, so traffic may burst that much after a
+/// pause. A limiter with a `parent` also waits for the parent, e.g. a torrent's limiter for
+/// the one covering all torrents.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    parent: Option<Arc<RateLimiter>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second, or `None` for no limit.
+    rate: Option<usize>,
+    /// Bytes that may pass right away; negative once callers are waiting for more.
+    tokens: f64,
+    filled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<usize>) -> RateLimiter {
+        RateLimiter {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate.unwrap_or(0) as f64,
+                filled: Instant::now(),
+            }),
+            parent: None,
+        }
+    }
+
+    pub fn with_parent(rate: Option<usize>, parent: Arc<RateLimiter>) -> RateLimiter {
+        RateLimiter {
+            parent: Some(parent),
+            ..RateLimiter::new(rate)
+        }
+    }
+
+    pub fn unlimited() -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::new(None))
+    }
+
+    pub fn rate(&self) -> Option<usize> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Changes the limit; whatever is already waiting keeps its place.
+    pub fn set_rate(&self, rate: Option<usize>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.fill();
+        bucket.rate = rate;
+        bucket.tokens = bucket.tokens.min(rate.unwrap_or(0) as f64);
+    }
+
+    /// Waits until `bytes` more may pass, here and in the parents.
+    pub async fn acquire(&self, bytes: usize) {
+        let mut limiter = Some(self);
+        while let Some(current) = limiter {
+            let wait = current.bucket.lock().unwrap().take(bytes);
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+            limiter = current.parent.as_deref();
+        }
+    }
+}
+
+impl Bucket {
+    fn fill(&mut self) {
+        let now = Instant::now();
+        if let Some(rate) = self.rate {
+            let earned = now.duration_since(self.filled).as_secs_f64() * rate as f64;
+            self.tokens = (self.tokens + earned).min(rate as f64);
+        }
+        self.filled = now;
+    }
+
+    /// Takes `bytes` out of the bucket, going into debt if there aren't enough, and tells how
+    /// long to wait until the debt is paid off.
+    fn take(&mut self, bytes: usize) -> Duration {
+        let Some(rate) = self.rate.filter(|&rate| rate > 0) else {
+            return Duration::ZERO;
+        };
+        self.fill();
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / rate as f64)
+    }
+}
+
+/// A rate in bytes per second, optionally with a K, M or G suffix for the binary multiples.
+pub fn parse_rate(value: &str) -> anyhow::Result<usize> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, suffix)) if suffix.is_ascii_alphabetic() => {
+            let unit = match suffix.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                _ => anyhow::bail!("unknown unit {:?}, use K, M or G", suffix),
+            };
+            (&value[..i], unit)
+        }
+        _ => (value, 1),
+    };
+    let rate = number.parse::<usize>()?.saturating_mul(unit);
+    if rate == 0 {
+        anyhow::bail!("must be at least 1");
+    }
+    Ok(rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rates() {
+        assert_eq!(parse_rate("1500").unwrap(), 1500);
+        assert_eq!(parse_rate("500K").unwrap(), 500 * 1024);
+        assert_eq!(parse_rate("2m").unwrap(), 2 << 20);
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("3T").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[tokio::test]
+    async fn unlimited_never_waits() {
+        let limiter = RateLimiter::unlimited();
+        let start = Instant::now();
+        limiter.acquire(usize::MAX / 2).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn waits_once_the_burst_is_spent() {
+        let limiter = RateLimiter::new(Some(100_000));
+        let start = Instant::now();
+        limiter.acquire(100_000).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        limiter.acquire(20_000).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(190), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn children_share_the_parent_limit() {
+        let parent = Arc::new(RateLimiter::new(Some(100_000)));
+        let a = RateLimiter::with_parent(None, parent.clone());
+        let b = RateLimiter::with_parent(None, parent);
+        let start = Instant::now();
+        a.acquire(60_000).await;
+        b.acquire(60_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[test]
+    fn lowering_the_rate_drops_the_burst() {
+        let limiter = RateLimiter::new(Some(1_000_000));
+        limiter.set_rate(Some(1000));
+        assert_eq!(limiter.rate(), Some(1000));
+        let wait = limiter.bucket.lock().unwrap().take(2000);
+        assert!(wait >= Duration::from_millis(990), "{:?}", wait);
+    }
+}
@@ -0,0 +1,320 @@
+use crate::bencode;
+use crate::bitfield::Bitfield;
+use crate::storage::{self, Incomplete};
+use crate::torrent::Torrent;
+use crate::tracker::TrackerState;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// State of a download that is kept between runs, stored bencoded next to its output as
+/// `<output>.resume`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResumeData {
+    #[serde(rename = "info hash", with = "serde_bytes")]
+    pub info_hash: Vec<u8>,
+    /// Bitfield of the pieces that have been verified and written.
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+    /// Bytes downloaded over all runs.
+    pub downloaded: usize,
+    /// Bytes uploaded over all runs.
+    pub uploaded: usize,
+    /// The files the pieces were written to; if they change, the pieces can't be trusted.
+    pub files: Vec<ResumeFile>,
+    /// The files that were renamed, for when the torrent is added again without them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub renamed: Vec<RenamedFile>,
+    /// Where the announce session with the trackers stood when this was saved.
+    #[serde(default, skip_serializing_if = "TrackerState::is_empty")]
+    pub tracker: TrackerState,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResumeFile {
+    pub path: String,
+    pub length: usize,
+}
+
+/// Where a file of the torrent is kept instead of its path in the metainfo.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RenamedFile {
+    /// In `Torrent::files`.
+    pub index: usize,
+    pub path: String,
+}
+
+impl ResumeData {
+    /// Resume data for a download that hasn't written anything yet.
+    pub fn new(torrent: &Torrent) -> anyhow::Result<ResumeData> {
+        Ok(ResumeData {
+            info_hash: torrent.info_hash().to_vec(),
+            pieces: vec![0; torrent.piece_count().div_ceil(8)],
+            downloaded: 0,
+            uploaded: 0,
+            files: layout(torrent)?,
+            renamed: torrent
+                .renamed
+                .iter()
+                .map(|(&index, path)| RenamedFile {
+                    index,
+                    path: path.to_string_lossy().into_owned(),
+                })
+                .collect(),
+            tracker: TrackerState::default(),
+        })
+    }
+
+    /// `torrent` with its files renamed as recorded, unless it renames some itself.
+    pub fn renaming(&self, torrent: &Torrent) -> Torrent {
+        let mut torrent = torrent.clone();
+        if torrent.renamed.is_empty() {
+            torrent.renamed = self
+                .renamed
+                .iter()
+                .map(|file| (file.index, PathBuf::from(&file.path)))
+                .collect();
+        }
+        torrent
+    }
+
+    /// Resume data for existing data of which the `valid` pieces check out, e.g. as found by
+    /// `storage::verify`.
+    pub fn from_verified(torrent: &Torrent, valid: &[bool]) -> anyhow::Result<ResumeData> {
+        let mut resume = ResumeData::new(torrent)?;
+        for (index, _) in valid.iter().enumerate().filter(|(_, &valid)| valid) {
+            resume.set_piece(index);
+        }
+        Ok(resume)
+    }
+
+    pub fn path(output: &Path) -> PathBuf {
+        let mut path = OsString::from(output);
+        path.push(".resume");
+        path.into()
+    }
+
+    /// Loads the resume data stored for `output`, unless there is none, it was written for a
+    /// different torrent, or the files on disk no longer have the sizes it recorded. The
+    /// files are looked for where they were renamed to, by `torrent` or else as recorded.
+    pub async fn load(torrent: &Torrent, output: &Path) -> Option<ResumeData> {
+        ResumeData::load_incomplete(torrent, output, &Incomplete::default()).await
+    }
+
+    /// Like `load`, for a download whose files are kept where `incomplete` says until they
+    /// are complete, or are already moved to `output`.
+    pub async fn load_incomplete(
+        torrent: &Torrent,
+        output: &Path,
+        incomplete: &Incomplete,
+    ) -> Option<ResumeData> {
+        let resume = ResumeData::load_recorded(torrent, output).await?;
+        let info_hash = torrent.info_hash();
+        for span in resume.renaming(torrent).files().ok()? {
+            let kept = incomplete.file_path(&info_hash, output, &span);
+            let metadata = match tokio::fs::metadata(kept).await {
+                Ok(metadata) => metadata,
+                Err(_) => tokio::fs::metadata(storage::file_path(output, &span))
+                    .await
+                    .ok()?,
+            };
+            if metadata.len() != span.length as u64 {
+                return None;
+            }
+        }
+        Some(resume)
+    }
+
+    /// Like `load`, but without looking at the files on disk, for content kept in a `Storage`
+    /// of its own.
+    pub async fn load_recorded(torrent: &Torrent, output: &Path) -> Option<ResumeData> {
+        let bytes = tokio::fs::read(ResumeData::path(output)).await.ok()?;
+        let resume: ResumeData = bencode::from_bytes(&bytes).ok()?;
+        let matches = resume.info_hash == torrent.info_hash()
+            && resume.pieces.len() == torrent.piece_count().div_ceil(8)
+            && layout(&resume.renaming(torrent)).is_ok_and(|files| files == resume.files);
+        matches.then_some(resume)
+    }
+
+    /// Writes the resume data, replacing the previous copy only once the new one is complete.
+    pub async fn save(&self, output: &Path) -> anyhow::Result<()> {
+        let path = ResumeData::path(output);
+        let mut partial = path.clone().into_os_string();
+        partial.push(".part");
+        tokio::fs::write(&partial, bencode::to_bytes(self)?).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(())
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.pieces
+            .get(index / 8)
+            .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+
+    pub fn set_piece(&mut self, index: usize) {
+        self.pieces[index / 8] |= 0x80 >> (index % 8);
+    }
+
+    /// The recorded pieces, as a bitfield of `torrent`'s.
+    pub fn bitfield(&self, torrent: &Torrent) -> Bitfield {
+        Bitfield::from_bytes(&self.pieces, torrent.piece_count())
+    }
+}
+
+/// The pieces of the data at `output` that check out, for other tools to pick up: those its
+/// resume data records, or those `storage::verify` finds if it has none or `check` is set.
+pub async fn verified_pieces(
+    torrent: &Torrent,
+    output: &Path,
+    check: bool,
+) -> crate::Result<Bitfield> {
+    if !check {
+        if let Some(resume) = ResumeData::load(torrent, output).await {
+            return Ok(resume.bitfield(torrent));
+        }
+    }
+    let valid = storage::verify(torrent, output).await?;
+    Ok(valid.into_iter().collect())
+}
+
+fn layout(torrent: &Torrent) -> anyhow::Result<Vec<ResumeFile>> {
+    Ok(torrent
+        .files()?
+        .into_iter()
+        .map(|span| ResumeFile {
+            path: span.path.to_string_lossy().into_owned(),
+            length: span.length,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{multi_file_torrent_for, test_data, torrent_for};
+
+    #[tokio::test]
+    async fn exports_the_verified_pieces() {
+        let data = test_data(1000);
+        let torrent = torrent_for(&data, 100);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        let mut partial = data.clone();
+        partial[950] ^= 1;
+        std::fs::write(&output, &partial).unwrap();
+        let checked = verified_pieces(&torrent, &output, false).await.unwrap();
+        assert_eq!(checked.as_bytes(), [0xff, 0b1000_0000]);
+        // a bitfield read back from its bytes is the same
+        let bytes = checked.as_bytes().to_vec();
+        assert_eq!(Bitfield::from_bytes(&bytes, torrent.piece_count()), checked);
+
+        // the resume data says what is recorded, unless asked to check it
+        let mut resume = ResumeData::new(&torrent).unwrap();
+        resume.set_piece(3);
+        resume.save(&output).await.unwrap();
+        let recorded = verified_pieces(&torrent, &output, false).await.unwrap();
+        assert_eq!(recorded.ones().collect::<Vec<_>>(), [3]);
+        assert_eq!(
+            verified_pieces(&torrent, &output, true).await.unwrap(),
+            checked
+        );
+    }
+
+    #[tokio::test]
+    async fn save_and_load() {
+        let data = test_data(1000);
+        let torrent = torrent_for(&data, 100);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        std::fs::write(&output, &data).unwrap();
+        let mut resume = ResumeData::new(&torrent).unwrap();
+        resume.set_piece(0);
+        resume.set_piece(9);
+        resume.downloaded = 200;
+        resume.save(&output).await.unwrap();
+
+        assert!(dir.path().join("test.bin.resume").exists());
+        let loaded = ResumeData::load(&torrent, &output).await.unwrap();
+        assert_eq!(loaded, resume);
+        assert!(loaded.has_piece(0) && loaded.has_piece(9));
+        assert!(!loaded.has_piece(1));
+    }
+
+    #[tokio::test]
+    async fn keeps_the_renamed_files() {
+        let data = test_data(1000);
+        let torrent = multi_file_torrent_for(&data, 100, &[("a", 400), ("sub/b", 600)]);
+        let mut renamed = torrent.clone();
+        renamed
+            .rename(Path::new("sub"), Path::new("other"))
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test");
+        std::fs::create_dir_all(output.join("other")).unwrap();
+        std::fs::write(output.join("a"), &data[..400]).unwrap();
+        std::fs::write(output.join("other/b"), &data[400..]).unwrap();
+        let resume = ResumeData::new(&renamed).unwrap();
+        resume.save(&output).await.unwrap();
+
+        // added again without the renames, the torrent finds its files where they went
+        let loaded = ResumeData::load(&torrent, &output).await.unwrap();
+        assert_eq!(loaded, resume);
+        let files = loaded.renaming(&torrent).files().unwrap();
+        assert_eq!(files[1].path, Path::new("other/b"));
+        // but renames of its own win
+        let mut elsewhere = torrent.clone();
+        elsewhere.rename(Path::new("a"), Path::new("z")).unwrap();
+        assert_eq!(ResumeData::load(&elsewhere, &output).await, None);
+    }
+
+    #[tokio::test]
+    async fn load_ignores_other_torrent() {
+        let data = test_data(1000);
+        let torrent = torrent_for(&data, 100);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        std::fs::write(&output, &data).unwrap();
+        ResumeData::new(&torrent)
+            .unwrap()
+            .save(&output)
+            .await
+            .unwrap();
+
+        let other = torrent_for(&test_data(500), 100);
+        assert_eq!(ResumeData::load(&other, &output).await, None);
+        assert!(ResumeData::load(&torrent, &output).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn load_ignores_changed_files() {
+        let data = test_data(1000);
+        let torrent = multi_file_torrent_for(&data, 100, &[("a", 400), ("sub/b", 600)]);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test");
+        std::fs::create_dir_all(output.join("sub")).unwrap();
+        std::fs::write(output.join("a"), &data[..400]).unwrap();
+        std::fs::write(output.join("sub/b"), &data[400..]).unwrap();
+        ResumeData::new(&torrent)
+            .unwrap()
+            .save(&output)
+            .await
+            .unwrap();
+        assert!(ResumeData::load(&torrent, &output).await.is_some());
+
+        std::fs::write(output.join("sub/b"), &data[400..500]).unwrap();
+        assert_eq!(ResumeData::load(&torrent, &output).await, None);
+        std::fs::remove_file(output.join("sub/b")).unwrap();
+        assert_eq!(ResumeData::load(&torrent, &output).await, None);
+    }
+
+    #[tokio::test]
+    async fn load_without_resume_file() {
+        let torrent = torrent_for(&test_data(1000), 100);
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            ResumeData::load(&torrent, &dir.path().join("test.bin")).await,
+            None
+        );
+    }
+}
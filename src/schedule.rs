@@ -0,0 +1,288 @@
+use serde::{Deserialize, Deserializer};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// How often `follow` looks at the clock.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Rate limits in bytes per second; `None` for no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub download: Option<usize>,
+    pub upload: Option<usize>,
+}
+
+/// Other rate limits for periods of the week, e.g. none at night and less during work hours.
+/// The first period covering the current local time applies; outside all of them the usual
+/// limits do.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Schedule {
+    pub periods: Vec<Period>,
+}
+
+/// A time of day from which other limits apply until another, on some days of the week.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Period {
+    /// The days the period starts on; every day if not set.
+    #[serde(default = "every_day")]
+    pub days: Days,
+    pub from: TimeOfDay,
+    /// Where the period ends, on the next day if this is before `from`; a period ending
+    /// where it starts lasts the whole day.
+    pub to: TimeOfDay,
+    /// Bytes per second; no limit if not set.
+    #[serde(default, deserialize_with = "crate::config::rate")]
+    pub max_down: Option<usize>,
+    #[serde(default, deserialize_with = "crate::config::rate")]
+    pub max_up: Option<usize>,
+}
+
+fn every_day() -> Days {
+    Days([true; 7])
+}
+
+impl Period {
+    /// Whether the period covers `minute` of the day on `day`, Monday being 0.
+    fn covers(&self, day: usize, minute: u32) -> bool {
+        let (from, to) = (self.from.0, self.to.0);
+        let yesterday = (day + 6) % 7;
+        if from < to {
+            self.days.0[day] && (from..to).contains(&minute)
+        } else if from > to {
+            // past midnight the period started the day before
+            (self.days.0[day] && minute >= from) || (self.days.0[yesterday] && minute < to)
+        } else {
+            self.days.0[day]
+        }
+    }
+}
+
+impl Schedule {
+    pub fn is_empty(&self) -> bool {
+        self.periods.is_empty()
+    }
+
+    /// The limits for `minute` of the day on `day`, Monday being 0: those of the first
+    /// period covering it, or `usual` if none do.
+    pub fn limits_at(&self, day: usize, minute: u32, usual: Limits) -> Limits {
+        self.periods
+            .iter()
+            .find(|period| period.covers(day, minute))
+            .map_or(usual, |period| Limits {
+                download: period.max_down,
+                upload: period.max_up,
+            })
+    }
+}
+
+/// Hands the limits of `schedule`, or `usual` outside its periods, to `apply` when it starts
+/// and whenever they change, for as long as it runs.
+pub async fn follow(schedule: Schedule, usual: Limits, mut apply: impl FnMut(Limits)) {
+    let mut current = None;
+    let mut checks = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        checks.tick().await;
+        let (day, minute) = local_time();
+        let limits = schedule.limits_at(day, minute, usual);
+        if current != Some(limits) {
+            if current.is_some() {
+                info!(
+                    download = ?limits.download,
+                    upload = ?limits.upload,
+                    "switching to scheduled rate limits"
+                );
+            }
+            apply(limits);
+            current = Some(limits);
+        }
+    }
+}
+
+/// The day of the week, Monday being 0, and the minute of the day it is here; in UTC where
+/// the local time zone isn't known.
+fn local_time() -> (usize, u32) {
+    #[cfg(unix)]
+    {
+        let now = unsafe { libc::time(std::ptr::null_mut()) };
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if !unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+            let day = (tm.tm_wday as usize + 6) % 7;
+            return (day, (tm.tm_hour * 60 + tm.tm_min) as u32);
+        }
+    }
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    // 1 January 1970 was a Thursday
+    let day = (seconds / 86400 + 3) % 7;
+    (day as usize, (seconds % 86400 / 60) as u32)
+}
+
+/// A time of day as minutes since midnight, written `HH:MM`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeOfDay(pub u32);
+
+impl FromStr for TimeOfDay {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<TimeOfDay> {
+        let invalid = || anyhow::anyhow!("invalid time of day `{}`, expected e.g. 09:30", text);
+        let (hours, minutes) = text.split_once(':').ok_or_else(invalid)?;
+        let hours: u32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+        if minutes >= 60 || hours * 60 + minutes > MINUTES_PER_DAY {
+            return Err(invalid());
+        }
+        // 24:00 ends a period at midnight, the same as 00:00
+        Ok(TimeOfDay((hours * 60 + minutes) % MINUTES_PER_DAY))
+    }
+}
+
+/// Days of the week, Monday first, written as names and ranges of them, e.g. `mon-fri` or
+/// `sat,sun`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Days(pub [bool; 7]);
+
+impl FromStr for Days {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<Days> {
+        let day = |name: &str| {
+            DAY_NAMES
+                .iter()
+                .position(|&day| name.trim().eq_ignore_ascii_case(day))
+                .ok_or_else(|| anyhow::anyhow!("unknown day `{}`, expected e.g. mon", name))
+        };
+        let mut days = [false; 7];
+        for part in text.split(',') {
+            let (first, last) = match part.split_once('-') {
+                Some((first, last)) => (day(first)?, day(last)?),
+                None => (day(part)?, day(part)?),
+            };
+            // a range may go round the end of the week, e.g. fri-mon
+            let mut day = first;
+            loop {
+                days[day] = true;
+                if day == last {
+                    break;
+                }
+                day = (day + 1) % 7;
+            }
+        }
+        Ok(Days(days))
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TimeOfDay, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Days {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Days, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn period(days: &str, from: &str, to: &str, max_down: Option<usize>) -> Period {
+        Period {
+            days: days.parse().unwrap(),
+            from: from.parse().unwrap(),
+            to: to.parse().unwrap(),
+            max_down,
+            max_up: None,
+        }
+    }
+
+    #[test]
+    fn parse_days_and_times() {
+        assert_eq!(
+            "mon-fri".parse::<Days>().unwrap(),
+            Days([true, true, true, true, true, false, false])
+        );
+        assert_eq!(
+            "Sat, sun,wed".parse::<Days>().unwrap(),
+            Days([false, false, true, false, false, true, true])
+        );
+        assert_eq!(
+            "fri-mon".parse::<Days>().unwrap(),
+            Days([true, false, false, false, true, true, true])
+        );
+        assert!("mon-".parse::<Days>().is_err());
+        assert!("weekdays".parse::<Days>().is_err());
+
+        assert_eq!("09:30".parse::<TimeOfDay>().unwrap(), TimeOfDay(570));
+        assert_eq!("0:00".parse::<TimeOfDay>().unwrap(), TimeOfDay(0));
+        assert_eq!("24:00".parse::<TimeOfDay>().unwrap(), TimeOfDay(0));
+        for invalid in ["9", "24:01", "12:60", "-1:00", "noon"] {
+            assert!(invalid.parse::<TimeOfDay>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn first_period_covering_the_time_applies() {
+        let usual = Limits {
+            download: Some(100),
+            upload: Some(50),
+        };
+        let schedule = Schedule {
+            periods: vec![
+                period("mon-fri", "09:00", "17:00", Some(1 << 20)),
+                // unlimited at night, starting on any day
+                period("mon-sun", "23:00", "07:00", None),
+                period("sat", "10:00", "10:00", Some(10)),
+            ],
+        };
+        let at = |day, time: &str| {
+            let minute = time.parse::<TimeOfDay>().unwrap().0;
+            schedule.limits_at(day, minute, usual).download
+        };
+        assert_eq!(at(0, "09:00"), Some(1 << 20));
+        assert_eq!(at(4, "16:59"), Some(1 << 20));
+        assert_eq!(at(4, "17:00"), Some(100));
+        assert_eq!(at(5, "12:00"), Some(10));
+        assert_eq!(at(5, "23:30"), None);
+        assert_eq!(at(6, "06:59"), None);
+        assert_eq!(at(6, "07:00"), Some(100));
+        assert_eq!(at(2, "08:59"), Some(100));
+        assert_eq!(Schedule::default().limits_at(0, 0, usual), usual);
+
+        // past midnight only if the period started the day before
+        let schedule = Schedule {
+            periods: vec![period("fri", "22:00", "02:00", Some(1))],
+        };
+        assert_eq!(schedule.limits_at(5, 60, usual).download, Some(1));
+        assert_eq!(schedule.limits_at(4, 60, usual).download, Some(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn follow_applies_the_limits_once() {
+        let applied = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = applied.clone();
+        let usual = Limits {
+            download: Some(100),
+            upload: None,
+        };
+        let following = tokio::spawn(follow(Schedule::default(), usual, move |limits| {
+            recorder.lock().unwrap().push(limits)
+        }));
+        tokio::time::sleep(CHECK_INTERVAL * 3).await;
+        following.abort();
+        assert_eq!(*applied.lock().unwrap(), [usual]);
+    }
+}
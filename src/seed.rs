@@ -0,0 +1,1371 @@
+use crate::bitfield::Bitfield;
+use crate::cache::{CacheStats, PieceCache, DEFAULT_CACHE_SIZE};
+use crate::choker::{Choker, UploadSlot, UPLOAD_SLOTS};
+use crate::disk::Disk;
+use crate::download::InboundPeer;
+use crate::events::EventSender;
+use crate::hook::{self, HookEvent};
+use crate::ipfilter::IpFilter;
+use crate::message::*;
+use crate::mse::EncryptionPolicy;
+use crate::peer::{self, IDLE_TIMEOUT, KEEP_ALIVE_INTERVAL};
+use crate::portmap::{PortMapper, Protocol};
+use crate::rate::RateLimiter;
+use crate::schedule::{self, Limits, Schedule};
+use crate::storage::{FileStorage, StorageBackend};
+use crate::torrent::Torrent;
+use crate::tracker::{
+    announce_periodically, Event, HttpOptions, Progress, TrackerList, TrackerOverride,
+    TrackerRequest,
+};
+use futures_util::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_util::codec::Framed;
+use tracing::{debug, warn};
+
+/// Requests for larger blocks end the connection unless configured otherwise; clients ask
+/// for 16 KiB.
+pub const DEFAULT_MAX_REQUEST_LENGTH: u32 = 16 * 1024;
+/// The most the request length may be configured to, for the clients that ask for up to
+/// 128 KiB.
+pub const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+
+/// How many pieces a peer with the Fast Extension may download from us while choked.
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+/// How `seed` serves a torrent, and when it stops on its own.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SeedOptions {
+    /// Bytes per second all peers together get at most.
+    pub max_upload_rate: Option<usize>,
+    /// Stop once this many times the torrent's length was uploaded.
+    pub ratio: Option<f64>,
+    /// Stop after seeding this long.
+    pub time: Option<Duration>,
+    /// Hand out the pieces a few at a time with a `SuperSeeder`, for a new swarm we are the
+    /// only seed of.
+    pub super_seeding: bool,
+    /// Ask the router to forward the listening port to us while seeding.
+    pub port_mapping: bool,
+    /// Blocklist of IP ranges whose peers aren't served, as `IpFilter::load` reads it.
+    pub ip_filter: Option<PathBuf>,
+    /// How pieces are read from the files.
+    pub storage_backend: StorageBackend,
+    /// Largest block a peer may request, up to `MAX_REQUEST_LENGTH`;
+    /// `DEFAULT_MAX_REQUEST_LENGTH` if not set.
+    pub max_request_length: Option<u32>,
+    /// Check every piece against its hash again when it is read to be uploaded, so data that
+    /// went bad on disk since the start is never sent.
+    pub verify_uploads: bool,
+    /// Bytes of the pieces read last kept in memory to upload from, 0 for none;
+    /// `DEFAULT_CACHE_SIZE` if not set. With `verify_uploads` it holds the pieces that passed
+    /// their check, at least one.
+    pub cache_size: Option<usize>,
+    /// Other upload limits for periods of the week, instead of `max_upload_rate`.
+    pub schedule: Schedule,
+    /// Shell command to run when seeding stops at `ratio` or `time`, as `hook::run` does.
+    pub on_seed_goal: Option<String>,
+    /// The `User-Agent` and TLS settings of the announces to HTTP trackers.
+    pub tracker_http: HttpOptions,
+    /// Trackers announced to in place of or besides the torrent's.
+    pub trackers: TrackerOverride,
+}
+
+/// How long the finished torrents of a session go on seeding.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SeedLimits {
+    /// Most torrents seeding at once; the others wait as `Finished` until one of them stops.
+    /// Torrents started by force don't count. `None` for no limit.
+    pub max_active_seeds: Option<usize>,
+    /// Stop seeding a torrent once this many times its length was uploaded in the session,
+    /// downloading included; `Session::set_seed_limits` sets another for one torrent.
+    pub ratio: Option<f64>,
+    /// Stop seeding a torrent after seeding it this long at a time.
+    pub time: Option<Duration>,
+    /// Shell command to run when a torrent stops seeding at its ratio or time, as `hook::run`
+    /// does.
+    pub on_seed_goal: Option<String>,
+}
+
+/// What every peer of one seeded torrent is served from.
+pub struct Seeding {
+    pub disk: Disk,
+    /// Every byte sent is added here.
+    pub progress: watch::Sender<Progress>,
+    /// Every block waits for this before it is sent.
+    pub upload_limit: RateLimiter,
+    /// Set when super-seeding, to decide which pieces each peer is told of.
+    pub super_seeder: Option<SuperSeeder>,
+    /// A peer requesting a larger block is disconnected.
+    pub max_request_length: u32,
+    /// Set when the pieces are checked on every read, to keep the ones checked last.
+    pub verified: Option<VerifiedPieces>,
+}
+
+impl Seeding {
+    /// The block a peer requested, once the upload limit lets it go out; `None` if its piece
+    /// failed its check. A request that doesn't fit in its piece, or asks for more than
+    /// `max_request_length`, is an error.
+    pub(crate) async fn read_block(
+        &self,
+        torrent: &Torrent,
+        index: u32,
+        begin: u32,
+        length: u32,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let offset = request_offset(torrent, index, begin, length, self.max_request_length)?;
+        let block = match &self.verified {
+            Some(verified) => {
+                let (piece, begin) = (index as usize, begin as usize);
+                verified
+                    .block(&self.disk, piece, begin, length as usize)
+                    .await?
+            }
+            None => Some(self.disk.read(offset, length as usize).await?),
+        };
+        if let Some(block) = &block {
+            self.upload_limit.acquire(block.len()).await;
+        }
+        Ok(block)
+    }
+
+    /// What the pieces were read from memory, in the pieces checked on every read or else in
+    /// the disk's cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        match &self.verified {
+            Some(verified) => verified.pieces.stats(),
+            None => self.disk.cache_stats(),
+        }
+    }
+
+    /// Counts `bytes` as sent to the peer holding `slot`.
+    pub(crate) fn sent(&self, slot: &UploadSlot, bytes: usize) {
+        slot.uploaded(bytes);
+        self.progress
+            .send_modify(|progress| progress.uploaded += bytes);
+    }
+}
+
+/// The pieces last read whole and checked against their hashes to upload blocks of them, so
+/// a piece is only read and hashed once for the many blocks peers request of it in a row.
+pub struct VerifiedPieces {
+    /// Only ever holds pieces that passed their check.
+    pieces: PieceCache,
+    /// Pieces that failed their check, which aren't uploaded any more.
+    corrupt: Mutex<HashSet<usize>>,
+}
+
+impl VerifiedPieces {
+    /// Keeps up to `budget` bytes of pieces.
+    pub fn new(budget: usize) -> VerifiedPieces {
+        VerifiedPieces {
+            pieces: PieceCache::new(budget),
+            corrupt: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// The `length` bytes at `begin` in the piece at `index`, which is read from `disk` and
+    /// checked unless it is kept already; `None` if the piece on disk doesn't match its hash.
+    async fn block(
+        &self,
+        disk: &Disk,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let piece = self.piece(disk, index).await?;
+        Ok(piece.map(|piece| piece[begin..begin + length].to_vec()))
+    }
+
+    async fn piece(&self, disk: &Disk, index: usize) -> anyhow::Result<Option<Arc<Vec<u8>>>> {
+        if self.corrupt.lock().unwrap().contains(&index) {
+            return Ok(None);
+        }
+        if let Some(piece) = self.pieces.get(index) {
+            return Ok(Some(piece));
+        }
+        let Some(piece) = disk.read_verified_piece(index).await? else {
+            warn!(
+                piece = index,
+                "piece on disk failed its hash check, no longer uploading it"
+            );
+            self.corrupt.lock().unwrap().insert(index);
+            return Ok(None);
+        };
+        let piece = Arc::new(piece);
+        // another peer may have read it meanwhile, which this replaces
+        self.pieces.insert(index, piece.clone());
+        Ok(Some(piece))
+    }
+}
+
+/// Super-seeding (BEP 16): we pretend to have no pieces, and tell every peer of one piece at a
+/// time, the one the fewest peers have or were told of. A peer hears of the next once it
+/// announces that it has the last, so the peers have to get the rest from each other and we
+/// upload every piece about once instead of the same ones to everyone.
+pub struct SuperSeeder {
+    /// For every piece, how many peers announced it or were told of it.
+    counts: Mutex<Vec<usize>>,
+}
+
+impl SuperSeeder {
+    pub fn new(piece_count: usize) -> SuperSeeder {
+        SuperSeeder {
+            counts: Mutex::new(vec![0; piece_count]),
+        }
+    }
+
+    /// Picks the piece to tell a peer of that has the pieces in `has`: the rarest one it
+    /// doesn't have, or `None` if it has all of them.
+    fn reveal(&self, has: &Bitfield) -> Option<u32> {
+        let mut counts = self.counts.lock().unwrap();
+        let index = has.zeros().min_by_key(|&index| counts[index])?;
+        counts[index] += 1;
+        Some(index as u32)
+    }
+
+    /// Counts a piece a peer announced to have.
+    fn announced(&self, index: usize) {
+        if let Some(count) = self.counts.lock().unwrap().get_mut(index) {
+            *count += 1;
+        }
+    }
+}
+
+/// Why `seed` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeedingStopped {
+    Shutdown,
+    RatioReached,
+    TimeLimitReached,
+}
+
+/// A share ratio to seed up to, like 2.0; it has to be above 0.
+pub fn parse_ratio(value: &str) -> anyhow::Result<f64> {
+    let ratio: f64 = value.parse()?;
+    if !(ratio.is_finite() && ratio > 0.0) {
+        anyhow::bail!("must be above 0");
+    }
+    Ok(ratio)
+}
+
+/// A time to seed for, in seconds or with an s, m, h or d suffix, e.g. 90m or 2d.
+pub fn parse_seed_time(value: &str) -> anyhow::Result<Duration> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, suffix)) if suffix.is_ascii_alphabetic() => {
+            let unit = match suffix.to_ascii_lowercase() {
+                's' => 1,
+                'm' => 60,
+                'h' => 60 * 60,
+                'd' => 24 * 60 * 60,
+                _ => anyhow::bail!("unknown unit {:?}, use s, m, h or d", suffix),
+            };
+            (&value[..i], unit)
+        }
+        _ => (value, 1),
+    };
+    let seconds = number.parse::<u64>()?.saturating_mul(unit);
+    if seconds == 0 {
+        anyhow::bail!("must be at least 1s");
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Serves the complete torrent stored at `data` to every peer that connects to `listener`,
+/// until `shutdown` completes or one of the limits in `options` is reached, and returns which
+/// it was.
+///
+/// Every piece is checked against its hash first, so we never hand out corrupt data. The
+/// trackers are told about us and get the uploaded byte count with every announce, and that
+/// we stopped when we do. The share ratio counts what was uploaded since `seed` was called,
+/// and the seeding time starts once the data is verified.
+pub async fn seed(
+    torrent: &Torrent,
+    data: &Path,
+    peer_id: [u8; 20],
+    listener: TcpListener,
+    options: SeedOptions,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<SeedingStopped> {
+    let filter = match &options.ip_filter {
+        Some(path) => IpFilter::load(path).await?,
+        None => IpFilter::default(),
+    };
+    let torrent = Arc::new(torrent.clone());
+    let storage = FileStorage::open(&torrent, data)
+        .await?
+        .with_backend(options.storage_backend)
+        .await?;
+    let cache_size = options.cache_size.unwrap_or(DEFAULT_CACHE_SIZE);
+    // checked pieces are cached apart from the disk, which then keeps none of its own
+    let disk = Disk::spawn(torrent.clone(), storage).with_cache(match options.verify_uploads {
+        true => 0,
+        false => cache_size,
+    });
+    verify(&torrent, &disk).await?;
+
+    let port = listener.local_addr()?.port();
+    let port_mapper = options
+        .port_mapping
+        .then(|| PortMapper::spawn(vec![(Protocol::Tcp, port)]));
+    let mut trackers = TrackerList::with_override(&torrent, &options.trackers)?
+        .with_http(options.tracker_http.clone());
+    let info_hash = torrent.info_hash();
+    let mut request = TrackerRequest::with_left(0, peer_id, port);
+    request.event = Some(Event::Started);
+    let response = trackers.announce(&info_hash, &request).await?;
+
+    let wait = response.reannounce_after();
+    // the tracker's peers connect to us on their own, so the peer lists are only drained
+    let (peer_tx, peer_rx) = mpsc::channel(4);
+    let (progress_tx, progress_rx) = watch::channel(Progress::default());
+    let announcer = tokio::spawn(announce_periodically(
+        trackers,
+        info_hash,
+        request,
+        wait,
+        peer_tx,
+        progress_rx,
+        EventSender::unobserved(info_hash),
+    ));
+
+    let seeding = Arc::new(Seeding {
+        disk,
+        progress: progress_tx,
+        upload_limit: RateLimiter::new(options.max_upload_rate),
+        super_seeder: options
+            .super_seeding
+            .then(|| SuperSeeder::new(torrent.piece_count())),
+        max_request_length: options
+            .max_request_length
+            .unwrap_or(DEFAULT_MAX_REQUEST_LENGTH)
+            .min(MAX_REQUEST_LENGTH),
+        // with less than a piece, every block would have all of it read and hashed
+        verified: options
+            .verify_uploads
+            .then(|| VerifiedPieces::new(cache_size.max(torrent.info.piece_length))),
+    });
+    let scheduler = (!options.schedule.is_empty()).then(|| {
+        let usual = Limits {
+            download: None,
+            upload: options.max_upload_rate,
+        };
+        let seeding = seeding.clone();
+        tokio::spawn(schedule::follow(
+            options.schedule.clone(),
+            usual,
+            move |limits| seeding.upload_limit.set_rate(limits.upload),
+        ))
+    });
+    let (inbound_tx, inbound) = mpsc::channel(16);
+    let accepting = tokio::spawn(accept_peers(
+        listener,
+        torrent.info_hashes(),
+        peer_id,
+        filter,
+        inbound_tx,
+    ));
+    let target = options
+        .ratio
+        .map(|ratio| (ratio * torrent.total_length() as f64).ceil() as usize);
+    let peers = SeedingPeers {
+        inbound,
+        announced: peer_rx,
+    };
+    let stopped = serve_swarm(&torrent, &seeding, peers, target, options.time, shutdown).await;
+
+    accepting.abort();
+    if let Some(scheduler) = scheduler {
+        scheduler.abort();
+        // it holds on to the seeding state until it is gone
+        let _ = scheduler.await;
+    }
+    let cache = seeding.cache_stats();
+    debug!(
+        hits = cache.hits,
+        misses = cache.misses,
+        "piece cache hit {:.0}% of reads",
+        cache.hit_ratio() * 100.0
+    );
+    // with the last sender gone the announcer sends the stopped event
+    drop(seeding);
+    announcer.await?;
+    if let Some(port_mapper) = port_mapper {
+        port_mapper.stop().await;
+    }
+    if let Some(command) = options.on_seed_goal.as_deref() {
+        if stopped != SeedingStopped::Shutdown {
+            let _ = hook::spawn(command, HookEvent::Seeded, &torrent, data).await;
+        }
+    }
+    Ok(stopped)
+}
+
+/// Reads every piece back from `disk` and checks it against the torrent's piece hashes.
+async fn verify(torrent: &Torrent, disk: &Disk) -> anyhow::Result<()> {
+    let indices: Vec<usize> = (0..torrent.piece_count()).collect();
+    let valid = disk.verify_pieces(&indices).await?;
+    match valid.iter().position(|&valid| !valid) {
+        Some(index) => anyhow::bail!("piece {} failed hash verification", index),
+        None => Ok(()),
+    }
+}
+
+/// Where the peers of a seeded torrent come from.
+pub(crate) struct SeedingPeers {
+    /// Peers that connected to us, with their handshakes answered.
+    pub inbound: mpsc::Receiver<InboundPeer>,
+    /// The peers the trackers sent, which connect to us on their own, so they are only
+    /// drained.
+    pub announced: mpsc::Receiver<Vec<SocketAddr>>,
+}
+
+/// Serves every peer that arrives from `peers` from `seeding`, until `shutdown` completes,
+/// `time` is up or `target` bytes are uploaded, and returns which it was.
+pub(crate) async fn serve_swarm(
+    torrent: &Arc<Torrent>,
+    seeding: &Arc<Seeding>,
+    mut peers: SeedingPeers,
+    target: Option<usize>,
+    time: Option<Duration>,
+    shutdown: impl Future<Output = ()>,
+) -> SeedingStopped {
+    let choker = Arc::new(Choker::new(UPLOAD_SLOTS));
+    let rechoking = tokio::spawn(choker.clone().run());
+    let mut connections = JoinSet::new();
+    let mut shutdown = std::pin::pin!(shutdown);
+    let time_limit = async {
+        match time {
+            Some(time) => tokio::time::sleep(time).await,
+            None => std::future::pending().await,
+        }
+    };
+    let mut time_limit = std::pin::pin!(time_limit);
+    let mut uploads = seeding.progress.subscribe();
+    let reached = |uploads: &mut watch::Receiver<Progress>| {
+        target.is_some_and(|target| uploads.borrow_and_update().uploaded >= target)
+    };
+    let stopped = loop {
+        if reached(&mut uploads) {
+            break SeedingStopped::RatioReached;
+        }
+        tokio::select! {
+            Some((stream, handshake)) = peers.inbound.recv() => {
+                let torrent = torrent.clone();
+                let seeding = seeding.clone();
+                let slot = choker.register();
+                connections.spawn(async move {
+                    let ip = stream.peer_addr()?.ip();
+                    // a super-seeding peer may only have the pieces it was told of, even while
+                    // choked
+                    let allowed_fast = handshake
+                        .supports_fast()
+                        .then(|| match seeding.super_seeder {
+                            Some(_) => Vec::new(),
+                            None => allowed_fast_set(
+                                ip,
+                                handshake.info_hash,
+                                torrent.piece_count(),
+                                ALLOWED_FAST_COUNT,
+                            ),
+                        });
+                    // a misbehaving peer only loses its own connection
+                    serve_peer(stream, &torrent, &seeding, slot, allowed_fast.as_deref()).await
+                });
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            Some(_) = peers.announced.recv() => {}
+            Ok(()) = uploads.changed(), if target.is_some() => {}
+            () = &mut time_limit => break SeedingStopped::TimeLimitReached,
+            () = &mut shutdown => break SeedingStopped::Shutdown,
+        }
+    };
+    connections.shutdown().await;
+    rechoking.abort();
+    stopped
+}
+
+/// Answers the handshakes of the peers connecting to `listener` for one of `info_hashes`,
+/// encrypted or not, and hands them to `inbound`; those in the ranges of `filter` aren't
+/// answered.
+async fn accept_peers(
+    listener: TcpListener,
+    info_hashes: Vec<[u8; 20]>,
+    peer_id: [u8; 20],
+    filter: IpFilter,
+    inbound: mpsc::Sender<InboundPeer>,
+) {
+    let mut handshakes = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let Ok((stream, from)) = accepted else {
+                    continue;
+                };
+                if filter.blocks(from.ip()) {
+                    continue;
+                }
+                let info_hashes = info_hashes.clone();
+                let inbound = inbound.clone();
+                handshakes.spawn(async move {
+                    let encryption = EncryptionPolicy::default();
+                    if let Ok(peer) = peer::accept(stream, &info_hashes, peer_id, encryption).await {
+                        let _ = inbound.send(peer).await;
+                    }
+                });
+            }
+            Some(_) = handshakes.join_next(), if !handshakes.is_empty() => {}
+        }
+    }
+}
+
+/// Serves blocks to a peer after the handshake from `seeding` until it disconnects.
+///
+/// The peer may only request blocks while the choker gives it an upload slot; requests made
+/// while choked are ignored. An invalid request ends the connection. When super-seeding, it
+/// may only request the pieces it was told of. Pieces that fail their check when `seeding`
+/// verifies them on every read aren't sent either.
+///
+/// `allowed_fast` is set for a peer that speaks the Fast Extension (BEP 6), and holds the
+/// pieces it may download even while choked. Such a peer is told so, hears that we have all
+/// pieces with have-all, or none when super-seeding, and has the requests we won't serve
+/// rejected.
+pub async fn serve_peer<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    torrent: &Torrent,
+    seeding: &Seeding,
+    slot: UploadSlot,
+    allowed_fast: Option<&[u32]>,
+) -> anyhow::Result<()> {
+    let piece_count = torrent.piece_count();
+    let mut framed = Framed::new(stream, MessageCodec);
+    match (&seeding.super_seeder, allowed_fast) {
+        (Some(_), Some(_)) => framed.feed(PeerMessage::HaveNone).await?,
+        (Some(_), None) => {
+            let bitfield = Bitfield::new(piece_count).as_bytes().to_vec();
+            framed.feed(PeerMessage::Bitfield(bitfield)).await?;
+        }
+        (None, Some(allowed_fast)) => {
+            framed.feed(PeerMessage::HaveAll).await?;
+            for &index in allowed_fast {
+                framed.feed(PeerMessage::AllowedFast(index)).await?;
+            }
+        }
+        (None, None) => {
+            let bitfield = Bitfield::full(piece_count).as_bytes().to_vec();
+            framed.feed(PeerMessage::Bitfield(bitfield)).await?;
+        }
+    }
+    // when super-seeding: what the peer announced, the pieces it was told of and the last one
+    let mut has = Bitfield::new(piece_count);
+    let mut revealed = HashSet::new();
+    let mut current = None;
+    if let Some(super_seeder) = &seeding.super_seeder {
+        current = super_seeder.reveal(&has);
+        if let Some(index) = current {
+            revealed.insert(index);
+            framed.feed(PeerMessage::Have(index)).await?;
+        }
+    }
+    framed.flush().await?;
+    let is_allowed_fast = |index| allowed_fast.is_some_and(|allowed| allowed.contains(&index));
+    let is_revealed = |index, revealed: &HashSet<u32>| {
+        seeding.super_seeder.is_none() || revealed.contains(&index)
+    };
+
+    let mut unchoked = slot.unchoked();
+    // a peer that is choked or has all it wants may have nothing to say for a long time, but
+    // either side still has to hear something every so often
+    let mut last_sent = Instant::now();
+    let mut last_received = Instant::now();
+    loop {
+        let message = tokio::select! {
+            message = framed.next() => match message {
+                Some(message) => message?,
+                None => break,
+            },
+            changed = unchoked.changed() => {
+                changed?;
+                let message = if *unchoked.borrow_and_update() {
+                    PeerMessage::Unchoke
+                } else {
+                    PeerMessage::Choke
+                };
+                framed.send(message).await?;
+                last_sent = Instant::now();
+                continue;
+            }
+            () = tokio::time::sleep_until(last_sent + KEEP_ALIVE_INTERVAL) => {
+                framed.send(PeerMessage::KeepAlive).await?;
+                last_sent = Instant::now();
+                continue;
+            }
+            () = tokio::time::sleep_until(last_received + IDLE_TIMEOUT) => {
+                anyhow::bail!("peer sent nothing for {} seconds", IDLE_TIMEOUT.as_secs());
+            }
+        };
+        last_received = Instant::now();
+        match message {
+            PeerMessage::Interested => slot.set_interested(true),
+            PeerMessage::NotInterested => slot.set_interested(false),
+            PeerMessage::Bitfield(bytes) => {
+                let announced = Bitfield::from_message(&bytes, piece_count)?;
+                if let Some(super_seeder) = &seeding.super_seeder {
+                    let announced = announced.difference(&has);
+                    for index in announced.ones() {
+                        super_seeder.announced(index);
+                    }
+                    has.union_with(&announced);
+                }
+            }
+            PeerMessage::Have(index) => {
+                if index as usize >= piece_count {
+                    anyhow::bail!(
+                        "peer announced piece {}, but the torrent has {} pieces",
+                        index,
+                        piece_count
+                    );
+                }
+                let Some(super_seeder) = &seeding.super_seeder else {
+                    continue;
+                };
+                if !has.get(index as usize) {
+                    has.set(index as usize);
+                    super_seeder.announced(index as usize);
+                }
+                // the peer got the piece we told it of, so it hears of the next
+                if current == Some(index) {
+                    current = super_seeder.reveal(&has);
+                    if let Some(index) = current {
+                        revealed.insert(index);
+                        framed.send(PeerMessage::Have(index)).await?;
+                        last_sent = Instant::now();
+                    }
+                }
+            }
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } if is_revealed(index, &revealed)
+                && (*unchoked.borrow() || is_allowed_fast(index)) =>
+            {
+                let Some(block) = seeding.read_block(torrent, index, begin, length).await? else {
+                    if allowed_fast.is_some() {
+                        framed
+                            .send(PeerMessage::RejectRequest {
+                                index,
+                                begin,
+                                length,
+                            })
+                            .await?;
+                        last_sent = Instant::now();
+                    }
+                    continue;
+                };
+                framed
+                    .send(PeerMessage::Piece {
+                        index,
+                        begin,
+                        block,
+                    })
+                    .await?;
+                last_sent = Instant::now();
+                seeding.sent(&slot, length as usize);
+            }
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } if allowed_fast.is_some() => {
+                framed
+                    .send(PeerMessage::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    })
+                    .await?;
+                last_sent = Instant::now();
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The allowed fast set (BEP 6) of the peer at `ip`: `count` pieces picked by hashing its /24
+/// with `info_hash`, so a peer can't get more by reconnecting from neighbouring addresses.
+/// The BEP only defines it for IPv4, so IPv6 peers get none.
+pub fn allowed_fast_set(
+    ip: IpAddr,
+    info_hash: [u8; 20],
+    piece_count: usize,
+    count: usize,
+) -> Vec<u32> {
+    let IpAddr::V4(ip) = ip else {
+        return Vec::new();
+    };
+    let count = count.min(piece_count);
+    let mut set = Vec::with_capacity(count);
+    let mut hash = (u32::from(ip) & 0xffff_ff00).to_be_bytes().to_vec();
+    hash.extend_from_slice(&info_hash);
+    while set.len() < count {
+        hash = Sha1::digest(&hash).to_vec();
+        for chunk in hash.chunks_exact(4) {
+            let index = u32::from_be_bytes(chunk.try_into().unwrap()) % piece_count as u32;
+            if set.len() < count && !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+/// Checks that a requested block lies within its piece and is at most `max_length` long, and
+/// returns its offset in the torrent.
+fn request_offset(
+    torrent: &Torrent,
+    index: u32,
+    begin: u32,
+    length: u32,
+    max_length: u32,
+) -> anyhow::Result<usize> {
+    let index = index as usize;
+    if index >= torrent.piece_count() {
+        anyhow::bail!("peer requested piece {} which doesn't exist", index);
+    }
+    if length == 0 || length > max_length {
+        anyhow::bail!("peer requested a block of {} bytes", length);
+    }
+    let piece_start = index * torrent.info.piece_length;
+    let piece_length = torrent.piece_size(index);
+    if begin as usize + length as usize > piece_length {
+        anyhow::bail!(
+            "peer requested {} bytes at {} from {}-byte piece {}",
+            length,
+            begin,
+            piece_length,
+            index
+        );
+    }
+    Ok(piece_start + begin as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{announce_body, spawn_tracker, test_data, torrent_for};
+    use crate::download::{download, DownloadOptions};
+
+    #[tokio::test]
+    async fn seed_to_downloader() {
+        let data = test_data(100_000);
+        let mut torrent = torrent_for(&data, 32 * 1024);
+        let (url, requests) = spawn_tracker(vec![announce_body(60, &[])]).await;
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("seeded.bin");
+        std::fs::write(&source, &data).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let seeder = {
+            let torrent = torrent.clone();
+            tokio::spawn(async move {
+                let shutdown = async {
+                    let _ = stopped.await;
+                };
+                seed(
+                    &torrent,
+                    &source,
+                    [9; 20],
+                    listener,
+                    SeedOptions::default(),
+                    shutdown,
+                )
+                .await
+            })
+        };
+
+        let output = dir.path().join("downloaded.bin");
+        let options = DownloadOptions::default();
+        download(&torrent, &[addr], [1; 20], &output, &options)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+
+        stop.send(()).unwrap();
+        assert_eq!(seeder.await.unwrap().unwrap(), SeedingStopped::Shutdown);
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].contains("left=0"));
+        assert!(requests[0].contains("event=started"));
+        let last = requests.last().unwrap();
+        assert!(last.contains("event=stopped"));
+        assert!(last.contains(&format!("uploaded={}", data.len())));
+        assert!(!requests.iter().any(|r| r.contains("event=completed")));
+    }
+
+    #[test]
+    fn parse_limits() {
+        assert_eq!(parse_ratio("2").unwrap(), 2.0);
+        assert_eq!(parse_ratio("0.5").unwrap(), 0.5);
+        assert_eq!(parse_seed_time("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(
+            parse_seed_time("90m").unwrap(),
+            Duration::from_secs(90 * 60)
+        );
+        assert_eq!(
+            parse_seed_time("2D").unwrap(),
+            Duration::from_secs(2 * 86400)
+        );
+        for bad in ["0", "-1", "inf", "NaN", "x"] {
+            assert!(parse_ratio(bad).is_err(), "{}", bad);
+        }
+        for bad in ["0h", "1w", "1.5h", "h", ""] {
+            assert!(parse_seed_time(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn seed_until_limits() {
+        let data = test_data(100_000);
+        let mut torrent = torrent_for(&data, 32 * 1024);
+        let (url, requests) = spawn_tracker(vec![announce_body(60, &[])]).await;
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("seeded.bin");
+        std::fs::write(&source, &data).unwrap();
+
+        // once every byte went out the ratio of 1 is reached, without anyone stopping us
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = SeedOptions {
+            ratio: Some(1.0),
+            ..SeedOptions::default()
+        };
+        let seeder = {
+            let (torrent, source) = (torrent.clone(), source.clone());
+            tokio::spawn(async move {
+                let shutdown = std::future::pending();
+                seed(&torrent, &source, [9; 20], listener, options, shutdown).await
+            })
+        };
+        let output = dir.path().join("downloaded.bin");
+        download(
+            &torrent,
+            &[addr],
+            [1; 20],
+            &output,
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+        let stopped = tokio::time::timeout(Duration::from_secs(10), seeder);
+        let stopped = stopped.await.unwrap().unwrap().unwrap();
+        assert_eq!(stopped, SeedingStopped::RatioReached);
+        let last = requests.lock().unwrap().last().unwrap().clone();
+        assert!(last.contains("event=stopped"));
+        assert!(last.contains(&format!("uploaded={}", data.len())));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let options = SeedOptions {
+            time: Some(Duration::from_millis(50)),
+            ..SeedOptions::default()
+        };
+        let shutdown = std::future::pending();
+        let stopped = seed(&torrent, &source, [9; 20], listener, options, shutdown).await;
+        assert_eq!(stopped.unwrap(), SeedingStopped::TimeLimitReached);
+        assert!(requests
+            .lock()
+            .unwrap()
+            .last()
+            .unwrap()
+            .contains("event=stopped"));
+    }
+
+    #[tokio::test]
+    async fn seed_to_encrypted_downloader() {
+        let data = test_data(100_000);
+        let mut torrent = torrent_for(&data, 32 * 1024);
+        let (url, _) = spawn_tracker(vec![announce_body(60, &[])]).await;
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("seeded.bin");
+        std::fs::write(&source, &data).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let seeder = {
+            let torrent = torrent.clone();
+            tokio::spawn(async move {
+                seed(
+                    &torrent,
+                    &source,
+                    [9; 20],
+                    listener,
+                    SeedOptions::default(),
+                    std::future::pending(),
+                )
+                .await
+            })
+        };
+
+        let output = dir.path().join("downloaded.bin");
+        let options = DownloadOptions {
+            encryption: EncryptionPolicy::RequireEncrypted,
+            ..DownloadOptions::default()
+        };
+        download(&torrent, &[addr], [1; 20], &output, &options)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        seeder.abort();
+    }
+
+    #[tokio::test]
+    #[should_panic]
+    async fn seed_refuses_corrupt_data() {
+        let data = test_data(1000);
+        let torrent = torrent_for(&data, 100);
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("corrupt.bin");
+        let mut corrupt = data.clone();
+        corrupt[500] ^= 1;
+        std::fs::write(&source, corrupt).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        seed(
+            &torrent,
+            &source,
+            [9; 20],
+            listener,
+            SeedOptions::default(),
+            std::future::pending(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn serve_peer_keeps_quiet_peers_alive() {
+        let data = test_data(1000);
+        let torrent = torrent_for(&data, 300);
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("test.bin");
+        std::fs::write(&source, &data).unwrap();
+        let seeding = seeding_from(&torrent, &source, false).await;
+        let slot = Arc::new(Choker::new(UPLOAD_SLOTS)).register();
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let peer = tokio::spawn(async move {
+            let mut framed = Framed::new(theirs, MessageCodec);
+            framed.send(PeerMessage::Interested).await.unwrap();
+            // after a keep-alive of its own the peer doesn't say anything
+            let mut keep_alive = std::pin::pin!(tokio::time::sleep(Duration::from_secs(100)));
+            let mut sent = false;
+            let mut received = Vec::new();
+            loop {
+                tokio::select! {
+                    message = framed.next() => match message {
+                        Some(Ok(message)) => received.push((message, Instant::now())),
+                        _ => return received,
+                    },
+                    () = &mut keep_alive, if !sent => {
+                        framed.send(PeerMessage::KeepAlive).await.unwrap();
+                        sent = true;
+                    }
+                }
+            }
+        });
+
+        let start = Instant::now();
+        let error = serve_peer(ours, &torrent, &seeding, slot, None)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("sent nothing"), "{:#}", error);
+        assert_eq!(start.elapsed().as_secs(), 100 + IDLE_TIMEOUT.as_secs());
+        let keep_alives: Vec<u64> = peer
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|(message, _)| *message == PeerMessage::KeepAlive)
+            .map(|(_, at)| (at - start).as_secs())
+            .collect();
+        assert_eq!(keep_alives, [120, 240]);
+    }
+
+    #[tokio::test]
+    async fn serve_peer_ends_on_out_of_range_request() {
+        let data = test_data(1000);
+        let torrent = torrent_for(&data, 300);
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("test.bin");
+        std::fs::write(&source, &data).unwrap();
+        let seeding = seeding_from(&torrent, &source, false).await;
+        let slot = Arc::new(Choker::new(UPLOAD_SLOTS)).register();
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let peer = tokio::spawn(async move {
+            let mut framed = Framed::new(theirs, MessageCodec);
+            let bitfield = framed.next().await.unwrap().unwrap();
+            framed.send(PeerMessage::Interested).await.unwrap();
+            let unchoke = framed.next().await.unwrap().unwrap();
+            for (begin, length) in [(0, 100), (50, 100)] {
+                let request = PeerMessage::Request {
+                    index: 3,
+                    begin,
+                    length,
+                };
+                framed.send(request).await.unwrap();
+            }
+            let mut replies = Vec::new();
+            while let Some(Ok(message)) = framed.next().await {
+                replies.push(message);
+            }
+            (bitfield, unchoke, replies)
+        });
+
+        // the last piece is only 100 bytes long
+        assert!(serve_peer(ours, &torrent, &seeding, slot, None)
+            .await
+            .is_err());
+        let (bitfield, unchoke, replies) = peer.await.unwrap();
+        assert_eq!(bitfield, PeerMessage::Bitfield(vec![0b1111_0000]));
+        assert_eq!(unchoke, PeerMessage::Unchoke);
+        assert_eq!(
+            replies,
+            vec![PeerMessage::Piece {
+                index: 3,
+                begin: 0,
+                block: data[900..].to_vec()
+            }]
+        );
+        assert_eq!(seeding.progress.borrow().uploaded, 100);
+    }
+
+    #[tokio::test]
+    async fn serve_peer_ends_on_oversized_requests_and_bogus_announcements() {
+        let data = test_data(2 * 65536);
+        let torrent = torrent_for(&data, 65536);
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("test.bin");
+        std::fs::write(&source, &data).unwrap();
+        let request = PeerMessage::Request {
+            index: 1,
+            begin: 0,
+            length: 32 * 1024,
+        };
+        for (message, max_request_length, error) in [
+            (
+                request.clone(),
+                DEFAULT_MAX_REQUEST_LENGTH,
+                Some("block of 32768"),
+            ),
+            (request, MAX_REQUEST_LENGTH, None),
+            (
+                PeerMessage::Have(2),
+                MAX_REQUEST_LENGTH,
+                Some("announced piece 2"),
+            ),
+            (
+                PeerMessage::Bitfield(vec![0, 0]),
+                MAX_REQUEST_LENGTH,
+                Some("bitfield of 2 bytes"),
+            ),
+        ] {
+            let seeding = Seeding {
+                max_request_length,
+                ..seeding_from(&torrent, &source, false).await
+            };
+            let slot = Arc::new(Choker::new(UPLOAD_SLOTS)).register();
+            let (ours, theirs) = tokio::io::duplex(64 * 1024);
+            let peer = tokio::spawn(async move {
+                let mut framed = Framed::new(theirs, MessageCodec);
+                framed.next().await.unwrap().unwrap();
+                framed.send(PeerMessage::Interested).await.unwrap();
+                framed.next().await.unwrap().unwrap();
+                framed.send(message).await.unwrap();
+                // the answer to a request that is served
+                framed.next().await
+            });
+            let served = serve_peer(ours, &torrent, &seeding, slot, None).await;
+            let answer = peer.await.unwrap();
+            match error {
+                Some(error) => {
+                    let e = served.unwrap_err();
+                    assert!(e.to_string().contains(error), "{:#}", e);
+                    assert!(answer.is_none());
+                }
+                None => {
+                    assert!(served.is_ok());
+                    let Some(Ok(PeerMessage::Piece { block, .. })) = answer else {
+                        panic!("{:?}", answer);
+                    };
+                    assert_eq!(block, &data[65536..][..32 * 1024]);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_fast_peer_while_choked() {
+        let data = test_data(1000);
+        let torrent = torrent_for(&data, 300);
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("test.bin");
+        std::fs::write(&source, &data).unwrap();
+        let seeding = seeding_from(&torrent, &source, false).await;
+        let slot = Arc::new(Choker::new(UPLOAD_SLOTS)).register();
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let peer = tokio::spawn(async move {
+            let mut framed = Framed::new(theirs, MessageCodec);
+            // without saying we're interested we stay choked
+            for index in [2, 0] {
+                let request = PeerMessage::Request {
+                    index,
+                    begin: 0,
+                    length: 100,
+                };
+                framed.send(request).await.unwrap();
+            }
+            let mut replies = Vec::new();
+            for _ in 0..4 {
+                replies.push(framed.next().await.unwrap().unwrap());
+            }
+            // still connected, so the server is still at it when we're done
+            (replies, framed)
+        });
+
+        let server = serve_peer(ours, &torrent, &seeding, slot, Some(&[2]));
+        let replies = tokio::select! {
+            replies = peer => replies.unwrap().0,
+            _ = server => panic!("server stopped"),
+        };
+        assert_eq!(
+            replies,
+            vec![
+                PeerMessage::HaveAll,
+                PeerMessage::AllowedFast(2),
+                PeerMessage::Piece {
+                    index: 2,
+                    begin: 0,
+                    block: data[600..700].to_vec()
+                },
+                PeerMessage::RejectRequest {
+                    index: 0,
+                    begin: 0,
+                    length: 100
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn corrupt_pieces_are_not_uploaded() {
+        let data = test_data(1000);
+        let torrent = torrent_for(&data, 300);
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("test.bin");
+        std::fs::write(&source, &data).unwrap();
+        let seeding = Seeding {
+            verified: Some(VerifiedPieces::new(DEFAULT_CACHE_SIZE)),
+            ..seeding_from(&torrent, &source, false).await
+        };
+        // a byte of piece 0 goes bad after we started seeding
+        let mut bad = data.clone();
+        bad[10] ^= 0xff;
+        std::fs::write(&source, &bad).unwrap();
+        let slot = Arc::new(Choker::new(UPLOAD_SLOTS)).register();
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let peer = tokio::spawn(async move {
+            let mut framed = Framed::new(theirs, MessageCodec);
+            for (index, begin) in [(2, 0), (0, 0), (2, 100)] {
+                let request = PeerMessage::Request {
+                    index,
+                    begin,
+                    length: 100,
+                };
+                framed.send(request).await.unwrap();
+            }
+            let mut replies = Vec::new();
+            for _ in 0..6 {
+                replies.push(framed.next().await.unwrap().unwrap());
+            }
+            (replies, framed)
+        });
+
+        let server = serve_peer(ours, &torrent, &seeding, slot, Some(&[0, 2]));
+        let replies = tokio::select! {
+            replies = peer => replies.unwrap().0,
+            _ = server => panic!("server stopped"),
+        };
+        assert_eq!(
+            replies,
+            vec![
+                PeerMessage::HaveAll,
+                PeerMessage::AllowedFast(0),
+                PeerMessage::AllowedFast(2),
+                PeerMessage::Piece {
+                    index: 2,
+                    begin: 0,
+                    block: data[600..700].to_vec()
+                },
+                PeerMessage::RejectRequest {
+                    index: 0,
+                    begin: 0,
+                    length: 100
+                },
+                PeerMessage::Piece {
+                    index: 2,
+                    begin: 100,
+                    block: data[700..800].to_vec()
+                },
+            ]
+        );
+        let verified = seeding.verified.as_ref().unwrap();
+        assert!(verified.corrupt.lock().unwrap().contains(&0));
+        // only piece 2 is kept, read once for both of its blocks
+        let stats = seeding.cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.size), (1, 2, 300));
+    }
+
+    #[tokio::test]
+    async fn verified_pieces_keep_the_last_used() {
+        let data = test_data(1000);
+        let torrent = torrent_for(&data, 300);
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("test.bin");
+        std::fs::write(&source, &data).unwrap();
+        let seeding = seeding_from(&torrent, &source, false).await;
+        let verified = VerifiedPieces::new(600);
+        for (index, begin) in [(0, 0), (1, 50), (0, 200), (2, 0)] {
+            let block = verified
+                .block(&seeding.disk, index, begin, 100)
+                .await
+                .unwrap();
+            let offset = index * 300 + begin;
+            assert_eq!(block.unwrap(), &data[offset..offset + 100]);
+        }
+        // 0 was used after 1, so 1 made room for 2
+        assert_eq!(verified.pieces.stats().size, 600);
+        assert!(verified.pieces.get(1).is_none());
+        assert!(verified.pieces.get(0).is_some() && verified.pieces.get(2).is_some());
+    }
+
+    async fn seeding_from(torrent: &Torrent, source: &Path, super_seeding: bool) -> Seeding {
+        let storage = FileStorage::open(torrent, source).await.unwrap();
+        Seeding {
+            disk: Disk::spawn(Arc::new(torrent.clone()), storage),
+            progress: watch::channel(Progress::default()).0,
+            upload_limit: RateLimiter::new(None),
+            super_seeder: super_seeding.then(|| SuperSeeder::new(torrent.piece_count())),
+            max_request_length: DEFAULT_MAX_REQUEST_LENGTH,
+            verified: None,
+        }
+    }
+
+    #[test]
+    fn super_seeder_reveals_the_rarest_piece() {
+        let super_seeder = SuperSeeder::new(4);
+        super_seeder.announced(0);
+        super_seeder.announced(2);
+        assert_eq!(super_seeder.reveal(&Bitfield::new(4)), Some(1));
+        assert_eq!(
+            super_seeder.reveal(&[false, true, false, false].into_iter().collect()),
+            Some(3)
+        );
+        // ties go to the first piece, and pieces the peer has are never picked
+        assert_eq!(super_seeder.reveal(&Bitfield::new(4)), Some(0));
+        assert_eq!(
+            super_seeder.reveal(&[true, true, false, true].into_iter().collect()),
+            Some(2)
+        );
+        assert_eq!(super_seeder.reveal(&Bitfield::full(4)), None);
+    }
+
+    #[tokio::test]
+    async fn super_seed_one_piece_at_a_time() {
+        let data = test_data(1000);
+        let torrent = torrent_for(&data, 300);
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("test.bin");
+        std::fs::write(&source, &data).unwrap();
+        let seeding = seeding_from(&torrent, &source, true).await;
+        // another peer already has the first piece
+        seeding.super_seeder.as_ref().unwrap().announced(0);
+        let slot = Arc::new(Choker::new(UPLOAD_SLOTS)).register();
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let peer = tokio::spawn(async move {
+            let mut framed = Framed::new(theirs, MessageCodec);
+            let mut replies = Vec::new();
+            for _ in 0..2 {
+                replies.push(framed.next().await.unwrap().unwrap());
+            }
+            framed.send(PeerMessage::Interested).await.unwrap();
+            replies.push(framed.next().await.unwrap().unwrap());
+            // only the piece we were told of is served
+            for index in [2, 1] {
+                let request = PeerMessage::Request {
+                    index,
+                    begin: 0,
+                    length: 100,
+                };
+                framed.send(request).await.unwrap();
+            }
+            replies.push(framed.next().await.unwrap().unwrap());
+            // having it gets us told of the next rarest one
+            framed.send(PeerMessage::Have(1)).await.unwrap();
+            replies.push(framed.next().await.unwrap().unwrap());
+            (replies, framed)
+        });
+
+        let server = serve_peer(ours, &torrent, &seeding, slot, None);
+        let replies = tokio::select! {
+            replies = peer => replies.unwrap().0,
+            _ = server => panic!("server stopped"),
+        };
+        assert_eq!(
+            replies,
+            vec![
+                PeerMessage::Bitfield(vec![0]),
+                PeerMessage::Have(1),
+                PeerMessage::Unchoke,
+                PeerMessage::Piece {
+                    index: 1,
+                    begin: 0,
+                    block: data[300..400].to_vec()
+                },
+                PeerMessage::Have(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn allowed_fast_set_matches_the_bep() {
+        let ip = "80.4.4.200".parse().unwrap();
+        assert_eq!(
+            allowed_fast_set(ip, [0xaa; 20], 1313, 7),
+            [1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, [0xaa; 20], 1313, 9),
+            [1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        // a torrent with fewer pieces than the set size has them all allowed
+        assert_eq!(allowed_fast_set(ip, [0xaa; 20], 3, 7).len(), 3);
+        assert!(allowed_fast_set("::1".parse().unwrap(), [0xaa; 20], 1313, 7).is_empty());
+    }
+}
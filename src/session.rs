@@ -0,0 +1,2031 @@
+use crate::cache::DEFAULT_CACHE_SIZE;
+use crate::connector::{BanList, ConnectionSlots};
+use crate::dht::{self, Dht, BOOTSTRAP_NODES};
+use crate::disk::Disk;
+use crate::download::{
+    ban_list, check_max_size, download_from_swarm, follow_schedule, Discovery, DownloadOptions,
+    InboundPeer,
+};
+use crate::error::Error;
+use crate::events::{self, EventKind, EventSender, TorrentEvent, THROUGHPUT_INTERVAL};
+use crate::extension::resolve_magnet;
+use crate::external_ip::ExternalIp;
+use crate::hook::{self, HookEvent};
+use crate::ipfilter::FilterStats;
+use crate::lsd::{self, Lsd, LSD_GROUP};
+use crate::magnet::Magnet;
+use crate::mse::Transport;
+use crate::net;
+use crate::peer;
+use crate::picker::{piece_priorities, Priority};
+use crate::portmap::{PortMapper, Protocol};
+use crate::rate::RateLimiter;
+use crate::resume::ResumeData;
+use crate::seed::{serve_swarm, Seeding, SeedingPeers, SeedingStopped, DEFAULT_MAX_REQUEST_LENGTH};
+use crate::snapshot::{SessionSnapshot, TorrentSnapshot};
+use crate::stats::{SwarmStats, TorrentStats};
+use crate::storage::{self, FileStorage, Storage};
+use crate::supervisor::catch_panic;
+use crate::torrent::Torrent;
+use crate::tracker::{announce_periodically, Event, Progress, TrackerList, TrackerRequest};
+use crate::utp::UtpSocket;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{debug, warn};
+
+/// How long `Session::shutdown` waits for the torrents to stop before giving up on them.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// Where `random_port` picks the listen port from: the ports no service is registered on.
+const DYNAMIC_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// Where a torrent in a session stands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Waiting to start until fewer torrents download than the session's
+    /// `max_active_downloads`.
+    Queued,
+    Downloading,
+    Paused,
+    /// Downloaded, and not seeding: without `DownloadOptions::seeding`, done with its seeding
+    /// goal, with files skipped, or waiting until fewer torrents seed than the session's
+    /// `max_active_seeds`.
+    Finished,
+    /// Downloaded, and serving the peers that connect to us for it.
+    Seeding,
+    /// The download gave up, with the reason why.
+    Failed(String),
+}
+
+struct Entry {
+    torrent: Arc<Torrent>,
+    output: PathBuf,
+    state: Arc<watch::Sender<TorrentState>>,
+    /// The torrent's own download limit, within the session's.
+    download_limit: Arc<RateLimiter>,
+    /// How eagerly each of the torrent's files is downloaded.
+    file_priorities: watch::Sender<Vec<Priority>>,
+    /// The piece a sequential download goes on from.
+    read_position: watch::Sender<usize>,
+    /// When pieces are wanted by, as set with `Session::set_piece_deadline`.
+    piece_deadlines: watch::Sender<HashMap<usize, Instant>>,
+    /// Kept over pauses, so the totals cover every run in the session.
+    stats: Arc<SwarmStats>,
+    /// Where the torrent is in the queue: the lower, the sooner it starts. Unique, but not
+    /// necessarily consecutive.
+    queue_position: usize,
+    /// Started regardless of the queue, and not counted against its limit.
+    forced: bool,
+    /// The torrent's own seeding goal, instead of the session's.
+    seed_ratio: Option<f64>,
+    seed_time: Option<Duration>,
+    /// Set once it reached its seeding goal, so that it isn't seeded again.
+    seeded: bool,
+    running: Option<Running>,
+}
+
+impl Entry {
+    /// Whether the torrent takes one of the session's `max_active_downloads`.
+    fn is_active(&self) -> bool {
+        !self.forced && *self.state.borrow() == TorrentState::Downloading
+    }
+
+    /// Whether the torrent takes one of the session's `max_active_seeds`.
+    fn is_seeding(&self) -> bool {
+        !self.forced && *self.state.borrow() == TorrentState::Seeding
+    }
+
+    /// Whether all of the torrent's files are downloaded, none skipped.
+    fn is_complete(&self) -> bool {
+        !self.file_priorities.borrow().contains(&Priority::Skip)
+    }
+
+    /// Whether the torrent is finished with every file and waits to be seeded.
+    fn awaits_seeding(&self) -> bool {
+        *self.state.borrow() == TorrentState::Finished && !self.seeded && self.is_complete()
+    }
+}
+
+/// The download of a torrent while it runs.
+struct Running {
+    task: JoinHandle<()>,
+    /// Where to send peers that connect to us for it.
+    inbound: mpsc::Sender<InboundPeer>,
+    /// Set to have the download stop on its own.
+    shutdown: watch::Sender<bool>,
+}
+
+struct Shared {
+    peer_id: [u8; 20],
+    port: u16,
+    options: DownloadOptions,
+    dht: Option<Arc<Dht>>,
+    lsd: Option<Arc<Lsd>>,
+    utp: Option<Arc<UtpSocket>>,
+    /// Covers the downloads of all torrents.
+    download_limit: Arc<RateLimiter>,
+    /// The connections of all torrents together stay within these.
+    slots: Arc<ConnectionSlots>,
+    /// A peer banned from one torrent is banned from all of them.
+    bans: Arc<BanList>,
+    /// What the trackers of all torrents and the DHT nodes say our address is.
+    external_ip: ExternalIp,
+    torrents: Mutex<HashMap<[u8; 20], Entry>>,
+    events: broadcast::Sender<TorrentEvent>,
+    /// Set once the session shuts down, so that no queued torrent starts anymore.
+    closed: AtomicBool,
+}
+
+/// Runs any number of torrents side by side, identified by their info hashes.
+///
+/// All of them share one listening port: incoming connections are handed to the torrent whose
+/// info hash they ask for, over TCP and, if enabled, uTP. Every torrent has its own trackers,
+/// peers and files.
+pub struct Session {
+    shared: Arc<Shared>,
+    /// The accept loops, and what follows the rate limit schedule.
+    listeners: Vec<JoinHandle<()>>,
+    port_mapper: Mutex<Option<PortMapper>>,
+}
+
+impl Session {
+    /// Starts listening on the port in `options`, or the next free one, over IPv6 and IPv4 or
+    /// on the addresses the options list; they apply to every torrent. With the DHT enabled,
+    /// it is joined through the bootstrap nodes in the background. With local peer discovery
+    /// enabled, every torrent is announced to the `LSD_GROUP` multicast group. With uTP
+    /// enabled, uTP connections are accepted on the UDP port of the same number, and the DHT
+    /// makes do with any other. With port mapping enabled, the router is asked to forward the
+    /// ports in the background. An IP filter is loaded up front, and keeps its ranges out of
+    /// every torrent. Without an announce key in the options, the session makes up one for
+    /// all its announces.
+    pub async fn new(peer_id: [u8; 20], mut options: DownloadOptions) -> crate::Result<Session> {
+        options.announce.key.get_or_insert_with(rand::random);
+        let bans = ban_list(&options).await.map_err(Error::Config)?;
+        let bind = options.bind.as_ref();
+        let listening = listen(&options).map_err(Error::Network)?;
+        let port = listening.port;
+        // connections to peers go out over the first
+        let utp = listening.utp.first().cloned();
+        let port_mapper = options.port_mapping.then(|| {
+            let mut ports = vec![(Protocol::Tcp, port)];
+            if options.utp || options.dht {
+                ports.push((Protocol::Udp, port));
+            }
+            PortMapper::spawn(ports)
+        });
+        let dht = if options.dht {
+            let dht_port = if utp.is_some() { 0 } else { port };
+            let state = options.dht_state.as_deref();
+            let dht = Dht::bind_dual_stack(dht_port, state, bind).await;
+            let dht = Arc::new(dht.map_err(Error::Network)?);
+            let bootstrapping = dht.clone();
+            tokio::spawn(async move {
+                // until it succeeds, lookups only find nothing and are retried
+                let _ = bootstrapping.bootstrap(&BOOTSTRAP_NODES).await;
+            });
+            Some(dht)
+        } else {
+            None
+        };
+        // the DHT's nodes vote along with the trackers
+        let external_ip = dht
+            .as_ref()
+            .map_or_else(ExternalIp::new, |dht| dht.external_ip().clone());
+        let lsd = if options.lsd {
+            Some(Arc::new(
+                Lsd::bind(LSD_GROUP).await.map_err(Error::Network)?,
+            ))
+        } else {
+            None
+        };
+        let download_limit = Arc::new(RateLimiter::new(options.max_download_rate));
+        let scheduler = follow_schedule(&options, &download_limit);
+        let slots = Arc::new(ConnectionSlots::new(
+            options.max_connections,
+            options.max_half_open,
+        ));
+        let shared = Arc::new(Shared {
+            peer_id,
+            port,
+            options,
+            dht,
+            lsd,
+            utp,
+            download_limit,
+            slots,
+            bans: Arc::new(bans),
+            external_ip,
+            torrents: Mutex::new(HashMap::new()),
+            events: events::channel(),
+            closed: AtomicBool::new(false),
+        });
+        let mut listeners: Vec<_> = listening
+            .tcp
+            .into_iter()
+            .map(|listener| tokio::spawn(accept_peers(listener, shared.clone())))
+            .collect();
+        listeners.extend(
+            listening
+                .utp
+                .into_iter()
+                .map(|socket| tokio::spawn(accept_utp_peers(socket, shared.clone()))),
+        );
+        listeners.extend(scheduler);
+        Ok(Session {
+            shared,
+            listeners,
+            port_mapper: Mutex::new(port_mapper),
+        })
+    }
+
+    /// The port peers can connect to us on.
+    pub fn port(&self) -> u16 {
+        self.shared.port
+    }
+
+    /// The peer id the session is known by to trackers and peers.
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.shared.peer_id
+    }
+
+    /// The options the session's downloads go by.
+    pub fn options(&self) -> &DownloadOptions {
+        &self.shared.options
+    }
+
+    /// Starts downloading `torrent` to `output`, or queues it at the end if the session has
+    /// as many downloads going as it may, and returns its state, which changes as the download
+    /// goes on. Fails with `Error::AlreadyAdded` if the session has the torrent already, and
+    /// with `Error::Disk` if it is larger than the `max_size` of the session's options.
+    pub fn add_torrent(
+        &self,
+        torrent: Torrent,
+        output: &Path,
+    ) -> crate::Result<watch::Receiver<TorrentState>> {
+        self.add(torrent, output, false)
+    }
+
+    /// Like `add_torrent`, but a torrent the session has already gets the trackers of
+    /// `torrent` it lacks, which the `Error::AlreadyAdded` it fails with lists. A running
+    /// download announces to them once it is started again.
+    pub fn add_or_merge(
+        &self,
+        torrent: Torrent,
+        output: &Path,
+    ) -> crate::Result<watch::Receiver<TorrentState>> {
+        self.add(torrent, output, true)
+    }
+
+    fn add(
+        &self,
+        torrent: Torrent,
+        output: &Path,
+        merge_trackers: bool,
+    ) -> crate::Result<watch::Receiver<TorrentState>> {
+        check_max_size(&torrent, &self.shared.options)?;
+        let info_hash = torrent.info_hash();
+        let mut torrents = self.shared.torrents.lock().unwrap();
+        if let Some(entry) = torrents.get_mut(&info_hash) {
+            let merged_trackers = match merge_trackers {
+                true => Arc::make_mut(&mut entry.torrent).merge_trackers(&torrent),
+                false => Vec::new(),
+            };
+            return Err(Error::already_added(info_hash, merged_trackers));
+        }
+        let entry = self.entry(torrent, output.to_path_buf(), queue_end(&torrents));
+        let receiver = entry.state.subscribe();
+        torrents.insert(info_hash, entry);
+        promote(&self.shared, &mut torrents);
+        Ok(receiver)
+    }
+
+    fn entry(&self, torrent: Torrent, output: PathBuf, queue_position: usize) -> Entry {
+        Entry {
+            torrent: Arc::new(torrent),
+            output,
+            state: Arc::new(watch::Sender::new(TorrentState::Queued)),
+            download_limit: Arc::new(RateLimiter::with_parent(
+                None,
+                self.shared.download_limit.clone(),
+            )),
+            file_priorities: watch::Sender::new(Vec::new()),
+            read_position: watch::Sender::new(0),
+            piece_deadlines: watch::Sender::new(HashMap::new()),
+            stats: Arc::new(SwarmStats::new()),
+            queue_position,
+            forced: false,
+            seed_ratio: None,
+            seed_time: None,
+            seeded: false,
+            running: None,
+        }
+    }
+
+    /// The session's torrents and their settings, for `restore` to add back later or in
+    /// another session.
+    pub fn snapshot(&self) -> SessionSnapshot {
+        let torrents = self.shared.torrents.lock().unwrap();
+        let mut entries: Vec<&Entry> = torrents.values().collect();
+        entries.sort_by_key(|entry| entry.queue_position);
+        let torrents = entries
+            .into_iter()
+            .map(|entry| {
+                let stats = entry.stats.snapshot();
+                TorrentSnapshot {
+                    torrent: Torrent::clone(&entry.torrent),
+                    output: entry.output.clone(),
+                    paused: *entry.state.borrow() == TorrentState::Paused,
+                    forced: entry.forced,
+                    download_limit: entry.download_limit.rate(),
+                    file_priorities: entry.file_priorities.borrow().clone(),
+                    downloaded: stats.downloaded,
+                    uploaded: stats.uploaded,
+                }
+            })
+            .collect();
+        SessionSnapshot {
+            download_limit: self.shared.download_limit.rate(),
+            torrents,
+        }
+    }
+
+    /// Adds the torrents of `snapshot` with the settings they had, at the end of the queue in
+    /// the order they were in, and takes over its download limit; returns their info hashes.
+    /// Each torrent picks up from the resume data next to its output. If any of them is
+    /// already in the session, none is added.
+    pub fn restore(&self, snapshot: SessionSnapshot) -> crate::Result<Vec<[u8; 20]>> {
+        let mut torrents = self.shared.torrents.lock().unwrap();
+        let mut info_hashes = Vec::new();
+        for restored in &snapshot.torrents {
+            let info_hash = restored.torrent.info_hash();
+            if torrents.contains_key(&info_hash) || info_hashes.contains(&info_hash) {
+                return Err(Error::already_added(info_hash, Vec::new()));
+            }
+            piece_priorities(&restored.torrent, &restored.file_priorities)
+                .map_err(Error::Config)?;
+            info_hashes.push(info_hash);
+        }
+        self.shared.download_limit.set_rate(snapshot.download_limit);
+        for (restored, info_hash) in snapshot.torrents.into_iter().zip(&info_hashes) {
+            let mut entry = self.entry(restored.torrent, restored.output, queue_end(&torrents));
+            if restored.paused {
+                entry.state.send_replace(TorrentState::Paused);
+            }
+            entry.download_limit.set_rate(restored.download_limit);
+            entry.file_priorities.send_replace(restored.file_priorities);
+            entry.stats = Arc::new(SwarmStats::with_totals(
+                restored.downloaded,
+                restored.uploaded,
+            ));
+            if restored.forced {
+                entry.forced = true;
+                start(&self.shared, &mut entry);
+            }
+            torrents.insert(*info_hash, entry);
+        }
+        promote(&self.shared, &mut torrents);
+        Ok(info_hashes)
+    }
+
+    /// Stops a torrent and forgets about it; what it downloaded stays on disk.
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) -> crate::Result<()> {
+        let mut torrents = self.shared.torrents.lock().unwrap();
+        let mut entry = torrents
+            .remove(info_hash)
+            .ok_or_else(|| unknown(info_hash))?;
+        stop(&mut entry);
+        promote(&self.shared, &mut torrents);
+        Ok(())
+    }
+
+    /// Stops downloading a torrent for now. The trackers are told we stopped, and the pieces
+    /// downloaded so far are kept for when it is resumed.
+    pub fn pause(&self, info_hash: &[u8; 20]) -> crate::Result<()> {
+        let mut torrents = self.shared.torrents.lock().unwrap();
+        let entry = torrents
+            .get_mut(info_hash)
+            .ok_or_else(|| unknown(info_hash))?;
+        entry.forced = false;
+        if stop(entry) || *entry.state.borrow() == TorrentState::Queued {
+            entry.state.send_replace(TorrentState::Paused);
+        }
+        promote(&self.shared, &mut torrents);
+        Ok(())
+    }
+
+    /// Continues a paused or failed torrent where it left off, once it is its turn in the
+    /// queue.
+    pub fn resume(&self, info_hash: &[u8; 20]) -> crate::Result<()> {
+        let mut torrents = self.shared.torrents.lock().unwrap();
+        let entry = torrents
+            .get_mut(info_hash)
+            .ok_or_else(|| unknown(info_hash))?;
+        if matches!(
+            *entry.state.borrow(),
+            TorrentState::Paused | TorrentState::Failed(_)
+        ) {
+            entry.state.send_replace(TorrentState::Queued);
+            promote(&self.shared, &mut torrents);
+        }
+        Ok(())
+    }
+
+    /// Starts a queued, paused or failed torrent right away, however many others are
+    /// downloading; it doesn't count against `max_active_downloads`, so a downloading torrent
+    /// that is forced makes room for a queued one. A finished torrent of a session that seeds
+    /// is seeded right away in the same way, past `max_active_seeds` and with no seeding goal.
+    /// Pausing it ends that.
+    pub fn force_start(&self, info_hash: &[u8; 20]) -> crate::Result<()> {
+        let mut torrents = self.shared.torrents.lock().unwrap();
+        let entry = torrents
+            .get_mut(info_hash)
+            .ok_or_else(|| unknown(info_hash))?;
+        entry.forced = true;
+        let state = entry.state.borrow().clone();
+        match state {
+            TorrentState::Queued | TorrentState::Paused | TorrentState::Failed(_) => {
+                start(&self.shared, entry)
+            }
+            TorrentState::Finished if entry.is_complete() => start_seeding(&self.shared, entry),
+            _ => {}
+        }
+        promote(&self.shared, &mut torrents);
+        Ok(())
+    }
+
+    /// Where a torrent is in the queue, counting from 0 for the one to start first, among all
+    /// of the session's torrents.
+    pub fn queue_position(&self, info_hash: &[u8; 20]) -> Option<usize> {
+        let torrents = self.shared.torrents.lock().unwrap();
+        let position = torrents.get(info_hash)?.queue_position;
+        Some(
+            torrents
+                .values()
+                .filter(|entry| entry.queue_position < position)
+                .count(),
+        )
+    }
+
+    /// Moves a torrent to `position` in the queue, or to its end if that is past it. The
+    /// torrents from there on move back one. Moving a queued torrent ahead of a downloading
+    /// one doesn't stop the download: it only starts first once a place frees up.
+    pub fn set_queue_position(&self, info_hash: &[u8; 20], position: usize) -> crate::Result<()> {
+        let mut torrents = self.shared.torrents.lock().unwrap();
+        if !torrents.contains_key(info_hash) {
+            return Err(unknown(info_hash));
+        }
+        let mut order: Vec<[u8; 20]> = torrents
+            .iter()
+            .filter(|(hash, _)| *hash != info_hash)
+            .map(|(hash, _)| *hash)
+            .collect();
+        order.sort_by_key(|hash| torrents[hash].queue_position);
+        order.insert(position.min(order.len()), *info_hash);
+        for (queue_position, hash) in order.iter().enumerate() {
+            torrents.get_mut(hash).unwrap().queue_position = queue_position;
+        }
+        promote(&self.shared, &mut torrents);
+        Ok(())
+    }
+
+    /// Limits the download of one torrent to `rate` bytes per second, or lifts its limit with
+    /// `None`. The session's own limit still applies on top.
+    pub fn set_download_limit(
+        &self,
+        info_hash: &[u8; 20],
+        rate: Option<usize>,
+    ) -> crate::Result<()> {
+        let torrents = self.shared.torrents.lock().unwrap();
+        let entry = torrents.get(info_hash).ok_or_else(|| unknown(info_hash))?;
+        entry.download_limit.set_rate(rate);
+        Ok(())
+    }
+
+    /// Sets how eagerly each file of a torrent is downloaded, in the order of `Torrent::files`;
+    /// files past the end of `priorities` are `Normal`. Pieces that only hold data of skipped
+    /// files aren't downloaded, and a finished torrent goes on with the files that weren't.
+    pub fn set_file_priorities(
+        &self,
+        info_hash: &[u8; 20],
+        priorities: Vec<Priority>,
+    ) -> crate::Result<()> {
+        let mut torrents = self.shared.torrents.lock().unwrap();
+        let entry = torrents
+            .get_mut(info_hash)
+            .ok_or_else(|| unknown(info_hash))?;
+        piece_priorities(&entry.torrent, &priorities).map_err(Error::Config)?;
+        entry.file_priorities.send_replace(priorities);
+        let state = entry.state.borrow().clone();
+        if matches!(state, TorrentState::Finished | TorrentState::Seeding) {
+            stop(entry);
+            entry.state.send_replace(TorrentState::Queued);
+            promote(&self.shared, &mut torrents);
+        }
+        Ok(())
+    }
+
+    /// Gives a torrent a seeding goal of its own, instead of the one of the session's
+    /// `DownloadOptions::seeding`: it stops seeding once `ratio` times its length was uploaded
+    /// in the session, or after seeding for `time`. `None` leaves that part to the session's.
+    /// A seeding torrent goes on by the new goal, and one done with its old goal is seeded
+    /// again until it reaches the new one.
+    pub fn set_seed_limits(
+        &self,
+        info_hash: &[u8; 20],
+        ratio: Option<f64>,
+        time: Option<Duration>,
+    ) -> crate::Result<()> {
+        let mut torrents = self.shared.torrents.lock().unwrap();
+        let entry = torrents
+            .get_mut(info_hash)
+            .ok_or_else(|| unknown(info_hash))?;
+        entry.seed_ratio = ratio;
+        entry.seed_time = time;
+        entry.seeded = false;
+        // seeding starts over with the new goal
+        if entry.is_seeding() {
+            stop(entry);
+            entry.state.send_replace(TorrentState::Finished);
+        }
+        promote(&self.shared, &mut torrents);
+        Ok(())
+    }
+
+    /// Tells a torrent that its data is about to be read from `offset` bytes in, e.g. after a
+    /// media player seeked there. A sequential download goes on in order from there.
+    pub fn set_read_position(&self, info_hash: &[u8; 20], offset: usize) -> crate::Result<()> {
+        let torrents = self.shared.torrents.lock().unwrap();
+        let entry = torrents.get(info_hash).ok_or_else(|| unknown(info_hash))?;
+        let piece = offset / entry.torrent.info.piece_length;
+        entry.read_position.send_replace(piece);
+        Ok(())
+    }
+
+    /// Wants the piece at `index` of a torrent by `deadline`, e.g. so a media player reading it
+    /// doesn't stall. It is downloaded before all other pieces until it is done, and from
+    /// another peer as well once its deadline is close, at the cost of some bandwidth spent on
+    /// the same blocks twice. Pieces of skipped files still aren't downloaded.
+    pub fn set_piece_deadline(
+        &self,
+        info_hash: &[u8; 20],
+        index: usize,
+        deadline: Instant,
+    ) -> crate::Result<()> {
+        let torrents = self.shared.torrents.lock().unwrap();
+        let entry = torrents.get(info_hash).ok_or_else(|| unknown(info_hash))?;
+        let piece_count = entry.torrent.piece_count();
+        if index >= piece_count {
+            return Err(Error::Other(anyhow::anyhow!(
+                "the torrent has no piece {} of {}",
+                index,
+                piece_count
+            )));
+        }
+        entry.piece_deadlines.send_modify(|deadlines| {
+            deadlines.insert(index, deadline);
+        });
+        Ok(())
+    }
+
+    /// Fetches the metadata of the torrent behind a magnet link, from the peers its trackers
+    /// or the session's DHT know of.
+    pub async fn resolve_magnet(&self, magnet: &Magnet) -> crate::Result<Torrent> {
+        let dht = self.shared.dht.as_deref();
+        let resolved = resolve_magnet(magnet, self.shared.peer_id, self.shared.port, dht).await?;
+        Ok(resolved.0)
+    }
+
+    /// Changes the limit on the download of all torrents together.
+    pub fn set_global_download_limit(&self, rate: Option<usize>) {
+        self.shared.download_limit.set_rate(rate);
+    }
+
+    /// Subscribes to the events of every torrent in the session. A subscriber that falls too
+    /// far behind misses events and gets `RecvError::Lagged` instead.
+    pub fn subscribe(&self) -> broadcast::Receiver<TorrentEvent> {
+        self.shared.events.subscribe()
+    }
+
+    /// Stops every torrent like pausing it does, except that each download still records the
+    /// pieces it wrote, saves its resume data and tells its trackers it stopped before this
+    /// returns. New connections are no longer accepted, mapped ports are removed again and the
+    /// DHT's routing table is saved.
+    /// Torrents that take longer than `SHUTDOWN_TIMEOUT` are given up on.
+    pub async fn shutdown(&self) {
+        self.shared.closed.store(true, Ordering::Relaxed);
+        for listener in &self.listeners {
+            listener.abort();
+        }
+        let tasks: Vec<JoinHandle<()>> = {
+            let mut torrents = self.shared.torrents.lock().unwrap();
+            torrents
+                .values_mut()
+                .filter_map(|entry| entry.running.take())
+                .map(|running| {
+                    running.shutdown.send_replace(true);
+                    running.task
+                })
+                .collect()
+        };
+        let deadline = tokio::time::Instant::now() + SHUTDOWN_TIMEOUT;
+        for mut task in tasks {
+            if tokio::time::timeout_at(deadline, &mut task).await.is_err() {
+                task.abort();
+            }
+        }
+        let port_mapper = self.port_mapper.lock().unwrap().take();
+        if let Some(port_mapper) = port_mapper {
+            port_mapper.stop().await;
+        }
+        if let Some(dht) = &self.shared.dht {
+            if let Err(e) = dht.save().await {
+                warn!("can't save the DHT state: {:#}", e);
+            }
+        }
+    }
+
+    /// The state of a torrent in the session, if it is one.
+    pub fn state(&self, info_hash: &[u8; 20]) -> Option<watch::Receiver<TorrentState>> {
+        let torrents = self.shared.torrents.lock().unwrap();
+        torrents.get(info_hash).map(|entry| entry.state.subscribe())
+    }
+
+    /// What a torrent in the session transferred, in all and with each connected peer.
+    pub fn stats(&self, info_hash: &[u8; 20]) -> Option<TorrentStats> {
+        let torrents = self.shared.torrents.lock().unwrap();
+        torrents.get(info_hash).map(|entry| entry.stats.snapshot())
+    }
+
+    /// How many nodes the DHT's routing table holds; `None` without the DHT.
+    pub fn dht_nodes(&self) -> Option<usize> {
+        self.shared.dht.as_ref().map(|dht| dht.node_count())
+    }
+
+    /// Our public address in each family, as most of the trackers and DHT nodes that told us
+    /// one agree; behind a NAT, the router's. Empty until one of them did.
+    pub fn external_ips(&self) -> Vec<IpAddr> {
+        self.shared.external_ip.ips()
+    }
+
+    /// How many peers the IP filter kept out, over all torrents.
+    pub fn filter_stats(&self) -> FilterStats {
+        self.shared.bans.filter_stats()
+    }
+}
+
+/// Starts as many of the queued torrents as there is room for, first the one first in the
+/// queue.
+fn promote(shared: &Arc<Shared>, torrents: &mut HashMap<[u8; 20], Entry>) {
+    if shared.closed.load(Ordering::Relaxed) {
+        return;
+    }
+    let active = torrents.values().filter(|entry| entry.is_active()).count();
+    let room = match shared.options.max_active_downloads {
+        Some(max) => max.saturating_sub(active),
+        None => usize::MAX,
+    };
+    let mut queued: Vec<(usize, [u8; 20])> = torrents
+        .iter()
+        .filter(|(_, entry)| *entry.state.borrow() == TorrentState::Queued)
+        .map(|(hash, entry)| (entry.queue_position, *hash))
+        .collect();
+    queued.sort();
+    for (_, hash) in queued.into_iter().take(room) {
+        start(shared, torrents.get_mut(&hash).unwrap());
+    }
+    let Some(seeding) = &shared.options.seeding else {
+        return;
+    };
+    let seeds = torrents.values().filter(|entry| entry.is_seeding()).count();
+    let room = match seeding.max_active_seeds {
+        Some(max) => max.saturating_sub(seeds),
+        None => usize::MAX,
+    };
+    let mut finished: Vec<(usize, [u8; 20])> = torrents
+        .iter()
+        .filter(|(_, entry)| entry.awaits_seeding())
+        .map(|(hash, entry)| (entry.queue_position, *hash))
+        .collect();
+    finished.sort();
+    for (_, hash) in finished.into_iter().take(room) {
+        start_seeding(shared, torrents.get_mut(&hash).unwrap());
+    }
+}
+
+fn start(shared: &Arc<Shared>, entry: &mut Entry) {
+    let (inbound_tx, inbound) = mpsc::channel(16);
+    let shared = shared.clone();
+    let torrent = entry.torrent.clone();
+    let output = entry.output.clone();
+    let state = entry.state.clone();
+    let download_limit = entry.download_limit.clone();
+    let file_priorities = entry.file_priorities.subscribe();
+    let read_position = entry.read_position.subscribe();
+    let piece_deadlines = entry.piece_deadlines.subscribe();
+    let stats = entry.stats.clone();
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    let shutting_down = shutdown_tx.subscribe();
+    let events = EventSender::new(torrent.info_hash(), shared.events.clone());
+    state.send_replace(TorrentState::Downloading);
+    let task = tokio::spawn(async move {
+        let discovery = Discovery {
+            port: shared.port,
+            inbound,
+            dht: shared.dht.clone(),
+            lsd: shared.lsd.clone(),
+            utp: shared.utp.clone(),
+            download_limit,
+            slots: shared.slots.clone(),
+            bans: shared.bans.clone(),
+            file_priorities,
+            read_position,
+            piece_deadlines,
+            stats,
+            shutdown,
+            external_ip: shared.external_ip.clone(),
+        };
+        // a bug in one download fails that torrent, rather than leaving it downloading forever
+        let result = catch_panic(download_from_swarm(
+            &torrent,
+            shared.peer_id,
+            discovery,
+            &output,
+            &shared.options,
+            events.clone(),
+        ))
+        .await;
+        // with the disk full, the next torrent in the queue wouldn't get far either
+        let disk_full = result.as_ref().is_err_and(storage::is_disk_full);
+        let shut_down = *shutting_down.borrow() || disk_full;
+        state.send_replace(match result {
+            Ok(()) if shut_down => TorrentState::Paused,
+            Ok(()) => TorrentState::Finished,
+            Err(e) if disk_full => {
+                warn!(name = %torrent.info.name, "pausing: {:#}", e);
+                events.send(EventKind::DiskFull);
+                TorrentState::Paused
+            }
+            Err(e) => {
+                warn!(name = %torrent.info.name, "download failed: {:#}", e);
+                TorrentState::Failed(format!("{:#}", e))
+            }
+        });
+        // its place goes to the next torrent in the queue
+        if !shut_down {
+            promote(&shared, &mut shared.torrents.lock().unwrap());
+        }
+    });
+    entry.running = Some(Running {
+        task,
+        inbound: inbound_tx,
+        shutdown: shutdown_tx,
+    });
+}
+
+/// Seeds the finished torrent of `entry` to the peers that connect to us for it, until it
+/// reaches its seeding goal or is stopped; one started by force has no goal.
+fn start_seeding(shared: &Arc<Shared>, entry: &mut Entry) {
+    let Some(limits) = &shared.options.seeding else {
+        return;
+    };
+    let goal = match entry.forced {
+        true => (None, None),
+        false => (
+            entry.seed_ratio.or(limits.ratio),
+            entry.seed_time.or(limits.time),
+        ),
+    };
+    let on_seed_goal = limits.on_seed_goal.clone();
+    let (inbound_tx, inbound) = mpsc::channel(16);
+    let shared = shared.clone();
+    let torrent = entry.torrent.clone();
+    let output = entry.output.clone();
+    let state = entry.state.clone();
+    let stats = entry.stats.clone();
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    let shutting_down = shutdown_tx.subscribe();
+    let events = EventSender::new(torrent.info_hash(), shared.events.clone());
+    state.send_replace(TorrentState::Seeding);
+    let task = tokio::spawn(async move {
+        let seeded = seed_finished(
+            &shared, &torrent, &output, goal, stats, inbound, shutdown, events,
+        );
+        let result = catch_panic(seeded).await;
+        let mut torrents = shared.torrents.lock().unwrap();
+        match result {
+            Ok(_) if *shutting_down.borrow() => {
+                state.send_replace(TorrentState::Paused);
+                return;
+            }
+            Ok(stopped) => {
+                debug!(name = %torrent.info.name, ?stopped, "stopped seeding");
+                let reached = stopped != SeedingStopped::Shutdown;
+                if let Some(entry) = torrents.get_mut(&torrent.info_hash()) {
+                    entry.seeded = reached;
+                }
+                if let Some(command) = on_seed_goal.as_deref().filter(|_| reached) {
+                    hook::spawn(command, HookEvent::Seeded, &torrent, &output);
+                }
+                state.send_replace(TorrentState::Finished);
+            }
+            Err(e) => {
+                warn!(name = %torrent.info.name, "seeding failed: {:#}", e);
+                state.send_replace(TorrentState::Failed(format!("{:#}", e)));
+            }
+        }
+        // its place goes to the next finished torrent
+        promote(&shared, &mut torrents);
+    });
+    entry.running = Some(Running {
+        task,
+        inbound: inbound_tx,
+        shutdown: shutdown_tx,
+    });
+}
+
+/// Serves `torrent` from its files at `output` to the peers arriving from `inbound`, announced
+/// to its trackers as a seed and to the DHT and the local network unless it is private, until
+/// `shutdown` is set or the share ratio or seeding time of `goal` is reached. The ratio counts
+/// what `stats` holds for the torrent already, and what is uploaded is added there.
+#[allow(clippy::too_many_arguments)]
+async fn seed_finished(
+    shared: &Shared,
+    torrent: &Torrent,
+    output: &Path,
+    goal: (Option<f64>, Option<Duration>),
+    stats: Arc<SwarmStats>,
+    inbound: mpsc::Receiver<InboundPeer>,
+    mut shutdown: watch::Receiver<bool>,
+    events: EventSender,
+) -> anyhow::Result<SeedingStopped> {
+    let options = &shared.options;
+    // the files are where the download left them, renamed as it found them
+    let torrent = Arc::new(match ResumeData::load_recorded(torrent, output).await {
+        Some(resume) => resume.renaming(torrent),
+        None => torrent.clone(),
+    });
+    let storage: Box<dyn Storage> = match &options.storage {
+        Some(storage) => storage.open(&torrent).await?,
+        None => Box::new(
+            FileStorage::open(&torrent, output)
+                .await?
+                .with_backend(options.storage_backend)
+                .await?,
+        ),
+    };
+    let cache_size = options.cache_size.unwrap_or(DEFAULT_CACHE_SIZE);
+    let disk = Disk::spawn(torrent.clone(), storage).with_cache(cache_size);
+
+    let mut trackers = TrackerList::with_override(&torrent, &options.trackers)?
+        .with_proxy(options.tracker_proxy.clone())
+        .with_bind(options.bind.clone())
+        .with_http(options.tracker_http.clone())
+        .with_external_ip(shared.external_ip.clone());
+    let mut request = TrackerRequest::with_left(0, shared.peer_id, shared.port);
+    request.event = Some(Event::Started);
+    request.params = options.announce.clone();
+    let (peer_tx, peer_rx) = mpsc::channel(4);
+    let (progress_tx, progress_rx) = watch::channel(Progress::default());
+    let info_hash = torrent.info_hash();
+    let announcers = match trackers.announce(&info_hash, &request).await {
+        Ok(response) => {
+            let announce = |info_hash, wait| {
+                tokio::spawn(announce_periodically(
+                    trackers.clone(),
+                    info_hash,
+                    request.clone(),
+                    wait,
+                    peer_tx.clone(),
+                    progress_rx.clone(),
+                    events.clone(),
+                ))
+            };
+            // as when downloading, the other info hash of a hybrid torrent follows right away
+            let mut announcers = vec![announce(info_hash, response.reannounce_after())];
+            let others = torrent.info_hashes().into_iter().skip(1);
+            announcers.extend(others.map(|other| announce(other, Duration::ZERO)));
+            announcers
+        }
+        // peers that know us from before, the DHT or the local network can still connect
+        Err(e) => {
+            events.send(EventKind::TrackerError(format!("{:#}", e)));
+            Vec::new()
+        }
+    };
+    // unlike the announcers, these have nothing to say when seeding stops, so they go with it
+    let mut lookups = JoinSet::new();
+    if let Some(dht) = shared.dht.clone().filter(|_| !torrent.is_private()) {
+        let progress = progress_rx.clone();
+        let lookup =
+            dht::announce_periodically(dht, info_hash, shared.port, peer_tx.clone(), progress);
+        lookups.spawn(lookup);
+    }
+    if let Some(lsd) = shared.lsd.clone().filter(|_| !torrent.is_private()) {
+        let progress = progress_rx.clone();
+        let announcer =
+            lsd::announce_periodically(lsd, info_hash, shared.port, peer_tx.clone(), progress);
+        lookups.spawn(announcer);
+    }
+    drop(peer_tx);
+    let counting = tokio::spawn(count_uploads(progress_rx, disk.clone(), stats.clone()));
+
+    let seeding = Arc::new(Seeding {
+        disk,
+        progress: progress_tx,
+        upload_limit: RateLimiter::new(None),
+        super_seeder: None,
+        max_request_length: DEFAULT_MAX_REQUEST_LENGTH,
+        verified: None,
+    });
+    let (ratio, time) = goal;
+    let uploaded = stats.snapshot().uploaded;
+    let target = ratio.map(|ratio| {
+        let target = (ratio * torrent.total_length() as f64).ceil() as usize;
+        target.saturating_sub(uploaded)
+    });
+    let peers = SeedingPeers {
+        inbound,
+        announced: peer_rx,
+    };
+    let shutdown = async {
+        let _ = shutdown.wait_for(|&shutdown| shutdown).await;
+    };
+    let stopped = serve_swarm(&torrent, &seeding, peers, target, time, shutdown).await;
+    // with the last sender gone the announcers send the stopped event
+    drop(seeding);
+    for announcer in announcers {
+        announcer.await?;
+    }
+    counting.await?;
+    Ok(stopped)
+}
+
+/// Adds what `progress` says was uploaded to `stats` as it grows, and samples the rates and
+/// how the cache of `disk` did, until the sender of `progress` is gone.
+async fn count_uploads(
+    mut progress: watch::Receiver<Progress>,
+    disk: Disk,
+    stats: Arc<SwarmStats>,
+) {
+    let start = tokio::time::Instant::now() + THROUGHPUT_INTERVAL;
+    let mut sampling = tokio::time::interval_at(start, THROUGHPUT_INTERVAL);
+    let mut counted = 0;
+    loop {
+        let closed = tokio::select! {
+            changed = progress.changed() => changed.is_err(),
+            _ = sampling.tick() => {
+                stats.sample(THROUGHPUT_INTERVAL);
+                stats.set_cache(disk.cache_stats());
+                continue;
+            }
+        };
+        let uploaded = progress.borrow_and_update().uploaded;
+        stats.uploaded(None, uploaded - counted);
+        counted = uploaded;
+        if closed {
+            return;
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for listener in &self.listeners {
+            listener.abort();
+        }
+        for entry in self.shared.torrents.lock().unwrap().values_mut() {
+            stop(entry);
+        }
+    }
+}
+
+/// Stops the torrent's download if it is still going, telling whether it was.
+///
+/// Dropping the download is enough: the pieces it finished are already recorded in the resume
+/// data, the tasks it owns are cancelled with it, and its tracker announcer sends the stopped
+/// event once the download is gone.
+fn stop(entry: &mut Entry) -> bool {
+    match entry.running.take() {
+        Some(running) if !running.task.is_finished() => {
+            running.task.abort();
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The queue position of a torrent added behind all of `torrents`.
+fn queue_end(torrents: &HashMap<[u8; 20], Entry>) -> usize {
+    torrents
+        .values()
+        .map(|entry| entry.queue_position + 1)
+        .max()
+        .unwrap_or(0)
+}
+
+fn unknown(info_hash: &[u8; 20]) -> Error {
+    Error::unknown_torrent(*info_hash)
+}
+
+/// The sockets a session accepts peers on.
+struct Listening {
+    /// The port trackers and the DHT are told.
+    port: u16,
+    tcp: Vec<TcpListener>,
+    /// None without uTP.
+    utp: Vec<Arc<UtpSocket>>,
+}
+
+/// Listens where `options` say, on the first port they allow that none of the sockets finds
+/// taken.
+fn listen(options: &DownloadOptions) -> anyhow::Result<Listening> {
+    let ports = (0..=options.listen_retries).filter_map(|attempt| {
+        if options.random_port {
+            Some(rand::random_range(DYNAMIC_PORTS))
+        } else if options.port == 0 {
+            // a free port for TCP may still be taken for uTP
+            Some(0)
+        } else {
+            options.port.checked_add(attempt)
+        }
+    });
+    let mut taken = Vec::new();
+    for port in ports {
+        match listen_on(port, options) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                debug!(port, "port taken, listening on another");
+                taken.push(port);
+            }
+            listening => return Ok(listening?),
+        }
+    }
+    Err(
+        anyhow::Error::new(io::Error::from(io::ErrorKind::AddrInUse))
+            .context(format!("no port free to listen on: tried {:?}", taken)),
+    )
+}
+
+/// Listens on `port`, any free one if it is 0, of every address `options` list, or of every
+/// address or the one of their `bind` if they list none. Addresses of their own port keep it.
+fn listen_on(port: u16, options: &DownloadOptions) -> io::Result<Listening> {
+    let mut listening = Listening {
+        port,
+        tcp: Vec::new(),
+        utp: Vec::new(),
+    };
+    let bind = options.bind.as_ref();
+    if options.listen.is_empty() {
+        let listener = net::listen_tcp_on(port, bind)?;
+        listening.port = listener.local_addr()?.port();
+        if options.utp {
+            let socket = net::bind_udp_on(listening.port, bind)?;
+            listening
+                .utp
+                .push(Arc::new(UtpSocket::from_socket(socket)?));
+        }
+        listening.tcp.push(listener);
+        return Ok(listening);
+    }
+    // the port the addresses without one share, once any free one was picked for the first
+    let mut shared = None;
+    let mut own = None;
+    for &addr in &options.listen {
+        let listener = match addr.port() {
+            0 => {
+                let listener =
+                    net::listen_tcp_at(SocketAddr::new(addr.ip(), shared.unwrap_or(port)))?;
+                shared = Some(listener.local_addr()?.port());
+                listener
+            }
+            _ => {
+                own.get_or_insert(addr.port());
+                net::listen_tcp_at(addr)?
+            }
+        };
+        if options.utp {
+            let socket = net::bind_udp_at(listener.local_addr()?)?;
+            listening
+                .utp
+                .push(Arc::new(UtpSocket::from_socket(socket)?));
+        }
+        listening.tcp.push(listener);
+    }
+    listening.port = shared.or(own).unwrap_or(port);
+    Ok(listening)
+}
+
+/// Hands every incoming connection to the running torrent it asks for.
+async fn accept_peers(listener: TcpListener, shared: Arc<Shared>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        admit(stream.into(), shared.clone());
+    }
+}
+
+/// Like `accept_peers`, for connections over uTP.
+async fn accept_utp_peers(socket: Arc<UtpSocket>, shared: Arc<Shared>) {
+    while let Ok((stream, _)) = socket.accept().await {
+        admit(stream.into(), shared.clone());
+    }
+}
+
+/// Hands `stream` to the running torrent its handshake asks for, by any of the torrent's info
+/// hashes.
+fn admit(stream: Transport, shared: Arc<Shared>) {
+    if stream
+        .peer_addr()
+        .is_ok_and(|addr| shared.bans.refuses_connection_from(addr.ip()))
+    {
+        return;
+    }
+    // the handshake may take a while, which mustn't hold up the next connection
+    tokio::spawn(async move {
+        let running: Vec<[u8; 20]> = {
+            let torrents = shared.torrents.lock().unwrap();
+            torrents
+                .values()
+                .filter(|entry| entry.running.is_some())
+                .flat_map(|entry| entry.torrent.info_hashes())
+                .collect()
+        };
+        let encryption = shared.options.encryption;
+        let Ok((stream, handshake)) =
+            peer::accept(stream, &running, shared.peer_id, encryption).await
+        else {
+            return;
+        };
+        let inbound = {
+            let torrents = shared.torrents.lock().unwrap();
+            torrents
+                .values()
+                .find(|entry| entry.torrent.info_hashes().contains(&handshake.info_hash))
+                .and_then(|entry| entry.running.as_ref())
+                .map(|running| running.inbound.clone())
+        };
+        if let Some(inbound) = inbound {
+            let _ = inbound.send((stream, handshake)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::download;
+    use crate::download::tests::{
+        announce_body, multi_file_torrent_for, spawn_seeder, spawn_stalling_peer, spawn_tracker,
+        test_data, torrent_for,
+    };
+    use crate::peer::generate_peer_id;
+    use crate::seed::SeedLimits;
+    use crate::storage::OpenStorage;
+    use crate::tracker::tests::unreachable_tracker;
+    use futures_util::future::BoxFuture;
+    use tokio::net::TcpStream;
+
+    fn options() -> DownloadOptions {
+        DownloadOptions {
+            port: 0,
+            ..DownloadOptions::default()
+        }
+    }
+
+    async fn finished(mut state: watch::Receiver<TorrentState>) -> TorrentState {
+        state
+            .wait_for(|state| matches!(state, TorrentState::Finished | TorrentState::Failed(_)))
+            .await
+            .unwrap()
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn downloads_several_torrents() {
+        let session = Session::new([1; 20], options()).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut downloads = Vec::new();
+        for (index, length) in [50_000, 70_000].into_iter().enumerate() {
+            let data = test_data(length);
+            let mut torrent = torrent_for(&data, 16384);
+            torrent.info.name = format!("test{}.bin", index);
+            let seeder = spawn_seeder(&torrent, data.clone()).await;
+            let (url, _) = spawn_tracker(vec![announce_body(60, &[seeder])]).await;
+            torrent.announce = url;
+            let output = dir.path().join(&torrent.info.name);
+            let state = session.add_torrent(torrent, &output).unwrap();
+            downloads.push((state, output, data));
+        }
+        for (state, output, data) in downloads {
+            assert_eq!(finished(state).await, TorrentState::Finished);
+            assert_eq!(std::fs::read(output).unwrap(), data);
+        }
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume() {
+        let data = test_data(50_000);
+        let mut torrent = torrent_for(&data, 16384);
+        let (stalling, _) = spawn_stalling_peer(&torrent).await;
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let (url, requests) = spawn_tracker(vec![
+            announce_body(60, &[stalling]),
+            announce_body(60, &[stalling]),
+            announce_body(60, &[seeder]),
+        ])
+        .await;
+        torrent.announce = url;
+        let info_hash = torrent.info_hash();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        let session = Session::new([1; 20], options()).await.unwrap();
+        let state = session.add_torrent(torrent.clone(), &output).unwrap();
+        assert!(session.add_torrent(torrent, &output).is_err());
+        while requests.lock().unwrap().is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        session.pause(&info_hash).unwrap();
+        assert_eq!(*state.borrow(), TorrentState::Paused);
+        // the stopped announce comes from the download's announcer winding down
+        while requests.lock().unwrap().len() < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(requests.lock().unwrap()[1].contains("event=stopped"));
+
+        session.resume(&info_hash).unwrap();
+        assert_eq!(finished(state).await, TorrentState::Finished);
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        session.remove_torrent(&info_hash).unwrap();
+        assert!(session.state(&info_hash).is_none());
+        assert!(matches!(
+            session.pause(&info_hash),
+            Err(Error::UnknownTorrent { info_hash: unknown, .. }) if unknown == info_hash
+        ));
+    }
+
+    #[tokio::test]
+    async fn queues_downloads_past_the_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = DownloadOptions {
+            max_active_downloads: Some(1),
+            ..options()
+        };
+        let session = Session::new([1; 20], options).await.unwrap();
+        let mut torrents = Vec::new();
+        for (index, seeded) in [false, true, true].into_iter().enumerate() {
+            let data = test_data(50_000);
+            let mut torrent = torrent_for(&data, 16384);
+            torrent.info.name = format!("test{}.bin", index);
+            let peer = match seeded {
+                true => spawn_seeder(&torrent, data).await,
+                false => spawn_stalling_peer(&torrent).await.0,
+            };
+            let (url, _) = spawn_tracker(vec![announce_body(60, &[peer])]).await;
+            torrent.announce = url;
+            let info_hash = torrent.info_hash();
+            let output = dir.path().join(&torrent.info.name);
+            torrents.push((info_hash, session.add_torrent(torrent, &output).unwrap()));
+        }
+        let [(stalled, stalled_state), (forced, forced_state), (last, last_state)] = &torrents[..]
+        else {
+            unreachable!();
+        };
+        assert_eq!(*stalled_state.borrow(), TorrentState::Downloading);
+        assert_eq!(*forced_state.borrow(), TorrentState::Queued);
+        assert_eq!(*last_state.borrow(), TorrentState::Queued);
+        assert_eq!(session.queue_position(last), Some(2));
+
+        // ahead of the others, but the running download isn't stopped for it
+        session.set_queue_position(last, 0).unwrap();
+        assert_eq!(session.queue_position(last), Some(0));
+        assert_eq!(session.queue_position(stalled), Some(1));
+        assert_eq!(*last_state.borrow(), TorrentState::Queued);
+
+        session.force_start(forced).unwrap();
+        assert_eq!(*forced_state.borrow(), TorrentState::Downloading);
+        assert_eq!(finished(forced_state.clone()).await, TorrentState::Finished);
+        assert_eq!(*last_state.borrow(), TorrentState::Queued);
+
+        // pausing the stalled download makes room
+        session.pause(stalled).unwrap();
+        assert_eq!(*stalled_state.borrow(), TorrentState::Paused);
+        assert_eq!(finished(last_state.clone()).await, TorrentState::Finished);
+        session.resume(stalled).unwrap();
+        assert_eq!(*stalled_state.borrow(), TorrentState::Downloading);
+        assert!(session.set_queue_position(&[0; 20], 0).is_err());
+        session.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_cleanly() {
+        let data = test_data(50_000);
+        let mut torrent = torrent_for(&data, 16384);
+        let (stalling, _) = spawn_stalling_peer(&torrent).await;
+        let (url, requests) = spawn_tracker(vec![announce_body(60, &[stalling])]).await;
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        let session = Session::new([1; 20], options()).await.unwrap();
+        let state = session.add_torrent(torrent.clone(), &output).unwrap();
+        while requests.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::timeout(Duration::from_secs(10), session.shutdown())
+            .await
+            .unwrap();
+        // by the time it returns the trackers were told and the resume data was saved
+        assert!(requests.lock().unwrap()[1].contains("event=stopped"));
+        assert_eq!(*state.borrow(), TorrentState::Paused);
+        assert!(ResumeData::load(&torrent, &output).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn announces_its_peer_id() {
+        let data = test_data(50_000);
+        let mut torrent = torrent_for(&data, 16384);
+        let (stalling, _) = spawn_stalling_peer(&torrent).await;
+        let (url, requests) = spawn_tracker(vec![announce_body(60, &[stalling])]).await;
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+
+        let peer_id = generate_peer_id("-XX0001-").unwrap();
+        let session = Session::new(peer_id, options()).await.unwrap();
+        assert_eq!(session.peer_id(), peer_id);
+        session
+            .add_torrent(torrent, &dir.path().join("test.bin"))
+            .unwrap();
+        while requests.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(requests.lock().unwrap()[0].contains("peer_id=-XX0001-"));
+        session.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn downloads_only_wanted_files() {
+        let data = test_data(64 * 1024);
+        let mut torrent = multi_file_torrent_for(&data, 16384, &[("a", 32768), ("b", 32768)]);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let (url, _) = spawn_tracker(vec![announce_body(60, &[seeder])]).await;
+        torrent.announce = url;
+        let info_hash = torrent.info_hash();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test");
+
+        let session = Session::new([1; 20], options()).await.unwrap();
+        let state = session.add_torrent(torrent, &output).unwrap();
+        session
+            .set_file_priorities(&info_hash, vec![Priority::Skip])
+            .unwrap();
+        assert_eq!(finished(state.clone()).await, TorrentState::Finished);
+        assert_eq!(std::fs::read(output.join("a")).unwrap(), vec![0; 32768]);
+        assert_eq!(std::fs::read(output.join("b")).unwrap(), &data[32768..]);
+
+        // wanting the skipped file later picks the download up again
+        session
+            .set_file_priorities(&info_hash, vec![Priority::High])
+            .unwrap();
+        assert_eq!(finished(state).await, TorrentState::Finished);
+        assert_eq!(std::fs::read(output.join("a")).unwrap(), &data[..32768]);
+        assert!(session
+            .set_file_priorities(&info_hash, vec![Priority::Low; 3])
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn downloads_with_piece_deadlines() {
+        let session = Session::new([1; 20], options()).await.unwrap();
+        let data = test_data(100_000);
+        let mut torrent = torrent_for(&data, 16384);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let (url, _) = spawn_tracker(vec![announce_body(60, &[seeder])]).await;
+        torrent.announce = url;
+        let info_hash = torrent.info_hash();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        let state = session.add_torrent(torrent, &output).unwrap();
+        session
+            .set_piece_deadline(&info_hash, 6, Instant::now())
+            .unwrap();
+        session
+            .set_piece_deadline(&info_hash, 3, Instant::now() + Duration::from_secs(1))
+            .unwrap();
+        assert!(session
+            .set_piece_deadline(&info_hash, 7, Instant::now())
+            .is_err());
+        assert!(session
+            .set_piece_deadline(&[2; 20], 0, Instant::now())
+            .is_err());
+        assert_eq!(finished(state).await, TorrentState::Finished);
+        assert_eq!(std::fs::read(output).unwrap(), data);
+    }
+
+    /// Storage on a disk that has no room left for anything.
+    #[derive(Debug)]
+    struct FullDisk;
+
+    impl Storage for FullDisk {
+        fn read_block(
+            &mut self,
+            _: usize,
+            length: usize,
+        ) -> BoxFuture<'_, anyhow::Result<Vec<u8>>> {
+            Box::pin(async move { Ok(vec![0; length]) })
+        }
+
+        fn write_block<'a>(
+            &'a mut self,
+            _: usize,
+            _: &'a [u8],
+        ) -> BoxFuture<'a, anyhow::Result<()>> {
+            Box::pin(async { Err(std::io::Error::from(std::io::ErrorKind::StorageFull).into()) })
+        }
+
+        fn flush(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    impl OpenStorage for FullDisk {
+        fn open(&self, _: &Torrent) -> BoxFuture<'static, anyhow::Result<Box<dyn Storage>>> {
+            Box::pin(async { Ok(Box::new(FullDisk) as Box<dyn Storage>) })
+        }
+    }
+
+    #[tokio::test]
+    async fn pauses_when_the_disk_is_full() {
+        let data = test_data(50_000);
+        let mut torrent = torrent_for(&data, 16384);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let (url, _) = spawn_tracker(vec![announce_body(60, &[seeder])]).await;
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+        let options = DownloadOptions {
+            storage: Some(Arc::new(FullDisk)),
+            ..options()
+        };
+
+        let session = Session::new([1; 20], options).await.unwrap();
+        let mut events = session.subscribe();
+        let mut state = session
+            .add_torrent(torrent, &dir.path().join("test.bin"))
+            .unwrap();
+        let paused = state
+            .wait_for(|state| !matches!(state, TorrentState::Queued | TorrentState::Downloading))
+            .await
+            .unwrap()
+            .clone();
+        assert_eq!(paused, TorrentState::Paused);
+        let mut kinds = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            kinds.push(event.kind);
+        }
+        assert!(kinds.contains(&EventKind::DiskFull));
+    }
+
+    /// Storage with a bug that brings down the disk writer.
+    #[derive(Debug)]
+    struct BrokenDisk;
+
+    impl Storage for BrokenDisk {
+        fn read_block(
+            &mut self,
+            _: usize,
+            length: usize,
+        ) -> BoxFuture<'_, anyhow::Result<Vec<u8>>> {
+            Box::pin(async move { Ok(vec![0; length]) })
+        }
+
+        fn write_block<'a>(
+            &'a mut self,
+            offset: usize,
+            _: &'a [u8],
+        ) -> BoxFuture<'a, anyhow::Result<()>> {
+            panic!("no room for a write at {}", offset)
+        }
+
+        fn flush(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    impl OpenStorage for BrokenDisk {
+        fn open(&self, _: &Torrent) -> BoxFuture<'static, anyhow::Result<Box<dyn Storage>>> {
+            Box::pin(async { Ok(Box::new(BrokenDisk) as Box<dyn Storage>) })
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_a_torrent_whose_task_panics() {
+        let data = test_data(50_000);
+        let mut torrent = torrent_for(&data, 16384);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let (url, _) = spawn_tracker(vec![announce_body(60, &[seeder])]).await;
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+        let options = DownloadOptions {
+            storage: Some(Arc::new(BrokenDisk)),
+            ..options()
+        };
+
+        let session = Session::new([1; 20], options).await.unwrap();
+        let state = session
+            .add_torrent(torrent, &dir.path().join("test.bin"))
+            .unwrap();
+        match finished(state).await {
+            TorrentState::Failed(message) => {
+                assert!(
+                    message.contains("disk writer failed: panicked: no room"),
+                    "{}",
+                    message
+                )
+            }
+            other => panic!("expected the torrent to fail, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn publishes_events() {
+        let data = test_data(50_000);
+        let mut torrent = torrent_for(&data, 16384);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let (url, _) = spawn_tracker(vec![announce_body(60, &[seeder])]).await;
+        torrent.announce = url;
+        let info_hash = torrent.info_hash();
+        let dir = tempfile::tempdir().unwrap();
+
+        let session = Session::new([1; 20], options()).await.unwrap();
+        let mut events = session.subscribe();
+        let state = session
+            .add_torrent(torrent, &dir.path().join("test.bin"))
+            .unwrap();
+        assert_eq!(finished(state).await, TorrentState::Finished);
+
+        let mut kinds = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            assert_eq!(event.info_hash, info_hash);
+            kinds.push(event.kind);
+        }
+        assert!(kinds.contains(&EventKind::PeerConnected(seeder)));
+        let mut pieces: Vec<usize> = kinds
+            .iter()
+            .filter_map(|kind| match kind {
+                EventKind::PieceCompleted(index) => Some(*index),
+                _ => None,
+            })
+            .collect();
+        pieces.sort();
+        assert_eq!(pieces, [0, 1, 2, 3]);
+        assert!(kinds.contains(&EventKind::TorrentFinished));
+        let stats = session.stats(&info_hash).unwrap();
+        assert_eq!(stats.downloaded, 50_000);
+        assert_eq!(stats.hash_failures, 0);
+    }
+
+    #[tokio::test]
+    async fn lists_connected_peers() {
+        let mut torrent = torrent_for(&test_data(50_000), 16384);
+        let (stalling, _) = spawn_stalling_peer(&torrent).await;
+        let (url, _) = spawn_tracker(vec![announce_body(60, &[stalling])]).await;
+        torrent.announce = url;
+        let info_hash = torrent.info_hash();
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::new([1; 20], options()).await.unwrap();
+        session
+            .add_torrent(torrent, &dir.path().join("test.bin"))
+            .unwrap();
+
+        let wait_for = |listed: bool| {
+            let session = &session;
+            tokio::time::timeout(std::time::Duration::from_secs(10), async move {
+                loop {
+                    let stats = session.stats(&info_hash).unwrap();
+                    if stats.peers.is_empty() != listed {
+                        return stats;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+            })
+        };
+        let stats = wait_for(true).await.unwrap();
+        assert_eq!(stats.peers[0].addr, stalling);
+        assert_eq!(stats.peers[0].client, None);
+        assert_eq!(stats.peers[0].downloaded, 0);
+        // a paused torrent's workers are cancelled, which unlists their peers
+        session.pause(&info_hash).unwrap();
+        wait_for(false).await.unwrap();
+        assert!(session.stats(&[0; 20]).is_none());
+    }
+
+    #[tokio::test]
+    async fn publishes_tracker_errors() {
+        let mut torrent = torrent_for(&test_data(1000), 100);
+        torrent.announce = unreachable_tracker();
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::new([1; 20], options()).await.unwrap();
+        let mut events = session.subscribe();
+        let state = session
+            .add_torrent(torrent, &dir.path().join("test.bin"))
+            .unwrap();
+        assert!(matches!(finished(state).await, TorrentState::Failed(_)));
+        assert!(matches!(
+            events.recv().await.unwrap().kind,
+            EventKind::TrackerError(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn restores_a_snapshot() {
+        let options = || DownloadOptions {
+            max_active_downloads: Some(0),
+            ..options()
+        };
+        let session = Session::new([1; 20], options()).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut info_hashes = Vec::new();
+        for name in ["a.bin", "b.bin"] {
+            let mut torrent = torrent_for(&test_data(1000), 100);
+            torrent.info.name = name.to_owned();
+            info_hashes.push(torrent.info_hash());
+            session
+                .add_torrent(torrent, &dir.path().join(name))
+                .unwrap();
+        }
+        let (a, b) = (&info_hashes[0], &info_hashes[1]);
+        session.set_queue_position(b, 0).unwrap();
+        session.pause(a).unwrap();
+        session.set_download_limit(a, Some(500)).unwrap();
+        session
+            .set_file_priorities(b, vec![Priority::High])
+            .unwrap();
+        session.set_global_download_limit(Some(2000));
+
+        let moved = Session::new([1; 20], options()).await.unwrap();
+        let restored = moved.restore(session.snapshot()).unwrap();
+        assert_eq!(restored, vec![*b, *a]);
+        assert_eq!(*moved.state(a).unwrap().borrow(), TorrentState::Paused);
+        assert_eq!(*moved.state(b).unwrap().borrow(), TorrentState::Queued);
+        assert_eq!(moved.queue_position(b), Some(0));
+        let snapshot = moved.snapshot();
+        assert_eq!(snapshot.download_limit, Some(2000));
+        assert_eq!(snapshot.torrents[0].file_priorities, vec![Priority::High]);
+        assert_eq!(snapshot.torrents[1].download_limit, Some(500));
+        assert_eq!(snapshot.torrents[1].output, dir.path().join("a.bin"));
+
+        // nothing is added twice, not even the torrents that weren't in the session yet
+        moved.remove_torrent(a).unwrap();
+        assert!(matches!(
+            moved.restore(session.snapshot()),
+            Err(Error::AlreadyAdded { .. })
+        ));
+        assert!(moved.state(a).is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_a_torrent_larger_than_the_max_size() {
+        let options = DownloadOptions {
+            max_size: Some(999),
+            ..options()
+        };
+        let session = Session::new([1; 20], options).await.unwrap();
+        let (url, requests) = spawn_tracker(vec![announce_body(60, &[])]).await;
+        let mut torrent = torrent_for(&test_data(1000), 100);
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+
+        let error = session.add_torrent(torrent.clone(), &output).unwrap_err();
+        assert!(matches!(error, Error::Disk(_)), "{:?}", error);
+        assert!(session.state(&torrent.info_hash()).is_none());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(requests.lock().unwrap().is_empty());
+        assert!(!output.exists());
+    }
+
+    #[tokio::test]
+    async fn merges_the_trackers_of_a_torrent_added_again() {
+        let options = DownloadOptions {
+            max_active_downloads: Some(0),
+            ..options()
+        };
+        let session = Session::new([1; 20], options).await.unwrap();
+        let output = tempfile::tempdir().unwrap().path().join("test.bin");
+        let mut torrent = torrent_for(&test_data(1000), 100);
+        torrent.announce = "http://one/announce".to_owned();
+        let info_hash = torrent.info_hash();
+        session.add_torrent(torrent.clone(), &output).unwrap();
+
+        let mut again = torrent.clone();
+        again.announce_list = vec![
+            vec!["http://one/announce".to_owned()],
+            vec!["http://two/announce".to_owned()],
+        ];
+        let error = session.add_torrent(again.clone(), &output).unwrap_err();
+        let Error::AlreadyAdded {
+            info_hash: rejected,
+            merged_trackers,
+            ..
+        } = error
+        else {
+            panic!("{}", error);
+        };
+        assert_eq!(rejected, info_hash);
+        assert!(merged_trackers.is_empty());
+        assert_eq!(
+            session.snapshot().torrents[0].torrent.announce_list.len(),
+            0
+        );
+
+        let error = session.add_or_merge(again.clone(), &output).unwrap_err();
+        assert!(matches!(&error, Error::AlreadyAdded { merged_trackers, .. }
+            if merged_trackers == &["http://two/announce"]));
+        assert!(error
+            .to_string()
+            .ends_with("added its tracker http://two/announce"));
+        let snapshot = session.snapshot();
+        assert_eq!(snapshot.torrents.len(), 1);
+        assert_eq!(
+            snapshot.torrents[0].torrent.announce_list,
+            again.announce_list
+        );
+        let error = session.add_or_merge(again, &output).unwrap_err();
+        assert!(matches!(error, Error::AlreadyAdded { merged_trackers, .. }
+            if merged_trackers.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn refuses_connections_for_unknown_torrents() {
+        let session = Session::new([1; 20], options()).await.unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], session.port()));
+        assert!(peer::connect(addr, [9; 20], [2; 20]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn listens_on_the_next_free_port() {
+        let taken = net::listen_tcp(0).unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let options = DownloadOptions {
+            port,
+            listen_retries: 3,
+            ..options()
+        };
+        let session = Session::new([1; 20], options.clone()).await.unwrap();
+        assert!((port + 1..=port + 3).contains(&session.port()));
+        TcpStream::connect(("127.0.0.1", session.port()))
+            .await
+            .unwrap();
+
+        let no_retries = DownloadOptions {
+            listen_retries: 0,
+            ..options
+        };
+        let error = Session::new([1; 20], no_retries).await.err().unwrap();
+        assert!(error.to_string().contains("no port free"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn listens_on_each_address() {
+        let addresses = DownloadOptions {
+            listen: vec!["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()],
+            utp: true,
+            ..options()
+        };
+        let session = Session::new([1; 20], addresses).await.unwrap();
+        for ip in ["127.0.0.1", "::1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            TcpStream::connect((ip, session.port())).await.unwrap();
+            let client = UtpSocket::bind((ip, 0)).await.unwrap();
+            client.connect((ip, session.port()).into()).await.unwrap();
+        }
+
+        let random = DownloadOptions {
+            random_port: true,
+            ..options()
+        };
+        let session = Session::new([1; 20], random).await.unwrap();
+        assert!(DYNAMIC_PORTS.contains(&session.port()));
+    }
+
+    #[tokio::test]
+    async fn accepts_utp_connections() {
+        let options = DownloadOptions {
+            utp: true,
+            ..options()
+        };
+        let session = Session::new([1; 20], options).await.unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], session.port()));
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = client.connect(addr).await.unwrap();
+        // the session knows no torrent to hand the peer to
+        assert!(peer::handshake(&mut stream, [9; 20], [2; 20])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_filtered_peers_out() {
+        let data = test_data(50_000);
+        let mut torrent = torrent_for(&data, 16384);
+        let seeder = spawn_seeder(&torrent, data).await;
+        let (url, _) = spawn_tracker(vec![announce_body(60, &[seeder])]).await;
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+        let blocklist = dir.path().join("blocklist.dat");
+        std::fs::write(
+            &blocklist,
+            "127.000.000.000 - 127.255.255.255 , 000 , Loopback\n",
+        )
+        .unwrap();
+        let options = DownloadOptions {
+            ip_filter: Some(blocklist),
+            ..options()
+        };
+        let session = Session::new([1; 20], options).await.unwrap();
+        let _state = session
+            .add_torrent(torrent.clone(), &dir.path().join("out"))
+            .unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], session.port()));
+        assert!(peer::connect(addr, torrent.info_hash(), [2; 20])
+            .await
+            .is_err());
+        let stats = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let stats = session.filter_stats();
+                if stats.blocked_outbound > 0 {
+                    return stats;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(stats.ranges, 1);
+        // the plaintext connection, and the encrypted one tried after it
+        assert_eq!(stats.blocked_inbound, 2);
+        assert!(session
+            .stats(&torrent.info_hash())
+            .unwrap()
+            .peers
+            .is_empty());
+
+        let options = DownloadOptions {
+            ip_filter: Some(dir.path().join("missing")),
+            ..DownloadOptions::default()
+        };
+        assert!(matches!(
+            Session::new([1; 20], options).await,
+            Err(Error::Config(_))
+        ));
+    }
+
+    async fn seeding(mut state: watch::Receiver<TorrentState>) -> TorrentState {
+        let state = state
+            .wait_for(|state| matches!(state, TorrentState::Seeding | TorrentState::Failed(_)));
+        tokio::time::timeout(Duration::from_secs(10), state)
+            .await
+            .unwrap()
+            .unwrap()
+            .clone()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn seeds_finished_torrents_up_to_their_ratio() {
+        let data = test_data(50_000);
+        let mut torrent = torrent_for(&data, 16384);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let (url, requests) = spawn_tracker(vec![announce_body(60, &[seeder])]).await;
+        torrent.announce = url;
+        let info_hash = torrent.info_hash();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test.bin");
+        let options = DownloadOptions {
+            seeding: Some(SeedLimits {
+                ratio: Some(1.0),
+                on_seed_goal: Some("echo \"$TORRENT_EVENT\" >> \"$TORRENT_PATH.log\"".to_owned()),
+                ..SeedLimits::default()
+            }),
+            ..options()
+        };
+        let session = Session::new([1; 20], options).await.unwrap();
+        let state = session.add_torrent(torrent.clone(), &output).unwrap();
+        assert_eq!(seeding(state.clone()).await, TorrentState::Seeding);
+        let announced_as_seed = || {
+            let requests = requests.lock().unwrap();
+            let mut started = requests.iter().filter(|r| r.contains("event=started"));
+            started
+                .next_back()
+                .is_some_and(|request| request.contains("left=0"))
+        };
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        while !announced_as_seed() {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "not announced as a seed"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // another peer gets all of it from us, which is the ratio's worth
+        let addr = SocketAddr::from(([127, 0, 0, 1], session.port()));
+        let copy = dir.path().join("copy.bin");
+        download(
+            &torrent,
+            &[addr],
+            [2; 20],
+            &copy,
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&copy).unwrap(), data);
+        assert_eq!(finished(state).await, TorrentState::Finished);
+        assert!(session.stats(&info_hash).unwrap().uploaded >= data.len());
+        let log = dir.path().join("test.bin.log");
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        while !log.exists() {
+            assert!(tokio::time::Instant::now() < deadline, "hook didn't run");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "seeded\n");
+        // done with its goal, it isn't seeded again
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            *session.state(&info_hash).unwrap().borrow(),
+            TorrentState::Finished
+        );
+        session.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn seeds_past_the_limit_once_another_reaches_its_goal() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = DownloadOptions {
+            seeding: Some(SeedLimits {
+                max_active_seeds: Some(1),
+                ..SeedLimits::default()
+            }),
+            ..options()
+        };
+        let session = Session::new([1; 20], options).await.unwrap();
+        let mut states = Vec::new();
+        for index in 0..2 {
+            let data = test_data(50_000);
+            let mut torrent = torrent_for(&data, 16384);
+            torrent.info.name = format!("test{}.bin", index);
+            let seeder = spawn_seeder(&torrent, data).await;
+            let (url, _) = spawn_tracker(vec![announce_body(60, &[seeder])]).await;
+            torrent.announce = url;
+            let output = dir.path().join(&torrent.info.name);
+            let state = session.add_torrent(torrent.clone(), &output).unwrap();
+            states.push((torrent.info_hash(), state));
+        }
+        // whichever finished first seeds, and the other waits
+        let both_done = async {
+            loop {
+                let current: Vec<TorrentState> = states
+                    .iter()
+                    .map(|(_, state)| state.borrow().clone())
+                    .collect();
+                if current.contains(&TorrentState::Seeding)
+                    && current.contains(&TorrentState::Finished)
+                {
+                    return current;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let current = tokio::time::timeout(Duration::from_secs(10), both_done)
+            .await
+            .unwrap();
+        let seeding_first = current[0] == TorrentState::Seeding;
+        let (first, first_state) = &states[if seeding_first { 0 } else { 1 }];
+        let (second, second_state) = &states[if seeding_first { 1 } else { 0 }];
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*second_state.borrow(), TorrentState::Finished);
+
+        // a goal of its own ends the first one's seeding, which frees its place
+        session
+            .set_seed_limits(first, None, Some(Duration::from_millis(100)))
+            .unwrap();
+        assert_eq!(seeding(second_state.clone()).await, TorrentState::Seeding);
+        assert_eq!(*first_state.borrow(), TorrentState::Finished);
+
+        // a forced one seeds anyway, past the limit and its goal
+        session.force_start(first).unwrap();
+        assert_eq!(*first_state.borrow(), TorrentState::Seeding);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(*first_state.borrow(), TorrentState::Seeding);
+        assert_eq!(*second_state.borrow(), TorrentState::Seeding);
+        session.pause(second).unwrap();
+        assert_eq!(*second_state.borrow(), TorrentState::Paused);
+        session.shutdown().await;
+    }
+}
@@ -0,0 +1,144 @@
+use crate::bencode;
+use crate::picker::Priority;
+use crate::torrent::Torrent;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Everything a session knows about its torrents, as `Session::snapshot` takes it and
+/// `Session::restore` puts it back, e.g. on another machine. Stored bencoded in one file.
+///
+/// What was downloaded isn't in it: that stays with the data, in the resume file next to
+/// each torrent's output, which has to move along with it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SessionSnapshot {
+    /// The limit on the download of all torrents together, in bytes per second.
+    #[serde(
+        rename = "download limit",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub download_limit: Option<usize>,
+    /// In queue order.
+    pub torrents: Vec<TorrentSnapshot>,
+}
+
+/// One torrent of a `SessionSnapshot`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TorrentSnapshot {
+    /// Stored as its metainfo file.
+    #[serde(with = "metainfo")]
+    pub torrent: Torrent,
+    pub output: PathBuf,
+    /// Paused rather than waiting in the queue; failed torrents are queued again.
+    pub paused: bool,
+    /// Started regardless of the queue.
+    pub forced: bool,
+    #[serde(
+        rename = "download limit",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub download_limit: Option<usize>,
+    /// Per file, in the order of `Torrent::files`; empty if all are `Normal`.
+    #[serde(
+        rename = "file priorities",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub file_priorities: Vec<Priority>,
+    /// Bytes downloaded and uploaded while in the session.
+    pub downloaded: usize,
+    pub uploaded: usize,
+}
+
+impl SessionSnapshot {
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<SessionSnapshot> {
+        Ok(bencode::from_bytes(bytes)?)
+    }
+
+    /// Fails for outputs whose paths aren't UTF-8.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(bencode::to_bytes(self)?)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<SessionSnapshot> {
+        let bytes = std::fs::read(path)?;
+        SessionSnapshot::from_bytes(&bytes)
+            .map_err(|e| e.context(format!("{} is no session snapshot", path.display())))
+    }
+
+    /// Writes the snapshot to `path`, replacing what is there only once it is complete.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut partial = OsString::from(path);
+        partial.push(".part");
+        std::fs::write(&partial, self.to_bytes()?)?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    /// Moves the output of every torrent into `dir`, under the same name, for data that was
+    /// copied to a different place than it was at.
+    pub fn relocate(&mut self, dir: &Path) {
+        for snapshot in &mut self.torrents {
+            if let Some(name) = snapshot.output.file_name() {
+                snapshot.output = dir.join(name);
+            }
+        }
+    }
+}
+
+mod metainfo {
+    use super::*;
+    use serde::de::Error;
+    use serde_bytes::ByteBuf;
+
+    pub fn serialize<S: Serializer>(torrent: &Torrent, serializer: S) -> Result<S::Ok, S::Error> {
+        serde_bytes::serialize(&torrent.to_bytes(), serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Torrent, D::Error> {
+        let bytes = ByteBuf::deserialize(deserializer)?;
+        Torrent::from_bytes(&bytes).map_err(|e| D::Error::custom(format!("{:#}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::torrent_for;
+
+    #[test]
+    fn round_trips() {
+        let torrent = torrent_for(&[7; 100], 32);
+        let mut snapshot = SessionSnapshot {
+            download_limit: Some(1000),
+            torrents: vec![TorrentSnapshot {
+                torrent: torrent.clone(),
+                output: PathBuf::from("/data/old/file"),
+                paused: true,
+                forced: false,
+                download_limit: None,
+                file_priorities: vec![Priority::High],
+                downloaded: 100,
+                uploaded: 250,
+            }],
+        };
+        snapshot.relocate(Path::new("/data/new"));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session");
+        snapshot.save(&path).unwrap();
+        let loaded = SessionSnapshot::load(&path).unwrap();
+        assert_eq!(loaded.download_limit, Some(1000));
+        let restored = &loaded.torrents[0];
+        assert_eq!(restored.torrent.info_hash(), torrent.info_hash());
+        assert_eq!(restored.output, Path::new("/data/new/file"));
+        assert!(restored.paused && !restored.forced);
+        assert_eq!(restored.download_limit, None);
+        assert_eq!(restored.file_priorities, vec![Priority::High]);
+        assert_eq!((restored.downloaded, restored.uploaded), (100, 250));
+
+        std::fs::write(&path, b"d8:torrentsi1ee").unwrap();
+        assert!(SessionSnapshot::load(&path).is_err());
+    }
+}
@@ -0,0 +1,420 @@
+use crate::cache::CacheStats;
+use crate::fingerprint::client_name;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How much of a rate average each new sample makes up.
+pub(crate) const RATE_WEIGHT: f64 = 0.3;
+/// Like `RATE_WEIGHT`, for round-trip times; the same as TCP's smoothed RTT.
+pub(crate) const ROUND_TRIP_WEIGHT: f64 = 0.125;
+
+/// An exponentially weighted moving average, which starts at its first sample.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Ewma {
+    weight: f64,
+    pub(crate) value: Option<f64>,
+}
+
+impl Ewma {
+    pub(crate) fn new(weight: f64) -> Ewma {
+        Ewma {
+            weight,
+            value: None,
+        }
+    }
+
+    pub(crate) fn add(&mut self, sample: f64) {
+        self.value = Some(match self.value {
+            Some(value) => value + self.weight * (sample - value),
+            None => sample,
+        });
+    }
+}
+
+/// What one connected peer sent and received, as `SwarmStats::snapshot` reports it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStats {
+    pub addr: SocketAddr,
+    /// The client the peer runs, if its peer id follows one of the usual conventions.
+    pub client: Option<String>,
+    /// Bytes of blocks received from the peer.
+    pub downloaded: usize,
+    /// Bytes of blocks sent to the peer.
+    pub uploaded: usize,
+    /// Bytes per second received, averaged over the last few samples.
+    pub download_rate: usize,
+    pub upload_rate: usize,
+    /// How long a block took to arrive after it was requested, averaged; `None` until one
+    /// did.
+    pub round_trip: Option<Duration>,
+    /// Pieces from the peer that failed their hash check.
+    pub hash_failures: usize,
+    /// Bytes of the pieces that failed their hash check.
+    pub corrupt: usize,
+    /// Bytes of `downloaded` that were no use because they had arrived already, e.g. from
+    /// another peer in endgame.
+    pub redundant: usize,
+}
+
+/// Like `PeerStats`, for a whole torrent, with the peers connected right now.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TorrentStats {
+    pub downloaded: usize,
+    pub uploaded: usize,
+    pub download_rate: usize,
+    pub upload_rate: usize,
+    pub hash_failures: usize,
+    pub corrupt: usize,
+    pub redundant: usize,
+    /// Ordered by address.
+    pub peers: Vec<PeerStats>,
+    /// How many of the connected peers have each piece, as of the last sample; empty before
+    /// the first.
+    pub availability: Vec<usize>,
+    /// Requests waiting for the torrent's disk task, as of the last sample.
+    pub disk_queue: usize,
+    /// How the pieces kept in memory were used, as of the last sample.
+    pub cache: CacheStats,
+}
+
+impl TorrentStats {
+    /// How many whole copies of the torrent the connected peers have between them: the
+    /// availability of the rarest piece, plus the share of the pieces more peers have. Below
+    /// 1, some piece is on none of them.
+    pub fn distributed_copies(&self) -> f64 {
+        let Some(&rarest) = self.availability.iter().min() else {
+            return 0.0;
+        };
+        let more = self
+            .availability
+            .iter()
+            .filter(|&&copies| copies > rarest)
+            .count();
+        rarest as f64 + more as f64 / self.availability.len() as f64
+    }
+
+    /// How much of what was downloaded went to waste, corrupt or redundant, in percent. A
+    /// lot of it corrupt means someone in the swarm sends bad data on purpose.
+    pub fn waste(&self) -> f64 {
+        waste(self.downloaded, self.corrupt, self.redundant)
+    }
+}
+
+impl PeerStats {
+    /// Like `TorrentStats::waste`, for what came from the peer.
+    pub fn waste(&self) -> f64 {
+        waste(self.downloaded, self.corrupt, self.redundant)
+    }
+}
+
+fn waste(downloaded: usize, corrupt: usize, redundant: usize) -> f64 {
+    match downloaded {
+        0 => 0.0,
+        downloaded => (corrupt + redundant).min(downloaded) as f64 * 100.0 / downloaded as f64,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Counters {
+    downloaded: usize,
+    uploaded: usize,
+    /// What was transferred since the last sample.
+    unsampled: (usize, usize),
+    download_rate: Ewma,
+    upload_rate: Ewma,
+    hash_failures: usize,
+    corrupt: usize,
+    redundant: usize,
+}
+
+impl Default for Counters {
+    fn default() -> Counters {
+        Counters {
+            downloaded: 0,
+            uploaded: 0,
+            unsampled: (0, 0),
+            download_rate: Ewma::new(RATE_WEIGHT),
+            upload_rate: Ewma::new(RATE_WEIGHT),
+            hash_failures: 0,
+            corrupt: 0,
+            redundant: 0,
+        }
+    }
+}
+
+impl Counters {
+    fn sample(&mut self, interval: Duration) {
+        let seconds = interval.as_secs_f64();
+        let (down, up) = std::mem::take(&mut self.unsampled);
+        self.download_rate.add(down as f64 / seconds);
+        self.upload_rate.add(up as f64 / seconds);
+    }
+
+    fn rates(&self) -> (usize, usize) {
+        let rate = |ewma: Ewma| ewma.value.unwrap_or(0.0).round() as usize;
+        (rate(self.download_rate), rate(self.upload_rate))
+    }
+}
+
+struct Peer {
+    client: Option<String>,
+    counters: Counters,
+    round_trip: Ewma,
+}
+
+#[derive(Default)]
+struct Inner {
+    torrent: Counters,
+    peers: HashMap<SocketAddr, Peer>,
+    availability: Vec<usize>,
+    disk_queue: usize,
+    cache: CacheStats,
+}
+
+/// The transfer statistics of one torrent and each of its connected peers, updated by the
+/// peer workers as blocks come and go.
+///
+/// Rates are averaged over the samples `sample` takes, so it has to be called at a steady
+/// interval. Transfers from a source that isn't a peer, like a web seed, only count for the
+/// torrent.
+#[derive(Default)]
+pub struct SwarmStats {
+    inner: Mutex<Inner>,
+}
+
+impl SwarmStats {
+    pub fn new() -> SwarmStats {
+        SwarmStats::default()
+    }
+
+    /// Statistics that go on counting from totals kept from before, e.g. in a
+    /// `SessionSnapshot`.
+    pub fn with_totals(downloaded: usize, uploaded: usize) -> SwarmStats {
+        let stats = SwarmStats::new();
+        {
+            let mut inner = stats.inner.lock().unwrap();
+            inner.torrent.downloaded = downloaded;
+            inner.torrent.uploaded = uploaded;
+        }
+        stats
+    }
+
+    /// Starts counting for a peer that completed the handshake with `peer_id`, which is
+    /// listed until the returned guard is dropped; what it transferred still counts for the
+    /// torrent after that.
+    pub fn peer_connected(self: &Arc<Self>, addr: SocketAddr, peer_id: &[u8; 20]) -> Listed {
+        let peer = Peer {
+            client: client_name(peer_id),
+            counters: Counters::default(),
+            round_trip: Ewma::new(ROUND_TRIP_WEIGHT),
+        };
+        self.inner.lock().unwrap().peers.insert(addr, peer);
+        Listed {
+            stats: self.clone(),
+            addr,
+        }
+    }
+
+    /// Counts `bytes` received from `from`, which took `round_trip` to arrive after they were
+    /// requested.
+    pub fn downloaded(&self, from: Option<SocketAddr>, bytes: usize, round_trip: Option<Duration>) {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { torrent, peers, .. } = &mut *inner;
+        let peer = from.and_then(|addr| peers.get_mut(&addr));
+        if let Some(peer) = peer {
+            peer.counters.downloaded += bytes;
+            peer.counters.unsampled.0 += bytes;
+            if let Some(round_trip) = round_trip {
+                peer.round_trip.add(round_trip.as_secs_f64());
+            }
+        }
+        torrent.downloaded += bytes;
+        torrent.unsampled.0 += bytes;
+    }
+
+    /// Counts `bytes` sent to `to`.
+    pub fn uploaded(&self, to: Option<SocketAddr>, bytes: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { torrent, peers, .. } = &mut *inner;
+        if let Some(peer) = to.and_then(|addr| peers.get_mut(&addr)) {
+            peer.counters.uploaded += bytes;
+            peer.counters.unsampled.1 += bytes;
+        }
+        torrent.uploaded += bytes;
+        torrent.unsampled.1 += bytes;
+    }
+
+    /// Counts a piece of `bytes` that failed its hash check, against the peer it came from
+    /// if it did.
+    pub fn hash_failed(&self, from: Option<SocketAddr>, bytes: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { torrent, peers, .. } = &mut *inner;
+        if let Some(peer) = from.and_then(|addr| peers.get_mut(&addr)) {
+            peer.counters.hash_failures += 1;
+            peer.counters.corrupt += bytes;
+        }
+        torrent.hash_failures += 1;
+        torrent.corrupt += bytes;
+    }
+
+    /// Counts `bytes` received from `from`, and counted as downloaded already, that were no
+    /// use because we had them already.
+    pub fn redundant(&self, from: Option<SocketAddr>, bytes: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { torrent, peers, .. } = &mut *inner;
+        if let Some(peer) = from.and_then(|addr| peers.get_mut(&addr)) {
+            peer.counters.redundant += bytes;
+        }
+        torrent.redundant += bytes;
+    }
+
+    /// The corrupt and redundant bytes of the whole torrent, as `snapshot` has them.
+    pub fn wasted(&self) -> (usize, usize) {
+        let inner = self.inner.lock().unwrap();
+        (inner.torrent.corrupt, inner.torrent.redundant)
+    }
+
+    /// Adds what was transferred over the last `interval` to the rate averages.
+    pub fn sample(&self, interval: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.torrent.sample(interval);
+        for peer in inner.peers.values_mut() {
+            peer.counters.sample(interval);
+        }
+    }
+
+    /// Takes the number of connected peers that have each piece, from the piece picker.
+    pub fn set_availability(&self, availability: &[usize]) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.availability.clear();
+        inner.availability.extend_from_slice(availability);
+    }
+
+    /// Takes the number of requests waiting for the torrent's disk task.
+    pub fn set_disk_queue(&self, queued: usize) {
+        self.inner.lock().unwrap().disk_queue = queued;
+    }
+
+    /// Takes how the torrent's piece cache did so far.
+    pub fn set_cache(&self, cache: CacheStats) {
+        self.inner.lock().unwrap().cache = cache;
+    }
+
+    pub fn snapshot(&self) -> TorrentStats {
+        let inner = self.inner.lock().unwrap();
+        let mut peers: Vec<PeerStats> = inner
+            .peers
+            .iter()
+            .map(|(&addr, peer)| {
+                let (download_rate, upload_rate) = peer.counters.rates();
+                PeerStats {
+                    addr,
+                    client: peer.client.clone(),
+                    downloaded: peer.counters.downloaded,
+                    uploaded: peer.counters.uploaded,
+                    download_rate,
+                    upload_rate,
+                    round_trip: peer.round_trip.value.map(Duration::from_secs_f64),
+                    hash_failures: peer.counters.hash_failures,
+                    corrupt: peer.counters.corrupt,
+                    redundant: peer.counters.redundant,
+                }
+            })
+            .collect();
+        peers.sort_by_key(|peer| peer.addr);
+        let (download_rate, upload_rate) = inner.torrent.rates();
+        TorrentStats {
+            downloaded: inner.torrent.downloaded,
+            uploaded: inner.torrent.uploaded,
+            download_rate,
+            upload_rate,
+            hash_failures: inner.torrent.hash_failures,
+            corrupt: inner.torrent.corrupt,
+            redundant: inner.torrent.redundant,
+            peers,
+            availability: inner.availability.clone(),
+            disk_queue: inner.disk_queue,
+            cache: inner.cache,
+        }
+    }
+}
+
+/// Keeps a peer listed in its `SwarmStats`, even when its worker is cancelled.
+pub struct Listed {
+    stats: Arc<SwarmStats>,
+    addr: SocketAddr,
+}
+
+impl Drop for Listed {
+    fn drop(&mut self) {
+        self.stats.inner.lock().unwrap().peers.remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprint::tests::peer_id;
+
+    #[test]
+    fn counts_per_peer_and_torrent() {
+        let stats = Arc::new(SwarmStats::new());
+        let (a, b): (SocketAddr, SocketAddr) =
+            ("1.2.3.4:1".parse().unwrap(), "1.2.3.4:2".parse().unwrap());
+        let _listed_b = stats.peer_connected(b, &peer_id(b"-qB4250-"));
+        let listed_a = stats.peer_connected(a, &[0; 20]);
+        stats.downloaded(Some(a), 1000, Some(Duration::from_millis(100)));
+        stats.downloaded(Some(a), 1000, Some(Duration::from_millis(200)));
+        stats.downloaded(Some(b), 500, None);
+        // a web seed's bytes only count for the torrent
+        stats.downloaded(None, 4000, None);
+        stats.hash_failed(Some(b), 500);
+        stats.redundant(Some(a), 1000);
+        stats.sample(Duration::from_secs(2));
+        stats.uploaded(Some(b), 300);
+        stats.sample(Duration::from_secs(1));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.downloaded, 6500);
+        assert_eq!(snapshot.uploaded, 300);
+        assert_eq!(snapshot.hash_failures, 1);
+        assert_eq!((snapshot.corrupt, snapshot.redundant), (500, 1000));
+        assert!((snapshot.waste() - 1500.0 * 100.0 / 6500.0).abs() < 1e-9);
+        // 3250 bytes per second, then nothing
+        assert_eq!(snapshot.download_rate, 2275);
+        assert_eq!(snapshot.upload_rate, 90);
+        let [first, second] = &snapshot.peers[..] else {
+            panic!("expected two peers: {:?}", snapshot.peers);
+        };
+        assert_eq!(first.addr, a);
+        assert_eq!(first.client, None);
+        assert_eq!(first.downloaded, 2000);
+        assert_eq!(first.download_rate, 700);
+        let round_trip = first.round_trip.unwrap().as_secs_f64();
+        assert!((round_trip - 0.1125).abs() < 1e-6, "{}", round_trip);
+        assert_eq!(second.client.as_deref(), Some("qBittorrent 4.2.5"));
+        assert_eq!((second.downloaded, second.uploaded), (500, 300));
+        assert_eq!((second.round_trip, second.hash_failures), (None, 1));
+        assert_eq!((first.waste(), second.waste()), (50.0, 100.0));
+
+        drop(listed_a);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.peers.len(), 1);
+        assert_eq!(snapshot.downloaded, 6500);
+    }
+
+    #[test]
+    fn distributed_copies() {
+        let stats = SwarmStats::new();
+        assert_eq!(stats.snapshot().distributed_copies(), 0.0);
+        stats.set_availability(&[2, 3, 2, 5]);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.availability, [2, 3, 2, 5]);
+        assert_eq!(snapshot.distributed_copies(), 2.5);
+        // a piece no peer has
+        stats.set_availability(&[0, 1, 1, 1]);
+        assert_eq!(stats.snapshot().distributed_copies(), 0.75);
+    }
+}
@@ -0,0 +1,1204 @@
+use crate::filemap::FileMap;
+use crate::torrent::{FileSpan, Torrent};
+use futures_util::future::BoxFuture;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// How the files of a download get their disk space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Allocation {
+    /// Files get their full size up front, but only what is written takes up disk space.
+    #[default]
+    Sparse,
+    /// All disk space is reserved up front, so the files don't end up fragmented and a full
+    /// disk shows before the download starts rather than partway through.
+    Full,
+    /// Files start out empty and grow as pieces are written.
+    None,
+}
+
+impl FromStr for Allocation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "sparse" => Ok(Allocation::Sparse),
+            "full" => Ok(Allocation::Full),
+            "none" => Ok(Allocation::None),
+            _ => anyhow::bail!(
+                "unknown allocation mode: {} (expected sparse, full or none)",
+                s
+            ),
+        }
+    }
+}
+
+/// How the blocks of a download are read from and written to its files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageBackend {
+    /// Seeking and reading or writing through the file handles.
+    #[default]
+    Buffered,
+    /// Copying straight to and from the files mapped into memory, which saves a copy through
+    /// the kernel for every block. Only on Unix, and only for files allocated up front.
+    Mmap,
+}
+
+impl FromStr for StorageBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "buffered" => Ok(StorageBackend::Buffered),
+            "mmap" => Ok(StorageBackend::Mmap),
+            _ => anyhow::bail!("unknown storage backend: {} (expected buffered or mmap)", s),
+        }
+    }
+}
+
+/// Where the files of a download are kept until every piece is in and verified, to be moved
+/// to their final place only then, so a half-finished file is never taken for a complete one.
+/// By default they are downloaded in place.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Incomplete {
+    /// Directory the download is kept in, under its info hash and the name of its output,
+    /// rather than at its output path.
+    pub dir: Option<PathBuf>,
+    /// Adds `.part` to the name of every file.
+    pub part_suffix: bool,
+}
+
+impl Incomplete {
+    /// Where the file of `span` is kept until a download of the torrent of `info_hash` to
+    /// `path` is complete. Downloads to outputs of the same name in different places don't
+    /// share the incomplete directory's files, each torrent has a directory of its own there.
+    pub fn file_path(&self, info_hash: &[u8; 20], path: &Path, span: &FileSpan) -> PathBuf {
+        let path = match (&self.dir, path.file_name()) {
+            (Some(dir), Some(name)) => dir.join(hex::encode(info_hash)).join(name),
+            _ => path.to_path_buf(),
+        };
+        let path = file_path(&path, span);
+        if !self.part_suffix {
+            return path;
+        }
+        let mut path = path.into_os_string();
+        path.push(".part");
+        path.into()
+    }
+}
+
+/// Where a torrent's content is kept while it is downloaded and seeded, addressed by offsets
+/// into the torrent as if all of its files were one.
+///
+/// `FileStorage` keeps it in files on disk; embedders can keep it anywhere else, e.g. in object
+/// storage, an archive or memory, by implementing this and handing `OpenStorage` to the
+/// download. Calls come one at a time from the disk task, and a piece is only written once it
+/// matched its hash.
+pub trait Storage: Send {
+    /// Reads `length` bytes starting at `offset`. What was never written may read as zeros.
+    fn read_block(
+        &mut self,
+        offset: usize,
+        length: usize,
+    ) -> BoxFuture<'_, anyhow::Result<Vec<u8>>>;
+
+    /// Writes `data` starting at `offset`.
+    fn write_block<'a>(
+        &'a mut self,
+        offset: usize,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Makes everything written so far durable, before the resume data claims it is.
+    fn flush(&mut self) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    /// Called once every wanted piece is written and flushed, to move or rename the content to
+    /// where it belongs when complete. Nothing by default.
+    fn complete(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for Box<S> {
+    fn read_block(
+        &mut self,
+        offset: usize,
+        length: usize,
+    ) -> BoxFuture<'_, anyhow::Result<Vec<u8>>> {
+        (**self).read_block(offset, length)
+    }
+
+    fn write_block<'a>(
+        &'a mut self,
+        offset: usize,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        (**self).write_block(offset, data)
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        (**self).flush()
+    }
+
+    fn complete(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        (**self).complete()
+    }
+}
+
+/// Makes the `Storage` a download keeps its torrent's content in, in place of files below the
+/// output path. Called again whenever the download is started, e.g. after a pause.
+pub trait OpenStorage: Send + Sync + std::fmt::Debug {
+    fn open(&self, torrent: &Torrent) -> BoxFuture<'static, anyhow::Result<Box<dyn Storage>>>;
+}
+
+/// The files holding a torrent's content, laid out back to back.
+///
+/// A single-file torrent is stored at the given path itself, a multi-file torrent in files
+/// below the given directory.
+pub struct FileStorage {
+    map: FileMap,
+    /// The file of every span of `map`; `None` for one that is missing, which reads as zeros.
+    files: Vec<Option<tokio::fs::File>>,
+    /// The files mapped into memory, each with its `files` entry, with the `Mmap` backend.
+    mapped: Option<Vec<Mmap>>,
+    writable: bool,
+    /// The files kept elsewhere until they are complete, by their index in `files`, with where
+    /// they are and where they go.
+    moves: Vec<(usize, PathBuf, PathBuf)>,
+    /// The directory incomplete files are kept in, if they aren't kept in place.
+    incomplete_dir: Option<PathBuf>,
+}
+
+impl FileStorage {
+    /// Creates every file, along with any missing directories, and allocates its space as
+    /// `allocation` says. Files that already exist keep their contents, so an interrupted
+    /// download can reuse them.
+    pub async fn create(
+        torrent: &Torrent,
+        path: &Path,
+        allocation: Allocation,
+    ) -> crate::Result<FileStorage> {
+        FileStorage::create_in(torrent, path, allocation, &Incomplete::default()).await
+    }
+
+    /// Like `create`, but keeps the files where `incomplete` says until `complete` moves them
+    /// to `path`. A file already at `path` and not kept elsewhere was moved there by an
+    /// earlier download, and stays.
+    pub async fn create_in(
+        torrent: &Torrent,
+        path: &Path,
+        allocation: Allocation,
+        incomplete: &Incomplete,
+    ) -> crate::Result<FileStorage> {
+        async {
+            let map = FileMap::new(torrent)?;
+            let info_hash = torrent.info_hash();
+            let mut files = Vec::new();
+            let mut moves = Vec::new();
+            for span in map.spans() {
+                let done = file_path(path, span);
+                let kept = incomplete.file_path(&info_hash, path, span);
+                let path = if kept == done
+                    || (!tokio::fs::try_exists(&kept).await?
+                        && tokio::fs::try_exists(&done).await?)
+                {
+                    done
+                } else {
+                    moves.push((files.len(), kept.clone(), done));
+                    kept
+                };
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                let file = tokio::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&path)
+                    .await?;
+                let length = span.length as u64;
+                if allocation == Allocation::Sparse || file.metadata().await?.len() > length {
+                    file.set_len(length).await?;
+                }
+                if allocation == Allocation::Full {
+                    let reserved = file.try_clone().await?.into_std().await;
+                    tokio::task::spawn_blocking(move || preallocate(&reserved, length))
+                        .await?
+                        .map_err(|e| {
+                            anyhow::anyhow!(
+                                "can't allocate {} bytes for {}: {}",
+                                length,
+                                path.display(),
+                                e
+                            )
+                        })?;
+                }
+                files.push(Some(file));
+            }
+            Ok(FileStorage {
+                map,
+                files,
+                mapped: None,
+                writable: true,
+                moves,
+                incomplete_dir: incomplete.dir.clone(),
+            })
+        }
+        .await
+        .map_err(crate::Error::Disk)
+    }
+
+    /// Opens the files of an already downloaded torrent for reading.
+    pub async fn open(torrent: &Torrent, path: &Path) -> crate::Result<FileStorage> {
+        async {
+            let map = FileMap::new(torrent)?;
+            let mut files = Vec::new();
+            for span in map.spans() {
+                let path = file_path(path, span);
+                let file = tokio::fs::File::open(&path)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("can't open {}: {}", path.display(), e))?;
+                let length = file.metadata().await?.len();
+                if length != span.length as u64 {
+                    anyhow::bail!(
+                        "{} is {} bytes long, expected {}",
+                        path.display(),
+                        length,
+                        span.length
+                    );
+                }
+                files.push(Some(file));
+            }
+            Ok(FileStorage::reading(map, files))
+        }
+        .await
+        .map_err(crate::Error::Disk)
+    }
+
+    /// Opens whichever of the torrent's files exist with the right size, skipping the rest.
+    /// Reads from skipped files come back as zeros.
+    async fn open_existing(torrent: &Torrent, path: &Path) -> anyhow::Result<FileStorage> {
+        let map = FileMap::new(torrent)?;
+        let mut files = Vec::new();
+        for span in map.spans() {
+            let file = match tokio::fs::File::open(file_path(path, span)).await {
+                // a short file, say an interrupted download, is read as far as it goes
+                Ok(file) if file.metadata().await?.len() <= span.length as u64 => Some(file),
+                _ => None,
+            };
+            files.push(file);
+        }
+        Ok(FileStorage::reading(map, files))
+    }
+
+    fn reading(map: FileMap, files: Vec<Option<tokio::fs::File>>) -> FileStorage {
+        FileStorage {
+            map,
+            files,
+            mapped: None,
+            writable: false,
+            moves: Vec::new(),
+            incomplete_dir: None,
+        }
+    }
+
+    /// Switches to reading and writing the files through `backend`. Mapping the files into
+    /// memory takes all of them at their full size, so it doesn't go with `Allocation::None`.
+    ///
+    /// A mapped file that is truncated behind our back can crash the process when it is read,
+    /// where buffered I/O would only fail the read.
+    pub async fn with_backend(mut self, backend: StorageBackend) -> crate::Result<FileStorage> {
+        async {
+            self.mapped = match backend {
+                StorageBackend::Buffered => None,
+                StorageBackend::Mmap => {
+                    let mut mapped = Vec::with_capacity(self.files.len());
+                    for (span, file) in self.map.spans().iter().zip(&self.files) {
+                        let file = match file {
+                            Some(file) if file.metadata().await?.len() == span.length as u64 => {
+                                file
+                            }
+                            _ => anyhow::bail!(
+                                "memory-mapped storage needs the files allocated, {} isn't",
+                                span.path.display()
+                            ),
+                        };
+                        mapped.push(Mmap::map(file, span.length, self.writable)?);
+                    }
+                    Some(mapped)
+                }
+            };
+            Ok(self)
+        }
+        .await
+        .map_err(crate::Error::Disk)
+    }
+
+    /// Writes `data` starting at `offset` in the torrent, splitting it across file boundaries.
+    pub async fn write_at(&mut self, offset: usize, data: &[u8]) -> crate::Result<()> {
+        async {
+            if !self.writable {
+                anyhow::bail!("storage is open for reading only");
+            }
+            for slice in self.map.slices(offset, data.len())? {
+                let data = &data[slice.range()];
+                if let Some(mapped) = &mut self.mapped {
+                    mapped[slice.file].as_mut_slice()[slice.offset..][..slice.length]
+                        .copy_from_slice(data);
+                    continue;
+                }
+                // only files opened for reading can be missing
+                let Some(file) = &mut self.files[slice.file] else {
+                    continue;
+                };
+                file.seek(SeekFrom::Start(slice.offset as u64)).await?;
+                file.write_all(data).await?;
+            }
+            Ok(())
+        }
+        .await
+        .map_err(crate::Error::Disk)
+    }
+
+    /// Reads `length` bytes starting at `offset` in the torrent. Whatever lies past the end of
+    /// a file that hasn't grown to its full size yet reads as zeros.
+    pub async fn read_at(&mut self, offset: usize, length: usize) -> crate::Result<Vec<u8>> {
+        let mut data = vec![0; length];
+        self.read_into(offset, &mut data).await?;
+        Ok(data)
+    }
+
+    /// Like `read_at`, but into `data`, which says how many bytes are read; for reading one
+    /// piece after another into the same buffer.
+    pub async fn read_into(&mut self, offset: usize, data: &mut [u8]) -> crate::Result<()> {
+        async {
+            for slice in self.map.slices(offset, data.len())? {
+                let buffer = &mut data[slice.range()];
+                if let Some(mapped) = &self.mapped {
+                    buffer.copy_from_slice(
+                        &mapped[slice.file].as_slice()[slice.offset..][..slice.length],
+                    );
+                    continue;
+                }
+                let Some(file) = &mut self.files[slice.file] else {
+                    buffer.fill(0);
+                    continue;
+                };
+                file.seek(SeekFrom::Start(slice.offset as u64)).await?;
+                let mut filled = 0;
+                while filled < buffer.len() {
+                    match file.read(&mut buffer[filled..]).await? {
+                        0 => break,
+                        read => filled += read,
+                    }
+                }
+                buffer[filled..].fill(0);
+            }
+            Ok(())
+        }
+        .await
+        .map_err(crate::Error::Disk)
+    }
+
+    /// Reads the piece at `index` back and checks it against its hash.
+    pub async fn verify_piece(&mut self, torrent: &Torrent, index: usize) -> crate::Result<bool> {
+        let offset = index * torrent.info.piece_length;
+        let piece = self.read_at(offset, torrent.piece_size(index)).await?;
+        Ok(torrent.verify_piece(index, &piece))
+    }
+
+    /// Hands what was written to the operating system. Writes to mapped files are in its page
+    /// cache as soon as they are made, so there is nothing to do for those.
+    pub async fn flush(&mut self) -> crate::Result<()> {
+        async {
+            for file in self.files.iter_mut().flatten() {
+                file.flush().await?;
+            }
+            Ok(())
+        }
+        .await
+        .map_err(crate::Error::Disk)
+    }
+
+    /// Moves the files kept elsewhere while incomplete to their final place, each replacing
+    /// whatever is there in one step, and reopens them there. Directories left empty in the
+    /// incomplete directory are removed.
+    pub async fn complete(&mut self) -> crate::Result<()> {
+        async {
+            self.flush().await?;
+            for (index, from, to) in std::mem::take(&mut self.moves) {
+                if let Some(parent) = to.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                move_file(&from, &to).await.map_err(|e| {
+                    anyhow::anyhow!("can't move {} to {}: {}", from.display(), to.display(), e)
+                })?;
+                let file = tokio::fs::OpenOptions::new()
+                    .read(true)
+                    .write(self.writable)
+                    .open(&to)
+                    .await?;
+                if let Some(mapped) = &mut self.mapped {
+                    mapped[index] =
+                        Mmap::map(&file, self.map.spans()[index].length, self.writable)?;
+                }
+                self.files[index] = Some(file);
+                if let Some(dir) = &self.incomplete_dir {
+                    // stops at the first directory that isn't empty, or isn't ours
+                    let mut parent = from.parent();
+                    while let Some(empty) =
+                        parent.filter(|parent| parent.starts_with(dir) && parent != dir)
+                    {
+                        if tokio::fs::remove_dir(empty).await.is_err() {
+                            break;
+                        }
+                        parent = empty.parent();
+                    }
+                }
+            }
+            Ok(())
+        }
+        .await
+        .map_err(crate::Error::Disk)
+    }
+}
+
+/// Renames `from` to `to`, or where they are on different file systems, copies it next to `to`
+/// first and renames the copy, so `to` never holds part of the file.
+async fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    match tokio::fs::rename(from, to).await {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            let mut copy = to.as_os_str().to_owned();
+            copy.push(".moving");
+            tokio::fs::copy(from, &copy).await?;
+            tokio::fs::rename(&copy, to).await?;
+            tokio::fs::remove_file(from).await
+        }
+        moved => moved,
+    }
+}
+
+impl Storage for FileStorage {
+    fn read_block(
+        &mut self,
+        offset: usize,
+        length: usize,
+    ) -> BoxFuture<'_, anyhow::Result<Vec<u8>>> {
+        Box::pin(async move { Ok(self.read_at(offset, length).await?) })
+    }
+
+    fn write_block<'a>(
+        &'a mut self,
+        offset: usize,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move { Ok(self.write_at(offset, data).await?) })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async { Ok(FileStorage::flush(self).await?) })
+    }
+
+    fn complete(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async { Ok(FileStorage::complete(self).await?) })
+    }
+}
+
+/// Keeps a torrent's content in memory, e.g. to test downloads without touching the disk.
+///
+/// Clones share the same content, so one can be handed to a download as its `OpenStorage`
+/// and looked at once it is done. Opening it for a torrent keeps what it holds if that is
+/// the torrent's size, as files would be kept when a download is started again.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    content: Arc<Mutex<Vec<u8>>>,
+    completed: Arc<AtomicBool>,
+}
+
+impl MemoryStorage {
+    /// Storage already holding `content`, e.g. for seeding it.
+    pub fn with_content(content: Vec<u8>) -> MemoryStorage {
+        MemoryStorage {
+            content: Arc::new(Mutex::new(content)),
+            completed: Arc::default(),
+        }
+    }
+
+    pub fn content(&self) -> Vec<u8> {
+        self.content.lock().unwrap().clone()
+    }
+
+    /// Whether the download completed it, as `Storage::complete` tells.
+    pub fn is_complete(&self) -> bool {
+        self.completed.load(Ordering::Relaxed)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_block(
+        &mut self,
+        offset: usize,
+        length: usize,
+    ) -> BoxFuture<'_, anyhow::Result<Vec<u8>>> {
+        let content = self.content.lock().unwrap();
+        let read = match content.get(offset..offset + length) {
+            Some(data) => Ok(data.to_vec()),
+            None => Err(anyhow::anyhow!(
+                "can't read {} bytes at {} of {}",
+                length,
+                offset,
+                content.len()
+            )),
+        };
+        Box::pin(async { read })
+    }
+
+    fn write_block<'a>(
+        &'a mut self,
+        offset: usize,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        let mut content = self.content.lock().unwrap();
+        let length = content.len();
+        let written = match content.get_mut(offset..offset + data.len()) {
+            Some(block) => {
+                block.copy_from_slice(data);
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!(
+                "can't write {} bytes at {} of {}",
+                data.len(),
+                offset,
+                length
+            )),
+        };
+        Box::pin(async { written })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn complete(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        self.completed.store(true, Ordering::Relaxed);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl OpenStorage for MemoryStorage {
+    fn open(&self, torrent: &Torrent) -> BoxFuture<'static, anyhow::Result<Box<dyn Storage>>> {
+        let length = torrent.total_length();
+        let mut content = self.content.lock().unwrap();
+        if content.len() != length {
+            *content = vec![0; length];
+            self.completed.store(false, Ordering::Relaxed);
+        }
+        let storage: Box<dyn Storage> = Box::new(self.clone());
+        Box::pin(async { Ok(storage) })
+    }
+}
+
+/// How much of a file the valid pieces `verify` found cover.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileState {
+    /// Every piece holding some of its data is valid.
+    Complete,
+    /// On disk, with pieces still to download.
+    Partial,
+    /// Not on disk at all.
+    Missing,
+}
+
+impl FileState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileState::Complete => "complete",
+            FileState::Partial => "partial",
+            FileState::Missing => "missing",
+        }
+    }
+}
+
+/// One file of a torrent, as `check_files` found it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileCheck {
+    /// Relative to the download location, as in `FileSpan`.
+    pub path: PathBuf,
+    /// The pieces holding some of the file's data.
+    pub pieces: usize,
+    /// How many of those are valid.
+    pub valid: usize,
+    pub state: FileState,
+}
+
+impl FileCheck {
+    /// The share of the file's pieces that are valid, in percent; 100 for an empty file.
+    pub fn percent(&self) -> f64 {
+        match self.pieces {
+            0 => 100.0,
+            pieces => self.valid as f64 * 100.0 / pieces as f64,
+        }
+    }
+}
+
+/// Tells for every file of the torrent at `path`, in the order of `Torrent::files`, how much
+/// of it the `valid` pieces `verify` found cover.
+pub async fn check_files(
+    torrent: &Torrent,
+    path: &Path,
+    valid: &[bool],
+) -> crate::Result<Vec<FileCheck>> {
+    async {
+        let piece_length = torrent.info.piece_length;
+        let mut checks = Vec::new();
+        for span in torrent.files()? {
+            let first = span.offset / piece_length;
+            let end = (span.offset + span.length)
+                .div_ceil(piece_length)
+                .max(first);
+            let pieces = &valid[first.min(valid.len())..end.min(valid.len())];
+            let valid = pieces.iter().filter(|&&valid| valid).count();
+            let state = if !tokio::fs::try_exists(file_path(path, &span)).await? {
+                FileState::Missing
+            } else if valid == pieces.len() {
+                FileState::Complete
+            } else {
+                FileState::Partial
+            };
+            checks.push(FileCheck {
+                path: span.path,
+                pieces: pieces.len(),
+                valid,
+                state,
+            });
+        }
+        Ok(checks)
+    }
+    .await
+    .map_err(crate::Error::Disk)
+}
+
+/// Checks the data at `path` against the torrent's piece hashes and tells for every piece
+/// whether it is valid. Pieces that touch a missing file, one longer than it should be or the
+/// end a short one lacks are invalid, unless the piece's missing data is all zeros.
+pub async fn verify(torrent: &Torrent, path: &Path) -> crate::Result<Vec<bool>> {
+    async {
+        let mut storage = FileStorage::open_existing(torrent, path).await?;
+        let mut valid = Vec::with_capacity(torrent.piece_count());
+        // one buffer for all the pieces, rather than one each
+        let mut piece = Vec::with_capacity(torrent.info.piece_length);
+        for index in 0..torrent.piece_count() {
+            piece.resize(torrent.piece_size(index), 0);
+            storage
+                .read_into(index * torrent.info.piece_length, &mut piece)
+                .await?;
+            valid.push(torrent.verify_piece(index, &piece));
+        }
+        Ok(valid)
+    }
+    .await
+    .map_err(crate::Error::Disk)
+}
+
+/// Fails unless the filesystem `path` is to be written on has `needed` bytes free. `path`
+/// doesn't have to exist yet: its nearest existing ancestor is checked.
+pub fn check_free_space(path: &Path, needed: u64) -> crate::Result<()> {
+    // a relative path with no existing directory in it is below the working directory
+    let dir = path
+        .ancestors()
+        .skip(1)
+        .find(|dir| !dir.as_os_str().is_empty() && dir.exists())
+        .unwrap_or(Path::new("."));
+    let free = free_space(dir).map_err(|e| {
+        crate::Error::Disk(anyhow::anyhow!(
+            "can't tell the free space in {}: {}",
+            dir.display(),
+            e
+        ))
+    })?;
+    let Some(free) = free else {
+        return Ok(());
+    };
+    if free < needed {
+        return Err(crate::Error::Disk(anyhow::anyhow!(
+            "not enough disk space in {}: {} bytes needed, {} free",
+            dir.display(),
+            needed,
+            free
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `error` came from writing to a full disk.
+pub fn is_disk_full(error: &anyhow::Error) -> bool {
+    let full = |cause: &(dyn std::error::Error + 'static)| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::StorageFull)
+    };
+    // the error a kind carries is its message rather than a cause
+    error.chain().any(|cause| {
+        full(cause)
+            || cause
+                .downcast_ref::<crate::Error>()
+                .is_some_and(|kind| kind.inner().chain().any(full))
+    })
+}
+
+/// The bytes an unprivileged user can still write to the filesystem `dir` is on, where that
+/// can be told.
+#[cfg(unix)]
+fn free_space(dir: &Path) -> std::io::Result<Option<u64>> {
+    use std::os::unix::ffi::OsStrExt;
+    let dir = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(dir.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn free_space(_: &Path) -> std::io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Reserves disk space for the first `length` bytes of `file`, keeping what it holds.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn preallocate(file: &std::fs::File, length: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if length == 0 {
+        return Ok(());
+    }
+    // posix_fallocate returns the error rather than setting errno
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length as libc::off_t) } {
+        0 => Ok(()),
+        error => Err(std::io::Error::from_raw_os_error(error)),
+    }
+}
+
+/// Reserves disk space for the first `length` bytes of `file` by writing zeros past its end.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn preallocate(mut file: &std::fs::File, length: u64) -> std::io::Result<()> {
+    use std::io::{Seek, Write};
+    let zeros = vec![0; 1024 * 1024];
+    let mut at = file.metadata()?.len();
+    file.seek(SeekFrom::Start(at))?;
+    while at < length {
+        let chunk = (length - at).min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..chunk])?;
+        at += chunk as u64;
+    }
+    Ok(())
+}
+
+/// A file mapped into memory, shared with the file itself so what is written to the memory
+/// ends up in the file.
+#[cfg(unix)]
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// the mapping is only ever touched through `&self` and `&mut self`, like a `Vec<u8>`
+#[cfg(unix)]
+unsafe impl Send for Mmap {}
+#[cfg(unix)]
+unsafe impl Sync for Mmap {}
+
+#[cfg(unix)]
+impl Mmap {
+    fn map(file: &tokio::fs::File, len: usize, writable: bool) -> anyhow::Result<Mmap> {
+        use std::os::unix::io::AsRawFd;
+        // an empty file can't be mapped, and has nothing to read or write anyway
+        if len == 0 {
+            return Ok(Mmap {
+                ptr: std::ptr::NonNull::dangling().as_ptr(),
+                len,
+            });
+        }
+        let protection = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                protection,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            let e = std::io::Error::last_os_error();
+            anyhow::bail!("can't map {} bytes of a file into memory: {}", len, e);
+        }
+        Ok(Mmap {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    /// Only for a mapping made writable.
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.ptr.cast(), self.len) };
+        }
+    }
+}
+
+/// Stands in for the Unix mappings where there are none; it can't be made.
+#[cfg(not(unix))]
+struct Mmap;
+
+#[cfg(not(unix))]
+impl Mmap {
+    fn map(_: &tokio::fs::File, _: usize, _: bool) -> anyhow::Result<Mmap> {
+        anyhow::bail!("memory-mapped storage isn't supported here")
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &[]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut []
+    }
+}
+
+/// Where the file of `span` is stored for a download to `path`.
+pub(crate) fn file_path(path: &Path, span: &FileSpan) -> PathBuf {
+    if span.path.as_os_str().is_empty() {
+        path.to_path_buf()
+    } else {
+        path.join(&span.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{multi_file_torrent_for, test_data, torrent_for};
+
+    #[test]
+    fn checks_free_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing/test.bin");
+        check_free_space(&path, 0).unwrap();
+        check_free_space(Path::new("test.bin"), 0).unwrap();
+        let error = check_free_space(&path, u64::MAX).unwrap_err().to_string();
+        assert!(error.starts_with("not enough disk space in"), "{}", error);
+    }
+
+    #[test]
+    fn tells_disk_full_errors() {
+        let full = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::StorageFull));
+        assert!(is_disk_full(&full.context("writing a piece")));
+        let denied = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        assert!(!is_disk_full(&denied));
+        assert!(!is_disk_full(&anyhow::anyhow!("no space left")));
+    }
+
+    #[tokio::test]
+    async fn write_piece_spanning_several_files() {
+        let data = test_data(10);
+        let torrent = multi_file_torrent_for(&data, 10, &[("a", 2), ("b", 3), ("c", 5)]);
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut storage = FileStorage::create(&torrent, dir.path(), Allocation::Sparse)
+            .await
+            .unwrap();
+        storage.write_at(1, &data[1..9]).await.unwrap();
+        storage.flush().await.unwrap();
+        assert_eq!(std::fs::read(dir.path().join("a")).unwrap(), [0, data[1]]);
+        assert_eq!(std::fs::read(dir.path().join("b")).unwrap(), &data[2..5]);
+        assert_eq!(
+            std::fs::read(dir.path().join("c")).unwrap(),
+            [&data[5..9], &[0][..]].concat()
+        );
+    }
+
+    #[tokio::test]
+    async fn read_spanning_several_files() {
+        let data = test_data(10);
+        let torrent = multi_file_torrent_for(&data, 10, &[("a", 2), ("sub/b", 3), ("c", 5)]);
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("a"), &data[..2]).unwrap();
+        std::fs::write(dir.path().join("sub/b"), &data[2..5]).unwrap();
+        std::fs::write(dir.path().join("c"), &data[5..]).unwrap();
+
+        let mut storage = FileStorage::open(&torrent, dir.path()).await.unwrap();
+        assert_eq!(storage.read_at(1, 8).await.unwrap(), &data[1..9]);
+        assert_eq!(storage.read_at(0, 10).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn verify_partial_data() {
+        let data = test_data(1000);
+        let torrent = multi_file_torrent_for(&data, 100, &[("a", 250), ("b", 500), ("c", 250)]);
+        let dir = tempfile::tempdir().unwrap();
+        let mut a = data[..250].to_vec();
+        a[120] ^= 1;
+        std::fs::write(dir.path().join("a"), a).unwrap();
+        // b is missing and c lacks its last 100 bytes
+        std::fs::write(dir.path().join("c"), &data[750..900]).unwrap();
+
+        let valid = verify(&torrent, dir.path()).await.unwrap();
+        assert_eq!(
+            valid,
+            [true, false, false, false, false, false, false, false, true, false]
+        );
+
+        let files = check_files(&torrent, dir.path(), &valid).await.unwrap();
+        let states: Vec<FileState> = files.iter().map(|file| file.state).collect();
+        assert_eq!(
+            states,
+            [FileState::Partial, FileState::Missing, FileState::Partial]
+        );
+
+        std::fs::write(dir.path().join("b"), &data[250..750]).unwrap();
+        let valid = verify(&torrent, dir.path()).await.unwrap();
+        assert_eq!(
+            valid,
+            [true, false, true, true, true, true, true, true, true, false]
+        );
+        let files = check_files(&torrent, dir.path(), &valid).await.unwrap();
+        // b shares its first piece with a, and its last with c
+        assert_eq!((files[1].valid, files[1].pieces), (6, 6));
+        assert_eq!(files[1].state, FileState::Complete);
+        assert_eq!((files[2].valid, files[2].pieces), (2, 3));
+        assert_eq!(files[2].state, FileState::Partial);
+    }
+
+    #[tokio::test]
+    async fn verify_a_file_missing_its_last_piece() {
+        let data = test_data(1000);
+        let torrent = torrent_for(&data, 100);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.bin");
+        std::fs::write(&path, &data[..900]).unwrap();
+
+        let valid = verify(&torrent, &path).await.unwrap();
+        let files = check_files(&torrent, &path, &valid).await.unwrap();
+        assert_eq!(files[0].state, FileState::Partial);
+        assert_eq!((files[0].valid, files[0].pieces), (9, 10));
+        assert_eq!(files[0].percent(), 90.0);
+
+        std::fs::write(&path, &data).unwrap();
+        let valid = verify(&torrent, &path).await.unwrap();
+        let files = check_files(&torrent, &path, &valid).await.unwrap();
+        assert_eq!(files[0].state, FileState::Complete);
+    }
+
+    #[tokio::test]
+    #[should_panic]
+    async fn open_rejects_wrong_length() {
+        let data = test_data(10);
+        let torrent = torrent_for(&data, 10);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.bin");
+        std::fs::write(&path, &data[..9]).unwrap();
+        FileStorage::open(&torrent, &path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn allocation_modes() {
+        let data = test_data(1000);
+        let torrent = multi_file_torrent_for(&data, 100, &[("a", 400), ("b", 600)]);
+        for (allocation, lengths) in [
+            (Allocation::Sparse, [400, 600]),
+            (Allocation::Full, [400, 600]),
+            (Allocation::None, [0, 0]),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let length = |name| std::fs::metadata(dir.path().join(name)).unwrap().len();
+            let mut storage = FileStorage::create(&torrent, dir.path(), allocation)
+                .await
+                .unwrap();
+            assert_eq!([length("a"), length("b")], lengths, "{:?}", allocation);
+
+            storage.write_at(100, &data[100..200]).await.unwrap();
+            storage.flush().await.unwrap();
+            let mut expected = vec![0; 1000];
+            expected[100..200].copy_from_slice(&data[100..200]);
+            assert_eq!(storage.read_at(0, 1000).await.unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn move_on_completion() {
+        let data = test_data(1000);
+        let torrent = multi_file_torrent_for(&data, 100, &[("a", 400), ("sub/b", 600)]);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test");
+        let incomplete = Incomplete {
+            dir: Some(dir.path().join("incomplete")),
+            part_suffix: true,
+        };
+        let mut storage =
+            FileStorage::create_in(&torrent, &output, Allocation::Sparse, &incomplete)
+                .await
+                .unwrap();
+        let kept = dir
+            .path()
+            .join("incomplete")
+            .join(hex::encode(torrent.info_hash()))
+            .join("test");
+        assert!(kept.join("a.part").exists() && kept.join("sub/b.part").exists());
+        assert!(!output.exists());
+
+        storage.write_at(0, &data).await.unwrap();
+        storage.complete().await.unwrap();
+        assert_eq!(std::fs::read(output.join("a")).unwrap(), &data[..400]);
+        assert_eq!(std::fs::read(output.join("sub/b")).unwrap(), &data[400..]);
+        // the emptied directories go, the incomplete directory itself stays
+        assert!(!kept.exists());
+        assert!(dir.path().join("incomplete").exists());
+        assert_eq!(storage.read_at(300, 200).await.unwrap(), &data[300..500]);
+        drop(storage);
+
+        // a download started again finds the files where they were moved to
+        let mut storage =
+            FileStorage::create_in(&torrent, &output, Allocation::Sparse, &incomplete)
+                .await
+                .unwrap();
+        assert!(!kept.exists());
+        assert_eq!(storage.read_at(0, 1000).await.unwrap(), data);
+
+        let single = Incomplete {
+            dir: None,
+            part_suffix: true,
+        };
+        let span = &torrent_for(&data, 100).files().unwrap()[0];
+        assert_eq!(
+            single.file_path(&[0; 20], Path::new("out/test.bin"), span),
+            Path::new("out/test.bin.part")
+        );
+        assert_eq!(
+            Incomplete::default().file_path(&[0; 20], Path::new("out/test.bin"), span),
+            Path::new("out/test.bin")
+        );
+    }
+
+    #[tokio::test]
+    async fn outputs_of_the_same_name_are_kept_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let incomplete = Incomplete {
+            dir: Some(dir.path().join("incomplete")),
+            part_suffix: true,
+        };
+        let mut storages = Vec::new();
+        for (data, output) in [(test_data(1000), "one"), (vec![7; 1000], "two")] {
+            let torrent = torrent_for(&data, 100);
+            let output = dir.path().join(output).join("test.bin");
+            let mut storage =
+                FileStorage::create_in(&torrent, &output, Allocation::Sparse, &incomplete)
+                    .await
+                    .unwrap();
+            storage.write_at(0, &data).await.unwrap();
+            storage.flush().await.unwrap();
+            storages.push((storage, data, output));
+        }
+        for (mut storage, data, output) in storages {
+            assert_eq!(storage.read_at(0, 1000).await.unwrap(), data);
+            storage.complete().await.unwrap();
+            assert_eq!(std::fs::read(output).unwrap(), data);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn memory_mapped_files() {
+        let data = test_data(1000);
+        let torrent = multi_file_torrent_for(&data, 100, &[("a", 400), ("b", 0), ("c", 600)]);
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = FileStorage::create(&torrent, dir.path(), Allocation::Sparse)
+            .await
+            .unwrap()
+            .with_backend(StorageBackend::Mmap)
+            .await
+            .unwrap();
+        storage.write_at(300, &data[300..700]).await.unwrap();
+        storage.flush().await.unwrap();
+        assert_eq!(storage.read_at(350, 100).await.unwrap(), &data[350..450]);
+        assert_eq!(
+            std::fs::read(dir.path().join("a")).unwrap()[300..],
+            data[300..400]
+        );
+        drop(storage);
+
+        let mut storage = FileStorage::open(&torrent, dir.path())
+            .await
+            .unwrap()
+            .with_backend(StorageBackend::Mmap)
+            .await
+            .unwrap();
+        assert_eq!(storage.read_at(300, 400).await.unwrap(), &data[300..700]);
+        assert!(storage.write_at(0, &data[..10]).await.is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let unallocated = FileStorage::create(&torrent, dir.path(), Allocation::None)
+            .await
+            .unwrap();
+        assert!(unallocated
+            .with_backend(StorageBackend::Mmap)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_storage() {
+        let data = test_data(1000);
+        let torrent = torrent_for(&data, 300);
+        let memory = MemoryStorage::default();
+        let mut storage = memory.open(&torrent).await.unwrap();
+        storage.write_block(300, &data[300..600]).await.unwrap();
+        assert_eq!(storage.read_block(400, 100).await.unwrap(), &data[400..500]);
+        assert!(storage.write_block(900, &data[..200]).await.is_err());
+        assert!(storage.read_block(1000, 1).await.is_err());
+        storage.complete().await.unwrap();
+        assert!(memory.is_complete());
+
+        // opening it again keeps what was written
+        let mut storage = memory.open(&torrent).await.unwrap();
+        assert_eq!(storage.read_block(300, 300).await.unwrap(), &data[300..600]);
+        let other = torrent_for(&data[..500], 300);
+        memory.open(&other).await.unwrap();
+        assert_eq!(memory.content(), vec![0; 500]);
+        assert!(!memory.is_complete());
+    }
+
+    #[test]
+    fn parse_allocation() {
+        assert_eq!("full".parse::<Allocation>().unwrap(), Allocation::Full);
+        assert_eq!("none".parse::<Allocation>().unwrap(), Allocation::None);
+        assert!("dense".parse::<Allocation>().is_err());
+        assert_eq!(
+            "mmap".parse::<StorageBackend>().unwrap(),
+            StorageBackend::Mmap
+        );
+        assert!("direct".parse::<StorageBackend>().is_err());
+    }
+}
@@ -0,0 +1,399 @@
+use crate::bitfield::Bitfield;
+use crate::events::{EventKind, TorrentEvent};
+use crate::resume::ResumeData;
+use crate::session::Session;
+use crate::storage;
+use crate::torrent::{FileSpan, Torrent};
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinSet;
+
+/// Longest request head we read before giving up on the client.
+const MAX_HEAD_LENGTH: usize = 8 * 1024;
+/// Most bytes sent in one write.
+const CHUNK_LENGTH: usize = 64 * 1024;
+/// How soon a piece the player is waiting for is wanted by.
+const PIECE_DEADLINE: Duration = Duration::from_secs(5);
+
+/// The index in `Torrent::files` of the torrent's largest file, the one worth streaming.
+pub fn largest_file(torrent: &Torrent) -> anyhow::Result<usize> {
+    let files = torrent.files()?;
+    (0..files.len())
+        .max_by_key(|&index| files[index].length)
+        .ok_or_else(|| anyhow::anyhow!("the torrent has no files"))
+}
+
+/// Serves a file of a torrent that `session` is downloading to `output` over HTTP on
+/// `listener`, with range requests, so a media player can play it while it downloads.
+///
+/// Every request moves the download's read position to where it starts, and the data is sent
+/// as its pieces arrive; a piece the player has to wait for gets a deadline of
+/// `PIECE_DEADLINE`. `events` has to be subscribed to before the torrent is added, so the
+/// pieces found on disk when it starts are seen too.
+pub async fn serve(
+    listener: TcpListener,
+    session: Arc<Session>,
+    torrent: Arc<Torrent>,
+    output: &Path,
+    file: usize,
+    events: broadcast::Receiver<TorrentEvent>,
+) -> anyhow::Result<()> {
+    let span = torrent
+        .files()?
+        .into_iter()
+        .nth(file)
+        .ok_or_else(|| anyhow::anyhow!("the torrent has no file {}", file))?;
+    let path = storage::file_path(output, &span);
+    let incomplete = &session.options().incomplete;
+    let incomplete_path = incomplete.file_path(&torrent.info_hash(), output, &span);
+    let (have, _) = watch::channel(Bitfield::new(torrent.piece_count()));
+    let have = Arc::new(have);
+    let tracker = tokio::spawn(track_pieces(
+        events,
+        torrent.clone(),
+        output.to_path_buf(),
+        have.clone(),
+    ));
+    let mut connections = JoinSet::new();
+    let result = loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => break Err(e.into()),
+                };
+                let file = StreamedFile {
+                    session: session.clone(),
+                    torrent: torrent.clone(),
+                    span: span.clone(),
+                    path: path.clone(),
+                    incomplete_path: incomplete_path.clone(),
+                    have: have.subscribe(),
+                };
+                connections.spawn(async move {
+                    // a client that goes away only ends its own connection
+                    let _ = file.respond(stream).await;
+                });
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    };
+    tracker.abort();
+    result
+}
+
+/// Keeps `have` up to date with the pieces of `torrent` that are on disk.
+async fn track_pieces(
+    mut events: broadcast::Receiver<TorrentEvent>,
+    torrent: Arc<Torrent>,
+    output: PathBuf,
+    have: Arc<watch::Sender<Bitfield>>,
+) {
+    let info_hash = torrent.info_hash();
+    loop {
+        match events.recv().await {
+            Ok(TorrentEvent {
+                info_hash: hash,
+                kind: EventKind::PieceCompleted(index),
+            }) if hash == info_hash => have.send_modify(|have| have.set(index)),
+            Ok(_) => {}
+            // the resume data has every piece written so far, including the missed ones
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                if let Some(resume) = ResumeData::load(&torrent, &output).await {
+                    have.send_modify(|have| {
+                        let pieces = have.len();
+                        have.union_with(&Bitfield::from_bytes(&resume.pieces, pieces));
+                    });
+                }
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+/// The part of a `Range` header that was asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Requested {
+    Whole,
+    Part(Range<u64>),
+    /// The range starts past the end of the file.
+    Unsatisfiable,
+}
+
+/// Which bytes of a file of `length` bytes a request with the `range` header value asks for.
+/// Only a single range is understood; anything else gets the whole file.
+fn requested_range(range: Option<&str>, length: u64) -> Requested {
+    let Some(spec) = range.and_then(|range| range.trim().strip_prefix("bytes=")) else {
+        return Requested::Whole;
+    };
+    let Some((start, end)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return Requested::Whole;
+    };
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        // the last `end` bytes
+        match end.parse::<u64>() {
+            Ok(0) => return Requested::Unsatisfiable,
+            Ok(suffix) => length.saturating_sub(suffix)..length,
+            Err(_) => return Requested::Whole,
+        }
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return Requested::Whole;
+        };
+        let end = match end {
+            "" => length,
+            end => match end.parse::<u64>() {
+                Ok(end) if end >= start => (end + 1).min(length),
+                _ => return Requested::Whole,
+            },
+        };
+        start..end
+    };
+    if range.start >= length {
+        return Requested::Unsatisfiable;
+    }
+    Requested::Part(range)
+}
+
+/// A guess at the media type of the file at `path` from its extension.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    match extension.map(str::to_ascii_lowercase).as_deref() {
+        Some("mp4" | "m4v") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("webm") => "video/webm",
+        Some("avi") => "video/x-msvideo",
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg") => "audio/ogg",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Reads the request head up to the blank line, without the body.
+pub(crate) async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<String> {
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_HEAD_LENGTH {
+            anyhow::bail!("request head is too long");
+        }
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            anyhow::bail!("connection closed before the request was complete");
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// The file a connection serves, and how to tell which of its pieces are there.
+struct StreamedFile {
+    session: Arc<Session>,
+    torrent: Arc<Torrent>,
+    span: FileSpan,
+    path: PathBuf,
+    /// Where the file is until the download is complete and moves it to `path`.
+    incomplete_path: PathBuf,
+    have: watch::Receiver<Bitfield>,
+}
+
+impl StreamedFile {
+    /// Answers one request on `stream` and closes it.
+    async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+        mut self,
+        mut stream: S,
+    ) -> anyhow::Result<()> {
+        let head = read_head(&mut stream).await?;
+        let mut lines = head.lines();
+        let method = lines
+            .next()
+            .and_then(|line| line.split(' ').next())
+            .unwrap_or_default();
+        if method != "GET" && method != "HEAD" {
+            let response = "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, HEAD\r\n\
+                            Content-Length: 0\r\nConnection: close\r\n\r\n";
+            stream.write_all(response.as_bytes()).await?;
+            return Ok(());
+        }
+        let range = lines.find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("range").then_some(value)
+        });
+        let length = self.span.length as u64;
+        let (status, range) = match requested_range(range, length) {
+            Requested::Whole => ("200 OK", 0..length),
+            Requested::Part(range) => ("206 Partial Content", range),
+            Requested::Unsatisfiable => {
+                let response = format!(
+                    "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\n\
+                     Content-Length: 0\r\nConnection: close\r\n\r\n",
+                    length
+                );
+                stream.write_all(response.as_bytes()).await?;
+                return Ok(());
+            }
+        };
+        let mut header = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nAccept-Ranges: bytes\r\nContent-Length: {}\r\n",
+            status,
+            content_type(&self.path),
+            range.end - range.start
+        );
+        if status.starts_with("206") {
+            header += &format!(
+                "Content-Range: bytes {}-{}/{}\r\n",
+                range.start,
+                range.end.saturating_sub(1),
+                length
+            );
+        }
+        header += "Connection: close\r\n\r\n";
+        stream.write_all(header.as_bytes()).await?;
+        if method == "HEAD" || range.is_empty() {
+            return Ok(());
+        }
+
+        let info_hash = self.torrent.info_hash();
+        let start = self.span.offset + range.start as usize;
+        self.session.set_read_position(&info_hash, start)?;
+        // opened once there is something in it, as the download may not have created it yet
+        let mut file = None;
+        let piece_length = self.torrent.info.piece_length;
+        let mut chunk = vec![0; CHUNK_LENGTH];
+        let mut at = start;
+        let end = self.span.offset + range.end as usize;
+        while at < end {
+            let piece = at / piece_length;
+            if !self.have.borrow().get(piece) {
+                let deadline = Instant::now() + PIECE_DEADLINE;
+                self.session
+                    .set_piece_deadline(&info_hash, piece, deadline)?;
+            }
+            self.have.wait_for(|have| have.get(piece)).await?;
+            if file.is_none() {
+                let mut opened = match tokio::fs::File::open(&self.incomplete_path).await {
+                    Ok(opened) => opened,
+                    Err(_) => tokio::fs::File::open(&self.path).await?,
+                };
+                opened.seek(SeekFrom::Start(range.start)).await?;
+                file = Some(opened);
+            }
+            let file = file.as_mut().unwrap();
+            let length = (end - at)
+                .min((piece + 1) * piece_length - at)
+                .min(CHUNK_LENGTH);
+            file.read_exact(&mut chunk[..length]).await?;
+            stream.write_all(&chunk[..length]).await?;
+            at += length;
+        }
+        stream.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{
+        announce_body, multi_file_torrent_for, spawn_seeder, spawn_tracker, test_data,
+    };
+    use crate::download::DownloadOptions;
+    use crate::picker::Priority;
+    use reqwest::header::{CONTENT_RANGE, RANGE};
+
+    #[test]
+    fn range_header() {
+        assert_eq!(requested_range(None, 100), Requested::Whole);
+        assert_eq!(
+            requested_range(Some(" bytes=10-19"), 100),
+            Requested::Part(10..20)
+        );
+        assert_eq!(
+            requested_range(Some("bytes=90-"), 100),
+            Requested::Part(90..100)
+        );
+        assert_eq!(
+            requested_range(Some("bytes=50-500"), 100),
+            Requested::Part(50..100)
+        );
+        assert_eq!(
+            requested_range(Some("bytes=-30"), 100),
+            Requested::Part(70..100)
+        );
+        assert_eq!(
+            requested_range(Some("bytes=100-"), 100),
+            Requested::Unsatisfiable
+        );
+        assert_eq!(
+            requested_range(Some("bytes=0-1,5-6"), 100),
+            Requested::Whole
+        );
+        assert_eq!(requested_range(Some("items=0-1"), 100), Requested::Whole);
+    }
+
+    #[test]
+    fn picks_largest_file() {
+        let data = test_data(1000);
+        let torrent = multi_file_torrent_for(&data, 100, &[("a", 300), ("b", 500), ("c", 200)]);
+        assert_eq!(largest_file(&torrent).unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn streams_ranges_while_downloading() {
+        let data = test_data(100_000);
+        let mut torrent =
+            multi_file_torrent_for(&data, 16384, &[("small", 10_000), ("movie.mp4", 90_000)]);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let (url, _) = spawn_tracker(vec![announce_body(60, &[seeder])]).await;
+        torrent.announce = url;
+        let info_hash = torrent.info_hash();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("test");
+
+        let options = DownloadOptions {
+            port: 0,
+            sequential: true,
+            ..DownloadOptions::default()
+        };
+        let session = Arc::new(Session::new([1; 20], options).await.unwrap());
+        let events = session.subscribe();
+        session.add_torrent(torrent.clone(), &output).unwrap();
+        let file = largest_file(&torrent).unwrap();
+        let mut priorities = vec![Priority::Skip; 2];
+        priorities[file] = Priority::High;
+        session.set_file_priorities(&info_hash, priorities).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}/", listener.local_addr().unwrap());
+        let server = {
+            let (session, torrent, output) = (session.clone(), Arc::new(torrent), output.clone());
+            tokio::spawn(
+                async move { serve(listener, session, torrent, &output, file, events).await },
+            )
+        };
+
+        let client = reqwest::Client::new();
+        let response = client
+            .get(&address)
+            .header(RANGE, "bytes=50000-59999")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 50000-59999/90000");
+        let body = response.bytes().await.unwrap();
+        assert_eq!(body, &data[60_000..70_000]);
+
+        let whole = client.get(&address).send().await.unwrap();
+        assert_eq!(whole.headers()["content-type"], "video/mp4");
+        assert_eq!(whole.bytes().await.unwrap(), &data[10_000..]);
+        server.abort();
+    }
+}
@@ -0,0 +1,129 @@
+use futures_util::FutureExt;
+use std::any::Any;
+use std::collections::HashMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use tokio::task::{self, JoinSet};
+use tracing::Instrument;
+
+/// Owns the tasks of one torrent's download: its disk writer and the DHT and local peer
+/// lookups. None of them outlives the supervisor, so a download that ends, or whose future is
+/// dropped because the torrent was paused or removed, leaves nothing running behind it.
+///
+/// The peer connections are owned by the download itself and go with it the same way. The
+/// tracker announcers are the exception: they run on for as long as it takes to tell their
+/// trackers the download stopped.
+#[derive(Default)]
+pub(crate) struct Supervisor {
+    tasks: JoinSet<anyhow::Result<()>>,
+    /// What each task is, for its errors.
+    names: HashMap<task::Id, &'static str>,
+}
+
+impl Supervisor {
+    pub fn new() -> Supervisor {
+        Supervisor::default()
+    }
+
+    /// Runs `task` in the current span until it ends or the supervisor is dropped.
+    pub fn spawn<F>(&mut self, name: &'static str, task: F)
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let handle = self.tasks.spawn(task.in_current_span());
+        self.names.insert(handle.id(), name);
+    }
+
+    /// Waits for a task to fail or panic, and returns what went wrong. Tasks that end fine
+    /// are let go; as long as none fails this never returns.
+    pub async fn failure(&mut self) -> anyhow::Error {
+        loop {
+            let Some(finished) = self.tasks.join_next_with_id().await else {
+                return std::future::pending().await;
+            };
+            let (id, error) = match finished {
+                Ok((id, Ok(()))) => {
+                    self.names.remove(&id);
+                    continue;
+                }
+                Ok((id, Err(e))) => (id, e),
+                Err(e) if e.is_panic() => (e.id(), panicked(e.into_panic())),
+                // only dropping the supervisor cancels its tasks
+                Err(e) => (e.id(), e.into()),
+            };
+            let name = self.names.remove(&id).unwrap_or("task");
+            return error.context(format!("{} failed", name));
+        }
+    }
+}
+
+/// Runs `future`, turning a panic in it into an error like any other, so that a bug in one
+/// download fails that torrent rather than leaving it hanging.
+pub(crate) async fn catch_panic<T>(
+    future: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    match AssertUnwindSafe(future).catch_unwind().await {
+        Ok(result) => result,
+        Err(payload) => Err(panicked(payload)),
+    }
+}
+
+fn panicked(payload: Box<dyn Any + Send>) -> anyhow::Error {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "no message".to_string(),
+        },
+    };
+    anyhow::anyhow!("panicked: {}", message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn reports_failed_and_panicking_tasks() {
+        let mut supervisor = Supervisor::new();
+        supervisor.spawn("fine", async { Ok(()) });
+        supervisor.spawn("disk writer", async { panic!("out of bounds") });
+        let error = supervisor.failure().await;
+        assert_eq!(
+            format!("{:#}", error),
+            "disk writer failed: panicked: out of bounds"
+        );
+
+        supervisor.spawn("lookup", async { anyhow::bail!("no route") });
+        let error = supervisor.failure().await;
+        assert_eq!(format!("{:#}", error), "lookup failed: no route");
+
+        let nothing_fails = tokio::time::timeout(Duration::from_millis(50), supervisor.failure());
+        assert!(nothing_fails.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn dropping_cancels_the_tasks() {
+        let running = Arc::new(());
+        let mut supervisor = Supervisor::new();
+        let held = running.clone();
+        supervisor.spawn("forever", async move {
+            let _held = held;
+            std::future::pending().await
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(Arc::strong_count(&running), 2);
+        drop(supervisor);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(Arc::strong_count(&running), 1);
+    }
+
+    #[tokio::test]
+    async fn catches_panics() {
+        let result: anyhow::Result<()> = catch_panic(async { panic!("bug {}", 42) }).await;
+        assert_eq!(result.unwrap_err().to_string(), "panicked: bug 42");
+        assert_eq!(catch_panic(async { Ok(7) }).await.unwrap(), 7);
+    }
+}
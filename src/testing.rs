@@ -0,0 +1,418 @@
+//! Scripted stand-ins for the peers and trackers of a swarm, so that downloads can be tested
+//! end to end and deterministically, over local sockets or in-process streams, without a real
+//! swarm. Together with `storage::MemoryStorage` nothing touches the disk either.
+
+use crate::bitfield::Bitfield;
+use crate::message::{MessageCodec, PeerMessage};
+use crate::peer::Handshake;
+use crate::torrent::Torrent;
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_util::codec::Framed;
+
+/// Bytes buffered each way by the streams of `MockPeer::duplex`.
+const DUPLEX_BUFFER: usize = 256 * 1024;
+
+/// A peer of a torrent that behaves as it is told: which pieces it has, whether it unchokes
+/// and answers requests, which pieces it sends corrupted and when it hangs up.
+///
+/// Every connection to it is served on its own, and every message it receives on any of them
+/// is handed to the receiver it is spawned with.
+#[derive(Debug, Clone)]
+pub struct MockPeer {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    piece_length: usize,
+    /// The content it has; `None` for a peer that never answers a request.
+    data: Option<Arc<Vec<u8>>>,
+    pieces: Bitfield,
+    unchokes: bool,
+    corrupted: HashSet<usize>,
+    /// Blocks sent on a connection before it is closed.
+    blocks_before_closing: Option<usize>,
+}
+
+impl MockPeer {
+    /// A peer that has all of `data`, the torrent's content, and hands out any of it.
+    pub fn seeder(torrent: &Torrent, data: Vec<u8>) -> MockPeer {
+        MockPeer {
+            data: Some(Arc::new(data)),
+            ..MockPeer::stalling(torrent)
+        }
+    }
+
+    /// A peer that claims to have every piece and unchokes us, but never answers a request.
+    pub fn stalling(torrent: &Torrent) -> MockPeer {
+        MockPeer {
+            info_hash: torrent.info_hash(),
+            peer_id: [8; 20],
+            piece_length: torrent.info.piece_length,
+            data: None,
+            pieces: Bitfield::full(torrent.piece_count()),
+            unchokes: true,
+            corrupted: HashSet::new(),
+            blocks_before_closing: None,
+        }
+    }
+
+    pub fn with_peer_id(self, peer_id: [u8; 20]) -> MockPeer {
+        MockPeer { peer_id, ..self }
+    }
+
+    /// Only has the pieces at `indices`, and ignores requests for the others.
+    pub fn with_pieces(self, indices: &[usize]) -> MockPeer {
+        let mut pieces = Bitfield::new(self.pieces.len());
+        for &index in indices {
+            pieces.set(index);
+        }
+        MockPeer { pieces, ..self }
+    }
+
+    /// Never unchokes us, so nothing can be requested from it.
+    pub fn choking(self) -> MockPeer {
+        MockPeer {
+            unchokes: false,
+            ..self
+        }
+    }
+
+    /// Sends the blocks of the piece at `index` with their bytes flipped, so it fails its hash
+    /// check.
+    pub fn corrupting(mut self, index: usize) -> MockPeer {
+        self.corrupted.insert(index);
+        self
+    }
+
+    /// Closes each connection once it sent `blocks` blocks on it.
+    pub fn closing_after(self, blocks: usize) -> MockPeer {
+        MockPeer {
+            blocks_before_closing: Some(blocks),
+            ..self
+        }
+    }
+
+    /// Accepts connections on a local port and serves each of them.
+    pub async fn listen(
+        self,
+    ) -> anyhow::Result<(SocketAddr, mpsc::UnboundedReceiver<PeerMessage>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (received, log) = mpsc::unbounded_channel();
+        let peer = Arc::new(self);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(peer.clone().serve(stream, received.clone()));
+            }
+        });
+        Ok((addr, log))
+    }
+
+    /// Serves a single connection over an in-process stream and hands back our end of it, as
+    /// if we had just connected to the peer and not sent the handshake yet.
+    pub fn duplex(self) -> (DuplexStream, mpsc::UnboundedReceiver<PeerMessage>) {
+        let (ours, theirs) = tokio::io::duplex(DUPLEX_BUFFER);
+        let (received, log) = mpsc::unbounded_channel();
+        tokio::spawn(Arc::new(self).serve(theirs, received));
+        (ours, log)
+    }
+
+    /// Answers the handshake on `stream` and then behaves as told until the other side hangs
+    /// up, handing the messages it receives to `received`.
+    async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+        self: Arc<Self>,
+        mut stream: S,
+        received: mpsc::UnboundedSender<PeerMessage>,
+    ) -> anyhow::Result<()> {
+        let mut handshake = [0; Handshake::LENGTH];
+        stream.read_exact(&mut handshake).await?;
+        let theirs = Handshake::from_bytes(&handshake)?;
+        if theirs.info_hash != self.info_hash {
+            anyhow::bail!("asked for info hash {}", hex::encode(theirs.info_hash));
+        }
+        stream
+            .write_all(&Handshake::new(self.info_hash, self.peer_id).to_bytes())
+            .await?;
+        let mut framed = Framed::new(stream, MessageCodec);
+        let bitfield = self.pieces.as_bytes().to_vec();
+        framed.send(PeerMessage::Bitfield(bitfield)).await?;
+        let mut sent = 0;
+        while let Some(message) = framed.next().await {
+            let message = message?;
+            let _ = received.send(message.clone());
+            match message {
+                PeerMessage::Interested if self.unchokes => {
+                    framed.send(PeerMessage::Unchoke).await?
+                }
+                PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    let Some(block) = self.block(index as usize, begin as usize, length as usize)
+                    else {
+                        continue;
+                    };
+                    framed
+                        .send(PeerMessage::Piece {
+                            index,
+                            begin,
+                            block,
+                        })
+                        .await?;
+                    sent += 1;
+                    if self.blocks_before_closing == Some(sent) {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// What is sent for a request, unless it goes unanswered.
+    fn block(&self, index: usize, begin: usize, length: usize) -> Option<Vec<u8>> {
+        if !self.unchokes || !self.pieces.get(index) {
+            return None;
+        }
+        let start = index * self.piece_length + begin;
+        let mut block = self.data.as_ref()?.get(start..start + length)?.to_vec();
+        if self.corrupted.contains(&index) {
+            block.iter_mut().for_each(|byte| *byte = !*byte);
+        }
+        Some(block)
+    }
+}
+
+/// What a `MockTracker` answers an announce with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Announce {
+    /// These peers, in the compact form, to be announced to again after `interval` seconds.
+    Peers {
+        interval: usize,
+        peers: Vec<SocketAddr>,
+    },
+    /// A failure reason instead of any peers.
+    Failure(String),
+}
+
+/// An HTTP tracker that answers announces with a script of responses in turn, repeating the
+/// last one, and records the request line of each announce.
+#[derive(Debug, Clone)]
+pub struct MockTracker {
+    responses: Vec<Announce>,
+}
+
+impl MockTracker {
+    /// A tracker that always hands out `peers`.
+    pub fn with_peers(peers: &[SocketAddr]) -> MockTracker {
+        MockTracker::scripted(vec![Announce::Peers {
+            interval: 1800,
+            peers: peers.to_vec(),
+        }])
+    }
+
+    /// A tracker that answers the first announces with `responses`, one each.
+    pub fn scripted(responses: Vec<Announce>) -> MockTracker {
+        assert!(!responses.is_empty(), "a tracker needs a response");
+        MockTracker { responses }
+    }
+
+    /// Serves announces on a local port, at the returned URL.
+    pub async fn spawn(self) -> anyhow::Result<SpawnedTracker> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/announce", listener.local_addr()?);
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = [0; 4096];
+                let Ok(n) = stream.read(&mut buffer).await else {
+                    continue;
+                };
+                let request = String::from_utf8_lossy(&buffer[..n]);
+                let count = {
+                    let mut log = log.lock().unwrap();
+                    log.push(request.lines().next().unwrap_or_default().to_owned());
+                    log.len()
+                };
+                let body = self.responses[(count - 1).min(self.responses.len() - 1)].to_bytes();
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(header.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            }
+        });
+        Ok(SpawnedTracker { url, requests })
+    }
+}
+
+impl Announce {
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Announce::Peers { interval, peers } => {
+                let mut compact = Vec::new();
+                for peer in peers {
+                    if let SocketAddr::V4(peer) = peer {
+                        compact.extend_from_slice(&peer.ip().octets());
+                        compact.extend_from_slice(&peer.port().to_be_bytes());
+                    }
+                }
+                let mut body =
+                    format!("d8:intervali{}e5:peers{}:", interval, compact.len()).into_bytes();
+                body.extend_from_slice(&compact);
+                body.push(b'e');
+                body
+            }
+            Announce::Failure(reason) => {
+                format!("d14:failure reason{}:{}e", reason.len(), reason).into_bytes()
+            }
+        }
+    }
+}
+
+/// A running `MockTracker`.
+#[derive(Debug, Clone)]
+pub struct SpawnedTracker {
+    pub url: String,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl SpawnedTracker {
+    /// The request line of every announce so far, e.g. to check its parameters.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{test_data, torrent_for};
+    use crate::download::{download_with_tracker, DownloadOptions, PeerSession};
+    use crate::peer;
+    use crate::storage::MemoryStorage;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn downloads_from_a_mock_swarm() {
+        let data = test_data(5 * 32768);
+        let mut torrent = torrent_for(&data, 32768);
+        // one peer sends a bad copy of the piece only it has at first, the other has them all
+        let (corrupt, _) = MockPeer::seeder(&torrent, data.clone())
+            .with_pieces(&[2])
+            .corrupting(2)
+            .listen()
+            .await
+            .unwrap();
+        let (seeder, _) = MockPeer::seeder(&torrent, data.clone())
+            .with_peer_id([9; 20])
+            .listen()
+            .await
+            .unwrap();
+        let tracker = MockTracker::scripted(vec![
+            Announce::Peers {
+                interval: 1,
+                peers: vec![corrupt],
+            },
+            Announce::Peers {
+                interval: 1800,
+                peers: vec![corrupt, seeder],
+            },
+        ])
+        .spawn()
+        .await
+        .unwrap();
+        torrent.announce = tracker.url.clone();
+
+        let dir = tempfile::tempdir().unwrap();
+        let memory = MemoryStorage::default();
+        let options = DownloadOptions {
+            port: 0,
+            storage: Some(Arc::new(memory.clone())),
+            ..DownloadOptions::default()
+        };
+        download_with_tracker(&torrent, [1; 20], &dir.path().join("test.bin"), &options)
+            .await
+            .unwrap();
+        assert_eq!(memory.content(), data);
+        assert!(memory.is_complete());
+        let requests = tracker.requests();
+        assert!(requests[0].contains("event=started"));
+        assert!(requests
+            .iter()
+            .any(|request| request.contains("event=completed")));
+    }
+
+    #[tokio::test]
+    async fn peers_over_in_process_streams() {
+        let data = test_data(100_000);
+        let torrent = torrent_for(&data, 32768);
+        let (mut stream, mut received) = MockPeer::seeder(&torrent, data.clone()).duplex();
+        let handshake = peer::handshake(&mut stream, torrent.info_hash(), [1; 20])
+            .await
+            .unwrap();
+        assert_eq!(handshake.peer_id, [8; 20]);
+        let mut session = PeerSession::start_for(stream, &handshake).await.unwrap();
+        assert!(session.has_piece(3));
+        let piece = session.download_piece(&torrent, 3).await.unwrap();
+        assert_eq!(piece, &data[3 * 32768..]);
+        let mut requested = 0;
+        while let Ok(message) = received.try_recv() {
+            requested += matches!(message, PeerMessage::Request { index: 3, .. }) as usize;
+        }
+        assert_eq!(requested, 1);
+
+        // a peer that hangs up partway through the piece, and one that never unchokes us
+        let (closing, _) = MockPeer::seeder(&torrent, data)
+            .closing_after(1)
+            .listen()
+            .await
+            .unwrap();
+        let (stream, _) = peer::connect(closing, torrent.info_hash(), [1; 20])
+            .await
+            .unwrap();
+        let mut session = PeerSession::start(stream).await.unwrap();
+        assert!(session.download_piece(&torrent, 0).await.is_err());
+        let (mut stream, _) = MockPeer::stalling(&torrent).choking().duplex();
+        peer::handshake(&mut stream, torrent.info_hash(), [1; 20])
+            .await
+            .unwrap();
+        let unchoked = tokio::time::timeout(Duration::from_millis(200), PeerSession::start(stream));
+        assert!(unchoked.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failing_announces() {
+        let tracker = MockTracker::scripted(vec![Announce::Failure("not registered".to_owned())])
+            .spawn()
+            .await
+            .unwrap();
+        let data = test_data(1000);
+        let mut torrent = torrent_for(&data, 300);
+        torrent.announce = tracker.url.clone();
+        let dir = tempfile::tempdir().unwrap();
+        let options = DownloadOptions {
+            port: 0,
+            storage: Some(Arc::new(MemoryStorage::default())),
+            ..DownloadOptions::default()
+        };
+        let error =
+            download_with_tracker(&torrent, [1; 20], &dir.path().join("test.bin"), &options)
+                .await
+                .unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("not registered"),
+            "{:#}",
+            error
+        );
+        assert_eq!(tracker.requests().len(), 1);
+    }
+}
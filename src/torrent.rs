@@ -0,0 +1,1384 @@
+use crate::bencode::{self, dict_value_span, Bencode, BencodeRef};
+use crate::magnet::Magnet;
+use crate::merkle::{self, BLOCK_SIZE};
+use crate::tracker;
+use anyhow::Context;
+use encoding_rs::Encoding;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+/// Metainfo (.torrent) file contents.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Torrent {
+    /// URL of the tracker; empty for trackerless torrents, whose peers come from the DHT.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub announce: String,
+    /// Tiers of tracker URLs (BEP 12); when present it takes precedence over `announce`.
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub announce_list: Vec<Vec<String>>,
+    /// Web seeds (BEP 19): URLs the files of the torrent can be downloaded from over HTTP.
+    #[serde(
+        rename = "url-list",
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "one_or_many"
+    )]
+    pub url_list: Vec<String>,
+    /// HTTP seeds (BEP 17): URLs of scripts that serve the pieces of the torrent by index.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub httpseeds: Vec<String>,
+    /// The piece hashes of every v2 file longer than one piece, keyed by its `pieces root`
+    /// (BEP 52). Each value is the file's 32-byte SHA-256 piece hashes back to back.
+    #[serde(
+        rename = "piece layers",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub piece_layers: BTreeMap<ByteBuf, ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// Name and version of the program that created the torrent.
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+    /// When the torrent was created, in seconds since the Unix epoch.
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+    /// The character set the names and texts of the torrent were written in, if not UTF-8,
+    /// as some older clients did. They are read into UTF-8 all the same.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    pub info: Info,
+    /// The info dictionary exactly as it was read, if the torrent was: the info hashes are
+    /// taken from these bytes and the torrent is written back with them, so keys `Info`
+    /// doesn't know about are kept. Has to be cleared when `info` is changed.
+    #[serde(skip)]
+    pub raw_info: Option<Vec<u8>>,
+    /// Files kept at other paths than the metainfo gives them, relative to the download
+    /// location, by their index in `files`; set with `rename`. Part of neither the metainfo
+    /// nor the info hash.
+    #[serde(skip)]
+    pub renamed: BTreeMap<usize, PathBuf>,
+}
+
+/// `url-list` may be a single URL instead of a list; an empty one means there are none.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    let urls = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    };
+    Ok(urls.into_iter().filter(|url| !url.is_empty()).collect())
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Info {
+    /// Suggested name of the file (or, for multi-file torrents, the directory) to save as.
+    pub name: String,
+    /// Number of bytes in each piece (the last piece may be shorter).
+    #[serde(rename = "piece length")]
+    pub piece_length: usize,
+    /// SHA-1 hash of every piece; v2-only torrents have none.
+    #[serde(default, skip_serializing_if = "Hashes::is_empty")]
+    pub pieces: Hashes,
+    #[serde(flatten)]
+    pub keys: Keys,
+    /// 2 for v2 and hybrid torrents (BEP 52).
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u64>,
+    /// 1 if peers may only come from the torrent's trackers (BEP 27).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+    /// The files of a v2 or hybrid torrent with their merkle roots.
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<FileTree>,
+}
+
+/// A torrent has either a single `length` or a list of `files`, never both; a v2-only torrent
+/// has neither.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Keys {
+    SingleFile {
+        /// Length of the file in bytes.
+        length: usize,
+    },
+    MultiFile {
+        files: Vec<File>,
+    },
+    /// The files are only listed in the `file tree`.
+    FileTree {},
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct File {
+    /// Length of the file in bytes.
+    pub length: usize,
+    /// Path components relative to the torrent's directory; the last one is the file name.
+    pub path: Vec<String>,
+    /// File attributes (BEP 47); `p` marks a padding file.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub attr: String,
+}
+
+impl File {
+    /// Padding files only hold the zeros aligning the next file to a piece boundary, so they
+    /// are not stored.
+    pub fn is_padding(&self) -> bool {
+        self.attr.contains('p')
+    }
+}
+
+/// A directory of a v2 file tree, by the names of its entries.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct FileTree(pub BTreeMap<String, FileTreeEntry>);
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FileTreeEntry {
+    /// A file is a dictionary whose only key is the empty string.
+    File {
+        #[serde(rename = "")]
+        file: TreeFile,
+    },
+    Directory(FileTree),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TreeFile {
+    pub length: usize,
+    /// Root of the merkle tree over the file's 16 KiB blocks; empty files have none.
+    #[serde(
+        rename = "pieces root",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pieces_root: Option<ByteBuf>,
+}
+
+impl FileTree {
+    /// Every file in the tree with its path, sorted by path: the order of the files in the
+    /// torrent's pieces.
+    pub fn files(&self) -> Vec<(Vec<String>, &TreeFile)> {
+        let mut files = Vec::new();
+        for (name, entry) in &self.0 {
+            match entry {
+                FileTreeEntry::File { file } => files.push((vec![name.clone()], file)),
+                FileTreeEntry::Directory(tree) => {
+                    for (mut path, file) in tree.files() {
+                        path.insert(0, name.clone());
+                        files.push((path, file));
+                    }
+                }
+            }
+        }
+        files
+    }
+}
+
+/// Where a file's data sits in the concatenated content of the torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSpan {
+    /// Path relative to the download location; empty for single-file torrents.
+    pub path: PathBuf,
+    pub offset: usize,
+    pub length: usize,
+}
+
+/// The metainfo `bytes` with the names of the torrent and its files, its comment and the
+/// name of its creator in UTF-8, or `None` if they are already. Each is taken from its BEP 3
+/// `.utf-8` variant if there is one, such as `name.utf-8`, else read in the character set of
+/// the `encoding` key if that is another, e.g. GBK; what still isn't UTF-8 after that has its
+/// invalid bytes replaced. The info hash is of the bytes as they are, not of these.
+fn to_utf8(bytes: &[u8]) -> Option<Vec<u8>> {
+    // most torrents are UTF-8 throughout, and copying their pieces to find that out is a waste
+    if !needs_utf8(bytes) {
+        return None;
+    }
+    // what doesn't decode is left for the deserializer to tell what is wrong with it
+    let Ok(Bencode::Dict(mut dict)) = bencode::decode(bytes) else {
+        return None;
+    };
+    let encoding = match dict.get(b"encoding".as_slice()) {
+        Some(Bencode::Bytes(label)) => other_encoding(label),
+        _ => None,
+    };
+    let mut changed = false;
+    for key in [b"comment".as_slice(), b"created by"] {
+        changed |= text_to_utf8(&mut dict, key, encoding);
+    }
+    if let Some(Bencode::Dict(info)) = dict.get_mut(b"info".as_slice()) {
+        changed |= text_to_utf8(info, b"name", encoding);
+        if let Some(Bencode::List(files)) = info.get_mut(b"files".as_slice()) {
+            for file in files {
+                if let Bencode::Dict(file) = file {
+                    changed |= text_to_utf8(file, b"path", encoding);
+                }
+            }
+        }
+    }
+    changed.then(|| Bencode::Dict(dict).to_bytes())
+}
+
+/// Whether `to_utf8` has anything to do with the metainfo `bytes`: they have an `encoding`
+/// other than UTF-8, a `.utf-8` variant, or a text that isn't UTF-8. Looked up without copying
+/// the byte strings out of `bytes`.
+fn needs_utf8(bytes: &[u8]) -> bool {
+    let Ok(BencodeRef::Dict(dict)) = bencode::decode_borrowed(bytes) else {
+        return false;
+    };
+    if let Some(BencodeRef::Bytes(label)) = dict.get(b"encoding".as_slice()) {
+        if other_encoding(label).is_some() {
+            return true;
+        }
+    }
+    let needs = |dict: &BTreeMap<&[u8], BencodeRef>, key: &[u8]| {
+        dict.contains_key([key, b".utf-8"].concat().as_slice())
+            || dict.get(key).is_some_and(|text| !is_utf8_ref(text))
+    };
+    if needs(&dict, b"comment") || needs(&dict, b"created by") {
+        return true;
+    }
+    let Some(BencodeRef::Dict(info)) = dict.get(b"info".as_slice()) else {
+        return false;
+    };
+    if needs(info, b"name") {
+        return true;
+    }
+    match info.get(b"files".as_slice()) {
+        Some(BencodeRef::List(files)) => files.iter().any(|file| match file {
+            BencodeRef::Dict(file) => needs(file, b"path"),
+            _ => false,
+        }),
+        _ => false,
+    }
+}
+
+/// Like `is_utf8`, of a borrowed text.
+fn is_utf8_ref(text: &BencodeRef) -> bool {
+    match text {
+        BencodeRef::Bytes(bytes) => std::str::from_utf8(bytes).is_ok(),
+        BencodeRef::List(parts) => parts
+            .iter()
+            .all(|part| matches!(part, BencodeRef::Bytes(_)) && is_utf8_ref(part)),
+        _ => false,
+    }
+}
+
+/// The character set of an `encoding` label, unless it is UTF-8 or unknown.
+fn other_encoding(label: &[u8]) -> Option<&'static Encoding> {
+    Encoding::for_label(label.trim_ascii()).filter(|&encoding| encoding != encoding_rs::UTF_8)
+}
+
+/// The metainfo `bytes` with the comment and the name of the creator in `encoding`, as
+/// `to_utf8` read them.
+fn from_utf8(bytes: &[u8], encoding: &'static Encoding) -> Vec<u8> {
+    let Ok(Bencode::Dict(mut dict)) = bencode::decode(bytes) else {
+        return bytes.to_vec();
+    };
+    for key in [b"comment".as_slice(), b"created by"] {
+        if let Some(Bencode::Bytes(text)) = dict.get_mut(key) {
+            let encoded = encoding
+                .encode(&String::from_utf8_lossy(text))
+                .0
+                .into_owned();
+            *text = encoded;
+        }
+    }
+    Bencode::Dict(dict).to_bytes()
+}
+
+/// Puts the text at `key` of `dict`, a byte string or a list of them like a file's `path`,
+/// in UTF-8 as `to_utf8` says; true if that changed it.
+fn text_to_utf8(
+    dict: &mut BTreeMap<Vec<u8>, Bencode>,
+    key: &[u8],
+    encoding: Option<&'static Encoding>,
+) -> bool {
+    let hint = dict.get(&[key, b".utf-8"].concat()).cloned();
+    let Some(text) = dict.get_mut(key) else {
+        return false;
+    };
+    let utf8 = match hint {
+        Some(hint @ Bencode::Bytes(_)) if is_utf8(&hint) && matches!(text, Bencode::Bytes(_)) => {
+            hint
+        }
+        Some(hint @ Bencode::List(_)) if is_utf8(&hint) && matches!(text, Bencode::List(_)) => hint,
+        _ => match transcode(text, encoding) {
+            Some(utf8) => utf8,
+            None => return false,
+        },
+    };
+    if *text == utf8 {
+        return false;
+    }
+    *text = utf8;
+    true
+}
+
+fn is_utf8(text: &Bencode) -> bool {
+    match text {
+        Bencode::Bytes(bytes) => std::str::from_utf8(bytes).is_ok(),
+        Bencode::List(parts) => parts
+            .iter()
+            .all(|part| matches!(part, Bencode::Bytes(_)) && is_utf8(part)),
+        _ => false,
+    }
+}
+
+/// `text` read in `encoding`, or as UTF-8 with its invalid bytes replaced without one.
+fn transcode(text: &Bencode, encoding: Option<&'static Encoding>) -> Option<Bencode> {
+    match text {
+        Bencode::Bytes(bytes) => {
+            let utf8 = match encoding {
+                Some(encoding) => encoding.decode_without_bom_handling(bytes).0,
+                None => String::from_utf8_lossy(bytes),
+            };
+            Some(Bencode::Bytes(utf8.into_owned().into_bytes()))
+        }
+        Bencode::List(parts) => parts
+            .iter()
+            .map(|part| match part {
+                Bencode::Bytes(_) => transcode(part, encoding),
+                _ => None,
+            })
+            .collect::<Option<_>>()
+            .map(Bencode::List),
+        _ => None,
+    }
+}
+
+impl Torrent {
+    pub fn from_bytes(bytes: &[u8]) -> crate::Result<Torrent> {
+        Torrent::parse(bytes).map_err(crate::Error::Metainfo)
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> crate::Result<Torrent> {
+        let bytes = std::fs::read(path).map_err(|e| crate::Error::Metainfo(e.into()))?;
+        Torrent::from_bytes(&bytes)
+    }
+
+    fn parse(bytes: &[u8]) -> anyhow::Result<Torrent> {
+        let mut torrent: Torrent = match to_utf8(bytes) {
+            Some(transcoded) => bencode::from_bytes(&transcoded)?,
+            None => bencode::from_bytes(bytes)?,
+        };
+        let info = dict_value_span(bytes, b"info")?.context("torrent has no info dictionary")?;
+        torrent.raw_info = Some(bytes[info].to_vec());
+        torrent.validate()?;
+        Ok(torrent)
+    }
+
+    /// The bencoded metainfo file. The info dictionary as it was read keeps the names in its
+    /// `encoding`, so the comment and the creator are written in it too; a torrent without it
+    /// is all UTF-8.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let encoding = self
+            .encoding
+            .as_deref()
+            .and_then(|label| other_encoding(label.as_bytes()));
+        let mut bytes = match (encoding, &self.raw_info) {
+            (Some(encoding), Some(_)) => {
+                let bytes = bencode::to_bytes(self).expect("torrent is serializable");
+                from_utf8(&bytes, encoding)
+            }
+            (Some(_), None) => {
+                let torrent = Torrent {
+                    encoding: None,
+                    ..self.clone()
+                };
+                bencode::to_bytes(&torrent).expect("torrent is serializable")
+            }
+            (None, _) => bencode::to_bytes(self).expect("torrent is serializable"),
+        };
+        if let Some(raw_info) = &self.raw_info {
+            let info = dict_value_span(&bytes, b"info")
+                .ok()
+                .flatten()
+                .expect("encoded torrent has an info dictionary");
+            bytes.splice(info, raw_info.iter().copied());
+        }
+        bytes
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+
+    /// A magnet link to the torrent with its name and every tracker, from which peers can
+    /// fetch the rest of the metadata.
+    pub fn to_magnet(&self) -> Magnet {
+        let mut trackers: Vec<String> = Vec::new();
+        for url in std::iter::once(&self.announce).chain(self.announce_list.iter().flatten()) {
+            if !url.is_empty() && !trackers.contains(url) {
+                trackers.push(url.clone());
+            }
+        }
+        Magnet {
+            info_hash: self.info_hash(),
+            name: Some(self.info.name.clone()),
+            trackers,
+            select_only: Vec::new(),
+        }
+    }
+
+    /// Adds the trackers of `other` that the torrent lacks, those of each of its tiers in a
+    /// tier of their own after the existing ones, and returns their URLs. Only `announce` and
+    /// `announce-list` change, so the info hash stays the same.
+    pub fn merge_trackers(&mut self, other: &Torrent) -> Vec<String> {
+        let mut tiers = tracker::tiers(self);
+        let mut added = Vec::new();
+        for tier in tracker::tiers(other) {
+            let new: Vec<String> = tier
+                .into_iter()
+                .filter(|url| !tiers.iter().flatten().any(|known| known == url))
+                .collect();
+            if !new.is_empty() {
+                added.extend(new.iter().cloned());
+                tiers.push(new);
+            }
+        }
+        if !added.is_empty() {
+            tiers.retain(|tier| !tier.is_empty());
+            if self.announce.is_empty() {
+                self.announce = tiers[0][0].clone();
+            }
+            self.announce_list = tiers;
+        }
+        added
+    }
+
+    /// Whether the torrent has v1 metadata: SHA-1 hashes of pieces over its files back to back.
+    pub fn is_v1(&self) -> bool {
+        !matches!(self.info.keys, Keys::FileTree {})
+    }
+
+    /// Whether the torrent has v2 metadata (BEP 52): a file tree with merkle roots, with every
+    /// file starting at a piece boundary. Hybrid torrents have both.
+    pub fn is_v2(&self) -> bool {
+        self.info.meta_version == Some(2) && self.info.file_tree.is_some()
+    }
+
+    /// Bytes of file data; the padding files of a hybrid torrent count too.
+    pub fn total_length(&self) -> usize {
+        match &self.info.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultiFile { files } => files.iter().map(|file| file.length).sum(),
+            Keys::FileTree {} => self.tree_files().iter().map(|file| file.1.length).sum(),
+        }
+    }
+
+    pub fn piece_count(&self) -> usize {
+        if self.is_v1() {
+            return self.info.pieces.0.len();
+        }
+        let piece_length = self.info.piece_length;
+        let files = self.tree_files();
+        files
+            .iter()
+            .map(|(_, file)| file.length.div_ceil(piece_length))
+            .sum()
+    }
+
+    /// Length of the piece at `index`. Only the last piece may be shorter than the rest, or,
+    /// in a v2-only torrent, the last piece of every file.
+    pub fn piece_size(&self, index: usize) -> usize {
+        let start = index * self.info.piece_length;
+        let end = match self.is_v1() {
+            true => self.total_length(),
+            false => self
+                .files()
+                .unwrap_or_default()
+                .iter()
+                .map(|span| span.offset + span.length)
+                .find(|&end| end > start)
+                .unwrap_or(0),
+        };
+        self.info.piece_length.min(end.saturating_sub(start))
+    }
+
+    /// Checks `piece`, the data of the piece at `index`, against its SHA-1 hash, or, in a
+    /// v2-only torrent, against the merkle tree of its file.
+    pub fn verify_piece(&self, index: usize, piece: &[u8]) -> bool {
+        if self.is_v1() {
+            let hash: [u8; 20] = Sha1::digest(piece).into();
+            return self.info.pieces.0.get(index) == Some(&hash);
+        }
+        let piece_length = self.info.piece_length;
+        let start = index * piece_length;
+        let Ok(spans) = self.files() else {
+            return false;
+        };
+        let files = self.tree_files();
+        let Some((span, (_, file))) = spans
+            .iter()
+            .zip(&files)
+            .find(|(span, _)| span.offset <= start && start < span.offset + span.length)
+        else {
+            return false;
+        };
+        let Some(root) = &file.pieces_root else {
+            return false;
+        };
+        if file.length <= piece_length {
+            return merkle::file_root(piece, piece_length) == root[..];
+        }
+        let position = (start - span.offset) / piece_length * 32;
+        let expected = self
+            .piece_layers
+            .get(root)
+            .and_then(|layer| layer.get(position..position + 32));
+        expected == Some(&merkle::piece_hash(piece, piece_length)[..])
+    }
+
+    /// Lays the torrent's files out in the order they are hashed into pieces: back to back,
+    /// leaving out padding files, or, in a v2-only torrent, each starting at a piece boundary.
+    ///
+    /// Paths that could escape the download location (`..`, absolute or empty components)
+    /// are rejected.
+    ///
+    /// Files that were renamed are at their new paths.
+    pub fn files(&self) -> anyhow::Result<Vec<FileSpan>> {
+        let mut spans = self.original_files()?;
+        for (&index, path) in &self.renamed {
+            if let Some(span) = spans.get_mut(index) {
+                span.path = path.clone();
+            }
+        }
+        Ok(spans)
+    }
+
+    /// Keeps the file or directory that is at `from` in `files` at `to` instead, both relative
+    /// to the download location. Meant for before the download starts: data already written
+    /// isn't moved. No two files may end up at the same path, nor one inside another.
+    pub fn rename(&mut self, from: &Path, to: &Path) -> anyhow::Result<()> {
+        let is_plain = |path: &Path| {
+            path.components().next().is_some()
+                && path.components().all(|c| matches!(c, Component::Normal(_)))
+        };
+        if !is_plain(from) || !is_plain(to) {
+            anyhow::bail!("can't rename {:?} to {:?}", from, to);
+        }
+        if matches!(self.info.keys, Keys::SingleFile { .. }) {
+            anyhow::bail!("the file of a single-file torrent is at the output path itself");
+        }
+        let mut files = self.files()?;
+        let mut renamed = self.renamed.clone();
+        for (index, file) in files.iter_mut().enumerate() {
+            if let Ok(rest) = file.path.strip_prefix(from) {
+                file.path = to.join(rest);
+                renamed.insert(index, file.path.clone());
+            }
+        }
+        if renamed == self.renamed {
+            anyhow::bail!("the torrent has no file or directory {:?}", from);
+        }
+        // a file inside another sorts right after it
+        let mut paths: Vec<&Path> = files.iter().map(|file| file.path.as_path()).collect();
+        paths.sort();
+        if let Some(pair) = paths.windows(2).find(|pair| pair[1].starts_with(pair[0])) {
+            anyhow::bail!("{:?} and {:?} would clash", pair[0], pair[1]);
+        }
+        self.renamed = renamed;
+        Ok(())
+    }
+
+    fn original_files(&self) -> anyhow::Result<Vec<FileSpan>> {
+        let files = match &self.info.keys {
+            Keys::SingleFile { length } => {
+                return Ok(vec![FileSpan {
+                    path: PathBuf::new(),
+                    offset: 0,
+                    length: *length,
+                }])
+            }
+            Keys::MultiFile { files } => files,
+            Keys::FileTree {} => return self.tree_spans(),
+        };
+
+        let mut spans = Vec::with_capacity(files.len());
+        let mut offset = 0;
+        for file in files {
+            if !file.is_padding() {
+                spans.push(FileSpan {
+                    path: relative_path(&file.path)?,
+                    offset,
+                    length: file.length,
+                });
+            }
+            offset += file.length;
+        }
+        Ok(spans)
+    }
+
+    fn tree_files(&self) -> Vec<(Vec<String>, &TreeFile)> {
+        self.info
+            .file_tree
+            .as_ref()
+            .map(FileTree::files)
+            .unwrap_or_default()
+    }
+
+    /// The files of the file tree, each starting at a piece boundary. A tree of one file is
+    /// stored like a single-file torrent.
+    fn tree_spans(&self) -> anyhow::Result<Vec<FileSpan>> {
+        let files = self.tree_files();
+        let single = files.len() == 1 && files[0].0.len() == 1;
+        let mut spans = Vec::with_capacity(files.len());
+        let mut offset = 0;
+        for (path, file) in files {
+            spans.push(FileSpan {
+                path: match single {
+                    true => PathBuf::new(),
+                    false => relative_path(&path)?,
+                },
+                offset,
+                length: file.length,
+            });
+            offset += file.length.next_multiple_of(self.info.piece_length);
+        }
+        Ok(spans)
+    }
+
+    /// Checks that the name, the file or directory the download goes to, is a single plain
+    /// path component, so that it can't lead out of the directory it is downloaded to.
+    pub(crate) fn validate_name(&self) -> anyhow::Result<()> {
+        relative_path(std::slice::from_ref(&self.info.name))
+            .with_context(|| format!("invalid name in torrent: {:?}", self.info.name))?;
+        Ok(())
+    }
+
+    /// Checks that what a v2 torrent says about its files adds up: every file has its merkle
+    /// root, the piece layers match the roots, and a hybrid torrent's v1 files are the same as
+    /// the v2 ones.
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(version) = self.info.meta_version.filter(|&version| version != 2) {
+            anyhow::bail!("unsupported meta version {}", version);
+        }
+        self.validate_name()?;
+        let piece_length = self.info.piece_length;
+        if piece_length == 0 {
+            anyhow::bail!("piece length is 0");
+        }
+        // metadata can come from any peer, and offsets past the end of memory would overflow
+        if self.is_v1() {
+            let lengths: Vec<usize> = match &self.info.keys {
+                Keys::SingleFile { length } => vec![*length],
+                Keys::MultiFile { files } => files.iter().map(|file| file.length).collect(),
+                Keys::FileTree {} => Vec::new(),
+            };
+            let total = lengths
+                .into_iter()
+                .try_fold(0usize, usize::checked_add)
+                .context("the files add up to more bytes than can be addressed")?;
+            let pieces = self.info.pieces.0.len();
+            if pieces != total.div_ceil(piece_length) {
+                anyhow::bail!(
+                    "torrent has {} piece hashes for {} bytes in pieces of {}",
+                    pieces,
+                    total,
+                    piece_length
+                );
+            }
+        }
+        if !self.is_v2() {
+            if !self.is_v1() {
+                anyhow::bail!("torrent lists no files");
+            }
+            return Ok(());
+        }
+        if !piece_length.is_power_of_two() || piece_length < BLOCK_SIZE {
+            anyhow::bail!("invalid piece length {} for a v2 torrent", piece_length);
+        }
+        // every file starts at a piece boundary
+        self.tree_files()
+            .iter()
+            .try_fold(0usize, |offset, (_, file)| {
+                offset.checked_add(file.length.checked_next_multiple_of(piece_length)?)
+            })
+            .context("the files add up to more bytes than can be addressed")?;
+        for (path, file) in self.tree_files() {
+            let Some(root) = &file.pieces_root else {
+                if file.length > 0 {
+                    anyhow::bail!("file {:?} has no pieces root", path);
+                }
+                continue;
+            };
+            if root.len() != 32 {
+                anyhow::bail!("pieces root of {:?} is {} bytes long", path, root.len());
+            }
+            if file.length <= piece_length {
+                continue;
+            }
+            let layer = self
+                .piece_layers
+                .get(root)
+                .with_context(|| format!("file {:?} has no piece layer", path))?;
+            if layer.len() != file.length.div_ceil(piece_length) * 32 {
+                anyhow::bail!("piece layer of {:?} has the wrong length", path);
+            }
+            let hashes: Vec<[u8; 32]> = layer
+                .chunks_exact(32)
+                .map(|hash| hash.try_into().expect("hash is 32 bytes long"))
+                .collect();
+            if merkle::root_from_layer(&hashes, piece_length) != root[..] {
+                anyhow::bail!("piece layer of {:?} doesn't match its pieces root", path);
+            }
+        }
+        if self.is_v1() {
+            let non_empty = |spans: Vec<FileSpan>| -> Vec<FileSpan> {
+                spans.into_iter().filter(|span| span.length > 0).collect()
+            };
+            if non_empty(self.files()?) != non_empty(self.tree_spans()?) {
+                anyhow::bail!("the v1 and v2 files of the hybrid torrent differ");
+            }
+        }
+        Ok(())
+    }
+
+    /// SHA-1 of the bencoded info dictionary, identifying the torrent to trackers and peers. A
+    /// v2-only torrent goes by its truncated v2 info hash instead.
+    pub fn info_hash(&self) -> [u8; 20] {
+        if !self.is_v1() {
+            if let Some(info_hash) = self.info_hash_v2() {
+                return info_hash[..20].try_into().expect("hash is 32 bytes long");
+            }
+        }
+        Sha1::digest(self.info_bytes()).into()
+    }
+
+    /// SHA-256 of the bencoded info dictionary of a v2 or hybrid torrent.
+    pub fn info_hash_v2(&self) -> Option<[u8; 32]> {
+        if !self.is_v2() {
+            return None;
+        }
+        Some(Sha256::digest(self.info_bytes()).into())
+    }
+
+    /// The bencoded info dictionary the info hashes are taken from.
+    fn info_bytes(&self) -> Cow<'_, [u8]> {
+        match &self.raw_info {
+            Some(raw_info) => Cow::Borrowed(raw_info),
+            None => {
+                Cow::Owned(bencode::to_bytes(&self.info).expect("info dictionary is serializable"))
+            }
+        }
+    }
+
+    /// The value of `key` in the info dictionary, keys `Info` has no field for included, such
+    /// as the `source` or `x-` keys private trackers add.
+    pub fn info_field(&self, key: &str) -> Option<Bencode> {
+        let info = self.info_bytes();
+        match bencode::decode_borrowed(&info).ok()? {
+            BencodeRef::Dict(mut dict) => dict.remove(key.as_bytes()).map(Bencode::from),
+            _ => None,
+        }
+    }
+
+    /// The info hashes the swarm knows the torrent by: a hybrid torrent is in both the v1 and
+    /// the v2 swarm, the latter under the truncated v2 info hash.
+    pub fn info_hashes(&self) -> Vec<[u8; 20]> {
+        let mut info_hashes = vec![self.info_hash()];
+        if let Some(info_hash) = self.info_hash_v2() {
+            let truncated = info_hash[..20].try_into().expect("hash is 32 bytes long");
+            if !info_hashes.contains(&truncated) {
+                info_hashes.push(truncated);
+            }
+        }
+        info_hashes
+    }
+}
+
+/// A file's path relative to the torrent's directory, rejecting paths that could escape it.
+fn relative_path(components: &[String]) -> anyhow::Result<PathBuf> {
+    let path: PathBuf = components.iter().collect();
+    let is_plain = |c: Component| matches!(c, Component::Normal(_));
+    if components.is_empty()
+        || components
+            .iter()
+            .any(|p| p.is_empty() || p.contains(['/', '\\']))
+        || !path.components().all(is_plain)
+    {
+        anyhow::bail!("invalid file path in torrent: {:?}", components);
+    }
+    Ok(path)
+}
+
+/// The `pieces` byte string, split into 20-byte SHA-1 hashes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hashes(pub Vec<[u8; 20]>);
+
+impl Hashes {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+mod hashes {
+    use super::Hashes;
+    use serde::de::{self, Deserialize, Deserializer, Visitor};
+    use serde::ser::{Serialize, Serializer};
+    use std::fmt;
+
+    struct HashesVisitor;
+
+    impl<'de> Visitor<'de> for HashesVisitor {
+        type Value = Hashes;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a byte string whose length is a multiple of 20")
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            if !v.len().is_multiple_of(20) {
+                return Err(E::custom(format!(
+                    "pieces length {} is not a multiple of 20",
+                    v.len()
+                )));
+            }
+            Ok(Hashes(
+                v.chunks_exact(20)
+                    .map(|chunk| chunk.try_into().expect("chunk is 20 bytes long"))
+                    .collect(),
+            ))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Hashes {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_bytes(HashesVisitor)
+        }
+    }
+
+    impl Serialize for Hashes {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serializer.serialize_bytes(&self.0.concat())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{multi_file_torrent_for, torrent_for};
+    use crate::tracker::TrackerList;
+
+    const SINGLE_FILE: &[u8] = b"d8:announce30:http://127.0.0.1:6969/announce\
+4:infod6:lengthi40000e4:name8:test.bin12:piece lengthi32768e\
+6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee";
+
+    #[test]
+    fn parse_single_file() {
+        let torrent = Torrent::from_bytes(SINGLE_FILE).unwrap();
+        assert_eq!(torrent.announce, "http://127.0.0.1:6969/announce");
+        assert_eq!(torrent.info.name, "test.bin");
+        assert_eq!(torrent.info.piece_length, 32768);
+        assert_eq!(torrent.total_length(), 40000);
+        assert_eq!(torrent.info.pieces.0, vec![[b'a'; 20], [b'b'; 20]]);
+    }
+
+    #[test]
+    fn parse_announce_list() {
+        let torrent = Torrent::from_bytes(
+            b"d8:announce1:a13:announce-listll1:a1:bel1:cee4:infod6:lengthi1e4:name1:a\
+12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        )
+        .unwrap();
+        assert_eq!(
+            torrent.announce_list,
+            vec![vec!["a".to_owned(), "b".to_owned()], vec!["c".to_owned()]]
+        );
+    }
+
+    #[test]
+    fn parse_trackerless() {
+        let torrent = Torrent::from_bytes(
+            b"d4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        )
+        .unwrap();
+        assert!(torrent.announce.is_empty());
+        assert!(TrackerList::from_torrent(&torrent).tiers.is_empty());
+    }
+
+    #[test]
+    fn parse_web_seeds() {
+        let torrent = Torrent::from_bytes(
+            b"d9:httpseedsl6:http:xe4:infod6:lengthi1e4:name1:a12:piece lengthi1e\
+6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-list6:http:ye",
+        )
+        .unwrap();
+        assert_eq!(torrent.url_list, vec!["http:y".to_owned()]);
+        assert_eq!(torrent.httpseeds, vec!["http:x".to_owned()]);
+
+        let torrent = Torrent::from_bytes(
+            b"d4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae\
+8:url-listl6:http:y0:6:http:zee",
+        )
+        .unwrap();
+        assert_eq!(
+            torrent.url_list,
+            vec!["http:y".to_owned(), "http:z".to_owned()]
+        );
+    }
+
+    #[test]
+    fn info_hash_single_file() {
+        let torrent = Torrent::from_bytes(SINGLE_FILE).unwrap();
+        assert_eq!(
+            hex::encode(torrent.info_hash()),
+            "26ed8d6622271ed9eebd66e5757f9adf897ad66a"
+        );
+    }
+
+    #[test]
+    fn info_hash_covers_unknown_keys() {
+        let bytes = b"d8:announce1:a4:infod6:lengthi1e4:name1:a12:piece lengthi1e\
+6:pieces20:xxxxxxxxxxxxxxxxxxxx6:source3:abcee";
+        let torrent = Torrent::from_bytes(bytes).unwrap();
+        let info = dict_value_span(bytes, b"info").unwrap().unwrap();
+        assert_eq!(
+            torrent.info_hash(),
+            <[u8; 20]>::from(Sha1::digest(&bytes[info]))
+        );
+        // written back as it was read, `source` and all
+        assert_eq!(torrent.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn keeps_custom_info_fields() {
+        let bytes = b"d8:announce1:a4:infod6:lengthi1e4:name1:a12:piece lengthi1e\
+6:pieces20:xxxxxxxxxxxxxxxxxxxx5:x-food3:bari1eeee";
+        let mut torrent = Torrent::from_bytes(bytes).unwrap();
+        let x_foo = Bencode::Dict([(b"bar".to_vec(), Bencode::Int(1))].into());
+        assert_eq!(torrent.info_field("x-foo"), Some(x_foo.clone()));
+        assert_eq!(torrent.info_field("x-bar"), None);
+        assert_eq!(
+            torrent.info_field("name"),
+            Some(Bencode::Bytes(b"a".to_vec()))
+        );
+
+        torrent.announce = "b".to_owned();
+        let written = Torrent::from_bytes(&torrent.to_bytes()).unwrap();
+        assert_eq!(written.info_field("x-foo"), Some(x_foo));
+        assert_eq!(written.info_hash(), torrent.info_hash());
+    }
+
+    #[test]
+    fn transcodes_names_of_other_encodings() {
+        // "中文" and "文件" in GBK
+        let bytes = b"d8:announce1:a7:comment2:\xd6\xd08:encoding3:GBK4:infod5:filesl\
+d6:lengthi1e4:pathl4:\xce\xc4\xbc\xfeee\
+e4:name4:\xd6\xd0\xce\xc412:piece lengthi1e6:pieces20:xxxxxxxxxxxxxxxxxxxxee";
+        let torrent = Torrent::from_bytes(bytes).unwrap();
+        assert_eq!(torrent.info.name, "中文");
+        assert_eq!(torrent.comment.as_deref(), Some("中"));
+        assert_eq!(torrent.files().unwrap()[0].path, PathBuf::from("文件"));
+        assert_eq!(torrent.encoding.as_deref(), Some("GBK"));
+        // the info hash is of the info dictionary as it is, and it is written back as it was
+        let info = dict_value_span(bytes, b"info").unwrap().unwrap();
+        assert_eq!(
+            torrent.info_hash(),
+            <[u8; 20]>::from(Sha1::digest(&bytes[info]))
+        );
+        assert_eq!(torrent.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn prefers_the_utf8_variants_of_names() {
+        let bytes = b"d4:infod6:lengthi1e4:name2:\xd6\xd010:name.utf-85:\xe4\xb8\xad.a\
+12:piece lengthi1e6:pieces20:xxxxxxxxxxxxxxxxxxxxee";
+        assert_eq!(Torrent::from_bytes(bytes).unwrap().info.name, "中.a");
+        // without either, what isn't UTF-8 is replaced rather than failing the torrent
+        let bytes = b"d4:infod6:lengthi1e4:name3:a\xffb12:piece lengthi1e\
+6:pieces20:xxxxxxxxxxxxxxxxxxxxee";
+        assert_eq!(Torrent::from_bytes(bytes).unwrap().info.name, "a\u{fffd}b");
+    }
+
+    #[test]
+    fn only_transcodes_torrents_that_need_it() {
+        assert!(!needs_utf8(SINGLE_FILE) && !needs_utf8(MULTI_FILE));
+        assert!(to_utf8(SINGLE_FILE).is_none());
+        for bytes in [
+            &b"d8:encoding3:GBK4:infod4:name1:aee"[..],
+            b"d4:infod4:name1:a10:name.utf-81:bee",
+            b"d7:comment1:\xff4:infod4:name1:aee",
+            b"d4:infod5:filesld4:pathl1:\xffeee4:name1:aee",
+        ] {
+            assert!(needs_utf8(bytes), "{:?}", String::from_utf8_lossy(bytes));
+        }
+        assert!(!needs_utf8(b"d8:encoding5:UTF-84:infod4:name1:aee"));
+    }
+
+    const MULTI_FILE: &[u8] = b"d8:announce30:http://127.0.0.1:6969/announce\
+4:infod5:filesld6:lengthi3e4:pathl1:aeed6:lengthi4e4:pathl3:dir1:beee\
+4:name4:root12:piece lengthi16384e6:pieces20:xxxxxxxxxxxxxxxxxxxxee";
+
+    #[test]
+    fn parse_multi_file() {
+        let torrent = Torrent::from_bytes(MULTI_FILE).unwrap();
+        assert_eq!(torrent.info.name, "root");
+        assert_eq!(torrent.total_length(), 7);
+        assert_eq!(
+            torrent.files().unwrap(),
+            vec![
+                FileSpan {
+                    path: PathBuf::from("a"),
+                    offset: 0,
+                    length: 3
+                },
+                FileSpan {
+                    path: PathBuf::from("dir/b"),
+                    offset: 3,
+                    length: 4
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn info_hash_multi_file() {
+        let torrent = Torrent::from_bytes(MULTI_FILE).unwrap();
+        assert_eq!(
+            hex::encode(torrent.info_hash()),
+            "fdff33ff3f9e3ecd239576ea3e1a83a9be08dc39"
+        );
+    }
+
+    #[test]
+    fn magnet_link() {
+        let mut torrent = Torrent::from_bytes(MULTI_FILE).unwrap();
+        torrent.announce_list = vec![
+            vec![torrent.announce.clone(), "http://backup".to_owned()],
+            vec!["udp://other:80".to_owned()],
+        ];
+        let magnet = torrent.to_magnet();
+        assert_eq!(magnet.info_hash, torrent.info_hash());
+        assert_eq!(magnet.name.as_deref(), Some("root"));
+        assert_eq!(
+            magnet.trackers,
+            [
+                "http://127.0.0.1:6969/announce",
+                "http://backup",
+                "udp://other:80"
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn files_rejects_parent_directory() {
+        let mut torrent = Torrent::from_bytes(MULTI_FILE).unwrap();
+        if let Keys::MultiFile { files } = &mut torrent.info.keys {
+            files[1].path = vec!["..".to_owned(), "escape".to_owned()];
+        }
+        torrent.files().unwrap();
+    }
+
+    #[test]
+    fn merges_trackers() {
+        let mut torrent = torrent_for(&[0; 100], 100);
+        let (info_hash, url) = (torrent.info_hash(), |name: &str| {
+            format!("http://{}/", name)
+        });
+        torrent.announce = url("a");
+        let mut other = torrent.clone();
+        other.announce_list = vec![vec![url("b"), url("a")], vec![url("c")]];
+        assert_eq!(torrent.merge_trackers(&other), vec![url("b"), url("c")]);
+        assert_eq!(torrent.announce, url("a"));
+        assert_eq!(
+            torrent.announce_list,
+            vec![vec![url("a")], vec![url("b")], vec![url("c")]]
+        );
+        assert!(torrent.merge_trackers(&other).is_empty());
+        assert_eq!(torrent.info_hash(), info_hash);
+
+        // a torrent without trackers takes over the first as its `announce`
+        let mut trackerless = other.clone();
+        (trackerless.announce, trackerless.announce_list) = (String::new(), Vec::new());
+        assert_eq!(trackerless.merge_trackers(&other).len(), 3);
+        assert_eq!(trackerless.announce, url("b"));
+        assert_eq!(trackerless.announce_list, other.announce_list);
+    }
+
+    #[test]
+    fn renames_files_and_directories() {
+        let data = vec![0; 300];
+        let mut torrent =
+            multi_file_torrent_for(&data, 100, &[("dir/a", 100), ("dir/b", 100), ("c", 100)]);
+        let (info_hash, bytes) = (torrent.info_hash(), torrent.to_bytes());
+        torrent
+            .rename(Path::new("dir"), Path::new("new/dir"))
+            .unwrap();
+        torrent.rename(Path::new("c"), Path::new("d")).unwrap();
+        let paths: Vec<PathBuf> = torrent
+            .files()
+            .unwrap()
+            .into_iter()
+            .map(|span| span.path)
+            .collect();
+        assert_eq!(paths, ["new/dir/a", "new/dir/b", "d"].map(PathBuf::from));
+        assert_eq!(torrent.info_hash(), info_hash);
+        assert_eq!(torrent.to_bytes(), bytes);
+
+        for (from, to) in [
+            ("d", "new/dir/a"),
+            ("d", "new"),
+            ("new/dir/a", "d/a"),
+            ("dir", "x"),
+            ("d", "../d"),
+            ("", "x"),
+        ] {
+            let renamed = torrent.renamed.clone();
+            let result = torrent.rename(Path::new(from), Path::new(to));
+            assert!(result.is_err(), "{} to {}", from, to);
+            assert_eq!(torrent.renamed, renamed);
+        }
+        let mut single = torrent_for(&data, 100);
+        assert!(single
+            .rename(Path::new("test.bin"), Path::new("x"))
+            .is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn parse_pieces_invalid_length() {
+        Torrent::from_bytes(
+            b"d8:announce1:a4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces3:abcee",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn rejects_names_that_leave_the_download_directory() {
+        let parse = |name: &str| {
+            let bytes = format!(
+                "d8:announce1:a4:infod6:lengthi1e4:name{}:{}12:piece lengthi16e6:pieces20:{}ee",
+                name.len(),
+                name,
+                "a".repeat(20)
+            );
+            Torrent::from_bytes(bytes.as_bytes()).map_err(|e| e.to_string())
+        };
+        assert!(parse("file.bin").is_ok());
+        for name in ["/etc/passwd", "../x", "a/b", "..", ""] {
+            let error = parse(name).unwrap_err();
+            assert!(error.starts_with("invalid name in torrent"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn rejects_pieces_that_dont_fit() {
+        let parse = |info: &str| {
+            let bytes = format!("d8:announce1:a4:infod{}4:name1:aee", info);
+            Torrent::from_bytes(bytes.as_bytes()).map_err(|e| e.to_string())
+        };
+        let hash = "6:pieces20:aaaaaaaaaaaaaaaaaaaa";
+        assert!(parse(&format!("6:lengthi10e12:piece lengthi16e{}", hash)).is_ok());
+        assert_eq!(
+            parse(&format!("6:lengthi10e12:piece lengthi0e{}", hash)).unwrap_err(),
+            "piece length is 0"
+        );
+        assert_eq!(
+            parse(&format!("6:lengthi40e12:piece lengthi16e{}", hash)).unwrap_err(),
+            "torrent has 1 piece hashes for 40 bytes in pieces of 16"
+        );
+        // files whose offsets would overflow
+        let huge = "d6:lengthi9223372036854775807e4:pathl1:bee";
+        let files = format!("5:filesl{}{}{}e", huge, huge, huge);
+        assert_eq!(
+            parse(&format!("{}12:piece lengthi16e{}", files, hash)).unwrap_err(),
+            "the files add up to more bytes than can be addressed"
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn parse_missing_info() {
+        Torrent::from_bytes(b"d8:announce1:ae").unwrap();
+    }
+
+    const PIECE_LENGTH: usize = 16384;
+
+    /// A v2 torrent of `files`, with a v1 part with padding files if `hybrid`.
+    fn v2_torrent(files: &[(&str, &[u8])], hybrid: bool) -> Torrent {
+        let mut tree = BTreeMap::new();
+        let mut piece_layers = BTreeMap::new();
+        let mut v1_files = Vec::new();
+        let mut v1_data = Vec::new();
+        for &(name, data) in files {
+            let root = ByteBuf::from(merkle::file_root(data, PIECE_LENGTH).to_vec());
+            if data.len() > PIECE_LENGTH {
+                let layer: Vec<u8> = data
+                    .chunks(PIECE_LENGTH)
+                    .flat_map(|piece| merkle::piece_hash(piece, PIECE_LENGTH))
+                    .collect();
+                piece_layers.insert(root.clone(), ByteBuf::from(layer));
+            }
+            let file = TreeFile {
+                length: data.len(),
+                pieces_root: Some(root),
+            };
+            tree.insert(name.to_owned(), FileTreeEntry::File { file });
+            v1_files.push(File {
+                length: data.len(),
+                path: vec![name.to_owned()],
+                attr: String::new(),
+            });
+            v1_data.extend_from_slice(data);
+            let padding = v1_data.len().next_multiple_of(PIECE_LENGTH) - v1_data.len();
+            if padding > 0 && name != files.last().unwrap().0 {
+                v1_files.push(File {
+                    length: padding,
+                    path: vec![".pad".to_owned(), padding.to_string()],
+                    attr: "p".to_owned(),
+                });
+                v1_data.resize(v1_data.len() + padding, 0);
+            }
+        }
+        Torrent {
+            announce: String::new(),
+            announce_list: Vec::new(),
+            url_list: Vec::new(),
+            httpseeds: Vec::new(),
+            piece_layers,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            encoding: None,
+            info: Info {
+                name: "v2".to_owned(),
+                piece_length: PIECE_LENGTH,
+                pieces: match hybrid {
+                    true => Hashes(
+                        v1_data
+                            .chunks(PIECE_LENGTH)
+                            .map(|piece| Sha1::digest(piece).into())
+                            .collect(),
+                    ),
+                    false => Hashes::default(),
+                },
+                keys: match hybrid {
+                    true => Keys::MultiFile { files: v1_files },
+                    false => Keys::FileTree {},
+                },
+                meta_version: Some(2),
+                private: None,
+                file_tree: Some(FileTree(tree)),
+            },
+            raw_info: None,
+            renamed: BTreeMap::new(),
+        }
+    }
+
+    fn reparse(torrent: &Torrent) -> crate::Result<Torrent> {
+        Torrent::from_bytes(&torrent.to_bytes())
+    }
+
+    #[test]
+    fn parse_v2_torrent() {
+        let a: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        let b = vec![9; 100];
+        let torrent = reparse(&v2_torrent(&[("a", &a), ("b", &b)], false)).unwrap();
+        assert!(torrent.is_v2() && !torrent.is_v1());
+        assert_eq!(torrent.total_length(), 40100);
+        assert_eq!(torrent.piece_count(), 4);
+        assert_eq!(torrent.piece_size(2), 40000 - 2 * PIECE_LENGTH);
+        assert_eq!(torrent.piece_size(3), 100);
+        let spans = torrent.files().unwrap();
+        assert_eq!(spans[1].offset, 3 * PIECE_LENGTH);
+
+        let pieces: Vec<&[u8]> = a.chunks(PIECE_LENGTH).chain([&b[..]]).collect();
+        for (index, piece) in pieces.iter().enumerate() {
+            assert!(torrent.verify_piece(index, piece), "piece {}", index);
+        }
+        assert!(!torrent.verify_piece(0, pieces[1]));
+        assert!(!torrent.verify_piece(3, &[9; 99]));
+
+        let info_hash_v2 = torrent.info_hash_v2().unwrap();
+        assert_eq!(torrent.info_hash(), info_hash_v2[..20]);
+        assert_eq!(torrent.info_hashes(), vec![torrent.info_hash()]);
+    }
+
+    #[test]
+    fn v2_single_file_is_stored_like_v1() {
+        let torrent = reparse(&v2_torrent(&[("only", &[1; 10])], false)).unwrap();
+        assert_eq!(torrent.files().unwrap()[0].path, PathBuf::new());
+        assert!(torrent.verify_piece(0, &[1; 10]));
+    }
+
+    #[test]
+    fn parse_hybrid_torrent() {
+        let a = vec![1; 20000];
+        let b = vec![2; 5000];
+        let torrent = reparse(&v2_torrent(&[("a", &a), ("b", &b)], true)).unwrap();
+        assert!(torrent.is_v1() && torrent.is_v2());
+        // the padding file isn't stored, but its zeros are part of the v1 pieces
+        assert_eq!(torrent.files().unwrap().len(), 2);
+        assert_eq!(torrent.piece_count(), 3);
+        let second: Vec<u8> = a[PIECE_LENGTH..]
+            .iter()
+            .copied()
+            .chain(vec![0; 12768])
+            .collect();
+        assert!(torrent.verify_piece(1, &second));
+
+        let info_hashes = torrent.info_hashes();
+        assert_eq!(info_hashes.len(), 2);
+        assert_eq!(info_hashes[1], torrent.info_hash_v2().unwrap()[..20]);
+    }
+
+    #[test]
+    fn rejects_inconsistent_v2_torrents() {
+        let a = vec![1; 40000];
+        let mut torrent = v2_torrent(&[("a", &a)], false);
+        for layer in torrent.piece_layers.values_mut() {
+            layer[0] ^= 1;
+        }
+        assert!(reparse(&torrent).is_err());
+
+        let mut torrent = v2_torrent(&[("a", &a), ("b", &[2; 10])], true);
+        if let Keys::MultiFile { files } = &mut torrent.info.keys {
+            files.retain(|file| !file.is_padding());
+        }
+        assert!(reparse(&torrent).is_err());
+
+        let mut torrent = v2_torrent(&[("a", &a)], false);
+        torrent.info.meta_version = Some(3);
+        assert!(reparse(&torrent).is_err());
+    }
+}
@@ -0,0 +1,408 @@
+use crate::net::Bind;
+use crate::tracker::{Event, Peers, ScrapeStats, TrackerRequest, TrackerResponse};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// Magic constant identifying the UDP tracker protocol in connect requests.
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+/// Trackers accept a connection id for up to a minute, so reconnect well before that.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(50);
+
+/// How long to wait for a tracker to answer before sending a request again.
+#[derive(Debug, Clone, Copy)]
+pub struct Retries {
+    /// Timeout of the first attempt; it doubles with every retry (`15 * 2 ^ n` in BEP 15).
+    pub initial_timeout: Duration,
+    pub attempts: u32,
+}
+
+impl Default for Retries {
+    fn default() -> Retries {
+        Retries {
+            initial_timeout: Duration::from_secs(15),
+            attempts: 4,
+        }
+    }
+}
+
+/// Announces to a `udp://host:port` tracker, from `bind` if given.
+pub async fn announce(
+    url: &str,
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+    retries: Retries,
+    bind: Option<&Bind>,
+) -> crate::Result<TrackerResponse> {
+    request_announce(url, info_hash, request, retries, bind)
+        .await
+        .map_err(crate::Error::Tracker)
+}
+
+async fn request_announce(
+    url: &str,
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+    retries: Retries,
+    bind: Option<&Bind>,
+) -> anyhow::Result<TrackerResponse> {
+    let mut body = Vec::with_capacity(82);
+    body.extend_from_slice(info_hash);
+    body.extend_from_slice(&request.peer_id);
+    body.extend_from_slice(&(request.downloaded as u64).to_be_bytes());
+    body.extend_from_slice(&(request.left as u64).to_be_bytes());
+    body.extend_from_slice(&(request.uploaded as u64).to_be_bytes());
+    let event: u32 = match request.event {
+        None => 0,
+        Some(Event::Completed) => 1,
+        Some(Event::Started) => 2,
+        Some(Event::Stopped) => 3,
+    };
+    body.extend_from_slice(&event.to_be_bytes());
+    // ip address: 0 lets the tracker use the sender's, and there is only room for IPv4
+    let ip = match request.params.ip {
+        Some(IpAddr::V4(ip)) => ip.octets(),
+        _ => [0; 4],
+    };
+    body.extend_from_slice(&ip);
+    let key = request.params.key.unwrap_or_else(rand::random);
+    body.extend_from_slice(&key.to_be_bytes());
+    // num_want: -1 for the tracker's default
+    let numwant = request
+        .params
+        .numwant
+        .map_or(-1, |numwant| numwant.min(i32::MAX as usize) as i32);
+    body.extend_from_slice(&numwant.to_be_bytes());
+    body.extend_from_slice(&request.port.to_be_bytes());
+
+    let (reply, tracker) = exchange(url, ACTION_ANNOUNCE, &body, retries, bind).await?;
+    if reply.len() < 12 {
+        anyhow::bail!("announce response too short: {} bytes", reply.len() + 8);
+    }
+    let count = |at: usize| u32::from_be_bytes(reply[at..at + 4].try_into().unwrap()) as usize;
+    let interval = count(0);
+    // a tracker we reach over IPv6 answers with IPv6 peers
+    let peers = if tracker.is_ipv6() {
+        Peers::from_compact6(&reply[12..])?
+    } else {
+        Peers::from_compact(&reply[12..])?
+    };
+    Ok(TrackerResponse {
+        interval,
+        min_interval: None,
+        peers,
+        warning: None,
+        tracker_id: None,
+        seeders: Some(count(8)),
+        leechers: Some(count(4)),
+        external_ip: None,
+    })
+}
+
+/// Asks a `udp://host:port` tracker how many peers the swarm of `info_hash` has.
+pub async fn scrape(
+    url: &str,
+    info_hash: &[u8; 20],
+    retries: Retries,
+) -> crate::Result<ScrapeStats> {
+    request_scrape(url, info_hash, retries)
+        .await
+        .map_err(crate::Error::Tracker)
+}
+
+async fn request_scrape(
+    url: &str,
+    info_hash: &[u8; 20],
+    retries: Retries,
+) -> anyhow::Result<ScrapeStats> {
+    let (reply, _) = exchange(url, ACTION_SCRAPE, info_hash, retries, None).await?;
+    if reply.len() < 12 {
+        anyhow::bail!("scrape response too short: {} bytes", reply.len() + 8);
+    }
+    let count = |at: usize| u32::from_be_bytes(reply[at..at + 4].try_into().unwrap()) as usize;
+    Ok(ScrapeStats {
+        seeders: count(0),
+        completed: count(4),
+        leechers: count(8),
+    })
+}
+
+/// Sends the request of `action` with `body` to the tracker at `url`, connecting first and
+/// trying again with longer timeouts while it doesn't answer. Returns the reply after the
+/// action and transaction id, and the address the tracker was reached at. The socket is bound
+/// to `bind` if given.
+async fn exchange(
+    url: &str,
+    action: u32,
+    body: &[u8],
+    retries: Retries,
+    bind: Option<&Bind>,
+) -> anyhow::Result<(Vec<u8>, SocketAddr)> {
+    let addr = resolve(url).await?;
+    let local: SocketAddr = match bind {
+        Some(bind) => SocketAddr::new(bind.address_for(addr.ip())?, 0),
+        None if addr.is_ipv4() => "0.0.0.0:0".parse()?,
+        None => "[::]:0".parse()?,
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+
+    let mut connection: Option<(u64, tokio::time::Instant)> = None;
+    let mut timeout = retries.initial_timeout;
+    for _ in 0..retries.attempts {
+        let attempt = async {
+            let connection_id = match connection {
+                Some((id, since)) if since.elapsed() < CONNECTION_ID_LIFETIME => id,
+                _ => {
+                    let id = connect(&socket, timeout).await?;
+                    connection = Some((id, tokio::time::Instant::now()));
+                    id
+                }
+            };
+            let transaction_id = rand::random();
+            let mut packet = Vec::with_capacity(16 + body.len());
+            packet.extend_from_slice(&connection_id.to_be_bytes());
+            packet.extend_from_slice(&action.to_be_bytes());
+            packet.extend_from_slice(&u32::to_be_bytes(transaction_id));
+            packet.extend_from_slice(body);
+            transact(&socket, &packet, action, transaction_id, timeout).await
+        };
+        match attempt.await {
+            Err(e) if e.is::<tokio::time::error::Elapsed>() => timeout *= 2,
+            result => return result.map(|reply| (reply, addr)),
+        }
+    }
+    anyhow::bail!(
+        "tracker {} didn't answer after {} attempts",
+        url,
+        retries.attempts
+    )
+}
+
+async fn resolve(url: &str) -> anyhow::Result<SocketAddr> {
+    let rest = match url.split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("udp") => rest,
+        _ => anyhow::bail!("not a UDP tracker URL: {}", url),
+    };
+    let host = rest.split('/').next().unwrap_or(rest);
+    tokio::net::lookup_host(host)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("tracker host {} did not resolve", host))
+}
+
+async fn connect(socket: &UdpSocket, timeout: Duration) -> anyhow::Result<u64> {
+    let transaction_id = rand::random();
+    let mut packet = Vec::with_capacity(16);
+    packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+    packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+    packet.extend_from_slice(&u32::to_be_bytes(transaction_id));
+
+    let reply = transact(socket, &packet, ACTION_CONNECT, transaction_id, timeout).await?;
+    if reply.len() < 8 {
+        anyhow::bail!("connect response too short: {} bytes", reply.len() + 8);
+    }
+    Ok(u64::from_be_bytes(reply[..8].try_into()?))
+}
+
+/// Sends `packet` and waits for the reply with a matching transaction id, returning the bytes
+/// after the action and transaction id.
+async fn transact(
+    socket: &UdpSocket,
+    packet: &[u8],
+    action: u32,
+    transaction_id: u32,
+    timeout: Duration,
+) -> anyhow::Result<Vec<u8>> {
+    socket.send(packet).await?;
+    let receive = async {
+        let mut buffer = vec![0; 2048];
+        loop {
+            let n = socket.recv(&mut buffer).await?;
+            let reply = &buffer[..n];
+            if n < 8 || reply[4..8] != transaction_id.to_be_bytes() {
+                // a late answer to an earlier attempt, or garbage
+                continue;
+            }
+            let reply_action = u32::from_be_bytes(reply[..4].try_into()?);
+            if reply_action == ACTION_ERROR {
+                anyhow::bail!(
+                    "tracker returned failure: {}",
+                    String::from_utf8_lossy(&reply[8..])
+                );
+            }
+            if reply_action != action {
+                anyhow::bail!("expected action {}, got {}", action, reply_action);
+            }
+            return Ok(reply[8..].to_vec());
+        }
+    };
+    // a timeout is returned as `Elapsed` so the caller can tell it apart from other failures
+    tokio::time::timeout(timeout, receive).await?
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn retries() -> Retries {
+        Retries {
+            initial_timeout: Duration::from_millis(100),
+            attempts: 3,
+        }
+    }
+
+    fn request() -> TrackerRequest {
+        TrackerRequest::with_left(1000, *b"00112233445566778899", 6881)
+    }
+
+    /// Answers connect and announce requests, ignoring the first `drop` packets it receives.
+    pub(crate) async fn spawn_tracker(drop: usize, error: Option<&'static str>) -> SocketAddr {
+        spawn_tracker_on("127.0.0.1:0", drop, error).await
+    }
+
+    /// Like `spawn_tracker`, listening on `addr`; over IPv6 it answers with the peer
+    /// `[::1]:6881`.
+    async fn spawn_tracker_on(addr: &str, drop: usize, error: Option<&'static str>) -> SocketAddr {
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0; 2048];
+            let mut received = 0;
+            loop {
+                let (n, from) = socket.recv_from(&mut buffer).await.unwrap();
+                received += 1;
+                if received <= drop {
+                    continue;
+                }
+                let packet = &buffer[..n];
+                let transaction_id = &packet[12..16];
+                let mut reply = Vec::new();
+                if let Some(message) = error {
+                    reply.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+                    reply.extend_from_slice(transaction_id);
+                    reply.extend_from_slice(message.as_bytes());
+                } else if packet[..8] == PROTOCOL_ID.to_be_bytes() {
+                    reply.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                    reply.extend_from_slice(transaction_id);
+                    reply.extend_from_slice(&42u64.to_be_bytes());
+                } else if packet[8..12] == ACTION_SCRAPE.to_be_bytes() {
+                    assert_eq!(n, 36);
+                    reply.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                    reply.extend_from_slice(transaction_id);
+                    for count in [5u32, 7, 3] {
+                        reply.extend_from_slice(&count.to_be_bytes());
+                    }
+                } else {
+                    assert_eq!(n, 98);
+                    assert_eq!(packet[..8], 42u64.to_be_bytes());
+                    reply.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                    reply.extend_from_slice(transaction_id);
+                    reply.extend_from_slice(&1800u32.to_be_bytes());
+                    // leechers and seeders
+                    reply.extend_from_slice(&2u32.to_be_bytes());
+                    reply.extend_from_slice(&9u32.to_be_bytes());
+                    if addr.is_ipv6() {
+                        reply.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+                    } else {
+                        reply.extend_from_slice(&[127, 0, 0, 1]);
+                    }
+                    reply.extend_from_slice(&6881u16.to_be_bytes());
+                }
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn announce_to_udp_tracker() {
+        let addr = spawn_tracker(0, None).await;
+        let url = format!("udp://{}/announce", addr);
+        let response = announce(&url, &[1; 20], &request(), retries(), None)
+            .await
+            .unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!((response.seeders, response.leechers), (Some(9), Some(2)));
+        assert_eq!(
+            response.peers.addrs,
+            vec!["127.0.0.1:6881".parse().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn announce_over_ipv6() {
+        let addr = spawn_tracker_on("[::1]:0", 0, None).await;
+        let url = format!("udp://{}/announce", addr);
+        let response = announce(&url, &[1; 20], &request(), retries(), None)
+            .await
+            .unwrap();
+        assert_eq!(response.peers.addrs, vec!["[::1]:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn announce_from_bound_address() {
+        let addr = spawn_tracker(0, None).await;
+        let url = format!("udp://{}", addr);
+        let bind = Bind::Address("127.0.0.1".parse().unwrap());
+        let response = announce(&url, &[1; 20], &request(), retries(), Some(&bind))
+            .await
+            .unwrap();
+        assert_eq!(response.peers.addrs.len(), 1);
+        // an IPv6 tracker can't be reached from an IPv4 address
+        let addr = spawn_tracker_on("[::1]:0", 0, None).await;
+        let url = format!("udp://{}", addr);
+        assert!(announce(&url, &[1; 20], &request(), retries(), Some(&bind))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn scrape_udp_tracker() {
+        let addr = spawn_tracker(1, None).await;
+        let url = format!("udp://{}/announce", addr);
+        let stats = scrape(&url, &[1; 20], retries()).await.unwrap();
+        assert_eq!(
+            stats,
+            ScrapeStats {
+                seeders: 5,
+                leechers: 3,
+                completed: 7,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn announce_retries_lost_packets() {
+        let addr = spawn_tracker(2, None).await;
+        let url = format!("udp://{}", addr);
+        let response = announce(&url, &[1; 20], &request(), retries(), None)
+            .await
+            .unwrap();
+        assert_eq!(response.peers.addrs.len(), 1);
+    }
+
+    #[tokio::test]
+    #[should_panic]
+    async fn announce_gives_up_after_retries() {
+        let addr = spawn_tracker(usize::MAX, None).await;
+        let url = format!("udp://{}", addr);
+        announce(&url, &[1; 20], &request(), retries(), None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[should_panic]
+    async fn announce_tracker_error() {
+        let addr = spawn_tracker(0, Some("unregistered torrent")).await;
+        let url = format!("udp://{}", addr);
+        announce(&url, &[1; 20], &request(), retries(), None)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,1079 @@
+use crate::net::{self, Bind};
+use bytes::{Buf, BytesMut};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+const VERSION: u8 = 1;
+const HEADER_LENGTH: usize = 20;
+/// Payload bytes per packet, so that a packet fits through any path without fragmenting.
+const MAX_PAYLOAD: usize = 1200;
+/// Queuing delay, in microseconds, LEDBAT keeps our packets at: any more means we are filling
+/// up a buffer on the path at the expense of other traffic.
+const TARGET_DELAY: f64 = 100_000.0;
+/// Most the congestion window grows by in a round trip.
+const MAX_WINDOW_INCREASE: f64 = 3000.0;
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const INITIAL_WINDOW: f64 = 10.0 * MAX_PAYLOAD as f64;
+const MAX_WINDOW: f64 = (4 << 20) as f64;
+/// The base delay is the lowest delay seen in this many of the last minutes.
+const BASE_DELAY_MINUTES: usize = 2;
+/// Received bytes buffered for the reader at most; it's the window we advertise.
+const RECEIVE_BUFFER: usize = 1 << 20;
+/// Written bytes buffered for sending at most, before writes wait.
+const SEND_BUFFER: usize = 256 * 1024;
+/// Packets received ahead of a missing one that are kept until it arrives.
+const MAX_OUT_OF_ORDER: u16 = 1024;
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+/// Times a packet is sent again before the connection counts as broken.
+const MAX_RETRANSMISSIONS: u32 = 5;
+const MAX_SYN_RETRANSMISSIONS: u32 = 1;
+/// Acknowledgements of the same packet that tell the one after it was lost.
+const DUPLICATE_ACKS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+/// A uTP packet: the 20-byte header, with any extensions skipped, and the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Packet {
+    kind: Kind,
+    connection_id: u16,
+    /// When the packet was sent, in microseconds on the sender's clock.
+    timestamp: u32,
+    /// How long the last packet the sender received took to arrive, as seen on its clock.
+    timestamp_diff: u32,
+    /// Bytes the sender is willing to receive.
+    window: u32,
+    seq: u16,
+    /// The last packet the sender received in order.
+    ack: u16,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.payload.len());
+        bytes.push((self.kind as u8) << 4 | VERSION);
+        bytes.push(0);
+        bytes.extend(self.connection_id.to_be_bytes());
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.extend(self.timestamp_diff.to_be_bytes());
+        bytes.extend(self.window.to_be_bytes());
+        bytes.extend(self.seq.to_be_bytes());
+        bytes.extend(self.ack.to_be_bytes());
+        bytes.extend(&self.payload);
+        bytes
+    }
+
+    fn parse(bytes: &[u8]) -> anyhow::Result<Packet> {
+        if bytes.len() < HEADER_LENGTH {
+            anyhow::bail!("uTP packet of {} bytes is too short", bytes.len());
+        }
+        if bytes[0] & 0x0f != VERSION {
+            anyhow::bail!("unsupported uTP version {}", bytes[0] & 0x0f);
+        }
+        let kind = match bytes[0] >> 4 {
+            0 => Kind::Data,
+            1 => Kind::Fin,
+            2 => Kind::State,
+            3 => Kind::Reset,
+            4 => Kind::Syn,
+            kind => anyhow::bail!("unknown uTP packet type {}", kind),
+        };
+        // extensions, e.g. selective acks, form a linked list after the header
+        let mut extension = bytes[1];
+        let mut offset = HEADER_LENGTH;
+        while extension != 0 {
+            let Some(&[next, length]) = bytes.get(offset..offset + 2) else {
+                anyhow::bail!("truncated uTP extension");
+            };
+            extension = next;
+            offset += 2 + length as usize;
+        }
+        let payload = bytes
+            .get(offset..)
+            .ok_or_else(|| anyhow::anyhow!("truncated uTP extension"))?;
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+        Ok(Packet {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            window: u32_at(12),
+            seq: u16_at(16),
+            ack: u16_at(18),
+            payload: payload.to_vec(),
+        })
+    }
+}
+
+/// Whether sequence number `a` comes no later than `b`, allowing for wrapping around.
+fn seq_le(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a) < 0x8000
+}
+
+fn now_micros() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_micros() as u32
+}
+
+/// LEDBAT congestion control (RFC 6817): the window grows while the queuing delay our packets
+/// see stays below `TARGET_DELAY` and shrinks once it's above, so that uTP backs off before
+/// other traffic on the same link suffers.
+#[derive(Debug)]
+struct Ledbat {
+    window: f64,
+    /// The lowest delay of each of the last minutes, oldest first.
+    delays: VecDeque<(Instant, u32)>,
+}
+
+impl Ledbat {
+    fn new() -> Ledbat {
+        Ledbat {
+            window: INITIAL_WINDOW,
+            delays: VecDeque::new(),
+        }
+    }
+
+    fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    /// `acked` bytes were acknowledged by a packet saying ours take `delay` microseconds to
+    /// arrive. Only differences between delays matter, so the clocks needn't agree.
+    fn on_ack(&mut self, acked: usize, delay: u32, now: Instant) {
+        match self.delays.back_mut() {
+            Some((start, lowest)) if now - *start < Duration::from_secs(60) => {
+                *lowest = (*lowest).min(delay)
+            }
+            _ => self.delays.push_back((now, delay)),
+        }
+        if self.delays.len() > BASE_DELAY_MINUTES {
+            self.delays.pop_front();
+        }
+        let base_delay = self.delays.iter().map(|&(_, delay)| delay).min();
+        let queuing_delay = delay.wrapping_sub(base_delay.unwrap_or(delay)) as f64;
+        let off_target = (TARGET_DELAY - queuing_delay) / TARGET_DELAY;
+        self.window += MAX_WINDOW_INCREASE * off_target * acked as f64 / self.window;
+        self.window = self.window.clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    fn on_loss(&mut self) {
+        self.window = (self.window / 2.0).max(MIN_WINDOW);
+    }
+
+    fn on_timeout(&mut self) {
+        self.window = MIN_WINDOW;
+    }
+}
+
+/// The retransmission timeout, from the round trips measured so far (RFC 6298).
+#[derive(Debug)]
+struct RoundTrip {
+    smoothed: Option<Duration>,
+    variance: Duration,
+    timeout: Duration,
+}
+
+impl RoundTrip {
+    fn new() -> RoundTrip {
+        RoundTrip {
+            smoothed: None,
+            variance: Duration::ZERO,
+            timeout: INITIAL_TIMEOUT,
+        }
+    }
+
+    fn sample(&mut self, rtt: Duration) {
+        let smoothed = match self.smoothed {
+            None => {
+                self.variance = rtt / 2;
+                rtt
+            }
+            Some(smoothed) => {
+                self.variance = (self.variance * 3 + smoothed.abs_diff(rtt)) / 4;
+                (smoothed * 7 + rtt) / 8
+            }
+        };
+        self.smoothed = Some(smoothed);
+        self.timeout = (smoothed + self.variance * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    fn back_off(&mut self) {
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+    }
+}
+
+/// What a `UtpStream` and the task driving its connection share.
+#[derive(Default)]
+struct StreamState {
+    /// Written bytes not yet put in a packet.
+    unsent: BytesMut,
+    writer: Option<Waker>,
+    /// Bytes received in order that the reader hasn't taken yet.
+    received: BytesMut,
+    reader: Option<Waker>,
+    /// The peer finished sending and all its data is in `received`.
+    eof: bool,
+    /// The window we advertised was too small for the peer to send more.
+    window_closed: bool,
+    shutdown: bool,
+    dropped: bool,
+    error: Option<io::ErrorKind>,
+}
+
+impl StreamState {
+    fn wake_writer(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            writer.wake();
+        }
+    }
+
+    fn wake_reader(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            reader.wake();
+        }
+    }
+}
+
+/// A reliable, ordered byte stream to a peer over a `UtpSocket`.
+pub struct UtpStream {
+    state: Arc<Mutex<StreamState>>,
+    notify: Arc<Notify>,
+    peer: SocketAddr,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        // the connection still sends what was written before closing
+        self.state.lock().unwrap().dropped = true;
+        self.notify.notify_one();
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.received.is_empty() {
+            let n = state.received.len().min(buf.remaining());
+            buf.put_slice(&state.received[..n]);
+            state.received.advance(n);
+            if state.window_closed {
+                self.notify.notify_one();
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(error) = state.error {
+            return Poll::Ready(Err(error.into()));
+        }
+        if state.eof {
+            return Poll::Ready(Ok(()));
+        }
+        state.reader = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(error) = state.error {
+            return Poll::Ready(Err(error.into()));
+        }
+        if state.shutdown {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let n = buf.len().min(SEND_BUFFER - state.unsent.len());
+        if n == 0 {
+            state.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        state.unsent.extend_from_slice(&buf[..n]);
+        self.notify.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // buffered data goes out as fast as the congestion window allows anyway
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.state.lock().unwrap().shutdown = true;
+        self.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+type Connections = Mutex<HashMap<(SocketAddr, u16), mpsc::UnboundedSender<Packet>>>;
+
+struct Shared {
+    socket: UdpSocket,
+    /// What the socket is bound to, which says whether peers' IPv4 addresses need mapping.
+    local: SocketAddr,
+    /// Where to send the packets of each connection, by peer and the id it sends them with.
+    connections: Connections,
+    /// Connections peers opened to us that haven't been accepted yet.
+    incoming: mpsc::Sender<UtpStream>,
+}
+
+impl Shared {
+    async fn send_to(&self, packet: &Packet, peer: SocketAddr) -> io::Result<usize> {
+        let addr = net::for_socket(peer, self.local);
+        self.socket.send_to(&packet.to_bytes(), addr).await
+    }
+
+    fn is_open(&self, peer: SocketAddr, recv_id: u16) -> bool {
+        let connections = self.connections.lock().unwrap();
+        connections.contains_key(&(peer, recv_id))
+    }
+
+    /// Registers a connection receiving packets from `peer` with `recv_id`, or returns None if
+    /// there is one already.
+    fn open(
+        self: &Arc<Self>,
+        peer: SocketAddr,
+        recv_id: u16,
+        send_id: u16,
+        phase: Phase,
+    ) -> Option<(UtpStream, Connection, mpsc::UnboundedReceiver<Packet>)> {
+        let (tx, packets) = mpsc::unbounded_channel();
+        {
+            let mut connections = self.connections.lock().unwrap();
+            if connections.contains_key(&(peer, recv_id)) {
+                return None;
+            }
+            connections.insert((peer, recv_id), tx);
+        }
+        let state = Arc::new(Mutex::new(StreamState::default()));
+        let notify = Arc::new(Notify::new());
+        let stream = UtpStream {
+            state: state.clone(),
+            notify: notify.clone(),
+            peer,
+        };
+        let connection = Connection {
+            shared: self.clone(),
+            peer,
+            recv_id,
+            send_id,
+            state,
+            notify,
+            phase,
+            seq: rand::random(),
+            ack: 0,
+            in_flight: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            their_fin: None,
+            fin_sent: false,
+            ledbat: Ledbat::new(),
+            round_trip: RoundTrip::new(),
+            their_window: MAX_PAYLOAD,
+            last_ack: None,
+            duplicate_acks: 0,
+            reply_delay: 0,
+        };
+        Some((stream, connection, packets))
+    }
+}
+
+/// A UDP socket carrying any number of uTP connections, both ones we open and ones peers
+/// open to us.
+pub struct UtpSocket {
+    shared: Arc<Shared>,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<UtpStream>>,
+    receiver: JoinHandle<()>,
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: impl ToSocketAddrs) -> anyhow::Result<UtpSocket> {
+        Ok(UtpSocket::from_socket(UdpSocket::bind(addr).await?)?)
+    }
+
+    /// Binds to `port` of every IPv6 and IPv4 address, as `net::bind_udp` does, or of the
+    /// address of `bind` if given.
+    pub fn listen(port: u16, bind: Option<&Bind>) -> anyhow::Result<UtpSocket> {
+        Ok(UtpSocket::from_socket(net::bind_udp_on(port, bind)?)?)
+    }
+
+    /// Carries uTP over `socket`, already bound.
+    pub fn from_socket(socket: UdpSocket) -> io::Result<UtpSocket> {
+        let (incoming_tx, incoming) = mpsc::channel(16);
+        let shared = Arc::new(Shared {
+            local: socket.local_addr()?,
+            socket,
+            connections: Mutex::new(HashMap::new()),
+            incoming: incoming_tx,
+        });
+        let receiver = tokio::spawn(receive(shared.clone()));
+        Ok(UtpSocket {
+            shared,
+            incoming: tokio::sync::Mutex::new(incoming),
+            receiver,
+        })
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.shared.socket.local_addr()?)
+    }
+
+    /// Opens a connection to `addr`, which fails if the peer doesn't answer after a few tries.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let (connected_tx, connected) = oneshot::channel();
+        let mut connected_tx = Some(connected_tx);
+        let (stream, mut connection, packets) = loop {
+            // ids are picked at random, until one isn't taken for this peer yet
+            let recv_id = rand::random::<u16>();
+            if self.shared.is_open(addr, recv_id) {
+                continue;
+            }
+            let phase = Phase::SynSent(connected_tx.take());
+            break self
+                .shared
+                .open(addr, recv_id, recv_id.wrapping_add(1), phase)
+                .ok_or(io::ErrorKind::AddrInUse)?;
+        };
+        connection.transmit(Kind::Syn, Vec::new()).await;
+        tokio::spawn(connection.run(packets));
+        match connected.await {
+            Ok(Ok(())) => Ok(stream),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::ErrorKind::ConnectionAborted.into()),
+        }
+    }
+
+    /// Waits for a peer to open a connection to us.
+    pub async fn accept(&self) -> io::Result<(UtpStream, SocketAddr)> {
+        match self.incoming.lock().await.recv().await {
+            Some(stream) => {
+                let peer = stream.peer;
+                Ok((stream, peer))
+            }
+            None => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+/// Hands every packet to its connection, and opens connections for the SYNs of new ones.
+async fn receive(shared: Arc<Shared>) {
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let Ok((n, from)) = shared.socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        // connections are known by the addresses they are opened to
+        let from = net::canonical(from);
+        let Ok(packet) = Packet::parse(&buffer[..n]) else {
+            continue;
+        };
+        // the connection a SYN opens receives with the id after the one in the SYN
+        let recv_id = match packet.kind {
+            Kind::Syn => packet.connection_id.wrapping_add(1),
+            _ => packet.connection_id,
+        };
+        let connection = shared
+            .connections
+            .lock()
+            .unwrap()
+            .get(&(from, recv_id))
+            .cloned();
+        if let Some(connection) = connection {
+            let _ = connection.send(packet);
+            continue;
+        }
+        match packet.kind {
+            Kind::Syn => {
+                let Ok(permit) = shared.incoming.try_reserve() else {
+                    continue;
+                };
+                let send_id = packet.connection_id;
+                let Some((stream, mut connection, packets)) =
+                    shared.open(from, recv_id, send_id, Phase::Connected)
+                else {
+                    continue;
+                };
+                // SYNs take a sequence number, so the peer's first data comes after it
+                connection.ack = packet.seq;
+                connection.reply_delay = now_micros().wrapping_sub(packet.timestamp);
+                connection.send_state().await;
+                tokio::spawn(connection.run(packets));
+                permit.send(stream);
+            }
+            Kind::Reset => {}
+            _ => {
+                let reset = Packet {
+                    kind: Kind::Reset,
+                    connection_id: packet.connection_id,
+                    timestamp: now_micros(),
+                    timestamp_diff: 0,
+                    window: 0,
+                    seq: rand::random(),
+                    ack: packet.seq,
+                    payload: Vec::new(),
+                };
+                let _ = shared.send_to(&reset, from).await;
+            }
+        }
+    }
+}
+
+enum Phase {
+    /// Waiting for the peer to answer our SYN; told the outcome once it does or gives up.
+    SynSent(Option<oneshot::Sender<io::Result<()>>>),
+    Connected,
+}
+
+/// A packet waiting to be acknowledged.
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+/// The sending and receiving end of one connection, run by a task of its own.
+struct Connection {
+    shared: Arc<Shared>,
+    peer: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    state: Arc<Mutex<StreamState>>,
+    notify: Arc<Notify>,
+    phase: Phase,
+    /// Sequence number of the next packet we send.
+    seq: u16,
+    /// The last packet we received in order.
+    ack: u16,
+    in_flight: VecDeque<Sent>,
+    out_of_order: HashMap<u16, Vec<u8>>,
+    their_fin: Option<u16>,
+    fin_sent: bool,
+    ledbat: Ledbat,
+    round_trip: RoundTrip,
+    their_window: usize,
+    last_ack: Option<u16>,
+    duplicate_acks: u32,
+    /// The `timestamp_diff` of the packets we send: the delay of the last one we received.
+    reply_delay: u32,
+}
+
+impl Connection {
+    async fn run(mut self, mut packets: mpsc::UnboundedReceiver<Packet>) {
+        loop {
+            self.send_queued().await;
+            if self.is_finished() {
+                break;
+            }
+            let deadline = self
+                .in_flight
+                .front()
+                .map(|sent| sent.sent_at + self.round_trip.timeout);
+            let notify = self.notify.clone();
+            tokio::select! {
+                packet = packets.recv() => match packet {
+                    Some(packet) => self.handle(packet).await,
+                    None => self.fail(io::ErrorKind::ConnectionAborted),
+                },
+                () = notify.notified() => self.update_window().await,
+                () = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.on_timeout().await;
+                }
+            }
+        }
+        self.shared
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&(self.peer, self.recv_id));
+        let mut state = self.state.lock().unwrap();
+        if !state.eof && state.error.is_none() {
+            state.error = Some(io::ErrorKind::ConnectionAborted);
+        }
+        state.wake_writer();
+        state.wake_reader();
+    }
+
+    fn is_finished(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        match self.phase {
+            _ if state.error.is_some() => true,
+            Phase::SynSent(_) => state.dropped,
+            Phase::Connected => {
+                self.fin_sent && self.in_flight.is_empty() && (state.eof || state.dropped)
+            }
+        }
+    }
+
+    fn fail(&mut self, error: io::ErrorKind) {
+        if let Phase::SynSent(connected) = &mut self.phase {
+            if let Some(connected) = connected.take() {
+                let _ = connected.send(Err(error.into()));
+            }
+        }
+        self.state.lock().unwrap().error = Some(error);
+    }
+
+    fn receive_window(&self) -> usize {
+        RECEIVE_BUFFER.saturating_sub(self.state.lock().unwrap().received.len())
+    }
+
+    async fn send(&mut self, mut packet: Packet) {
+        let window = self.receive_window();
+        self.state.lock().unwrap().window_closed = window < MAX_PAYLOAD;
+        packet.timestamp = now_micros();
+        packet.timestamp_diff = self.reply_delay;
+        packet.window = window as u32;
+        packet.ack = self.ack;
+        // a lost packet is as good as one the socket failed to send
+        let _ = self.shared.send_to(&packet, self.peer).await;
+    }
+
+    async fn send_state(&mut self) {
+        let packet = Packet {
+            kind: Kind::State,
+            connection_id: self.send_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            window: 0,
+            seq: self.seq,
+            ack: self.ack,
+            payload: Vec::new(),
+        };
+        self.send(packet).await;
+    }
+
+    /// Sends a packet that takes a sequence number and so has to be acknowledged.
+    async fn transmit(&mut self, kind: Kind, payload: Vec<u8>) {
+        let packet = Packet {
+            kind,
+            // the SYN carries the id we receive with, and the peer sends with it
+            connection_id: match kind {
+                Kind::Syn => self.recv_id,
+                _ => self.send_id,
+            },
+            timestamp: 0,
+            timestamp_diff: 0,
+            window: 0,
+            seq: self.seq,
+            ack: self.ack,
+            payload,
+        };
+        self.seq = self.seq.wrapping_add(1);
+        self.send(packet.clone()).await;
+        self.in_flight.push_back(Sent {
+            packet,
+            sent_at: Instant::now(),
+            transmissions: 1,
+        });
+    }
+
+    async fn retransmit_oldest(&mut self) {
+        let Some(oldest) = self.in_flight.front_mut() else {
+            return;
+        };
+        oldest.sent_at = Instant::now();
+        oldest.transmissions += 1;
+        let packet = oldest.packet.clone();
+        self.send(packet).await;
+    }
+
+    /// Puts written data into packets as far as the congestion window and the peer's receive
+    /// window allow, and sends the FIN once everything has been sent after a shutdown.
+    async fn send_queued(&mut self) {
+        if !matches!(self.phase, Phase::Connected) || self.fin_sent {
+            return;
+        }
+        loop {
+            let in_flight: usize = self.in_flight.iter().map(|s| s.packet.payload.len()).sum();
+            let window = self.ledbat.window().min(self.their_window);
+            let (payload, closing) = {
+                let mut state = self.state.lock().unwrap();
+                let length = state.unsent.len().min(MAX_PAYLOAD);
+                // a packet always fits when none are in flight, or nothing would ever be sent
+                let fits = in_flight == 0 || in_flight + length <= window;
+                let payload = if length > 0 && fits {
+                    state.wake_writer();
+                    Some(state.unsent.split_to(length).to_vec())
+                } else {
+                    None
+                };
+                (
+                    payload,
+                    state.unsent.is_empty() && (state.shutdown || state.dropped),
+                )
+            };
+            match payload {
+                Some(payload) => self.transmit(Kind::Data, payload).await,
+                None if closing => {
+                    self.transmit(Kind::Fin, Vec::new()).await;
+                    self.fin_sent = true;
+                    return;
+                }
+                None => return,
+            }
+        }
+    }
+
+    async fn handle(&mut self, packet: Packet) {
+        self.reply_delay = now_micros().wrapping_sub(packet.timestamp);
+        self.their_window = packet.window as usize;
+        match packet.kind {
+            Kind::Reset => return self.fail(io::ErrorKind::ConnectionReset),
+            // our answer to the SYN got lost
+            Kind::Syn => return self.send_state().await,
+            _ => {}
+        }
+        if let Phase::SynSent(connected) = &mut self.phase {
+            if packet.kind != Kind::State || packet.ack != self.seq.wrapping_sub(1) {
+                return;
+            }
+            // the STATE doesn't take a sequence number, the peer's first data will have it
+            self.ack = packet.seq.wrapping_sub(1);
+            if let Some(connected) = connected.take() {
+                let _ = connected.send(Ok(()));
+            }
+            self.phase = Phase::Connected;
+        }
+        self.on_ack(&packet).await;
+        match packet.kind {
+            Kind::Data => {
+                self.receive(packet.seq, packet.payload);
+                self.send_state().await;
+            }
+            Kind::Fin => {
+                self.their_fin = Some(packet.seq);
+                self.receive(packet.seq, Vec::new());
+                self.send_state().await;
+            }
+            _ => {}
+        }
+    }
+
+    async fn on_ack(&mut self, packet: &Packet) {
+        let now = Instant::now();
+        let mut acked = 0;
+        let mut any = false;
+        while let Some(sent) = self.in_flight.front() {
+            if !seq_le(sent.packet.seq, packet.ack) || !seq_le(packet.ack, self.seq) {
+                break;
+            }
+            // a retransmitted packet's round trip can't be told from the first one's
+            if sent.transmissions == 1 {
+                self.round_trip.sample(now - sent.sent_at);
+            }
+            acked += sent.packet.payload.len();
+            any = true;
+            self.in_flight.pop_front();
+        }
+        if any {
+            self.duplicate_acks = 0;
+            if acked > 0 {
+                self.ledbat.on_ack(acked, packet.timestamp_diff, now);
+            }
+        } else if packet.kind == Kind::State
+            && self.last_ack == Some(packet.ack)
+            && !self.in_flight.is_empty()
+        {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == DUPLICATE_ACKS {
+                self.ledbat.on_loss();
+                self.retransmit_oldest().await;
+            }
+        }
+        self.last_ack = Some(packet.ack);
+    }
+
+    /// Takes in the payload of packet `seq`, in order: packets after a missing one wait until
+    /// it arrives.
+    fn receive(&mut self, seq: u16, payload: Vec<u8>) {
+        let next = self.ack.wrapping_add(1);
+        if seq != next {
+            if !seq_le(seq, self.ack) && seq.wrapping_sub(self.ack) < MAX_OUT_OF_ORDER {
+                self.out_of_order.insert(seq, payload);
+            }
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let mut payload = Some(payload);
+        while let Some(data) = payload {
+            self.ack = self.ack.wrapping_add(1);
+            if self.their_fin == Some(self.ack) {
+                state.eof = true;
+                break;
+            }
+            state.received.extend_from_slice(&data);
+            payload = self.out_of_order.remove(&self.ack.wrapping_add(1));
+        }
+        if self.their_fin == Some(self.ack.wrapping_add(1)) {
+            self.ack = self.ack.wrapping_add(1);
+            state.eof = true;
+        }
+        state.wake_reader();
+    }
+
+    /// Lets the peer know once the reader has made room in a window that was full.
+    async fn update_window(&mut self) {
+        let closed = self.state.lock().unwrap().window_closed;
+        if closed && self.receive_window() >= MAX_PAYLOAD {
+            self.send_state().await;
+        }
+    }
+
+    async fn on_timeout(&mut self) {
+        let Some(oldest) = self.in_flight.front() else {
+            return;
+        };
+        let limit = match self.phase {
+            Phase::SynSent(_) => MAX_SYN_RETRANSMISSIONS,
+            Phase::Connected => MAX_RETRANSMISSIONS,
+        };
+        if oldest.transmissions > limit {
+            return self.fail(io::ErrorKind::TimedOut);
+        }
+        self.round_trip.back_off();
+        self.ledbat.on_timeout();
+        self.retransmit_oldest().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn packet(kind: Kind, payload: &[u8]) -> Packet {
+        Packet {
+            kind,
+            connection_id: 0x1234,
+            timestamp: 1,
+            timestamp_diff: 2,
+            window: 3,
+            seq: 4,
+            ack: 5,
+            payload: payload.to_vec(),
+        }
+    }
+
+    /// Forwards datagrams between `client` and `server`, except every `drop_every`th one.
+    async fn spawn_lossy_relay(server: SocketAddr, drop_every: usize) -> SocketAddr {
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = relay.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut client = None;
+            let mut buffer = vec![0; 64 * 1024];
+            for count in 1.. {
+                let (n, from) = relay.recv_from(&mut buffer).await.unwrap();
+                if count % drop_every == 0 {
+                    continue;
+                }
+                let to = if from == server {
+                    client.unwrap()
+                } else {
+                    client = Some(from);
+                    server
+                };
+                relay.send_to(&buffer[..n], to).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn packet_bytes_round_trip() {
+        let packet = packet(Kind::Data, b"hello");
+        let bytes = packet.to_bytes();
+        assert_eq!(
+            bytes[..HEADER_LENGTH],
+            [0x01, 0, 0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 4, 0, 5]
+        );
+        assert_eq!(Packet::parse(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn parse_skips_extensions() {
+        let mut bytes = packet(Kind::State, b"").to_bytes();
+        // a selective ack of four bytes
+        bytes[1] = 1;
+        bytes.extend([0, 4, 0xff, 0, 0, 0]);
+        bytes.extend(b"data");
+        let parsed = Packet::parse(&bytes).unwrap();
+        assert_eq!(parsed.kind, Kind::State);
+        assert_eq!(parsed.payload, b"data");
+        bytes.truncate(HEADER_LENGTH + 3);
+        assert!(Packet::parse(&bytes).is_err());
+        assert!(Packet::parse(&[0x41; 10]).is_err());
+        assert!(Packet::parse(&[0x52; HEADER_LENGTH]).is_err());
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        assert!(seq_le(1, 2));
+        assert!(seq_le(2, 2));
+        assert!(!seq_le(3, 2));
+        assert!(seq_le(0xfffe, 3));
+        assert!(!seq_le(3, 0xfffe));
+    }
+
+    #[test]
+    fn ledbat_backs_off_above_target_delay() {
+        let mut ledbat = Ledbat::new();
+        let now = Instant::now();
+        ledbat.on_ack(MAX_PAYLOAD, 10_000, now);
+        let grown = ledbat.window;
+        assert!(grown > INITIAL_WINDOW);
+        // the base delay is 10ms, so 60ms more is still below the target
+        ledbat.on_ack(MAX_PAYLOAD, 70_000, now);
+        assert!(ledbat.window > grown);
+        let before = ledbat.window;
+        ledbat.on_ack(MAX_PAYLOAD, 310_000, now);
+        assert!(ledbat.window < before);
+        ledbat.on_loss();
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window, MIN_WINDOW);
+    }
+
+    #[test]
+    fn round_trip_timeout() {
+        let mut round_trip = RoundTrip::new();
+        assert_eq!(round_trip.timeout, INITIAL_TIMEOUT);
+        round_trip.sample(Duration::from_millis(200));
+        assert_eq!(round_trip.timeout, Duration::from_millis(600));
+        round_trip.back_off();
+        assert_eq!(round_trip.timeout, Duration::from_millis(1200));
+        for _ in 0..50 {
+            round_trip.sample(Duration::from_millis(1));
+        }
+        assert_eq!(round_trip.timeout, MIN_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn transfer_both_ways() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let data: Vec<u8> = (0..500_000).map(|i| (i % 251) as u8).collect();
+        let expected = data.clone();
+        let serving = tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let mut received = vec![0; expected.len()];
+            stream.read_exact(&mut received).await.unwrap();
+            assert!(received == expected);
+            stream.write_all(b"done").await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).await.unwrap();
+            rest
+        });
+
+        let mut stream = client.connect(server_addr).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), server_addr);
+        stream.write_all(&data).await.unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"done");
+        stream.write_all(b"bye").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let rest = tokio::time::timeout(Duration::from_secs(10), serving)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rest, b"bye");
+    }
+
+    #[tokio::test]
+    async fn transfer_survives_packet_loss() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay = spawn_lossy_relay(server.local_addr().unwrap(), 7).await;
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 249) as u8).collect();
+        let sending = {
+            let data = data.clone();
+            tokio::spawn(async move {
+                let mut stream = client.connect(relay).await.unwrap();
+                stream.write_all(&data).await.unwrap();
+                drop(stream);
+                // the connection lives on until everything is acknowledged
+                client
+            })
+        };
+
+        let (mut stream, _) = server.accept().await.unwrap();
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(20), stream.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received == data);
+        let _client = sending.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_fails_without_answer() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let error = client
+            .connect(silent.local_addr().unwrap())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(client.shared.connections.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_connection_is_reset() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let data = packet(Kind::Data, b"hello").to_bytes();
+        socket
+            .send_to(&data, server.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut buffer = [0; 100];
+        let n = socket.recv(&mut buffer).await.unwrap();
+        let reset = Packet::parse(&buffer[..n]).unwrap();
+        assert_eq!(reset.kind, Kind::Reset);
+        assert_eq!(reset.connection_id, 0x1234);
+        assert_eq!(reset.ack, 4);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::daemon::Daemon;
+use crate::magnet::Magnet;
+use crate::torrent::Torrent;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often the watched directory is looked at.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What a file in the watched directory holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Torrent,
+    /// A text file with a magnet link.
+    Magnet,
+}
+
+fn kind(path: &Path) -> Option<Kind> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "torrent" => Some(Kind::Torrent),
+        "magnet" => Some(Kind::Magnet),
+        _ => None,
+    }
+}
+
+/// Adds every `.torrent` file, and every `.magnet` file holding a magnet link, that shows up in
+/// `dir` to `daemon`, looking every `interval`. Added files are moved to `done`, and ones that
+/// can't be read are renamed to end in `.invalid`, so neither is picked up again.
+///
+/// A file is only read once its length stayed the same between two looks, so one that is
+/// still being copied in isn't mistaken for a broken one. Magnet links are resolved in the
+/// background, and tried again on the next look if that fails. Runs until it is dropped.
+pub async fn watch_directory(
+    daemon: Arc<Daemon>,
+    dir: &Path,
+    done: &Path,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(done).await?;
+    let mut watcher = Watcher {
+        daemon,
+        done: done.to_path_buf(),
+        lengths: HashMap::new(),
+        resolving: Arc::default(),
+    };
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        // the directory may be gone for a while, e.g. on a network mount
+        let _ = watcher.scan(dir).await;
+    }
+}
+
+struct Watcher {
+    daemon: Arc<Daemon>,
+    done: PathBuf,
+    /// The length each file had when last looked at.
+    lengths: HashMap<PathBuf, u64>,
+    /// Magnet files whose links are being resolved.
+    resolving: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl Watcher {
+    async fn scan(&mut self, dir: &Path) -> anyhow::Result<()> {
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        let mut lengths = HashMap::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let Some(kind) = kind(&path) else {
+                continue;
+            };
+            let metadata = entry.metadata().await?;
+            if !metadata.is_file() || self.resolving.lock().unwrap().contains(&path) {
+                continue;
+            }
+            let length = metadata.len();
+            lengths.insert(path.clone(), length);
+            if self.lengths.get(&path) == Some(&length) {
+                self.ingest(path, kind).await;
+            }
+        }
+        self.lengths = lengths;
+        Ok(())
+    }
+
+    async fn ingest(&self, path: PathBuf, kind: Kind) {
+        let Ok(contents) = tokio::fs::read(&path).await else {
+            return;
+        };
+        match kind {
+            Kind::Torrent => match Torrent::from_bytes(&contents) {
+                // one that was added already counts as done too
+                Ok(torrent) => {
+                    let _ = self.daemon.add(torrent, None, false);
+                    move_to(&path, &self.done).await;
+                }
+                Err(_) => mark_invalid(&path).await,
+            },
+            Kind::Magnet => {
+                let link = String::from_utf8_lossy(&contents);
+                let Ok(magnet) = Magnet::parse(link.trim()) else {
+                    mark_invalid(&path).await;
+                    return;
+                };
+                self.resolving.lock().unwrap().insert(path.clone());
+                let (daemon, done, resolving) = (
+                    self.daemon.clone(),
+                    self.done.clone(),
+                    self.resolving.clone(),
+                );
+                tokio::spawn(async move {
+                    if let Ok(torrent) = daemon.session().resolve_magnet(&magnet).await {
+                        let _ = daemon.add(torrent, None, false);
+                        move_to(&path, &done).await;
+                    }
+                    resolving.lock().unwrap().remove(&path);
+                });
+            }
+        }
+    }
+}
+
+async fn move_to(path: &Path, dir: &Path) {
+    if let Some(name) = path.file_name() {
+        let _ = tokio::fs::rename(path, dir.join(name)).await;
+    }
+}
+
+async fn mark_invalid(path: &Path) {
+    let mut invalid = path.as_os_str().to_owned();
+    invalid.push(".invalid");
+    let _ = tokio::fs::rename(path, invalid).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::{
+        announce_body, spawn_seeder, spawn_tracker, test_data, torrent_for,
+    };
+    use crate::download::DownloadOptions;
+    use crate::session::{Session, TorrentState};
+
+    #[tokio::test]
+    async fn adds_torrents_that_show_up() {
+        let data = test_data(50_000);
+        let mut torrent = torrent_for(&data, 16384);
+        let seeder = spawn_seeder(&torrent, data.clone()).await;
+        let (url, _) = spawn_tracker(vec![announce_body(60, &[seeder])]).await;
+        torrent.announce = url;
+        let dir = tempfile::tempdir().unwrap();
+        let (watched, done, downloads) = (
+            dir.path().join("watch"),
+            dir.path().join("done"),
+            dir.path().join("downloads"),
+        );
+        std::fs::create_dir_all(&watched).unwrap();
+        let options = DownloadOptions {
+            port: 0,
+            ..DownloadOptions::default()
+        };
+        let session = Session::new([1; 20], options).await.unwrap();
+        let daemon = Arc::new(Daemon::new(session, &downloads));
+        let watcher = tokio::spawn({
+            let (daemon, watched, done) = (daemon.clone(), watched.clone(), done.clone());
+            async move { watch_directory(daemon, &watched, &done, Duration::from_millis(20)).await }
+        });
+
+        std::fs::write(watched.join("test.torrent"), torrent.to_bytes()).unwrap();
+        std::fs::write(watched.join("broken.torrent"), b"d4:info").unwrap();
+        std::fs::write(watched.join("notes.txt"), b"not a torrent").unwrap();
+        let mut state = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(state) = daemon.session().state(&torrent.info_hash()) {
+                    return state;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let finished = state.wait_for(|state| *state == TorrentState::Finished);
+        tokio::time::timeout(Duration::from_secs(10), finished)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::fs::read(downloads.join("test.bin")).unwrap(), data);
+        assert!(done.join("test.torrent").exists());
+        assert!(!watched.join("test.torrent").exists());
+        // the broken file is set aside once it stopped changing, and other files are left be
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(watched.join("broken.torrent.invalid").exists());
+        assert!(watched.join("notes.txt").exists());
+        watcher.abort();
+    }
+
+    #[test]
+    fn file_kinds() {
+        assert_eq!(kind(Path::new("a/b.torrent")), Some(Kind::Torrent));
+        assert_eq!(kind(Path::new("b.TORRENT")), Some(Kind::Torrent));
+        assert_eq!(kind(Path::new("c.magnet")), Some(Kind::Magnet));
+        assert_eq!(kind(Path::new("c.torrent.invalid")), None);
+        assert_eq!(kind(Path::new("torrent")), None);
+    }
+}
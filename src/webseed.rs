@@ -0,0 +1,306 @@
+use crate::filemap::FileMap;
+use crate::torrent::Torrent;
+use crate::tracker::urlencode;
+use reqwest::header::RANGE;
+use reqwest::{Client, StatusCode, Url};
+use std::ops::Range;
+use std::time::Duration;
+
+/// How long a web seed gets to deliver a piece before it counts as failed.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A server the data of a torrent can be downloaded from over HTTP, alongside its peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSeed {
+    /// Serves the files of the torrent, fetched with range requests (`url-list`, BEP 19).
+    Url(String),
+    /// A script serving whole pieces by index (`httpseeds`, BEP 17).
+    Http(String),
+}
+
+impl WebSeed {
+    pub fn from_torrent(torrent: &Torrent) -> Vec<WebSeed> {
+        let urls = torrent.url_list.iter().cloned().map(WebSeed::Url);
+        urls.chain(torrent.httpseeds.iter().cloned().map(WebSeed::Http))
+            .collect()
+    }
+
+    pub fn url(&self) -> &str {
+        match self {
+            WebSeed::Url(url) | WebSeed::Http(url) => url,
+        }
+    }
+
+    /// Downloads the piece at `index` and checks it against its hash.
+    pub async fn fetch_piece(
+        &self,
+        client: &Client,
+        torrent: &Torrent,
+        index: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let piece = self.fetch_unverified(client, torrent, index).await?;
+        if !torrent.verify_piece(index, &piece) {
+            anyhow::bail!("piece {} failed hash verification", index);
+        }
+        Ok(piece)
+    }
+
+    /// Downloads the piece at `index`, leaving the hash check to the caller.
+    pub(crate) async fn fetch_unverified(
+        &self,
+        client: &Client,
+        torrent: &Torrent,
+        index: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let size = torrent.piece_size(index);
+        let piece = match self {
+            WebSeed::Url(url) => {
+                // whatever no file covers is padding, which is all zeros
+                let mut piece = vec![0; size];
+                for (file, range, at) in file_ranges(url, torrent, index)? {
+                    let length = range.len();
+                    let bytes = fetch_range(client, file, range).await?;
+                    piece[at..at + length].copy_from_slice(&bytes);
+                }
+                piece
+            }
+            WebSeed::Http(url) => {
+                let separator = if url.contains('?') { '&' } else { '?' };
+                let url = format!(
+                    "{}{}info_hash={}&piece={}",
+                    url,
+                    separator,
+                    urlencode(&torrent.info_hash()),
+                    index
+                );
+                let response = client.get(&url).send().await?.error_for_status()?;
+                response.bytes().await?.to_vec()
+            }
+        };
+        if piece.len() != size {
+            anyhow::bail!(
+                "web seed sent {} bytes for piece {} of {} bytes",
+                piece.len(),
+                index,
+                size
+            );
+        }
+        Ok(piece)
+    }
+}
+
+/// The files the piece at `index` is part of, each with the URL it is served at by the web
+/// seed at `base`, the range of its bytes within the file that belong to the piece and where
+/// in the piece they go.
+fn file_ranges(
+    base: &str,
+    torrent: &Torrent,
+    index: usize,
+) -> anyhow::Result<Vec<(Url, Range<usize>, usize)>> {
+    let base = Url::parse(base)?;
+    let map = FileMap::new(torrent)?;
+    let mut ranges = Vec::new();
+    for slice in map.piece_slices(index, 0, torrent.piece_size(index))? {
+        let path: Vec<String> = map.spans()[slice.file]
+            .path
+            .iter()
+            .map(|component| component.to_string_lossy().into_owned())
+            .collect();
+        let url = file_url(&base, &torrent.info.name, &path)?;
+        ranges.push((url, slice.offset..slice.offset + slice.length, slice.start));
+    }
+    Ok(ranges)
+}
+
+/// A single-file torrent is served at `base` itself, unless it ends with a slash; then, as for
+/// multi-file torrents, `base` is the directory the torrent's name and the file's path are
+/// appended to.
+fn file_url(base: &Url, name: &str, path: &[String]) -> anyhow::Result<Url> {
+    if path.is_empty() && !base.path().ends_with('/') {
+        return Ok(base.clone());
+    }
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("web seed URL {} can't have a path", base))?
+        .pop_if_empty()
+        .push(name)
+        .extend(path);
+    Ok(url)
+}
+
+async fn fetch_range(client: &Client, url: Url, range: Range<usize>) -> anyhow::Result<Vec<u8>> {
+    let response = client
+        .get(url.clone())
+        .header(RANGE, format!("bytes={}-{}", range.start, range.end - 1))
+        .send()
+        .await?
+        .error_for_status()?;
+    let status = response.status();
+    let body = response.bytes().await?;
+    let data = match status {
+        StatusCode::PARTIAL_CONTENT => &body[..],
+        // a server that doesn't do ranges sends the whole file
+        StatusCode::OK => body.get(range.clone()).unwrap_or_default(),
+        status => anyhow::bail!("web seed answered {} for {}", status, url),
+    };
+    if data.len() != range.len() {
+        anyhow::bail!(
+            "web seed sent {} of {} bytes from {}",
+            data.len(),
+            range.len(),
+            url
+        );
+    }
+    Ok(data.to_vec())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::download::tests::{multi_file_torrent_for, test_data, torrent_for};
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves every request with `respond`, which gets the request line and headers and
+    /// returns the status line and body of the response.
+    async fn spawn_http_server(
+        respond: impl Fn(&str) -> (&'static str, Vec<u8>) + Send + 'static,
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = [0; 4096];
+                let n = stream.read(&mut buffer).await.unwrap();
+                let (status, body) = respond(&String::from_utf8_lossy(&buffer[..n]));
+                let header = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                stream.write_all(header.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+            }
+        });
+        url
+    }
+
+    /// Serves `files` by path, answering range requests; returns the URL of the server.
+    pub(crate) async fn spawn_web_seed(files: Vec<(&str, Vec<u8>)>) -> String {
+        let files: HashMap<String, Vec<u8>> = files
+            .into_iter()
+            .map(|(path, data)| (path.to_owned(), data))
+            .collect();
+        spawn_http_server(move |request| {
+            let path = request.split(' ').nth(1).unwrap_or_default();
+            let Some(data) = files.get(path) else {
+                return ("404 Not Found", Vec::new());
+            };
+            let range = request.lines().find_map(|line| {
+                let (start, end) = line.strip_prefix("range: bytes=")?.split_once('-')?;
+                Some(start.parse::<usize>().ok()?..end.parse::<usize>().ok()? + 1)
+            });
+            match range {
+                Some(range) => ("206 Partial Content", data[range].to_vec()),
+                None => ("200 OK", data.clone()),
+            }
+        })
+        .await
+    }
+
+    #[test]
+    fn file_urls() {
+        let data = test_data(100);
+        let torrent = torrent_for(&data, 100);
+        let ranges = |base| file_ranges(base, &torrent, 0).unwrap();
+        assert_eq!(
+            ranges("http://seed/files/test.bin")[0].0.as_str(),
+            "http://seed/files/test.bin"
+        );
+        assert_eq!(
+            ranges("http://seed/files/")[0].0.as_str(),
+            "http://seed/files/test.bin"
+        );
+
+        let torrent =
+            multi_file_torrent_for(&data, 60, &[("a b", 50), ("dir/c", 0), ("dir/d", 50)]);
+        let ranges = file_ranges("http://seed/files", &torrent, 0).unwrap();
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(ranges[0].0.as_str(), "http://seed/files/test/a%20b");
+        assert_eq!(ranges[0].1, 0..50);
+        assert_eq!(ranges[1].0.as_str(), "http://seed/files/test/dir/d");
+        assert_eq!(ranges[1].1, 0..10);
+        let ranges = file_ranges("http://seed/files/", &torrent, 1).unwrap();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].1, 10..50);
+    }
+
+    #[tokio::test]
+    async fn fetch_piece_from_url_seed() {
+        let data = test_data(100);
+        let torrent = multi_file_torrent_for(&data, 32, &[("a", 40), ("dir/b", 60)]);
+        let url = spawn_web_seed(vec![
+            ("/seed/test/a", data[..40].to_vec()),
+            ("/seed/test/dir/b", data[40..].to_vec()),
+        ])
+        .await;
+        let seed = WebSeed::Url(format!("{}/seed/", url));
+        let client = Client::new();
+        for index in 0..4 {
+            let piece = seed.fetch_piece(&client, &torrent, index).await.unwrap();
+            assert_eq!(piece, data[index * 32..(index * 32 + 32).min(100)]);
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_piece_from_server_without_ranges() {
+        let data = test_data(100);
+        let torrent = torrent_for(&data, 32);
+        let file = data.clone();
+        let url = spawn_http_server(move |_| ("200 OK", file.clone())).await;
+        let piece = WebSeed::Url(url)
+            .fetch_piece(&Client::new(), &torrent, 1)
+            .await
+            .unwrap();
+        assert_eq!(piece, data[32..64]);
+    }
+
+    #[tokio::test]
+    async fn fetch_piece_from_http_seed() {
+        let data = test_data(100);
+        let torrent = torrent_for(&data, 32);
+        let info_hash = urlencode(&torrent.info_hash());
+        let pieces = data.clone();
+        let url = spawn_http_server(move |request| {
+            let query = request.split(' ').nth(1).unwrap_or_default();
+            let Some(index) = query
+                .strip_prefix(&format!("/seed?id=1&info_hash={}&piece=", info_hash))
+                .and_then(|index| index.parse::<usize>().ok())
+            else {
+                return ("400 Bad Request", Vec::new());
+            };
+            let start = index * 32;
+            ("200 OK", pieces[start..(start + 32).min(100)].to_vec())
+        })
+        .await;
+        let seed = WebSeed::Http(format!("{}/seed?id=1", url));
+        let piece = seed.fetch_piece(&Client::new(), &torrent, 3).await.unwrap();
+        assert_eq!(piece, data[96..]);
+    }
+
+    #[tokio::test]
+    async fn fetch_piece_rejects_corrupt_data() {
+        let data = test_data(100);
+        let torrent = torrent_for(&data, 32);
+        let url = spawn_web_seed(vec![("/test.bin", vec![0; 100])]).await;
+        let seed = WebSeed::Url(format!("{}/test.bin", url));
+        let error = seed.fetch_piece(&Client::new(), &torrent, 0).await;
+        assert!(format!("{:#}", error.unwrap_err()).contains("hash verification"));
+        let missing = WebSeed::Url(format!("{}/other.bin", url));
+        assert!(missing
+            .fetch_piece(&Client::new(), &torrent, 0)
+            .await
+            .is_err());
+    }
+}